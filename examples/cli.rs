@@ -0,0 +1,486 @@
+use redis_starter_rust::resp::inline::split_args;
+use redis_starter_rust::resp::resp_deserializer::FrameDecoder;
+use redis_starter_rust::resp::resp_serializer::serialize_resp_data;
+use redis_starter_rust::resp::RespType;
+
+use bytes::{Bytes, BytesMut};
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{self, BufRead, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+// A minimal redis-cli. With a command on the command line it runs just that command, otherwise
+// it reads commands from an interactive prompt. --bigkeys and --memkeys analyse the keyspace
+// instead.
+
+const SCAN_COUNT: &str = "100";
+
+struct CliConfig {
+    host: String,
+    port: String,
+    raw: bool,
+    // Whether to switch the connection to RESP3 with HELLO before anything else
+    resp3: bool,
+    command: Vec<String>,
+    analysis: Option<Analysis>,
+    // How long to sleep for every 100 SCANs while analysing
+    interval: Duration,
+}
+
+// Which keys --bigkeys and --memkeys rank as biggest
+#[derive(Clone, Copy, PartialEq)]
+enum Analysis {
+    // By length, bytes for strings and elements for everything else
+    Length,
+    Memory,
+}
+
+// What the analysis found for one type of key
+#[derive(Default)]
+struct TypeSummary {
+    keys: u64,
+    total_length: u64,
+    total_memory: u64,
+    biggest: Option<(Bytes, u64)>,
+}
+
+fn main() {
+    let config = parse_args();
+    let address = format!("{}:{}", config.host, config.port);
+    let mut stream = match TcpStream::connect(&address) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Could not connect to {}: {}", address, e);
+            std::process::exit(1);
+        }
+    };
+
+    if config.resp3 {
+        let hello = vec![b"HELLO".to_vec(), b"3".to_vec()];
+        match send_command(&mut stream, hello) {
+            Ok(RespType::Error(e)) => eprintln!("Failed to switch to RESP3: {}", e),
+            Ok(_) => (),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(analysis) = config.analysis {
+        if let Err(e) = find_big_keys(&mut stream, analysis, config.interval) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if !config.command.is_empty() {
+        let args = config
+            .command
+            .iter()
+            .map(|x| x.as_bytes().to_vec())
+            .collect();
+        match send_command(&mut stream, args) {
+            Ok(reply) => println!("{}", format_reply(&reply, config.raw)),
+            Err(e) => eprintln!("{}", e),
+        }
+        return;
+    }
+
+    let stdin = io::stdin();
+    loop {
+        print!("{}> ", address);
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => return,
+            Ok(_) => (),
+            Err(e) => {
+                eprintln!("Failed to read from stdin: {}", e);
+                return;
+            }
+        }
+        let args = match split_args(line.as_bytes()) {
+            Some(x) => x,
+            None => {
+                println!("Invalid argument(s)");
+                continue;
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+        if args.len() == 1 && matches!(args[0].as_slice(), b"quit" | b"exit") {
+            return;
+        }
+        match send_command(&mut stream, args) {
+            Ok(reply) => println!("{}", format_reply(&reply, config.raw)),
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        }
+    }
+}
+
+fn parse_args() -> CliConfig {
+    let args: Vec<String> = env::args().collect();
+    let mut config = CliConfig {
+        host: String::from("127.0.0.1"),
+        port: String::from("6379"),
+        raw: false,
+        resp3: false,
+        command: Vec::new(),
+        analysis: None,
+        interval: Duration::ZERO,
+    };
+    let mut index = 1;
+    while index < args.len() {
+        match args[index].as_str() {
+            "-i" => {
+                config.interval = match args.get(index + 1).map(|x| x.parse::<f64>()) {
+                    Some(Ok(x)) if x >= 0.0 => Duration::from_secs_f64(x),
+                    _ => panic!("Error: -i requires a number of seconds"),
+                };
+                index += 1;
+            }
+            "--bigkeys" => config.analysis = Some(Analysis::Length),
+            "--memkeys" => config.analysis = Some(Analysis::Memory),
+            "-h" | "-p" => {
+                let value = match args.get(index + 1) {
+                    Some(x) => x.clone(),
+                    None => panic!("Error: {} requires a value", args[index]),
+                };
+                if args[index] == "-h" {
+                    config.host = value;
+                } else {
+                    config.port = value;
+                }
+                index += 1;
+            }
+            "--raw" => config.raw = true,
+            "--no-raw" => config.raw = false,
+            "-2" => config.resp3 = false,
+            "-3" => config.resp3 = true,
+            // Everything from the first non-option onwards is the command to run
+            _ => {
+                config.command = args[index..].to_vec();
+                break;
+            }
+        }
+        index += 1;
+    }
+    config
+}
+
+fn send_command(stream: &mut TcpStream, args: Vec<Vec<u8>>) -> Result<RespType, String> {
+    let mut replies = send_pipeline(stream, vec![args])?;
+    Ok(replies.remove(0))
+}
+
+// Sends every command before reading any of the replies, which come back in the same order
+fn send_pipeline(
+    stream: &mut TcpStream,
+    commands: Vec<Vec<Vec<u8>>>,
+) -> Result<Vec<RespType>, String> {
+    let mut request = Vec::new();
+    let expected = commands.len();
+    for args in commands {
+        let command = RespType::Array(
+            args.into_iter()
+                .map(|x| RespType::BulkString(Some(Bytes::from(x))))
+                .collect(),
+        );
+        request.extend_from_slice(&serialize_resp_data(command));
+    }
+    stream
+        .write_all(&request)
+        .map_err(|e| format!("Failed to send command: {}", e))?;
+
+    let mut replies = Vec::with_capacity(expected);
+    let mut buffer = BytesMut::new();
+    let mut decoder = FrameDecoder::new();
+    let mut chunk = [0; 4096];
+    while replies.len() < expected {
+        match decoder.decode(&mut buffer) {
+            Ok(Some((reply, _))) => {
+                replies.push(reply);
+                continue;
+            }
+            Ok(None) => (),
+            Err(e) => return Err(e.to_string()),
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) => return Err(String::from("Server closed the connection")),
+            Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+            Err(e) => return Err(format!("Failed to read reply: {}", e)),
+        }
+    }
+    Ok(replies)
+}
+
+// Walks the keyspace with SCAN, like redis-cli --bigkeys and --memkeys, so the server only ever
+// does a little work at a time. Reports the biggest key of each type as it's found, then a summary.
+fn find_big_keys(
+    stream: &mut TcpStream,
+    analysis: Analysis,
+    interval: Duration,
+) -> Result<(), String> {
+    println!("\n# Scanning the entire keyspace to find biggest keys as well as");
+    println!("# average sizes per key type.  You can use -i 0.1 to sleep 0.1 sec");
+    println!("# per 100 SCAN commands (not usually needed).\n");
+
+    let mut summaries: BTreeMap<String, TypeSummary> = BTreeMap::new();
+    let (mut sampled, mut total_key_length) = (0u64, 0u64);
+    let mut cursor = Bytes::from("0");
+    let mut scans = 0u64;
+    loop {
+        let scan = vec![
+            b"SCAN".to_vec(),
+            cursor.to_vec(),
+            b"COUNT".to_vec(),
+            SCAN_COUNT.into(),
+        ];
+        let keys = match send_command(stream, scan)? {
+            RespType::Array(mut x) if x.len() == 2 => match (x.pop(), x.pop()) {
+                (Some(RespType::Array(keys)), Some(RespType::BulkString(Some(next)))) => {
+                    cursor = next;
+                    keys.into_iter()
+                        .filter_map(|x| match x {
+                            RespType::BulkString(Some(key)) => Some(key),
+                            _ => None,
+                        })
+                        .collect::<Vec<Bytes>>()
+                }
+                _ => return Err(String::from("Unexpected reply to SCAN")),
+            },
+            RespType::Error(e) => return Err(format!("SCAN failed: {}", e)),
+            _ => return Err(String::from("Unexpected reply to SCAN")),
+        };
+
+        let types = send_pipeline(
+            stream,
+            keys.iter()
+                .map(|key| vec![b"TYPE".to_vec(), key.to_vec()])
+                .collect(),
+        )?;
+        // Keys deleted since the SCAN are "none", and skipped
+        let typed: Vec<(Bytes, String)> = keys
+            .into_iter()
+            .zip(types)
+            .filter_map(|(key, reply)| match reply {
+                RespType::SimpleString(x) if x != "none" => Some((key, x)),
+                _ => None,
+            })
+            .collect();
+        let mut commands = Vec::new();
+        for (key, type_name) in &typed {
+            commands.push(vec![length_command(type_name).into(), key.to_vec()]);
+            commands.push(vec![b"MEMORY".to_vec(), b"USAGE".to_vec(), key.to_vec()]);
+        }
+        let sizes = send_pipeline(stream, commands)?;
+
+        for ((key, type_name), size) in typed.into_iter().zip(sizes.chunks(2)) {
+            let (length, memory) = match size {
+                [RespType::Integer(length), RespType::Integer(memory)] => {
+                    (*length as u64, *memory as u64)
+                }
+                _ => continue,
+            };
+            sampled += 1;
+            total_key_length += key.len() as u64;
+            let summary = summaries.entry(type_name.clone()).or_default();
+            summary.keys += 1;
+            summary.total_length += length;
+            summary.total_memory += memory;
+            let ranked = match analysis {
+                Analysis::Length => length,
+                Analysis::Memory => memory,
+            };
+            if summary.biggest.as_ref().is_none_or(|(_, x)| ranked > *x) {
+                println!(
+                    "Biggest {:>6} found so far {} with {} {}",
+                    type_name,
+                    quote(&key),
+                    ranked,
+                    unit(&type_name, analysis)
+                );
+                summary.biggest = Some((key, ranked));
+            }
+        }
+
+        scans += 1;
+        if !interval.is_zero() && scans.is_multiple_of(100) {
+            thread::sleep(interval);
+        }
+        if cursor.as_ref() == b"0" {
+            break;
+        }
+    }
+
+    println!("\n-------- summary -------\n");
+    println!("Sampled {} keys in the keyspace!", sampled);
+    println!(
+        "Total key length in bytes is {} (avg len {:.2})\n",
+        total_key_length,
+        average(total_key_length, sampled)
+    );
+    for (type_name, summary) in &summaries {
+        if let Some((key, ranked)) = &summary.biggest {
+            println!(
+                "Biggest {:>6} found {} has {} {}",
+                type_name,
+                quote(key),
+                ranked,
+                unit(type_name, analysis)
+            );
+        }
+    }
+    println!();
+    for (type_name, summary) in &summaries {
+        println!(
+            "{} {}s with {} {} (avg size {:.2}), estimated memory {} bytes",
+            summary.keys,
+            type_name,
+            summary.total_length,
+            unit(type_name, Analysis::Length),
+            average(summary.total_length, summary.keys),
+            summary.total_memory
+        );
+    }
+    Ok(())
+}
+
+fn length_command(type_name: &str) -> &'static str {
+    match type_name {
+        "string" => "STRLEN",
+        "list" => "LLEN",
+        "hash" => "HLEN",
+        "set" => "SCARD",
+        "zset" => "ZCARD",
+        _ => "XLEN",
+    }
+}
+
+fn unit(type_name: &str, analysis: Analysis) -> &'static str {
+    match (analysis, type_name) {
+        (Analysis::Memory, _) | (_, "string") => "bytes",
+        (_, "list") => "items",
+        (_, "hash") => "fields",
+        (_, "stream") => "entries",
+        _ => "members",
+    }
+}
+
+fn average(total: u64, count: u64) -> f64 {
+    if count == 0 {
+        return 0.0;
+    }
+    total as f64 / count as f64
+}
+
+fn format_reply(reply: &RespType, raw: bool) -> String {
+    if raw {
+        return format_raw(reply);
+    }
+    format_pretty(reply, 0)
+}
+
+fn format_raw(reply: &RespType) -> String {
+    match reply {
+        RespType::Integer(x) => x.to_string(),
+        RespType::SimpleString(x) | RespType::Error(x) | RespType::BigNumber(x) => x.clone(),
+        RespType::BulkString(Some(x)) | RespType::VerbatimString(_, x) => {
+            String::from_utf8_lossy(x).into_owned()
+        }
+        RespType::BulkString(None) | RespType::NullArray | RespType::Null => String::new(),
+        RespType::Double(x) => x.to_string(),
+        RespType::Boolean(x) => (*x as i64).to_string(),
+        RespType::Array(x) | RespType::Set(x) | RespType::Push(x) => {
+            x.iter().map(format_raw).collect::<Vec<_>>().join("\n")
+        }
+        RespType::Map(x) => x
+            .iter()
+            .flat_map(|(key, value)| [format_raw(key), format_raw(value)])
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+// Nested aggregate elements are indented to line up under their parent's numbering
+fn format_pretty(reply: &RespType, indent: usize) -> String {
+    match reply {
+        RespType::Integer(x) => format!("(integer) {}", x),
+        RespType::SimpleString(x) => x.clone(),
+        RespType::Error(x) => format!("(error) {}", x),
+        RespType::BulkString(Some(x)) => quote(x),
+        RespType::BulkString(None) | RespType::NullArray | RespType::Null => String::from("(nil)"),
+        RespType::Double(x) => format!("(double) {}", x),
+        RespType::Boolean(x) => format!("({})", x),
+        RespType::BigNumber(x) => format!("(big number) {}", x),
+        RespType::VerbatimString(_, x) => String::from_utf8_lossy(x).into_owned(),
+        RespType::Array(x) | RespType::Push(x) => {
+            format_aggregate(x.iter().map(|x| (x, None)).collect(), ')', indent)
+        }
+        RespType::Set(x) => format_aggregate(x.iter().map(|x| (x, None)).collect(), '~', indent),
+        RespType::Map(x) => format_aggregate(
+            x.iter().map(|(key, value)| (key, Some(value))).collect(),
+            '#',
+            indent,
+        ),
+    }
+}
+
+// Map entries are shown as "key => value", everything else one element per entry
+fn format_aggregate(
+    entries: Vec<(&RespType, Option<&RespType>)>,
+    marker: char,
+    indent: usize,
+) -> String {
+    if entries.is_empty() {
+        return String::from("(empty array)");
+    }
+    let width = entries.len().to_string().len();
+    entries
+        .into_iter()
+        .enumerate()
+        .map(|(index, (element, value))| {
+            let prefix = format!("{:>width$}{} ", index + 1, marker, width = width);
+            let padding = if index == 0 { 0 } else { indent };
+            let mut entry = format!(
+                "{}{}{}",
+                " ".repeat(padding),
+                prefix,
+                format_pretty(element, indent + prefix.len())
+            );
+            if let Some(value) = value {
+                entry.push_str(" => ");
+                entry.push_str(&format_pretty(value, indent + prefix.len()));
+            }
+            entry
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn quote(data: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for &byte in data {
+        match byte {
+            b'\\' => quoted.push_str("\\\\"),
+            b'"' => quoted.push_str("\\\""),
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            7 => quoted.push_str("\\a"),
+            8 => quoted.push_str("\\b"),
+            x if x.is_ascii_graphic() || x == b' ' => quoted.push(x as char),
+            x => quoted.push_str(&format!("\\x{:02x}", x)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
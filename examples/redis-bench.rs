@@ -0,0 +1,269 @@
+use redis_starter_rust::redis::store::random_u64;
+use redis_starter_rust::resp::resp_deserializer::FrameDecoder;
+use redis_starter_rust::resp::resp_serializer::serialize_resp_data;
+use redis_starter_rust::resp::RespType;
+
+use bytes::{Bytes, BytesMut};
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// Drives SET/GET/INCR/LPUSH workloads against a running server, in the style of
+// redis-benchmark, and reports throughput and latency percentiles for each
+
+const WORKLOADS: [&str; 4] = ["set", "get", "incr", "lpush"];
+
+struct BenchConfig {
+    host: String,
+    port: String,
+    connections: usize,
+    requests: usize,
+    pipeline: usize,
+    data_size: usize,
+    keyspace: u64,
+    workloads: Vec<String>,
+}
+
+struct WorkloadResult {
+    // One per successful request
+    latencies: Vec<Duration>,
+    errors: usize,
+}
+
+#[tokio::main]
+async fn main() {
+    let config = Arc::new(parse_args());
+    for workload in &config.workloads {
+        run_workload(Arc::clone(&config), workload).await;
+    }
+}
+
+fn parse_args() -> BenchConfig {
+    let args: Vec<String> = env::args().collect();
+    let mut config = BenchConfig {
+        host: String::from("127.0.0.1"),
+        port: String::from("6379"),
+        connections: 50,
+        requests: 100_000,
+        pipeline: 1,
+        data_size: 3,
+        keyspace: 100_000,
+        workloads: WORKLOADS.iter().map(|x| x.to_string()).collect(),
+    };
+    let mut index = 1;
+    while index < args.len() {
+        let flag = args[index].as_str();
+        let value = match args.get(index + 1) {
+            Some(x) => x.clone(),
+            None => panic!("Error: {} requires a value", flag),
+        };
+        match flag {
+            "-h" => config.host = value,
+            "-p" => config.port = value,
+            "-c" => config.connections = parse_positive(flag, &value),
+            "-n" => config.requests = parse_positive(flag, &value),
+            "-P" => config.pipeline = parse_positive(flag, &value),
+            "-d" => config.data_size = parse_positive(flag, &value),
+            "-r" => config.keyspace = parse_positive(flag, &value) as u64,
+            "-t" => {
+                config.workloads = value.split(',').map(|x| x.trim().to_lowercase()).collect();
+                for workload in &config.workloads {
+                    if !WORKLOADS.contains(&workload.as_str()) {
+                        panic!("Error: unknown workload {}", workload);
+                    }
+                }
+            }
+            other => panic!("Error: unknown option {}", other),
+        }
+        index += 2;
+    }
+    config
+}
+
+fn parse_positive(flag: &str, value: &str) -> usize {
+    match value.parse::<usize>() {
+        Ok(x) if x > 0 => x,
+        _ => panic!("Error: {} expects a positive integer, got {}", flag, value),
+    }
+}
+
+fn build_command(config: &BenchConfig, workload: &str) -> Vec<u8> {
+    let key = format!("key:{:012}", random_u64() % config.keyspace);
+    let value = "x".repeat(config.data_size);
+    let args: Vec<&str> = match workload {
+        "set" => vec!["SET", &key, &value],
+        "get" => vec!["GET", &key],
+        "incr" => vec!["INCR", "counter:__bench__"],
+        "lpush" => vec!["LPUSH", "mylist:__bench__", &value],
+        other => panic!("Unknown workload {}", other),
+    };
+    serialize_resp_data(RespType::Array(
+        args.into_iter()
+            .map(|x| RespType::BulkString(Some(Bytes::from(x.to_string()))))
+            .collect(),
+    ))
+}
+
+async fn run_workload(config: Arc<BenchConfig>, workload: &str) {
+    let remaining = Arc::new(AtomicUsize::new(config.requests));
+    let start = Instant::now();
+    let mut tasks = Vec::new();
+    for _ in 0..config.connections {
+        let config = Arc::clone(&config);
+        let remaining = Arc::clone(&remaining);
+        let workload = workload.to_string();
+        tasks.push(tokio::spawn(async move {
+            run_connection(config, &workload, remaining).await
+        }));
+    }
+
+    let mut latencies = Vec::with_capacity(config.requests);
+    let mut errors = 0;
+    for task in tasks {
+        match task.await {
+            Ok(result) => {
+                latencies.extend(result.latencies);
+                errors += result.errors;
+            }
+            Err(e) => println!("Benchmark connection failed: {}", e),
+        }
+    }
+    let elapsed = start.elapsed();
+    report(&config, workload, latencies, errors, elapsed);
+}
+
+// Sends batches of `pipeline` commands until the shared request budget runs out. Every command
+// in a batch is given the latency of the whole batch, as redis-benchmark does.
+async fn run_connection(
+    config: Arc<BenchConfig>,
+    workload: &str,
+    remaining: Arc<AtomicUsize>,
+) -> WorkloadResult {
+    let mut result = WorkloadResult {
+        latencies: Vec::new(),
+        errors: 0,
+    };
+    let address = format!("{}:{}", config.host, config.port);
+    let mut stream = match TcpStream::connect(&address).await {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Failed to connect to {}: {}", address, e);
+            return result;
+        }
+    };
+    let mut buffer = BytesMut::with_capacity(4096);
+    let mut decoder = FrameDecoder::new();
+    loop {
+        let batch = claim_batch(&remaining, config.pipeline);
+        if batch == 0 {
+            return result;
+        }
+        let mut request = Vec::new();
+        for _ in 0..batch {
+            request.extend(build_command(&config, workload));
+        }
+
+        let sent_at = Instant::now();
+        if stream.write_all(&request).await.is_err() {
+            result.errors += batch;
+            return result;
+        }
+        let mut replies = 0;
+        let mut failed = 0;
+        while replies < batch {
+            match decoder.decode(&mut buffer) {
+                Ok(Some((reply, _))) => {
+                    if let RespType::Error(_) = reply {
+                        failed += 1;
+                    }
+                    replies += 1;
+                }
+                Err(e) => {
+                    println!("Server sent a malformed reply: {}", e);
+                    result.errors += failed + batch - replies;
+                    return result;
+                }
+                Ok(None) => match stream.read_buf(&mut buffer).await {
+                    Ok(0) | Err(_) => {
+                        // The server closed the connection, count whatever is left as failed
+                        result.errors += failed + batch - replies;
+                        return result;
+                    }
+                    Ok(_) => (),
+                },
+            }
+        }
+        let latency = sent_at.elapsed();
+        result.errors += failed;
+        result
+            .latencies
+            .extend(std::iter::repeat_n(latency, batch - failed));
+    }
+}
+
+fn claim_batch(remaining: &AtomicUsize, pipeline: usize) -> usize {
+    let mut current = remaining.load(Ordering::Relaxed);
+    loop {
+        let batch = current.min(pipeline);
+        if batch == 0 {
+            return 0;
+        }
+        match remaining.compare_exchange_weak(
+            current,
+            current - batch,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => return batch,
+            Err(x) => current = x,
+        }
+    }
+}
+
+fn report(
+    config: &BenchConfig,
+    workload: &str,
+    mut latencies: Vec<Duration>,
+    errors: usize,
+    elapsed: Duration,
+) {
+    latencies.sort_unstable();
+    println!("====== {} ======", workload.to_uppercase());
+    println!(
+        "  {} requests completed in {:.2} seconds",
+        latencies.len(),
+        elapsed.as_secs_f64()
+    );
+    println!(
+        "  {} parallel clients, {} byte payload, pipeline {}",
+        config.connections, config.data_size, config.pipeline
+    );
+    if errors > 0 {
+        println!("  {} requests failed", errors);
+    }
+    if latencies.is_empty() {
+        println!();
+        return;
+    }
+    println!(
+        "  throughput: {:.2} requests per second",
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    for percentile in [50.0, 95.0, 99.0, 99.9, 100.0] {
+        println!(
+            "  p{:<5} {:.3} ms",
+            percentile,
+            percentile_of(&latencies, percentile).as_secs_f64() * 1000.0
+        );
+    }
+    println!();
+}
+
+// `latencies` must be sorted and non-empty
+fn percentile_of(latencies: &[Duration], percentile: f64) -> Duration {
+    let rank = ((percentile / 100.0) * latencies.len() as f64).ceil() as usize;
+    latencies[rank.clamp(1, latencies.len()) - 1]
+}
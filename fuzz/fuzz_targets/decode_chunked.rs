@@ -0,0 +1,30 @@
+#![no_main]
+
+use bytes::{Bytes, BytesMut};
+use libfuzzer_sys::fuzz_target;
+use redis_starter_rust::resp::resp_deserializer::{parse_frames, FrameDecoder};
+use redis_starter_rust::resp::resp_serializer::serialize_resp_data;
+
+// The first byte picks a chunk size, and the rest is fed to the decoder in chunks of that size.
+// Every frame it accepts must survive a round trip through the serializer.
+fuzz_target!(|data: &[u8]| {
+    let (chunk_size, data) = match data.split_first() {
+        Some((x, rest)) => (*x as usize % 64 + 1, rest),
+        None => return,
+    };
+    let mut decoder = FrameDecoder::new();
+    let mut buffer = BytesMut::new();
+    for chunk in data.chunks(chunk_size) {
+        buffer.extend_from_slice(chunk);
+        loop {
+            match decoder.decode(&mut buffer) {
+                Ok(Some((frame, _))) => {
+                    let serialized = Bytes::from(serialize_resp_data(frame.clone()));
+                    assert_eq!(parse_frames(serialized), Ok(vec![frame]));
+                }
+                Ok(None) => break,
+                Err(_) => return,
+            }
+        }
+    }
+});
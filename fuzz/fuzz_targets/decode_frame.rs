@@ -0,0 +1,12 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use redis_starter_rust::resp::resp_deserializer::FrameDecoder;
+
+// Decodes arbitrary input in one go, which must never panic
+fuzz_target!(|data: &[u8]| {
+    let mut decoder = FrameDecoder::new();
+    let mut buffer = BytesMut::from(data);
+    while let Ok(Some(_)) = decoder.decode(&mut buffer) {}
+});
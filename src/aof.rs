@@ -0,0 +1,287 @@
+use crate::rdb;
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+pub mod writer;
+
+const RDB_MAGIC: &[u8] = b"REDIS";
+// No count or length in a well formed AOF comes anywhere near this many digits
+const MAX_HEADER_LENGTH: usize = 32;
+
+// What checking an AOF found, in the manner of redis-check-aof
+#[derive(Debug, PartialEq)]
+pub struct AofCheck {
+    // Commands read after the RDB preamble, if there is one
+    pub commands: usize,
+    // The length of the longest prefix made of whole commands and transactions, which is what
+    // fixing the file truncates it to
+    pub valid_up_to: usize,
+    // The offset of the first corruption and what's wrong there
+    pub error: Option<(usize, String)>,
+    // A corrupt RDB preamble can't be fixed by truncating, as nothing would be left
+    pub preamble_corrupt: bool,
+}
+
+// Validates an AOF: an optional RDB preamble followed by commands as RESP arrays of bulk strings,
+// with every MULTI closed by an EXEC
+pub fn check(data: &[u8]) -> AofCheck {
+    let mut result = AofCheck {
+        commands: 0,
+        valid_up_to: 0,
+        error: None,
+        preamble_corrupt: false,
+    };
+    let mut index = 0;
+    if data.starts_with(RDB_MAGIC) {
+        match rdb::rdb_length(data) {
+            Ok(length) => index = length,
+            Err((offset, message)) => {
+                result.error = Some((offset, format!("RDB preamble is corrupt: {}", message)));
+                result.preamble_corrupt = true;
+                return result;
+            }
+        }
+    }
+    result.valid_up_to = index;
+
+    // Where the open transaction's MULTI starts, as a transaction is only valid once it's closed
+    let mut multi_start = None;
+    while index < data.len() {
+        let start = index;
+        let name = match read_command(data, &mut index) {
+            Ok(x) => x,
+            Err(error) => {
+                result.error = Some(error);
+                return result;
+            }
+        };
+        result.commands += 1;
+        match (name.to_ascii_lowercase().as_slice(), multi_start) {
+            (b"multi", Some(_)) => {
+                result.error = Some((start, String::from("unexpected MULTI")));
+                return result;
+            }
+            (b"multi", None) => multi_start = Some(start),
+            (b"exec", None) => {
+                result.error = Some((start, String::from("unexpected EXEC")));
+                return result;
+            }
+            (b"exec", Some(_)) => multi_start = None,
+            _ => (),
+        }
+        if multi_start.is_none() {
+            result.valid_up_to = index;
+        }
+    }
+    if let Some(start) = multi_start {
+        result.error = Some((
+            start,
+            String::from("reached the end of the file inside MULTI"),
+        ));
+    }
+    result
+}
+
+// Reads one command, returning its name and moving `index` past it
+fn read_command<'a>(data: &'a [u8], index: &mut usize) -> Result<&'a [u8], (usize, String)> {
+    let count = read_header(data, index, b'*')?;
+    if count == 0 {
+        return Err((*index, String::from("empty command")));
+    }
+    let mut name = &data[0..0];
+    for argument in 0..count {
+        let length = read_header(data, index, b'$')?;
+        let end = match index.checked_add(length) {
+            Some(x) if x.saturating_add(2) <= data.len() => x,
+            _ => return Err((data.len(), String::from("unexpected end of file"))),
+        };
+        if &data[end..end + 2] != b"\r\n" {
+            return Err((end, String::from("expected \\r\\n after a bulk string")));
+        }
+        if argument == 0 {
+            name = &data[*index..end];
+        }
+        *index = end + 2;
+    }
+    Ok(name)
+}
+
+// Reads a "*<count>\r\n" or "$<length>\r\n" line
+fn read_header(data: &[u8], index: &mut usize, prefix: u8) -> Result<usize, (usize, String)> {
+    let start = *index;
+    match data.get(start) {
+        Some(x) if *x == prefix => (),
+        Some(x) => {
+            let message = format!("expected '{}', got '{}'", prefix as char, x.escape_ascii());
+            return Err((start, message));
+        }
+        None => return Err((start, String::from("unexpected end of file"))),
+    }
+    let line = &data[start + 1..data.len().min(start + 1 + MAX_HEADER_LENGTH)];
+    let end = match line.windows(2).position(|x| x == b"\r\n") {
+        Some(x) => x,
+        None if line.len() < MAX_HEADER_LENGTH => {
+            return Err((data.len(), String::from("unexpected end of file")))
+        }
+        None => return Err((start, String::from("header line too long"))),
+    };
+    let value = std::str::from_utf8(&line[..end])
+        .ok()
+        .and_then(|x| x.parse::<usize>().ok());
+    match value {
+        Some(x) => {
+            *index = start + 1 + end + 2;
+            Ok(x)
+        }
+        None => Err((start, String::from("invalid count or length"))),
+    }
+}
+
+// The --check-aof mode. Reports on the file and, with `fix`, offers to truncate it to its valid
+// part. Returns the exit code.
+pub fn run_check(path: &Path, fix: bool) -> i32 {
+    let data = match fs::read(path) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Cannot open file {}: {}", path.display(), e);
+            return 1;
+        }
+    };
+    let result = check(&data);
+    let line = data[..result.valid_up_to]
+        .iter()
+        .filter(|x| **x == b'\n')
+        .count()
+        + 1;
+    println!(
+        "AOF analyzed: filename={}, size={}, ok_up_to={}, ok_up_to_line={}, diff={}",
+        path.display(),
+        data.len(),
+        result.valid_up_to,
+        line,
+        data.len() - result.valid_up_to
+    );
+    let (offset, message) = match result.error {
+        Some(x) => x,
+        None => {
+            println!(
+                "AOF {} is valid ({} commands)",
+                path.display(),
+                result.commands
+            );
+            return 0;
+        }
+    };
+    println!("Corruption at offset {}: {}", offset, message);
+    if result.preamble_corrupt {
+        println!(
+            "RDB preamble of AOF {} is not sane, aborting",
+            path.display()
+        );
+        return 1;
+    }
+    if !fix {
+        println!(
+            "AOF {} is not valid. Use the --fix option to try fixing it.",
+            path.display()
+        );
+        return 1;
+    }
+
+    print!(
+        "This will shrink the AOF {} from {} bytes, with {} bytes, to {} bytes\nContinue? [y/N]: ",
+        path.display(),
+        data.len(),
+        data.len() - result.valid_up_to,
+        result.valid_up_to
+    );
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    let _ = io::stdin().lock().read_line(&mut answer);
+    if !answer.trim().eq_ignore_ascii_case("y") {
+        println!("Aborting...");
+        return 1;
+    }
+    let truncated = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_len(result.valid_up_to as u64));
+    match truncated {
+        Ok(()) => {
+            println!("Successfully truncated AOF {}", path.display());
+            0
+        }
+        Err(e) => {
+            println!("Failed to truncate AOF {}: {}", path.display(), e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SET: &[u8] = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n";
+    const MULTI: &[u8] = b"*1\r\n$5\r\nMULTI\r\n";
+    const EXEC: &[u8] = b"*1\r\n$4\r\nEXEC\r\n";
+
+    #[test]
+    fn whole_commands_and_transactions_are_valid() {
+        let aof = [SET, MULTI, SET, EXEC, SET].concat();
+        let result = check(&aof);
+        assert_eq!(result.error, None);
+        assert_eq!(result.commands, 5);
+        assert_eq!(result.valid_up_to, aof.len());
+    }
+
+    #[test]
+    fn corruption_is_located_and_the_tail_after_the_last_good_command_cut() {
+        // A command cut short, as by a crash mid-write
+        let aof = [SET, &SET[..10]].concat();
+        let result = check(&aof);
+        assert_eq!(result.valid_up_to, SET.len());
+        assert_eq!(
+            result.error,
+            Some((aof.len(), String::from("unexpected end of file")))
+        );
+
+        // Garbage where a command should start
+        let aof = [SET, b"garbage", SET].concat();
+        let result = check(&aof);
+        assert_eq!(result.valid_up_to, SET.len());
+        assert_eq!(result.error.unwrap().0, SET.len());
+
+        // A bulk string that's longer than it claims
+        let aof = [SET, b"*1\r\n$4\r\nPINGG\r\n"].concat();
+        assert_eq!(check(&aof).error.unwrap().0, SET.len() + 12);
+
+        // A transaction that was never closed is dropped as a whole
+        let aof = [SET, MULTI, SET].concat();
+        let result = check(&aof);
+        assert_eq!(result.valid_up_to, SET.len());
+        assert_eq!(result.error.unwrap().0, SET.len());
+    }
+
+    #[test]
+    fn commands_are_read_after_an_rdb_preamble() {
+        let mut rdb = b"REDIS0011".to_vec();
+        // An aux field, db 0 with one key, then EOF and the checksum
+        rdb.extend_from_slice(b"\xfa\x09redis-ver\x057.2.0");
+        rdb.extend_from_slice(b"\xfe\x00\xfb\x01\x00\x00\x03foo\x03bar");
+        rdb.push(0xff);
+        rdb.extend_from_slice(&rdb::crc64::crc64(&rdb).to_le_bytes());
+        assert_eq!(rdb::rdb_length(&rdb), Ok(rdb.len()));
+
+        let aof = [&rdb[..], SET].concat();
+        let result = check(&aof);
+        assert_eq!(result.error, None);
+        assert_eq!(result.commands, 1);
+
+        let result = check(&rdb[..rdb.len() - 4]);
+        assert!(result.preamble_corrupt);
+        assert_eq!(result.valid_up_to, 0);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::redis::commands::Command;
+use crate::resp::resp_serializer::serialize_command;
+use crate::warning;
+
+use core::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+const EVERYSEC_PERIOD: Duration = Duration::from_secs(1);
+
+// When appended commands are fsynced: before the command replies, about once a second, or
+// whenever the OS gets to it
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AppendFsync {
+    Always,
+    EverySec,
+    No,
+}
+
+impl AppendFsync {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "always" => Some(AppendFsync::Always),
+            "everysec" => Some(AppendFsync::EverySec),
+            "no" => Some(AppendFsync::No),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AppendFsync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppendFsync::Always => write!(f, "always"),
+            AppendFsync::EverySec => write!(f, "everysec"),
+            AppendFsync::No => write!(f, "no"),
+        }
+    }
+}
+
+enum Job {
+    // Data to append, and who to tell once it's been fsynced under appendfsync always
+    Append(Vec<u8>, Option<oneshot::Sender<()>>),
+    // From here on appended data is also kept for the rewritten file
+    StartRewrite,
+    // The rewritten file, which gets everything kept since the rewrite started before it
+    // replaces the AOF
+    FinishRewrite(PathBuf, oneshot::Sender<io::Result<()>>),
+    AbortRewrite,
+    SetFsync(AppendFsync),
+    // Fsyncs whatever was appended before it, whatever the policy
+    Flush(oneshot::Sender<io::Result<()>>),
+}
+
+struct Feed {
+    jobs: Sender<Job>,
+    // The database the file last selected, None until it selects one
+    selected_db: Option<usize>,
+    fsync: AppendFsync,
+}
+
+// Appends writes to the AOF. The file is written and fsynced on a thread of its own, so that
+// commands only wait on the disk under appendfsync always.
+pub struct AofWriter {
+    // Held while sending, so that a SELECT and the command it's for are appended together
+    feed: Mutex<Feed>,
+}
+
+impl AofWriter {
+    pub fn open(path: PathBuf, fsync: AppendFsync) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let (jobs, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name(String::from("aof-writer"))
+            .spawn(move || write_jobs(file, path, fsync, receiver))?;
+        Ok(AofWriter {
+            feed: Mutex::new(Feed {
+                jobs,
+                selected_db: None,
+                fsync,
+            }),
+        })
+    }
+
+    // Appends a write to database `db`, selecting it first if the file doesn't have it selected
+    pub async fn append(&self, db: usize, command: &Command) {
+        let mut data = Vec::new();
+        let acked = {
+            let mut feed = self.feed.lock().unwrap();
+            let (ack, acked) = match feed.fsync {
+                AppendFsync::Always => {
+                    let (ack, acked) = oneshot::channel();
+                    (Some(ack), Some(acked))
+                }
+                _ => (None, None),
+            };
+            if feed.selected_db != Some(db) {
+                data = serialize_command(&Command::Select(db.to_string()));
+                feed.selected_db = Some(db);
+            }
+            data.extend_from_slice(&serialize_command(command));
+            let _ = feed.jobs.send(Job::Append(data, ack));
+            acked
+        };
+        if let Some(acked) = acked {
+            let _ = acked.await;
+        }
+    }
+
+    // The rewritten file starts with a snapshot taken right after this, and goes on with what's
+    // appended from here on
+    pub fn start_rewrite(&self) {
+        let mut feed = self.feed.lock().unwrap();
+        // A snapshot doesn't select any database
+        feed.selected_db = None;
+        let _ = feed.jobs.send(Job::StartRewrite);
+    }
+
+    // Moves the file at `path`, holding the snapshot, into place as the new AOF
+    pub async fn finish_rewrite(&self, path: PathBuf) -> io::Result<()> {
+        let (done, finished) = oneshot::channel();
+        let _ = self
+            .feed
+            .lock()
+            .unwrap()
+            .jobs
+            .send(Job::FinishRewrite(path, done));
+        finished
+            .await
+            .unwrap_or_else(|_| Err(io::Error::other("the AOF writer has stopped")))
+    }
+
+    pub fn abort_rewrite(&self) {
+        let _ = self.feed.lock().unwrap().jobs.send(Job::AbortRewrite);
+    }
+
+    // Waits for everything appended so far to be written and fsynced, as before shutting down
+    pub async fn flush(&self) -> io::Result<()> {
+        let (done, flushed) = oneshot::channel();
+        let _ = self.feed.lock().unwrap().jobs.send(Job::Flush(done));
+        flushed
+            .await
+            .unwrap_or_else(|_| Err(io::Error::other("the AOF writer has stopped")))
+    }
+
+    // Switches to another fsync policy, as CONFIG SET appendfsync does
+    pub fn set_fsync(&self, fsync: AppendFsync) {
+        let mut feed = self.feed.lock().unwrap();
+        feed.fsync = fsync;
+        let _ = feed.jobs.send(Job::SetFsync(fsync));
+    }
+}
+
+// Runs until the AofWriter is dropped, fsyncing whatever is left before it stops
+fn write_jobs(mut file: File, path: PathBuf, mut fsync: AppendFsync, jobs: Receiver<Job>) {
+    let mut rewrite_buffer: Option<Vec<u8>> = None;
+    let mut last_fsync = Instant::now();
+    let mut unsynced = false;
+    loop {
+        match jobs.recv_timeout(EVERYSEC_PERIOD) {
+            Ok(Job::Append(data, ack)) => {
+                if let Err(e) = file.write_all(&data) {
+                    warning!("Error writing to the AOF: {}", e);
+                }
+                if let Some(buffer) = &mut rewrite_buffer {
+                    buffer.extend_from_slice(&data);
+                }
+                unsynced = true;
+                if fsync == AppendFsync::Always {
+                    sync(&file);
+                    unsynced = false;
+                }
+                if let Some(ack) = ack {
+                    let _ = ack.send(());
+                }
+            }
+            Ok(Job::StartRewrite) => rewrite_buffer = Some(Vec::new()),
+            Ok(Job::AbortRewrite) => rewrite_buffer = None,
+            Ok(Job::SetFsync(x)) => fsync = x,
+            Ok(Job::Flush(done)) => {
+                let result = file.sync_data();
+                if result.is_ok() {
+                    unsynced = false;
+                }
+                let _ = done.send(result);
+            }
+            Ok(Job::FinishRewrite(rewritten, done)) => {
+                let buffer = rewrite_buffer.take().unwrap_or_default();
+                let result = replace_file(&rewritten, &path, &buffer);
+                let _ = done.send(result.map(|x| file = x));
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if fsync == AppendFsync::EverySec && unsynced && last_fsync.elapsed() >= EVERYSEC_PERIOD {
+            sync(&file);
+            last_fsync = Instant::now();
+            unsynced = false;
+        }
+    }
+    if unsynced && fsync != AppendFsync::No {
+        sync(&file);
+    }
+}
+
+// Appends `buffer` to the file at `rewritten` and renames it over `path`, returning it open for
+// appending
+fn replace_file(rewritten: &Path, path: &Path, buffer: &[u8]) -> io::Result<File> {
+    let mut file = OpenOptions::new().append(true).open(rewritten)?;
+    file.write_all(buffer)?;
+    file.sync_all()?;
+    fs::rename(rewritten, path)?;
+    Ok(file)
+}
+
+fn sync(file: &File) {
+    if let Err(e) = file.sync_data() {
+        warning!("Error fsyncing the AOF: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn writes_since_a_rewrite_started_follow_its_snapshot() {
+        let dir = std::env::temp_dir().join(format!("aof-writer-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("appendonly.aof");
+        let writer = AofWriter::open(path.clone(), AppendFsync::Always).unwrap();
+        writer.append(0, &Command::Incr(String::from("a"))).await;
+        writer.append(0, &Command::Incr(String::from("b"))).await;
+        let select = serialize_command(&Command::Select(String::from("0")));
+        let incr = |key: &str| serialize_command(&Command::Incr(key.to_string()));
+        assert_eq!(
+            fs::read(&path).unwrap(),
+            [select.clone(), incr("a"), incr("b")].concat()
+        );
+
+        writer.start_rewrite();
+        writer.append(0, &Command::Incr(String::from("c"))).await;
+        let rewritten = dir.join("rewritten.aof");
+        fs::write(&rewritten, b"snapshot").unwrap();
+        writer.finish_rewrite(rewritten.clone()).await.unwrap();
+        writer.append(0, &Command::Incr(String::from("d"))).await;
+        assert_eq!(
+            fs::read(&path).unwrap(),
+            [b"snapshot".to_vec(), select, incr("c"), incr("d")].concat()
+        );
+        assert!(!rewritten.exists());
+        drop(writer);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+use crate::redis::commands::args_to_command;
+use crate::redis::dispatch::Dispatcher;
+use crate::redis::state::ClientContext;
+use crate::resp::resp_deserializer::parse_frames;
+use crate::resp::RespType;
+
+use bytes::Bytes;
+
+// Runs commands against a server in the same process, skipping the network and RESP parsing.
+// Each client behaves like a separate connection. Obtained from Server::client().
+pub struct Client {
+    dispatcher: Dispatcher,
+    context: ClientContext,
+}
+
+impl Client {
+    pub(crate) fn new(dispatcher: Dispatcher) -> Self {
+        let context = ClientContext::new(dispatcher.server().stats.next_client_id());
+        Self {
+            dispatcher,
+            context,
+        }
+    }
+
+    // A separate client of the same server, like opening a second connection
+    pub fn another(&self) -> Self {
+        Client::new(self.dispatcher.clone())
+    }
+
+    // Runs a command given as its name followed by its arguments, e.g. ["SET", "key", "value"].
+    // Returns None for commands the server doesn't reply to, such as a successful SHUTDOWN.
+    pub async fn command<A: AsRef<[u8]>>(&mut self, args: &[A]) -> Option<RespType> {
+        let mut args: Vec<RespType> = args
+            .iter()
+            .map(|x| RespType::BulkString(Some(Bytes::copy_from_slice(x.as_ref()))))
+            .collect();
+        if args.is_empty() {
+            panic!("Expected a command name");
+        }
+        let name = match args.remove(0) {
+            RespType::BulkString(Some(x)) => String::from_utf8_lossy(&x).into_owned(),
+            _ => unreachable!(),
+        };
+        let reply = match args_to_command(&name, args) {
+            Ok(command) => self.dispatcher.dispatch(command, &mut self.context).await,
+            Err(e) => self.dispatcher.reject(&e, &mut self.context),
+        };
+        let reply = reply.into_vec();
+        parse_frames(Bytes::from(reply))
+            .expect("Server sent a malformed reply")
+            .pop()
+    }
+
+    // Waits for the next message published to a channel or pattern the client subscribed to
+    pub async fn message(&mut self) -> RespType {
+        let message = self.context.subscriptions.next_message().await;
+        parse_frames(Bytes::from(message.serialize(self.context.protocol)))
+            .expect("Server sent a malformed message")
+            .remove(0)
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.dispatcher
+            .server()
+            .pubsub
+            .unsubscribe_all(self.context.id, &mut self.context.subscriptions);
+    }
+}
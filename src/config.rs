@@ -1,86 +1,1318 @@
+use crate::aof::writer::AppendFsync;
+use crate::log::LogLevel;
+use crate::redis::cron::{MAX_HZ, MIN_HZ};
+use crate::redis::eviction::EvictionPolicy;
+use crate::redis::glob;
+use crate::redis::keyspace::KeyspaceMode;
+use crate::redis::notify::NotifyFlags;
+use crate::redis::output::{ClientClass, OutputBufferLimit, OutputBufferLimits};
+use crate::redis::sorted_set::ListpackLimits;
 use crate::redis::RedisState;
-use std::{env, path::PathBuf};
+use crate::resp::resp_deserializer::{
+    FrameLimits, DEFAULT_MAX_BULK_LENGTH, DEFAULT_MAX_MULTIBULK_LENGTH,
+};
+use core::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::{env, fs, path::PathBuf, thread};
 
+#[derive(Clone)]
 pub struct Config {
     pub port: String,
     pub role: RedisState,
-    pub master_replid: Option<String>,
-    pub master_repl_offset: Option<String>,
     pub master_port: Option<String>,
     pub master_host: Option<String>,
     pub rdb_dir: Option<PathBuf>,
     pub rdb_filename: Option<PathBuf>,
+    // Save points as (seconds, changes): the dataset is saved in the background once it's had
+    // at least that many changes in that many seconds. None means never.
+    pub save: Vec<(u64, u64)>,
+    // Whether writes are logged to the AOF, which is then what the dataset is loaded from
+    pub appendonly: bool,
+    pub appendfsync: AppendFsync,
+    // In the same directory as the RDB file
+    pub appendfilename: PathBuf,
+    // How many logical databases there are, numbered from 0
+    pub databases: usize,
+    // Where to also accept connections over a unix socket, if anywhere
+    pub unixsocket: Option<PathBuf>,
+    // The unix socket's permissions, like 0o770, or 0 to leave them to the umask
+    pub unixsocketperm: u32,
+    pub keyspace_mode: KeyspaceMode,
+    // Worker threads in thread-per-core mode
+    pub threads: usize,
+    pub maxmemory: usize,
+    pub maxmemory_policy: EvictionPolicy,
+    pub maxmemory_samples: usize,
+    pub lfu_log_factor: u32,
+    pub lfu_decay_time: u32,
+    pub hz: u64,
+    // Seconds any step of the replication handshake may take
+    pub repl_timeout: u64,
+    // Bytes of the replication stream a master keeps for replicas that reconnect, see Backlog
+    pub repl_backlog_size: usize,
+    // Whether a replica keeps answering from its possibly stale data while its master link is down
+    pub replica_serve_stale_data: bool,
+    pub proto_max_bulk_len: usize,
+    pub proto_max_multibulk_len: usize,
+    pub client_output_buffer_limits: OutputBufferLimits,
+    // Whether each kind of deletion frees big values on the background thread, see lazyfree
+    pub lazyfree_lazy_expire: bool,
+    pub lazyfree_lazy_eviction: bool,
+    pub lazyfree_lazy_user_del: bool,
+    pub lazyfree_lazy_user_flush: bool,
+    // Connections allowed open from any one IP, and accepted per second overall. 0 is unlimited.
+    pub max_connections_per_ip: usize,
+    pub max_accept_rate: usize,
+    // Connections open at once, past which new ones are turned away
+    pub maxclients: usize,
+    // Seconds a client may sit idle before it's disconnected, 0 for never. Masters, replicas,
+    // subscribers and monitors are never disconnected for it.
+    pub timeout: u64,
+    // Seconds of silence after which TCP keepalive probes an accepted connection, 0 for off
+    pub tcp_keepalive: u64,
+    // Whether every command's latency is recorded, and the percentiles INFO latencystats shows
+    pub latency_tracking: bool,
+    pub latency_tracking_info_percentiles: Vec<f64>,
+    // Entries kept in the ACL LOG
+    pub acllog_max_len: usize,
+    // Commands taking at least this many microseconds are kept in the slow log, which keeps the
+    // last slowlog_max_len of them. Negative turns it off.
+    pub slowlog_log_slower_than: i64,
+    pub slowlog_max_len: usize,
+    // Which keyspace events are published over pub/sub, off by default
+    pub notify_keyspace_events: NotifyFlags,
+    // The password clients have to AUTH with before anything else, if any
+    pub requirepass: Option<String>,
+    // The password a replica authenticates to its master with, if it asks for one
+    pub masterauth: Option<String>,
+    pub zset_max_listpack_entries: usize,
+    pub zset_max_listpack_value: usize,
+    pub loglevel: LogLevel,
+    // Who may run DEBUG, which can crash the server, so nobody unless it's turned on
+    pub enable_debug_command: ProtectedAccess,
+    // The file the server was started with, if any, see from_args
+    pub config_file: Option<PathBuf>,
 }
 
-enum ConfigParseError {
-    NoArgFound,
-}
-
-impl Config {
-    pub fn parse() -> Self {
-        let args: Vec<String> = env::args().collect();
-        let mut config = Config {
+impl Default for Config {
+    fn default() -> Self {
+        Config {
             port: String::from("6379"),
             role: RedisState::Master,
-            master_replid: None,
-            master_repl_offset: None,
             master_port: None,
             master_host: None,
             rdb_dir: None,
             rdb_filename: None,
+            save: vec![(3600, 1), (300, 100), (60, 10000)],
+            appendonly: false,
+            appendfsync: AppendFsync::EverySec,
+            appendfilename: PathBuf::from("appendonly.aof"),
+            databases: 16,
+            unixsocket: None,
+            unixsocketperm: 0,
+            keyspace_mode: KeyspaceMode::Shared,
+            threads: thread::available_parallelism().map_or(1, |x| x.get()),
+            maxmemory: 0,
+            maxmemory_policy: EvictionPolicy::NoEviction,
+            maxmemory_samples: 5,
+            lfu_log_factor: 10,
+            lfu_decay_time: 1,
+            hz: 10,
+            repl_timeout: 60,
+            repl_backlog_size: 1024 * 1024,
+            replica_serve_stale_data: true,
+            proto_max_bulk_len: DEFAULT_MAX_BULK_LENGTH,
+            proto_max_multibulk_len: DEFAULT_MAX_MULTIBULK_LENGTH,
+            client_output_buffer_limits: OutputBufferLimits::default(),
+            lazyfree_lazy_expire: false,
+            lazyfree_lazy_eviction: false,
+            lazyfree_lazy_user_del: false,
+            lazyfree_lazy_user_flush: false,
+            max_connections_per_ip: 0,
+            max_accept_rate: 0,
+            maxclients: 10000,
+            timeout: 0,
+            tcp_keepalive: 300,
+            latency_tracking: true,
+            latency_tracking_info_percentiles: vec![50.0, 99.0, 99.9],
+            acllog_max_len: 128,
+            slowlog_log_slower_than: 10000,
+            slowlog_max_len: 128,
+            notify_keyspace_events: NotifyFlags::default(),
+            requirepass: None,
+            masterauth: None,
+            zset_max_listpack_entries: ListpackLimits::default().max_entries,
+            zset_max_listpack_value: ListpackLimits::default().max_value,
+            loglevel: LogLevel::Notice,
+            enable_debug_command: ProtectedAccess::No,
+            config_file: None,
+        }
+    }
+}
+
+const MIN_PROTO_MAX_BULK_LEN: usize = 1024 * 1024;
+
+// Every parameter CONFIG GET reports, in the order it reports them
+const PARAMETERS: &[&str] = &[
+    "port",
+    "replicaof",
+    "dir",
+    "dbfilename",
+    "save",
+    "appendonly",
+    "appendfsync",
+    "appendfilename",
+    "databases",
+    "unixsocket",
+    "unixsocketperm",
+    "keyspace-mode",
+    "maxmemory",
+    "maxmemory-policy",
+    "maxmemory-samples",
+    "lfu-log-factor",
+    "lfu-decay-time",
+    "hz",
+    "repl-timeout",
+    "repl-backlog-size",
+    "replica-serve-stale-data",
+    "proto-max-bulk-len",
+    "proto-max-multibulk-len",
+    "lazyfree-lazy-expire",
+    "lazyfree-lazy-eviction",
+    "lazyfree-lazy-user-del",
+    "lazyfree-lazy-user-flush",
+    "max-connections-per-ip",
+    "max-accept-rate",
+    "maxclients",
+    "timeout",
+    "tcp-keepalive",
+    "latency-tracking",
+    "latency-tracking-info-percentiles",
+    "acllog-max-len",
+    "slowlog-log-slower-than",
+    "slowlog-max-len",
+    "notify-keyspace-events",
+    "requirepass",
+    "masterauth",
+    "zset-max-listpack-entries",
+    "zset-max-ziplist-entries",
+    "zset-max-listpack-value",
+    "zset-max-ziplist-value",
+    "loglevel",
+    "enable-debug-command",
+];
+
+// The parameters CONFIG SET can change, which everything reads afresh each time it needs them.
+// The rest are only read as the server starts.
+const MUTABLE_PARAMETERS: &[&str] = &[
+    "dir",
+    "dbfilename",
+    "save",
+    "appendfsync",
+    "maxmemory",
+    "maxmemory-policy",
+    "maxmemory-samples",
+    "lfu-log-factor",
+    "lfu-decay-time",
+    "repl-backlog-size",
+    "replica-serve-stale-data",
+    "lazyfree-lazy-expire",
+    "lazyfree-lazy-eviction",
+    "lazyfree-lazy-user-del",
+    "lazyfree-lazy-user-flush",
+    "timeout",
+    "tcp-keepalive",
+    "latency-tracking",
+    "latency-tracking-info-percentiles",
+    "slowlog-log-slower-than",
+    "slowlog-max-len",
+    "notify-keyspace-events",
+    "requirepass",
+    "masterauth",
+    "zset-max-listpack-entries",
+    "zset-max-ziplist-entries",
+    "zset-max-listpack-value",
+    "zset-max-ziplist-value",
+    "loglevel",
+];
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigSetError {
+    Unknown,
+    Immutable,
+    // Why the value won't do
+    Invalid(String),
+}
+
+// The configuration the whole server shares. CONFIG SET replaces it with a changed copy, so
+// whoever holds on to what current returned keeps seeing one consistent version of it.
+pub struct LiveConfig {
+    current: RwLock<Arc<Config>>,
+}
+
+impl LiveConfig {
+    pub fn new(config: Config) -> Self {
+        LiveConfig {
+            current: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    // For changes that can't fail
+    pub fn replace(&self, change: impl FnOnce(&mut Config)) {
+        let mut current = self.current.write().unwrap();
+        let mut config = Config::clone(&current);
+        change(&mut config);
+        *current = Arc::new(config);
+    }
+
+    // Runs `change` against a copy of the configuration, which replaces it unless it fails
+    pub fn update<E>(&self, change: impl FnOnce(&mut Config) -> Result<(), E>) -> Result<(), E> {
+        let mut current = self.current.write().unwrap();
+        let mut config = Config::clone(&current);
+        change(&mut config)?;
+        *current = Arc::new(config);
+        Ok(())
+    }
+}
+
+enum ConfigParseError {
+    NoArgFound,
+}
+
+// Who may run a command that's too dangerous to leave open, as enable-debug-command sets it.
+// Local connections are those over the unix socket or loopback, and in-process clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtectedAccess {
+    No,
+    Yes,
+    Local,
+}
+
+impl ProtectedAccess {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "no" => Some(ProtectedAccess::No),
+            "yes" => Some(ProtectedAccess::Yes),
+            "local" => Some(ProtectedAccess::Local),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ProtectedAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ProtectedAccess::No => "no",
+            ProtectedAccess::Yes => "yes",
+            ProtectedAccess::Local => "local",
         };
+        write!(f, "{}", name)
+    }
+}
+
+// A configuration the server can't start with, for main to report
+#[derive(Debug, PartialEq)]
+pub struct ConfigError(pub String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Config error: {}", self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ConfigError {
+    fn missing(flag: &str) -> Self {
+        ConfigError(format!("{} requires a value", flag))
+    }
+
+    fn invalid(flag: &str, value: &str) -> Self {
+        ConfigError(format!("invalid {} value {}", flag, value))
+    }
+}
+
+impl Config {
+    pub fn parse() -> Result<Self, ConfigError> {
+        Self::from_args(env::args().collect())
+    }
+
+    // The configuration from the command line, `args` being the program followed by its
+    // arguments. If the first argument isn't a flag it's a redis.conf-style file, read first so
+    // that flags override what it says.
+    pub fn from_args(mut args: Vec<String>) -> Result<Self, ConfigError> {
+        let mut config = Config::default();
+        // The arguments at indexes 1..file_args_end came from the file, a flag and its value for
+        // each of file_lines
+        let mut file_args_end = 1;
+        let mut file_lines = Vec::new();
+        let path = args.get(1).filter(|x| !x.starts_with('-')).cloned();
+        if let Some(path) = &path {
+            let contents = fs::read_to_string(path).map_err(|e| {
+                ConfigError(format!(
+                    "Fatal error, can't open config file '{}': {}",
+                    path, e
+                ))
+            })?;
+            let (file_args, lines) = config_file_args(&contents).map_err(|x| x.into_error(path))?;
+            file_args_end += file_args.len();
+            file_lines = lines;
+            args.splice(1..2, file_args);
+            let path = PathBuf::from(path);
+            config.config_file = Some(fs::canonicalize(&path).unwrap_or(path));
+        }
         let mut index = 0;
         while index < args.len() {
-            match args[index].as_str() {
-                "--port" => match read_next_arg(&args, &mut index) {
-                    Ok(x) => {
-                        config.port = x;
+            let flag_index = index;
+            let applied = config.apply_flag(&args, &mut index);
+            let in_file = path
+                .as_deref()
+                .filter(|_| (1..file_args_end).contains(&flag_index));
+            let bad_line = |message: String| {
+                let (number, line) = file_lines[(flag_index - 1) / 2].clone();
+                BadLine {
+                    number,
+                    line,
+                    message,
+                }
+            };
+            // Unknown flags are only an error in the file
+            match (applied, in_file) {
+                (Ok(true), _) | (Ok(false), None) => (),
+                (Ok(false), Some(path)) => {
+                    let message = String::from("Bad directive or wrong number of arguments");
+                    return Err(bad_line(message).into_error(path));
+                }
+                (Err(e), Some(path)) => return Err(bad_line(e.0).into_error(path)),
+                (Err(e), None) => return Err(e),
+            }
+            index += 1; // Move to the next argument
+        }
+        Ok(config)
+    }
+
+    // Applies the flag at `index`, moving it past the values the flag took. Returns whether the
+    // flag is one there is.
+    fn apply_flag(&mut self, args: &[String], index: &mut usize) -> Result<bool, ConfigError> {
+        match args[*index].as_str() {
+            "--port" => match read_next_arg(args, index) {
+                Ok(x) if x.parse::<u16>().is_ok() => self.port = x,
+                Ok(x) => return Err(ConfigError::invalid("--port", &x)),
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--port"));
+                }
+            },
+            // Either "<host> <port>" or the two as separate arguments
+            "--replicaof" => {
+                let mut parts: Vec<String> = match read_next_arg(args, index) {
+                    Ok(x) => x.split_whitespace().map(String::from).collect(),
+                    Err(ConfigParseError::NoArgFound) => Vec::new(),
+                };
+                if parts.len() == 1 {
+                    if let Ok(port) = read_next_arg(args, index) {
+                        parts.push(port);
                     }
-                    Err(ConfigParseError::NoArgFound) => {
-                        panic!("Error: --port requires a value");
+                }
+                match parts.as_slice() {
+                    [host, port] if port.parse::<u16>().is_ok() => {
+                        self.set_replica_of(host.clone(), port.clone())
+                    }
+                    [host, port] if host == "no" && port == "one" => self.set_master(),
+                    _ => {
+                        return Err(ConfigError(String::from(
+                            "--replicaof requires a host and a port",
+                        )))
                     }
+                }
+            }
+            "--dir" => match read_next_arg(args, index) {
+                Ok(x) => {
+                    self.rdb_dir = Some(PathBuf::from(x));
+                }
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--dir"));
+                }
+            },
+            "--dbfilename" => match read_next_arg(args, index) {
+                Ok(x) => self.rdb_filename = Some(PathBuf::from(x)),
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--dbfilename"));
+                }
+            },
+            // Pairs of seconds and changes, like "3600 1 300 100", or "" to turn saving off
+            "--save" => match read_next_arg(args, index) {
+                Ok(x) => match parse_save_points(&x) {
+                    Some(points) => self.save = points,
+                    None => return Err(ConfigError::invalid("--save", &x)),
                 },
-                "--replicaof" => match read_next_arg(&args, &mut index) {
-                    Ok(x) => {
-                        let parts: Vec<&str> = x.split(" ").collect();
-                        config.master_host = Some(parts[0].to_string());
-                        config.master_port = Some(parts[1].to_string());
-                        config.role = RedisState::Replica;
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--save"));
+                }
+            },
+            "--appendonly" => match read_next_arg(args, index) {
+                Ok(x) => match parse_yes_no(&x) {
+                    Some(enabled) => self.appendonly = enabled,
+                    None => return Err(ConfigError::invalid("--appendonly", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--appendonly"));
+                }
+            },
+            "--appendfsync" => match read_next_arg(args, index) {
+                Ok(x) => match AppendFsync::parse(&x) {
+                    Some(policy) => self.appendfsync = policy,
+                    None => return Err(ConfigError::invalid("--appendfsync", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--appendfsync"));
+                }
+            },
+            "--appendfilename" => match read_next_arg(args, index) {
+                Ok(x) => self.appendfilename = PathBuf::from(x),
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--appendfilename"));
+                }
+            },
+            "--unixsocket" => match read_next_arg(args, index) {
+                Ok(x) => self.unixsocket = Some(PathBuf::from(x)),
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--unixsocket"));
+                }
+            },
+            // In octal, like chmod takes it
+            "--unixsocketperm" => match read_next_arg(args, index) {
+                Ok(x) => match u32::from_str_radix(&x, 8) {
+                    Ok(mode) if mode <= 0o777 => self.unixsocketperm = mode,
+                    _ => return Err(ConfigError::invalid("--unixsocketperm", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--unixsocketperm"));
+                }
+            },
+            // Connections are plain TCP or a unix socket, there's no TLS listener to serve
+            "--tls-port" => {
+                return Err(ConfigError(String::from(
+                    "--tls-port: TLS isn't supported by this build",
+                )))
+            }
+            "--keyspace-mode" => match read_next_arg(args, index) {
+                Ok(x) => {
+                    self.keyspace_mode = match x.to_lowercase().as_str() {
+                        "shared" => KeyspaceMode::Shared,
+                        "actor" => KeyspaceMode::Actor,
+                        "thread-per-core" => KeyspaceMode::ThreadPerCore,
+                        other => return Err(ConfigError::invalid("--keyspace-mode", other)),
                     }
-                    Err(ConfigParseError::NoArgFound) => {
-                        panic!("Error: --replicaof requires two values");
+                }
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--keyspace-mode"));
+                }
+            },
+            "--threads" => match read_next_arg(args, index) {
+                Ok(x) => match x.parse::<usize>() {
+                    Ok(threads) if threads > 0 => self.threads = threads,
+                    _ => return Err(ConfigError::invalid("--threads", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--threads"));
+                }
+            },
+            "--maxmemory" => match read_next_arg(args, index) {
+                Ok(x) => match parse_memory(&x) {
+                    Some(bytes) => self.maxmemory = bytes,
+                    None => return Err(ConfigError::invalid("--maxmemory", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--maxmemory"));
+                }
+            },
+            "--maxmemory-policy" => match read_next_arg(args, index) {
+                Ok(x) => match EvictionPolicy::parse(&x) {
+                    Some(policy) => self.maxmemory_policy = policy,
+                    None => return Err(ConfigError::invalid("--maxmemory-policy", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--maxmemory-policy"));
+                }
+            },
+            "--maxmemory-samples" => match read_next_arg(args, index) {
+                Ok(x) => match x.parse::<usize>() {
+                    Ok(samples) if samples > 0 => self.maxmemory_samples = samples,
+                    _ => return Err(ConfigError::invalid("--maxmemory-samples", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--maxmemory-samples"));
+                }
+            },
+            "--lfu-log-factor" => match read_next_arg(args, index) {
+                Ok(x) => match x.parse::<u32>() {
+                    Ok(factor) => self.lfu_log_factor = factor,
+                    Err(_) => return Err(ConfigError::invalid("--lfu-log-factor", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--lfu-log-factor"));
+                }
+            },
+            "--lfu-decay-time" => match read_next_arg(args, index) {
+                Ok(x) => match x.parse::<u32>() {
+                    Ok(minutes) => self.lfu_decay_time = minutes,
+                    Err(_) => return Err(ConfigError::invalid("--lfu-decay-time", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--lfu-decay-time"));
+                }
+            },
+            "--databases" => match read_next_arg(args, index) {
+                Ok(x) => match x.parse::<usize>() {
+                    Ok(databases) if databases > 0 => self.databases = databases,
+                    _ => return Err(ConfigError::invalid("--databases", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--databases"));
+                }
+            },
+            "--hz" => match read_next_arg(args, index) {
+                // Out of range values are clamped, as in Redis
+                Ok(x) => match x.parse::<u64>() {
+                    Ok(hz) => self.hz = hz.clamp(MIN_HZ, MAX_HZ),
+                    Err(_) => return Err(ConfigError::invalid("--hz", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--hz"));
+                }
+            },
+            "--repl-timeout" => match read_next_arg(args, index) {
+                Ok(x) => match x.parse::<u64>() {
+                    Ok(seconds) if seconds > 0 => self.repl_timeout = seconds,
+                    _ => return Err(ConfigError::invalid("--repl-timeout", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--repl-timeout"));
+                }
+            },
+            "--repl-backlog-size" => match read_next_arg(args, index) {
+                Ok(x) => match parse_memory(&x) {
+                    Some(bytes) if bytes > 0 => self.repl_backlog_size = bytes,
+                    _ => return Err(ConfigError::invalid("--repl-backlog-size", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--repl-backlog-size"));
+                }
+            },
+            "--replica-serve-stale-data" => match read_next_arg(args, index) {
+                Ok(x) => match parse_yes_no(&x) {
+                    Some(serve) => self.replica_serve_stale_data = serve,
+                    None => return Err(ConfigError::invalid("--replica-serve-stale-data", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--replica-serve-stale-data"));
+                }
+            },
+            "--proto-max-bulk-len" => match read_next_arg(args, index) {
+                // Redis doesn't allow less than 1mb either
+                Ok(x) => match parse_memory(&x) {
+                    Some(bytes) if bytes >= MIN_PROTO_MAX_BULK_LEN => {
+                        self.proto_max_bulk_len = bytes
                     }
+                    _ => return Err(ConfigError::invalid("--proto-max-bulk-len", &x)),
                 },
-                "--dir" => match read_next_arg(&args, &mut index) {
-                    Ok(x) => {
-                        config.rdb_dir = Some(PathBuf::from(x));
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--proto-max-bulk-len"));
+                }
+            },
+            "--proto-max-multibulk-len" => match read_next_arg(args, index) {
+                Ok(x) => match x.parse::<usize>() {
+                    Ok(length) if length > 0 => self.proto_max_multibulk_len = length,
+                    _ => return Err(ConfigError::invalid("--proto-max-multibulk-len", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--proto-max-multibulk-len"));
+                }
+            },
+            // Takes "<class> <hard> <soft> <soft seconds>", and can be given once per class
+            "--client-output-buffer-limit" => match read_next_arg(args, index) {
+                Ok(x) => {
+                    let parts: Vec<&str> = x.split_whitespace().collect();
+                    let (class, limit) = match parts.as_slice() {
+                        [class, hard, soft, seconds] => (
+                            ClientClass::parse(class),
+                            OutputBufferLimit::parse(hard, soft, seconds),
+                        ),
+                        _ => (None, None),
+                    };
+                    match (class, limit) {
+                        (Some(class), Some(limit)) => {
+                            self.client_output_buffer_limits.set(class, limit)
+                        }
+                        _ => return Err(ConfigError::invalid("--client-output-buffer-limit", &x)),
                     }
+                }
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--client-output-buffer-limit"));
+                }
+            },
+            "--lazyfree-lazy-expire"
+            | "--lazyfree-lazy-eviction"
+            | "--lazyfree-lazy-user-del"
+            | "--lazyfree-lazy-user-flush" => {
+                let option = args[*index].clone();
+                let lazy = match read_next_arg(args, index) {
+                    Ok(x) => match parse_yes_no(&x) {
+                        Some(lazy) => lazy,
+                        None => return Err(ConfigError::invalid(&option, &x)),
+                    },
                     Err(ConfigParseError::NoArgFound) => {
-                        panic!("Error: --dir requires a value");
+                        return Err(ConfigError::missing(&option));
                     }
+                };
+                match option.as_str() {
+                    "--lazyfree-lazy-expire" => self.lazyfree_lazy_expire = lazy,
+                    "--lazyfree-lazy-eviction" => self.lazyfree_lazy_eviction = lazy,
+                    "--lazyfree-lazy-user-del" => self.lazyfree_lazy_user_del = lazy,
+                    _ => self.lazyfree_lazy_user_flush = lazy,
+                }
+            }
+            "--max-connections-per-ip" => match read_next_arg(args, index) {
+                Ok(x) => match x.parse::<usize>() {
+                    Ok(limit) => self.max_connections_per_ip = limit,
+                    Err(_) => return Err(ConfigError::invalid("--max-connections-per-ip", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--max-connections-per-ip"));
+                }
+            },
+            "--max-accept-rate" => match read_next_arg(args, index) {
+                Ok(x) => match x.parse::<usize>() {
+                    Ok(rate) => self.max_accept_rate = rate,
+                    Err(_) => return Err(ConfigError::invalid("--max-accept-rate", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--max-accept-rate"));
+                }
+            },
+            "--maxclients" => match read_next_arg(args, index) {
+                Ok(x) => match x.parse::<usize>() {
+                    Ok(limit) if limit > 0 => self.maxclients = limit,
+                    _ => return Err(ConfigError::invalid("--maxclients", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--maxclients"));
+                }
+            },
+            "--timeout" => match read_next_arg(args, index) {
+                Ok(x) => match x.parse::<u64>() {
+                    Ok(seconds) => self.timeout = seconds,
+                    Err(_) => return Err(ConfigError::invalid("--timeout", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--timeout"));
+                }
+            },
+            "--tcp-keepalive" => match read_next_arg(args, index) {
+                Ok(x) => match x.parse::<u64>() {
+                    Ok(seconds) => self.tcp_keepalive = seconds,
+                    Err(_) => return Err(ConfigError::invalid("--tcp-keepalive", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--tcp-keepalive"));
+                }
+            },
+            "--latency-tracking" => match read_next_arg(args, index) {
+                Ok(x) => match parse_yes_no(&x) {
+                    Some(track) => self.latency_tracking = track,
+                    None => return Err(ConfigError::invalid("--latency-tracking", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--latency-tracking"));
+                }
+            },
+            "--latency-tracking-info-percentiles" => match read_next_arg(args, index) {
+                Ok(x) => match parse_percentiles(&x) {
+                    Some(percentiles) => self.latency_tracking_info_percentiles = percentiles,
+                    None => {
+                        return Err(ConfigError::invalid(
+                            "--latency-tracking-info-percentiles",
+                            &x,
+                        ))
+                    }
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--latency-tracking-info-percentiles"));
+                }
+            },
+            "--acllog-max-len" => match read_next_arg(args, index) {
+                Ok(x) => match x.parse::<usize>() {
+                    Ok(length) => self.acllog_max_len = length,
+                    Err(_) => return Err(ConfigError::invalid("--acllog-max-len", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--acllog-max-len"));
+                }
+            },
+            "--slowlog-log-slower-than" => match read_next_arg(args, index) {
+                Ok(x) => match x.parse::<i64>() {
+                    Ok(usec) => self.slowlog_log_slower_than = usec,
+                    Err(_) => return Err(ConfigError::invalid("--slowlog-log-slower-than", &x)),
                 },
-                "--dbfilename" => match read_next_arg(&args, &mut index) {
-                    Ok(x) => config.rdb_filename = Some(PathBuf::from(x)),
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--slowlog-log-slower-than"));
+                }
+            },
+            "--slowlog-max-len" => match read_next_arg(args, index) {
+                Ok(x) => match x.parse::<usize>() {
+                    Ok(length) => self.slowlog_max_len = length,
+                    Err(_) => return Err(ConfigError::invalid("--slowlog-max-len", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--slowlog-max-len"));
+                }
+            },
+            "--notify-keyspace-events" => match read_next_arg(args, index) {
+                Ok(x) => match NotifyFlags::parse(&x) {
+                    Some(flags) => self.notify_keyspace_events = flags,
+                    None => return Err(ConfigError::invalid("--notify-keyspace-events", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--notify-keyspace-events"));
+                }
+            },
+            "--requirepass" => match read_next_arg(args, index) {
+                Ok(x) => self.requirepass = Some(x).filter(|x| !x.is_empty()),
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--requirepass"));
+                }
+            },
+            "--masterauth" => match read_next_arg(args, index) {
+                Ok(x) => self.masterauth = Some(x).filter(|x| !x.is_empty()),
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--masterauth"));
+                }
+            },
+            // The ziplist names are still accepted, as in Redis
+            "--zset-max-listpack-entries"
+            | "--zset-max-ziplist-entries"
+            | "--zset-max-listpack-value"
+            | "--zset-max-ziplist-value" => {
+                let option = args[*index].clone();
+                let limit = match read_next_arg(args, index) {
+                    Ok(x) => match x.parse::<usize>() {
+                        Ok(limit) => limit,
+                        Err(_) => return Err(ConfigError::invalid(&option, &x)),
+                    },
                     Err(ConfigParseError::NoArgFound) => {
-                        panic!("Error: --dbfilename requires a value");
+                        return Err(ConfigError::missing(&option));
                     }
+                };
+                if option.ends_with("-entries") {
+                    self.zset_max_listpack_entries = limit;
+                } else {
+                    self.zset_max_listpack_value = limit;
+                }
+            }
+            "--loglevel" => match read_next_arg(args, index) {
+                Ok(x) => match LogLevel::parse(&x) {
+                    Some(level) => self.loglevel = level,
+                    None => return Err(ConfigError::invalid("--loglevel", &x)),
                 },
-                _ => {}
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--loglevel"));
+                }
+            },
+            "--enable-debug-command" => match read_next_arg(args, index) {
+                Ok(x) => match ProtectedAccess::parse(&x) {
+                    Some(access) => self.enable_debug_command = access,
+                    None => return Err(ConfigError::invalid("--enable-debug-command", &x)),
+                },
+                Err(ConfigParseError::NoArgFound) => {
+                    return Err(ConfigError::missing("--enable-debug-command"));
+                }
+            },
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub fn frame_limits(&self) -> FrameLimits {
+        FrameLimits {
+            max_bulk_length: self.proto_max_bulk_len,
+            max_multibulk_length: self.proto_max_multibulk_len,
+        }
+    }
+
+    pub fn zset_listpack_limits(&self) -> ListpackLimits {
+        ListpackLimits {
+            max_entries: self.zset_max_listpack_entries,
+            max_value: self.zset_max_listpack_value,
+        }
+    }
+
+    // Where SAVE and BGSAVE write the dataset, dump.rdb in the working directory by default
+    pub fn rdb_path(&self) -> PathBuf {
+        let dir = self.rdb_dir.clone().unwrap_or_else(|| PathBuf::from("."));
+        match &self.rdb_filename {
+            Some(filename) => dir.join(filename),
+            None => dir.join("dump.rdb"),
+        }
+    }
+
+    pub fn aof_path(&self) -> PathBuf {
+        let dir = self.rdb_dir.clone().unwrap_or_else(|| PathBuf::from("."));
+        dir.join(&self.appendfilename)
+    }
+
+    // The names of the parameters matching the glob-style `pattern`
+    pub fn matching_parameters(pattern: &str) -> impl Iterator<Item = &'static str> + '_ {
+        PARAMETERS
+            .iter()
+            .copied()
+            .filter(move |x| glob::matches(pattern.as_bytes(), x.as_bytes()))
+    }
+
+    // The value of parameter `name` as CONFIG GET shows it
+    pub fn get(&self, name: &str) -> Option<String> {
+        let yes_no = |x: bool| String::from(if x { "yes" } else { "no" });
+        let joined = |x: Vec<String>| x.join(" ");
+        let value = match name {
+            "port" => self.port.clone(),
+            "replicaof" => match (&self.master_host, &self.master_port) {
+                (Some(host), Some(port)) => format!("{} {}", host, port),
+                _ => String::new(),
+            },
+            "dir" => match &self.rdb_dir {
+                Some(x) => x.display().to_string(),
+                None => env::current_dir().map_or(String::from("."), |x| x.display().to_string()),
+            },
+            "dbfilename" => self
+                .rdb_filename
+                .as_ref()
+                .map_or(String::from("dump.rdb"), |x| x.display().to_string()),
+            "save" => joined(
+                self.save
+                    .iter()
+                    .map(|(seconds, changes)| format!("{} {}", seconds, changes))
+                    .collect(),
+            ),
+            "appendonly" => yes_no(self.appendonly),
+            "appendfsync" => self.appendfsync.to_string(),
+            "appendfilename" => self.appendfilename.display().to_string(),
+            "databases" => self.databases.to_string(),
+            "unixsocket" => self
+                .unixsocket
+                .as_ref()
+                .map_or(String::new(), |x| x.display().to_string()),
+            "unixsocketperm" => format!("{:o}", self.unixsocketperm),
+            "keyspace-mode" => self.keyspace_mode.to_string(),
+            "maxmemory" => self.maxmemory.to_string(),
+            "maxmemory-policy" => self.maxmemory_policy.to_string(),
+            "maxmemory-samples" => self.maxmemory_samples.to_string(),
+            "lfu-log-factor" => self.lfu_log_factor.to_string(),
+            "lfu-decay-time" => self.lfu_decay_time.to_string(),
+            "hz" => self.hz.to_string(),
+            "repl-timeout" => self.repl_timeout.to_string(),
+            "repl-backlog-size" => self.repl_backlog_size.to_string(),
+            "replica-serve-stale-data" => yes_no(self.replica_serve_stale_data),
+            "proto-max-bulk-len" => self.proto_max_bulk_len.to_string(),
+            "proto-max-multibulk-len" => self.proto_max_multibulk_len.to_string(),
+            "lazyfree-lazy-expire" => yes_no(self.lazyfree_lazy_expire),
+            "lazyfree-lazy-eviction" => yes_no(self.lazyfree_lazy_eviction),
+            "lazyfree-lazy-user-del" => yes_no(self.lazyfree_lazy_user_del),
+            "lazyfree-lazy-user-flush" => yes_no(self.lazyfree_lazy_user_flush),
+            "max-connections-per-ip" => self.max_connections_per_ip.to_string(),
+            "max-accept-rate" => self.max_accept_rate.to_string(),
+            "maxclients" => self.maxclients.to_string(),
+            "timeout" => self.timeout.to_string(),
+            "tcp-keepalive" => self.tcp_keepalive.to_string(),
+            "latency-tracking" => yes_no(self.latency_tracking),
+            "latency-tracking-info-percentiles" => joined(
+                self.latency_tracking_info_percentiles
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect(),
+            ),
+            "acllog-max-len" => self.acllog_max_len.to_string(),
+            "slowlog-log-slower-than" => self.slowlog_log_slower_than.to_string(),
+            "slowlog-max-len" => self.slowlog_max_len.to_string(),
+            "notify-keyspace-events" => self.notify_keyspace_events.to_string(),
+            "requirepass" => self.requirepass.clone().unwrap_or_default(),
+            "masterauth" => self.masterauth.clone().unwrap_or_default(),
+            "zset-max-listpack-entries" | "zset-max-ziplist-entries" => {
+                self.zset_max_listpack_entries.to_string()
             }
-            index += 1; // Move to the next argument
+            "zset-max-listpack-value" | "zset-max-ziplist-value" => {
+                self.zset_max_listpack_value.to_string()
+            }
+            "loglevel" => self.loglevel.to_string(),
+            "enable-debug-command" => self.enable_debug_command.to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    // Changes parameter `name` to `value`, as CONFIG SET does
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), ConfigSetError> {
+        if !MUTABLE_PARAMETERS.contains(&name) {
+            return match PARAMETERS.contains(&name) {
+                true => Err(ConfigSetError::Immutable),
+                false => Err(ConfigSetError::Unknown),
+            };
         }
-        if config.role == RedisState::Master {
-            config.master_replid = Some(String::from("8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb"));
-            config.master_repl_offset = Some(String::from("0"));
+        let invalid = |x: &str| ConfigSetError::Invalid(x.to_string());
+        let yes_no =
+            || parse_yes_no(value).ok_or_else(|| invalid("argument must be 'yes' or 'no'"));
+        match name {
+            "dir" => self.rdb_dir = Some(PathBuf::from(value)),
+            "dbfilename" => self.rdb_filename = Some(PathBuf::from(value)),
+            "save" => {
+                self.save =
+                    parse_save_points(value).ok_or_else(|| invalid("Invalid save parameters"))?
+            }
+            "appendfsync" => {
+                self.appendfsync = AppendFsync::parse(value)
+                    .ok_or_else(|| invalid("argument(s) must be one of: always, everysec, no"))?
+            }
+            "maxmemory" => {
+                self.maxmemory =
+                    parse_memory(value).ok_or_else(|| invalid("argument must be a memory value"))?
+            }
+            "maxmemory-policy" => {
+                self.maxmemory_policy = EvictionPolicy::parse(value)
+                    .ok_or_else(|| invalid("argument must be a valid eviction policy"))?
+            }
+            "maxmemory-samples" => match parse_integer(value)? {
+                0 => return Err(invalid("argument must be a positive integer")),
+                x => self.maxmemory_samples = x,
+            },
+            "lfu-log-factor" => self.lfu_log_factor = parse_integer(value)?,
+            "lfu-decay-time" => self.lfu_decay_time = parse_integer(value)?,
+            "repl-backlog-size" => match parse_memory(value) {
+                Some(bytes) if bytes > 0 => self.repl_backlog_size = bytes,
+                _ => return Err(invalid("argument must be a memory value")),
+            },
+            "replica-serve-stale-data" => self.replica_serve_stale_data = yes_no()?,
+            "lazyfree-lazy-expire" => self.lazyfree_lazy_expire = yes_no()?,
+            "lazyfree-lazy-eviction" => self.lazyfree_lazy_eviction = yes_no()?,
+            "lazyfree-lazy-user-del" => self.lazyfree_lazy_user_del = yes_no()?,
+            "lazyfree-lazy-user-flush" => self.lazyfree_lazy_user_flush = yes_no()?,
+            "timeout" => self.timeout = parse_integer(value)?,
+            "tcp-keepalive" => self.tcp_keepalive = parse_integer(value)?,
+            "latency-tracking" => self.latency_tracking = yes_no()?,
+            "latency-tracking-info-percentiles" => {
+                self.latency_tracking_info_percentiles = parse_percentiles(value)
+                    .ok_or_else(|| invalid("percentiles must be between 0 and 100"))?
+            }
+            "slowlog-log-slower-than" => self.slowlog_log_slower_than = parse_integer(value)?,
+            "slowlog-max-len" => self.slowlog_max_len = parse_integer(value)?,
+            "notify-keyspace-events" => {
+                self.notify_keyspace_events = NotifyFlags::parse(value)
+                    .ok_or_else(|| invalid("Invalid event class character. Use 'Ag$lshzxeKEt'."))?
+            }
+            // An empty password turns authentication off
+            "requirepass" => self.requirepass = Some(value.to_string()).filter(|x| !x.is_empty()),
+            "masterauth" => self.masterauth = Some(value.to_string()).filter(|x| !x.is_empty()),
+            "zset-max-listpack-entries" | "zset-max-ziplist-entries" => {
+                self.zset_max_listpack_entries = parse_integer(value)?
+            }
+            "loglevel" => {
+                self.loglevel = LogLevel::parse(value).ok_or_else(|| {
+                    invalid("argument(s) must be one of: debug, verbose, notice, warning, nothing")
+                })?
+            }
+            _ => self.zset_max_listpack_value = parse_integer(value)?,
         }
-        config
+        Ok(())
+    }
+
+    pub fn set_replica_of(&mut self, host: String, port: String) {
+        self.master_host = Some(host);
+        self.master_port = Some(port);
+        self.role = RedisState::Replica;
+    }
+
+    pub fn set_master(&mut self) {
+        self.master_host = None;
+        self.master_port = None;
+        self.role = RedisState::Master;
     }
 }
 
-fn read_next_arg(args: &Vec<String>, curr_index: &mut usize) -> Result<String, ConfigParseError> {
+// The number and text of a line of a config file
+type FileLine = (usize, String);
+
+// A line of a config file the server can't start with
+struct BadLine {
+    number: usize,
+    line: String,
+    message: String,
+}
+
+impl BadLine {
+    // Reported like redis-server reports it
+    fn into_error(self, path: &str) -> ConfigError {
+        ConfigError(format!(
+            "Reading the configuration file {}, at line {}\n>>> '{}'\n{}",
+            path, self.number, self.line, self.message
+        ))
+    }
+}
+
+// The lines of a redis.conf-style file as the flags they'd be given as: "port 6380" becomes
+// ["--port", "6380"], with the values of directives taking several, like "replicaof host 6379",
+// joined by spaces. Save lines add up to one --save, as each is a save point of its own. Along
+// with them come the number and text of the line each flag was read from.
+fn config_file_args(contents: &str) -> Result<(Vec<String>, Vec<FileLine>), BadLine> {
+    let mut args = Vec::new();
+    let mut lines = Vec::new();
+    let mut save_index = None;
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let bad_line = |message: &str| BadLine {
+            number: number + 1,
+            line: line.to_string(),
+            message: message.to_string(),
+        };
+        let words = split_config_line(line)
+            .ok_or_else(|| bad_line("Unbalanced quotes in configuration line"))?;
+        let directive = match words[0].to_lowercase().as_str() {
+            "slaveof" => String::from("replicaof"),
+            x => x.to_string(),
+        };
+        if words.len() < 2 {
+            return Err(bad_line("wrong number of arguments"));
+        }
+        let value = words[1..].join(" ");
+        match (directive.as_str(), save_index) {
+            ("save", Some(index)) => {
+                let points: &mut String = &mut args[index];
+                points.push(' ');
+                points.push_str(&value);
+            }
+            _ => {
+                if directive == "save" {
+                    save_index = Some(args.len() + 1);
+                }
+                args.push(format!("--{}", directive));
+                args.push(value);
+                lines.push((number + 1, line.to_string()));
+            }
+        }
+    }
+    Ok((args, lines))
+}
+
+// A line split into words, which can be quoted with " or ' to hold spaces or be empty. Double
+// quoted words take escapes like \n. None if a quote isn't closed.
+fn split_config_line(line: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|x| x.is_whitespace()).is_some() {}
+        let quote = match chars.peek() {
+            None => return Some(words),
+            Some('"') | Some('\'') => chars.next(),
+            Some(_) => None,
+        };
+        let mut word = String::new();
+        loop {
+            match (chars.next(), quote) {
+                (None, Some(_)) => return None,
+                (None, None) => break,
+                (Some(x), Some(q)) if x == q => break,
+                (Some(x), None) if x.is_whitespace() => break,
+                (Some('\\'), Some('"')) => word.push(match chars.next()? {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    x => x,
+                }),
+                (Some(x), _) => word.push(x),
+            }
+        }
+        words.push(word);
+    }
+}
+
+fn read_next_arg(args: &[String], curr_index: &mut usize) -> Result<String, ConfigParseError> {
     if *curr_index + 1 >= args.len() {
         return Err(ConfigParseError::NoArgFound);
     }
     *curr_index += 1;
     Ok(args[*curr_index].clone())
 }
+
+pub fn parse_yes_no(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+// Pairs of seconds and changes, "3600 1 300 100" being two save points
+pub fn parse_save_points(value: &str) -> Option<Vec<(u64, u64)>> {
+    let numbers = value
+        .split_whitespace()
+        .map(|x| x.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if !numbers.len().is_multiple_of(2) {
+        return None;
+    }
+    Some(numbers.chunks(2).map(|x| (x[0], x[1])).collect())
+}
+
+fn parse_integer<T: FromStr>(value: &str) -> Result<T, ConfigSetError> {
+    value.parse::<T>().map_err(|_| {
+        ConfigSetError::Invalid(String::from("argument couldn't be parsed into an integer"))
+    })
+}
+
+// A space separated list, like "50 99 99.9"
+pub fn parse_percentiles(value: &str) -> Option<Vec<f64>> {
+    value
+        .split_whitespace()
+        .map(|x| x.parse::<f64>().ok().filter(|x| (0.0..=100.0).contains(x)))
+        .collect()
+}
+
+// Parses sizes like 1048576, 100kb or 2gb into bytes
+pub fn parse_memory(value: &str) -> Option<usize> {
+    let value = value.to_lowercase();
+    let split_at = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split_at);
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.parse::<usize>().ok().map(|x| x * multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_parameters_read_back_the_way_get_shows_them() {
+        let mut config = Config::default();
+        config.set("maxmemory", "1mb").unwrap();
+        config.set("save", "900 1 60 1000").unwrap();
+        config.set("maxmemory-policy", "ALLKEYS-LRU").unwrap();
+        assert_eq!(config.get("maxmemory").unwrap(), "1048576");
+        assert_eq!(config.get("save").unwrap(), "900 1 60 1000");
+        assert_eq!(config.get("maxmemory-policy").unwrap(), "allkeys-lru");
+        config.set("loglevel", "WARNING").unwrap();
+        assert_eq!(config.get("loglevel").unwrap(), "warning");
+        assert!(config.set("loglevel", "loud").is_err());
+
+        assert_eq!(config.set("databases", "4"), Err(ConfigSetError::Immutable));
+        assert_eq!(config.set("bogus", "4"), Err(ConfigSetError::Unknown));
+        assert!(matches!(
+            config.set("save", "900"),
+            Err(ConfigSetError::Invalid(_))
+        ));
+        assert_eq!(config.get("save").unwrap(), "900 1 60 1000");
+        assert_eq!(
+            Config::matching_parameters("maxmemory*").collect::<Vec<_>>(),
+            ["maxmemory", "maxmemory-policy", "maxmemory-samples"]
+        );
+    }
+
+    #[test]
+    fn flags_override_the_config_file() {
+        let path = std::env::temp_dir().join(format!("redis-conf-test-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# A comment\nport 7000\nreplicaof 10.0.0.1 6380\nsave \"\"\nsave 900 1\nsave 60 1000\n\
+             appendonly yes\nrequirepass \"two words\"\ndbfilename file.rdb\n\
+             enable-debug-command local\n",
+        )
+        .unwrap();
+        let args = ["redis-server", path.to_str().unwrap(), "--port", "7001"];
+        let config = Config::from_args(args.iter().map(|x| x.to_string()).collect()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.port, "7001");
+        assert_eq!(config.get("replicaof").unwrap(), "10.0.0.1 6380");
+        assert_eq!(config.role, RedisState::Replica);
+        assert_eq!(config.save, [(900, 1), (60, 1000)]);
+        assert!(config.appendonly);
+        assert_eq!(config.requirepass.as_deref(), Some("two words"));
+        assert_eq!(config.get("dbfilename").unwrap(), "file.rdb");
+        assert_eq!(config.enable_debug_command, ProtectedAccess::Local);
+        assert!(config.config_file.is_some());
+
+        let args = ["redis-server", "--replicaof", "localhost", "6381"];
+        let config = Config::from_args(args.iter().map(|x| x.to_string()).collect()).unwrap();
+        assert_eq!(config.get("replicaof").unwrap(), "localhost 6381");
+        assert!(config_file_args("bogus").is_err());
+        assert!(config_file_args("requirepass \"open").is_err());
+
+        let args = ["redis-server", "--tls-port", "6380"];
+        assert!(Config::from_args(args.iter().map(|x| x.to_string()).collect()).is_err());
+
+        // Bad flags are reported rather than panicking
+        let from_args =
+            |args: &[&str]| Config::from_args(args.iter().map(|x| x.to_string()).collect());
+        assert!(from_args(&["redis-server", "--hz", "fast"]).is_err());
+        assert_eq!(
+            from_args(&["redis-server", "--port", "abc"]).err(),
+            Some(ConfigError(String::from("invalid --port value abc")))
+        );
+        assert_eq!(
+            from_args(&["redis-server", "--port"]).err(),
+            Some(ConfigError(String::from("--port requires a value")))
+        );
+        assert!(from_args(&["redis-server", "--databases", "0"]).is_err());
+        assert!(from_args(&["redis-server", "--replicaof", "localhost"]).is_err());
+    }
+
+    #[test]
+    fn config_file_errors_name_the_file_and_line() {
+        let path = std::env::temp_dir().join(format!("redis-conf-errors-{}", std::process::id()));
+        let from_file = |contents: &str| {
+            fs::write(&path, contents).unwrap();
+            let args = vec![String::from("redis-server"), path.display().to_string()];
+            Config::from_args(args).err().unwrap().0
+        };
+        let error = |number: usize, line: &str, message: &str| {
+            format!(
+                "Reading the configuration file {}, at line {}\n>>> '{}'\n{}",
+                path.display(),
+                number,
+                line,
+                message
+            )
+        };
+        assert_eq!(
+            from_file("port 7000\n\nport abc\n"),
+            error(3, "port abc", "invalid --port value abc")
+        );
+        assert_eq!(
+            from_file("# A comment\nbogus yes\n"),
+            error(2, "bogus yes", "Bad directive or wrong number of arguments")
+        );
+        assert_eq!(
+            from_file("save 900 1\nsave\n"),
+            error(2, "save", "wrong number of arguments")
+        );
+        fs::remove_file(&path).unwrap();
+
+        let args = vec![String::from("redis-server"), path.display().to_string()];
+        let error = Config::from_args(args).err().unwrap();
+        assert!(error.0.contains("can't open config file"));
+    }
+}
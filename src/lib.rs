@@ -0,0 +1,11 @@
+pub mod aof;
+pub mod client;
+pub mod config;
+pub mod log;
+pub mod rdb;
+pub mod redis;
+pub mod resp;
+pub mod server;
+
+pub use client::Client;
+pub use server::{Server, ServerBuilder, ServerError, ShutdownHandle};
@@ -0,0 +1,213 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The server's log, written to stdout a line at a time the way Redis writes it: the pid, a
+// timestamp and a character for the level, then the message. Lines below the level picked with
+// --loglevel or CONFIG SET loglevel are dropped. Tasks serving a connection log with what it is,
+// e.g. "client id=3 addr=127.0.0.1:5000", see scope.
+//
+// Log with the macros, which take format! arguments:
+//   notice!("Connecting to MASTER {}:{}", host, port);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Debug,
+    Verbose,
+    Notice,
+    Warning,
+    // Logs nothing at all
+    Nothing,
+}
+
+impl LogLevel {
+    pub fn parse(level: &str) -> Option<Self> {
+        match level.to_lowercase().as_str() {
+            "debug" => Some(LogLevel::Debug),
+            "verbose" => Some(LogLevel::Verbose),
+            "notice" => Some(LogLevel::Notice),
+            "warning" => Some(LogLevel::Warning),
+            "nothing" => Some(LogLevel::Nothing),
+            _ => None,
+        }
+    }
+
+    fn symbol(self) -> char {
+        match self {
+            LogLevel::Debug => '.',
+            LogLevel::Verbose => '-',
+            LogLevel::Notice => '*',
+            LogLevel::Warning | LogLevel::Nothing => '#',
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            LogLevel::Debug => "debug",
+            LogLevel::Verbose => "verbose",
+            LogLevel::Notice => "notice",
+            LogLevel::Warning => "warning",
+            LogLevel::Nothing => "nothing",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Notice as u8);
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+// Whether lines at `level` are logged, for callers that would do work just to log
+pub fn enabled(level: LogLevel) -> bool {
+    level != LogLevel::Nothing && level as u8 >= LEVEL.load(Ordering::Relaxed)
+}
+
+tokio::task_local! {
+    static CONTEXT: String;
+}
+
+// Runs `future` with every line it logs tagged with `context`. Tasks it spawns aren't, unless
+// they're scoped too.
+pub async fn scope<F: Future>(context: String, future: F) -> F::Output {
+    CONTEXT.scope(context, future).await
+}
+
+#[doc(hidden)]
+pub fn write(level: LogLevel, message: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    let context = CONTEXT.try_with(|x| x.clone()).ok();
+    // In one piece, so that lines from different threads don't interleave
+    print!(
+        "{}",
+        format_line(level, SystemTime::now(), context.as_deref(), message)
+    );
+}
+
+fn format_line(
+    level: LogLevel,
+    time: SystemTime,
+    context: Option<&str>,
+    message: fmt::Arguments,
+) -> String {
+    let context = context.map_or(String::new(), |x| format!("[{}] ", x));
+    format!(
+        "{} {} {} {}{}\n",
+        std::process::id(),
+        timestamp(time),
+        level.symbol(),
+        context,
+        message
+    )
+}
+
+// Like "16 Oct 2026 09:41:07.250", in UTC
+fn timestamp(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let ms = time.duration_since(UNIX_EPOCH).map_or(0, |x| x.as_millis());
+    let (days, ms) = ((ms / 86_400_000) as i64, ms % 86_400_000);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{} {} {} {:02}:{:02}:{:02}.{:03}",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+// The Gregorian date `days` after 1970-01-01, as (year, month, day), by Howard Hinnant's
+// algorithm
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::LogLevel::Debug, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::LogLevel::Verbose, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! notice {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::LogLevel::Notice, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::LogLevel::Warning, format_args!($($arg)*))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn lines_carry_the_time_level_and_context() {
+        // 2024-02-29 13:05:09.042
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_211_909_042);
+        let line = format_line(
+            LogLevel::Warning,
+            time,
+            Some("client id=3 addr=127.0.0.1:5000"),
+            format_args!("Closing connection: {}", "reset"),
+        );
+        assert_eq!(
+            line,
+            format!(
+                "{} 29 Feb 2024 13:05:09.042 # [client id=3 addr=127.0.0.1:5000] Closing connection: reset\n",
+                std::process::id()
+            )
+        );
+        let line = format_line(LogLevel::Debug, UNIX_EPOCH, None, format_args!("x"));
+        assert!(line.ends_with(" 1 Jan 1970 00:00:00.000 . x\n"));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(20_742), (2026, 10, 16));
+    }
+
+    #[test]
+    fn levels_parse_case_insensitively_and_order_by_importance() {
+        assert_eq!(LogLevel::parse("WARNING"), Some(LogLevel::Warning));
+        assert_eq!(LogLevel::parse("loud"), None);
+        assert!(LogLevel::Debug < LogLevel::Notice);
+        assert_eq!(LogLevel::Verbose.to_string(), "verbose");
+    }
+}
@@ -1,20 +1,61 @@
-use std::sync::Arc;
-use tokio::net::TcpListener;
+use redis_starter_rust::aof;
+use redis_starter_rust::config::Config;
+use redis_starter_rust::redis::identity;
+use redis_starter_rust::{notice, warning, Client, Server, ServerError};
 
-use crate::config::*;
-use crate::redis::Redis;
-
-pub mod config;
-pub mod rdb;
-pub mod redis;
-pub mod resp;
+use std::env;
+use std::io;
+use std::path::PathBuf;
+use tokio::signal::unix::{signal, SignalKind};
 
 #[tokio::main]
-async fn main() -> Result<(), Box<(dyn std::error::Error + 'static)>> {
-    let config = Arc::new(Config::parse());
-    let host = String::from("127.0.0.1");
-    let listener = TcpListener::bind(format!("{}:{}", host, &config.port)).await?;
-    let mut redis = Redis::new(Arc::clone(&config), listener).await.expect("");
-    redis.listen().await?;
+async fn main() -> Result<(), ServerError> {
+    let args: Vec<String> = env::args().collect();
+    if args.iter().any(|x| x == "--version" || x == "-v") {
+        println!("{}", identity::version_line());
+        return Ok(());
+    }
+    // --check-aof <file> [--fix] does redis-check-aof's job instead of starting a server
+    if let Some(index) = args.iter().position(|x| x == "--check-aof") {
+        let path = match args.get(index + 1) {
+            Some(x) => PathBuf::from(x),
+            None => {
+                eprintln!("\n*** FATAL CONFIG ERROR ***\n--check-aof requires a file");
+                std::process::exit(1);
+            }
+        };
+        let fix = args.iter().any(|x| x == "--fix");
+        std::process::exit(aof::run_check(&path, fix));
+    }
+
+    let config = match Config::parse() {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("\n*** FATAL CONFIG ERROR ***\n{}", e.0);
+            std::process::exit(1);
+        }
+    };
+    let server = Server::from_config(config).await?;
+    shut_down_on_signals(server.client())?;
+    server.run().await
+}
+
+// SIGINT and SIGTERM shut the server down the way SHUTDOWN does, saving first if save points are
+// configured. Should that fail the server keeps running, as Redis does.
+fn shut_down_on_signals(mut client: Client) -> io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = terminate.recv() => notice!("Received SIGTERM, scheduling shutdown..."),
+                _ = interrupt.recv() => notice!("Received SIGINT, scheduling shutdown..."),
+            }
+            match client.command(&["SHUTDOWN"]).await {
+                None => return,
+                Some(reply) => warning!("Could not shut down: {:?}", reply),
+            }
+        }
+    });
     Ok(())
 }
@@ -1,98 +1,559 @@
-use std::collections::HashMap;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::redis::clock::unix_ms_to_mstime;
+use crate::redis::sorted_set::{ListpackLimits, SortedSet};
+use crate::redis::store::Entry;
+use crate::redis::stream::{Stream, StreamId};
+use crate::redis::string::StringValue;
+use crate::redis::value::Value;
+
+use crc64::crc64;
+
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+
+pub mod crc64;
+mod lzf;
+mod packed;
+pub mod writer;
 
 pub struct RdbParser {
     data: Vec<u8>,
-    index: usize,
+}
+
+impl RdbParser {
+    // Public
+    pub fn new(data: Vec<u8>) -> Self {
+        Self { data }
+    }
+
+    // Every database in the file, indexed by number. Databases the file skips over come back
+    // empty. Anything the parser can't make sense of, a checksum that doesn't match included,
+    // fails the whole file rather than leave a partially loaded dataset, with the offset the file
+    // stops making sense at.
+    pub fn rdb_to_db(&self) -> Result<Vec<HashMap<String, Entry>>, (usize, String)> {
+        let mut walker = RdbWalker::new(&self.data);
+        walker.databases = Some(Vec::new());
+        walker.walk()?;
+        Ok(walker.databases.unwrap_or_default())
+    }
 }
 
 const EOF_FLAG: u8 = 0xff;
 const EXPIRY_MS_FLAG: u8 = 0xfc;
 const EXPIRY_S_FLAG: u8 = 0xfd;
+const AUX_FLAG: u8 = 0xfa;
+const RESIZEDB_FLAG: u8 = 0xfb;
+const SELECTDB_FLAG: u8 = 0xfe;
+const IDLE_FLAG: u8 = 0xf8;
+const FREQ_FLAG: u8 = 0xf9;
+const MAGIC: &[u8] = b"REDIS";
+// Checksums were added in version 5
+const FIRST_CHECKSUMMED_VERSION: u32 = 5;
 
-impl RdbParser {
-    // Public
-    pub fn new(data: Vec<u8>) -> Self {
-        Self { data, index: 0 }
+const STRING_TYPE: u8 = 0;
+const LIST_TYPE: u8 = 1;
+const SET_TYPE: u8 = 2;
+// Sorted sets with their scores as strings
+const ZSET_TYPE: u8 = 3;
+const HASH_TYPE: u8 = 4;
+// Sorted sets with their scores as binary doubles
+const ZSET_2_TYPE: u8 = 5;
+const HASH_ZIPMAP_TYPE: u8 = 9;
+const LIST_ZIPLIST_TYPE: u8 = 10;
+const SET_INTSET_TYPE: u8 = 11;
+const ZSET_ZIPLIST_TYPE: u8 = 12;
+const HASH_ZIPLIST_TYPE: u8 = 13;
+const LIST_QUICKLIST_TYPE: u8 = 14;
+const STREAM_LISTPACKS_TYPE: u8 = 15;
+const HASH_LISTPACK_TYPE: u8 = 16;
+const ZSET_LISTPACK_TYPE: u8 = 17;
+const LIST_QUICKLIST_2_TYPE: u8 = 18;
+const STREAM_LISTPACKS_2_TYPE: u8 = 19;
+const SET_LISTPACK_TYPE: u8 = 20;
+const STREAM_LISTPACKS_3_TYPE: u8 = 21;
+// How the nodes of a LIST_QUICKLIST_2_TYPE list are stored: one element on its own, or a listpack
+const QUICKLIST_PLAIN_NODE: u64 = 1;
+const QUICKLIST_PACKED_NODE: u64 = 2;
+// Flags on stream entries: removed with XDEL, and having the same fields as their node's first
+const STREAM_DELETED_FLAG: i64 = 1;
+const STREAM_SAMEFIELDS_FLAG: i64 = 2;
+
+// Walks an RDB file without loading anything, returning how many bytes it takes up, checksum
+// included. This is how the RDB preamble of an AOF is told apart from the commands after it.
+// Errors carry the offset the file stops making sense at.
+pub fn rdb_length(data: &[u8]) -> Result<usize, (usize, String)> {
+    RdbWalker::new(data).walk()
+}
+
+// Decodes an RDB file from start to end, every value included so that a corrupt one is noticed
+// whether or not the file is being loaded
+struct RdbWalker<'a> {
+    data: &'a [u8],
+    index: usize,
+    // Where the entries go when loading
+    databases: Option<Vec<HashMap<String, Entry>>>,
+    db: usize,
+    // The expiration time read for the next key, as a UNIX time in milliseconds
+    expiry: Option<u64>,
+}
+
+impl<'a> RdbWalker<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        RdbWalker {
+            data,
+            index: 0,
+            databases: None,
+            db: 0,
+            expiry: None,
+        }
     }
 
-    pub fn rdb_to_db(&mut self) -> (HashMap<String, String>, HashMap<String, SystemTime>) {
-        self.parse_header();
-        self.parse_metadata();
-        let mut database: HashMap<String, String> = HashMap::new();
-        let mut expiry: HashMap<String, SystemTime> = HashMap::new();
-        while self.data[self.index] != EOF_FLAG {
-            println!("Reading KEY-Value");
-            let (expiration, key, value) = self.parse_key_value();
-            if let Some(x) = expiration {
-                expiry.insert(key.clone(), x);
+    fn walk(&mut self) -> Result<usize, (usize, String)> {
+        if self.take(MAGIC.len())? != MAGIC {
+            return Err((0, String::from("missing the REDIS magic string")));
+        }
+        let version = std::str::from_utf8(self.take(4)?)
+            .ok()
+            .and_then(|x| x.parse::<u32>().ok())
+            .ok_or_else(|| self.error("invalid RDB version"))?;
+        loop {
+            match self.byte()? {
+                EOF_FLAG => {
+                    if version >= FIRST_CHECKSUMMED_VERSION {
+                        self.checksum()?;
+                    }
+                    return Ok(self.index);
+                }
+                AUX_FLAG => {
+                    self.string()?;
+                    self.string()?;
+                }
+                RESIZEDB_FLAG => {
+                    self.length()?;
+                    self.length()?;
+                }
+                SELECTDB_FLAG => {
+                    self.db = self.usize()?;
+                }
+                // What the eviction policy knew about the next key, which starts over on load
+                IDLE_FLAG => {
+                    self.length()?;
+                }
+                FREQ_FLAG => {
+                    self.byte()?;
+                }
+                EXPIRY_S_FLAG => {
+                    let seconds = u32::from_le_bytes(self.array()?);
+                    self.expiry = Some(seconds as u64 * 1000);
+                }
+                EXPIRY_MS_FLAG => {
+                    self.expiry = Some(u64::from_le_bytes(self.array()?));
+                }
+                value_type => {
+                    let start = self.index;
+                    let key = String::from_utf8(self.string()?.to_vec())
+                        .map_err(|_| (start, String::from("key is not valid UTF-8")))?;
+                    let value = self.value(value_type)?;
+                    let expiry = self.expiry.take();
+                    if let Some(databases) = &mut self.databases {
+                        let mut entry = Entry::new(value);
+                        // RDB files store absolute UNIX times, while the keyspace tracks expiry
+                        // on the monotonic clock
+                        entry.expires_at = expiry.map(unix_ms_to_mstime);
+                        if databases.len() <= self.db {
+                            databases.resize_with(self.db + 1, HashMap::new);
+                        }
+                        databases[self.db].insert(key, entry);
+                    }
+                }
             }
-            database.insert(key, value);
         }
-        (database, expiry)
     }
 
-    // Private
-    fn parse_header(&mut self) {
-        let header = &self.data[0..9];
-        println!("Header: {:?}", header);
-        self.index += 9;
+    // Files saved with rdbchecksum off end with a checksum of 0, which isn't checked
+    fn checksum(&mut self) -> Result<(), (usize, String)> {
+        let expected = crc64(&self.data[..self.index]);
+        let start = self.index;
+        let checksum = u64::from_le_bytes(self.array()?);
+        if checksum != 0 && checksum != expected {
+            return Err((
+                start,
+                format!(
+                    "wrong RDB checksum, expected {:016x} but got {:016x}",
+                    checksum, expected
+                ),
+            ));
+        }
+        Ok(())
     }
 
-    fn parse_metadata(&mut self) {
-        let hash_start_index = self.data.iter().position(|&b| b == 0xfb).unwrap();
-        self.index = hash_start_index;
-        // Skip over RESIZE DB Field
-        self.index += 3;
+    fn value(&mut self, value_type: u8) -> Result<Value, (usize, String)> {
+        let value = match value_type {
+            STRING_TYPE => Value::Str(StringValue::from_bytes(self.string()?)),
+            LIST_TYPE => Value::List(self.strings()?.into_iter().collect()),
+            SET_TYPE => Value::Set(self.strings()?.into_iter().collect()),
+            ZSET_TYPE | ZSET_2_TYPE => {
+                let mut zset = SortedSet::new();
+                for _ in 0..self.length()? {
+                    let member = self.string()?;
+                    let score = match value_type {
+                        ZSET_2_TYPE => f64::from_le_bytes(self.array()?),
+                        _ => self.string_score()?,
+                    };
+                    zset.insert(member, score, &ListpackLimits::default());
+                }
+                Value::ZSet(zset)
+            }
+            HASH_TYPE => {
+                let mut hash = HashMap::new();
+                for _ in 0..self.length()? {
+                    hash.insert(self.string()?, self.string()?);
+                }
+                Value::Hash(hash)
+            }
+            HASH_ZIPMAP_TYPE => Value::Hash(self.packed(packed::zipmap)?.into_iter().collect()),
+            LIST_ZIPLIST_TYPE => Value::List(self.packed(packed::ziplist)?.into()),
+            SET_INTSET_TYPE => Value::Set(self.packed(packed::intset)?.into_iter().collect()),
+            SET_LISTPACK_TYPE => Value::Set(self.packed(packed::listpack)?.into_iter().collect()),
+            HASH_ZIPLIST_TYPE | HASH_LISTPACK_TYPE => {
+                let decode = match value_type {
+                    HASH_ZIPLIST_TYPE => packed::ziplist,
+                    _ => packed::listpack,
+                };
+                let pairs = self.packed(|x| packed::pairs(decode(x)?))?;
+                Value::Hash(pairs.into_iter().collect())
+            }
+            ZSET_ZIPLIST_TYPE | ZSET_LISTPACK_TYPE => {
+                let decode = match value_type {
+                    ZSET_ZIPLIST_TYPE => packed::ziplist,
+                    _ => packed::listpack,
+                };
+                let start = self.index;
+                let mut zset = SortedSet::new();
+                for (member, score) in self.packed(|x| packed::pairs(decode(x)?))? {
+                    let score = parse_score(&score)
+                        .ok_or_else(|| (start, String::from("invalid sorted set score")))?;
+                    zset.insert(member, score, &ListpackLimits::default());
+                }
+                Value::ZSet(zset)
+            }
+            LIST_QUICKLIST_TYPE => {
+                let mut list = VecDeque::new();
+                for _ in 0..self.length()? {
+                    list.extend(self.packed(packed::ziplist)?);
+                }
+                Value::List(list)
+            }
+            LIST_QUICKLIST_2_TYPE => {
+                let mut list = VecDeque::new();
+                for _ in 0..self.length()? {
+                    match self.length()? {
+                        QUICKLIST_PLAIN_NODE => list.push_back(self.string()?),
+                        QUICKLIST_PACKED_NODE => list.extend(self.packed(packed::listpack)?),
+                        other => {
+                            return Err(
+                                self.error(&format!("invalid quicklist node type {}", other))
+                            )
+                        }
+                    }
+                }
+                Value::List(list)
+            }
+            STREAM_LISTPACKS_TYPE | STREAM_LISTPACKS_2_TYPE | STREAM_LISTPACKS_3_TYPE => {
+                Value::Stream(self.stream(value_type)?)
+            }
+            other => return Err(self.error(&format!("unsupported value type {}", other))),
+        };
+        Ok(value)
+    }
+
+    // Nodes of entries keyed by their first entry's ID, then the stream's metadata and consumer
+    // groups. Consumer groups aren't supported, so they're read past and dropped.
+    fn stream(&mut self, value_type: u8) -> Result<Stream, (usize, String)> {
+        let mut stream = Stream::new();
+        for _ in 0..self.length()? {
+            let start = self.index;
+            let key = self.string()?;
+            let master = match <[u8; 16]>::try_from(&key[..]) {
+                Ok(x) => StreamId {
+                    ms: u64::from_be_bytes(x[..8].try_into().unwrap()),
+                    seq: u64::from_be_bytes(x[8..].try_into().unwrap()),
+                },
+                Err(_) => return Err((start, String::from("invalid stream node ID"))),
+            };
+            stream
+                .entries
+                .extend(self.packed(|x| packed::stream_node(master, x))?);
+        }
+        // The length, which the entries already give
+        self.length()?;
+        stream.last_id = StreamId {
+            ms: self.length()?,
+            seq: self.length()?,
+        };
+        let version_2 = value_type != STREAM_LISTPACKS_TYPE;
+        if version_2 {
+            // The first ID, the largest deleted ID and how many entries were ever added
+            for _ in 0..5 {
+                self.length()?;
+            }
+        }
+        for _ in 0..self.length()? {
+            // The group's name and last delivered ID, and from version 2 its read counter
+            self.string()?;
+            self.length()?;
+            self.length()?;
+            if version_2 {
+                self.length()?;
+            }
+            // Pending entries, each an ID, a delivery time and a delivery count
+            for _ in 0..self.length()? {
+                self.take(24)?;
+                self.length()?;
+            }
+            // Consumers, each with a name, when it was last seen, from version 3 when it was last
+            // active, and the IDs of its pending entries
+            for _ in 0..self.length()? {
+                self.string()?;
+                self.take(8)?;
+                if value_type == STREAM_LISTPACKS_3_TYPE {
+                    self.take(8)?;
+                }
+                for _ in 0..self.length()? {
+                    self.take(16)?;
+                }
+            }
+        }
+        Ok(stream)
     }
 
-    fn parse_key_value(&mut self) -> (Option<SystemTime>, String, String) {
-        let expiry = self.parse_expiry();
-        // Skip over Value field
-        self.index += 1;
-        let length_key = self.data[self.index];
-        self.index += 1;
+    // A length-prefixed run of strings, as lists and sets are stored
+    fn strings(&mut self) -> Result<Vec<Bytes>, (usize, String)> {
+        let mut strings = Vec::new();
+        for _ in 0..self.length()? {
+            strings.push(self.string()?);
+        }
+        Ok(strings)
+    }
 
-        let key = &self.data[self.index..(self.index + length_key as usize)];
-        let key = std::str::from_utf8(key).unwrap();
-        self.index += length_key as usize;
+    // A score written as its digits, prefixed by their length, with three lengths standing in
+    // for NaN and the infinities
+    fn string_score(&mut self) -> Result<f64, (usize, String)> {
+        let score = match self.byte()? {
+            253 => f64::NAN,
+            254 => f64::INFINITY,
+            255 => f64::NEG_INFINITY,
+            length => {
+                let start = self.index;
+                let digits = self.take(length as usize)?;
+                parse_score(digits).ok_or((start, String::from("invalid sorted set score")))?
+            }
+        };
+        Ok(score)
+    }
 
-        println!("Key Length: {}", length_key);
-        println!("Key: {}", key);
+    // A string holding one of the compact encodings, decoded by `decode`
+    fn packed<T>(
+        &mut self,
+        decode: impl FnOnce(&[u8]) -> Result<T, String>,
+    ) -> Result<T, (usize, String)> {
+        let start = self.index;
+        let data = self.string()?;
+        decode(&data).map_err(|message| (start, message))
+    }
 
-        let length_value = self.data[self.index];
-        self.index += 1;
+    // Returns the length, or the format of a specially encoded string
+    fn length_or_encoding(&mut self) -> Result<Result<u64, u8>, (usize, String)> {
+        let first = self.byte()?;
+        let length = match first >> 6 {
+            0b00 => (first & 0x3f) as u64,
+            0b01 => (((first & 0x3f) as u64) << 8) | self.byte()? as u64,
+            0b11 => return Ok(Err(first & 0x3f)),
+            _ => match first {
+                0x80 => u32::from_be_bytes(self.take(4)?.try_into().unwrap()) as u64,
+                0x81 => u64::from_be_bytes(self.take(8)?.try_into().unwrap()),
+                _ => return Err(self.error("invalid length encoding")),
+            },
+        };
+        Ok(Ok(length))
+    }
 
-        let value = &self.data[self.index..(self.index + length_value as usize)];
-        let value = std::str::from_utf8(value).unwrap();
-        self.index += length_value as usize;
+    fn length(&mut self) -> Result<u64, (usize, String)> {
+        match self.length_or_encoding()? {
+            Ok(x) => Ok(x),
+            Err(_) => Err(self.error("expected a length, found an encoded string")),
+        }
+    }
 
-        println!("Length of Value: {}", length_value);
-        println!("Value: {}", value);
-        if let Some(x) = expiry {
-            println!("Expiry: {:?}", x);
+    // A string, with integers as their digits and LZF compressed strings decompressed
+    fn string(&mut self) -> Result<Bytes, (usize, String)> {
+        let length = match self.length_or_encoding()? {
+            Ok(x) => x,
+            // Integers of 8, 16 and 32 bits
+            Err(0) => return Ok(int(i8::from_le_bytes(self.array()?) as i64)),
+            Err(1) => return Ok(int(i16::from_le_bytes(self.array()?) as i64)),
+            Err(2) => return Ok(int(i32::from_le_bytes(self.array()?) as i64)),
+            // LZF compressed, the compressed length followed by the original one
+            Err(3) => {
+                let compressed = self.usize()?;
+                let length = self.usize()?;
+                let start = self.index;
+                let data = self.take(compressed)?;
+                return match lzf::decompress(data, length) {
+                    Some(x) => Ok(Bytes::from(x)),
+                    None => Err((start, String::from("invalid LZF compressed string"))),
+                };
+            }
+            Err(_) => return Err(self.error("invalid string encoding")),
+        };
+        match usize::try_from(length) {
+            Ok(x) => self.take(x).map(Bytes::copy_from_slice),
+            Err(_) => Err(self.error("string length out of range")),
         }
+    }
 
-        (expiry, key.to_string(), value.to_string())
+    fn usize(&mut self) -> Result<usize, (usize, String)> {
+        let length = self.length()?;
+        usize::try_from(length).map_err(|_| self.error("length out of range"))
     }
 
-    fn parse_expiry(&mut self) -> Option<SystemTime> {
-        match self.data[self.index] {
-            EXPIRY_MS_FLAG => {
-                self.index += 1;
-                let bytes: [u8; 8] = self.data[self.index..self.index + 8].try_into().unwrap();
-                self.index += 8;
-                let millis = u64::from_le_bytes(bytes);
-                Some(UNIX_EPOCH + Duration::from_millis(millis))
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], (usize, String)> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn byte(&mut self) -> Result<u8, (usize, String)> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], (usize, String)> {
+        match self.data.get(self.index..self.index.saturating_add(count)) {
+            Some(x) => {
+                self.index += count;
+                Ok(x)
             }
-            EXPIRY_S_FLAG => {
-                self.index += 1;
-                let bytes: [u8; 4] = self.data[self.index..self.index + 4].try_into().unwrap();
-                self.index += 4;
-                let secs = u32::from_le_bytes(bytes) as u64;
-                Some(UNIX_EPOCH + Duration::from_secs(secs))
+            None => Err(self.error("unexpected end of file")),
+        }
+    }
+
+    fn error(&self, message: &str) -> (usize, String) {
+        (self.index, String::from(message))
+    }
+}
+
+fn int(value: i64) -> Bytes {
+    Bytes::from(value.to_string())
+}
+
+// Scores in the older encodings are written as text, "inf" and "-inf" included
+fn parse_score(digits: &[u8]) -> Option<f64> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A version 11 file with `body` between the header and EOF, and its checksum
+    fn file(body: &[u8]) -> Vec<u8> {
+        let mut file = b"REDIS0011".to_vec();
+        file.extend_from_slice(body);
+        file.push(EOF_FLAG);
+        file.extend_from_slice(&crc64(&file).to_le_bytes());
+        file
+    }
+
+    fn load(file: Vec<u8>) -> Result<HashMap<String, Entry>, (usize, String)> {
+        let mut databases = RdbParser::new(file).rdb_to_db()?;
+        Ok(databases.pop().unwrap_or_default())
+    }
+
+    #[test]
+    fn compact_encodings_are_loaded_as_plain_values() {
+        let mut body = vec![SELECTDB_FLAG, 0];
+        // 12345 as a 16 bit integer, expiring in the year 2100
+        body.extend_from_slice(&[EXPIRY_S_FLAG, 0x80, 0x66, 0x6b, 0xf4]);
+        body.extend_from_slice(&[STRING_TYPE, 3, b'i', b'n', b't', 0xc1, 0x39, 0x30]);
+        // Ten a's, LZF compressed
+        body.extend_from_slice(&[STRING_TYPE, 3, b'l', b'z', b'f', 0xc3, 5, 10]);
+        body.extend_from_slice(&[0x00, b'a', 0xe0, 0x00, 0x00]);
+        // A quicklist of a listpack node holding "a" and 1, then a plain node
+        body.extend_from_slice(&[LIST_QUICKLIST_2_TYPE, 4, b'l', b'i', b's', b't', 2]);
+        body.extend_from_slice(&[QUICKLIST_PACKED_NODE as u8, 12, 12, 0, 0, 0, 2, 0]);
+        body.extend_from_slice(&[0x81, b'a', 2, 0x01, 1, 0xff]);
+        body.extend_from_slice(&[QUICKLIST_PLAIN_NODE as u8, 1, b'z']);
+        // An intset of -2 and 300, two bytes each
+        body.extend_from_slice(&[SET_INTSET_TYPE, 3, b's', b'e', b't', 12, 2, 0, 0, 0, 2, 0]);
+        body.extend_from_slice(&[0, 0, 0xfe, 0xff, 0x2c, 0x01]);
+        // A ziplist hash of f => 1
+        body.extend_from_slice(&[HASH_ZIPLIST_TYPE, 4, b'h', b'a', b's', b'h', 16]);
+        body.extend_from_slice(&[0; 10]);
+        body.extend_from_slice(&[0, 0x01, b'f', 3, 0xf2, 0xff]);
+        // A sorted set with its scores as text, one of them infinite
+        body.extend_from_slice(&[ZSET_TYPE, 4, b'z', b's', b'e', b't', 2]);
+        body.extend_from_slice(&[1, b'a', 3, b'1', b'.', b'5', 1, b'b', 254]);
+        let db = load(file(&body)).unwrap();
+
+        let string = |key: &str| match &db[key].value {
+            Value::Str(x) => x.to_bytes(),
+            other => panic!("Expected a string, got {:?}", other),
+        };
+        assert_eq!(string("int"), "12345");
+        assert!(db["int"].expires_at.is_some());
+        assert_eq!(string("lzf"), "aaaaaaaaaa");
+        assert!(db["lzf"].expires_at.is_none());
+        match &db["list"].value {
+            Value::List(x) => assert_eq!(x, &VecDeque::from(["a", "1", "z"].map(Bytes::from))),
+            other => panic!("Expected a list, got {:?}", other),
+        }
+        match &db["set"].value {
+            Value::Set(x) => assert!(x.contains("-2".as_bytes()) && x.contains("300".as_bytes())),
+            other => panic!("Expected a set, got {:?}", other),
+        }
+        match &db["hash"].value {
+            Value::Hash(x) => assert_eq!(x[&Bytes::from("f")], "1"),
+            other => panic!("Expected a hash, got {:?}", other),
+        }
+        match &db["zset"].value {
+            Value::ZSet(x) => {
+                assert_eq!(x.score(b"a"), Some(1.5));
+                assert_eq!(x.score(b"b"), Some(f64::INFINITY));
             }
-            _ => None,
+            other => panic!("Expected a sorted set, got {:?}", other),
         }
     }
+
+    #[test]
+    fn files_that_dont_make_sense_fail_as_a_whole() {
+        let body = [STRING_TYPE, 1, b'k', 1, b'v'];
+        let mut corrupt = file(&body);
+        corrupt[13] = b'x';
+        assert_eq!(
+            load(corrupt).unwrap_err().0,
+            file(&body).len() - 8,
+            "a checksum that doesn't match"
+        );
+        // Checksums of 0 aren't checked
+        let mut unchecked = file(&body);
+        let length = unchecked.len();
+        unchecked[length - 8..].fill(0);
+        assert_eq!(load(unchecked).unwrap().len(), 1);
+
+        let mut truncated = file(&body);
+        truncated.truncate(12);
+        assert_eq!(
+            load(truncated).unwrap_err(),
+            (12, String::from("unexpected end of file"))
+        );
+        // Module values
+        let body = [STRING_TYPE, 1, b'k', 1, b'v', 7, 1, b'm'];
+        assert_eq!(
+            load(file(&body)).unwrap_err(),
+            (17, String::from("unsupported value type 7"))
+        );
+        // A listpack cut short
+        let body = [SET_LISTPACK_TYPE, 1, b's', 8, 8, 0, 0, 0, 1, 0, 0x81, b'a'];
+        assert_eq!(
+            load(file(&body)).unwrap_err().1,
+            "compact encoding ends early"
+        );
+    }
 }
@@ -0,0 +1,61 @@
+// The CRC-64/Jones checksum RDB files end with, as in Redis's crc64.c. Reflected, with neither an
+// initial value nor a final xor.
+const POLY: u64 = 0x95ac9329ac4bc9b5;
+
+const TABLE: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ POLY,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+};
+
+// A checksum that's fed as the file is written, or read
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Crc64 {
+    crc: u64,
+}
+
+impl Crc64 {
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.crc = TABLE[((self.crc ^ *byte as u64) & 0xff) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    pub fn value(&self) -> u64 {
+        self.crc
+    }
+}
+
+pub fn crc64(data: &[u8]) -> u64 {
+    let mut crc = Crc64::default();
+    crc.update(data);
+    crc.value()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_redis_check_value() {
+        assert_eq!(crc64(b"123456789"), 0xe9c6d914c4b8d9ca);
+        let mut crc = Crc64::default();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.value(), 0xe9c6d914c4b8d9ca);
+        assert_eq!(crc64(b""), 0);
+    }
+}
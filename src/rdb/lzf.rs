@@ -0,0 +1,58 @@
+// LZF, which Redis compresses long strings in RDB files with, as in its lzf_d.c. The data is a
+// series of literal runs and back references into what's been decompressed so far.
+
+// Decompresses `data` to the `length` bytes it was compressed from. None if it isn't valid LZF
+// or doesn't come out at exactly that length.
+pub fn decompress(data: &[u8], length: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut index = 0;
+    while index < data.len() {
+        let control = data[index] as usize;
+        index += 1;
+        if control < 32 {
+            // A run of control + 1 literal bytes
+            let run = data.get(index..index + control + 1)?;
+            out.extend_from_slice(run);
+            index += control + 1;
+        } else {
+            // A back reference of at least 3 bytes, the length in the top 3 bits and extended by
+            // another byte when they're all set
+            let mut run = control >> 5;
+            if run == 7 {
+                run += *data.get(index)? as usize;
+                index += 1;
+            }
+            let distance = ((control & 0x1f) << 8) + *data.get(index)? as usize + 1;
+            index += 1;
+            let start = out.len().checked_sub(distance)?;
+            // Byte by byte, as the reference can overlap what it's copying out
+            for offset in 0..run + 2 {
+                out.push(out[start + offset]);
+            }
+        }
+        if out.len() > length {
+            return None;
+        }
+    }
+    (out.len() == length).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literals_and_overlapping_references_are_expanded() {
+        // "a", then 9 bytes from 1 back
+        let data = [0x00, b'a', 0xe0, 0x00, 0x00];
+        assert_eq!(decompress(&data, 10), Some(vec![b'a'; 10]));
+        // "abc", then 3 bytes from 3 back
+        let data = [0x02, b'a', b'b', b'c', 0x20, 0x02];
+        assert_eq!(decompress(&data, 6), Some(b"abcabc".to_vec()));
+
+        assert_eq!(decompress(&data, 7), None);
+        // A reference to before the start
+        assert_eq!(decompress(&[0x20, 0x00], 3), None);
+        assert_eq!(decompress(&[0x05, b'a'], 6), None);
+    }
+}
@@ -0,0 +1,243 @@
+use super::{STREAM_DELETED_FLAG, STREAM_SAMEFIELDS_FLAG};
+
+use crate::redis::stream::{StreamEntry, StreamId};
+use crate::redis::string::parse_canonical_int;
+
+use bytes::Bytes;
+
+// The compact encodings Redis keeps small collections in, each saved in an RDB file as a single
+// string: listpacks, and the ziplists, intsets and zipmaps older versions used. They're decoded
+// to their elements, integers as their digits the way commands would see them.
+
+pub fn listpack(data: &[u8]) -> Result<Vec<Bytes>, String> {
+    let mut cursor = Cursor { data, index: 0 };
+    // Total bytes and element count
+    cursor.take(6)?;
+    let mut elements = Vec::new();
+    loop {
+        let start = cursor.index;
+        let first = cursor.byte()?;
+        let element = match first {
+            0xff => break,
+            0x00..=0x7f => int(first as i64),
+            0x80..=0xbf => cursor.bytes((first & 0x3f) as usize)?,
+            // 13 bit signed integers
+            0xc0..=0xdf => {
+                let x = ((first as u16 & 0x1f) << 8) | cursor.byte()? as u16;
+                int(((x << 3) as i16 >> 3) as i64)
+            }
+            0xe0..=0xef => {
+                let length = ((first as usize & 0x0f) << 8) | cursor.byte()? as usize;
+                cursor.bytes(length)?
+            }
+            0xf0 => {
+                let length = u32::from_le_bytes(cursor.array()?);
+                cursor.bytes(length as usize)?
+            }
+            0xf1 => int(i16::from_le_bytes(cursor.array()?) as i64),
+            0xf2 => {
+                let [a, b, c] = cursor.array()?;
+                int((i32::from_le_bytes([0, a, b, c]) >> 8) as i64)
+            }
+            0xf3 => int(i32::from_le_bytes(cursor.array()?) as i64),
+            0xf4 => int(i64::from_le_bytes(cursor.array()?)),
+            other => return Err(format!("invalid listpack encoding {:#x}", other)),
+        };
+        elements.push(element);
+        // The element's length again, only needed to walk the listpack backwards
+        let backlen = match cursor.index - start {
+            0..128 => 1,
+            128..16_384 => 2,
+            16_384..2_097_152 => 3,
+            2_097_152..268_435_456 => 4,
+            _ => 5,
+        };
+        cursor.take(backlen)?;
+    }
+    Ok(elements)
+}
+
+pub fn ziplist(data: &[u8]) -> Result<Vec<Bytes>, String> {
+    let mut cursor = Cursor { data, index: 0 };
+    // Total bytes, the offset of the last entry and the entry count
+    cursor.take(10)?;
+    let mut elements = Vec::new();
+    loop {
+        // Each entry starts with the previous one's length, which 0xff never is
+        match cursor.byte()? {
+            0xff => break,
+            0xfe => {
+                cursor.take(4)?;
+            }
+            _ => (),
+        }
+        let first = cursor.byte()?;
+        let element = match first >> 6 {
+            0b00 => cursor.bytes((first & 0x3f) as usize)?,
+            0b01 => {
+                let length = ((first as usize & 0x3f) << 8) | cursor.byte()? as usize;
+                cursor.bytes(length)?
+            }
+            0b10 => {
+                let length = u32::from_be_bytes(cursor.array()?);
+                cursor.bytes(length as usize)?
+            }
+            _ => match first {
+                0xc0 => int(i16::from_le_bytes(cursor.array()?) as i64),
+                0xd0 => int(i32::from_le_bytes(cursor.array()?) as i64),
+                0xe0 => int(i64::from_le_bytes(cursor.array()?)),
+                0xf0 => {
+                    let [a, b, c] = cursor.array()?;
+                    int((i32::from_le_bytes([0, a, b, c]) >> 8) as i64)
+                }
+                0xfe => int(i8::from_le_bytes(cursor.array()?) as i64),
+                // 0 to 12 stored in the encoding itself
+                0xf1..=0xfd => int((first & 0x0f) as i64 - 1),
+                other => return Err(format!("invalid ziplist encoding {:#x}", other)),
+            },
+        };
+        elements.push(element);
+    }
+    Ok(elements)
+}
+
+// A set of integers, all stored at the width of the widest
+pub fn intset(data: &[u8]) -> Result<Vec<Bytes>, String> {
+    let mut cursor = Cursor { data, index: 0 };
+    let width = u32::from_le_bytes(cursor.array()?);
+    let length = u32::from_le_bytes(cursor.array()?);
+    let mut elements = Vec::new();
+    for _ in 0..length {
+        let value = match width {
+            2 => i16::from_le_bytes(cursor.array()?) as i64,
+            4 => i32::from_le_bytes(cursor.array()?) as i64,
+            8 => i64::from_le_bytes(cursor.array()?),
+            other => return Err(format!("invalid intset encoding {}", other)),
+        };
+        elements.push(int(value));
+    }
+    Ok(elements)
+}
+
+// The hash encoding from before ziplists, fields and values with some free space after each value
+pub fn zipmap(data: &[u8]) -> Result<Vec<(Bytes, Bytes)>, String> {
+    let mut cursor = Cursor { data, index: 0 };
+    // The field count, which saturates
+    cursor.byte()?;
+    let mut pairs = Vec::new();
+    while let Some(length) = cursor.zipmap_length()? {
+        let field = cursor.bytes(length)?;
+        let length = cursor
+            .zipmap_length()?
+            .ok_or_else(|| String::from("zipmap field without a value"))?;
+        let free = cursor.byte()?;
+        let value = cursor.bytes(length)?;
+        cursor.take(free as usize)?;
+        pairs.push((field, value));
+    }
+    Ok(pairs)
+}
+
+// Hashes and sorted sets are stored flattened, each field followed by its value or score
+pub fn pairs(elements: Vec<Bytes>) -> Result<Vec<(Bytes, Bytes)>, String> {
+    if !elements.len().is_multiple_of(2) {
+        return Err(String::from(
+            "odd number of elements in a hash or sorted set",
+        ));
+    }
+    let mut elements = elements.into_iter();
+    let mut pairs = Vec::new();
+    while let (Some(x), Some(y)) = (elements.next(), elements.next()) {
+        pairs.push((x, y));
+    }
+    Ok(pairs)
+}
+
+// The entries of a stream node, laid out as RdbWriter writes them: a master entry with the
+// fields of the node's first entry, then each entry with its ID relative to `master`. Entries
+// flagged as deleted are left out.
+pub fn stream_node(master: StreamId, data: &[u8]) -> Result<Vec<(StreamId, StreamEntry)>, String> {
+    let elements = &mut listpack(data)?.into_iter();
+    let count = next_int(elements)?.saturating_add(next_int(elements)?);
+    let master_fields: Vec<Bytes> = (0..next_int(elements)?)
+        .map(|_| next(elements))
+        .collect::<Result<_, _>>()?;
+    next(elements)?;
+
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let flags = next_int(elements)?;
+        let id = StreamId {
+            ms: master.ms.wrapping_add(next_int(elements)? as u64),
+            seq: master.seq.wrapping_add(next_int(elements)? as u64),
+        };
+        let fields: StreamEntry = match flags & STREAM_SAMEFIELDS_FLAG {
+            0 => (0..next_int(elements)?)
+                .map(|_| Ok((next(elements)?, next(elements)?)))
+                .collect::<Result<_, String>>()?,
+            _ => master_fields
+                .iter()
+                .map(|field| Ok((field.clone(), next(elements)?)))
+                .collect::<Result<_, String>>()?,
+        };
+        // How many elements the entry took
+        next(elements)?;
+        if flags & STREAM_DELETED_FLAG == 0 {
+            entries.push((id, fields));
+        }
+    }
+    Ok(entries)
+}
+
+fn next(elements: &mut impl Iterator<Item = Bytes>) -> Result<Bytes, String> {
+    elements
+        .next()
+        .ok_or_else(|| String::from("stream node ends early"))
+}
+
+fn next_int(elements: &mut impl Iterator<Item = Bytes>) -> Result<i64, String> {
+    parse_canonical_int(&next(elements)?)
+        .ok_or_else(|| String::from("expected an integer in a stream node"))
+}
+
+fn int(value: i64) -> Bytes {
+    Bytes::from(value.to_string())
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        match self.data.get(self.index..self.index.saturating_add(count)) {
+            Some(x) => {
+                self.index += count;
+                Ok(x)
+            }
+            None => Err(String::from("compact encoding ends early")),
+        }
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<Bytes, String> {
+        self.take(count).map(Bytes::copy_from_slice)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    // A zipmap length: one byte, or 254 and then four. None for 255, the end of the zipmap.
+    fn zipmap_length(&mut self) -> Result<Option<usize>, String> {
+        match self.byte()? {
+            0xff => Ok(None),
+            0xfe => Ok(Some(u32::from_le_bytes(self.array()?) as usize)),
+            x => Ok(Some(x as usize)),
+        }
+    }
+}
@@ -0,0 +1,462 @@
+use super::crc64::Crc64;
+use super::{
+    AUX_FLAG, EOF_FLAG, EXPIRY_MS_FLAG, HASH_TYPE, LIST_TYPE, MAGIC, RESIZEDB_FLAG, SELECTDB_FLAG,
+    SET_TYPE, STREAM_LISTPACKS_TYPE, STREAM_SAMEFIELDS_FLAG, STRING_TYPE, ZSET_2_TYPE,
+};
+
+use crate::redis::clock::{mstime_to_unix_ms, unix_ms};
+use crate::redis::identity;
+use crate::redis::store::{Entry, Snapshot};
+use crate::redis::stream::{Stream, StreamId};
+use crate::redis::string::{parse_canonical_int, StringValue};
+use crate::redis::value::Value;
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+const VERSION: &[u8] = b"0011";
+// Integers that fit in 8, 16 and 32 bits are stored as such rather than as their digits
+const INT8_ENCODING: u8 = 0xc0;
+const INT16_ENCODING: u8 = 0xc1;
+const INT32_ENCODING: u8 = 0xc2;
+// Entries per stream node, like Redis's stream-node-max-entries
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+// Saves every database in `snapshot`, indexed by number, as the RDB file at `path`. The file is
+// written under a temporary name and renamed into place, so a crash midway never leaves a
+// truncated file behind.
+pub fn write_rdb_file(path: &Path, snapshot: &[Snapshot]) -> io::Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let temp_path = dir.join(format!("temp-{}.rdb", std::process::id()));
+    let result = (|| {
+        let file = write_snapshot(BufWriter::new(File::create(&temp_path)?), snapshot)?;
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&temp_path, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+// A whole RDB file holding every database in `snapshot`, written to `out`
+pub fn write_snapshot<W: Write>(out: W, snapshot: &[Snapshot]) -> io::Result<W> {
+    let mut writer = RdbWriter::new(out);
+    writer.write_header()?;
+    for (index, db) in snapshot.iter().enumerate() {
+        writer.write_db(index, db)?;
+    }
+    writer.finish()
+}
+
+// Encodes the dataset as an RDB file, the way Redis saves it, written to `out` as it goes:
+// the header, then each database, then finish for the EOF opcode and the checksum.
+pub struct RdbWriter<W: Write> {
+    out: W,
+    checksum: Crc64,
+}
+
+impl<W: Write> RdbWriter<W> {
+    pub fn new(out: W) -> Self {
+        RdbWriter {
+            out,
+            checksum: Crc64::default(),
+        }
+    }
+
+    // The magic string and version, followed by the aux fields Redis starts its files with
+    pub fn write_header(&mut self) -> io::Result<()> {
+        self.write(MAGIC)?;
+        self.write(VERSION)?;
+        let ctime = (unix_ms() / 1000).to_string();
+        let bits = usize::BITS.to_string();
+        for (key, value) in [
+            ("redis-ver", identity::VERSION),
+            ("redis-bits", &bits),
+            ("ctime", &ctime),
+        ] {
+            self.write(&[AUX_FLAG])?;
+            self.write_raw_string(key.as_bytes())?;
+            self.write_raw_string(value.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    // Every entry in `snapshot` as database `index`. Empty databases are left out, as in Redis.
+    pub fn write_db(&mut self, index: usize, snapshot: &Snapshot) -> io::Result<()> {
+        let (keys, expires) = snapshot.iter().fold((0, 0), |(keys, expires), (_, entry)| {
+            (keys + 1, expires + entry.expires_at.is_some() as usize)
+        });
+        if keys == 0 {
+            return Ok(());
+        }
+        self.write(&[SELECTDB_FLAG])?;
+        self.write_length(index as u64)?;
+        self.write(&[RESIZEDB_FLAG])?;
+        self.write_length(keys as u64)?;
+        self.write_length(expires as u64)?;
+        for (key, entry) in snapshot.iter() {
+            self.write_entry(key, entry)?;
+        }
+        Ok(())
+    }
+
+    // Ends the file with the EOF opcode and the checksum of everything before it
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write(&[EOF_FLAG])?;
+        let checksum = self.checksum.value().to_le_bytes();
+        self.out.write_all(&checksum)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write_entry(&mut self, key: &str, entry: &Entry) -> io::Result<()> {
+        // Expiration times are absolute UNIX times on disk
+        if let Some(expires_at) = entry.expires_at {
+            self.write(&[EXPIRY_MS_FLAG])?;
+            self.write(&mstime_to_unix_ms(expires_at).to_le_bytes())?;
+        }
+        match &entry.value {
+            Value::Str(x) => {
+                self.write(&[STRING_TYPE])?;
+                self.write_string(key.as_bytes())?;
+                match x {
+                    StringValue::Int(x) => self.write_int_or_string(*x, x.to_string().as_bytes()),
+                    other => self.write_string(&other.to_bytes()),
+                }
+            }
+            Value::List(x) => {
+                self.write(&[LIST_TYPE])?;
+                self.write_string(key.as_bytes())?;
+                self.write_length(x.len() as u64)?;
+                x.iter().try_for_each(|x| self.write_string(x))
+            }
+            Value::Set(x) => {
+                self.write(&[SET_TYPE])?;
+                self.write_string(key.as_bytes())?;
+                self.write_length(x.len() as u64)?;
+                x.iter().try_for_each(|x| self.write_string(x))
+            }
+            Value::Hash(x) => {
+                self.write(&[HASH_TYPE])?;
+                self.write_string(key.as_bytes())?;
+                self.write_length(x.len() as u64)?;
+                x.iter().try_for_each(|(field, value)| {
+                    self.write_string(field)?;
+                    self.write_string(value)
+                })
+            }
+            Value::ZSet(x) => {
+                self.write(&[ZSET_2_TYPE])?;
+                self.write_string(key.as_bytes())?;
+                self.write_length(x.len() as u64)?;
+                x.iter().try_for_each(|(member, score)| {
+                    self.write_string(member)?;
+                    self.write(&score.to_le_bytes())
+                })
+            }
+            Value::Stream(x) => {
+                self.write(&[STREAM_LISTPACKS_TYPE])?;
+                self.write_string(key.as_bytes())?;
+                self.write_stream(x)
+            }
+        }
+    }
+
+    // Entries go in listpack nodes keyed by their first entry's ID, followed by the length and
+    // last ID. Consumer groups aren't supported, so there are none to write.
+    fn write_stream(&mut self, stream: &Stream) -> io::Result<()> {
+        let entries: Vec<_> = stream.entries.iter().collect();
+        let nodes: Vec<_> = entries.chunks(STREAM_NODE_MAX_ENTRIES).collect();
+        self.write_length(nodes.len() as u64)?;
+        for node in nodes {
+            let (master_id, _) = node[0];
+            let mut key = master_id.ms.to_be_bytes().to_vec();
+            key.extend_from_slice(&master_id.seq.to_be_bytes());
+            self.write_string(&key)?;
+            self.write_string(&stream_node_listpack(node))?;
+        }
+        self.write_length(stream.len() as u64)?;
+        self.write_length(stream.last_id.ms)?;
+        self.write_length(stream.last_id.seq)?;
+        self.write_length(0)
+    }
+
+    fn write_string(&mut self, data: &[u8]) -> io::Result<()> {
+        match parse_canonical_int(data) {
+            Some(x) => self.write_int_or_string(x, data),
+            None => self.write_raw_string(data),
+        }
+    }
+
+    fn write_raw_string(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_length(data.len() as u64)?;
+        self.write(data)
+    }
+
+    fn write_int_or_string(&mut self, value: i64, digits: &[u8]) -> io::Result<()> {
+        if let Ok(x) = i8::try_from(value) {
+            return self.write(&[&[INT8_ENCODING][..], &x.to_le_bytes()].concat());
+        }
+        if let Ok(x) = i16::try_from(value) {
+            return self.write(&[&[INT16_ENCODING][..], &x.to_le_bytes()].concat());
+        }
+        if let Ok(x) = i32::try_from(value) {
+            return self.write(&[&[INT32_ENCODING][..], &x.to_le_bytes()].concat());
+        }
+        self.write_raw_string(digits)
+    }
+
+    fn write_length(&mut self, length: u64) -> io::Result<()> {
+        match length {
+            0..0x40 => self.write(&[length as u8]),
+            0x40..0x4000 => self.write(&[0x40 | (length >> 8) as u8, length as u8]),
+            0x4000..=0xffff_ffff => {
+                self.write(&[&[0x80][..], &(length as u32).to_be_bytes()].concat())
+            }
+            _ => self.write(&[&[0x81][..], &length.to_be_bytes()].concat()),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.checksum.update(data);
+        self.out.write_all(data)
+    }
+}
+
+// A stream node the way Redis lays it out in a listpack: a master entry with the fields of the
+// node's first entry, then each entry with its ID relative to the master's. Entries with the
+// master's fields only store their values.
+fn stream_node_listpack(node: &[(&StreamId, &Vec<(bytes::Bytes, bytes::Bytes)>)]) -> Vec<u8> {
+    let (master_id, master_fields) = node[0];
+    let mut listpack = Listpack::default();
+    listpack.push_int(node.len() as i64);
+    // Deleted entries
+    listpack.push_int(0);
+    listpack.push_int(master_fields.len() as i64);
+    for (field, _) in master_fields.iter() {
+        listpack.push_string(field);
+    }
+    listpack.push_int(0);
+
+    for (id, fields) in node {
+        let same_fields = fields.len() == master_fields.len()
+            && fields
+                .iter()
+                .zip(master_fields.iter())
+                .all(|((x, _), (y, _))| x == y);
+        listpack.push_int(if same_fields {
+            STREAM_SAMEFIELDS_FLAG
+        } else {
+            0
+        });
+        listpack.push_int(id.ms.wrapping_sub(master_id.ms) as i64);
+        listpack.push_int(id.seq.wrapping_sub(master_id.seq) as i64);
+        if same_fields {
+            for (_, value) in fields.iter() {
+                listpack.push_string(value);
+            }
+        } else {
+            listpack.push_int(fields.len() as i64);
+            for (field, value) in fields.iter() {
+                listpack.push_string(field);
+                listpack.push_string(value);
+            }
+        }
+        // How many elements the entry took, so that it can be walked backwards
+        let elements = match same_fields {
+            true => 3 + fields.len(),
+            false => 4 + 2 * fields.len(),
+        };
+        listpack.push_int(elements as i64);
+    }
+    listpack.finish()
+}
+
+// Redis's listpack format: a header with the total size and element count, then each element
+// with its encoding and, for walking backwards, how long it is
+#[derive(Default)]
+struct Listpack {
+    elements: Vec<u8>,
+    count: usize,
+}
+
+impl Listpack {
+    fn push_int(&mut self, value: i64) {
+        let mut element = Vec::new();
+        match value {
+            0..=127 => element.push(value as u8),
+            -4096..=4095 => {
+                let x = (value as u16) & 0x1fff;
+                element.extend_from_slice(&[0xc0 | (x >> 8) as u8, x as u8]);
+            }
+            _ if i16::try_from(value).is_ok() => {
+                element.push(0xf1);
+                element.extend_from_slice(&(value as i16).to_le_bytes());
+            }
+            -8_388_608..=8_388_607 => {
+                element.push(0xf2);
+                element.extend_from_slice(&(value as i32).to_le_bytes()[..3]);
+            }
+            _ if i32::try_from(value).is_ok() => {
+                element.push(0xf3);
+                element.extend_from_slice(&(value as i32).to_le_bytes());
+            }
+            _ => {
+                element.push(0xf4);
+                element.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        self.push(element);
+    }
+
+    fn push_string(&mut self, data: &[u8]) {
+        let mut element = Vec::with_capacity(data.len() + 5);
+        match data.len() {
+            0..64 => element.push(0x80 | data.len() as u8),
+            64..4096 => {
+                element.extend_from_slice(&[0xe0 | (data.len() >> 8) as u8, data.len() as u8])
+            }
+            _ => {
+                element.push(0xf0);
+                element.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            }
+        }
+        element.extend_from_slice(data);
+        self.push(element);
+    }
+
+    fn push(&mut self, element: Vec<u8>) {
+        let length = element.len();
+        self.elements.extend_from_slice(&element);
+        // The length is written so that it reads right to left, seven bits per byte, every byte
+        // but the leftmost flagged as continuing
+        let mut backlen = vec![(length & 127) as u8];
+        let mut rest = length >> 7;
+        while rest > 0 {
+            backlen[0] |= 128;
+            backlen.insert(0, (rest & 127) as u8);
+            rest >>= 7;
+        }
+        self.elements.extend_from_slice(&backlen);
+        self.count += 1;
+    }
+
+    fn finish(self) -> Vec<u8> {
+        // Total bytes and element count, the count saturating for very long listpacks
+        let total = 6 + self.elements.len() + 1;
+        let mut listpack = Vec::with_capacity(total);
+        listpack.extend_from_slice(&(total as u32).to_le_bytes());
+        listpack.extend_from_slice(&(self.count.min(u16::MAX as usize) as u16).to_le_bytes());
+        listpack.extend_from_slice(&self.elements);
+        listpack.push(0xff);
+        listpack
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdb::crc64::crc64;
+    use crate::rdb::rdb_length;
+    use crate::redis::sorted_set::{ListpackLimits, SortedSet};
+    use crate::redis::store::Store;
+
+    use bytes::Bytes;
+    use std::collections::{HashMap, HashSet, VecDeque};
+
+    #[test]
+    fn every_type_is_written_in_a_well_formed_file() {
+        let mut entries = HashMap::new();
+        let strings = [
+            ("int", Bytes::from("-3")),
+            ("big", Bytes::from("12345678901")),
+            ("text", Bytes::from("hello")),
+            ("long", Bytes::from(vec![b'x'; 20_000])),
+        ];
+        for (key, value) in strings {
+            let value = Value::Str(StringValue::from_bytes(value));
+            entries.insert(key.to_string(), Entry::new(value));
+        }
+        let list = VecDeque::from([Bytes::from("a"), Bytes::from("7")]);
+        entries.insert(String::from("list"), Entry::new(Value::List(list)));
+        let set = HashSet::from([Bytes::from("a")]);
+        entries.insert(String::from("set"), Entry::new(Value::Set(set)));
+        let hash = HashMap::from([(Bytes::from("f"), Bytes::from("v"))]);
+        entries.insert(String::from("hash"), Entry::new(Value::Hash(hash)));
+        let mut zset = SortedSet::new();
+        zset.insert(Bytes::from("m"), 1.5, &ListpackLimits::default());
+        entries.insert(String::from("zset"), Entry::new(Value::ZSet(zset)));
+        let mut stream = Stream::new();
+        for ms in 1..=150 {
+            let fields = match ms % 2 {
+                0 => vec![(Bytes::from("f"), Bytes::from("v"))],
+                _ => vec![(Bytes::from("g"), Bytes::from(vec![b'y'; 100]))],
+            };
+            stream
+                .add(
+                    crate::redis::stream::IdSpec::Explicit(StreamId { ms, seq: 0 }),
+                    fields,
+                    0,
+                )
+                .unwrap();
+        }
+        entries.insert(String::from("stream"), Entry::new(Value::Stream(stream)));
+        let mut expiring = Entry::new(Value::Str(StringValue::from_bytes(Bytes::from("v"))));
+        expiring.expires_at = Some(crate::redis::clock::mstime() + 60_000);
+        entries.insert(String::from("expiring"), expiring);
+        let store = Store::from_entries(entries);
+
+        let mut writer = RdbWriter::new(Vec::new());
+        writer.write_header().unwrap();
+        let snapshot = store.snapshot();
+        writer.write_db(0, &snapshot).unwrap();
+        // Empty databases aren't written at all
+        writer.write_db(1, &Store::new().snapshot()).unwrap();
+        let file = writer.finish().unwrap();
+
+        assert_eq!(&file[..9], b"REDIS0011");
+        assert_eq!(rdb_length(&file), Ok(file.len()));
+        let (data, checksum) = file.split_at(file.len() - 8);
+        assert_eq!(crc64(data).to_le_bytes(), checksum);
+
+        // Everything comes back as it was, and the empty database not at all
+        let loaded = crate::rdb::RdbParser::new(file).rdb_to_db().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].len(), snapshot.iter().count());
+        for (key, entry) in snapshot.iter() {
+            let loaded = &loaded[0][key.as_str()];
+            assert_eq!(loaded.value.type_name(), entry.value.type_name());
+            assert_eq!(loaded.value.len(), entry.value.len());
+            assert_eq!(loaded.expires_at.is_some(), entry.expires_at.is_some());
+        }
+        match &loaded[0]["stream"].value {
+            Value::Stream(x) => {
+                assert_eq!(x.last_id, StreamId { ms: 150, seq: 0 });
+                assert_eq!(x.entries[&StreamId { ms: 150, seq: 0 }][0].0, "f");
+                assert_eq!(x.entries[&StreamId { ms: 149, seq: 0 }][0].1.len(), 100);
+            }
+            other => panic!("Expected a stream, got {:?}", other),
+        }
+        match &loaded[0]["int"].value {
+            Value::Str(x) => assert_eq!(x.to_bytes(), "-3"),
+            other => panic!("Expected a string, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn listpacks_match_redis_layout() {
+        let mut listpack = Listpack::default();
+        listpack.push_string(b"a");
+        listpack.push_int(1);
+        listpack.push_int(-1);
+        listpack.push_string(&[b'z'; 200]);
+        let encoded = listpack.finish();
+        assert_eq!(&encoded[..6], &[219, 0, 0, 0, 4, 0]);
+        assert_eq!(&encoded[6..14], &[0x81, b'a', 2, 1, 1, 0xdf, 0xff, 2]);
+        // A 12 bit string length, and a two byte backlen for the 202 bytes it took
+        assert_eq!(&encoded[14..16], &[0xe0, 200]);
+        assert_eq!(&encoded[216..], &[1, 0xca, 0xff]);
+    }
+}
@@ -1,26 +1,88 @@
+use self::acl::{Acl, DEFAULT_USER, NOAUTH_ERROR};
+use self::admission::{Admission, Admitted};
+use self::backlog::Backlog;
+use self::blocking::Blocking;
+use self::clients::{Clients, ConnectionInfo};
 use self::commands::Command;
-use self::processing::*;
-use self::replica::is_stream_replica;
-use self::synchronize::construct_rdb;
+use self::connection::{Connection, ConnectionWriter, Listener, Transport};
+use self::dispatch::Dispatcher;
+use self::identity::Identity;
+use self::keyspace::{Keyspace, KeyspaceMode};
+use self::latency::LatencyStats;
+use self::monitor::Monitors;
+use self::output::{ClientClass, OutputBuffer, Reply};
+use self::persistence::Persistence;
+use self::pubsub::PubSub;
+use self::replica::{LinkState, MasterLink, ReplicaLink, ReplicaOffset};
+use self::scripting::Scripts;
+use self::slowlog::SlowLog;
+use self::state::{ClientContext, Replication, ServerState, Stats, NO_DB_SELECTED};
+use self::store::Store;
 
-use crate::config::Config;
+use crate::aof::writer::AofWriter;
+use crate::config::{Config, LiveConfig};
+use crate::log;
 use crate::rdb::RdbParser;
-use crate::resp::resp_deserializer::RespParser;
+use crate::resp::resp_deserializer::{ProtocolError, RespParser};
+use crate::resp::resp_serializer::serialize_resp_data;
+use crate::resp::{shared, RespType};
+use crate::server::{wait_for_shutdown, ServerError, ShutdownHandle};
+use crate::{notice, verbose, warning};
 
+use bytes::{Bytes, BytesMut};
 use core::fmt;
 use std::collections::HashMap;
-use std::sync::Arc;
-use std::time::SystemTime;
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, watch, RwLock};
 use tokio::task;
+use tokio::time::{self, Duration};
 
+pub mod acl;
+pub mod admission;
+pub mod backlog;
+pub mod bitmap;
+pub mod blocking;
+pub mod clients;
+pub mod clock;
+pub mod command_table;
 pub mod commands;
+pub mod connection;
+pub mod crash;
+pub mod cron;
+pub mod dispatch;
+pub mod eviction;
+pub mod expiry;
+pub mod glob;
+pub mod identity;
+pub mod info;
+pub mod keyspace;
+pub mod latency;
+pub mod lazyfree;
+pub mod lru;
+pub mod monitor;
+pub mod notify;
+pub mod output;
+pub mod persistence;
 pub mod processing;
+pub mod pubsub;
+pub mod range;
 pub mod replica;
+pub mod scripting;
+pub mod sha1;
+pub mod shutdown;
+pub mod slowlog;
+pub mod sorted_set;
+pub mod state;
+pub mod store;
+pub mod stream;
+pub mod string;
 pub mod synchronize;
+pub mod value;
+pub mod workers;
 
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum RedisState {
@@ -37,226 +99,725 @@ impl fmt::Display for RedisState {
     }
 }
 
+const MAX_BATCHED_REPLIES: usize = 64 * 1024;
+const HANDSHAKE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+pub type ReplicaConnections = Arc<RwLock<HashMap<i32, ReplicaLink>>>;
+
 pub struct Redis {
-    database: Arc<Mutex<HashMap<String, String>>>,
-    expiry: Arc<RwLock<HashMap<String, SystemTime>>>,
-    config: Arc<Config>,
-    listener: TcpListener,
-    replica_connections: Arc<RwLock<Option<HashMap<i32, Arc<RwLock<TcpStream>>>>>>,
-    master_connection: Option<Arc<RwLock<TcpStream>>>,
+    server: Arc<ServerState>,
+    // Taken by listen, which hands it to the first worker in thread-per-core mode
+    listener: Option<TcpListener>,
+    // Listeners besides the TCP port, like the unix socket, served from this runtime
+    other_listeners: Vec<Listener>,
+    shutdown: watch::Receiver<bool>,
+    // Worker runtimes, in thread-per-core mode only
+    workers: Vec<Handle>,
 }
 
-impl Redis {
-    async fn handle_conn(&mut self, stream: Arc<RwLock<TcpStream>>, parser: Option<RespParser>) {
-        let database: Arc<Mutex<HashMap<String, String>>> = Arc::clone(&self.database);
-        let config = Arc::clone(&self.config);
-        let replica_connections = Arc::clone(&self.replica_connections);
-        let expiry = Arc::clone(&self.expiry);
-        // Each connection should have a dedicated parser
-        let mut parser = match parser {
-            Some(x) => x,
-            None => RespParser::new(String::from(""), Arc::clone(&stream)),
+// Everything a connection task needs, independent of which runtime it runs on
+#[derive(Clone)]
+struct ConnectionContext {
+    dispatcher: Dispatcher,
+    shutdown: watch::Receiver<bool>,
+}
+
+// Returns false if the connection has to be closed, either because it's gone or because the
+// client isn't reading its replies fast enough
+async fn flush_replies(
+    stream: &ConnectionWriter,
+    output: &mut OutputBuffer,
+    replies: &mut BytesMut,
+) -> bool {
+    if replies.is_empty() {
+        return true;
+    }
+    let result = match output.write(stream, replies) {
+        Ok(()) => output.flush_all(stream).await,
+        Err(e) => Err(e),
+    };
+    replies.clear();
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            verbose!("Closing connection: {}", e);
+            false
+        }
+    }
+}
+
+// Writes a large bulk string reply behind whatever is already batched, streaming the value from
+// where it's stored instead of copying it into the batch
+async fn stream_bulk(
+    stream: &ConnectionWriter,
+    output: &mut OutputBuffer,
+    replies: &mut BytesMut,
+    data: &[u8],
+) -> bool {
+    let mut header = Vec::new();
+    shared::write_bulk_header(data.len(), &mut header);
+    replies.extend_from_slice(&header);
+    if !flush_replies(stream, output, replies).await {
+        return false;
+    }
+    if let Err(e) = output.write_streamed(stream, data).await {
+        verbose!("Closing connection: {}", e);
+        return false;
+    }
+    replies.extend_from_slice(b"\r\n");
+    true
+}
+
+async fn accept_connections(
+    listener: Listener,
+    context: ConnectionContext,
+) -> Result<(), ServerError> {
+    let mut shutdown = context.shutdown.clone();
+    loop {
+        let mut stream = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = wait_for_shutdown(&mut shutdown) => {
+                listener.close();
+                return Ok(());
+            }
         };
-        let mut total_bytes_processed = 0;
-        let mut write_bytes_processed = 0;
-        let mut write_commands_to_process = 0;
-        task::spawn(async move {
-            loop {
-                // If a stream is a replica stream, don't automatically listen to it after the
-                // handshake
-                let command: Command;
-                if !is_stream_replica(Arc::clone(&replica_connections), Arc::clone(&stream)).await {
-                    if let Some((comm, bytes)) = parser.parse_command().await {
-                        // Increase bytes processed every time we process a command
-                        command = comm;
-                        if config.role == RedisState::Replica {
-                            total_bytes_processed += bytes;
-                        } else if command.is_write() {
-                            write_bytes_processed += bytes;
-                            write_commands_to_process += 1;
-                        }
-                    } else {
-                        // other side has ended connection
+        verbose!("Accepted {}", stream.peer());
+        let server = context.dispatcher.server();
+        let admitted = match server.admission.admit(stream.peer_ip()) {
+            Ok(x) => x,
+            Err(rejection) => {
+                // Like Redis when maxclients is reached, a best effort error and then goodbye
+                warning!("Rejecting connection from {}: {}", stream.peer(), rejection);
+                server
+                    .stats
+                    .rejected_connections
+                    .fetch_add(1, Ordering::Relaxed);
+                let error = serialize_resp_data(RespType::Error(rejection.to_string()));
+                tokio::spawn(async move {
+                    let _ = stream.write_all(&error).await;
+                });
+                continue;
+            }
+        };
+        server
+            .stats
+            .connections_received
+            .fetch_add(1, Ordering::Relaxed);
+        let config = server.config.current();
+        if config.tcp_keepalive > 0 {
+            if let Err(e) = stream.set_keepalive(config.tcp_keepalive) {
+                warning!("Unable to set keepalive on {}: {}", stream.peer(), e);
+            }
+        }
+        let (reader, writer) = stream.into_split();
+        let parser = RespParser::new(BytesMut::new(), reader).with_limits(config.frame_limits());
+        handle_conn(context.clone(), parser, writer, false, Some(admitted));
+    }
+}
+
+// Serves a connection from a task on the current runtime, which finishes once it's closed.
+// Commands are read by a task of their own, see CommandReader, so that the connection is read
+// from while replies are being written to it. `admitted` is held until then, see Admission.
+fn handle_conn(
+    context: ConnectionContext,
+    parser: RespParser,
+    mut stream: ConnectionWriter,
+    is_master_link: bool,
+    admitted: Option<Admitted>,
+) -> task::JoinHandle<()> {
+    let ConnectionContext {
+        dispatcher,
+        mut shutdown,
+    } = context;
+    let server = Arc::clone(dispatcher.server());
+    let config = server.config.current();
+    let mut client = ClientContext::new(server.stats.next_client_id());
+    let mut replies = BytesMut::new();
+    let mut output = OutputBuffer::new(
+        ClientClass::Normal,
+        config.client_output_buffer_limits.normal,
+    );
+    let (peer, transport) = (stream.peer(), stream.transport());
+    client.addr = Some(peer.clone());
+    client.transport = Some(transport);
+    // Connections made before the default user needed a password stay logged in, as in Redis
+    client.authenticated = !server
+        .acl
+        .requires_password(DEFAULT_USER, config.requirepass.as_deref());
+    if is_master_link {
+        client.authenticated = true;
+        client.user = None;
+        // A continued stream goes on in the database it last selected
+        client.db = server.replication.master_db.load(Ordering::SeqCst);
+    }
+    // Everything logged about the connection says which it is
+    let log_context = match is_master_link {
+        true => format!("master id={} addr={}", client.id, peer),
+        false => format!("client id={} addr={}", client.id, peer),
+    };
+    let info = Arc::new(ConnectionInfo::new(&client, peer, transport));
+    let registered = server.clients.register(&info);
+    let mut killed = info.killed();
+    let (connection_info, reporting_server) = (Arc::clone(&info), Arc::clone(&server));
+    let reader_context = log_context.clone();
+    let connection = async move {
+        let (info, _admitted, _registered) = (connection_info, admitted, registered);
+        let spawn_reader = |parser| {
+            let context = reader_context.clone();
+            CommandReader::spawn(parser, is_master_link, &info, &server, context)
+        };
+        let mut reader = spawn_reader(parser);
+        loop {
+            // Like Redis, never time out masters, subscribers or monitors
+            let exempt_from_timeout =
+                is_master_link || client.subscriptions.is_active() || client.monitoring.is_active();
+            let parsed = tokio::select! {
+                parsed = reader.next() => parsed,
+                // Messages go out as soon as they're published, between commands
+                message = client.subscriptions.next_message() => {
+                    replies.extend_from_slice(&message.serialize(client.protocol));
+                    if !flush_replies(&stream, &mut output, &mut replies).await {
+                        let _ = stream.shutdown().await;
                         break;
                     }
-                } else {
-                    tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
                     continue;
                 }
-
-                // If command is write and this is the master, propagate command to all replicas
-                if config.role == RedisState::Master && command.is_write() {
-                    let replica_connections = replica_connections.read().await;
-                    if let Some(ref connections) = *replica_connections {
-                        for (_fd, replica_stream) in connections.iter() {
-                            synchronize::propagate_command_to_replica(
-                                Arc::clone(&replica_stream),
-                                &command,
-                            )
-                            .await;
-                        }
+                line = client.monitoring.next_line() => {
+                    replies.extend_from_slice(&line);
+                    if !flush_replies(&stream, &mut output, &mut replies).await {
+                        let _ = stream.shutdown().await;
+                        break;
                     }
+                    continue;
                 }
-
-                match command {
-                    Command::Echo(message) => {
-                        handle_echo(message, Arc::clone(&stream), config.role).await;
-                    }
-                    Command::Ping => {
-                        handle_ping(Arc::clone(&stream), config.role).await;
-                    }
-                    Command::Set(key, value, lifespan) => {
-                        handle_set(
-                            key,
-                            value,
-                            lifespan,
-                            Arc::clone(&stream),
-                            Arc::clone(&database),
-                            Arc::clone(&expiry),
-                            config.role,
-                        )
-                        .await;
+                _ = wait_for_shutdown(&mut shutdown) => break,
+                _ = idle_timeout(&server, exempt_from_timeout) => {
+                    verbose!("Closing idle client");
+                    let _ = stream.shutdown().await;
+                    break;
+                }
+                // CLIENT KILL, after which whatever replies are waiting still go out
+                _ = wait_for_shutdown(&mut killed) => {
+                    flush_replies(&stream, &mut output, &mut replies).await;
+                    let _ = stream.shutdown().await;
+                    break;
+                }
+            };
+            let ParsedCommand {
+                command,
+                bytes,
+                forwarded,
+                more_buffered,
+            } = match parsed {
+                Ok(Some(x)) => x,
+                // other side has ended connection
+                Ok(None) => break,
+                Err(e) => {
+                    // Like Redis, explain the error to clients before hanging up, but never
+                    // reply to a master
+                    verbose!("Closing connection: {}", e);
+                    if !is_master_link {
+                        let error = RespType::Error(format!("ERR {}", e));
+                        replies.extend_from_slice(&serialize_resp_data(error));
                     }
-                    Command::Get(key) => {
-                        handle_get(
-                            key,
-                            Arc::clone(&stream),
-                            Arc::clone(&database),
-                            Arc::clone(&expiry),
-                        )
-                        .await;
+                    flush_replies(&stream, &mut output, &mut replies).await;
+                    let _ = stream.shutdown().await;
+                    break;
+                }
+            };
+
+            // Applying the rest of a stream that's stopped making sense would only make things
+            // worse, so the link is dropped, and the replica reconnects for a full resync
+            let command = match command {
+                Ok(command) => command,
+                Err(e) if is_master_link => {
+                    warning!("Replication stream is out of sync, {}. Resyncing", e);
+                    *server.replication.master_replid.lock().unwrap() = None;
+                    let _ = stream.shutdown().await;
+                    break;
+                }
+                // A command its parser rejects is answered with the error, and the connection
+                // carries on with the next one
+                Err(e) => {
+                    replies.extend_from_slice(&dispatcher.reject(&e, &mut client).into_vec());
+                    if !more_buffered && !flush_replies(&stream, &mut output, &mut replies).await {
+                        let _ = stream.shutdown().await;
+                        break;
                     }
-                    Command::Info(arg) => {
-                        handle_info(arg, Arc::clone(&config), Arc::clone(&stream)).await;
+                    continue;
+                }
+            };
+            if is_master_link && !command.is_replicated() {
+                warning!(
+                    "Replication stream is out of sync, masters don't send {}. Resyncing",
+                    command.name()
+                );
+                *server.replication.master_replid.lock().unwrap() = None;
+                let _ = stream.shutdown().await;
+                break;
+            }
+
+            let applying = match is_master_link {
+                true => Some(server.replication.applying.lock().await),
+                false => None,
+            };
+            // Our master only reads the answers to the GETACKs it asks for our offset with
+            let replies_expected = !is_master_link
+                || matches!(&command, Command::ReplConf(x, _) if x.eq_ignore_ascii_case("getack"));
+            let quit = matches!(command, Command::Quit);
+            let psync = matches!(command, Command::Psync(_, _));
+
+            let response = match command {
+                // The reader stops after a PSYNC, see CommandReader, and only carries on if it's
+                // refused
+                Command::Psync(_, _) if !client.authenticated => Reply::Serialized(
+                    serialize_resp_data(RespType::Error(NOAUTH_ERROR.to_string())),
+                ),
+                // Replicas find their master by host and port, so never over a unix socket
+                Command::Psync(_, _) if transport != Transport::Tcp => {
+                    let error = String::from("ERR PSYNC is only supported over TCP");
+                    Reply::Serialized(serialize_resp_data(RespType::Error(error)))
+                }
+                // A replica passes on its master's stream, so it needs to be following one
+                Command::Psync(_, _)
+                    if server.config.current().role == RedisState::Replica
+                        && server.replication.link.state() != LinkState::Up =>
+                {
+                    let error =
+                        String::from("NOMASTERLINK Can't SYNC while not connected with my master");
+                    Reply::Serialized(serialize_resp_data(RespType::Error(error)))
+                }
+                Command::Psync(replication_id, offset) => {
+                    // The RDB transfer is written straight to the stream, so anything queued
+                    // ahead of it has to go out first
+                    if !flush_replies(&stream, &mut output, &mut replies).await {
+                        break;
                     }
-                    Command::ReplConf(arg1, _arg2) => {
-                        match arg1.to_lowercase().as_str() {
-                            "getack" => {
-                                if config.role == RedisState::Master {
-                                    panic!("Recieving REPLCONF command as a master, should exclusively be sent by masters to replicas");
-                                }
-                                replica::handle_replconf_getack(
-                                    Arc::clone(&stream),
-                                    total_bytes_processed - 37,
-                                )
-                                .await;
-                            }
-                            _ => replica::handle_replconf(Arc::clone(&stream)).await,
-                        };
+                    // The connection now belongs to replication
+                    let (read_half, buffered) = match reader.into_parser().await {
+                        Some(x) => x.into_parts(),
+                        None => break,
+                    };
+                    let stream = match read_half.reunite(stream) {
+                        Ok(Connection::Tcp(x)) => x,
+                        Ok(_) => panic!("Expected replicas to be connected over TCP"),
+                        Err(e) => panic!("Expected the halves of one connection: {}", e),
+                    };
+                    if let Err(e) = replica::serve_psync(
+                        &server,
+                        stream,
+                        buffered,
+                        replication_id,
+                        offset,
+                        client.listening_port,
+                        client.capa_eof,
+                    )
+                    .await
+                    {
+                        warning!("Full resync with replica failed: {}", e);
                     }
-                    Command::Psync(replication_id, offset) => {
-                        if config.role == RedisState::Replica {
-                            panic!("Recieving PSYNC command as a replica, should exclusively be sent by replicas to masters");
-                        }
-                        replica::handle_psync(
-                            replication_id,
-                            offset,
-                            Arc::clone(&stream),
-                            Arc::clone(&database),
-                        )
-                        .await;
-
-                        use std::os::unix::io::AsRawFd;
-                        let mut guard = replica_connections.write().await;
-                        match *guard {
-                            Some(ref mut connections) => {
-                                let fd = stream.read().await.as_raw_fd();
-                                let _ = connections.insert(fd, Arc::clone(&stream));
-                            }
-                            None => panic!("Master should have a hashmap dedicated to storing connections to replicas"),
-                        }
+                    break;
+                }
+                command => {
+                    info.start_command(command.name());
+                    let reply = dispatcher.dispatch(command, &mut client).await;
+                    info.finish_command(&client);
+                    let class = match client.subscriptions.is_active() {
+                        true => ClientClass::Pubsub,
+                        false => ClientClass::Normal,
+                    };
+                    output.set_class(class, config.client_output_buffer_limits.get(class));
+                    reply
+                }
+            };
+            if let Some(forwarded) = forwarded {
+                synchronize::propagate(&server.replication, &forwarded).await;
+                server.replication.offset.advance(bytes);
+                server
+                    .replication
+                    .master_db
+                    .store(client.db, Ordering::SeqCst);
+            }
+            drop(applying);
+            if psync {
+                reader = match reader.into_parser().await {
+                    Some(x) => spawn_reader(x),
+                    None => break,
+                };
+            }
+            match response {
+                _ if !replies_expected => (),
+                Reply::Serialized(x) => replies.extend_from_slice(&x),
+                Reply::Bulk(x) => {
+                    if !stream_bulk(&stream, &mut output, &mut replies, &x).await {
+                        let _ = stream.shutdown().await;
+                        break;
                     }
-                    Command::Wait(replicas_to_wait_for, timeout) => {
-                        if config.role == RedisState::Replica {
-                            panic!(
-                                "Replica recieved WAIT command as replica - only meant for MASTER"
-                            );
+                }
+            }
+
+            // Replies to pipelined commands are batched, and only written out once every
+            // command already sitting in the read buffer has been processed, or once the batch
+            // gets big. Nothing more is read until they've been written, so a client that
+            // doesn't read its replies stops being served.
+            if (quit || replies.len() >= MAX_BATCHED_REPLIES || !more_buffered)
+                && !flush_replies(&stream, &mut output, &mut replies).await
+            {
+                let _ = stream.shutdown().await;
+                break;
+            }
+            if quit {
+                let _ = stream.shutdown().await;
+                break;
+            }
+        }
+        server
+            .pubsub
+            .unsubscribe_all(client.id, &mut client.subscriptions);
+        server.monitors.detach(client.id);
+    };
+    // A panic while serving the connection closes it, and nothing else
+    task::spawn(log::scope(log_context, async move {
+        if let Err(crash) = crash::contain(connection).await {
+            crash::report(&crash, &info, &reporting_server);
+        }
+    }))
+}
+
+// Finishes once the client has gone `timeout` seconds without sending a command, unless it's
+// `exempt`. The timer starts over with every command, and picks up CONFIG SET timeout then.
+// Blocked clients are waiting on a command, so they're never idle.
+async fn idle_timeout(server: &ServerState, exempt: bool) {
+    let timeout = server.config.current().timeout;
+    if timeout == 0 || exempt {
+        return std::future::pending().await;
+    }
+    time::sleep(Duration::from_secs(timeout)).await
+}
+
+// How many parsed commands the reader may get ahead of the connection task by
+const READ_AHEAD_COMMANDS: usize = 16;
+
+struct ParsedCommand {
+    // Or the error to reply with, if it isn't a valid command
+    command: Result<Command, String>,
+    // How many bytes it came in as
+    bytes: usize,
+    // The bytes themselves, on the master link, which passes them on to our own replicas
+    forwarded: Option<Vec<u8>>,
+    // Whether another command had already been read behind it, so that its reply can wait to
+    // be written along with the next one's
+    more_buffered: bool,
+}
+
+// Reads a connection's commands from a task of its own, handing them to the connection task as
+// they're parsed. It stops at the first PSYNC, since that may hand the connection over to
+// replication, and gives its parser back through into_parser. Dropping the reader stops it. A
+// panic while parsing is reported like one in the connection task, and ends the connection.
+struct CommandReader {
+    commands: mpsc::Receiver<Result<ParsedCommand, ProtocolError>>,
+    task: Option<task::JoinHandle<Option<RespParser>>>,
+}
+
+impl CommandReader {
+    fn spawn(
+        mut parser: RespParser,
+        is_master_link: bool,
+        info: &Arc<ConnectionInfo>,
+        server: &Arc<ServerState>,
+        log_context: String,
+    ) -> Self {
+        let (sender, commands) = mpsc::channel(READ_AHEAD_COMMANDS);
+        let (info, server) = (Arc::clone(info), Arc::clone(server));
+        let read = async move {
+            loop {
+                let (command, bytes, forwarded) =
+                    match next_command(&mut parser, is_master_link).await {
+                        Ok(Some(x)) => x,
+                        // Dropping the sender tells the connection task
+                        Ok(None) => break,
+                        Err(e) => {
+                            let _ = sender.send(Err(e)).await;
+                            break;
                         }
-                        handle_wait(
-                            Arc::clone(&replica_connections),
-                            Arc::clone(&stream),
-                            timeout,
-                            replicas_to_wait_for,
-                            write_bytes_processed,
-                            write_commands_to_process,
-                        )
-                        .await;
-                        write_commands_to_process = 0;
-                    }
-                    Command::ConfigGet(path_type) => {
-                        handle_config_get(Arc::clone(&stream), Arc::clone(&config), path_type)
-                            .await;
-                    }
-                    Command::Keys(selector_arg) => {
-                        handle_keys(Arc::clone(&stream), Arc::clone(&database), selector_arg).await;
-                    }
+                    };
+                let psync = matches!(command, Ok(Command::Psync(_, _)));
+                let parsed = ParsedCommand {
+                    command,
+                    bytes,
+                    forwarded,
+                    more_buffered: parser.has_buffered_command(),
                 };
+                if sender.send(Ok(parsed)).await.is_err() || psync {
+                    break;
+                }
             }
-        });
+            parser
+        };
+        let task = task::spawn(log::scope(log_context, async move {
+            match crash::contain(read).await {
+                Ok(parser) => Some(parser),
+                Err(crash) => {
+                    crash::report(&crash, &info, &server);
+                    None
+                }
+            }
+        }));
+        CommandReader {
+            commands,
+            task: Some(task),
+        }
     }
 
-    pub async fn listen(&mut self) -> Result<Self, Box<(dyn std::error::Error + 'static)>> {
-        match self.config.role {
-            RedisState::Replica => {
-                let parser = replica::perform_handshake(self).await;
-                let master_connection = {
-                    match &self.master_connection {
-                        Some(x) => Arc::clone(&x),
-                        None => panic!("Expected to have master connection on replica"),
+    // Returns Ok(None) once the other side has closed the connection
+    async fn next(&mut self) -> Result<Option<ParsedCommand>, ProtocolError> {
+        self.commands.recv().await.transpose()
+    }
+
+    // Waits for the reader to stop, which it does after a PSYNC. None if it panicked.
+    async fn into_parser(mut self) -> Option<RespParser> {
+        let task = self.task.take().expect("Expected the reader to be running");
+        task.await.ok().flatten()
+    }
+}
+
+impl Drop for CommandReader {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+// Reads the next command, along with the bytes it came in as on the master link
+async fn next_command(
+    parser: &mut RespParser,
+    is_master_link: bool,
+) -> Result<Option<(Result<Command, String>, usize, Option<Vec<u8>>)>, ProtocolError> {
+    if !is_master_link {
+        let parsed = parser.parse_command().await?;
+        return Ok(parsed.map(|(command, bytes)| (command, bytes, None)));
+    }
+    let parsed = parser.parse_forwarded_command().await?;
+    Ok(parsed.map(|(command, raw)| (command, raw.len(), Some(raw))))
+}
+
+// Clients are served while a replica syncs with its master. Whenever the link fails or goes away,
+// the replica reconnects and syncs again, until the server shuts down or the returned sender
+// stops the link, as REPLICAOF does.
+fn connect_to_master(server: &Arc<ServerState>) -> Arc<watch::Sender<bool>> {
+    let (stop, stopped) = watch::channel(false);
+    let stop = Arc::new(stop);
+    // The link's connection stops along with it
+    let context = ConnectionContext {
+        dispatcher: Dispatcher::new(Arc::clone(server)),
+        shutdown: stopped.clone(),
+    };
+    let (mut shutdown, mut stopped) = (server.shutdown.clone(), stopped);
+    let (server, stop_on_shutdown) = (Arc::clone(server), Arc::clone(&stop));
+    task::spawn(log::scope(String::from("master link"), async move {
+        let link = &server.replication.link;
+        let keep_linked = async {
+            loop {
+                match replica::perform_handshake(&server).await {
+                    Ok((stream, parser)) => {
+                        link.set_state(LinkState::Up);
+                        let _ = handle_conn(context.clone(), parser, stream, true, None).await;
+                        link.set_state(LinkState::Down);
+                        notice!("Lost the link with master, reconnecting");
                     }
-                };
-                self.handle_conn(master_connection, Some(parser)).await;
+                    Err(e) => {
+                        link.set_state(LinkState::Down);
+                        warning!("Handshake with master failed, retrying: {}", e);
+                    }
+                }
+                time::sleep(HANDSHAKE_RETRY_DELAY).await;
+            }
+        };
+        tokio::select! {
+            _ = keep_linked => (),
+            _ = wait_for_shutdown(&mut stopped) => (),
+            _ = wait_for_shutdown(&mut shutdown) => {
+                stop_on_shutdown.send_replace(true);
             }
-            RedisState::Master => (),
         }
-        loop {
-            let (stream, _) = self.listener.accept().await?;
-            println!("New stream connected to master: {:?}", stream);
-            let stream = Arc::new(RwLock::new(stream));
-            self.handle_conn(Arc::clone(&stream), None).await;
+        link.set_state(LinkState::Down);
+    }));
+    stop
+}
+
+impl Redis {
+    fn connection_context(&self) -> ConnectionContext {
+        ConnectionContext {
+            dispatcher: self.dispatcher(),
+            shutdown: self.shutdown.clone(),
+        }
+    }
+
+    pub fn dispatcher(&self) -> Dispatcher {
+        Dispatcher::new(Arc::clone(&self.server))
+    }
+
+    pub async fn listen(&mut self) -> Result<(), ServerError> {
+        let server = &self.server;
+        cron::spawn_cron(Arc::clone(server), self.shutdown.clone());
+        if server.config.current().role == RedisState::Replica {
+            let link_task = connect_to_master(server);
+            *server.replication.link_task.lock().await = Some(link_task);
+        }
+        for listener in std::mem::take(&mut self.other_listeners) {
+            let context = self.connection_context();
+            task::spawn(async move {
+                if let Err(e) = accept_connections(listener, context).await {
+                    warning!("Stopped accepting connections: {}", e);
+                }
+            });
+        }
+        let listener = match self.listener.take() {
+            Some(x) => x,
+            None => panic!("Expected listen to only be called once"),
+        };
+        if self.workers.is_empty() {
+            return accept_connections(Listener::Tcp(listener), self.connection_context()).await;
+        }
+
+        // Every worker accepts connections on a listener of its own, and serves them on its own
+        // thread. The first reuses the listener the server was built with.
+        let address = listener.local_addr()?;
+        let mut listener = Some(listener.into_std()?);
+        let mut accept_loops = Vec::new();
+        for worker in &self.workers {
+            let inherited = listener.take();
+            let context = self.connection_context();
+            accept_loops.push(worker.spawn(async move {
+                let listener = match inherited {
+                    Some(x) => TcpListener::from_std(x)?,
+                    None => workers::bind_reuseport(address)?,
+                };
+                accept_connections(Listener::Tcp(listener), context).await
+            }));
         }
+        for accept_loop in accept_loops {
+            match accept_loop.await {
+                Ok(result) => result?,
+                // The worker's runtime was dropped because the server is shutting down
+                Err(e) if e.is_cancelled() => (),
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+        Ok(())
     }
 
     pub async fn new(
-        config: Arc<Config>,
+        config: Config,
         listener: TcpListener,
-    ) -> Result<Self, Box<(dyn std::error::Error + 'static)>> {
-        let connections: Arc<RwLock<Option<HashMap<i32, Arc<RwLock<TcpStream>>>>>> =
-            match config.role {
-                RedisState::Master => Arc::new(RwLock::new(Some(HashMap::new()))),
-                RedisState::Replica => Arc::new(RwLock::new(None)),
-            };
-        let mut database: Arc<Mutex<HashMap<String, String>>> =
-            Arc::new(Mutex::new(HashMap::new()));
-        let mut expiry: Arc<RwLock<HashMap<String, SystemTime>>> =
-            Arc::new(RwLock::new(HashMap::new()));
-        match (&config.rdb_dir, &config.rdb_filename) {
-            (Some(dir), Some(filename)) => {
-                let mut full_path = dir.clone();
-                full_path.push(filename);
-                match File::open(full_path).await {
-                    Ok(mut file) => {
-                        let mut contents = vec![];
-                        let _ = file.read_to_end(&mut contents).await;
-                        // Here we will parse the RDB file which returns a database
-                        let mut rdb_parser = RdbParser::new(contents);
-                        let (data_map, expiry_map) = rdb_parser.rdb_to_db();
-                        database = Arc::new(Mutex::new(data_map));
-                        expiry = Arc::new(RwLock::new(expiry_map));
-                    }
-                    Err(_) => (),
-                };
+        other_listeners: Vec<Listener>,
+        scripts: Scripts,
+        shutdown_handle: &ShutdownHandle,
+    ) -> Result<Self, ServerError> {
+        let shutdown = shutdown_handle.subscribe();
+        lru::set_lfu_params(config.lfu_log_factor, config.lfu_decay_time);
+        log::set_level(config.loglevel);
+        let mut loaded = Vec::new();
+        // With the AOF on, the dataset is loaded from it alone, as in Redis. The commands after
+        // its RDB preamble are replayed once the server is up.
+        let mut aof_commands = Bytes::new();
+        if config.appendonly {
+            let (databases, commands) =
+                persistence::read_append_only_file(&config.aof_path()).await?;
+            loaded = databases;
+            aof_commands = commands;
+        } else if let (Some(dir), Some(filename)) = (&config.rdb_dir, &config.rdb_filename) {
+            let mut full_path = dir.clone();
+            full_path.push(filename);
+            if let Ok(contents) = tokio::fs::read(&full_path).await {
+                // A file that's there but can't be loaded in full stops the server from starting
+                loaded = RdbParser::new(contents)
+                    .rdb_to_db()
+                    .map_err(|(offset, message)| {
+                        format!(
+                            "Bad file format reading the RDB file {}: {} at offset {}",
+                            full_path.display(),
+                            message,
+                            offset
+                        )
+                    })?;
+                let keys: usize = loaded.iter().map(|x| x.len()).sum();
+                notice!("DB loaded from disk: {} keys", keys);
             }
-            _ => (),
+        }
+        if loaded.len() > config.databases {
+            return Err(format!(
+                "The data file was created by a server configured with more than {} databases",
+                config.databases
+            )
+            .into());
+        }
+        loaded.resize_with(config.databases, HashMap::new);
+        let databases: Vec<Store> = loaded.into_iter().map(Store::from_entries).collect();
+        for db in &databases {
+            db.notifications.set_flags(config.notify_keyspace_events);
         }
 
+        let workers = match config.keyspace_mode {
+            KeyspaceMode::ThreadPerCore => workers::start_workers(config.threads, shutdown.clone()),
+            _ => Vec::new(),
+        };
+
+        let admission = Admission::new(
+            config.maxclients,
+            config.max_connections_per_ip,
+            config.max_accept_rate,
+        );
+        let acl = Acl::new(config.acllog_max_len);
+        let backlog = Backlog::new(config.repl_backlog_size);
+        let server = ServerState {
+            keyspace: Keyspace::new(databases, config.keyspace_mode, &workers),
+            config: LiveConfig::new(config),
+            shutdown: shutdown.clone(),
+            shutdown_handle: shutdown_handle.downgrade(),
+            replication: Replication {
+                replicas: Arc::new(RwLock::new(HashMap::new())),
+                master_offset: AtomicUsize::new(0),
+                write_offset: AtomicUsize::new(0),
+                selected_db: AtomicUsize::new(NO_DB_SELECTED),
+                replid: Mutex::new(identity::random_id()),
+                backlog: Mutex::new(backlog),
+                offset: ReplicaOffset::default(),
+                master_replid: Mutex::new(None),
+                master_db: AtomicUsize::new(0),
+                link: MasterLink::default(),
+                applying: tokio::sync::Mutex::new(()),
+                link_task: tokio::sync::Mutex::new(None),
+            },
+            stats: Stats::default(),
+            admission,
+            clients: Clients::default(),
+            acl,
+            identity: Identity::default(),
+            blocking: Blocking::default(),
+            exec_lock: RwLock::new(()),
+            pubsub: PubSub::default(),
+            monitors: Monitors::default(),
+            persistence: Persistence::default(),
+            aof: OnceLock::new(),
+            latency: LatencyStats::default(),
+            slowlog: SlowLog::default(),
+            scripts,
+        };
+        let server = Arc::new(server);
+        let config = server.config.current();
+        if config.appendonly {
+            persistence::replay_append_only_file(&server, aof_commands).await;
+            let writer = AofWriter::open(config.aof_path(), config.appendfsync)?;
+            let _ = server.aof.set(writer);
+        }
         Ok(Redis {
-            database,
-            expiry,
-            config,
-            listener,
-            replica_connections: connections,
-            master_connection: None,
+            server,
+            listener: Some(listener),
+            other_listeners,
+            shutdown,
+            workers,
         })
     }
 }
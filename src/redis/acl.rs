@@ -0,0 +1,582 @@
+use super::clock;
+use super::glob;
+use super::sha1;
+
+use bytes::Bytes;
+use core::fmt;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+pub const DEFAULT_USER: &str = "default";
+pub const NOAUTH_ERROR: &str = "NOAUTH Authentication required.";
+pub const WRONGPASS_ERROR: &str = "WRONGPASS invalid username-password pair or user is disabled.";
+// Denials this close together that only differ in their client are counted as one entry
+const LOG_ENTRY_MERGE_WINDOW_MS: u64 = 60_000;
+// How many of the newest entries are looked through for one to merge into
+const LOG_ENTRIES_TO_MERGE_WITH: usize = 10;
+
+// What a user may do. Connections act as the default user, which starts out allowed everything.
+#[derive(Debug, Clone)]
+pub struct User {
+    enabled: bool,
+    // Whether any password logs in as the user. Otherwise it takes one of `passwords`, which are
+    // kept as SHA1 digests rather than in the clear.
+    nopass: bool,
+    passwords: Vec<String>,
+    all_commands: bool,
+    // Commands allowed or denied on top of all_commands, by table name. A container like
+    // "config" covers all its subcommands unless they have a later rule of their own.
+    commands: HashMap<String, bool>,
+    all_keys: bool,
+    key_patterns: Vec<String>,
+    all_channels: bool,
+    channel_patterns: Vec<String>,
+}
+
+impl User {
+    // A new user is disabled and may do nothing, until rules say otherwise
+    fn new() -> Self {
+        User {
+            enabled: false,
+            nopass: false,
+            passwords: Vec::new(),
+            all_commands: false,
+            commands: HashMap::new(),
+            all_keys: false,
+            key_patterns: Vec::new(),
+            all_channels: false,
+            channel_patterns: Vec::new(),
+        }
+    }
+
+    fn apply(&mut self, rule: &str) -> Result<(), String> {
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allcommands" | "+@all" => {
+                self.all_commands = true;
+                self.commands.clear();
+            }
+            "nocommands" | "-@all" => {
+                self.all_commands = false;
+                self.commands.clear();
+            }
+            "allkeys" | "~*" => {
+                self.all_keys = true;
+                self.key_patterns.clear();
+            }
+            "resetkeys" => {
+                self.all_keys = false;
+                self.key_patterns.clear();
+            }
+            "allchannels" | "&*" => {
+                self.all_channels = true;
+                self.channel_patterns.clear();
+            }
+            "resetchannels" => {
+                self.all_channels = false;
+                self.channel_patterns.clear();
+            }
+            "reset" => *self = User::new(),
+            // Key and channel patterns and passwords keep their case
+            _ => match rule.split_at_checked(1) {
+                Some(("&", pattern)) if !self.all_channels => {
+                    self.channel_patterns.push(pattern.to_string())
+                }
+                Some(("&", _)) => {
+                    return Err(String::from(
+                        "Adding a pattern after the * pattern (or the 'allchannels' flag) is not valid and does not have any effect. Try 'resetchannels' to start with an empty list of channels",
+                    ))
+                }
+                Some((">", password)) => {
+                    let digest = sha1::hex_digest(password.as_bytes());
+                    if !self.passwords.contains(&digest) {
+                        self.passwords.push(digest);
+                    }
+                    self.nopass = false;
+                }
+                Some(("<", password)) => {
+                    let digest = sha1::hex_digest(password.as_bytes());
+                    match self.passwords.iter().position(|x| *x == digest) {
+                        Some(index) => {
+                            self.passwords.remove(index);
+                        }
+                        None => {
+                            return Err(String::from(
+                                "The password you are trying to remove from the user does not exist",
+                            ))
+                        }
+                    }
+                }
+                Some(("~", pattern)) if !self.all_keys => {
+                    self.key_patterns.push(pattern.to_string())
+                }
+                Some(("~", _)) => {
+                    return Err(String::from(
+                        "Adding a pattern after the * pattern (or the 'allkeys' flag) is not valid and does not have any effect. Try 'resetkeys' to start with an empty list of patterns",
+                    ))
+                }
+                Some((sign @ ("+" | "-"), name)) if !name.starts_with('@') => {
+                    // A rule for a whole container overrides its subcommands' earlier ones
+                    let name = name.to_lowercase();
+                    let subcommands = format!("{}|", name);
+                    self.commands.retain(|x, _| !x.starts_with(&subcommands));
+                    self.commands.insert(name, sign == "+");
+                }
+                _ => return Err(String::from("Syntax error")),
+            },
+        }
+        Ok(())
+    }
+
+    fn has_password(&self, password: &str) -> bool {
+        self.nopass
+            || self
+                .passwords
+                .contains(&sha1::hex_digest(password.as_bytes()))
+    }
+
+    fn can_run(&self, command: &str) -> bool {
+        let container = command.split('|').next().unwrap_or(command);
+        match self.commands.get(command).or(self.commands.get(container)) {
+            Some(allowed) => *allowed,
+            None => self.all_commands,
+        }
+    }
+
+    fn can_access(&self, key: &str) -> bool {
+        self.all_keys
+            || self
+                .key_patterns
+                .iter()
+                .any(|x| glob::matches(x.as_bytes(), key.as_bytes()))
+    }
+
+    // PSUBSCRIBE's patterns have to be one of the user's own, rather than match one
+    fn can_use_channel(&self, channel: &[u8], is_pattern: bool) -> bool {
+        self.all_channels
+            || self.channel_patterns.iter().any(|x| match is_pattern {
+                true => x.as_bytes() == channel,
+                false => glob::matches(x.as_bytes(), channel),
+            })
+    }
+
+    // The user's rules, as ACL LIST shows them
+    fn describe(&self) -> String {
+        let mut rules = vec![String::from(if self.enabled { "on" } else { "off" })];
+        if self.nopass {
+            rules.push(String::from("nopass"));
+        }
+        rules.extend(self.passwords.iter().map(|x| format!("#{}", x)));
+        if self.all_keys {
+            rules.push(String::from("~*"));
+        }
+        rules.extend(self.key_patterns.iter().map(|x| format!("~{}", x)));
+        if self.all_channels {
+            rules.push(String::from("&*"));
+        } else if self.channel_patterns.is_empty() {
+            rules.push(String::from("resetchannels"));
+        }
+        rules.extend(self.channel_patterns.iter().map(|x| format!("&{}", x)));
+        rules.push(String::from(if self.all_commands {
+            "+@all"
+        } else {
+            "-@all"
+        }));
+        let mut commands: Vec<_> = self.commands.iter().collect();
+        commands.sort();
+        rules.extend(
+            commands
+                .into_iter()
+                .map(|(name, allowed)| format!("{}{}", if *allowed { "+" } else { "-" }, name)),
+        );
+        rules.join(" ")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DenialReason {
+    Command,
+    Key,
+    Channel,
+}
+
+impl fmt::Display for DenialReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DenialReason::Command => write!(f, "command"),
+            DenialReason::Key => write!(f, "key"),
+            DenialReason::Channel => write!(f, "channel"),
+        }
+    }
+}
+
+// Where a refused command was run from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogContext {
+    Toplevel,
+    Multi,
+    Lua,
+}
+
+impl fmt::Display for LogContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogContext::Toplevel => write!(f, "toplevel"),
+            LogContext::Multi => write!(f, "multi"),
+            LogContext::Lua => write!(f, "lua"),
+        }
+    }
+}
+
+// Why a command was refused: the reason, and the command, key or channel that was off limits
+#[derive(Debug, PartialEq)]
+pub struct Denial {
+    pub reason: DenialReason,
+    pub object: String,
+}
+
+impl Denial {
+    // The error sent back to the client
+    pub fn error(&self, username: &str) -> String {
+        match self.reason {
+            DenialReason::Command => format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                username, self.object
+            ),
+            DenialReason::Key => String::from("NOPERM No permissions to access a key"),
+            DenialReason::Channel => String::from("NOPERM No permissions to access a channel"),
+        }
+    }
+}
+
+// A denial, or several alike, in the ACL log
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub count: u64,
+    pub reason: DenialReason,
+    pub context: LogContext,
+    pub object: String,
+    pub username: String,
+    // The client that was last denied, in the manner of CLIENT LIST
+    pub client_info: String,
+    pub entry_id: u64,
+    // UNIX times in milliseconds
+    pub created: u64,
+    pub updated: u64,
+}
+
+pub struct Acl {
+    users: Mutex<HashMap<String, User>>,
+    // Newest first, at most max_log_len of them
+    log: Mutex<VecDeque<LogEntry>>,
+    next_entry_id: Mutex<u64>,
+    max_log_len: usize,
+}
+
+impl Acl {
+    pub fn new(max_log_len: usize) -> Self {
+        let mut default = User::new();
+        for rule in ["on", "nopass", "allkeys", "allchannels", "allcommands"] {
+            default
+                .apply(rule)
+                .expect("The default user's rules are valid");
+        }
+        Acl {
+            users: Mutex::new(HashMap::from([(DEFAULT_USER.to_string(), default)])),
+            log: Mutex::new(VecDeque::new()),
+            next_entry_id: Mutex::new(0),
+            max_log_len,
+        }
+    }
+
+    // Creates the user if needed and applies `rules` in order. Nothing changes if any is invalid.
+    pub fn set_user(&self, name: &str, rules: &[String]) -> Result<(), String> {
+        let mut users = self.users.lock().unwrap();
+        let mut user = users.get(name).cloned().unwrap_or_else(User::new);
+        for rule in rules {
+            if let Err(e) = user.apply(rule) {
+                return Err(format!(
+                    "ERR Error in ACL SETUSER modifier '{}': {}",
+                    rule, e
+                ));
+            }
+        }
+        users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    // Every user as a line of rules, sorted by name
+    pub fn list(&self) -> Vec<String> {
+        let users = self.users.lock().unwrap();
+        let mut names: Vec<&String> = users.keys().collect();
+        names.sort();
+        names
+            .into_iter()
+            .map(|x| format!("user {} {}", x, users[x].describe()))
+            .collect()
+    }
+
+    // Whether `password` logs in as `username`. requirepass, while it's set, is the default user's
+    // only password, whatever ACL SETUSER gave it.
+    pub fn authenticate(&self, username: &str, password: &str, requirepass: Option<&str>) -> bool {
+        let users = self.users.lock().unwrap();
+        let user = match users.get(username) {
+            Some(x) if x.enabled => x,
+            _ => return false,
+        };
+        match requirepass.filter(|_| username == DEFAULT_USER) {
+            Some(x) => x == password,
+            None => user.has_password(password),
+        }
+    }
+
+    // Whether logging in as `username` takes a password, as opposed to any at all
+    pub fn requires_password(&self, username: &str, requirepass: Option<&str>) -> bool {
+        if username == DEFAULT_USER && requirepass.is_some() {
+            return true;
+        }
+        let users = self.users.lock().unwrap();
+        users.get(username).is_none_or(|x| !x.nopass)
+    }
+
+    // Whether `username` may run `command` on `keys` and `channels`. A user that no longer exists
+    // may do nothing.
+    pub fn check(
+        &self,
+        username: &str,
+        command: &str,
+        keys: &[String],
+        channels: &[Bytes],
+    ) -> Result<(), Denial> {
+        let users = self.users.lock().unwrap();
+        let user = users.get(username);
+        if !user.is_some_and(|x| x.can_run(command)) {
+            return Err(Denial {
+                reason: DenialReason::Command,
+                object: command.to_string(),
+            });
+        }
+        if let Some(key) = keys
+            .iter()
+            .find(|x| !user.is_some_and(|user| user.can_access(x)))
+        {
+            return Err(Denial {
+                reason: DenialReason::Key,
+                object: key.clone(),
+            });
+        }
+        let is_pattern = command == "psubscribe";
+        match channels
+            .iter()
+            .find(|x| !user.is_some_and(|user| user.can_use_channel(x, is_pattern)))
+        {
+            Some(channel) => Err(Denial {
+                reason: DenialReason::Channel,
+                object: String::from_utf8_lossy(channel).into_owned(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    pub fn log_denial(
+        &self,
+        denial: &Denial,
+        context: LogContext,
+        username: &str,
+        client_info: String,
+    ) {
+        let now = clock::unix_ms();
+        let mut log = self.log.lock().unwrap();
+        let similar = log.iter().take(LOG_ENTRIES_TO_MERGE_WITH).position(|x| {
+            x.reason == denial.reason
+                && x.context == context
+                && x.object == denial.object
+                && x.username == username
+                && now.saturating_sub(x.updated) < LOG_ENTRY_MERGE_WINDOW_MS
+        });
+        let entry = match similar.and_then(|x| log.remove(x)) {
+            Some(mut entry) => {
+                entry.count += 1;
+                entry.client_info = client_info;
+                entry.updated = now;
+                entry
+            }
+            None => {
+                let mut next_entry_id = self.next_entry_id.lock().unwrap();
+                let entry_id = *next_entry_id;
+                *next_entry_id += 1;
+                LogEntry {
+                    count: 1,
+                    reason: denial.reason,
+                    context,
+                    object: denial.object.clone(),
+                    username: username.to_string(),
+                    client_info,
+                    entry_id,
+                    created: now,
+                    updated: now,
+                }
+            }
+        };
+        log.push_front(entry);
+        log.truncate(self.max_log_len);
+    }
+
+    // The newest `count` entries, newest first
+    pub fn log(&self, count: usize) -> Vec<LogEntry> {
+        let log = self.log.lock().unwrap();
+        log.iter().take(count).cloned().collect()
+    }
+
+    pub fn reset_log(&self) {
+        self.log.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &str) -> Vec<String> {
+        rules.split(' ').map(String::from).collect()
+    }
+
+    #[test]
+    fn denials_are_checked_and_logged_per_user() {
+        let acl = Acl::new(2);
+        acl.set_user(
+            "alice",
+            &rules("on ~cache:* +@all -del -config +config|get"),
+        )
+        .unwrap();
+        let keys = vec![String::from("cache:1")];
+        assert_eq!(acl.check("alice", "get", &keys, &[]), Ok(()));
+        assert_eq!(acl.check("alice", "config|get", &[], &[]), Ok(()));
+        let denial = acl.check("alice", "del", &keys, &[]).unwrap_err();
+        assert_eq!(denial.reason, DenialReason::Command);
+        assert_eq!(
+            denial.error("alice"),
+            "NOPERM User alice has no permissions to run the 'del' command"
+        );
+        let other_keys = vec![String::from("cache:1"), String::from("session:1")];
+        assert_eq!(
+            acl.check("alice", "get", &other_keys, &[]),
+            Err(Denial {
+                reason: DenialReason::Key,
+                object: String::from("session:1"),
+            })
+        );
+        assert!(acl.check("nobody", "ping", &[], &[]).is_err());
+        assert_eq!(acl.check(DEFAULT_USER, "del", &other_keys, &[]), Ok(()));
+        assert_eq!(
+            acl.list(),
+            vec![
+                "user alice on ~cache:* resetchannels +@all -config +config|get -del",
+                "user default on nopass ~* &* +@all",
+            ]
+        );
+
+        // A bad rule leaves the user as it was
+        assert!(acl.set_user("alice", &rules("+@all bogus")).is_err());
+        assert!(acl.check("alice", "del", &keys, &[]).is_err());
+
+        // Repeats are merged into one entry, which moves to the front
+        acl.log_denial(&denial, LogContext::Toplevel, "alice", String::from("id=1"));
+        let key_denial = acl.check("alice", "get", &other_keys, &[]).unwrap_err();
+        acl.log_denial(
+            &key_denial,
+            LogContext::Toplevel,
+            "alice",
+            String::from("id=1"),
+        );
+        acl.log_denial(&denial, LogContext::Toplevel, "alice", String::from("id=2"));
+        let log = acl.log(10);
+        assert_eq!(log.len(), 2);
+        assert_eq!((log[0].count, log[0].entry_id), (2, 0));
+        assert_eq!(log[0].client_info, "id=2");
+        assert_eq!(
+            (log[1].reason, log[1].object.as_str()),
+            (DenialReason::Key, "session:1")
+        );
+
+        // The oldest entries are dropped past the limit
+        let command_denial = acl.check("alice", "config|set", &[], &[]).unwrap_err();
+        acl.log_denial(
+            &command_denial,
+            LogContext::Toplevel,
+            "alice",
+            String::from("id=1"),
+        );
+        let log = acl.log(10);
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].object, "config|set");
+        assert_eq!(acl.log(1).len(), 1);
+        acl.reset_log();
+        assert!(acl.log(10).is_empty());
+    }
+
+    #[test]
+    fn channels_are_checked_against_the_users_patterns() {
+        let acl = Acl::new(1);
+        acl.set_user("alice", &rules("on +@all &news.*")).unwrap();
+        let channels = [Bytes::from("news.sport")];
+        assert_eq!(acl.check("alice", "publish", &[], &channels), Ok(()));
+        assert_eq!(acl.check("alice", "subscribe", &[], &channels), Ok(()));
+        // Patterns have to be the user's own, not just match them
+        assert!(acl.check("alice", "psubscribe", &[], &channels).is_err());
+        let patterns = [Bytes::from("news.*")];
+        assert_eq!(acl.check("alice", "psubscribe", &[], &patterns), Ok(()));
+        let denial = acl
+            .check("alice", "subscribe", &[], &[Bytes::from("weather")])
+            .unwrap_err();
+        assert_eq!(denial.reason.to_string(), "channel");
+        assert_eq!(
+            denial.error("alice"),
+            "NOPERM No permissions to access a channel"
+        );
+        assert!(acl.set_user("alice", &rules("allchannels &more")).is_err());
+        acl.set_user("alice", &rules("resetchannels")).unwrap();
+        assert!(acl.check("alice", "publish", &[], &channels).is_err());
+        assert_eq!(
+            acl.check(DEFAULT_USER, "psubscribe", &[], &channels),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn users_are_authenticated_with_their_own_passwords() {
+        let acl = Acl::new(1);
+        assert!(acl.authenticate(DEFAULT_USER, "anything", None));
+        assert!(!acl.requires_password(DEFAULT_USER, None));
+        assert!(acl.authenticate(DEFAULT_USER, "secret", Some("secret")));
+        assert!(!acl.authenticate(DEFAULT_USER, "wrong", Some("secret")));
+        assert!(acl.requires_password(DEFAULT_USER, Some("secret")));
+
+        acl.set_user("alice", &rules("on +@all")).unwrap();
+        assert!(!acl.authenticate("alice", "secret", Some("secret")));
+        acl.set_user("alice", &rules(">one >two")).unwrap();
+        assert!(acl.authenticate("alice", "one", Some("secret")));
+        assert!(acl.authenticate("alice", "two", None));
+        assert!(acl.set_user("alice", &rules("<three")).is_err());
+        acl.set_user("alice", &rules("<one")).unwrap();
+        assert!(!acl.authenticate("alice", "one", None));
+        assert!(acl.list()[0].starts_with("user alice on #"));
+        acl.set_user("alice", &rules("nopass")).unwrap();
+        assert!(acl.authenticate("alice", "anything", None));
+        acl.set_user("alice", &rules("resetpass")).unwrap();
+        assert!(!acl.authenticate("alice", "two", None));
+        assert!(acl.requires_password("alice", None));
+
+        acl.set_user(DEFAULT_USER, &rules("off")).unwrap();
+        assert!(!acl.authenticate(DEFAULT_USER, "secret", Some("secret")));
+    }
+}
@@ -0,0 +1,147 @@
+use core::fmt;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+// Decides which new connections are served, so that one misbehaving client can't crowd out the
+// rest. The per IP and accept rate limits are off when 0.
+pub struct Admission {
+    max_clients: usize,
+    max_per_ip: usize,
+    max_accept_rate: usize,
+    open: Arc<Mutex<OpenConnections>>,
+    // When the current one second window started, and how many connections it has accepted
+    window: Mutex<(Instant, usize)>,
+}
+
+// The connections admitted and not yet closed, in all and per IP
+#[derive(Default)]
+struct OpenConnections {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Rejection {
+    MaxClients,
+    TooManyFromIp,
+    AcceptRateExceeded,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::MaxClients => write!(f, "ERR max number of clients reached"),
+            Rejection::TooManyFromIp => write!(f, "ERR max number of clients per IP reached"),
+            Rejection::AcceptRateExceeded => {
+                write!(f, "ERR max connection accept rate reached, try again later")
+            }
+        }
+    }
+}
+
+// Counts as an open connection, and one of its IP's, for as long as it's kept
+pub struct Admitted {
+    ip: Option<IpAddr>,
+    open: Arc<Mutex<OpenConnections>>,
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        open.total -= 1;
+        let ip = match self.ip {
+            Some(x) => x,
+            None => return,
+        };
+        if let Some(count) = open.per_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+impl Admission {
+    pub fn new(max_clients: usize, max_per_ip: usize, max_accept_rate: usize) -> Self {
+        Admission {
+            max_clients,
+            max_per_ip,
+            max_accept_rate,
+            open: Arc::new(Mutex::new(OpenConnections::default())),
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    // Admits a connection from `ip`, which has to keep the returned guard until it's closed.
+    // Connections without an IP, over unix sockets, don't count against the per IP limit.
+    pub fn admit(&self, ip: Option<IpAddr>) -> Result<Admitted, Rejection> {
+        let mut open = self.open.lock().unwrap();
+        if open.total >= self.max_clients {
+            return Err(Rejection::MaxClients);
+        }
+        let count = ip.map_or(0, |x| open.per_ip.get(&x).copied().unwrap_or(0));
+        if ip.is_some() && self.max_per_ip > 0 && count >= self.max_per_ip {
+            return Err(Rejection::TooManyFromIp);
+        }
+        if self.max_accept_rate > 0 {
+            let mut window = self.window.lock().unwrap();
+            if window.0.elapsed() >= RATE_WINDOW {
+                *window = (Instant::now(), 0);
+            }
+            if window.1 >= self.max_accept_rate {
+                return Err(Rejection::AcceptRateExceeded);
+            }
+            window.1 += 1;
+        }
+        open.total += 1;
+        if let Some(ip) = ip {
+            open.per_ip.insert(ip, count + 1);
+        }
+        Ok(Admitted {
+            ip,
+            open: Arc::clone(&self.open),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_are_limited_in_all_per_ip_and_per_second() {
+        let (first, second): (IpAddr, IpAddr) =
+            ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let (first, second) = (Some(first), Some(second));
+        let admission = Admission::new(10, 2, 0);
+        let a = admission.admit(first).unwrap();
+        let _b = admission.admit(first).unwrap();
+        assert_eq!(admission.admit(first).err(), Some(Rejection::TooManyFromIp));
+        assert!(admission.admit(second).is_ok());
+        // Closing a connection makes room for another
+        drop(a);
+        assert!(admission.admit(first).is_ok());
+        assert!(admission.admit(None).is_ok());
+
+        let admission = Admission::new(10, 0, 2);
+        let _a = admission.admit(first).unwrap();
+        let _b = admission.admit(second).unwrap();
+        assert_eq!(
+            admission.admit(second).err(),
+            Some(Rejection::AcceptRateExceeded)
+        );
+
+        // Unix socket connections count towards maxclients too
+        let admission = Admission::new(2, 0, 0);
+        let a = admission.admit(first).unwrap();
+        let _b = admission.admit(None).unwrap();
+        assert_eq!(admission.admit(second).err(), Some(Rejection::MaxClients));
+        drop(a);
+        assert!(admission.admit(second).is_ok());
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::VecDeque;
+
+// The tail of the replication stream, kept by a master so that a replica whose link dropped can
+// carry on from where it was, instead of syncing the whole dataset again. Offsets are where the
+// stream stood after a byte, so a replica that has applied `offset` bytes is missing everything
+// after `offset`.
+pub struct Backlog {
+    data: VecDeque<u8>,
+    capacity: usize,
+    // The offset after the last byte kept
+    end: usize,
+}
+
+impl Backlog {
+    pub fn new(capacity: usize) -> Self {
+        Backlog {
+            data: VecDeque::new(),
+            capacity,
+            end: 0,
+        }
+    }
+
+    // Keeps `data`, dropping the oldest bytes once over capacity
+    pub fn append(&mut self, data: &[u8]) {
+        self.end += data.len();
+        let kept = &data[data.len().saturating_sub(self.capacity)..];
+        let overflow = (self.data.len() + kept.len()).saturating_sub(self.capacity);
+        self.data.drain(..overflow);
+        self.data.extend(kept);
+    }
+
+    // Everything after `offset`, unless some of it has already been dropped or was never
+    // written
+    pub fn since(&self, offset: usize) -> Option<Vec<u8>> {
+        let start = self.start();
+        if offset < start || offset > self.end {
+            return None;
+        }
+        Some(self.data.range(offset - start..).copied().collect())
+    }
+
+    // Empties the backlog, which from here on follows a stream at `offset`
+    pub fn reset(&mut self, offset: usize) {
+        self.data.clear();
+        self.end = offset;
+    }
+
+    pub fn resize(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        let overflow = self.data.len().saturating_sub(capacity);
+        self.data.drain(..overflow);
+    }
+
+    // The offset before the first byte kept
+    pub fn start(&self) -> usize {
+        self.end - self.data.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_tail_of_the_stream_is_kept() {
+        let mut backlog = Backlog::new(8);
+        backlog.append(b"abcd");
+        assert_eq!(backlog.since(0).unwrap(), b"abcd");
+        assert_eq!(backlog.since(3).unwrap(), b"d");
+        assert_eq!(backlog.since(4).unwrap(), b"");
+        assert_eq!(backlog.since(5), None);
+
+        backlog.append(b"efghij");
+        assert_eq!(backlog.start(), 2);
+        assert_eq!(backlog.since(1), None);
+        assert_eq!(backlog.since(2).unwrap(), b"cdefghij");
+        backlog.append(b"0123456789");
+        assert_eq!(backlog.since(12).unwrap(), b"23456789");
+
+        backlog.resize(2);
+        assert_eq!(backlog.since(18).unwrap(), b"89");
+        backlog.reset(100);
+        assert!(backlog.is_empty());
+        assert_eq!(backlog.since(100).unwrap(), b"");
+        assert_eq!(backlog.since(20), None);
+    }
+}
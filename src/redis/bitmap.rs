@@ -0,0 +1,176 @@
+use super::range;
+
+use bytes::Bytes;
+use core::fmt;
+
+// Strings seen as arrays of bits, as SETBIT, GETBIT, BITCOUNT and BITOP see them. Bit 0 is the
+// most significant bit of the first byte, and bits past the end of the string are all 0.
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BitOperation {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+impl BitOperation {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "and" => Some(BitOperation::And),
+            "or" => Some(BitOperation::Or),
+            "xor" => Some(BitOperation::Xor),
+            "not" => Some(BitOperation::Not),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for BitOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitOperation::And => write!(f, "AND"),
+            BitOperation::Or => write!(f, "OR"),
+            BitOperation::Xor => write!(f, "XOR"),
+            BitOperation::Not => write!(f, "NOT"),
+        }
+    }
+}
+
+// What BITCOUNT's start and end count in
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BitUnit {
+    Byte,
+    Bit,
+}
+
+pub fn get_bit(bytes: &[u8], offset: u64) -> bool {
+    match usize::try_from(offset / 8).ok().and_then(|x| bytes.get(x)) {
+        Some(byte) => byte & mask(offset) != 0,
+        None => false,
+    }
+}
+
+// Sets or clears the bit at `offset`, growing the string with zero bytes to reach it. Returns the
+// bit as it was.
+pub fn set_bit(bytes: &mut Vec<u8>, offset: u64, on: bool) -> bool {
+    let index = (offset / 8) as usize;
+    if bytes.len() <= index {
+        bytes.resize(index + 1, 0);
+    }
+    let was_on = bytes[index] & mask(offset) != 0;
+    match on {
+        true => bytes[index] |= mask(offset),
+        false => bytes[index] &= !mask(offset),
+    }
+    was_on
+}
+
+// Counts the bits that are set, in the whole string or between the inclusive start and end,
+// which resolve against the string like GETRANGE's do
+pub fn count(bytes: &[u8], range: Option<(i64, i64, BitUnit)>) -> u64 {
+    let (start, end, unit) = match range {
+        Some(x) => x,
+        None => return ones(bytes),
+    };
+    if unit == BitUnit::Byte {
+        return ones(&bytes[range::normalize(start, end, bytes.len())]);
+    }
+    let bits = range::normalize(start, end, bytes.len() * 8);
+    if bits.is_empty() {
+        return 0;
+    }
+    let (first, last) = (bits.start / 8, (bits.end - 1) / 8);
+    let mut total = ones(&bytes[first..=last]);
+    // Leave out the bits of the first and last bytes that are outside the range
+    total -= bytes[first]
+        .checked_shr(8 - (bits.start % 8) as u32)
+        .unwrap_or(0)
+        .count_ones() as u64;
+    if !bits.end.is_multiple_of(8) {
+        total -= (bytes[last] & (0xff >> (bits.end % 8))).count_ones() as u64;
+    }
+    total
+}
+
+// Combines the sources byte by byte into a string as long as the longest of them, the shorter
+// ones being padded with zero bytes. NOT takes a single source.
+pub fn combine(operation: BitOperation, sources: &[Bytes]) -> Vec<u8> {
+    let len = sources.iter().map(Bytes::len).max().unwrap_or(0);
+    let byte = |source: &Bytes, index: usize| source.get(index).copied().unwrap_or(0);
+    (0..len)
+        .map(|index| {
+            let mut bytes = sources.iter().map(|x| byte(x, index));
+            let first = bytes.next().unwrap_or(0);
+            match operation {
+                BitOperation::And => bytes.fold(first, |x, y| x & y),
+                BitOperation::Or => bytes.fold(first, |x, y| x | y),
+                BitOperation::Xor => bytes.fold(first, |x, y| x ^ y),
+                BitOperation::Not => !first,
+            }
+        })
+        .collect()
+}
+
+fn mask(offset: u64) -> u8 {
+    0x80 >> (offset % 8)
+}
+
+fn ones(bytes: &[u8]) -> u64 {
+    bytes.iter().map(|x| x.count_ones() as u64).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bits_are_counted_from_the_most_significant_end() {
+        let mut bytes = Vec::new();
+        assert!(!set_bit(&mut bytes, 1, true));
+        assert!(!set_bit(&mut bytes, 14, true));
+        assert_eq!(bytes, [0b0100_0000, 0b0000_0010]);
+        assert!(set_bit(&mut bytes, 1, false));
+        assert_eq!(bytes, [0, 0b0000_0010]);
+        assert!(get_bit(&bytes, 14));
+        assert!(!get_bit(&bytes, 15));
+        assert!(!get_bit(&bytes, 1000));
+    }
+
+    #[test]
+    fn bit_ranges_leave_out_the_ends_of_partial_bytes() {
+        // 11111111 00001111 11110000
+        let bytes = [0xff, 0x0f, 0xf0];
+        let cases = [
+            (None, 16),
+            (Some((0, 0, BitUnit::Byte)), 8),
+            (Some((1, -1, BitUnit::Byte)), 8),
+            (Some((-1, -1, BitUnit::Byte)), 4),
+            (Some((2, 1, BitUnit::Byte)), 0),
+            (Some((0, -1, BitUnit::Bit)), 16),
+            (Some((4, 11, BitUnit::Bit)), 4),
+            (Some((12, 19, BitUnit::Bit)), 8),
+            (Some((5, 5, BitUnit::Bit)), 1),
+            (Some((8, 11, BitUnit::Bit)), 0),
+            (Some((-4, -1, BitUnit::Bit)), 0),
+            (Some((-8, -5, BitUnit::Bit)), 4),
+            (Some((7, 16, BitUnit::Bit)), 6),
+        ];
+        for (range, expected) in cases {
+            assert_eq!(count(&bytes, range), expected, "{:?}", range);
+        }
+    }
+
+    #[test]
+    fn shorter_sources_are_padded_with_zeros() {
+        let sources = [
+            Bytes::from_static(&[0xff, 0x0f]),
+            Bytes::from_static(&[0x3c]),
+        ];
+        assert_eq!(combine(BitOperation::And, &sources), [0x3c, 0x00]);
+        assert_eq!(combine(BitOperation::Or, &sources), [0xff, 0x0f]);
+        assert_eq!(combine(BitOperation::Xor, &sources), [0xc3, 0x0f]);
+        assert_eq!(combine(BitOperation::Not, &sources[1..]), [0xc3]);
+        assert!(combine(BitOperation::Or, &[Bytes::new()]).is_empty());
+    }
+}
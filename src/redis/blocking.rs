@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+// Clients blocked until a key they're waiting on is written to, like XREAD BLOCK. Each waiting
+// client has its own Notify registered under every key it waits on. Writers signal the key, which
+// stores a permit if the client isn't parked yet, so a write between the client checking the key
+// and going to sleep still wakes it.
+//
+// Clients are registered under a key in the order they started waiting, so that those popping
+// from lists can be served first come, first served: see Watch::is_first.
+#[derive(Default)]
+pub struct Blocking {
+    waiting: Mutex<HashMap<String, Vec<Arc<Notify>>>>,
+}
+
+impl Blocking {
+    // Starts waiting on `keys`. Register before checking the keys, then wait on the returned
+    // Watch if there was nothing there.
+    pub fn watch(&self, keys: &[String]) -> Watch<'_> {
+        let notify = Arc::new(Notify::new());
+        let mut waiting = self.waiting.lock().unwrap();
+        for key in keys {
+            waiting
+                .entry(key.clone())
+                .or_default()
+                .push(Arc::clone(&notify));
+        }
+        Watch {
+            blocking: self,
+            keys: keys.to_vec(),
+            notify,
+        }
+    }
+
+    // Wakes every client waiting on `key`
+    pub fn signal(&self, key: &str) {
+        if let Some(clients) = self.waiting.lock().unwrap().get(key) {
+            for notify in clients {
+                notify.notify_one();
+            }
+        }
+    }
+
+    // Wakes every waiting client, for when keys change wholesale like with SWAPDB
+    pub fn signal_all(&self) {
+        for notify in self.waiting.lock().unwrap().values().flatten() {
+            notify.notify_one();
+        }
+    }
+}
+
+// A client's registration, which is withdrawn once it's dropped
+pub struct Watch<'a> {
+    blocking: &'a Blocking,
+    keys: Vec<String>,
+    notify: Arc<Notify>,
+}
+
+impl Watch<'_> {
+    // Resolves once any of the keys has been signalled since the last call
+    pub async fn changed(&self) {
+        self.notify.notified().await
+    }
+
+    // Whether no client that started waiting on `key` earlier is still waiting on it
+    pub fn is_first(&self, key: &str) -> bool {
+        match self.blocking.waiting.lock().unwrap().get(key) {
+            Some(clients) => clients.first().is_none_or(|x| Arc::ptr_eq(x, &self.notify)),
+            None => true,
+        }
+    }
+}
+
+impl Drop for Watch<'_> {
+    fn drop(&mut self) {
+        let mut waiting = self.blocking.waiting.lock().unwrap();
+        for key in &self.keys {
+            if let Some(clients) = waiting.get_mut(key) {
+                let was_first = clients
+                    .first()
+                    .is_some_and(|x| Arc::ptr_eq(x, &self.notify));
+                clients.retain(|x| !Arc::ptr_eq(x, &self.notify));
+                match clients.first() {
+                    None => {
+                        waiting.remove(key);
+                    }
+                    // The next in line may have been passed over while this client had its turn
+                    Some(next) if was_first => next.notify_one(),
+                    Some(_) => (),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn signals_before_waiting_are_not_lost() {
+        let blocking = Blocking::default();
+        let keys = vec![String::from("a"), String::from("b")];
+        let watch = blocking.watch(&keys);
+        blocking.signal("c");
+        assert!(timeout(Duration::from_millis(10), watch.changed())
+            .await
+            .is_err());
+        blocking.signal("b");
+        assert!(timeout(Duration::from_millis(10), watch.changed())
+            .await
+            .is_ok());
+        drop(watch);
+        assert!(blocking.waiting.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_next_in_line_is_woken_when_the_first_leaves() {
+        let blocking = Blocking::default();
+        let key = [String::from("a")];
+        let first = blocking.watch(&key);
+        let second = blocking.watch(&key);
+        assert!(first.is_first("a"));
+        assert!(!second.is_first("a"));
+        drop(first);
+        assert!(second.is_first("a"));
+        assert!(timeout(Duration::from_millis(10), second.changed())
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,223 @@
+use super::connection::Transport;
+use super::state::ClientContext;
+
+use crate::resp::Protocol;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::watch;
+
+// Every open connection, ordered by client id, as CLIENT LIST and CLIENT KILL see them
+#[derive(Default)]
+pub struct Clients {
+    connected: Arc<Mutex<BTreeMap<u64, Arc<ConnectionInfo>>>>,
+}
+
+// Keeps its connection listed for as long as it's kept
+pub struct Registered {
+    id: u64,
+    connected: Arc<Mutex<BTreeMap<u64, Arc<ConnectionInfo>>>>,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.connected.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Clients {
+    pub fn register(&self, info: &Arc<ConnectionInfo>) -> Registered {
+        let id = info.client_id;
+        self.connected.lock().unwrap().insert(id, Arc::clone(info));
+        Registered {
+            id,
+            connected: Arc::clone(&self.connected),
+        }
+    }
+
+    pub fn list(&self) -> Vec<Arc<ConnectionInfo>> {
+        self.connected.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.connected.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Closes every connection the filter matches, returning how many there were. `caller` is
+    // the client asking, for SKIPME.
+    pub fn kill(&self, filter: &KillFilter, caller: u64) -> usize {
+        let matching: Vec<Arc<ConnectionInfo>> = self
+            .list()
+            .into_iter()
+            .filter(|x| !(filter.skip_me && x.client_id == caller) && x.matches(filter))
+            .collect();
+        for connection in &matching {
+            connection.kill();
+        }
+        matching.len()
+    }
+}
+
+// Which connections CLIENT KILL closes. Every filter given has to match. The old form, just an
+// address, doesn't skip the caller and replies OK rather than a count.
+#[derive(Debug, PartialEq, Clone)]
+pub struct KillFilter {
+    pub id: Option<u64>,
+    pub addr: Option<String>,
+    pub user: Option<String>,
+    pub skip_me: bool,
+    pub old_form: bool,
+}
+
+// What a connection is doing, kept up to date for CLIENT LIST, and so that it can be reported if
+// the connection panics
+pub struct ConnectionInfo {
+    pub client_id: u64,
+    pub peer: String,
+    transport: Transport,
+    connected_at: Instant,
+    activity: Mutex<Activity>,
+    // Fired by CLIENT KILL, which the connection task waits on alongside its commands
+    killed: watch::Sender<bool>,
+}
+
+struct Activity {
+    // The command being run, or None between commands
+    command: Option<&'static str>,
+    last_command: Option<&'static str>,
+    last_interaction: Instant,
+    // The client's own settings, as of its last command
+    name: Option<String>,
+    db: usize,
+    protocol: Protocol,
+    user: Option<String>,
+}
+
+impl ConnectionInfo {
+    pub fn new(client: &ClientContext, peer: String, transport: Transport) -> Self {
+        let now = Instant::now();
+        Self {
+            client_id: client.id,
+            peer,
+            transport,
+            connected_at: now,
+            activity: Mutex::new(Activity {
+                command: None,
+                last_command: None,
+                last_interaction: now,
+                name: client.name.clone(),
+                db: client.db,
+                protocol: client.protocol,
+                user: client.user.clone(),
+            }),
+            killed: watch::channel(false).0,
+        }
+    }
+
+    pub fn command(&self) -> Option<&'static str> {
+        self.activity.lock().unwrap().command
+    }
+
+    pub fn start_command(&self, name: &'static str) {
+        let mut activity = self.activity.lock().unwrap();
+        activity.command = Some(name);
+        activity.last_command = Some(name);
+        activity.last_interaction = Instant::now();
+    }
+
+    // Picks up whatever the command changed about the client, like its name or database
+    pub fn finish_command(&self, client: &ClientContext) {
+        let mut activity = self.activity.lock().unwrap();
+        activity.command = None;
+        activity.db = client.db;
+        activity.protocol = client.protocol;
+        if activity.name != client.name {
+            activity.name = client.name.clone();
+        }
+        if activity.user != client.user {
+            activity.user = client.user.clone();
+        }
+    }
+
+    // A line of CLIENT LIST. Ages are in seconds.
+    pub fn describe(&self) -> String {
+        let activity = self.activity.lock().unwrap();
+        format!(
+            "id={} addr={} transport={} name={} age={} idle={} db={} resp={} user={} cmd={}",
+            self.client_id,
+            self.peer,
+            self.transport,
+            activity.name.as_deref().unwrap_or(""),
+            self.connected_at.elapsed().as_secs(),
+            activity.last_interaction.elapsed().as_secs(),
+            activity.db,
+            activity.protocol,
+            activity.user.as_deref().unwrap_or(""),
+            activity.last_command.unwrap_or("NULL"),
+        )
+    }
+
+    pub fn kill(&self) {
+        self.killed.send_replace(true);
+    }
+
+    // Fires once the connection is killed
+    pub fn killed(&self) -> watch::Receiver<bool> {
+        self.killed.subscribe()
+    }
+
+    fn matches(&self, filter: &KillFilter) -> bool {
+        let user = self.activity.lock().unwrap().user.clone();
+        filter.id.is_none_or(|x| x == self.client_id)
+            && filter.addr.as_ref().is_none_or(|x| *x == self.peer)
+            && filter
+                .user
+                .as_ref()
+                .is_none_or(|x| user.as_ref() == Some(x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect(clients: &Clients, id: u64, peer: &str) -> (Arc<ConnectionInfo>, Registered) {
+        let info = Arc::new(ConnectionInfo::new(
+            &ClientContext::new(id),
+            peer.to_string(),
+            Transport::Tcp,
+        ));
+        let registered = clients.register(&info);
+        (info, registered)
+    }
+
+    #[test]
+    fn only_connections_matching_every_filter_are_killed() {
+        let clients = Clients::default();
+        let (first, _first) = connect(&clients, 1, "127.0.0.1:1000");
+        let (second, _second) = connect(&clients, 2, "127.0.0.1:2000");
+        let (third, third_registered) = connect(&clients, 3, "127.0.0.1:2000");
+        assert_eq!(clients.len(), 3);
+
+        let filter = KillFilter {
+            id: None,
+            addr: Some(String::from("127.0.0.1:2000")),
+            user: Some(String::from("default")),
+            skip_me: true,
+            old_form: false,
+        };
+        assert_eq!(clients.kill(&filter, 3), 1);
+        assert!(!*first.killed().borrow());
+        assert!(*second.killed().borrow());
+        assert!(!*third.killed().borrow());
+
+        drop(third_registered);
+        let ids: Vec<u64> = clients.list().iter().map(|x| x.client_id).collect();
+        assert_eq!(ids, vec![1, 2]);
+    }
+}
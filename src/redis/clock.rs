@@ -0,0 +1,74 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// The server's notion of time. Expiry is tracked in milliseconds on a monotonic clock, so that
+// wall clock steps from NTP or an operator can't make keys expire early or never. Only
+// persistence and replication deal in absolute UNIX times, converted at the boundary.
+//
+// Like Redis's mstime, the clock is cached: the cron and every command refresh it, so that all
+// the keys a command touches are judged against the same instant. Until it's first refreshed,
+// it's read on every call.
+static CACHED_MSTIME: AtomicU64 = AtomicU64::new(u64::MAX);
+
+pub fn mstime() -> u64 {
+    match CACHED_MSTIME.load(Ordering::Relaxed) {
+        u64::MAX => monotonic_ms(),
+        x => x,
+    }
+}
+
+// Refreshes for different threads can race, so the cached time only ever moves forwards
+pub fn update_cached_time() {
+    CACHED_MSTIME.fetch_max(monotonic_ms(), Ordering::Relaxed);
+}
+
+// Milliseconds elapsed since the clock was first read
+pub fn monotonic_ms() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+pub fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_millis() as u64)
+}
+
+pub fn unix_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_micros() as u64)
+}
+
+// The monotonic time at which the wall clock will read `unix_ms`, as far as can be told now.
+// Times from before the clock started map to its start.
+pub fn unix_ms_to_mstime(unix_ms: u64) -> u64 {
+    let (now, unix_now) = (monotonic_ms(), self::unix_ms());
+    match unix_ms.checked_sub(unix_now) {
+        Some(remaining) => now + remaining,
+        None => now.saturating_sub(unix_now - unix_ms),
+    }
+}
+
+pub fn mstime_to_unix_ms(mstime: u64) -> u64 {
+    let (now, unix_now) = (monotonic_ms(), unix_ms());
+    match mstime.checked_sub(now) {
+        Some(remaining) => unix_now + remaining,
+        None => unix_now.saturating_sub(now - mstime),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_times_survive_the_round_trip() {
+        let in_a_minute = unix_ms() + 60_000;
+        let converted = mstime_to_unix_ms(unix_ms_to_mstime(in_a_minute));
+        // Allow for the clocks ticking between the reads
+        assert!(converted.abs_diff(in_a_minute) <= 2);
+        assert!(unix_ms_to_mstime(1000) <= monotonic_ms());
+    }
+}
@@ -0,0 +1,631 @@
+use bytes::Bytes;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::sync::OnceLock;
+
+// Where a command's keys sit among its arguments. Positions index the full argv, with the command
+// name at 0, as in Redis's firstkey/lastkey/step. Anything that needs a command's keys before
+// running it, be it routing, access checks or COMMAND GETKEYS, should ask here.
+pub enum KeySpec {
+    None,
+    // Every `step`th argument from `first` to `last`. A negative `last` counts back from the end,
+    // -1 being the final argument.
+    Range {
+        first: usize,
+        last: isize,
+        step: usize,
+    },
+    // For commands whose keys depend on other arguments, like a numkeys count or a STORE option
+    Custom(fn(&[Bytes]) -> Vec<usize>),
+}
+
+impl KeySpec {
+    pub fn positions(&self, argv: &[Bytes]) -> Vec<usize> {
+        match self {
+            KeySpec::None => Vec::new(),
+            KeySpec::Range { first, last, step } => {
+                let last = match usize::try_from(*last) {
+                    Ok(x) => x,
+                    Err(_) => match argv.len().checked_sub(last.unsigned_abs()) {
+                        Some(x) => x,
+                        None => return Vec::new(),
+                    },
+                };
+                (*first..=last.min(argv.len().saturating_sub(1)))
+                    .step_by(*step)
+                    .collect()
+            }
+            KeySpec::Custom(find) => find(argv).into_iter().filter(|x| *x < argv.len()).collect(),
+        }
+    }
+
+    // Redis's firstkey, lastkey and step, which COMMAND INFO shows. Zeros for commands without
+    // keys, or whose keys can't be found by position alone.
+    pub fn range(&self) -> (i64, i64, i64) {
+        match self {
+            KeySpec::Range { first, last, step } => (*first as i64, *last as i64, *step as i64),
+            KeySpec::None | KeySpec::Custom(_) => (0, 0, 0),
+        }
+    }
+}
+
+// What kind of command it is, as in Redis's command flags
+pub type CommandFlags = u16;
+
+pub const WRITE: CommandFlags = 1 << 0;
+pub const READONLY: CommandFlags = 1 << 1;
+// May grow the dataset, and so is refused once maxmemory is reached
+pub const DENYOOM: CommandFlags = 1 << 2;
+// For running the server rather than its data, which MONITOR doesn't show
+pub const ADMIN: CommandFlags = 1 << 3;
+pub const PUBSUB: CommandFlags = 1 << 4;
+// Scripts can't run it
+pub const NOSCRIPT: CommandFlags = 1 << 5;
+pub const BLOCKING: CommandFlags = 1 << 6;
+// A replica runs it even while it refuses to serve stale data
+pub const STALE: CommandFlags = 1 << 7;
+pub const FAST: CommandFlags = 1 << 8;
+// A connection may run it before it has authenticated, when a password is required
+pub const NO_AUTH: CommandFlags = 1 << 9;
+
+const FLAG_NAMES: [(CommandFlags, &str); 10] = [
+    (WRITE, "write"),
+    (READONLY, "readonly"),
+    (DENYOOM, "denyoom"),
+    (ADMIN, "admin"),
+    (PUBSUB, "pubsub"),
+    (NOSCRIPT, "noscript"),
+    (BLOCKING, "blocking"),
+    (STALE, "stale"),
+    (FAST, "fast"),
+    (NO_AUTH, "no_auth"),
+];
+
+pub struct CommandSpec {
+    // Lowercase, with subcommands written as "container|subcommand"
+    pub name: &'static str,
+    // How many arguments it takes, counting its name and any subcommand's. Negative for at least
+    // that many.
+    pub arity: i32,
+    pub flags: CommandFlags,
+    // The group COMMAND DOCS puts it in, like "string" or "sorted-set"
+    pub group: &'static str,
+    pub keys: KeySpec,
+}
+
+impl CommandSpec {
+    pub fn has(&self, flag: CommandFlags) -> bool {
+        self.flags & flag != 0
+    }
+
+    // Whether `count` arguments, counted the same way, suit it
+    pub fn accepts(&self, count: usize) -> bool {
+        match usize::try_from(self.arity) {
+            Ok(exactly) => count == exactly,
+            Err(_) => count >= self.arity.unsigned_abs() as usize,
+        }
+    }
+
+    // Its flags by name, with movablekeys for commands whose keys depend on their arguments
+    pub fn flag_names(&self) -> Vec<&'static str> {
+        let mut names: Vec<&str> = FLAG_NAMES
+            .iter()
+            .filter(|(flag, _)| self.has(*flag))
+            .map(|(_, name)| *name)
+            .collect();
+        if matches!(self.keys, KeySpec::Custom(_)) {
+            names.push("movablekeys");
+        }
+        names
+    }
+
+    // The ACL categories it falls in, going by its flags and group
+    pub fn acl_categories(&self) -> Vec<&'static str> {
+        let mut categories = Vec::new();
+        let by_flag = [
+            (WRITE, "@write"),
+            (READONLY, "@read"),
+            (ADMIN, "@admin"),
+            (ADMIN, "@dangerous"),
+            (PUBSUB, "@pubsub"),
+            (BLOCKING, "@blocking"),
+        ];
+        categories.extend(
+            by_flag
+                .iter()
+                .filter(|(x, _)| self.has(*x))
+                .map(|(_, x)| *x),
+        );
+        let by_group = match self.group {
+            "generic" => Some("@keyspace"),
+            "string" => Some("@string"),
+            "list" => Some("@list"),
+            "set" => Some("@set"),
+            "sorted-set" => Some("@sortedset"),
+            "hash" => Some("@hash"),
+            "stream" => Some("@stream"),
+            "bitmap" => Some("@bitmap"),
+            "transactions" => Some("@transaction"),
+            "connection" => Some("@connection"),
+            "scripting" => Some("@scripting"),
+            _ => None,
+        };
+        categories.extend(by_group);
+        categories.push(if self.has(FAST) { "@fast" } else { "@slow" });
+        categories
+    }
+}
+
+const fn spec(
+    name: &'static str,
+    arity: i32,
+    flags: CommandFlags,
+    group: &'static str,
+    keys: KeySpec,
+) -> CommandSpec {
+    CommandSpec {
+        name,
+        arity,
+        flags,
+        group,
+        keys,
+    }
+}
+
+const NO_KEYS: KeySpec = KeySpec::None;
+
+const fn single_key(position: usize) -> KeySpec {
+    KeySpec::Range {
+        first: position,
+        last: position as isize,
+        step: 1,
+    }
+}
+
+const ALL_FROM_FIRST: KeySpec = KeySpec::Range {
+    first: 1,
+    last: -1,
+    step: 1,
+};
+
+// Every key before a trailing argument, like BLPOP's timeout
+const ALL_BUT_LAST: KeySpec = KeySpec::Range {
+    first: 1,
+    last: -2,
+    step: 1,
+};
+
+// Keys each followed by a value, like MSET's
+const KEY_VALUE_PAIRS: KeySpec = KeySpec::Range {
+    first: 1,
+    last: -1,
+    step: 2,
+};
+
+static COMMAND_TABLE: &[CommandSpec] = &[
+    spec("ping", -1, FAST, "connection", NO_KEYS),
+    spec("echo", 2, FAST, "connection", NO_KEYS),
+    spec("set", -3, WRITE | DENYOOM, "string", single_key(1)),
+    spec("get", 2, READONLY | FAST, "string", single_key(1)),
+    spec("info", -1, STALE, "server", NO_KEYS),
+    spec("replconf", -1, ADMIN | NOSCRIPT | STALE, "server", NO_KEYS),
+    spec("psync", -3, ADMIN | NOSCRIPT, "server", NO_KEYS),
+    spec("wait", 3, NOSCRIPT, "generic", NO_KEYS),
+    spec("config|get", -3, ADMIN | STALE, "server", NO_KEYS),
+    spec("config|set", -4, ADMIN | STALE, "server", NO_KEYS),
+    spec("keys", 2, READONLY, "generic", NO_KEYS),
+    spec("del", -2, WRITE, "generic", ALL_FROM_FIRST),
+    spec("exists", -2, READONLY | FAST, "generic", ALL_FROM_FIRST),
+    spec(
+        "rename",
+        3,
+        WRITE,
+        "generic",
+        KeySpec::Range {
+            first: 1,
+            last: 2,
+            step: 1,
+        },
+    ),
+    spec("randomkey", 1, READONLY, "generic", NO_KEYS),
+    spec("dbsize", 1, READONLY | FAST, "server", NO_KEYS),
+    spec("flushdb", -1, WRITE, "server", NO_KEYS),
+    spec("flushall", -1, WRITE, "server", NO_KEYS),
+    spec("swapdb", 3, WRITE | FAST, "server", NO_KEYS),
+    spec("object", -3, READONLY, "generic", single_key(2)),
+    spec("memory|usage", -3, READONLY, "server", single_key(2)),
+    spec("scan", -2, READONLY, "generic", NO_KEYS),
+    spec(
+        "hello",
+        -1,
+        NOSCRIPT | STALE | FAST | NO_AUTH,
+        "connection",
+        NO_KEYS,
+    ),
+    spec(
+        "auth",
+        -2,
+        NOSCRIPT | STALE | FAST | NO_AUTH,
+        "connection",
+        NO_KEYS,
+    ),
+    spec(
+        "quit",
+        -1,
+        NOSCRIPT | STALE | FAST | NO_AUTH,
+        "connection",
+        NO_KEYS,
+    ),
+    spec("select", 2, STALE | FAST, "connection", NO_KEYS),
+    spec("type", 2, READONLY | FAST, "generic", single_key(1)),
+    spec("strlen", 2, READONLY | FAST, "string", single_key(1)),
+    spec("llen", 2, READONLY | FAST, "list", single_key(1)),
+    spec("hlen", 2, READONLY | FAST, "hash", single_key(1)),
+    spec("scard", 2, READONLY | FAST, "set", single_key(1)),
+    spec("zcard", 2, READONLY | FAST, "sorted-set", single_key(1)),
+    spec("xlen", 2, READONLY | FAST, "stream", single_key(1)),
+    spec("acl|setuser", -3, ADMIN | STALE, "server", NO_KEYS),
+    spec("acl|list", 2, ADMIN | STALE, "server", NO_KEYS),
+    spec("acl|log", -2, ADMIN | STALE, "server", NO_KEYS),
+    spec("role", 1, STALE | FAST, "server", NO_KEYS),
+    spec("client|id", 2, STALE, "connection", NO_KEYS),
+    spec("client|setname", 3, STALE, "connection", NO_KEYS),
+    spec("client|getname", 2, STALE, "connection", NO_KEYS),
+    spec("client|list", -2, ADMIN | STALE, "connection", NO_KEYS),
+    spec("client|kill", -3, ADMIN | STALE, "connection", NO_KEYS),
+    spec("replicaof", 3, ADMIN | NOSCRIPT | STALE, "server", NO_KEYS),
+    spec("latency|histogram", -2, ADMIN, "server", NO_KEYS),
+    spec("ttl", 2, READONLY | FAST, "generic", single_key(1)),
+    spec("pttl", 2, READONLY | FAST, "generic", single_key(1)),
+    spec("persist", 2, WRITE | FAST, "generic", single_key(1)),
+    spec("expire", -3, WRITE | FAST, "generic", single_key(1)),
+    spec("incr", 2, WRITE | DENYOOM | FAST, "string", single_key(1)),
+    spec("decr", 2, WRITE | DENYOOM | FAST, "string", single_key(1)),
+    spec("incrby", 3, WRITE | DENYOOM | FAST, "string", single_key(1)),
+    spec("decrby", 3, WRITE | DENYOOM | FAST, "string", single_key(1)),
+    spec("mget", -2, READONLY | FAST, "string", ALL_FROM_FIRST),
+    spec("mset", -3, WRITE | DENYOOM, "string", KEY_VALUE_PAIRS),
+    spec("msetnx", -3, WRITE | DENYOOM, "string", KEY_VALUE_PAIRS),
+    spec("append", 3, WRITE | DENYOOM | FAST, "string", single_key(1)),
+    spec("getrange", 4, READONLY, "string", single_key(1)),
+    spec("setrange", 4, WRITE | DENYOOM, "string", single_key(1)),
+    spec("setbit", 4, WRITE | DENYOOM, "bitmap", single_key(1)),
+    spec("getbit", 3, READONLY | FAST, "bitmap", single_key(1)),
+    spec("bitcount", -2, READONLY, "bitmap", single_key(1)),
+    spec(
+        "bitop",
+        -4,
+        WRITE | DENYOOM,
+        "bitmap",
+        KeySpec::Range {
+            first: 2,
+            last: -1,
+            step: 1,
+        },
+    ),
+    spec("lpush", -3, WRITE | DENYOOM | FAST, "list", single_key(1)),
+    spec("rpush", -3, WRITE | DENYOOM | FAST, "list", single_key(1)),
+    spec("lrange", 4, READONLY, "list", single_key(1)),
+    spec("lpop", -2, WRITE | FAST, "list", single_key(1)),
+    spec("rpop", -2, WRITE | FAST, "list", single_key(1)),
+    spec("blpop", -3, WRITE | BLOCKING, "list", ALL_BUT_LAST),
+    spec("brpop", -3, WRITE | BLOCKING, "list", ALL_BUT_LAST),
+    spec("hset", -4, WRITE | DENYOOM | FAST, "hash", single_key(1)),
+    spec("hget", 3, READONLY | FAST, "hash", single_key(1)),
+    spec("hdel", -3, WRITE | FAST, "hash", single_key(1)),
+    spec("hgetall", 2, READONLY, "hash", single_key(1)),
+    spec(
+        "zadd",
+        -4,
+        WRITE | DENYOOM | FAST,
+        "sorted-set",
+        single_key(1),
+    ),
+    spec("zrange", -4, READONLY, "sorted-set", single_key(1)),
+    spec("zscore", 3, READONLY | FAST, "sorted-set", single_key(1)),
+    spec("zrank", -3, READONLY | FAST, "sorted-set", single_key(1)),
+    spec("xadd", -5, WRITE | DENYOOM | FAST, "stream", single_key(1)),
+    spec("xrange", -4, READONLY, "stream", single_key(1)),
+    spec(
+        "xread",
+        -4,
+        READONLY | BLOCKING,
+        "stream",
+        KeySpec::Custom(xread_keys),
+    ),
+    spec("multi", 1, NOSCRIPT | STALE | FAST, "transactions", NO_KEYS),
+    spec("exec", 1, NOSCRIPT | STALE, "transactions", NO_KEYS),
+    spec(
+        "discard",
+        1,
+        NOSCRIPT | STALE | FAST,
+        "transactions",
+        NO_KEYS,
+    ),
+    spec(
+        "subscribe",
+        -2,
+        PUBSUB | NOSCRIPT | STALE,
+        "pubsub",
+        NO_KEYS,
+    ),
+    spec(
+        "unsubscribe",
+        -1,
+        PUBSUB | NOSCRIPT | STALE,
+        "pubsub",
+        NO_KEYS,
+    ),
+    spec(
+        "psubscribe",
+        -2,
+        PUBSUB | NOSCRIPT | STALE,
+        "pubsub",
+        NO_KEYS,
+    ),
+    spec(
+        "punsubscribe",
+        -1,
+        PUBSUB | NOSCRIPT | STALE,
+        "pubsub",
+        NO_KEYS,
+    ),
+    spec("publish", 3, PUBSUB | STALE | FAST, "pubsub", NO_KEYS),
+    spec("save", 1, ADMIN | NOSCRIPT, "server", NO_KEYS),
+    spec("bgsave", 1, ADMIN | NOSCRIPT, "server", NO_KEYS),
+    spec("bgrewriteaof", 1, ADMIN | NOSCRIPT, "server", NO_KEYS),
+    spec("shutdown", -1, ADMIN | NOSCRIPT | STALE, "server", NO_KEYS),
+    spec("monitor", 1, ADMIN | NOSCRIPT | STALE, "server", NO_KEYS),
+    spec(
+        "debug|panic",
+        2,
+        ADMIN | NOSCRIPT | STALE,
+        "server",
+        NO_KEYS,
+    ),
+    spec("slowlog|get", -2, ADMIN | STALE, "server", NO_KEYS),
+    spec("slowlog|len", 2, ADMIN | STALE, "server", NO_KEYS),
+    spec("slowlog|reset", 2, ADMIN | STALE, "server", NO_KEYS),
+    spec(
+        "eval",
+        -3,
+        NOSCRIPT | STALE,
+        "scripting",
+        KeySpec::Custom(eval_keys),
+    ),
+    spec(
+        "evalsha",
+        -3,
+        NOSCRIPT | STALE,
+        "scripting",
+        KeySpec::Custom(eval_keys),
+    ),
+    spec("script|load", 3, NOSCRIPT | STALE, "scripting", NO_KEYS),
+    spec("script|exists", -3, NOSCRIPT | STALE, "scripting", NO_KEYS),
+    spec("script|flush", -2, NOSCRIPT | STALE, "scripting", NO_KEYS),
+    spec("command", -1, STALE, "server", NO_KEYS),
+    spec("command|count", 2, STALE, "server", NO_KEYS),
+    spec("command|info", -2, STALE, "server", NO_KEYS),
+    spec("command|docs", -2, STALE, "server", NO_KEYS),
+    spec("command|getkeys", -3, STALE, "server", NO_KEYS),
+];
+
+// The first half of the arguments after STREAMS, the second being their IDs
+fn xread_keys(argv: &[Bytes]) -> Vec<usize> {
+    match argv.iter().position(|x| x.eq_ignore_ascii_case(b"streams")) {
+        Some(streams) => {
+            let num_keys = (argv.len() - streams - 1) / 2;
+            (streams + 1..streams + 1 + num_keys).collect()
+        }
+        None => Vec::new(),
+    }
+}
+
+// As many keys as numkeys says, after the script and numkeys
+fn eval_keys(argv: &[Bytes]) -> Vec<usize> {
+    let num_keys = argv
+        .get(2)
+        .and_then(|x| std::str::from_utf8(x).ok()?.parse::<usize>().ok())
+        .unwrap_or(0);
+    (3..3 + num_keys).collect()
+}
+
+pub fn all() -> &'static [CommandSpec] {
+    COMMAND_TABLE
+}
+
+// The table by name, and the containers in it, built the first time a command is looked up
+struct Index {
+    by_name: HashMap<&'static str, &'static CommandSpec>,
+    containers: HashSet<&'static str>,
+}
+
+static INDEX: OnceLock<Index> = OnceLock::new();
+
+fn index() -> &'static Index {
+    INDEX.get_or_init(|| Index {
+        by_name: COMMAND_TABLE.iter().map(|x| (x.name, x)).collect(),
+        containers: COMMAND_TABLE
+            .iter()
+            .filter_map(|x| Some(x.name.split_once('|')?.0))
+            .collect(),
+    })
+}
+
+// Names are mostly lowercase already, so only the others are copied to lowercase them
+fn lowercase(name: &str) -> Cow<'_, str> {
+    match name.bytes().any(|x| x.is_ascii_uppercase()) {
+        true => Cow::Owned(name.to_ascii_lowercase()),
+        false => Cow::Borrowed(name),
+    }
+}
+
+// Looks a command up by its table name, ignoring case
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    index().by_name.get(lowercase(name).as_ref()).copied()
+}
+
+// Whether the command has subcommands, like CONFIG
+pub fn is_container(name: &str) -> bool {
+    index().containers.contains(lowercase(name).as_ref())
+}
+
+// The spec for a command given `first` as its first argument: the container's subcommand if it
+// names one, else the command itself
+pub fn lookup_call(name: &str, first: Option<&str>) -> Option<&'static CommandSpec> {
+    if let Some(subcommand) = first.filter(|_| is_container(name)) {
+        if let Some(x) = lookup(&format!("{}|{}", name, subcommand)) {
+            return Some(x);
+        }
+    }
+    lookup(name)
+}
+
+// The spec for a full argv, resolving subcommands of containers like CONFIG
+pub fn lookup_argv(argv: &[Bytes]) -> Option<&'static CommandSpec> {
+    let name = String::from_utf8_lossy(argv.first()?);
+    let first = argv.get(1).map(|x| String::from_utf8_lossy(x));
+    lookup_call(&name, first.as_deref())
+}
+
+// A command as COMMAND lists it. Containers are listed once with their subcommands, and only
+// some, like COMMAND itself, can be run without one.
+pub struct TopLevel {
+    pub name: &'static str,
+    pub spec: Option<&'static CommandSpec>,
+    pub subcommands: Vec<&'static CommandSpec>,
+}
+
+// Every command, in table order
+pub fn top_level() -> Vec<TopLevel> {
+    let mut commands: Vec<TopLevel> = Vec::new();
+    for spec in COMMAND_TABLE {
+        let (name, subcommand) = match spec.name.split_once('|') {
+            Some((container, _)) => (container, Some(spec)),
+            None => (spec.name, None),
+        };
+        let index = match commands.iter().position(|x| x.name == name) {
+            Some(x) => x,
+            None => {
+                commands.push(TopLevel {
+                    name,
+                    spec: None,
+                    subcommands: Vec::new(),
+                });
+                commands.len() - 1
+            }
+        };
+        match subcommand {
+            Some(x) => commands[index].subcommands.push(x),
+            None => commands[index].spec = Some(spec),
+        }
+    }
+    commands
+}
+
+// The keys of a full argv, or None for commands that don't exist
+pub fn get_keys(argv: &[Bytes]) -> Option<Vec<Bytes>> {
+    let spec = lookup_argv(argv)?;
+    Some(
+        spec.keys
+            .positions(argv)
+            .into_iter()
+            .map(|x| argv[x].clone())
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis::commands::args_to_command;
+    use crate::resp::RespType;
+
+    fn argv(args: &[&str]) -> Vec<Bytes> {
+        args.iter().map(|x| Bytes::from(x.to_string())).collect()
+    }
+
+    #[test]
+    fn parsed_commands_agree_with_their_specs() {
+        let cases: &[&[&str]] = &[
+            &["SET", "k", "v", "px", "100"],
+            &["GET", "k"],
+            &["DEL", "a", "b", "c"],
+            &["OBJECT", "encoding", "k"],
+            &["MEMORY", "usage", "k", "samples", "5"],
+            &["CONFIG", "get", "maxmemory"],
+            &["KEYS", "*"],
+            &["ZCARD", "k"],
+            &["XADD", "k", "*", "f", "v"],
+            &["XREAD", "COUNT", "2", "STREAMS", "a", "b", "0", "$"],
+            &["EVAL", "script", "2", "a", "b", "arg"],
+        ];
+        for case in cases {
+            let argv = argv(case);
+            let args = argv[1..]
+                .iter()
+                .map(|x| RespType::BulkString(Some(x.clone())))
+                .collect();
+            let command = args_to_command(case[0], args).unwrap();
+            assert_eq!(lookup_argv(&argv).unwrap().name, command.name());
+            let keys: Vec<Bytes> = command.keys().into_iter().map(Bytes::from).collect();
+            assert_eq!(get_keys(&argv), Some(keys), "{:?}", case);
+        }
+        assert_eq!(get_keys(&argv(&["NOSUCHCOMMAND", "k"])), None);
+    }
+
+    #[test]
+    fn ranges_and_custom_specs_find_their_positions() {
+        let args = argv(&["MSET", "a", "1", "b", "2"]);
+        let pairs = KeySpec::Range {
+            first: 1,
+            last: -1,
+            step: 2,
+        };
+        assert_eq!(pairs.positions(&args), vec![1, 3]);
+        let short = KeySpec::Range {
+            first: 1,
+            last: -10,
+            step: 1,
+        };
+        assert!(short.positions(&args).is_empty());
+
+        // EVAL script numkeys key...
+        fn numkeys(argv: &[Bytes]) -> Vec<usize> {
+            let count = std::str::from_utf8(&argv[2]).unwrap().parse::<usize>();
+            (3..3 + count.unwrap_or(0)).collect()
+        }
+        let eval = argv(&["EVAL", "return 1", "5", "a"]);
+        assert_eq!(KeySpec::Custom(numkeys).positions(&eval), vec![3]);
+    }
+
+    #[test]
+    fn specs_are_found_with_their_subcommands() {
+        let spec = lookup_call("config", Some("GET")).unwrap();
+        assert_eq!(spec.name, "config|get");
+        assert!(spec.accepts(3) && spec.accepts(5) && !spec.accepts(2));
+        assert!(spec.has(ADMIN) && !spec.has(WRITE));
+        // COMMAND runs on its own as well as with a subcommand
+        assert_eq!(lookup_call("command", None).unwrap().name, "command");
+        assert_eq!(
+            lookup_call("command", Some("bogus")).unwrap().name,
+            "command"
+        );
+        assert!(lookup("GET").unwrap().accepts(2) && !lookup("get").unwrap().accepts(3));
+        assert_eq!(
+            lookup("xread").unwrap().flag_names(),
+            ["readonly", "blocking", "movablekeys"]
+        );
+        assert_eq!(
+            lookup("lpush").unwrap().acl_categories(),
+            ["@write", "@list", "@fast"]
+        );
+
+        let commands = top_level();
+        let config = commands.iter().find(|x| x.name == "config").unwrap();
+        assert!(config.spec.is_none());
+        assert_eq!(config.subcommands.len(), 2);
+        let names: Vec<&str> = commands.iter().map(|x| x.name).collect();
+        assert!(names.contains(&"get") && !names.iter().any(|x| x.contains('|')));
+    }
+}
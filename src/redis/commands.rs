@@ -1,208 +1,1822 @@
+use super::bitmap::{BitOperation, BitUnit};
+use super::clients::KillFilter;
+use super::clock;
+use super::command_table::{self, CommandSpec};
+use super::stream::{IdSpec, StreamId};
+use super::string::NOT_AN_INTEGER_ERROR;
+
 use crate::resp::RespType;
 
-#[derive(Debug)]
+use bytes::Bytes;
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum Command {
     Ping,
     Echo(String),
-    Set(String, String, Option<u64>),
+    Set(String, Bytes, SetOptions),
     Get(String),
-    Info(String),
-    ReplConf(String, Option<String>),
+    Info(Vec<String>),
+    ReplConf(String, Vec<String>),
     Psync(String, String),
     Wait(i32, i32),
-    ConfigGet(String),
+    // Glob-style patterns of the parameters to get
+    ConfigGet(Vec<String>),
+    // Parameters, lowercased, and the values to set them to
+    ConfigSet(Vec<(String, String)>),
     Keys(String),
+    Del(Vec<String>),
+    Exists(Vec<String>),
+    // The key and its new name
+    Rename(String, String),
+    RandomKey,
+    DbSize,
+    // Whether to free the keys in the background, if ASYNC or SYNC says so
+    FlushDb(Option<bool>),
+    FlushAll(Option<bool>),
+    // The two database indices, as given
+    SwapDb(String, String),
+    Object(String, String),
+    MemoryUsage(String),
+    // Cursor, count, and optionally a pattern keys must match and a type to filter by
+    Scan(u64, usize, Option<String>, Option<String>),
+    // The protocol version to switch to, if any, and the username and password to authenticate
+    // with
+    Hello(Option<String>, Option<(String, String)>),
+    // The username, the default user's if not given, and the password
+    Auth(Option<String>, String),
+    Quit,
+    Select(String),
+    Type(String),
+    // The length commands, each only accepting keys of its own type
+    Strlen(String),
+    Llen(String),
+    Hlen(String),
+    Scard(String),
+    Zcard(String),
+    Xlen(String),
+    // A username and the rules to apply to it
+    AclSetUser(String, Vec<String>),
+    AclList,
+    // Either how many entries to show, or RESET
+    AclLog(Option<String>),
+    Role,
+    ClientId,
+    // An empty name clears it
+    ClientSetName(String),
+    ClientGetName,
+    ClientList,
+    ClientKill(KillFilter),
+    // A host and port, or NO ONE to stop replicating
+    ReplicaOf(String, String),
+    // The commands to show, all of them if none are given
+    LatencyHistogram(Vec<String>),
+    Ttl(String),
+    Pttl(String),
+    Persist(String),
+    // Also what PEXPIRE and PEXPIREAT parse to, the latter being how masters propagate EXPIRE
+    Expire(String, Expiry),
+    Incr(String),
+    Decr(String),
+    IncrBy(String, i64),
+    DecrBy(String, i64),
+    MGet(Vec<String>),
+    // Key and value pairs, set all at once
+    MSet(Vec<(String, Bytes)>),
+    MSetNx(Vec<(String, Bytes)>),
+    Append(String, Bytes),
+    // Start and end offsets, both inclusive
+    GetRange(String, i64, i64),
+    // The offset to write the value at
+    SetRange(String, usize, Bytes),
+    // The offset of the bit and what to set it to
+    SetBit(String, u64, bool),
+    GetBit(String, u64),
+    // Start and end, both inclusive, and whether they count bytes or bits
+    BitCount(String, Option<(i64, i64, BitUnit)>),
+    // The destination key followed by the sources
+    BitOp(BitOperation, String, Vec<String>),
+    LPush(String, Vec<Bytes>),
+    RPush(String, Vec<Bytes>),
+    // Start and end indices, both inclusive
+    LRange(String, i64, i64),
+    // How many elements to pop, if given, which makes the reply an array
+    LPop(String, Option<usize>),
+    RPop(String, Option<usize>),
+    // The keys to pop from, the first holding a list going first, and the timeout in
+    // milliseconds, None waiting for as long as it takes
+    BLPop(Vec<String>, Option<u64>),
+    BRPop(Vec<String>, Option<u64>),
+    // Field and value pairs
+    HSet(String, Vec<(Bytes, Bytes)>),
+    HGet(String, Bytes),
+    HDel(String, Vec<Bytes>),
+    HGetAll(String),
+    // Score and member pairs
+    ZAdd(String, Vec<(f64, Bytes)>),
+    // Start and end indices, both inclusive, and whether to reply WITHSCORES
+    ZRange(String, i64, i64, bool),
+    ZScore(String, Bytes),
+    ZRank(String, Bytes),
+    XAdd(String, IdSpec, Vec<(Bytes, Bytes)>),
+    // Start and end IDs, both inclusive, and how many entries to return at most
+    XRange(String, StreamId, StreamId, Option<usize>),
+    // COUNT, BLOCK in milliseconds, and the streams with the ID to read after, None being "$"
+    XRead(Option<usize>, Option<u64>, Vec<(String, Option<StreamId>)>),
+    Multi,
+    Exec,
+    Discard,
+    // Channels for SUBSCRIBE, glob-style patterns for PSUBSCRIBE. Unsubscribing from none means
+    // from all of them.
+    Subscribe(Vec<Bytes>),
+    Unsubscribe(Vec<Bytes>),
+    PSubscribe(Vec<Bytes>),
+    PUnsubscribe(Vec<Bytes>),
+    // Channel and message
+    Publish(Bytes, Bytes),
+    Save,
+    BgSave,
+    BgRewriteAof,
+    // Whether to save the RDB file first, if SAVE or NOSAVE says so
+    Shutdown(Option<bool>),
+    Monitor,
+    // How many entries, or "-1" for all of them
+    SlowlogGet(Option<String>),
+    SlowlogLen,
+    SlowlogReset,
+    // The script's body, or its SHA1 for EVALSHA, then its KEYS and ARGV
+    Eval(String, Vec<String>, Vec<Bytes>),
+    EvalSha(String, Vec<String>, Vec<Bytes>),
+    ScriptLoad(String),
+    ScriptExists(Vec<String>),
+    // ASYNC or SYNC, if given, which makes no difference here
+    ScriptFlush(Option<bool>),
+    // COMMAND, which describes every command
+    CommandList,
+    CommandCount,
+    // The commands to describe, all of them if none are given
+    CommandInfo(Vec<String>),
+    CommandDocs(Vec<String>),
+    // A full command, whose keys to find
+    CommandGetKeys(Vec<Bytes>),
+    // Panics the way a bug would, which ends the connection and nothing else
+    DebugPanic,
+}
+
+// A key's expiration as given to SET
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Expiry {
+    // PX, milliseconds from now
+    After(u64),
+    // PXAT, a UNIX time in milliseconds. Masters propagate every expiration this way, so that
+    // replicas expire keys when the master does however late they apply the write.
+    At(u64),
+}
+
+// SET's NX and XX
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SetCondition {
+    IfMissing,
+    IfExists,
+}
+
+// Everything SET accepts after the key and value
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct SetOptions {
+    pub expiry: Option<Expiry>,
+    pub condition: Option<SetCondition>,
+    // KEEPTTL, keep the key's current expiration rather than clearing it
+    pub keep_ttl: bool,
+    // GET, reply with the value the key had before
+    pub get: bool,
+}
+
+impl Expiry {
+    // When the key expires, in clock::mstime milliseconds
+    pub fn deadline(&self) -> u64 {
+        match self {
+            Expiry::After(ms) => clock::mstime() + ms,
+            Expiry::At(unix_ms) => clock::unix_ms_to_mstime(*unix_ms),
+        }
+    }
+
+    pub fn to_absolute(self) -> Self {
+        match self {
+            Expiry::After(ms) => Expiry::At(clock::unix_ms() + ms),
+            absolute => absolute,
+        }
+    }
 }
 
 impl Command {
+    // Lowercase, as commands are reported by Redis
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Ping => "ping",
+            Command::Echo(_) => "echo",
+            Command::Set(_, _, _) => "set",
+            Command::Get(_) => "get",
+            Command::Info(_) => "info",
+            Command::ReplConf(_, _) => "replconf",
+            Command::Psync(_, _) => "psync",
+            Command::Wait(_, _) => "wait",
+            Command::ConfigGet(_) => "config|get",
+            Command::ConfigSet(_) => "config|set",
+            Command::Keys(_) => "keys",
+            Command::Del(_) => "del",
+            Command::Exists(_) => "exists",
+            Command::Rename(_, _) => "rename",
+            Command::RandomKey => "randomkey",
+            Command::DbSize => "dbsize",
+            Command::FlushDb(_) => "flushdb",
+            Command::FlushAll(_) => "flushall",
+            Command::SwapDb(_, _) => "swapdb",
+            Command::Object(_, _) => "object",
+            Command::MemoryUsage(_) => "memory|usage",
+            Command::Scan(_, _, _, _) => "scan",
+            Command::Hello(_, _) => "hello",
+            Command::Auth(_, _) => "auth",
+            Command::Quit => "quit",
+            Command::Select(_) => "select",
+            Command::Type(_) => "type",
+            Command::Strlen(_) => "strlen",
+            Command::Llen(_) => "llen",
+            Command::Hlen(_) => "hlen",
+            Command::Scard(_) => "scard",
+            Command::Zcard(_) => "zcard",
+            Command::Xlen(_) => "xlen",
+            Command::AclSetUser(_, _) => "acl|setuser",
+            Command::AclList => "acl|list",
+            Command::AclLog(_) => "acl|log",
+            Command::Role => "role",
+            Command::ClientId => "client|id",
+            Command::ClientSetName(_) => "client|setname",
+            Command::ClientGetName => "client|getname",
+            Command::ClientList => "client|list",
+            Command::ClientKill(_) => "client|kill",
+            Command::Monitor => "monitor",
+            Command::DebugPanic => "debug|panic",
+            Command::SlowlogGet(_) => "slowlog|get",
+            Command::SlowlogLen => "slowlog|len",
+            Command::SlowlogReset => "slowlog|reset",
+            Command::Eval(_, _, _) => "eval",
+            Command::EvalSha(_, _, _) => "evalsha",
+            Command::ScriptLoad(_) => "script|load",
+            Command::ScriptExists(_) => "script|exists",
+            Command::ScriptFlush(_) => "script|flush",
+            Command::CommandList => "command",
+            Command::CommandCount => "command|count",
+            Command::CommandInfo(_) => "command|info",
+            Command::CommandDocs(_) => "command|docs",
+            Command::CommandGetKeys(_) => "command|getkeys",
+            Command::ReplicaOf(_, _) => "replicaof",
+            Command::LatencyHistogram(_) => "latency|histogram",
+            Command::Ttl(_) => "ttl",
+            Command::Pttl(_) => "pttl",
+            Command::Persist(_) => "persist",
+            Command::Expire(_, _) => "expire",
+            Command::Incr(_) => "incr",
+            Command::Decr(_) => "decr",
+            Command::IncrBy(_, _) => "incrby",
+            Command::DecrBy(_, _) => "decrby",
+            Command::MGet(_) => "mget",
+            Command::MSet(_) => "mset",
+            Command::MSetNx(_) => "msetnx",
+            Command::Append(_, _) => "append",
+            Command::GetRange(_, _, _) => "getrange",
+            Command::SetRange(_, _, _) => "setrange",
+            Command::SetBit(_, _, _) => "setbit",
+            Command::GetBit(_, _) => "getbit",
+            Command::BitCount(_, _) => "bitcount",
+            Command::BitOp(_, _, _) => "bitop",
+            Command::LPush(_, _) => "lpush",
+            Command::RPush(_, _) => "rpush",
+            Command::LRange(_, _, _) => "lrange",
+            Command::LPop(_, _) => "lpop",
+            Command::RPop(_, _) => "rpop",
+            Command::BLPop(_, _) => "blpop",
+            Command::BRPop(_, _) => "brpop",
+            Command::HSet(_, _) => "hset",
+            Command::HGet(_, _) => "hget",
+            Command::HDel(_, _) => "hdel",
+            Command::HGetAll(_) => "hgetall",
+            Command::ZAdd(_, _) => "zadd",
+            Command::ZRange(_, _, _, _) => "zrange",
+            Command::ZScore(_, _) => "zscore",
+            Command::ZRank(_, _) => "zrank",
+            Command::XAdd(_, _, _) => "xadd",
+            Command::XRange(_, _, _, _) => "xrange",
+            Command::XRead(_, _, _) => "xread",
+            Command::Multi => "multi",
+            Command::Exec => "exec",
+            Command::Discard => "discard",
+            Command::Subscribe(_) => "subscribe",
+            Command::Unsubscribe(_) => "unsubscribe",
+            Command::PSubscribe(_) => "psubscribe",
+            Command::PUnsubscribe(_) => "punsubscribe",
+            Command::Publish(_, _) => "publish",
+            Command::Save => "save",
+            Command::Shutdown(_) => "shutdown",
+            Command::BgSave => "bgsave",
+            Command::BgRewriteAof => "bgrewriteaof",
+        }
+    }
+
+    // The command as a client would send it, name first. Parsing these arguments gives back the
+    // same command, which is how commands are propagated to replicas.
+    pub fn to_args(&self) -> Vec<Bytes> {
+        let mut args: Vec<Bytes> = self
+            .name()
+            .split('|')
+            .map(|x| arg(x.to_uppercase()))
+            .collect();
+        match self {
+            Command::Ping
+            | Command::Quit
+            | Command::AclList
+            | Command::Role
+            | Command::ClientId
+            | Command::ClientGetName
+            | Command::Monitor
+            | Command::DebugPanic
+            | Command::SlowlogLen
+            | Command::SlowlogReset
+            | Command::ClientList
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Save
+            | Command::BgSave
+            | Command::BgRewriteAof
+            | Command::RandomKey
+            | Command::DbSize
+            | Command::CommandList
+            | Command::CommandCount => (),
+            Command::Echo(x)
+            | Command::Get(x)
+            | Command::Keys(x)
+            | Command::MemoryUsage(x)
+            | Command::Select(x)
+            | Command::ClientSetName(x)
+            | Command::Type(x)
+            | Command::Strlen(x)
+            | Command::Llen(x)
+            | Command::Hlen(x)
+            | Command::Scard(x)
+            | Command::Zcard(x)
+            | Command::Xlen(x)
+            | Command::Ttl(x)
+            | Command::Pttl(x)
+            | Command::Persist(x)
+            | Command::Incr(x)
+            | Command::Decr(x)
+            | Command::HGetAll(x)
+            | Command::ScriptLoad(x) => args.push(arg(x)),
+            Command::Set(key, value, options) => {
+                args.extend([arg(key), value.clone()]);
+                match options.expiry {
+                    Some(Expiry::After(ms)) => args.extend([arg("PX"), arg(ms)]),
+                    Some(Expiry::At(unix_ms)) => args.extend([arg("PXAT"), arg(unix_ms)]),
+                    None => (),
+                }
+                let flags = [
+                    (options.condition == Some(SetCondition::IfMissing), "NX"),
+                    (options.condition == Some(SetCondition::IfExists), "XX"),
+                    (options.keep_ttl, "KEEPTTL"),
+                    (options.get, "GET"),
+                ];
+                args.extend(flags.iter().filter(|(set, _)| *set).map(|(_, x)| arg(x)));
+            }
+            Command::ReplConf(x, y) => args.extend([arg(x)].into_iter().chain(y.iter().map(arg))),
+            Command::Psync(x, y)
+            | Command::Object(x, y)
+            | Command::Rename(x, y)
+            | Command::SwapDb(x, y)
+            | Command::ReplicaOf(x, y) => args.extend([arg(x), arg(y)]),
+            Command::FlushDb(lazy) | Command::FlushAll(lazy) | Command::ScriptFlush(lazy) => {
+                args.extend(lazy.map(|x| arg(if x { "ASYNC" } else { "SYNC" })))
+            }
+            Command::Shutdown(save) => {
+                args.extend(save.map(|x| arg(if x { "SAVE" } else { "NOSAVE" })))
+            }
+            Command::Wait(replicas, timeout) => args.extend([arg(replicas), arg(timeout)]),
+            Command::Del(keys)
+            | Command::Exists(keys)
+            | Command::ConfigGet(keys)
+            | Command::MGet(keys)
+            | Command::ScriptExists(keys)
+            | Command::CommandInfo(keys)
+            | Command::CommandDocs(keys) => args.extend(keys.iter().map(arg)),
+            Command::MSet(pairs) | Command::MSetNx(pairs) => {
+                args.extend(pairs.iter().flat_map(|(x, y)| [arg(x), y.clone()]))
+            }
+            Command::ConfigSet(pairs) => {
+                args.extend(pairs.iter().flat_map(|(x, y)| [arg(x), arg(y)]))
+            }
+            Command::Scan(cursor, count, pattern, type_name) => {
+                args.extend([arg(cursor), arg("COUNT"), arg(count)]);
+                if let Some(x) = pattern {
+                    args.extend([arg("MATCH"), arg(x)]);
+                }
+                if let Some(x) = type_name {
+                    args.extend([arg("TYPE"), arg(x)]);
+                }
+            }
+            Command::Hello(version, auth) => {
+                args.extend(version.iter().map(arg));
+                if let Some((username, password)) = auth {
+                    args.extend([arg("AUTH"), arg(username), arg(password)]);
+                }
+            }
+            Command::Auth(username, password) => {
+                args.extend(username.iter().map(arg));
+                args.push(arg(password));
+            }
+            Command::AclSetUser(username, rules) => {
+                args.push(arg(username));
+                args.extend(rules.iter().map(arg));
+            }
+            Command::AclLog(x) | Command::SlowlogGet(x) => args.extend(x.iter().map(arg)),
+            Command::ClientKill(filter) if filter.old_form => {
+                args.extend(filter.addr.iter().map(arg))
+            }
+            Command::ClientKill(filter) => {
+                args.extend(filter.id.iter().flat_map(|x| [arg("ID"), arg(x)]));
+                args.extend(filter.addr.iter().flat_map(|x| [arg("ADDR"), arg(x)]));
+                args.extend(filter.user.iter().flat_map(|x| [arg("USER"), arg(x)]));
+                args.extend([
+                    arg("SKIPME"),
+                    arg(if filter.skip_me { "yes" } else { "no" }),
+                ]);
+            }
+            Command::LatencyHistogram(commands) => args.extend(commands.iter().map(arg)),
+            Command::Info(sections) => args.extend(sections.iter().map(arg)),
+            // PEXPIRE and PEXPIREAT, which say exactly what the expiry holds
+            Command::Expire(key, expiry) => {
+                args = match expiry {
+                    Expiry::After(ms) => vec![arg("PEXPIRE"), arg(key), arg(ms)],
+                    Expiry::At(unix_ms) => vec![arg("PEXPIREAT"), arg(key), arg(unix_ms)],
+                }
+            }
+            Command::IncrBy(key, amount) | Command::DecrBy(key, amount) => {
+                args.extend([arg(key), arg(amount)])
+            }
+            Command::LPush(key, values)
+            | Command::RPush(key, values)
+            | Command::HDel(key, values) => {
+                args.push(arg(key));
+                args.extend(values.iter().cloned());
+            }
+            Command::LRange(key, start, end) | Command::GetRange(key, start, end) => {
+                args.extend([arg(key), arg(start), arg(end)])
+            }
+            Command::Append(key, value) => args.extend([arg(key), value.clone()]),
+            Command::SetRange(key, offset, value) => {
+                args.extend([arg(key), arg(offset), value.clone()])
+            }
+            Command::SetBit(key, offset, on) => {
+                args.extend([arg(key), arg(offset), arg(*on as u8)])
+            }
+            Command::GetBit(key, offset) => args.extend([arg(key), arg(offset)]),
+            Command::BitCount(key, range) => {
+                args.push(arg(key));
+                if let Some((start, end, unit)) = range {
+                    args.extend([arg(start), arg(end)]);
+                    if *unit == BitUnit::Bit {
+                        args.push(arg("BIT"));
+                    }
+                }
+            }
+            Command::BitOp(operation, destination, keys) => {
+                args.extend([arg(operation), arg(destination)]);
+                args.extend(keys.iter().map(arg));
+            }
+            Command::LPop(key, count) | Command::RPop(key, count) => {
+                args.push(arg(key));
+                args.extend(count.iter().map(arg));
+            }
+            Command::BLPop(keys, timeout) | Command::BRPop(keys, timeout) => {
+                args.extend(keys.iter().map(arg));
+                // In seconds, as given
+                args.push(arg(timeout.unwrap_or(0) as f64 / 1000.0));
+            }
+            Command::HSet(key, pairs) => {
+                args.push(arg(key));
+                args.extend(pairs.iter().flat_map(|(x, y)| [x.clone(), y.clone()]));
+            }
+            Command::HGet(key, member)
+            | Command::ZScore(key, member)
+            | Command::ZRank(key, member) => args.extend([arg(key), member.clone()]),
+            Command::ZAdd(key, pairs) => {
+                args.push(arg(key));
+                args.extend(pairs.iter().flat_map(|(x, y)| [arg(x), y.clone()]));
+            }
+            Command::ZRange(key, start, end, with_scores) => {
+                args.extend([arg(key), arg(start), arg(end)]);
+                if *with_scores {
+                    args.push(arg("WITHSCORES"));
+                }
+            }
+            Command::XAdd(key, id, fields) => {
+                let id = match id {
+                    IdSpec::Auto => String::from("*"),
+                    IdSpec::AutoSeq(ms) => format!("{}-*", ms),
+                    IdSpec::Explicit(id) => id.to_string(),
+                };
+                args.extend([arg(key), arg(id)]);
+                args.extend(fields.iter().flat_map(|(x, y)| [x.clone(), y.clone()]));
+            }
+            Command::XRange(key, start, end, count) => {
+                args.extend([arg(key), arg(start), arg(end)]);
+                if let Some(x) = count {
+                    args.extend([arg("COUNT"), arg(x)]);
+                }
+            }
+            Command::XRead(count, block, streams) => {
+                if let Some(x) = count {
+                    args.extend([arg("COUNT"), arg(x)]);
+                }
+                if let Some(x) = block {
+                    args.extend([arg("BLOCK"), arg(x)]);
+                }
+                args.push(arg("STREAMS"));
+                args.extend(streams.iter().map(|(key, _)| arg(key)));
+                args.extend(streams.iter().map(|(_, id)| match id {
+                    Some(x) => arg(x),
+                    None => arg("$"),
+                }));
+            }
+            Command::Subscribe(names)
+            | Command::Unsubscribe(names)
+            | Command::PSubscribe(names)
+            | Command::PUnsubscribe(names) => args.extend(names.iter().cloned()),
+            Command::Publish(channel, message) => args.extend([channel.clone(), message.clone()]),
+            Command::CommandGetKeys(argv) => args.extend(argv.iter().cloned()),
+            Command::Eval(script, keys, argv) | Command::EvalSha(script, keys, argv) => {
+                args.extend([arg(script), arg(keys.len())]);
+                args.extend(keys.iter().map(arg));
+                args.extend(argv.iter().cloned());
+            }
+        }
+        args
+    }
+
+    pub fn spec(&self) -> &'static CommandSpec {
+        match command_table::lookup(self.name()) {
+            Some(x) => x,
+            None => panic!("{} is missing from the command table", self.name()),
+        }
+    }
+
+    // Whether a replica runs the command even while it refuses to serve stale data, like Redis's
+    // CMD_STALE flag
+    pub fn is_allowed_when_stale(&self) -> bool {
+        self.spec().has(command_table::STALE)
+    }
+
+    // Commands for running the server rather than its data, like Redis's CMD_ADMIN flag, which
+    // MONITOR doesn't show
+    pub fn is_admin(&self) -> bool {
+        self.spec().has(command_table::ADMIN)
+    }
+
     pub fn is_write(&self) -> bool {
+        self.spec().has(command_table::WRITE)
+    }
+
+    // Writes whose effect isn't known until they've run, like the ID XADD generates, or which key
+    // BLPOP pops from. They propagate what they did themselves, rather than the command as it was
+    // given.
+    pub fn is_propagated_after_running(&self) -> bool {
+        matches!(
+            self,
+            Command::XAdd(_, _, _) | Command::BLPop(_, _) | Command::BRPop(_, _)
+        )
+    }
+
+    // What a RESP2 client may run while it's subscribed to anything
+    // What a connection may run before it has authenticated, when a password is required
+    pub fn is_allowed_unauthenticated(&self) -> bool {
+        self.spec().has(command_table::NO_AUTH)
+    }
+
+    pub fn is_allowed_when_subscribed(&self) -> bool {
+        matches!(
+            self,
+            Command::Ping
+                | Command::Subscribe(_)
+                | Command::Unsubscribe(_)
+                | Command::PSubscribe(_)
+                | Command::PUnsubscribe(_)
+        )
+    }
+
+    // Commands that can wait indefinitely for other clients, which mustn't hold up a transaction
+    // meanwhile
+    pub fn is_blocking(&self) -> bool {
+        matches!(
+            self,
+            Command::XRead(_, Some(_), _)
+                | Command::BLPop(_, _)
+                | Command::BRPop(_, _)
+                | Command::Wait(_, _)
+        )
+    }
+
+    // Commands scripts can't run, like Redis's CMD_NOSCRIPT flag
+    pub fn is_noscript(&self) -> bool {
+        self.spec().has(command_table::NOSCRIPT)
+    }
+
+    // The command as it runs inside a transaction or a script, where nothing may block, as in
+    // Redis
+    pub fn without_blocking(self) -> Command {
+        match self {
+            Command::XRead(count, Some(_), streams) => Command::XRead(count, None, streams),
+            Command::BLPop(keys, _) => Command::BLPop(keys, Some(0)),
+            Command::BRPop(keys, _) => Command::BRPop(keys, Some(0)),
+            command => command,
+        }
+    }
+
+    // Whether a master sends the command down the replication stream. Finding anything else there
+    // means a replica has lost its place in the stream.
+    pub fn is_replicated(&self) -> bool {
+        self.is_write()
+            || matches!(
+                self,
+                Command::Ping | Command::Select(_) | Command::ReplConf(_, _)
+            )
+    }
+
+    // Whether the command may grow the dataset, and so must be refused once maxmemory is reached
+    pub fn is_denyoom(&self) -> bool {
+        self.spec().has(command_table::DENYOOM)
+    }
+
+    // The keys in the keyspace this command reads or writes, the same ones its KeySpec finds in
+    // the raw arguments
+    pub fn keys(&self) -> Vec<String> {
         match self {
-            Command::Set(_, _, _) => true,
-            _ => false,
+            Command::Set(key, _, _)
+            | Command::Get(key)
+            | Command::Object(_, key)
+            | Command::MemoryUsage(key)
+            | Command::Type(key)
+            | Command::Strlen(key)
+            | Command::Llen(key)
+            | Command::Hlen(key)
+            | Command::Scard(key)
+            | Command::Zcard(key)
+            | Command::Xlen(key)
+            | Command::Ttl(key)
+            | Command::Pttl(key)
+            | Command::Persist(key)
+            | Command::Expire(key, _)
+            | Command::Incr(key)
+            | Command::Decr(key)
+            | Command::IncrBy(key, _)
+            | Command::DecrBy(key, _)
+            | Command::Append(key, _)
+            | Command::GetRange(key, _, _)
+            | Command::SetRange(key, _, _)
+            | Command::SetBit(key, _, _)
+            | Command::GetBit(key, _)
+            | Command::BitCount(key, _)
+            | Command::LPush(key, _)
+            | Command::RPush(key, _)
+            | Command::LRange(key, _, _)
+            | Command::LPop(key, _)
+            | Command::RPop(key, _)
+            | Command::HSet(key, _)
+            | Command::HGet(key, _)
+            | Command::HDel(key, _)
+            | Command::HGetAll(key)
+            | Command::ZAdd(key, _)
+            | Command::ZRange(key, _, _, _)
+            | Command::ZScore(key, _)
+            | Command::ZRank(key, _)
+            | Command::XAdd(key, _, _)
+            | Command::XRange(key, _, _, _) => vec![key.clone()],
+            Command::XRead(_, _, streams) => streams.iter().map(|(key, _)| key.clone()).collect(),
+            Command::Eval(_, keys, _) | Command::EvalSha(_, keys, _) => keys.clone(),
+            Command::Del(keys)
+            | Command::Exists(keys)
+            | Command::MGet(keys)
+            | Command::BLPop(keys, _)
+            | Command::BRPop(keys, _) => keys.clone(),
+            Command::Rename(key, new_key) => vec![key.clone(), new_key.clone()],
+            Command::MSet(pairs) | Command::MSetNx(pairs) => {
+                pairs.iter().map(|(key, _)| key.clone()).collect()
+            }
+            Command::BitOp(_, destination, keys) => {
+                [destination].into_iter().chain(keys).cloned().collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // The pub/sub channels the command subscribes or publishes to, or with PSUBSCRIBE the
+    // patterns it subscribes to. Unsubscribing needs no permission.
+    pub fn channels(&self) -> &[Bytes] {
+        match self {
+            Command::Subscribe(channels) | Command::PSubscribe(channels) => channels,
+            Command::Publish(channel, _) => std::slice::from_ref(channel),
+            _ => &[],
         }
     }
 }
 
+// Longer than any command name, which leaves room for the ones still to come
+const MAX_COMMAND_NAME_LENGTH: usize = 32;
+
 // Public
-pub fn args_to_command(command_name: &str, args: Vec<RespType>) -> Command {
-    match command_name.to_lowercase().as_str() {
-        "echo" => create_echo(args),
-        "ping" => create_ping(args),
-        "set" => create_set(args),
-        "info" => create_info(args),
-        "get" => create_get(args),
-        "replconf" => create_replconf(args),
-        "psync" => create_psync(args),
-        "wait" => create_wait(args),
-        "config" => create_config(args),
-        "keys" => create_key(args),
-        other @ _ => panic!("No support for command type: {}", other),
+pub fn args_to_command(command_name: &str, args: Vec<RespType>) -> Result<Command, String> {
+    // Names are matched case-insensitively, lowercased on the stack rather than into a String
+    let mut lowercase = [0u8; MAX_COMMAND_NAME_LENGTH];
+    let name = match lowercase.get_mut(..command_name.len()) {
+        Some(name) => {
+            name.copy_from_slice(command_name.as_bytes());
+            name.make_ascii_lowercase();
+            std::str::from_utf8(name).expect("ASCII lowercasing keeps UTF-8 valid")
+        }
+        None => "",
+    };
+    // Argument counts the command table rules out never reach the command's own parser. Only
+    // containers need their first argument, to find the subcommand's spec.
+    let first = match command_table::is_container(name) {
+        true => args.first().and_then(turn_arg_to_string),
+        false => None,
+    };
+    if let Some(spec) = command_table::lookup_call(name, first.as_deref()) {
+        if !spec.accepts(args.len() + 1) {
+            return Err(wrong_arity(spec.name));
+        }
     }
+    let command = match name {
+        "echo" => create_echo(args)?,
+        "ping" => create_ping(args)?,
+        "set" => create_set(args)?,
+        "info" => create_info(args)?,
+        "get" => create_get(args)?,
+        "replconf" => create_replconf(args)?,
+        "psync" => create_psync(args)?,
+        "wait" => create_wait(args)?,
+        "config" => create_config(args)?,
+        "keys" => create_key(args)?,
+        "del" => Command::Del(read_keys(args, "DEL")?),
+        "exists" => Command::Exists(read_keys(args, "EXISTS")?),
+        "rename" => create_rename(args)?,
+        "randomkey" => {
+            read_no_args(args, "RANDOMKEY")?;
+            Command::RandomKey
+        }
+        "dbsize" => {
+            read_no_args(args, "DBSIZE")?;
+            Command::DbSize
+        }
+        "flushdb" => Command::FlushDb(read_flush_mode(args, "FLUSHDB")?),
+        "flushall" => Command::FlushAll(read_flush_mode(args, "FLUSHALL")?),
+        "swapdb" => create_swapdb(args)?,
+        "object" => create_object(args)?,
+        "memory" => create_memory(args)?,
+        "scan" => create_scan(args)?,
+        "hello" => create_hello(args)?,
+        "auth" => create_auth(args)?,
+        "quit" => {
+            read_no_args(args, "QUIT")?;
+            Command::Quit
+        }
+        "select" => create_select(args)?,
+        "type" => Command::Type(read_single_key(args, "TYPE")?),
+        "strlen" => Command::Strlen(read_single_key(args, "STRLEN")?),
+        "llen" => Command::Llen(read_single_key(args, "LLEN")?),
+        "hlen" => Command::Hlen(read_single_key(args, "HLEN")?),
+        "scard" => Command::Scard(read_single_key(args, "SCARD")?),
+        "zcard" => Command::Zcard(read_single_key(args, "ZCARD")?),
+        "xlen" => Command::Xlen(read_single_key(args, "XLEN")?),
+        "acl" => create_acl(args)?,
+        "role" => create_role(args)?,
+        "client" => create_client(args)?,
+        "replicaof" | "slaveof" => create_replicaof(args)?,
+        "multi" => {
+            read_no_args(args, "MULTI")?;
+            Command::Multi
+        }
+        "exec" => {
+            read_no_args(args, "EXEC")?;
+            Command::Exec
+        }
+        "discard" => {
+            read_no_args(args, "DISCARD")?;
+            Command::Discard
+        }
+        "subscribe" => Command::Subscribe(read_channels(args, "SUBSCRIBE", 1)?),
+        "unsubscribe" => Command::Unsubscribe(read_channels(args, "UNSUBSCRIBE", 0)?),
+        "psubscribe" => Command::PSubscribe(read_channels(args, "PSUBSCRIBE", 1)?),
+        "punsubscribe" => Command::PUnsubscribe(read_channels(args, "PUNSUBSCRIBE", 0)?),
+        "publish" => {
+            let channels = read_channels(args, "PUBLISH", 2)?;
+            match <[Bytes; 2]>::try_from(channels) {
+                Ok([channel, message]) => Command::Publish(channel, message),
+                Err(_) => return Err(wrong_arity("PUBLISH")),
+            }
+        }
+        "save" => {
+            read_no_args(args, "SAVE")?;
+            Command::Save
+        }
+        "bgsave" => {
+            read_no_args(args, "BGSAVE")?;
+            Command::BgSave
+        }
+        "bgrewriteaof" => {
+            read_no_args(args, "BGREWRITEAOF")?;
+            Command::BgRewriteAof
+        }
+        "shutdown" => create_shutdown(args)?,
+        "monitor" => {
+            read_no_args(args, "MONITOR")?;
+            Command::Monitor
+        }
+        "latency" => create_latency(args)?,
+        "slowlog" => create_slowlog(args)?,
+        "eval" => {
+            let (script, keys, argv) = read_script_call(args, "EVAL")?;
+            Command::Eval(script, keys, argv)
+        }
+        "evalsha" => {
+            let (sha, keys, argv) = read_script_call(args, "EVALSHA")?;
+            Command::EvalSha(sha, keys, argv)
+        }
+        "script" => create_script(args)?,
+        "command" => create_command(args)?,
+        "ttl" => Command::Ttl(read_single_key(args, "TTL")?),
+        "pttl" => Command::Pttl(read_single_key(args, "PTTL")?),
+        "persist" => Command::Persist(read_single_key(args, "PERSIST")?),
+        "expire" => create_expire(args, "EXPIRE")?,
+        "pexpire" => create_expire(args, "PEXPIRE")?,
+        "pexpireat" => create_expire(args, "PEXPIREAT")?,
+        "incr" => Command::Incr(read_single_key(args, "INCR")?),
+        "decr" => Command::Decr(read_single_key(args, "DECR")?),
+        "incrby" => {
+            let (key, increment) = read_key_and_increment(args, "INCRBY")?;
+            Command::IncrBy(key, increment)
+        }
+        "decrby" => {
+            let (key, decrement) = read_key_and_increment(args, "DECRBY")?;
+            Command::DecrBy(key, decrement)
+        }
+        "lpush" => {
+            let (key, values) = read_key_and_values(args, "LPUSH")?;
+            Command::LPush(key, values)
+        }
+        "rpush" => {
+            let (key, values) = read_key_and_values(args, "RPUSH")?;
+            Command::RPush(key, values)
+        }
+        "mget" => Command::MGet(read_keys(args, "MGET")?),
+        "mset" => Command::MSet(read_key_value_pairs(args, "MSET")?),
+        "msetnx" => Command::MSetNx(read_key_value_pairs(args, "MSETNX")?),
+        "append" => {
+            let (key, value) = read_key_and_member(args, "APPEND")?;
+            Command::Append(key, value)
+        }
+        "getrange" => {
+            let (key, start, end) = read_key_and_range(&args, "GETRANGE")?;
+            Command::GetRange(key, start, end)
+        }
+        "setrange" => create_setrange(args)?,
+        "setbit" => create_setbit(args)?,
+        "getbit" => {
+            let (key, offset) = read_key_and_member(args, "GETBIT")?;
+            Command::GetBit(key, read_bit_offset(&offset)?)
+        }
+        "bitcount" => create_bitcount(args)?,
+        "bitop" => create_bitop(args)?,
+        "lrange" => {
+            let (key, start, end) = read_key_and_range(&args, "LRANGE")?;
+            Command::LRange(key, start, end)
+        }
+        "hset" => create_hset(args)?,
+        "hget" => {
+            let (key, field) = read_key_and_member(args, "HGET")?;
+            Command::HGet(key, field)
+        }
+        "hdel" => {
+            let (key, fields) = read_key_and_values(args, "HDEL")?;
+            Command::HDel(key, fields)
+        }
+        "hgetall" => Command::HGetAll(read_single_key(args, "HGETALL")?),
+        "zadd" => create_zadd(args)?,
+        "xadd" => create_xadd(args)?,
+        "xrange" => create_xrange(args)?,
+        "xread" => create_xread(args)?,
+        "zrange" => create_zrange(args)?,
+        "zscore" => {
+            let (key, member) = read_key_and_member(args, "ZSCORE")?;
+            Command::ZScore(key, member)
+        }
+        "zrank" => {
+            let (key, member) = read_key_and_member(args, "ZRANK")?;
+            Command::ZRank(key, member)
+        }
+        "lpop" => {
+            let (key, count) = read_key_and_count(args, "LPOP")?;
+            Command::LPop(key, count)
+        }
+        "rpop" => {
+            let (key, count) = read_key_and_count(args, "RPOP")?;
+            Command::RPop(key, count)
+        }
+        "blpop" => {
+            let (keys, timeout) = read_keys_and_timeout(args, "BLPOP")?;
+            Command::BLPop(keys, timeout)
+        }
+        "brpop" => {
+            let (keys, timeout) = read_keys_and_timeout(args, "BRPOP")?;
+            Command::BRPop(keys, timeout)
+        }
+        "debug" => create_debug(args)?,
+        _ => return Err(format!("ERR unknown command '{}'", command_name)),
+    };
+    Ok(command)
 }
 
 // Private
+const SYNTAX_ERROR: &str = "ERR syntax error";
+// Clients always send arguments as bulk strings
+const ARGUMENT_TYPE_ERROR: &str = "ERR expected the arguments to be strings";
+const STREAM_ID_ERROR: &str = "ERR Invalid stream ID specified as stream command argument";
+
+fn wrong_arity(command_name: &str) -> String {
+    format!(
+        "ERR wrong number of arguments for '{}' command",
+        command_name.to_lowercase().replace(' ', "|")
+    )
+}
+
+fn unknown_subcommand(command_name: &str, subcommand: &str) -> String {
+    format!(
+        "ERR unknown subcommand or wrong number of arguments for '{}'. Try {} HELP.",
+        subcommand, command_name
+    )
+}
+
 fn turn_arg_to_string(arg: &RespType) -> Option<String> {
     match arg {
-        RespType::BulkString(x) => Some(String::from(x.as_ref().unwrap())),
+        RespType::BulkString(Some(x)) => Some(String::from_utf8_lossy(x).into_owned()),
         RespType::SimpleString(x) => Some(String::from(x)),
         _ => None,
     }
 }
 
-fn create_set(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        2 | 4 => (),
-        _ => panic!("Number of args for SET is wrong"),
+fn turn_arg_to_bytes(arg: &RespType) -> Option<Bytes> {
+    match arg {
+        RespType::BulkString(Some(x)) => Some(x.clone()),
+        RespType::SimpleString(x) => Some(Bytes::from(x.clone())),
+        _ => None,
     }
+}
 
-    let mut string_args = Vec::new();
-    for arg in args.iter().take(2) {
-        match turn_arg_to_string(arg) {
-            Some(x) => string_args.push(x),
-            None => panic!("First two arguments for SET need to be strings"),
-        }
+fn string_arg(arg: &RespType) -> Result<String, String> {
+    turn_arg_to_string(arg).ok_or_else(|| String::from(ARGUMENT_TYPE_ERROR))
+}
+
+fn bytes_arg(arg: &RespType) -> Result<Bytes, String> {
+    turn_arg_to_bytes(arg).ok_or_else(|| String::from(ARGUMENT_TYPE_ERROR))
+}
+
+fn read_strings(args: &[RespType]) -> Result<Vec<String>, String> {
+    args.iter().map(string_arg).collect()
+}
+
+fn create_set(args: Vec<RespType>) -> Result<Command, String> {
+    if args.len() < 2 {
+        return Err(wrong_arity("SET"));
     }
-    let optional_arg = if args.len() == 4 {
-        match turn_arg_to_string(&args[2]) {
-            Some(x) if x == "px" => (),
-            _ => panic!("Expected third argument to SET to be px"),
+
+    let key = string_arg(&args[0])?;
+    let value = bytes_arg(&args[1])?;
+    let invalid_expire_time = || String::from("ERR invalid expire time in 'set' command");
+    let mut options = SetOptions::default();
+    let mut index = 2;
+    while index < args.len() {
+        let option = string_arg(&args[index])?.to_lowercase();
+        index += 1;
+        match option.as_str() {
+            "nx" | "xx" if options.condition.is_none() => {
+                options.condition = Some(match option.as_str() {
+                    "nx" => SetCondition::IfMissing,
+                    _ => SetCondition::IfExists,
+                })
+            }
+            "keepttl" if options.expiry.is_none() => options.keep_ttl = true,
+            "get" => options.get = true,
+            "ex" | "px" | "exat" | "pxat" if options.expiry.is_none() && !options.keep_ttl => {
+                let amount = match args.get(index).and_then(turn_arg_to_string) {
+                    Some(x) => match x.parse::<i64>() {
+                        Ok(val) if val > 0 => val as u64,
+                        Ok(_) => return Err(invalid_expire_time()),
+                        Err(_) => return Err(NOT_AN_INTEGER_ERROR.to_string()),
+                    },
+                    None => return Err(SYNTAX_ERROR.to_string()),
+                };
+                index += 1;
+                let ms = match option.as_str() {
+                    "ex" | "exat" => match amount.checked_mul(1000) {
+                        Some(x) => x,
+                        None => return Err(invalid_expire_time()),
+                    },
+                    _ => amount,
+                };
+                options.expiry = Some(match option.as_str() {
+                    "ex" | "px" => Expiry::After(ms),
+                    _ => Expiry::At(ms),
+                });
+            }
+            _ => return Err(SYNTAX_ERROR.to_string()),
         }
-        match turn_arg_to_string(&args[3]) {
-            Some(x) => match x.parse::<u64>() {
-                Ok(val) => Some(val),
-                Err(e) => panic!("Failed to convert px argument of SET to u64: {}", e),
+    }
+
+    Ok(Command::Set(key, value, options))
+}
+
+fn read_no_args(args: Vec<RespType>, command_name: &str) -> Result<(), String> {
+    match args.is_empty() {
+        true => Ok(()),
+        false => Err(wrong_arity(command_name)),
+    }
+}
+
+fn arg(x: impl ToString) -> Bytes {
+    Bytes::from(x.to_string())
+}
+
+// Channel names or patterns, at least `min` of them
+fn read_channels(
+    args: Vec<RespType>,
+    command_name: &str,
+    min: usize,
+) -> Result<Vec<Bytes>, String> {
+    if args.len() < min {
+        return Err(wrong_arity(command_name));
+    }
+    args.iter().map(bytes_arg).collect()
+}
+
+// For commands that take nothing but a key
+fn read_single_key(args: Vec<RespType>, command_name: &str) -> Result<String, String> {
+    if args.len() != 1 {
+        return Err(wrong_arity(command_name));
+    }
+    string_arg(&args[0])
+}
+
+fn read_key_and_increment(
+    args: Vec<RespType>,
+    command_name: &str,
+) -> Result<(String, i64), String> {
+    if args.len() != 2 {
+        return Err(wrong_arity(command_name));
+    }
+    let key = string_arg(&args[0])?;
+    match turn_arg_to_string(&args[1]).map(|x| x.parse::<i64>()) {
+        Some(Ok(x)) => Ok((key, x)),
+        _ => Err(NOT_AN_INTEGER_ERROR.to_string()),
+    }
+}
+
+// For commands that take a key followed by one or more values
+fn read_key_and_values(
+    args: Vec<RespType>,
+    command_name: &str,
+) -> Result<(String, Vec<Bytes>), String> {
+    if args.len() < 2 {
+        return Err(wrong_arity(command_name));
+    }
+    let key = string_arg(&args[0])?;
+    let values = args[1..].iter().map(bytes_arg).collect::<Result<_, _>>()?;
+    Ok((key, values))
+}
+
+// For commands that take any number of keys each followed by its value, like MSET
+fn read_key_value_pairs(
+    args: Vec<RespType>,
+    command_name: &str,
+) -> Result<Vec<(String, Bytes)>, String> {
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return Err(wrong_arity(command_name));
+    }
+    args.chunks(2)
+        .map(|pair| Ok((string_arg(&pair[0])?, bytes_arg(&pair[1])?)))
+        .collect()
+}
+
+// For commands that take a key and an optional count, like LPOP
+fn read_key_and_count(
+    args: Vec<RespType>,
+    command_name: &str,
+) -> Result<(String, Option<usize>), String> {
+    if args.is_empty() || args.len() > 2 {
+        return Err(wrong_arity(command_name));
+    }
+    let key = string_arg(&args[0])?;
+    let count = match args.get(1).map(string_arg).transpose()? {
+        Some(x) => match x.parse::<usize>() {
+            Ok(x) => Some(x),
+            Err(_) => return Err(String::from("ERR value is out of range, must be positive")),
+        },
+        None => None,
+    };
+    Ok((key, count))
+}
+
+// For blocking commands that take keys followed by a timeout in seconds, like BLPOP. A timeout of
+// 0 waits for as long as it takes, and comes back as None.
+fn read_keys_and_timeout(
+    args: Vec<RespType>,
+    command_name: &str,
+) -> Result<(Vec<String>, Option<u64>), String> {
+    if args.len() < 2 {
+        return Err(wrong_arity(command_name));
+    }
+    let mut args = read_strings(&args)?;
+    let timeout = args.pop().expect("Checked the number of arguments");
+    let seconds = match timeout.parse::<f64>() {
+        Ok(x) if x.is_finite() => x,
+        _ => return Err(String::from("ERR timeout is not a float or out of range")),
+    };
+    if seconds < 0.0 {
+        return Err(String::from("ERR timeout is negative"));
+    }
+    let timeout = match (seconds * 1000.0).round() as u64 {
+        0 if seconds == 0.0 => None,
+        ms => Some(ms),
+    };
+    Ok((args, timeout))
+}
+
+// For commands that take a key and exactly one field or member
+fn read_key_and_member(args: Vec<RespType>, command_name: &str) -> Result<(String, Bytes), String> {
+    let (key, mut members) = read_key_and_values(args, command_name)?;
+    if members.len() != 1 {
+        return Err(wrong_arity(command_name));
+    }
+    Ok((key, members.remove(0)))
+}
+
+fn create_zadd(args: Vec<RespType>) -> Result<Command, String> {
+    let (key, values) = read_key_and_values(args, "ZADD")?;
+    if !values.len().is_multiple_of(2) {
+        return Err(SYNTAX_ERROR.to_string());
+    }
+    let pairs = values
+        .chunks(2)
+        .map(
+            |pair| match std::str::from_utf8(&pair[0]).map(|x| x.parse::<f64>()) {
+                Ok(Ok(x)) if !x.is_nan() => Ok((x, pair[1].clone())),
+                _ => Err(String::from("ERR value is not a valid float")),
             },
-            None => panic!("Expected px argument to be provided as a string"),
-        }
-    } else {
-        None
+        )
+        .collect::<Result<_, _>>()?;
+    Ok(Command::ZAdd(key, pairs))
+}
+
+fn create_xadd(args: Vec<RespType>) -> Result<Command, String> {
+    let (key, values) = read_key_and_values(args, "XADD")?;
+    if values.len() < 3 || values.len().is_multiple_of(2) {
+        return Err(wrong_arity("XADD"));
+    }
+    let id = match std::str::from_utf8(&values[0]).ok().and_then(IdSpec::parse) {
+        Some(x) => x,
+        None => return Err(STREAM_ID_ERROR.to_string()),
     };
+    let fields = values[1..]
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect();
+    Ok(Command::XAdd(key, id, fields))
+}
 
-    Command::Set(string_args[0].clone(), string_args[1].clone(), optional_arg)
+// "-" and "+" are the smallest and greatest IDs, and a bare millisecond time covers all of it
+fn parse_range_bound(bound: Option<String>, missing_seq: u64) -> Result<StreamId, String> {
+    let id = match bound.as_deref() {
+        Some("-") => Some(StreamId::default()),
+        Some("+") => Some(StreamId::MAX),
+        Some(x) => StreamId::parse(x, missing_seq),
+        None => None,
+    };
+    id.ok_or_else(|| STREAM_ID_ERROR.to_string())
 }
 
-fn create_get(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        1 => (),
-        _ => panic!("Number of arguments for GET is wrong"),
+fn create_xrange(args: Vec<RespType>) -> Result<Command, String> {
+    let count = match args.len() {
+        3 => None,
+        5 => match (turn_arg_to_string(&args[3]), turn_arg_to_string(&args[4])) {
+            (Some(option), Some(count)) if option.eq_ignore_ascii_case("count") => {
+                match count.parse::<usize>() {
+                    Ok(x) => Some(x),
+                    Err(_) => return Err(NOT_AN_INTEGER_ERROR.to_string()),
+                }
+            }
+            _ => return Err(SYNTAX_ERROR.to_string()),
+        },
+        _ => return Err(wrong_arity("XRANGE")),
     };
-    let arg_value = match turn_arg_to_string(&args[0]) {
-        Some(x) => x,
-        None => panic!("Expected GET argument to be a string"),
+    let key = string_arg(&args[0])?;
+    let start = parse_range_bound(turn_arg_to_string(&args[1]), 0)?;
+    let end = parse_range_bound(turn_arg_to_string(&args[2]), u64::MAX)?;
+    Ok(Command::XRange(key, start, end, count))
+}
+
+fn create_xread(args: Vec<RespType>) -> Result<Command, String> {
+    let string_args = read_strings(&args)?;
+    let (mut count, mut block) = (None, None);
+    let mut index = 0;
+    while index < string_args.len() {
+        let option = string_args[index].to_lowercase();
+        if option == "streams" {
+            break;
+        }
+        let value = match string_args.get(index + 1).map(|x| x.parse::<u64>()) {
+            Some(Ok(x)) => x,
+            Some(Err(_)) => return Err(NOT_AN_INTEGER_ERROR.to_string()),
+            None => return Err(SYNTAX_ERROR.to_string()),
+        };
+        match option.as_str() {
+            "count" => count = Some(value as usize),
+            "block" => block = Some(value),
+            _ => return Err(SYNTAX_ERROR.to_string()),
+        }
+        index += 2;
+    }
+    let streams = &string_args[(index + 1).min(string_args.len())..];
+    if streams.is_empty() || !streams.len().is_multiple_of(2) {
+        return Err(String::from(
+            "ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be \
+             specified.",
+        ));
+    }
+    let (keys, ids) = streams.split_at(streams.len() / 2);
+    let streams = keys
+        .iter()
+        .zip(ids)
+        .map(|(key, id)| {
+            let id = match id.as_str() {
+                "$" => None,
+                id => match StreamId::parse(id, 0) {
+                    Some(x) => Some(x),
+                    None => return Err(STREAM_ID_ERROR.to_string()),
+                },
+            };
+            Ok((key.clone(), id))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Command::XRead(count, block, streams))
+}
+
+fn create_zrange(args: Vec<RespType>) -> Result<Command, String> {
+    let with_scores = match args.get(3).and_then(turn_arg_to_string) {
+        Some(x) if x.eq_ignore_ascii_case("withscores") && args.len() == 4 => true,
+        None if args.len() == 3 => false,
+        _ => return Err(SYNTAX_ERROR.to_string()),
     };
-    Command::Get(arg_value)
+    let (key, start, end) = read_key_and_range(&args[..3], "ZRANGE")?;
+    Ok(Command::ZRange(key, start, end, with_scores))
 }
 
-fn create_echo(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        1 => (),
-        _ => panic!("Number of arguments for ECHO is wrong"),
+fn create_setrange(args: Vec<RespType>) -> Result<Command, String> {
+    let (key, mut values) = read_key_and_values(args, "SETRANGE")?;
+    if values.len() != 2 {
+        return Err(wrong_arity("SETRANGE"));
+    }
+    let value = values.pop().expect("Expected a value after the offset");
+    let offset = match std::str::from_utf8(&values[0]).map(|x| x.parse::<i64>()) {
+        Ok(Ok(x)) if x >= 0 => x as usize,
+        Ok(Ok(_)) => return Err(String::from("ERR offset is out of range")),
+        _ => return Err(NOT_AN_INTEGER_ERROR.to_string()),
     };
-    let arg_value = match &args[0] {
-        RespType::BulkString(x) => String::from(x.as_ref().unwrap()),
-        _ => panic!("Expect echo command to have a bulkstring as an argument"),
+    Ok(Command::SetRange(key, offset, value))
+}
+
+fn create_setbit(args: Vec<RespType>) -> Result<Command, String> {
+    let (key, values) = read_key_and_values(args, "SETBIT")?;
+    if values.len() != 2 {
+        return Err(wrong_arity("SETBIT"));
+    }
+    let on = match &values[1][..] {
+        b"0" => false,
+        b"1" => true,
+        _ => return Err(String::from("ERR bit is not an integer or out of range")),
     };
-    Command::Echo(arg_value)
+    Ok(Command::SetBit(key, read_bit_offset(&values[0])?, on))
 }
 
-fn create_replconf(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        1 | 2 => (),
-        _ => panic!("Number of arguments for REPLCONF is wrong"),
+fn read_bit_offset(offset: &Bytes) -> Result<u64, String> {
+    match std::str::from_utf8(offset).map(|x| x.parse::<u64>()) {
+        Ok(Ok(x)) => Ok(x),
+        _ => Err(String::from(
+            "ERR bit offset is not an integer or out of range",
+        )),
+    }
+}
+
+// BITCOUNT key [start end [BYTE | BIT]]
+fn create_bitcount(args: Vec<RespType>) -> Result<Command, String> {
+    let args = read_keys(args, "BITCOUNT")?;
+    let index = |x: &String| {
+        x.parse::<i64>()
+            .map_err(|_| NOT_AN_INTEGER_ERROR.to_string())
     };
-    let arg1 = match turn_arg_to_string(&args[0]) {
+    let range = match &args[1..] {
+        [] => None,
+        [start, end] => Some((index(start)?, index(end)?, BitUnit::Byte)),
+        [start, end, unit] => match unit.to_lowercase().as_str() {
+            "byte" => Some((index(start)?, index(end)?, BitUnit::Byte)),
+            "bit" => Some((index(start)?, index(end)?, BitUnit::Bit)),
+            _ => return Err(SYNTAX_ERROR.to_string()),
+        },
+        _ => return Err(SYNTAX_ERROR.to_string()),
+    };
+    Ok(Command::BitCount(args[0].clone(), range))
+}
+
+fn create_bitop(args: Vec<RespType>) -> Result<Command, String> {
+    let mut args = read_keys(args, "BITOP")?;
+    if args.len() < 3 {
+        return Err(wrong_arity("BITOP"));
+    }
+    let operation = match BitOperation::parse(&args[0]) {
         Some(x) => x,
-        None => panic!("Expected first argument for REPLCONF to be a string"),
+        None => return Err(SYNTAX_ERROR.to_string()),
     };
-    let optional_arg = if args.len() == 2 {
-        match turn_arg_to_string(&args[1]) {
-            Some(x) => Some(x),
-            None => panic!("Expected second argument for REPLCFONF to be a string"),
-        }
-    } else {
-        None
+    let keys = args.split_off(2);
+    if operation == BitOperation::Not && keys.len() != 1 {
+        return Err(String::from(
+            "ERR BITOP NOT must be called with a single source key.",
+        ));
+    }
+    Ok(Command::BitOp(operation, args.remove(1), keys))
+}
+
+fn create_hset(args: Vec<RespType>) -> Result<Command, String> {
+    let (key, values) = read_key_and_values(args, "HSET")?;
+    if !values.len().is_multiple_of(2) {
+        return Err(wrong_arity("HSET"));
+    }
+    let pairs = values
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect();
+    Ok(Command::HSet(key, pairs))
+}
+
+// For commands that take a key followed by inclusive start and end indices, like LRANGE
+fn read_key_and_range(args: &[RespType], command_name: &str) -> Result<(String, i64, i64), String> {
+    if args.len() != 3 {
+        return Err(wrong_arity(command_name));
+    }
+    let key = string_arg(&args[0])?;
+    let index = |arg: &RespType| match turn_arg_to_string(arg).map(|x| x.parse::<i64>()) {
+        Some(Ok(x)) => Ok(x),
+        _ => Err(NOT_AN_INTEGER_ERROR.to_string()),
+    };
+    Ok((key, index(&args[1])?, index(&args[2])?))
+}
+
+fn create_get(args: Vec<RespType>) -> Result<Command, String> {
+    if args.len() != 1 {
+        return Err(wrong_arity("GET"));
+    }
+    Ok(Command::Get(string_arg(&args[0])?))
+}
+
+fn create_echo(args: Vec<RespType>) -> Result<Command, String> {
+    if args.len() != 1 {
+        return Err(wrong_arity("ECHO"));
+    }
+    let arg_value = match &args[0] {
+        RespType::BulkString(Some(x)) => String::from_utf8_lossy(x).into_owned(),
+        _ => return Err(ARGUMENT_TYPE_ERROR.to_string()),
     };
-    Command::ReplConf(arg1.clone(), optional_arg)
+    Ok(Command::Echo(arg_value))
+}
+
+// The options after the first, like "eof capa psync2" in REPLCONF capa eof capa psync2
+fn create_replconf(args: Vec<RespType>) -> Result<Command, String> {
+    if args.is_empty() {
+        return Err(wrong_arity("REPLCONF"));
+    }
+    let mut string_args = read_strings(&args)?;
+    let option = string_args.remove(0);
+    Ok(Command::ReplConf(option, string_args))
+}
+
+fn create_psync(args: Vec<RespType>) -> Result<Command, String> {
+    if args.len() != 2 {
+        return Err(wrong_arity("PSYNC"));
+    }
+    let string_args = read_strings(&args)?;
+    Ok(Command::Psync(
+        string_args[0].clone(),
+        string_args[1].clone(),
+    ))
+}
+
+fn create_ping(args: Vec<RespType>) -> Result<Command, String> {
+    if !args.is_empty() {
+        return Err(wrong_arity("PING"));
+    }
+    Ok(Command::Ping)
+}
+
+fn create_info(args: Vec<RespType>) -> Result<Command, String> {
+    Ok(Command::Info(read_strings(&args)?))
+}
+
+fn create_wait(args: Vec<RespType>) -> Result<Command, String> {
+    if args.len() != 2 {
+        return Err(wrong_arity("WAIT"));
+    }
+    let mut arg_values: Vec<i32> = Vec::new();
+    for arg in read_strings(&args)? {
+        match arg.parse::<i32>() {
+            Ok(x) => arg_values.push(x),
+            Err(_) => return Err(NOT_AN_INTEGER_ERROR.to_string()),
+        }
+    }
+    Ok(Command::Wait(arg_values[0], arg_values[1]))
+}
+
+fn create_hello(args: Vec<RespType>) -> Result<Command, String> {
+    let args = read_strings(&args)?;
+    match args.as_slice() {
+        [] => Ok(Command::Hello(None, None)),
+        [version] => Ok(Command::Hello(Some(version.clone()), None)),
+        [version, auth, username, password] if auth.eq_ignore_ascii_case("auth") => {
+            Ok(Command::Hello(
+                Some(version.clone()),
+                Some((username.clone(), password.clone())),
+            ))
+        }
+        _ => Err(SYNTAX_ERROR.to_string()),
+    }
 }
 
-fn create_psync(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        2 => (),
-        _ => panic!("Number of arguments for PSYNC is wrong"),
+fn create_auth(args: Vec<RespType>) -> Result<Command, String> {
+    let args = read_strings(&args)?;
+    match args.as_slice() {
+        [password] => Ok(Command::Auth(None, password.clone())),
+        [username, password] => Ok(Command::Auth(Some(username.clone()), password.clone())),
+        _ => Err(SYNTAX_ERROR.to_string()),
+    }
+}
+
+fn create_role(args: Vec<RespType>) -> Result<Command, String> {
+    if !args.is_empty() {
+        return Err(wrong_arity("ROLE"));
+    }
+    Ok(Command::Role)
+}
+
+fn create_client(args: Vec<RespType>) -> Result<Command, String> {
+    let string_args = read_strings(&args)?;
+    let subcommand = string_args.first().map(|x| x.to_lowercase());
+    match (
+        subcommand.as_deref(),
+        &string_args[1.min(string_args.len())..],
+    ) {
+        (Some("id"), []) => Ok(Command::ClientId),
+        (Some("setname"), [name]) => Ok(Command::ClientSetName(name.clone())),
+        (Some("getname"), []) => Ok(Command::ClientGetName),
+        (Some("list"), []) => Ok(Command::ClientList),
+        (Some("kill"), filters) => Ok(Command::ClientKill(read_kill_filter(filters)?)),
+        (Some(other), _) => Err(unknown_subcommand("CLIENT", other)),
+        (None, _) => Err(wrong_arity("CLIENT")),
+    }
+}
+
+// Either the old form, a single address, or filter and value pairs
+fn read_kill_filter(args: &[String]) -> Result<KillFilter, String> {
+    let mut filter = KillFilter {
+        id: None,
+        addr: None,
+        user: None,
+        skip_me: true,
+        old_form: false,
     };
-    let mut string_args = Vec::new();
-    for arg in args.iter().take(2) {
-        match turn_arg_to_string(arg) {
-            Some(x) => string_args.push(x),
-            None => panic!("First two arguments for SET need to be strings"),
+    if let [addr] = args {
+        filter.addr = Some(addr.clone());
+        filter.skip_me = false;
+        filter.old_form = true;
+        return Ok(filter);
+    }
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return Err(SYNTAX_ERROR.to_string());
+    }
+    for pair in args.chunks(2) {
+        let value = pair[1].clone();
+        match pair[0].to_lowercase().as_str() {
+            "id" => match value.parse::<u64>() {
+                Ok(x) if x > 0 => filter.id = Some(x),
+                _ => return Err(String::from("ERR client-id should be greater than 0")),
+            },
+            "addr" => filter.addr = Some(value),
+            "user" => filter.user = Some(value),
+            "skipme" => match value.to_lowercase().as_str() {
+                "yes" => filter.skip_me = true,
+                "no" => filter.skip_me = false,
+                _ => return Err(SYNTAX_ERROR.to_string()),
+            },
+            _ => return Err(SYNTAX_ERROR.to_string()),
         }
     }
-    Command::Psync(string_args[0].clone(), string_args[1].clone())
+    Ok(filter)
 }
 
-fn create_ping(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        0 => (),
-        _ => panic!("Number of arguments for PING is wrong"),
+fn create_select(args: Vec<RespType>) -> Result<Command, String> {
+    if args.len() != 1 {
+        return Err(wrong_arity("SELECT"));
+    }
+    Ok(Command::Select(string_arg(&args[0])?))
+}
+
+fn create_config(args: Vec<RespType>) -> Result<Command, String> {
+    let string_args = read_strings(&args)?;
+    let subcommand = string_args.first().map(|x| x.to_lowercase());
+    match (subcommand.as_deref(), args.len()) {
+        (Some("get"), 2..) => Ok(Command::ConfigGet(
+            string_args[1..].iter().map(|x| x.to_lowercase()).collect(),
+        )),
+        (Some("set"), x) if x >= 3 && !x.is_multiple_of(2) => {
+            let pairs = string_args[1..]
+                .chunks(2)
+                .map(|x| (x[0].to_lowercase(), x[1].clone()))
+                .collect();
+            Ok(Command::ConfigSet(pairs))
+        }
+        (Some(other), _) => Err(unknown_subcommand("CONFIG", other)),
+        (None, _) => Err(wrong_arity("CONFIG")),
+    }
+}
+
+fn create_key(args: Vec<RespType>) -> Result<Command, String> {
+    if args.len() != 1 {
+        return Err(wrong_arity("KEYS"));
+    }
+    Ok(Command::Keys(string_arg(&args[0])?))
+}
+
+// EXPIRE takes seconds from now, PEXPIRE milliseconds from now and PEXPIREAT a UNIX time in
+// milliseconds. Times that have already passed expire the key straight away.
+fn create_expire(args: Vec<RespType>, command_name: &str) -> Result<Command, String> {
+    if args.len() != 2 {
+        return Err(wrong_arity(command_name));
+    }
+    let key = string_arg(&args[0])?;
+    let amount = match turn_arg_to_string(&args[1]).map(|x| x.parse::<i64>()) {
+        Some(Ok(x)) => x,
+        _ => return Err(NOT_AN_INTEGER_ERROR.to_string()),
+    };
+    let expiry = match (command_name, u64::try_from(amount)) {
+        (_, Err(_)) | (_, Ok(0)) => Expiry::At(0),
+        ("EXPIRE", Ok(seconds)) => match seconds.checked_mul(1000) {
+            Some(ms) => Expiry::After(ms),
+            None => {
+                return Err(format!(
+                    "ERR invalid expire time in '{}' command",
+                    command_name.to_lowercase()
+                ))
+            }
+        },
+        ("PEXPIRE", Ok(ms)) => Expiry::After(ms),
+        (_, Ok(unix_ms)) => Expiry::At(unix_ms),
     };
-    Command::Ping
+    Ok(Command::Expire(key, expiry))
 }
 
-fn create_info(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        1 => (),
-        _ => panic!("Number of arguments for INFO is wrong"),
+// One or more keys, as DEL and EXISTS take them
+fn read_keys(args: Vec<RespType>, command_name: &str) -> Result<Vec<String>, String> {
+    if args.is_empty() {
+        return Err(wrong_arity(command_name));
+    }
+    read_strings(&args)
+}
+
+fn create_rename(args: Vec<RespType>) -> Result<Command, String> {
+    if args.len() != 2 {
+        return Err(wrong_arity("RENAME"));
+    }
+    Ok(Command::Rename(
+        string_arg(&args[0])?,
+        string_arg(&args[1])?,
+    ))
+}
+
+// FLUSHDB and FLUSHALL take an optional ASYNC or SYNC, returned as whether to flush lazily
+fn read_flush_mode(args: Vec<RespType>, command_name: &str) -> Result<Option<bool>, String> {
+    let mode = match args.as_slice() {
+        [] => return Ok(None),
+        [x] => turn_arg_to_string(x).map(|x| x.to_lowercase()),
+        _ => return Err(wrong_arity(command_name)),
     };
-    let arg_value = match turn_arg_to_string(&args[0]) {
-        Some(x) => x,
-        None => panic!("Expected first argument for INFO to be a string"),
+    match mode.as_deref() {
+        Some("async") => Ok(Some(true)),
+        Some("sync") => Ok(Some(false)),
+        _ => Err(SYNTAX_ERROR.to_string()),
+    }
+}
+
+fn create_shutdown(args: Vec<RespType>) -> Result<Command, String> {
+    let modifier = match args.as_slice() {
+        [] => return Ok(Command::Shutdown(None)),
+        [x] => turn_arg_to_string(x).map(|x| x.to_lowercase()),
+        _ => return Err(SYNTAX_ERROR.to_string()),
     };
-    Command::Info(arg_value)
+    match modifier.as_deref() {
+        Some("save") => Ok(Command::Shutdown(Some(true))),
+        Some("nosave") => Ok(Command::Shutdown(Some(false))),
+        _ => Err(SYNTAX_ERROR.to_string()),
+    }
+}
+
+fn create_swapdb(args: Vec<RespType>) -> Result<Command, String> {
+    if args.len() != 2 {
+        return Err(wrong_arity("SWAPDB"));
+    }
+    Ok(Command::SwapDb(
+        string_arg(&args[0])?,
+        string_arg(&args[1])?,
+    ))
+}
+
+fn create_replicaof(args: Vec<RespType>) -> Result<Command, String> {
+    if args.len() != 2 {
+        return Err(wrong_arity("REPLICAOF"));
+    }
+    Ok(Command::ReplicaOf(
+        string_arg(&args[0])?,
+        string_arg(&args[1])?,
+    ))
+}
+
+fn create_object(args: Vec<RespType>) -> Result<Command, String> {
+    if args.len() != 2 {
+        return Err(wrong_arity("OBJECT"));
+    }
+    let string_args = read_strings(&args)?;
+    Ok(Command::Object(
+        string_args[0].clone(),
+        string_args[1].clone(),
+    ))
 }
 
-fn create_wait(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        2 => (),
-        _ => panic!("Number of arguments for WAIT is wrong"),
+fn create_memory(args: Vec<RespType>) -> Result<Command, String> {
+    let string_args = read_strings(&args)?;
+    match string_args.first().map(|x| x.to_lowercase()).as_deref() {
+        // SAMPLES is accepted for compatibility, sizes are already kept up to date per entry
+        Some("usage") => match string_args.len() {
+            2 => Ok(Command::MemoryUsage(string_args[1].clone())),
+            4 if string_args[2].eq_ignore_ascii_case("samples") => {
+                Ok(Command::MemoryUsage(string_args[1].clone()))
+            }
+            _ => Err(SYNTAX_ERROR.to_string()),
+        },
+        Some(other) => Err(unknown_subcommand("MEMORY", other)),
+        None => Err(wrong_arity("MEMORY")),
+    }
+}
+
+fn create_scan(args: Vec<RespType>) -> Result<Command, String> {
+    let string_args = read_strings(&args)?;
+    let cursor = match string_args.first().map(|x| x.parse::<u64>()) {
+        Some(Ok(x)) => x,
+        Some(Err(_)) => return Err(String::from("ERR invalid cursor")),
+        None => return Err(wrong_arity("SCAN")),
     };
-    let mut arg_values: Vec<i32> = Vec::new();
-    for arg in args {
-        match turn_arg_to_string(&arg) {
-            Some(x) => match x.parse::<i32>() {
-                Ok(x) => arg_values.push(x),
-                Err(_) => panic!("Could not parse WAIT arguments to i32"),
+    let mut count = 10;
+    let mut pattern = None;
+    let mut type_name = None;
+    for option in string_args[1..].chunks(2) {
+        match (option[0].to_lowercase().as_str(), option.get(1)) {
+            ("count", Some(x)) => match x.parse::<i64>() {
+                Ok(x) if x > 0 => count = x as usize,
+                Ok(_) => return Err(SYNTAX_ERROR.to_string()),
+                Err(_) => return Err(NOT_AN_INTEGER_ERROR.to_string()),
             },
-            None => panic!("Arguments to WAIT should be strings"),
+            // Matching everything is the same as not matching at all
+            ("match", Some(x)) => pattern = (x != "*").then(|| x.clone()),
+            ("type", Some(x)) => type_name = Some(x.to_lowercase()),
+            _ => return Err(SYNTAX_ERROR.to_string()),
         }
     }
-    Command::Wait(arg_values[0].clone(), arg_values[1].clone())
+    Ok(Command::Scan(cursor, count, pattern, type_name))
 }
 
-fn create_config(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        2 => (),
-        _ => panic!("Number of arguments for CONFIG GET is wrong"),
+fn create_acl(args: Vec<RespType>) -> Result<Command, String> {
+    let string_args = read_strings(&args)?;
+    let subcommand = string_args.first().map(|x| x.to_lowercase());
+    match (subcommand.as_deref(), args.len()) {
+        (Some("setuser"), 2..) => Ok(Command::AclSetUser(
+            string_args[1].clone(),
+            string_args[2..].to_vec(),
+        )),
+        (Some("list"), 1) => Ok(Command::AclList),
+        (Some("log"), 1 | 2) => Ok(Command::AclLog(string_args.get(1).cloned())),
+        (Some(other), _) => Err(unknown_subcommand("ACL", other)),
+        (None, _) => Err(wrong_arity("ACL")),
     }
-    match turn_arg_to_string(&args[0]) {
-        Some(x) if x.as_str().to_lowercase() == "get" => (),
-        _ => panic!("Expected CONFIG to be followed by GET"),
-    };
-    let arg_value = match turn_arg_to_string(&args[1]) {
-        Some(x) => x,
-        None => panic!("Expected argument for CONFIG GET to be a string"),
+}
+
+fn create_slowlog(args: Vec<RespType>) -> Result<Command, String> {
+    let string_args = read_strings(&args)?;
+    let subcommand = string_args.first().map(|x| x.to_lowercase());
+    match (subcommand.as_deref(), args.len()) {
+        (Some("get"), 1 | 2) => Ok(Command::SlowlogGet(string_args.get(1).cloned())),
+        (Some("len"), 1) => Ok(Command::SlowlogLen),
+        (Some("reset"), 1) => Ok(Command::SlowlogReset),
+        (Some(other), _) => Err(unknown_subcommand("SLOWLOG", other)),
+        (None, _) => Err(wrong_arity("SLOWLOG")),
+    }
+}
+
+// The script, then numkeys keys and the arguments after them
+fn read_script_call(
+    args: Vec<RespType>,
+    command_name: &str,
+) -> Result<(String, Vec<String>, Vec<Bytes>), String> {
+    if args.len() < 2 {
+        return Err(wrong_arity(command_name));
+    }
+    let script = string_arg(&args[0])?;
+    let num_keys = match turn_arg_to_string(&args[1]).map(|x| x.parse::<i64>()) {
+        Some(Ok(x)) if x < 0 => return Err(String::from("ERR Number of keys can't be negative")),
+        Some(Ok(x)) if x as usize > args.len() - 2 => {
+            return Err(String::from(
+                "ERR Number of keys can't be greater than number of args",
+            ))
+        }
+        Some(Ok(x)) => x as usize,
+        _ => return Err(NOT_AN_INTEGER_ERROR.to_string()),
     };
+    let keys = read_strings(&args[2..2 + num_keys])?;
+    let argv = args[2 + num_keys..]
+        .iter()
+        .map(bytes_arg)
+        .collect::<Result<_, _>>()?;
+    Ok((script, keys, argv))
+}
 
-    Command::ConfigGet(arg_value)
+fn create_script(args: Vec<RespType>) -> Result<Command, String> {
+    let string_args = read_strings(&args)?;
+    match string_args.first().map(|x| x.to_lowercase()).as_deref() {
+        Some("load") if string_args.len() == 2 => Ok(Command::ScriptLoad(string_args[1].clone())),
+        Some("exists") if string_args.len() > 1 => {
+            Ok(Command::ScriptExists(string_args[1..].to_vec()))
+        }
+        Some("flush") => {
+            let mode = read_flush_mode(args.into_iter().skip(1).collect(), "SCRIPT FLUSH")?;
+            Ok(Command::ScriptFlush(mode))
+        }
+        Some(other) => Err(unknown_subcommand("SCRIPT", other)),
+        None => Err(wrong_arity("SCRIPT")),
+    }
 }
 
-fn create_key(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        1 => (),
-        _ => panic!("Number of arguments for KEYS is wrong"),
+fn create_command(args: Vec<RespType>) -> Result<Command, String> {
+    let mut args: Vec<Bytes> = args.iter().map(bytes_arg).collect::<Result<_, _>>()?;
+    if args.is_empty() {
+        return Ok(Command::CommandList);
     }
-    let arg_value = match turn_arg_to_string(&args[0]) {
-        Some(x) => x,
-        None => panic!("Expected argument for KEYS to be a string"),
+    let subcommand = String::from_utf8_lossy(&args.remove(0)).to_lowercase();
+    let names = || {
+        args.iter()
+            .map(|x| String::from_utf8_lossy(x).into_owned())
+            .collect()
     };
-    Command::Keys(arg_value)
+    match subcommand.as_str() {
+        "count" => Ok(Command::CommandCount),
+        "info" => Ok(Command::CommandInfo(names())),
+        "docs" => Ok(Command::CommandDocs(names())),
+        "getkeys" => Ok(Command::CommandGetKeys(args)),
+        other => Err(unknown_subcommand("COMMAND", other)),
+    }
+}
+
+fn create_latency(args: Vec<RespType>) -> Result<Command, String> {
+    let string_args = read_strings(&args)?;
+    match string_args.first().map(|x| x.to_lowercase()).as_deref() {
+        Some("histogram") => Ok(Command::LatencyHistogram(string_args[1..].to_vec())),
+        Some(other) => Err(unknown_subcommand("LATENCY", other)),
+        None => Err(wrong_arity("LATENCY")),
+    }
+}
+
+fn create_debug(args: Vec<RespType>) -> Result<Command, String> {
+    let string_args = read_strings(&args)?;
+    match string_args.first().map(|x| x.to_lowercase()).as_deref() {
+        Some("panic") if string_args.len() == 1 => Ok(Command::DebugPanic),
+        Some(other) => Err(unknown_subcommand("DEBUG", other)),
+        None => Err(wrong_arity("DEBUG")),
+    }
 }
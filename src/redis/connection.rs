@@ -0,0 +1,385 @@
+use core::fmt;
+use std::fs::Permissions;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{tcp, unix, TcpListener, TcpStream, UnixListener, UnixStream};
+
+// Something OutputBuffer can write to without blocking, and wait on until it can take more
+pub trait Socket {
+    fn try_write(&self, data: &[u8]) -> io::Result<usize>;
+    fn writable(&self) -> impl Future<Output = io::Result<()>> + Send + '_;
+}
+
+impl Socket for TcpStream {
+    fn try_write(&self, data: &[u8]) -> io::Result<usize> {
+        TcpStream::try_write(self, data)
+    }
+
+    fn writable(&self) -> impl Future<Output = io::Result<()>> + Send + '_ {
+        TcpStream::writable(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    Tcp,
+    Unix,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Tcp => write!(f, "tcp"),
+            Transport::Unix => write!(f, "unix"),
+        }
+    }
+}
+
+// A client's connection, over whichever listener it arrived on. Past accepting it, everything
+// treats connections the same.
+#[derive(Debug)]
+pub enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Connection {
+    pub fn transport(&self) -> Transport {
+        match self {
+            Connection::Tcp(_) => Transport::Tcp,
+            Connection::Unix(_) => Transport::Unix,
+        }
+    }
+
+    // The peer's address, or for unix sockets the socket's path, as Redis shows it in addr=
+    pub fn peer(&self) -> String {
+        let peer = match self {
+            Connection::Tcp(x) => x.peer_addr().map(|x| x.to_string()),
+            Connection::Unix(x) => x.local_addr().map(|x| match x.as_pathname() {
+                Some(path) => format!("{}:0", path.display()),
+                None => String::from("unix:0"),
+            }),
+        };
+        peer.unwrap_or_else(|_| String::from("unknown"))
+    }
+
+    // What per-IP limits count the connection against, which unix sockets don't have
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        match self {
+            Connection::Tcp(x) => x.peer_addr().ok().map(|x| x.ip()),
+            Connection::Unix(_) => None,
+        }
+    }
+
+    // Turns TCP keepalive on, so that a peer that's gone without closing the connection is
+    // noticed once it's been silent for about `interval` seconds. Unix sockets don't need it.
+    pub fn set_keepalive(&self, interval: u64) -> io::Result<()> {
+        match self {
+            Connection::Tcp(x) => keepalive::enable(x.as_raw_fd(), interval),
+            Connection::Unix(_) => Ok(()),
+        }
+    }
+
+    // Splits the connection so that it can be read from and written to at the same time, by
+    // whoever owns each half
+    pub fn into_split(self) -> (ConnectionReader, ConnectionWriter) {
+        match self {
+            Connection::Tcp(x) => {
+                let (reader, writer) = x.into_split();
+                (ConnectionReader::Tcp(reader), ConnectionWriter::Tcp(writer))
+            }
+            Connection::Unix(x) => {
+                let (reader, writer) = x.into_split();
+                (
+                    ConnectionReader::Unix(reader),
+                    ConnectionWriter::Unix(writer),
+                )
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(x) => Pin::new(x).poll_write(cx, data),
+            Connection::Unix(x) => Pin::new(x).poll_write(cx, data),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(x) => Pin::new(x).poll_flush(cx),
+            Connection::Unix(x) => Pin::new(x).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(x) => Pin::new(x).poll_shutdown(cx),
+            Connection::Unix(x) => Pin::new(x).poll_shutdown(cx),
+        }
+    }
+}
+
+// The half of a connection that commands are read from
+#[derive(Debug)]
+pub enum ConnectionReader {
+    Tcp(tcp::OwnedReadHalf),
+    Unix(unix::OwnedReadHalf),
+}
+
+impl ConnectionReader {
+    // Puts the connection back together, as when a replica's connection is handed over to
+    // replication. Fails if the halves came from different connections.
+    pub fn reunite(self, writer: ConnectionWriter) -> io::Result<Connection> {
+        match (self, writer) {
+            (ConnectionReader::Tcp(reader), ConnectionWriter::Tcp(writer)) => reader
+                .reunite(writer)
+                .map(Connection::Tcp)
+                .map_err(io::Error::other),
+            (ConnectionReader::Unix(reader), ConnectionWriter::Unix(writer)) => reader
+                .reunite(writer)
+                .map(Connection::Unix)
+                .map_err(io::Error::other),
+            _ => Err(io::Error::other(
+                "the halves are from different connections",
+            )),
+        }
+    }
+}
+
+impl AsyncRead for ConnectionReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ConnectionReader::Tcp(x) => Pin::new(x).poll_read(cx, buf),
+            ConnectionReader::Unix(x) => Pin::new(x).poll_read(cx, buf),
+        }
+    }
+}
+
+// The half of a connection that replies are written to. Dropping it shuts the connection down
+// for writing.
+#[derive(Debug)]
+pub enum ConnectionWriter {
+    Tcp(tcp::OwnedWriteHalf),
+    Unix(unix::OwnedWriteHalf),
+}
+
+impl ConnectionWriter {
+    pub fn transport(&self) -> Transport {
+        match self {
+            ConnectionWriter::Tcp(_) => Transport::Tcp,
+            ConnectionWriter::Unix(_) => Transport::Unix,
+        }
+    }
+
+    // As Connection::peer
+    pub fn peer(&self) -> String {
+        let peer = match self {
+            ConnectionWriter::Tcp(x) => x.peer_addr().map(|x| x.to_string()),
+            ConnectionWriter::Unix(x) => x.local_addr().map(|x| match x.as_pathname() {
+                Some(path) => format!("{}:0", path.display()),
+                None => String::from("unix:0"),
+            }),
+        };
+        peer.unwrap_or_else(|_| String::from("unknown"))
+    }
+}
+
+impl Socket for ConnectionWriter {
+    fn try_write(&self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            ConnectionWriter::Tcp(x) => x.try_write(data),
+            ConnectionWriter::Unix(x) => x.try_write(data),
+        }
+    }
+
+    async fn writable(&self) -> io::Result<()> {
+        match self {
+            ConnectionWriter::Tcp(x) => x.writable().await,
+            ConnectionWriter::Unix(x) => x.writable().await,
+        }
+    }
+}
+
+impl AsyncWrite for ConnectionWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ConnectionWriter::Tcp(x) => Pin::new(x).poll_write(cx, data),
+            ConnectionWriter::Unix(x) => Pin::new(x).poll_write(cx, data),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ConnectionWriter::Tcp(x) => Pin::new(x).poll_flush(cx),
+            ConnectionWriter::Unix(x) => Pin::new(x).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ConnectionWriter::Tcp(x) => Pin::new(x).poll_shutdown(cx),
+            ConnectionWriter::Unix(x) => Pin::new(x).poll_shutdown(cx),
+        }
+    }
+}
+
+// One of the sockets the server accepts connections on. Every listener feeds the same accept
+// loop, see accept_connections.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    // Binds a unix socket at `path`, replacing whatever socket a previous run left behind. Its
+    // permissions are set to `mode` unless that's 0.
+    pub fn bind_unix(path: &Path, mode: u32) -> io::Result<Self> {
+        match std::fs::remove_file(path) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        let listener = UnixListener::bind(path)?;
+        if mode != 0 {
+            std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+        }
+        Ok(Listener::Unix(listener))
+    }
+
+    // Removes the unix socket's file once the server stops listening, as Redis does on shutdown
+    pub fn close(self) {
+        if let Listener::Unix(listener) = self {
+            if let Some(path) = listener
+                .local_addr()
+                .ok()
+                .and_then(|x| x.as_pathname().map(Path::to_path_buf))
+            {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Tcp(x) => Ok(Connection::Tcp(x.accept().await?.0)),
+            Listener::Unix(x) => Ok(Connection::Unix(x.accept().await?.0)),
+        }
+    }
+}
+
+// Keepalive as Redis sets it up: once a connection has been silent for the interval, it's probed
+// every third of that, and dropped after 3 probes go unanswered. Tokio can only turn keepalive on,
+// so the socket options are set directly. Elsewhere than Linux, connections go without.
+#[cfg(target_os = "linux")]
+mod keepalive {
+    use std::ffi::{c_int, c_void};
+    use std::io;
+    use std::os::unix::io::RawFd;
+
+    const SOL_SOCKET: c_int = 1;
+    const SO_KEEPALIVE: c_int = 9;
+    const IPPROTO_TCP: c_int = 6;
+    const TCP_KEEPIDLE: c_int = 4;
+    const TCP_KEEPINTVL: c_int = 5;
+    const TCP_KEEPCNT: c_int = 6;
+    // The most TCP_KEEPIDLE takes
+    const MAX_KEEPIDLE: u64 = 32_767;
+
+    extern "C" {
+        fn setsockopt(
+            socket: c_int,
+            level: c_int,
+            name: c_int,
+            value: *const c_void,
+            length: u32,
+        ) -> c_int;
+    }
+
+    pub fn enable(fd: RawFd, interval: u64) -> io::Result<()> {
+        let interval = interval.clamp(1, MAX_KEEPIDLE) as c_int;
+        set(fd, SOL_SOCKET, SO_KEEPALIVE, 1)?;
+        set(fd, IPPROTO_TCP, TCP_KEEPIDLE, interval)?;
+        set(fd, IPPROTO_TCP, TCP_KEEPINTVL, (interval / 3).max(1))?;
+        set(fd, IPPROTO_TCP, TCP_KEEPCNT, 3)
+    }
+
+    fn set(fd: RawFd, level: c_int, name: c_int, value: c_int) -> io::Result<()> {
+        let length = std::mem::size_of::<c_int>() as u32;
+        // The value is read during the call only, and is as long as we say
+        let result = unsafe { setsockopt(fd, level, name, &value as *const c_int as _, length) };
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::net::{TcpListener, TcpStream};
+        use std::os::unix::io::AsRawFd;
+
+        extern "C" {
+            fn getsockopt(
+                socket: c_int,
+                level: c_int,
+                name: c_int,
+                value: *mut c_void,
+                length: *mut u32,
+            ) -> c_int;
+        }
+
+        fn get(fd: RawFd, level: c_int, name: c_int) -> c_int {
+            let (mut value, mut length): (c_int, u32) = (0, std::mem::size_of::<c_int>() as u32);
+            let result =
+                unsafe { getsockopt(fd, level, name, &mut value as *mut c_int as _, &mut length) };
+            assert_eq!(result, 0);
+            value
+        }
+
+        #[test]
+        fn keepalive_probes_after_the_interval() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let fd = stream.as_raw_fd();
+            assert_eq!(get(fd, SOL_SOCKET, SO_KEEPALIVE), 0);
+            enable(fd, 60).unwrap();
+            assert_eq!(get(fd, SOL_SOCKET, SO_KEEPALIVE), 1);
+            assert_eq!(get(fd, IPPROTO_TCP, TCP_KEEPIDLE), 60);
+            assert_eq!(get(fd, IPPROTO_TCP, TCP_KEEPINTVL), 20);
+            assert_eq!(get(fd, IPPROTO_TCP, TCP_KEEPCNT), 3);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod keepalive {
+    use std::io;
+    use std::os::unix::io::RawFd;
+
+    pub fn enable(_fd: RawFd, _interval: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+use super::clients::ConnectionInfo;
+use super::state::ServerState;
+use crate::warning;
+
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::Once;
+use std::task::{Context, Poll};
+
+static INSTALL_HOOK: Once = Once::new();
+
+thread_local! {
+    // Set while a contained future is being polled on this thread
+    static CONTAINING: Cell<bool> = const { Cell::new(false) };
+    static BACKTRACE: RefCell<Option<Backtrace>> = const { RefCell::new(None) };
+}
+
+pub struct Crash {
+    message: String,
+    backtrace: Option<Backtrace>,
+}
+
+// Runs `future`, turning a panic inside it into an error rather than letting it unwind into the
+// runtime. The future is dropped along with whatever it owns, such as its connection, once the
+// returned future is.
+pub fn contain<F: Future>(future: F) -> Contained<F> {
+    INSTALL_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        // Contained panics are reported by report() instead, with the backtrace recorded here
+        panic::set_hook(Box::new(move |info| {
+            if CONTAINING.with(|x| x.get()) {
+                BACKTRACE.with(|x| *x.borrow_mut() = Some(Backtrace::force_capture()));
+            } else {
+                previous(info);
+            }
+        }));
+    });
+    Contained {
+        future: Box::pin(future),
+    }
+}
+
+pub struct Contained<F> {
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Contained<F> {
+    type Output = Result<F::Output, Crash>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let was_containing = CONTAINING.with(|x| x.replace(true));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.future.as_mut().poll(cx)));
+        CONTAINING.with(|x| x.set(was_containing));
+        match result {
+            Ok(Poll::Ready(x)) => Poll::Ready(Ok(x)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(Crash {
+                message: panic_message(payload),
+                backtrace: BACKTRACE.with(|x| x.borrow_mut().take()),
+            })),
+        }
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(x) => *x,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(x) => String::from(*x),
+            Err(_) => String::from("<non-string panic payload>"),
+        },
+    }
+}
+
+// Logs everything known about a connection that panicked, in the spirit of Redis's bug report
+pub fn report(crash: &Crash, connection: &ConnectionInfo, server: &ServerState) {
+    let panics = server
+        .stats
+        .connection_panics
+        .fetch_add(1, Ordering::Relaxed)
+        + 1;
+    let command = connection.command().unwrap_or("none");
+    let backtrace = match &crash.backtrace {
+        Some(x) => x.to_string(),
+        None => String::from("unavailable"),
+    };
+    warning!(
+        "=== CONNECTION PANIC REPORT START ===\n\
+         panic: {}\n\
+         client: id={} addr={}\n\
+         command: {}\n\
+         connections_received: {} commands_processed: {} connection_panics: {}\n\
+         backtrace:\n{}\n\
+         === CONNECTION PANIC REPORT END === Only this connection was closed",
+        crash.message,
+        connection.client_id,
+        connection.peer,
+        command,
+        server.stats.connections_received.load(Ordering::Relaxed),
+        server.stats.commands_processed.load(Ordering::Relaxed),
+        panics,
+        backtrace,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn panics_are_contained_with_a_backtrace() {
+        let crash = match contain(async { panic!("handler bug") }).await {
+            Ok(()) => panic!("Expected the panic to be caught"),
+            Err(x) => x,
+        };
+        assert_eq!(crash.message, "handler bug");
+        assert!(crash.backtrace.is_some());
+
+        assert_eq!(contain(async { 7 }).await.ok(), Some(7));
+    }
+}
@@ -0,0 +1,101 @@
+use super::clock;
+use super::commands::Command;
+use super::expiry::active_expire_cycle;
+use super::lru::update_lru_clock;
+use super::notify::{self, NotifyFlags};
+use super::persistence::autosave_if_due;
+use super::state::ServerState;
+use super::synchronize::{propagate, propagate_command_to_replicas, request_acks};
+use super::RedisState;
+
+use crate::debug;
+use crate::resp::resp_serializer::serialize_command;
+use crate::server::wait_for_shutdown;
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task;
+use tokio::time::{self, Duration};
+
+pub const MIN_HZ: u64 = 1;
+pub const MAX_HZ: u64 = 500;
+// Matches Redis's default repl-ping-replica-period
+const REPLICA_PING_PERIOD_MS: u64 = 10_000;
+// How often replicas are asked for their offset, to check that they're still in sync
+const REPLICA_ACK_CHECK_PERIOD_MS: u64 = 1000;
+
+// All periodic housekeeping runs from this single task, `hz` times a second, in the spirit of
+// Redis's serverCron. Jobs that should run less often than every tick use run_with_period.
+pub fn spawn_cron(server: Arc<ServerState>, mut shutdown: watch::Receiver<bool>) {
+    let hz = server.config.current().hz.clamp(MIN_HZ, MAX_HZ);
+    task::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs_f64(1.0 / hz as f64));
+        let mut cronloops: u64 = 0;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => (),
+                _ = wait_for_shutdown(&mut shutdown) => return,
+            }
+            // Periods are counted in ticks, so ones that aren't a whole number of ticks round down
+            let run_with_period = |ms: u64| cronloops.is_multiple_of((ms * hz / 1000).max(1));
+
+            update_lru_clock();
+            clock::update_cached_time();
+            // REPLICAOF may have changed it since the last tick
+            let role = server.config.current().role;
+
+            // Replicas leave expiry to their master, which propagates a DEL for each key. Like
+            // any write, the keys are deleted and propagated holding applying.
+            if role == RedisState::Master {
+                let _applying = server.replication.applying.lock().await;
+                let lazy = server.config.current().lazyfree_lazy_expire;
+                let expired = server
+                    .keyspace
+                    .run_all(move |databases| {
+                        databases
+                            .iter()
+                            .map(|db| active_expire_cycle(db, lazy))
+                            .collect::<Vec<_>>()
+                    })
+                    .await;
+                for (db, expired_keys) in expired.into_iter().enumerate() {
+                    if expired_keys.is_empty() {
+                        continue;
+                    }
+                    debug!("Actively expired {} keys in db{}", expired_keys.len(), db);
+                    server
+                        .stats
+                        .expired_keys
+                        .fetch_add(expired_keys.len() as u64, Ordering::Relaxed);
+                    let flags = server.config.current().notify_keyspace_events;
+                    for key in &expired_keys {
+                        let class = NotifyFlags::EXPIRED;
+                        notify::publish(&server.pubsub, flags, class, db, "expired", key);
+                    }
+                    let del = Command::Del(expired_keys);
+                    propagate_command_to_replicas(&server.replication, db, &del).await;
+                    if let Some(writer) = server.aof.get() {
+                        writer.append(db, &del).await;
+                    }
+                }
+            }
+
+            // Checking on replicas every tick catches the ones that have been over the soft limit
+            // for too long. A replica's own replicas get its master's PINGs instead of ours.
+            let data = if role == RedisState::Master && run_with_period(REPLICA_PING_PERIOD_MS) {
+                serialize_command(&Command::Ping)
+            } else {
+                Vec::new()
+            };
+            propagate(&server.replication, &data).await;
+            if role == RedisState::Master && run_with_period(REPLICA_ACK_CHECK_PERIOD_MS) {
+                request_acks(&server.replication).await;
+            }
+
+            autosave_if_due(&server);
+
+            cronloops += 1;
+        }
+    });
+}
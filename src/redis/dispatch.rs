@@ -0,0 +1,886 @@
+use super::acl::{LogContext, NOAUTH_ERROR};
+use super::clock;
+use super::commands::{Command, SetOptions};
+use super::eviction::{evict_if_needed, OutOfMemory, OOM_ERROR};
+use super::info::{self, DatabaseStats};
+use super::keyspace::Keyspace;
+use super::notify::{self, NotifyFlags};
+use super::output::Reply;
+use super::persistence::{self, BGSAVE_IN_PROGRESS_ERROR};
+use super::processing::*;
+use super::pubsub::Kind;
+use super::replica::{self, LinkState, MASTERDOWN_ERROR, READONLY_ERROR, WAIT_ON_REPLICA_ERROR};
+use super::scripting::{ScriptCall, NOSCRIPT_ERROR};
+use super::shutdown::{self, SHUTDOWN_ERROR};
+use super::state::{ClientContext, ServerState, Transaction};
+use super::stream::IdSpec;
+use super::{synchronize, RedisState};
+
+use crate::config::ProtectedAccess;
+use crate::resp::resp_serializer::{serialize_for, serialize_resp_data};
+use crate::resp::{shared, Protocol, RespType};
+use crate::{debug, warning};
+
+use bytes::Bytes;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+
+const DEBUG_NOT_ALLOWED_ERROR: &str = "ERR DEBUG command not allowed. If the enable-debug-command option is set to \"local\", you can run it from a local connection, otherwise you need to set this option in the configuration file, and then restart the server.";
+
+// Executes parsed commands against the server, independent of how they arrived. TCP connections
+// and in-process clients both go through here.
+#[derive(Clone)]
+pub struct Dispatcher {
+    server: Arc<ServerState>,
+}
+
+impl Dispatcher {
+    pub fn new(server: Arc<ServerState>) -> Self {
+        Self { server }
+    }
+
+    pub fn server(&self) -> &Arc<ServerState> {
+        &self.server
+    }
+
+    // Runs `command` and returns its reply, or queues it if the client is in a transaction.
+    // PSYNC needs the connection's stream, so it's handled by the connection itself.
+    pub async fn dispatch(&self, command: Command, client: &mut ClientContext) -> Reply {
+        if !client.authenticated && !command.is_allowed_unauthenticated() {
+            return error_reply(NOAUTH_ERROR);
+        }
+        // Every other command is shown to monitors as it runs, see execute
+        if matches!(
+            command,
+            Command::Multi
+                | Command::Exec
+                | Command::Discard
+                | Command::Eval(_, _, _)
+                | Command::EvalSha(_, _, _)
+        ) {
+            self.server.monitors.feed(client, &command);
+        }
+        match command {
+            Command::Multi if client.transaction.is_some() => {
+                error_reply("ERR MULTI calls can not be nested")
+            }
+            Command::Multi => {
+                client.transaction = Some(Transaction::default());
+                shared::OK.to_vec().into()
+            }
+            Command::Exec => self.exec(client).await,
+            Command::Discard => match client.transaction.take() {
+                Some(_) => shared::OK.to_vec().into(),
+                None => error_reply("ERR DISCARD without MULTI"),
+            },
+            Command::Monitor if client.transaction.is_some() => {
+                error_reply("ERR MONITOR isn't allowed inside a transaction")
+            }
+            command if client.transaction.is_some() => {
+                // Refusing a command now rather than at EXEC, like Redis, means the whole
+                // transaction is discarded
+                let allowed = self
+                    .check_permissions(&command, &command.keys(), client, LogContext::Multi)
+                    .and_then(|_| self.check_writable(&command, client));
+                if let Err(reply) = allowed {
+                    if let Some(transaction) = &mut client.transaction {
+                        transaction.aborted = true;
+                    }
+                    return reply;
+                }
+                if let Some(transaction) = &mut client.transaction {
+                    transaction.commands.push(command);
+                }
+                serialize_resp_data(RespType::SimpleString(String::from("QUEUED"))).into()
+            }
+            // Its snapshot has to line up with where the AOF stands, so nothing may run meanwhile
+            Command::BgRewriteAof => {
+                let _guard = self.server.exec_lock.write().await;
+                self.execute(Command::BgRewriteAof, client, LogContext::Toplevel)
+                    .await
+            }
+            // Like a transaction, nothing else runs until the script is done
+            command @ (Command::Eval(_, _, _) | Command::EvalSha(_, _, _)) => {
+                let _guard = self.server.exec_lock.write().await;
+                self.eval(command, client, LogContext::Toplevel).await
+            }
+            // Commands already running finish first, and none start while it saves
+            Command::Shutdown(save) => {
+                let _guard = self.server.exec_lock.write().await;
+                self.execute(Command::Shutdown(save), client, LogContext::Toplevel)
+                    .await
+            }
+            command => {
+                let _guard = match command.is_blocking() {
+                    true => None,
+                    false => Some(self.server.exec_lock.read().await),
+                };
+                self.execute(command, client, LogContext::Toplevel).await
+            }
+        }
+    }
+
+    // Replies to a command its parser rejected. Like a command refused while queued, this
+    // discards the transaction the client is in.
+    pub fn reject(&self, error: &str, client: &mut ClientContext) -> Reply {
+        if let Some(transaction) = &mut client.transaction {
+            transaction.aborted = true;
+        }
+        error_reply(error)
+    }
+
+    // Runs the queued commands one after the other, with no other client's commands in between
+    async fn exec(&self, client: &mut ClientContext) -> Reply {
+        let transaction = match client.transaction.take() {
+            Some(x) => x,
+            None => return error_reply("ERR EXEC without MULTI"),
+        };
+        if transaction.aborted {
+            return error_reply("EXECABORT Transaction discarded because of previous errors.");
+        }
+        let _guard = self.server.exec_lock.write().await;
+        let mut replies = Vec::new();
+        shared::write_length_header(b'*', transaction.commands.len(), &mut replies);
+        for command in transaction.commands {
+            let reply = match command {
+                Command::Eval(_, _, _) | Command::EvalSha(_, _, _) => {
+                    self.eval(command, client, LogContext::Multi).await
+                }
+                command => {
+                    self.execute(command.without_blocking(), client, LogContext::Multi)
+                        .await
+                }
+            };
+            replies.extend(reply.into_vec());
+        }
+        replies.into()
+    }
+
+    // Runs a single command, which scripts do through here too. `context` is where it was run
+    // from, for ACL LOG.
+    pub(crate) async fn execute(
+        &self,
+        command: Command,
+        client: &mut ClientContext,
+        context: LogContext,
+    ) -> Reply {
+        let server = &self.server;
+        server
+            .stats
+            .commands_processed
+            .fetch_add(1, Ordering::Relaxed);
+        clock::update_cached_time();
+        debug!("Running {} in db{}", command.name(), client.db);
+        let keys = command.keys();
+        // In thread-per-core mode this sends the command's jobs to the thread owning its keys
+        let keyspace = &server.keyspace.select(client.db).route(&keys);
+        let config = &server.config.current();
+        let replication = &server.replication;
+
+        if let Err(reply) = self.check_permissions(&command, &keys, client, context) {
+            return reply;
+        }
+
+        if let Err(reply) = self.check_writable(&command, client) {
+            return reply;
+        }
+
+        if client.protocol == Protocol::Resp2
+            && client.subscriptions.is_active()
+            && !command.is_allowed_when_subscribed()
+        {
+            return error_reply(&format!(
+                "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                command.name()
+            ));
+        }
+
+        if config.role == RedisState::Replica
+            && !config.replica_serve_stale_data
+            && !command.is_allowed_when_stale()
+            && replication.link.state() != LinkState::Up
+        {
+            return serialize_resp_data(RespType::Error(MASTERDOWN_ERROR.to_string())).into();
+        }
+
+        server.monitors.feed(client, &command);
+
+        if command.is_write() {
+            server.stats.dirty.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // The master settles relative expirations before running or propagating the command, so
+        // that its replicas expire the key at the same moment it does
+        let command = match command {
+            Command::Set(key, value, options) if config.role == RedisState::Master => {
+                let expiry = options.expiry.map(|x| x.to_absolute());
+                Command::Set(key, value, SetOptions { expiry, ..options })
+            }
+            Command::Expire(key, expiry) if config.role == RedisState::Master => {
+                Command::Expire(key, expiry.to_absolute())
+            }
+            command => command,
+        };
+
+        // A write holds applying from before it runs until it's been propagated, so that writes go
+        // down the replication stream and into the AOF in the order they were applied. Our
+        // master's link already holds it, and blocking pops only take it once they can pop.
+        let applying = match command.is_write() && !command.is_blocking() && !client.is_obeyed() {
+            true => Some(replication.applying.lock().await),
+            false => None,
+        };
+
+        // The master reclaims expired keys as soon as a command touches them. Replicas only
+        // hide them, and wait for the DEL this sends down the replication stream. Deleting them
+        // is a write like any other, so a read takes applying first when it has any to delete.
+        if config.role == RedisState::Master && !keys.is_empty() {
+            let lazy = config.lazyfree_lazy_expire;
+            let must_lock = applying.is_none() && !client.is_obeyed();
+            let checked = || {
+                let keys = keys.clone();
+                keyspace.run(move |db| db.any_expired(&keys))
+            };
+            let expiring = match must_lock && checked().await {
+                true => Some(replication.applying.lock().await),
+                false => None,
+            };
+            let expired_keys = match !must_lock || expiring.is_some() {
+                true => {
+                    keyspace
+                        .run(move |db| db.expire_keys_if_needed(&keys, lazy))
+                        .await
+                }
+                false => Vec::new(),
+            };
+            if !expired_keys.is_empty() {
+                server
+                    .stats
+                    .expired_keys
+                    .fetch_add(expired_keys.len() as u64, Ordering::Relaxed);
+                for key in &expired_keys {
+                    let (flags, class) = (config.notify_keyspace_events, NotifyFlags::EXPIRED);
+                    notify::publish(&server.pubsub, flags, class, client.db, "expired", key);
+                }
+                let del = Command::Del(expired_keys);
+                synchronize::propagate_command_to_replicas(replication, client.db, &del).await;
+                self.append_to_aof(client.db, &del).await;
+            }
+        }
+
+        // Make room before any command that can grow the dataset, refusing it outright when
+        // nothing may be evicted
+        if config.role == RedisState::Master && config.maxmemory > 0 && command.is_denyoom() {
+            let (maxmemory, policy, samples, lazy) = (
+                config.maxmemory,
+                config.maxmemory_policy,
+                config.maxmemory_samples,
+                config.lazyfree_lazy_eviction,
+            );
+            match keyspace
+                .run_all(move |databases| {
+                    evict_if_needed(databases, maxmemory, policy, samples, lazy)
+                })
+                .await
+            {
+                Ok(evicted) => {
+                    for (db, evicted_keys) in evicted.into_iter().enumerate() {
+                        if evicted_keys.is_empty() {
+                            continue;
+                        }
+                        for key in &evicted_keys {
+                            let (flags, class) =
+                                (config.notify_keyspace_events, NotifyFlags::EVICTED);
+                            notify::publish(&server.pubsub, flags, class, db, "evicted", key);
+                        }
+                        let del = Command::Del(evicted_keys);
+                        synchronize::propagate_command_to_replicas(replication, db, &del).await;
+                        self.append_to_aof(db, &del).await;
+                    }
+                }
+                Err(OutOfMemory) => {
+                    return serialize_resp_data(RespType::Error(OOM_ERROR.to_string())).into();
+                }
+            }
+        }
+
+        // Running the command consumes it, so what's propagated once it has run is a copy
+        let propagated =
+            (command.is_write() && !command.is_propagated_after_running()).then(|| command.clone());
+
+        // Only the command itself is timed, like Redis's latency tracking. The slow log's copy
+        // of the arguments is taken now, as running the command consumes it, and like in Redis
+        // commands carrying passwords are left out.
+        let name = command.name();
+        let args = (config.slowlog_log_slower_than >= 0
+            && !matches!(command, Command::Auth(_, _) | Command::Hello(_, _)))
+        .then(|| command.to_args());
+        let is_write = command.is_write();
+        let started = Instant::now();
+        let protocol = client.protocol;
+        let response = match command {
+            Command::Echo(message) => handle_echo(message).await,
+            Command::Ping => handle_ping(client).await,
+            Command::Set(key, value, options) => {
+                keyspace
+                    .run(move |db| handle_set(key, value, options, db, protocol))
+                    .await
+            }
+            Command::Get(key) => {
+                let reply = keyspace.run(move |db| handle_get(key, db, protocol)).await;
+                self.record_latency(name, started, args, client);
+                return reply;
+            }
+            Command::Info(sections) => {
+                let databases = keyspace.run_all(DatabaseStats::gather).await;
+                info::handle_info(sections, server, databases, protocol).await
+            }
+            Command::ReplConf(option, args) => match option.to_lowercase().as_str() {
+                // Only a master asks for acknowledgements, so one has nothing to answer with
+                "getack" if config.role == RedisState::Master => {
+                    serialize_resp_data(RespType::Error(String::from(
+                        "ERR REPLCONF GETACK is only sent by a master to its replicas",
+                    )))
+                }
+                // The offset doesn't include the GETACK itself yet
+                "getack" => replica::handle_replconf_getack(server.replication.offset.get()).await,
+                "listening-port" => {
+                    client.listening_port = args.first().and_then(|x| x.parse().ok());
+                    replica::handle_replconf().await
+                }
+                // Each capability comes after its own "capa", as in capa eof capa psync2
+                "capa" => {
+                    let mut capabilities = args.iter().step_by(2);
+                    client.capa_eof = capabilities.any(|x| x.eq_ignore_ascii_case("eof"));
+                    replica::handle_replconf().await
+                }
+                _ => replica::handle_replconf().await,
+            },
+            Command::Psync(_, _) => serialize_resp_data(RespType::Error(String::from(
+                "ERR PSYNC is only supported over a network connection",
+            ))),
+            Command::Role => handle_role(server).await,
+            Command::ClientId => serialize_resp_data(RespType::Integer(client.id as i64)),
+            Command::ClientSetName(name) => handle_client_setname(name, client),
+            Command::ClientGetName => handle_client_getname(client, protocol),
+            Command::ClientList => handle_client_list(&server.clients, protocol),
+            Command::ClientKill(filter) => handle_client_kill(filter, &server.clients, client.id),
+            Command::Wait(_, _) if config.role == RedisState::Replica => {
+                serialize_resp_data(RespType::Error(String::from(WAIT_ON_REPLICA_ERROR)))
+            }
+            Command::Wait(replicas_to_wait_for, timeout) => {
+                handle_wait(server, replicas_to_wait_for, timeout).await
+            }
+            Command::ReplicaOf(host, port) => replica::handle_replicaof(server, host, port).await,
+            Command::ConfigGet(patterns) => handle_config_get(config, patterns, protocol),
+            Command::ConfigSet(pairs) => {
+                let reply = handle_config_set(server, pairs);
+                // The databases only keep the events that are going to be published
+                let flags = server.config.current().notify_keyspace_events;
+                keyspace
+                    .run_all(move |databases| {
+                        databases
+                            .iter()
+                            .for_each(|db| db.notifications.set_flags(flags))
+                    })
+                    .await;
+                reply
+            }
+            Command::Hello(version, auth) => handle_hello(version, auth, client, server),
+            Command::Auth(username, password) => handle_auth(username, password, client, server),
+            // The connection closes once the reply is out
+            Command::Quit => shared::OK.to_vec(),
+            Command::Select(index) => handle_select(index, client, config.databases),
+            Command::Keys(pattern) => {
+                let snapshot = keyspace.run(|db| db.snapshot()).await;
+                handle_keys(&snapshot, pattern)
+            }
+            Command::Object(subcommand, key) => {
+                let policy = config.maxmemory_policy;
+                keyspace
+                    .run(move |db| handle_object(subcommand, key, policy, db, protocol))
+                    .await
+            }
+            Command::Type(key) => keyspace.run(move |db| handle_type(key, db)).await,
+            Command::Strlen(key) => keyspace.run(move |db| handle_len(key, "string", db)).await,
+            Command::Llen(key) => keyspace.run(move |db| handle_len(key, "list", db)).await,
+            Command::Hlen(key) => keyspace.run(move |db| handle_len(key, "hash", db)).await,
+            Command::Scard(key) => keyspace.run(move |db| handle_len(key, "set", db)).await,
+            Command::Zcard(key) => keyspace.run(move |db| handle_len(key, "zset", db)).await,
+            Command::Xlen(key) => keyspace.run(move |db| handle_len(key, "stream", db)).await,
+            Command::MemoryUsage(key) => {
+                keyspace
+                    .run(move |db| handle_memory_usage(key, db, protocol))
+                    .await
+            }
+            Command::Scan(cursor, count, pattern, type_name) => {
+                keyspace
+                    .run(move |db| handle_scan(cursor, count, pattern, type_name, db))
+                    .await
+            }
+            Command::AclSetUser(username, rules) => {
+                handle_acl_setuser(&server.acl, username, rules)
+            }
+            Command::AclList => handle_acl_list(&server.acl),
+            Command::AclLog(arg) => handle_acl_log(&server.acl, arg, protocol),
+            Command::Ttl(key) => keyspace.run(move |db| handle_ttl(key, 1000, db)).await,
+            Command::Pttl(key) => keyspace.run(move |db| handle_ttl(key, 1, db)).await,
+            Command::Persist(key) => keyspace.run(move |db| handle_expire(key, None, db)).await,
+            Command::Expire(key, expiry) => {
+                keyspace
+                    .run(move |db| handle_expire(key, Some(expiry), db))
+                    .await
+            }
+            Command::Incr(key) => self.incr_by(keyspace, key, 1).await,
+            Command::Decr(key) => self.incr_by(keyspace, key, -1).await,
+            Command::IncrBy(key, increment) => self.incr_by(keyspace, key, increment as i128).await,
+            Command::DecrBy(key, decrement) => {
+                self.incr_by(keyspace, key, -(decrement as i128)).await
+            }
+            Command::MGet(keys) => {
+                keyspace
+                    .run(move |db| handle_mget(keys, db, protocol))
+                    .await
+            }
+            Command::MSet(pairs) => keyspace.run(move |db| handle_mset(pairs, false, db)).await,
+            Command::MSetNx(pairs) => keyspace.run(move |db| handle_mset(pairs, true, db)).await,
+            Command::Append(key, value) => {
+                let max_len = config.proto_max_bulk_len;
+                keyspace
+                    .run(move |db| handle_append(key, value, max_len, db))
+                    .await
+            }
+            Command::GetRange(key, start, end) => {
+                keyspace
+                    .run(move |db| handle_getrange(key, start, end, db))
+                    .await
+            }
+            Command::SetRange(key, offset, value) => {
+                let max_len = config.proto_max_bulk_len;
+                keyspace
+                    .run(move |db| handle_setrange(key, offset, value, max_len, db))
+                    .await
+            }
+            Command::SetBit(key, offset, on) => {
+                let max_len = config.proto_max_bulk_len;
+                keyspace
+                    .run(move |db| handle_setbit(key, offset, on, max_len, db))
+                    .await
+            }
+            Command::GetBit(key, offset) => {
+                let max_len = config.proto_max_bulk_len;
+                keyspace
+                    .run(move |db| handle_getbit(key, offset, max_len, db))
+                    .await
+            }
+            Command::BitCount(key, range) => {
+                keyspace
+                    .run(move |db| handle_bitcount(key, range, db))
+                    .await
+            }
+            Command::BitOp(operation, destination, keys) => {
+                keyspace
+                    .run(move |db| handle_bitop(operation, destination, keys, db))
+                    .await
+            }
+            Command::LPush(key, values) => {
+                let signalled = key.clone();
+                let reply = keyspace
+                    .run(move |db| handle_push(key, values, End::Front, db))
+                    .await;
+                server.blocking.signal(&signalled);
+                reply
+            }
+            Command::RPush(key, values) => {
+                let signalled = key.clone();
+                let reply = keyspace
+                    .run(move |db| handle_push(key, values, End::Back, db))
+                    .await;
+                server.blocking.signal(&signalled);
+                reply
+            }
+            Command::LRange(key, start, end) => {
+                keyspace
+                    .run(move |db| handle_lrange(key, start, end, db))
+                    .await
+            }
+            Command::LPop(key, count) => {
+                keyspace
+                    .run(move |db| handle_pop(key, count, End::Front, db, protocol))
+                    .await
+            }
+            Command::RPop(key, count) => {
+                keyspace
+                    .run(move |db| handle_pop(key, count, End::Back, db, protocol))
+                    .await
+            }
+            Command::BLPop(keys, timeout) => {
+                self.blocking_pop(keyspace, client, keys, timeout, End::Front)
+                    .await
+            }
+            Command::BRPop(keys, timeout) => {
+                self.blocking_pop(keyspace, client, keys, timeout, End::Back)
+                    .await
+            }
+            Command::HSet(key, pairs) => keyspace.run(move |db| handle_hset(key, pairs, db)).await,
+            Command::HGet(key, field) => {
+                keyspace
+                    .run(move |db| handle_hget(key, field, db, protocol))
+                    .await
+            }
+            Command::HDel(key, fields) => {
+                keyspace.run(move |db| handle_hdel(key, fields, db)).await
+            }
+            Command::HGetAll(key) => {
+                keyspace
+                    .run(move |db| handle_hgetall(key, db, protocol))
+                    .await
+            }
+            Command::ZAdd(key, pairs) => {
+                let limits = config.zset_listpack_limits();
+                keyspace
+                    .run(move |db| handle_zadd(key, pairs, &limits, db))
+                    .await
+            }
+            Command::ZRange(key, start, end, with_scores) => {
+                keyspace
+                    .run(move |db| handle_zrange(key, start, end, with_scores, db, protocol))
+                    .await
+            }
+            Command::ZScore(key, member) => {
+                keyspace
+                    .run(move |db| handle_zscore(key, member, db, protocol))
+                    .await
+            }
+            Command::ZRank(key, member) => {
+                keyspace
+                    .run(move |db| handle_zrank(key, member, db, protocol))
+                    .await
+            }
+            Command::XAdd(key, id, fields) => {
+                let (job_key, job_fields) = (key.clone(), fields.clone());
+                let (reply, added) = keyspace
+                    .run(move |db| handle_xadd(job_key, id, job_fields, db))
+                    .await;
+                if let Some(id) = added {
+                    server.blocking.signal(&key);
+                    // With the ID it was given, so that replicas' streams match ours, as does
+                    // the AOF's when it's replayed
+                    let command = Command::XAdd(key, IdSpec::Explicit(id), fields);
+                    if config.role == RedisState::Master {
+                        synchronize::propagate_command_to_replicas(
+                            replication,
+                            client.db,
+                            &command,
+                        )
+                        .await;
+                    }
+                    self.append_to_aof(client.db, &command).await;
+                }
+                reply
+            }
+            Command::XRange(key, start, end, count) => {
+                keyspace
+                    .run(move |db| handle_xrange(key, start, end, count, db))
+                    .await
+            }
+            Command::XRead(count, block, streams) => {
+                handle_xread(server, keyspace, count, block, streams, protocol).await
+            }
+            Command::LatencyHistogram(commands) => {
+                handle_latency_histogram(&server.latency, commands, protocol)
+            }
+            Command::Del(keys) => {
+                let lazy = config.lazyfree_lazy_user_del;
+                keyspace.run(move |db| handle_del(keys, db, lazy)).await
+            }
+            Command::Exists(keys) => keyspace.run(move |db| handle_exists(keys, db)).await,
+            Command::Rename(key, new_key) => {
+                let signalled = new_key.clone();
+                let reply = keyspace
+                    .run(move |db| handle_rename(key, new_key, db))
+                    .await;
+                // A stream moved in may be what an XREAD BLOCK is waiting on
+                server.blocking.signal(&signalled);
+                reply
+            }
+            Command::FlushDb(lazy) => {
+                let lazy = lazy.unwrap_or(config.lazyfree_lazy_user_flush);
+                keyspace
+                    .run(move |db| handle_flush(std::slice::from_ref(db), lazy))
+                    .await
+            }
+            Command::FlushAll(lazy) => {
+                let lazy = lazy.unwrap_or(config.lazyfree_lazy_user_flush);
+                keyspace
+                    .run_all(move |databases| handle_flush(databases, lazy))
+                    .await
+            }
+            Command::SwapDb(first, second) => {
+                let reply = keyspace
+                    .run_all(move |databases| handle_swapdb(first, second, databases))
+                    .await;
+                // Clients blocked in either database may now have something to read
+                server.blocking.signal_all();
+                reply
+            }
+            Command::RandomKey => keyspace.run(move |db| handle_randomkey(db, protocol)).await,
+            Command::DbSize => {
+                let size = keyspace.run(|db| db.len()).await;
+                serialize_resp_data(RespType::Integer(size as i64))
+            }
+            Command::Subscribe(channels) => {
+                handle_subscribe(&server.pubsub, client, Kind::Channel, channels)
+            }
+            Command::Unsubscribe(channels) => {
+                handle_unsubscribe(&server.pubsub, client, Kind::Channel, channels)
+            }
+            Command::PSubscribe(patterns) => {
+                handle_subscribe(&server.pubsub, client, Kind::Pattern, patterns)
+            }
+            Command::PUnsubscribe(patterns) => {
+                handle_unsubscribe(&server.pubsub, client, Kind::Pattern, patterns)
+            }
+            Command::Publish(channel, message) => handle_publish(&server.pubsub, channel, message),
+            Command::SlowlogGet(count) => handle_slowlog_get(&server.slowlog, count),
+            Command::SlowlogLen => {
+                serialize_resp_data(RespType::Integer(server.slowlog.len() as i64))
+            }
+            Command::SlowlogReset => {
+                server.slowlog.reset();
+                shared::OK.to_vec()
+            }
+            Command::Monitor => {
+                server.monitors.attach(client.id, &mut client.monitoring);
+                shared::OK.to_vec()
+            }
+            Command::DebugPanic if !debug_allowed(config.enable_debug_command, client) => {
+                serialize_resp_data(RespType::Error(String::from(DEBUG_NOT_ALLOWED_ERROR)))
+            }
+            Command::DebugPanic => panic!("DEBUG PANIC called by client {}", client.id),
+            Command::Save if server.persistence.bgsave_in_progress() => {
+                serialize_resp_data(RespType::Error(BGSAVE_IN_PROGRESS_ERROR.to_string()))
+            }
+            Command::Save => match persistence::save(server).await {
+                Ok(()) => shared::OK.to_vec(),
+                Err(e) => {
+                    warning!("Failed saving the DB: {}", e);
+                    serialize_resp_data(RespType::Error(String::from("ERR")))
+                }
+            },
+            Command::BgSave => match persistence::background_save(server) {
+                Ok(()) => serialize_resp_data(RespType::SimpleString(String::from(
+                    "Background saving started",
+                ))),
+                Err(e) => serialize_resp_data(RespType::Error(e.to_string())),
+            },
+            Command::BgRewriteAof => match persistence::background_rewrite_aof(server).await {
+                Ok(()) => serialize_resp_data(RespType::SimpleString(String::from(
+                    "Background append only file rewriting started",
+                ))),
+                Err(e) => serialize_resp_data(RespType::Error(e.to_string())),
+            },
+            // Going down, the client gets no reply, as its connection closes
+            Command::Shutdown(save) => match shutdown::prepare_for_shutdown(server, save).await {
+                Ok(()) => {
+                    server.shutdown_handle.shutdown();
+                    Vec::new()
+                }
+                Err(e) => {
+                    warning!("Errors trying to shut down the server: {}", e);
+                    serialize_resp_data(RespType::Error(SHUTDOWN_ERROR.to_string()))
+                }
+            },
+            Command::ScriptLoad(body) => match server.scripts.load(&body) {
+                Ok(sha) => serialize_resp_data(RespType::BulkString(Some(Bytes::from(sha)))),
+                Err(e) => serialize_resp_data(RespType::Error(e)),
+            },
+            Command::ScriptExists(shas) => serialize_resp_data(RespType::Array(
+                shas.iter()
+                    .map(|x| RespType::Integer(server.scripts.exists(x) as i64))
+                    .collect(),
+            )),
+            Command::ScriptFlush(_) => {
+                server.scripts.flush();
+                shared::OK.to_vec()
+            }
+            Command::CommandList => handle_command_list(protocol),
+            Command::CommandCount => handle_command_count(),
+            Command::CommandInfo(names) => handle_command_info(names, protocol),
+            Command::CommandDocs(names) => handle_command_docs(names, protocol),
+            Command::CommandGetKeys(argv) => handle_command_getkeys(argv),
+            Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Eval(_, _, _)
+            | Command::EvalSha(_, _, _) => {
+                unreachable!("{} is handled by dispatch", name)
+            }
+        };
+        self.record_latency(name, started, args, client);
+        if let Some(command) = propagated {
+            if config.role == RedisState::Master {
+                synchronize::propagate_command_to_replicas(replication, client.db, &command).await;
+            }
+            self.append_to_aof(client.db, &command).await;
+        }
+        drop(applying);
+        if is_write && config.notify_keyspace_events.is_on() {
+            let events = keyspace.run(|db| db.notifications.take()).await;
+            let flags = config.notify_keyspace_events;
+            notify::publish_all(&server.pubsub, flags, client.db, events);
+        }
+        Reply::Serialized(response)
+    }
+
+    // EVAL and EVALSHA, with the caller holding exec_lock for writing. EVAL loads the script as
+    // it runs it, as in Redis. The commands the script runs are propagated as they run.
+    async fn eval(
+        &self,
+        command: Command,
+        client: &mut ClientContext,
+        context: LogContext,
+    ) -> Reply {
+        let server = &self.server;
+        server
+            .stats
+            .commands_processed
+            .fetch_add(1, Ordering::Relaxed);
+        if let Err(reply) = self.check_permissions(&command, &command.keys(), client, context) {
+            return reply;
+        }
+        let name = command.name();
+        let args =
+            (server.config.current().slowlog_log_slower_than >= 0).then(|| command.to_args());
+        let (script, keys, argv) = match command {
+            Command::Eval(body, keys, argv) => match server.scripts.load(&body) {
+                Ok(sha) => (server.scripts.get(&sha), keys, argv),
+                Err(e) => return error_reply(&e),
+            },
+            Command::EvalSha(sha, keys, argv) => (server.scripts.get(&sha), keys, argv),
+            _ => unreachable!("Expected EVAL or EVALSHA"),
+        };
+        let script = match script {
+            Some(x) => x,
+            None => return error_reply(NOSCRIPT_ERROR),
+        };
+        let started = Instant::now();
+        let mut call = ScriptCall::new(self, client, keys, argv);
+        let reply = match script(&mut call).await {
+            Ok(reply) => serialize_for(reply, client.protocol),
+            Err(e) => serialize_resp_data(RespType::Error(e)),
+        };
+        self.record_latency(name, started, args, client);
+        reply.into()
+    }
+
+    // Permissions come first, as in Redis, and every refusal is logged for ACL LOG
+    fn check_permissions(
+        &self,
+        command: &Command,
+        keys: &[String],
+        client: &ClientContext,
+        context: LogContext,
+    ) -> Result<(), Reply> {
+        if let Some(user) = &client.user {
+            if let Err(denial) =
+                self.server
+                    .acl
+                    .check(user, command.name(), keys, command.channels())
+            {
+                let info = client.info(command.name());
+                self.server.acl.log_denial(&denial, context, user, info);
+                return Err(serialize_resp_data(RespType::Error(denial.error(user))).into());
+            }
+        }
+        Ok(())
+    }
+
+    // A replica only takes writes from its master, so that its data can't diverge from the
+    // master's
+    fn check_writable(&self, command: &Command, client: &ClientContext) -> Result<(), Reply> {
+        if command.is_write()
+            && !client.is_obeyed()
+            && self.server.config.current().role == RedisState::Replica
+        {
+            return Err(error_reply(READONLY_ERROR));
+        }
+        Ok(())
+    }
+
+    // Writes are logged to the AOF as they're propagated, when it's on
+    async fn append_to_aof(&self, db: usize, command: &Command) {
+        if let Some(writer) = self.server.aof.get() {
+            writer.append(db, command).await;
+        }
+    }
+
+    // BLPOP and BRPOP go down the replication stream and into the AOF as the LPOP or RPOP of the
+    // key they popped from, if they popped anything. The pop holds applying until then.
+    async fn blocking_pop(
+        &self,
+        keyspace: &Keyspace,
+        client: &ClientContext,
+        keys: Vec<String>,
+        timeout: Option<u64>,
+        end: End,
+    ) -> Vec<u8> {
+        let server = &self.server;
+        let (reply, popped) =
+            handle_blocking_pop(server, keyspace, keys, timeout, end, client.protocol).await;
+        if let Some((key, _applying)) = popped {
+            let command = match end {
+                End::Front => Command::LPop(key, None),
+                End::Back => Command::RPop(key, None),
+            };
+            if server.config.current().role == RedisState::Master {
+                synchronize::propagate_command_to_replicas(
+                    &server.replication,
+                    client.db,
+                    &command,
+                )
+                .await;
+            }
+            self.append_to_aof(client.db, &command).await;
+        }
+        reply
+    }
+
+    async fn incr_by(&self, keyspace: &Keyspace, key: String, increment: i128) -> Vec<u8> {
+        keyspace
+            .run(move |db| handle_incr_by(key, increment, db))
+            .await
+    }
+
+    // For LATENCY HISTOGRAM, and SLOWLOG if `args` are given
+    fn record_latency(
+        &self,
+        command: &str,
+        started: Instant,
+        args: Option<Vec<Bytes>>,
+        client: &ClientContext,
+    ) {
+        let (config, elapsed) = (self.server.config.current(), started.elapsed());
+        if config.latency_tracking {
+            self.server.latency.record(command, elapsed);
+        }
+        if let Some(args) = args {
+            let (threshold, max_len) = (config.slowlog_log_slower_than, config.slowlog_max_len);
+            self.server
+                .slowlog
+                .record(threshold, max_len, elapsed, args, client);
+        }
+    }
+}
+
+fn debug_allowed(access: ProtectedAccess, client: &ClientContext) -> bool {
+    match access {
+        ProtectedAccess::No => false,
+        ProtectedAccess::Yes => true,
+        ProtectedAccess::Local => client.is_local(),
+    }
+}
+
+fn error_reply(message: &str) -> Reply {
+    serialize_resp_data(RespType::Error(message.to_string())).into()
+}
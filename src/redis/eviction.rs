@@ -0,0 +1,422 @@
+use super::clock::mstime;
+use super::lazyfree;
+use super::store::{random_u64, Store};
+use crate::debug;
+
+use core::fmt;
+
+pub const OOM_ERROR: &str = "OOM command not allowed when used memory > 'maxmemory'.";
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum EvictionPolicy {
+    NoEviction,
+    AllKeysRandom,
+    AllKeysLru,
+    VolatileLru,
+    AllKeysLfu,
+    VolatileLfu,
+    VolatileTtl,
+    VolatileRandom,
+}
+
+impl EvictionPolicy {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "noeviction" => Some(EvictionPolicy::NoEviction),
+            "allkeys-random" => Some(EvictionPolicy::AllKeysRandom),
+            "allkeys-lru" => Some(EvictionPolicy::AllKeysLru),
+            "volatile-lru" => Some(EvictionPolicy::VolatileLru),
+            "allkeys-lfu" => Some(EvictionPolicy::AllKeysLfu),
+            "volatile-lfu" => Some(EvictionPolicy::VolatileLfu),
+            "volatile-ttl" => Some(EvictionPolicy::VolatileTtl),
+            "volatile-random" => Some(EvictionPolicy::VolatileRandom),
+            _ => None,
+        }
+    }
+
+    // Whether entries are ranked by access frequency rather than idle time, which decides what
+    // OBJECT FREQ and OBJECT IDLETIME may report
+    pub fn is_lfu(&self) -> bool {
+        matches!(
+            self,
+            EvictionPolicy::AllKeysLfu | EvictionPolicy::VolatileLfu
+        )
+    }
+
+    fn volatile_only(&self) -> bool {
+        matches!(
+            self,
+            EvictionPolicy::VolatileLru
+                | EvictionPolicy::VolatileLfu
+                | EvictionPolicy::VolatileTtl
+                | EvictionPolicy::VolatileRandom
+        )
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvictionPolicy::NoEviction => write!(f, "noeviction"),
+            EvictionPolicy::AllKeysRandom => write!(f, "allkeys-random"),
+            EvictionPolicy::AllKeysLru => write!(f, "allkeys-lru"),
+            EvictionPolicy::VolatileLru => write!(f, "volatile-lru"),
+            EvictionPolicy::AllKeysLfu => write!(f, "allkeys-lfu"),
+            EvictionPolicy::VolatileLfu => write!(f, "volatile-lfu"),
+            EvictionPolicy::VolatileTtl => write!(f, "volatile-ttl"),
+            EvictionPolicy::VolatileRandom => write!(f, "volatile-random"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct OutOfMemory;
+
+// Evicts keys until the dataset, across every database, fits in `maxmemory` bytes, returning the
+// evicted keys of each database. Fails if the policy doesn't allow eviction or there is nothing
+// left that it may evict. `lazy` frees evicted values on the background thread, though memory is
+// accounted as freed straight away.
+pub fn evict_if_needed(
+    databases: &[Store],
+    maxmemory: usize,
+    policy: EvictionPolicy,
+    samples: usize,
+    lazy: bool,
+) -> Result<Vec<Vec<String>>, OutOfMemory> {
+    let mut evicted = vec![Vec::new(); databases.len()];
+    if maxmemory == 0 {
+        return Ok(evicted);
+    }
+    while databases.iter().map(Store::used_memory).sum::<usize>() > maxmemory {
+        if policy == EvictionPolicy::NoEviction {
+            return Err(OutOfMemory);
+        }
+        // Each database puts its best candidate forward and the best of those goes, the way
+        // Redis fills its pool from every database
+        let victim = databases
+            .iter()
+            .enumerate()
+            .filter(|(_, db)| !db.is_empty())
+            .filter_map(|(index, db)| {
+                best_candidate(db, policy, samples).map(|(score, key)| (score, index, key))
+            })
+            .max_by_key(|(score, _, _)| *score);
+        let (index, key) = match victim {
+            Some((_, index, key)) => (index, key),
+            None => return Err(OutOfMemory),
+        };
+        let db = &databases[index];
+        db.eviction_pool().remove(&key);
+        let removed = db.write(&key).remove(&key);
+        if let Some(entry) = removed {
+            lazyfree::free(entry, lazy);
+            debug!("Evicted key {} under {}", key, policy);
+            evicted[index].push(key);
+        }
+    }
+    Ok(evicted)
+}
+
+// How many of the best candidates seen so far are remembered between evictions
+const EVICTION_POOL_SIZE: usize = 16;
+
+// Candidates sorted by ascending score, so the best one to evict is always last
+pub struct EvictionPool {
+    candidates: Vec<(u64, String)>,
+}
+
+impl EvictionPool {
+    pub fn new() -> Self {
+        Self {
+            candidates: Vec::with_capacity(EVICTION_POOL_SIZE),
+        }
+    }
+
+    fn insert(&mut self, score: u64, key: String) {
+        if self.candidates.iter().any(|(_, x)| *x == key) {
+            return;
+        }
+        if self.candidates.len() == EVICTION_POOL_SIZE {
+            if score <= self.candidates[0].0 {
+                return;
+            }
+            self.candidates.remove(0);
+        }
+        let position = self.candidates.partition_point(|(x, _)| *x < score);
+        self.candidates.insert(position, (score, key));
+    }
+
+    fn best(&self) -> Option<&(u64, String)> {
+        self.candidates.last()
+    }
+
+    fn pop_best(&mut self) -> Option<String> {
+        self.candidates.pop().map(|(_, key)| key)
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.candidates.retain(|(_, x)| x != key);
+    }
+}
+
+impl Default for EvictionPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Like Redis, approximates the ideal victim by sampling a handful of keys into a pool of the
+// best candidates seen so far, rather than keeping the whole keyspace ordered. Returns the best
+// one with its score, higher being better to evict, leaving it in the pool.
+fn best_candidate(db: &Store, policy: EvictionPolicy, samples: usize) -> Option<(u64, String)> {
+    let mut pool = db.eviction_pool();
+    let mut attempts = 0;
+    let mut num_sampled = 0;
+    while num_sampled < samples.max(1) && attempts < samples.max(1) * db.num_shards() {
+        attempts += 1;
+        let shard = db.read_shard(random_u64() as usize % db.num_shards());
+        let key = match policy.volatile_only() {
+            true => shard.random_volatile_key(),
+            false => shard.random_key(),
+        };
+        let (key, entry) = match key.and_then(|x| shard.peek(x).map(|y| (x, y))) {
+            Some(x) => x,
+            None => continue,
+        };
+        num_sampled += 1;
+        // Higher scores are better eviction candidates
+        let score = match policy {
+            EvictionPolicy::AllKeysLru | EvictionPolicy::VolatileLru => entry.idle_ms(),
+            EvictionPolicy::AllKeysLfu | EvictionPolicy::VolatileLfu => {
+                255 - entry.access_frequency() as u64
+            }
+            EvictionPolicy::VolatileTtl => match entry.expires_at {
+                Some(x) => u64::MAX - x.saturating_sub(mstime()),
+                None => 0,
+            },
+            // A random score makes the pick between databases random too
+            _ => return Some((random_u64(), key.clone())),
+        };
+        pool.insert(score, key.clone());
+    }
+    // Candidates may have been deleted since they were pooled
+    while let Some((score, key)) = pool.best() {
+        if db.read(key).peek(key).is_some() {
+            return Some((*score, key.clone()));
+        }
+        pool.pop_best();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis::store::Entry;
+    use crate::redis::value::Value;
+    use bytes::Bytes;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    const SAMPLES: usize = 100;
+
+    fn insert(db: &Store, key: &str, expires_at: Option<u64>) {
+        let mut entry = Entry::new(Value::Str(Bytes::from("value").into()));
+        entry.expires_at = expires_at;
+        db.write(key).insert(key.to_string(), entry);
+    }
+
+    // A database of `count` keys, all idle for a while, named key:0 and so on
+    fn idle_keys(count: usize, expires_at: Option<u64>) -> Store {
+        let db = Store::new();
+        for i in 0..count {
+            let key = format!("key:{}", i);
+            insert(&db, &key, expires_at);
+            db.read(&key).peek(&key).unwrap().set_idle_ms(100_000);
+        }
+        db
+    }
+
+    fn keys(db: &Store) -> Vec<String> {
+        let mut keys: Vec<String> = db
+            .read_all()
+            .iter()
+            .flat_map(|shard| shard.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    // Room for `count` of the keys in `db`, which are all the same size
+    fn room_for(db: &Store, count: usize) -> usize {
+        db.used_memory() / db.len() * count
+    }
+
+    fn size_of(db: &Store, key: &str) -> usize {
+        db.read(key).peek(key).unwrap().size()
+    }
+
+    #[test]
+    fn noeviction_refuses_to_go_over_the_limit() {
+        let db = idle_keys(4, None);
+        let databases = [db];
+        let maxmemory = room_for(&databases[0], 3);
+        assert!(evict_if_needed(
+            &databases,
+            maxmemory,
+            EvictionPolicy::NoEviction,
+            SAMPLES,
+            false
+        )
+        .is_err());
+        assert_eq!(databases[0].len(), 4);
+        // No limit, or one that isn't reached, needs nothing evicted
+        let fits = room_for(&databases[0], 4);
+        for maxmemory in [0, fits] {
+            let evicted = evict_if_needed(
+                &databases,
+                maxmemory,
+                EvictionPolicy::NoEviction,
+                SAMPLES,
+                false,
+            );
+            assert_eq!(evicted.unwrap(), vec![Vec::<String>::new()]);
+        }
+    }
+
+    #[test]
+    fn allkeys_lru_evicts_the_least_recently_used_keys() {
+        let db = idle_keys(8, None);
+        insert(&db, "recent", None);
+        let databases = [db];
+        let maxmemory = size_of(&databases[0], "recent");
+        let evicted = evict_if_needed(
+            &databases,
+            maxmemory,
+            EvictionPolicy::AllKeysLru,
+            SAMPLES,
+            false,
+        );
+        assert_eq!(evicted.unwrap()[0].len(), 8);
+        assert_eq!(keys(&databases[0]), ["recent"]);
+    }
+
+    #[test]
+    fn volatile_lru_only_evicts_keys_with_an_expiry() {
+        let db = idle_keys(4, None);
+        let expires_at = Some(mstime() + 100_000);
+        insert(&db, "volatile:recent", expires_at);
+        insert(&db, "volatile:idle", expires_at);
+        db.read("volatile:idle")
+            .peek("volatile:idle")
+            .unwrap()
+            .set_idle_ms(100_000);
+        let databases = [db];
+        let maxmemory = databases[0].used_memory() - 1;
+
+        let evicted = evict_if_needed(
+            &databases,
+            maxmemory,
+            EvictionPolicy::VolatileLru,
+            SAMPLES,
+            false,
+        );
+        assert_eq!(evicted.unwrap(), vec![vec![String::from("volatile:idle")]]);
+        // Once the volatile keys are gone there's nothing left it may evict
+        let evicted = evict_if_needed(&databases, 1, EvictionPolicy::VolatileLru, SAMPLES, false);
+        assert!(evicted.is_err());
+        assert_eq!(keys(&databases[0]), ["key:0", "key:1", "key:2", "key:3"]);
+    }
+
+    #[test]
+    fn allkeys_random_evicts_until_the_dataset_fits() {
+        let databases = [idle_keys(10, None), idle_keys(10, None)];
+        let maxmemory = room_for(&databases[0], 5);
+        let evicted = evict_if_needed(
+            &databases,
+            maxmemory,
+            EvictionPolicy::AllKeysRandom,
+            SAMPLES,
+            false,
+        )
+        .unwrap();
+        assert_eq!(evicted.iter().map(Vec::len).sum::<usize>(), 15);
+        assert!(databases.iter().map(Store::used_memory).sum::<usize>() <= maxmemory);
+    }
+
+    #[test]
+    fn allkeys_lfu_keeps_frequently_used_keys() {
+        let db = idle_keys(8, None);
+        insert(&db, "frequent", None);
+        for _ in 0..100 {
+            db.read("frequent").get("frequent");
+        }
+        let databases = [db];
+        let maxmemory = size_of(&databases[0], "frequent");
+        evict_if_needed(
+            &databases,
+            maxmemory,
+            EvictionPolicy::AllKeysLfu,
+            SAMPLES,
+            false,
+        )
+        .unwrap();
+        assert_eq!(keys(&databases[0]), ["frequent"]);
+    }
+
+    #[test]
+    fn volatile_ttl_evicts_the_keys_closest_to_expiring() {
+        let db = Store::new();
+        for (i, ttl) in [50_000, 10_000, 90_000, 30_000].into_iter().enumerate() {
+            insert(&db, &format!("key:{}", i), Some(mstime() + ttl));
+        }
+        let databases = [db];
+        let maxmemory = room_for(&databases[0], 2);
+        let evicted = evict_if_needed(
+            &databases,
+            maxmemory,
+            EvictionPolicy::VolatileTtl,
+            SAMPLES,
+            false,
+        );
+        assert_eq!(
+            evicted.unwrap(),
+            vec![vec![String::from("key:1"), String::from("key:3")]]
+        );
+    }
+
+    #[test]
+    fn evicting_while_databases_are_swapped_doesnt_deadlock() {
+        let databases = Arc::new([idle_keys(64, None), idle_keys(64, None)]);
+        let maxmemory = room_for(&databases[0], 96);
+        let (done, finished) = mpsc::channel();
+        let swapping = Arc::clone(&databases);
+        let swapped = done.clone();
+        thread::spawn(move || {
+            for _ in 0..2000 {
+                swapping[0].swap(&swapping[1]);
+            }
+            swapped.send(()).unwrap();
+        });
+        let evicting = Arc::clone(&databases);
+        thread::spawn(move || {
+            for i in 0..2000 {
+                insert(&evicting[i % 2], &format!("new:{}", i), None);
+                let _ = evict_if_needed(
+                    &evicting[..],
+                    maxmemory,
+                    EvictionPolicy::AllKeysLru,
+                    5,
+                    false,
+                );
+            }
+            done.send(()).unwrap();
+        });
+        for _ in 0..2 {
+            finished
+                .recv_timeout(Duration::from_secs(30))
+                .expect("Expected swapping and evicting to finish");
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use super::store::Store;
+
+const KEYS_PER_SAMPLE: usize = 20;
+// Keep sampling a shard while more than this percentage of the sampled keys had expired
+const ACCEPTABLE_EXPIRED_PERCENT: usize = 25;
+// Bounds the time a single cycle can hold any one shard's lock
+const MAX_SAMPLES_PER_SHARD: usize = 16;
+
+// Samples keys with a TTL in every shard and deletes the expired ones, returning their names.
+// `lazy` frees them on the background thread.
+pub fn active_expire_cycle(db: &Store, lazy: bool) -> Vec<String> {
+    let mut expired_keys = Vec::new();
+    for index in 0..db.num_shards() {
+        let mut shard = db.write_shard(index);
+        for _ in 0..MAX_SAMPLES_PER_SHARD {
+            let num_sampled = KEYS_PER_SAMPLE.min(shard.volatile_len());
+            if num_sampled == 0 {
+                break;
+            }
+            let expired = shard.expire_sample(KEYS_PER_SAMPLE, lazy);
+            let mostly_expired = expired.len() * 100 > num_sampled * ACCEPTABLE_EXPIRED_PERCENT;
+            expired_keys.extend(expired);
+            if !mostly_expired {
+                break;
+            }
+        }
+    }
+    expired_keys
+}
@@ -0,0 +1,112 @@
+// Matches `string` against a glob-style `pattern` the way Redis's stringmatchlen does, as used by
+// KEYS, SCAN MATCH and ACL key patterns. `*` matches any run of bytes, `?` any one byte, `[abc]`,
+// `[^abc]` and `[a-z]` a class of bytes, and `\` escapes the byte after it.
+pub fn matches(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Where to resume after the last `*` if what follows it stops matching: the pattern just past
+    // the star, and the next position in the string for the star to swallow
+    let mut backtrack = None;
+    while s < string.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                // Runs of stars are the same as one
+                while pattern.get(p) == Some(&b'*') {
+                    p += 1;
+                }
+                if p == pattern.len() {
+                    return true;
+                }
+                backtrack = Some((p, s + 1));
+                continue;
+            }
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => match_class(pattern, p + 1, string[s]),
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == string[s]).then_some(p + 2),
+            Some(x) => (*x == string[s]).then_some(p + 1),
+            None => None,
+        };
+        match (step, backtrack) {
+            (Some(next), _) => {
+                p = next;
+                s += 1;
+            }
+            (None, Some((star, resume))) => {
+                p = star;
+                s = resume;
+                backtrack = Some((star, resume + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|x| *x == b'*')
+}
+
+// Matches `byte` against the class whose contents start at `start`, returning where the pattern
+// continues after its closing bracket. An unclosed class runs to the end of the pattern.
+fn match_class(pattern: &[u8], start: usize, byte: u8) -> Option<usize> {
+    let mut p = start;
+    let negated = pattern.get(p) == Some(&b'^');
+    if negated {
+        p += 1;
+    }
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            matched |= pattern[p + 1] == byte;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (low, high) = (
+                pattern[p].min(pattern[p + 2]),
+                pattern[p].max(pattern[p + 2]),
+            );
+            matched |= (low..=high).contains(&byte);
+            p += 3;
+        } else {
+            matched |= pattern[p] == byte;
+            p += 1;
+        }
+    }
+    (matched != negated).then_some((p + 1).min(pattern.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_match_like_redis() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*", "", true),
+            ("*", "anything", true),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h*llo", "hllo", true),
+            ("h*llo", "heeeello", true),
+            ("h*llo", "hello world", false),
+            ("*llo*", "hello world", true),
+            ("user:*:name", "user:1:2:name", true),
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-b]llo", "hbllo", true),
+            ("h[b-a]llo", "hallo", true),
+            ("h[a-b]llo", "hcllo", false),
+            ("h\\*llo", "h*llo", true),
+            ("h\\*llo", "hello", false),
+            ("[\\]]", "]", true),
+            ("abc", "abcd", false),
+            ("abc*", "ab", false),
+            ("a**c", "abbbc", true),
+        ];
+        for (pattern, string, expected) in cases {
+            assert_eq!(
+                matches(pattern.as_bytes(), string.as_bytes()),
+                *expected,
+                "{} against {}",
+                pattern,
+                string
+            );
+        }
+    }
+}
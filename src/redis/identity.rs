@@ -0,0 +1,86 @@
+use super::store::random_u64;
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+// Set by builds made from a checkout, like Redis's release.h. Zeroes otherwise, as in Redis.
+const GIT_SHA1: Option<&str> = option_env!("REDIS_GIT_SHA1");
+const RUN_ID_LENGTH: usize = 40;
+
+// Who this server process is. The run_id is new every time the server starts, which is how
+// monitoring and failover tools tell a restart from a server that's merely been unreachable.
+pub struct Identity {
+    pub run_id: String,
+    started: Instant,
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Identity {
+            run_id: random_id(),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Identity {
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+// 40 random hex characters, the shape of both run ids and replication ids
+pub fn random_id() -> String {
+    let mut id = String::with_capacity(RUN_ID_LENGTH);
+    while id.len() < RUN_ID_LENGTH {
+        id.push_str(&format!("{:016x}", random_u64()));
+    }
+    id.truncate(RUN_ID_LENGTH);
+    id
+}
+
+pub fn git_sha1() -> &'static str {
+    GIT_SHA1.unwrap_or("00000000")
+}
+
+// Tells builds apart the way Redis's build id does, from what went into the binary
+pub fn build_id() -> String {
+    let mut hasher = DefaultHasher::new();
+    (
+        VERSION,
+        git_sha1(),
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        cfg!(debug_assertions),
+    )
+        .hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+// What --version prints, in redis-server's format
+pub fn version_line() -> String {
+    format!(
+        "Redis server v={} sha={}:0 malloc=libc bits={} build={}",
+        VERSION,
+        git_sha1(),
+        usize::BITS,
+        build_id()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_start_gets_a_new_run_id() {
+        let (first, second) = (Identity::default(), Identity::default());
+        assert_eq!(first.run_id.len(), RUN_ID_LENGTH);
+        assert!(first.run_id.chars().all(|x| x.is_ascii_hexdigit()));
+        assert_ne!(first.run_id, second.run_id);
+        // The build id is the same for the whole binary
+        assert_eq!(build_id(), build_id());
+    }
+}
@@ -0,0 +1,223 @@
+use super::clock;
+use super::identity;
+use super::lazyfree;
+use super::replica::LinkState;
+use super::state::ServerState;
+use super::store::Store;
+use super::RedisState;
+
+use crate::resp::resp_serializer::serialize_for;
+use crate::resp::{Protocol, RespType};
+
+use bytes::Bytes;
+use std::sync::atomic::Ordering;
+
+// What INFO with no section, or "default", reports
+const DEFAULT_SECTIONS: &[&str] = &[
+    "server",
+    "clients",
+    "memory",
+    "persistence",
+    "stats",
+    "replication",
+    "keyspace",
+];
+// The sections beyond the default ones, which "all" and "everything" add
+const EXTRA_SECTIONS: &[&str] = &["latencystats"];
+
+// What INFO reports about the databases themselves, gathered in one pass over them
+pub struct DatabaseStats {
+    used_memory: usize,
+    hits: u64,
+    misses: u64,
+    // Each database's keys and keys with an expiration
+    keys: Vec<(usize, usize)>,
+}
+
+impl DatabaseStats {
+    pub fn gather(databases: &[Store]) -> Self {
+        let mut stats = DatabaseStats {
+            used_memory: 0,
+            hits: 0,
+            misses: 0,
+            keys: Vec::with_capacity(databases.len()),
+        };
+        for db in databases {
+            let (hits, misses) = db.lookups();
+            stats.used_memory += db.used_memory();
+            stats.hits += hits;
+            stats.misses += misses;
+            stats.keys.push((db.len(), db.volatile_len()));
+        }
+        stats
+    }
+}
+
+// Renders the requested sections in their usual order, separated by blank lines. Section names
+// are case-insensitive, and ones that don't exist are left out.
+pub async fn handle_info(
+    requested: Vec<String>,
+    server: &ServerState,
+    databases: DatabaseStats,
+    protocol: Protocol,
+) -> Vec<u8> {
+    let requested: Vec<String> = requested.iter().map(|x| x.to_lowercase()).collect();
+    let everything = requested.iter().any(|x| x == "all" || x == "everything");
+    let default = requested.is_empty() || requested.iter().any(|x| x == "default");
+    let mut sections = Vec::new();
+    for name in DEFAULT_SECTIONS.iter().chain(EXTRA_SECTIONS) {
+        let is_default = DEFAULT_SECTIONS.contains(name);
+        if everything || (default && is_default) || requested.iter().any(|x| x == name) {
+            sections.push(render(name, server, &databases).await);
+        }
+    }
+    serialize_for(
+        RespType::VerbatimString(String::from("txt"), Bytes::from(sections.join("\r\n"))),
+        protocol,
+    )
+}
+
+async fn render(section: &str, server: &ServerState, databases: &DatabaseStats) -> String {
+    let config = server.config.current();
+    let stats = &server.stats;
+    match section {
+        "server" => {
+            let uptime = server.identity.uptime().as_secs();
+            format!(
+                "# Server\r\nredis_version:{}\r\nredis_git_sha1:{}\r\nredis_build_id:{}\r\nredis_mode:standalone\r\nos:{} {}\r\narch_bits:{}\r\nprocess_id:{}\r\nrun_id:{}\r\ntcp_port:{}\r\nserver_time_usec:{}\r\nuptime_in_seconds:{}\r\nuptime_in_days:{}\r\nhz:{}\r\nexecutable:{}\r\nconfig_file:{}\r\n",
+                identity::VERSION,
+                identity::git_sha1(),
+                identity::build_id(),
+                std::env::consts::OS,
+                std::env::consts::ARCH,
+                usize::BITS,
+                std::process::id(),
+                server.identity.run_id,
+                config.port,
+                clock::unix_ms() * 1000,
+                uptime,
+                uptime / (24 * 60 * 60),
+                config.hz,
+                std::env::current_exe().map_or(String::new(), |x| x.display().to_string()),
+                config
+                    .config_file
+                    .as_ref()
+                    .map_or(String::new(), |x| x.display().to_string())
+            )
+        }
+        "clients" => format!(
+            "# Clients\r\nconnected_clients:{}\r\nmaxclients:{}\r\n",
+            server.clients.len(),
+            config.maxclients
+        ),
+        "memory" => format!(
+            "# Memory\r\nused_memory:{}\r\nmaxmemory:{}\r\nmaxmemory_policy:{}\r\nlazyfree_pending_objects:{}\r\n",
+            databases.used_memory,
+            config.maxmemory,
+            config.maxmemory_policy,
+            lazyfree::pending()
+        ),
+        "persistence" => format!(
+            "# Persistence\r\nrdb_changes_since_last_save:{}\r\nrdb_bgsave_in_progress:{}\r\nrdb_last_save_time:{}\r\nrdb_last_bgsave_status:{}\r\naof_enabled:{}\r\naof_rewrite_in_progress:{}\r\naof_last_bgrewrite_status:{}\r\n",
+            stats.dirty.load(Ordering::Relaxed),
+            server.persistence.bgsave_in_progress() as u8,
+            server.persistence.last_save(),
+            if server.persistence.last_bgsave_ok() { "ok" } else { "err" },
+            config.appendonly as u8,
+            server.persistence.aof_rewrite_in_progress() as u8,
+            if server.persistence.last_aof_rewrite_ok() { "ok" } else { "err" }
+        ),
+        "stats" => format!(
+            "# Stats\r\ntotal_connections_received:{}\r\nrejected_connections:{}\r\ntotal_commands_processed:{}\r\nexpired_keys:{}\r\nkeyspace_hits:{}\r\nkeyspace_misses:{}\r\nlazyfreed_objects:{}\r\nconnection_panics:{}\r\n",
+            stats.connections_received.load(Ordering::Relaxed),
+            stats.rejected_connections.load(Ordering::Relaxed),
+            stats.commands_processed.load(Ordering::Relaxed),
+            stats.expired_keys.load(Ordering::Relaxed),
+            databases.hits,
+            databases.misses,
+            lazyfree::freed(),
+            stats.connection_panics.load(Ordering::Relaxed)
+        ),
+        "replication" => render_replication(server).await,
+        "keyspace" => {
+            let mut info = String::from("# Keyspace\r\n");
+            for (db, (keys, expires)) in databases.keys.iter().enumerate() {
+                if *keys > 0 {
+                    info.push_str(&format!("db{}:keys={},expires={}\r\n", db, keys, expires));
+                }
+            }
+            info
+        }
+        "latencystats" => {
+            let mut info = String::from("# Latencystats\r\n");
+            for (name, histogram) in server.latency.called(&[]) {
+                let percentiles: Vec<String> = config
+                    .latency_tracking_info_percentiles
+                    .iter()
+                    .map(|x| format!("p{}={:.3}", x, histogram.percentile(*x) as f64))
+                    .collect();
+                info.push_str(&format!(
+                    "latency_percentiles_usec_{}:{}\r\n",
+                    name,
+                    percentiles.join(",")
+                ));
+            }
+            info
+        }
+        other => unreachable!("{} is not an INFO section", other),
+    }
+}
+
+async fn render_replication(server: &ServerState) -> String {
+    let config = server.config.current();
+    let replication = &server.replication;
+    let mut info = format!("# Replication\r\nrole:{}\r\n", config.role);
+    if config.role == RedisState::Replica {
+        let link = &replication.link;
+        info.push_str(&format!(
+            "master_host:{}\r\nmaster_port:{}\r\nmaster_link_status:{}\r\nmaster_sync_in_progress:{}\r\nslave_repl_offset:{}\r\n",
+            config.master_host.as_deref().unwrap_or(""),
+            config.master_port.as_deref().unwrap_or(""),
+            link.state(),
+            (link.state() == LinkState::Syncing) as u8,
+            replication.offset.get()
+        ));
+        if let Some(x) = link.down_for() {
+            info.push_str(&format!("master_link_down_since_seconds:{}\r\n", x));
+        }
+    }
+    // A replica's own replicas follow the stream it passes on
+    let replicas: Vec<String> = replication
+        .replicas
+        .read()
+        .await
+        .values()
+        .map(|link| {
+            format!(
+                "ip={},port={},state=online,offset={}",
+                link.ip,
+                link.listening_port.unwrap_or(0),
+                link.ack.offset()
+            )
+        })
+        .collect();
+    info.push_str(&format!("connected_slaves:{}\r\n", replicas.len()));
+    for (index, replica) in replicas.iter().enumerate() {
+        info.push_str(&format!("slave{}:{}\r\n", index, replica));
+    }
+    let replid = replication.replid.lock().unwrap().clone();
+    let (start, length, capacity) = {
+        let backlog = replication.backlog.lock().unwrap();
+        (backlog.start(), backlog.len(), backlog.capacity())
+    };
+    // Like Redis, the backlog's first byte is counted from 1
+    info.push_str(&format!(
+        "master_replid:{}\r\nmaster_repl_offset:{}\r\nrepl_backlog_active:1\r\nrepl_backlog_size:{}\r\nrepl_backlog_first_byte_offset:{}\r\nrepl_backlog_histlen:{}\r\n",
+        replid,
+        replication.master_offset.load(Ordering::SeqCst),
+        capacity,
+        start + 1,
+        length
+    ));
+    info
+}
@@ -0,0 +1,188 @@
+use super::store::{Snapshot, Store};
+use super::workers::current_worker;
+
+use core::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+
+const ACTOR_QUEUE_SIZE: usize = 1024;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum KeyspaceMode {
+    // Connections lock the shards of the store directly
+    Shared,
+    // A single task owns the store and runs every job sent to it in arrival order
+    Actor,
+    // Each worker thread accepts its own connections and owns a slice of the store's shards.
+    // Commands whose keys all belong to one worker are run on that worker's thread.
+    ThreadPerCore,
+}
+
+impl fmt::Display for KeyspaceMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyspaceMode::Shared => write!(f, "shared"),
+            KeyspaceMode::Actor => write!(f, "actor"),
+            KeyspaceMode::ThreadPerCore => write!(f, "thread-per-core"),
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce(&[Store]) + Send>;
+
+// Handle through which connections access the databases, independent of which mode it runs in.
+// Jobs run against the database it's been narrowed down to with select, 0 unless it has.
+#[derive(Clone)]
+pub struct Keyspace {
+    access: Access,
+    db: usize,
+}
+
+#[derive(Clone)]
+enum Access {
+    Shared(Arc<Vec<Store>>),
+    Actor(mpsc::Sender<Job>),
+    // Jobs without an owner, such as those spanning several workers' shards, lock the store
+    // directly. Shards are owned by worker `index % workers`, in every database.
+    ThreadPerCore(Arc<Vec<Store>>, Arc<Vec<mpsc::Sender<Job>>>),
+    // A thread-per-core keyspace narrowed down to the worker owning a command's keys
+    Worker(Arc<Vec<Store>>, usize, mpsc::Sender<Job>),
+}
+
+impl Keyspace {
+    // `workers` are the worker runtimes for thread-per-core mode, unused otherwise
+    pub fn new(databases: Vec<Store>, mode: KeyspaceMode, workers: &[Handle]) -> Self {
+        let access = match mode {
+            KeyspaceMode::Shared => Access::Shared(Arc::new(databases)),
+            KeyspaceMode::Actor => {
+                let (sender, mut receiver) = mpsc::channel::<Job>(ACTOR_QUEUE_SIZE);
+                task::spawn(async move {
+                    while let Some(job) = receiver.recv().await {
+                        job(&databases);
+                    }
+                });
+                Access::Actor(sender)
+            }
+            KeyspaceMode::ThreadPerCore => {
+                let databases = Arc::new(databases);
+                let senders = workers
+                    .iter()
+                    .map(|worker| {
+                        let (sender, mut receiver) = mpsc::channel::<Job>(ACTOR_QUEUE_SIZE);
+                        let databases = Arc::clone(&databases);
+                        worker.spawn(async move {
+                            while let Some(job) = receiver.recv().await {
+                                job(&databases);
+                            }
+                        });
+                        sender
+                    })
+                    .collect();
+                Access::ThreadPerCore(databases, Arc::new(senders))
+            }
+        };
+        Keyspace { access, db: 0 }
+    }
+
+    // The keyspace narrowed down to database `db`, which has to exist
+    pub fn select(&self, db: usize) -> Keyspace {
+        Keyspace {
+            access: self.access.clone(),
+            db,
+        }
+    }
+
+    // The keyspace to run a command touching `keys` against. In thread-per-core mode that's the
+    // worker owning all of them, if there is one. Otherwise it's this keyspace.
+    pub fn route(&self, keys: &[String]) -> Keyspace {
+        let (databases, workers) = match &self.access {
+            Access::ThreadPerCore(databases, workers) => (databases, workers),
+            _ => return self.clone(),
+        };
+        let mut owners = keys
+            .iter()
+            .map(|key| databases[self.db].shard_index(key) % workers.len());
+        match owners.next() {
+            Some(owner) if owners.all(|x| x == owner) => Keyspace {
+                access: Access::Worker(Arc::clone(databases), owner, workers[owner].clone()),
+                db: self.db,
+            },
+            _ => self.clone(),
+        }
+    }
+
+    // Runs `job` against the selected database. In actor mode jobs never interleave, so
+    // everything done inside a single job is atomic with respect to other connections.
+    pub async fn run<R, F>(&self, job: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce(&Store) -> R + Send + 'static,
+    {
+        let db = self.db;
+        self.run_all(move |databases| job(&databases[db])).await
+    }
+
+    // Runs `job` against every database, indexed by number
+    pub async fn run_all<R, F>(&self, job: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce(&[Store]) -> R + Send + 'static,
+    {
+        match &self.access {
+            Access::Shared(databases) | Access::ThreadPerCore(databases, _) => job(databases),
+            // Already on the owning thread, so there's nobody to hand the job to
+            Access::Worker(databases, owner, _) if current_worker() == Some(*owner) => {
+                job(databases)
+            }
+            Access::Actor(sender) | Access::Worker(_, _, sender) => {
+                let (reply_sender, reply_receiver) = oneshot::channel();
+                // A job that panics mustn't take the actor, and every other connection's jobs,
+                // down with it. The panic is the caller's instead, contained like any other.
+                let job: Job = Box::new(move |databases| {
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| job(databases)));
+                    let _ = reply_sender.send(result);
+                });
+                if sender.send(job).await.is_err() {
+                    panic!("Keyspace actor has shut down");
+                }
+                let result = reply_receiver
+                    .await
+                    .expect("Keyspace actor dropped a job without replying");
+                match result {
+                    Ok(x) => x,
+                    Err(payload) => panic::resume_unwind(payload),
+                }
+            }
+        }
+    }
+
+    // A point-in-time view of every database, indexed by number
+    pub async fn snapshot(&self) -> Vec<Snapshot> {
+        self.run_all(|databases| databases.iter().map(Store::snapshot).collect())
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_panicking_job_leaves_the_actor_and_its_shards_usable() {
+        let keyspace = Keyspace::new(vec![Store::new()], KeyspaceMode::Actor, &[]);
+        let panicking = keyspace.clone();
+        let crashed = tokio::spawn(async move {
+            panicking
+                .run(|db| {
+                    let _shard = db.write("key");
+                    panic!("Panicked while holding the shard");
+                })
+                .await
+        });
+        assert!(crashed.await.unwrap_err().is_panic());
+        assert_eq!(keyspace.run(|db| db.read("key").len()).await, 0);
+    }
+}
@@ -0,0 +1,182 @@
+use super::command_table;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Latencies below this many microseconds get a bucket each. Above it, every power of two is split
+// into SUB_BUCKETS buckets of equal width, which keeps percentiles within about 12% while a
+// histogram stays a fixed few kilobytes.
+const LINEAR_BUCKETS: usize = 16;
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const BUCKETS: usize = LINEAR_BUCKETS + (64 - 4) * SUB_BUCKETS;
+
+// How long a command takes to run, in microseconds. Recording never blocks, as every command
+// does it.
+pub struct Histogram {
+    calls: AtomicU64,
+    buckets: Box<[AtomicU64]>,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            calls: AtomicU64::new(0),
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&self, usec: u64) {
+        self.buckets[bucket_of(usec)].fetch_add(1, Ordering::Relaxed);
+        self.calls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn calls(&self) -> u64 {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    // The latency `percentile` percent of calls took at most, as the top of the bucket it falls
+    // in. 0 before any calls.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let counts = self.counts();
+        let calls: u64 = counts.iter().sum();
+        if calls == 0 {
+            return 0;
+        }
+        let target = ((percentile / 100.0 * calls as f64).ceil() as u64).clamp(1, calls);
+        let mut seen = 0;
+        for (bucket, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return bucket_high(bucket);
+            }
+        }
+        bucket_high(BUCKETS - 1)
+    }
+
+    // How many calls took at most 1, 2, 4... microseconds, up to the power of two that covers
+    // every call, like LATENCY HISTOGRAM reports
+    pub fn cumulative(&self) -> Vec<(u64, u64)> {
+        let counts = self.counts();
+        let calls: u64 = counts.iter().sum();
+        let mut cumulative = Vec::new();
+        let (mut bucket, mut seen) = (0, 0);
+        for boundary in (0..64).map(|x| 1u64 << x) {
+            if seen == calls {
+                break;
+            }
+            while bucket < BUCKETS && bucket_high(bucket) <= boundary {
+                seen += counts[bucket];
+                bucket += 1;
+            }
+            cumulative.push((boundary, seen));
+        }
+        cumulative
+    }
+
+    fn counts(&self) -> Vec<u64> {
+        self.buckets
+            .iter()
+            .map(|x| x.load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+fn bucket_of(usec: u64) -> usize {
+    if usec < LINEAR_BUCKETS as u64 {
+        return usec as usize;
+    }
+    let exponent = 63 - usec.leading_zeros();
+    let sub_bucket = (usec >> (exponent - SUB_BUCKET_BITS)) as usize & (SUB_BUCKETS - 1);
+    LINEAR_BUCKETS + (exponent as usize - 4) * SUB_BUCKETS + sub_bucket
+}
+
+// The highest latency that lands in `bucket`
+fn bucket_high(bucket: usize) -> u64 {
+    if bucket < LINEAR_BUCKETS {
+        return bucket as u64;
+    }
+    let exponent = ((bucket - LINEAR_BUCKETS) / SUB_BUCKETS + 4) as u32;
+    let sub_bucket = ((bucket - LINEAR_BUCKETS) % SUB_BUCKETS) as u64;
+    let width = 1u64 << (exponent - SUB_BUCKET_BITS);
+    ((SUB_BUCKETS as u64 + sub_bucket) * width).saturating_add(width - 1)
+}
+
+// A histogram per command in the command table, for LATENCY HISTOGRAM and INFO latencystats
+pub struct LatencyStats {
+    commands: Vec<(&'static str, Histogram)>,
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        LatencyStats {
+            commands: command_table::all()
+                .iter()
+                .map(|x| (x.name, Histogram::default()))
+                .collect(),
+        }
+    }
+}
+
+impl LatencyStats {
+    pub fn record(&self, command: &str, duration: Duration) {
+        if let Some((_, histogram)) = self.commands.iter().find(|(x, _)| *x == command) {
+            histogram.record(duration.as_micros() as u64);
+        }
+    }
+
+    // Every command that has been called, by name. Those named in `filter` only, if it isn't
+    // empty, where naming a container like CONFIG covers its subcommands.
+    pub fn called(&self, filter: &[String]) -> Vec<(&'static str, &Histogram)> {
+        self.commands
+            .iter()
+            .filter(|(_, histogram)| histogram.calls() > 0)
+            .filter(|(name, _)| {
+                let container = name.split('|').next().unwrap_or(name);
+                filter.is_empty()
+                    || filter
+                        .iter()
+                        .any(|x| x.eq_ignore_ascii_case(name) || x.eq_ignore_ascii_case(container))
+            })
+            .map(|(name, histogram)| (*name, histogram))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_and_cumulative_counts_follow_the_recorded_latencies() {
+        for usec in [0, 1, 15, 16, 17, 100, 1000, 123_456, u64::MAX] {
+            let bucket = bucket_of(usec);
+            assert!(bucket_high(bucket) >= usec, "{} in bucket {}", usec, bucket);
+            assert!(bucket == 0 || bucket_high(bucket - 1) < usec);
+        }
+
+        let histogram = Histogram::default();
+        assert_eq!(histogram.percentile(50.0), 0);
+        assert!(histogram.cumulative().is_empty());
+        for _ in 0..90 {
+            histogram.record(3);
+        }
+        for _ in 0..10 {
+            histogram.record(1000);
+        }
+        assert_eq!(histogram.calls(), 100);
+        assert_eq!(histogram.percentile(50.0), 3);
+        assert_eq!(histogram.percentile(90.0), 3);
+        // Within a bucket's width of the real value
+        let p99 = histogram.percentile(99.0);
+        assert!((1000..1100).contains(&p99), "{}", p99);
+        assert_eq!(histogram.percentile(100.0), p99);
+
+        let cumulative = histogram.cumulative();
+        assert_eq!(cumulative[..3], [(1, 0), (2, 0), (4, 90)]);
+        assert_eq!(cumulative.last(), Some(&(1024, 100)));
+        assert_eq!(cumulative[cumulative.len() - 2], (512, 90));
+    }
+}
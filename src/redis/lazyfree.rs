@@ -0,0 +1,95 @@
+use super::store::Entry;
+use super::value::Value;
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, LazyLock};
+use std::thread;
+
+// Values made of more allocations than this are worth handing to the background thread, as in
+// Redis. Anything smaller is cheaper to free on the spot.
+const LAZYFREE_THRESHOLD: usize = 64;
+
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+static FREED: AtomicU64 = AtomicU64::new(0);
+
+// Deleted entries are dropped on this thread, so freeing a huge collection doesn't stall the
+// connection that deleted it
+static RECLAIMER: LazyLock<Sender<Arc<Entry>>> = LazyLock::new(|| {
+    let (sender, receiver) = mpsc::channel::<Arc<Entry>>();
+    thread::Builder::new()
+        .name(String::from("lazyfree"))
+        .spawn(move || {
+            for entry in receiver {
+                drop(entry);
+                PENDING.fetch_sub(1, Ordering::Relaxed);
+                FREED.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+        .expect("Failed to spawn the lazyfree thread");
+    sender
+});
+
+// Frees a deleted entry, on the background thread if `lazy` is set and the entry is big enough
+// for it to matter. Which kinds of deletion are lazy is up to the lazyfree-lazy-* options.
+pub fn free(entry: Arc<Entry>, lazy: bool) {
+    if !lazy || free_effort(&entry.value) <= LAZYFREE_THRESHOLD {
+        return;
+    }
+    PENDING.fetch_add(1, Ordering::Relaxed);
+    if let Err(mpsc::SendError(entry)) = RECLAIMER.send(entry) {
+        PENDING.fetch_sub(1, Ordering::Relaxed);
+        drop(entry);
+    }
+}
+
+// Entries waiting on the background thread, reported as lazyfree_pending_objects
+pub fn pending() -> usize {
+    PENDING.load(Ordering::Relaxed)
+}
+
+// Entries the background thread has freed, reported as lazyfreed_objects
+pub fn freed() -> u64 {
+    FREED.load(Ordering::Relaxed)
+}
+
+// Roughly how many allocations freeing the value takes
+fn free_effort(value: &Value) -> usize {
+    match value {
+        Value::Str(_) => 1,
+        Value::List(x) => x.len(),
+        Value::Hash(x) => x.len(),
+        Value::Set(x) => x.len(),
+        Value::ZSet(x) => x.len(),
+        Value::Stream(x) => x.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::collections::VecDeque;
+    use std::time::{Duration, Instant};
+
+    fn list(len: usize) -> Arc<Entry> {
+        let elements: VecDeque<Bytes> = (0..len).map(|x| Bytes::from(x.to_string())).collect();
+        Arc::new(Entry::new(Value::List(elements)))
+    }
+
+    #[test]
+    fn only_big_values_are_freed_in_the_background() {
+        let before = freed();
+        free(list(LAZYFREE_THRESHOLD), true);
+        free(list(1000), false);
+        assert_eq!(pending() as u64 + freed(), before);
+
+        free(list(1000), true);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while freed() != before + 1 {
+            assert!(Instant::now() < deadline, "Entry was never freed");
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(pending(), 0);
+    }
+}
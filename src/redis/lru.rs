@@ -0,0 +1,101 @@
+use super::clock::monotonic_ms;
+use super::store::random_u64;
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// Access tracking for the LRU and LFU eviction policies.
+//
+// Like Redis, access times are kept as a 24 bit clock with one second resolution, which wraps
+// roughly every 194 days and is cheap to store on every entry
+pub const LRU_CLOCK_MAX: u32 = (1 << 24) - 1;
+pub const LRU_CLOCK_RESOLUTION_MS: u64 = 1000;
+
+// The cron refreshes this every tick so that stamping an access is a single atomic load. Until
+// it first runs, the clock is computed on every call.
+static CACHED_LRU_CLOCK: AtomicU32 = AtomicU32::new(u32::MAX);
+
+pub fn lru_clock() -> u32 {
+    match CACHED_LRU_CLOCK.load(Ordering::Relaxed) {
+        u32::MAX => compute_lru_clock(),
+        x => x,
+    }
+}
+
+pub fn update_lru_clock() {
+    CACHED_LRU_CLOCK.store(compute_lru_clock(), Ordering::Relaxed);
+}
+
+fn compute_lru_clock() -> u32 {
+    ((monotonic_ms() / LRU_CLOCK_RESOLUTION_MS) as u32) & LRU_CLOCK_MAX
+}
+
+// Milliseconds since `lru` was stamped, accounting for the clock having wrapped
+pub fn estimate_idle_ms(lru: u32) -> u64 {
+    let now = lru_clock();
+    let ticks = if now >= lru {
+        now - lru
+    } else {
+        (LRU_CLOCK_MAX - lru) + now
+    };
+    ticks as u64 * LRU_CLOCK_RESOLUTION_MS
+}
+
+// LFU counters are 8 bit and grow logarithmically, so that a counter near 255 takes millions of
+// accesses. They halve in relevance as they age, by one step per `lfu-decay-time` minutes.
+pub const LFU_INIT_VAL: u8 = 5;
+static LFU_LOG_FACTOR: AtomicU32 = AtomicU32::new(10);
+static LFU_DECAY_TIME: AtomicU32 = AtomicU32::new(1);
+
+pub fn set_lfu_params(log_factor: u32, decay_time: u32) {
+    LFU_LOG_FACTOR.store(log_factor, Ordering::Relaxed);
+    LFU_DECAY_TIME.store(decay_time, Ordering::Relaxed);
+}
+
+// The LFU state packs the minute of the last decrement into the high 16 bits and the counter
+// into the low 8
+pub fn lfu_initial() -> u32 {
+    (lfu_minutes() << 8) | LFU_INIT_VAL as u32
+}
+
+// Applies any pending decay and a probabilistic increment, returning the new state
+pub fn lfu_access(state: u32) -> u32 {
+    let counter = lfu_log_incr(lfu_decayed_counter(state));
+    (lfu_minutes() << 8) | counter as u32
+}
+
+// The counter with decay applied for the time since it was last updated
+pub fn lfu_decayed_counter(state: u32) -> u8 {
+    let counter = (state & 255) as u8;
+    let decay_time = LFU_DECAY_TIME.load(Ordering::Relaxed);
+    if decay_time == 0 {
+        return counter;
+    }
+    let periods = lfu_elapsed_minutes(state >> 8) / decay_time;
+    counter.saturating_sub(periods.min(255) as u8)
+}
+
+fn lfu_log_incr(counter: u8) -> u8 {
+    if counter == 255 {
+        return counter;
+    }
+    let baseval = counter.saturating_sub(LFU_INIT_VAL) as f64;
+    let probability = 1.0 / (baseval * LFU_LOG_FACTOR.load(Ordering::Relaxed) as f64 + 1.0);
+    let roll = random_u64() as f64 / u64::MAX as f64;
+    if roll < probability {
+        return counter + 1;
+    }
+    counter
+}
+
+fn lfu_minutes() -> u32 {
+    ((monotonic_ms() / 60_000) & 65535) as u32
+}
+
+fn lfu_elapsed_minutes(last_minutes: u32) -> u32 {
+    let now = lfu_minutes();
+    if now >= last_minutes {
+        now - last_minutes
+    } else {
+        65535 - last_minutes + now
+    }
+}
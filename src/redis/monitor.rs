@@ -0,0 +1,166 @@
+use super::clock;
+use super::commands::Command;
+use super::state::ClientContext;
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+// The connections that ran MONITOR, each sent a line for every command any other client runs
+#[derive(Default)]
+pub struct Monitors {
+    attached: Mutex<HashMap<u64, UnboundedSender<Bytes>>>,
+    // How many are attached, so that commands don't take the lock when nobody's watching
+    count: AtomicUsize,
+}
+
+impl Monitors {
+    // Running MONITOR again changes nothing
+    pub fn attach(&self, client: u64, monitoring: &mut Monitoring) {
+        if monitoring.is_active() {
+            return;
+        }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut attached = self.attached.lock().unwrap();
+        attached.insert(client, sender);
+        self.count.store(attached.len(), Ordering::Relaxed);
+        monitoring.feed = Some(receiver);
+    }
+
+    // Sends `command` to every monitor but the client running it, as a line like
+    // +1339518083.107412 [0 127.0.0.1:60866] "SET" "key" "value". Monitors that have gone away
+    // are dropped along the way.
+    pub fn feed(&self, client: &ClientContext, command: &Command) {
+        if self.count.load(Ordering::Relaxed) == 0 || command.is_admin() {
+            return;
+        }
+        let now = clock::unix_us();
+        let mut line = format!(
+            "+{}.{:06} [{} {}]",
+            now / 1_000_000,
+            now % 1_000_000,
+            client.db,
+            client.addr.as_deref().unwrap_or("local")
+        );
+        for arg in redacted_args(command) {
+            line.push(' ');
+            line.push_str(&quote(&arg));
+        }
+        line.push_str("\r\n");
+        let line = Bytes::from(line);
+        let mut attached = self.attached.lock().unwrap();
+        attached.retain(|id, sender| *id == client.id || sender.send(line.clone()).is_ok());
+        self.count.store(attached.len(), Ordering::Relaxed);
+    }
+
+    // For clients that are going away
+    pub fn detach(&self, client: u64) {
+        let mut attached = self.attached.lock().unwrap();
+        attached.remove(&client);
+        self.count.store(attached.len(), Ordering::Relaxed);
+    }
+}
+
+// A client's side of MONITOR: the queue its lines wait in until it gets to write them out
+#[derive(Default)]
+pub struct Monitoring {
+    // Made by MONITOR
+    feed: Option<UnboundedReceiver<Bytes>>,
+}
+
+impl Monitoring {
+    pub fn is_active(&self) -> bool {
+        self.feed.is_some()
+    }
+
+    // Resolves with the next line for the client. Never resolves for clients that aren't
+    // monitoring.
+    pub async fn next_line(&mut self) -> Bytes {
+        match &mut self.feed {
+            Some(receiver) => receiver
+                .recv()
+                .await
+                .expect("Expected monitors to be detached before they're dropped"),
+            None => future::pending().await,
+        }
+    }
+}
+
+// Passwords never show up, like in Redis
+fn redacted_args(command: &Command) -> Vec<Bytes> {
+    let mut args = command.to_args();
+    let redacted = match command {
+        Command::Auth(_, _) => 1..args.len(),
+        Command::Hello(_, Some(_)) => args.len() - 2..args.len(),
+        _ => 0..0,
+    };
+    for arg in &mut args[redacted] {
+        *arg = Bytes::from("(redacted)");
+    }
+    args
+}
+
+// Quotes an argument the way Redis's sdscatrepr does, escaping anything unprintable
+fn quote(arg: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for byte in arg {
+        match byte {
+            b'\\' => quoted.push_str("\\\\"),
+            b'"' => quoted.push_str("\\\""),
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            0x07 => quoted.push_str("\\a"),
+            0x08 => quoted.push_str("\\b"),
+            x if x.is_ascii_graphic() || *x == b' ' => quoted.push(*x as char),
+            x => quoted.push_str(&format!("\\x{:02x}", x)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn commands_reach_every_other_monitor() {
+        let monitors = Monitors::default();
+        let (mut first, mut second) = (Monitoring::default(), Monitoring::default());
+        monitors.attach(1, &mut first);
+        monitors.attach(2, &mut second);
+        assert!(first.is_active());
+
+        let mut client = ClientContext::new(1);
+        client.db = 3;
+        client.addr = Some(String::from("127.0.0.1:6000"));
+        let set = Command::Append(String::from("k\"ey"), Bytes::from_static(b"a b\n\x01"));
+        monitors.feed(&client, &set);
+        let line = second.next_line().await;
+        let (time, rest) = line.split_at(line.iter().position(|x| *x == b' ').unwrap());
+        assert!(time.starts_with(b"+") && time.contains(&b'.'));
+        assert_eq!(
+            rest,
+            b" [3 127.0.0.1:6000] \"APPEND\" \"k\\\"ey\" \"a b\\n\\x01\"\r\n"
+        );
+
+        monitors.feed(&client, &Command::Auth(None, String::from("secret")));
+        assert!(second
+            .next_line()
+            .await
+            .ends_with(b"\"AUTH\" \"(redacted)\"\r\n"));
+        monitors.feed(&client, &Command::ConfigGet(vec![String::from("*")]));
+
+        // Monitors that went away are forgotten on the next command
+        drop(second);
+        monitors.feed(&ClientContext::new(3), &Command::Ping);
+        assert!(first.next_line().await.ends_with(b"\"PING\"\r\n"));
+        assert_eq!(monitors.attached.lock().unwrap().len(), 1);
+        monitors.detach(1);
+        assert_eq!(monitors.count.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,186 @@
+use super::pubsub::PubSub;
+
+use bytes::Bytes;
+use std::fmt;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Mutex;
+
+// The notify-keyspace-events flags: which classes of event are published, and on which of the
+// two channels
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct NotifyFlags(u16);
+
+impl NotifyFlags {
+    pub const KEYSPACE: Self = Self(1 << 0);
+    pub const KEYEVENT: Self = Self(1 << 1);
+    pub const GENERIC: Self = Self(1 << 2);
+    pub const STRING: Self = Self(1 << 3);
+    pub const LIST: Self = Self(1 << 4);
+    pub const SET: Self = Self(1 << 5);
+    pub const HASH: Self = Self(1 << 6);
+    pub const ZSET: Self = Self(1 << 7);
+    pub const EXPIRED: Self = Self(1 << 8);
+    pub const EVICTED: Self = Self(1 << 9);
+    pub const STREAM: Self = Self(1 << 10);
+    // What A stands for
+    const ALL: Self = Self(0b111_1111_1100);
+
+    const LETTERS: [(char, Self); 11] = [
+        ('g', Self::GENERIC),
+        ('$', Self::STRING),
+        ('l', Self::LIST),
+        ('s', Self::SET),
+        ('h', Self::HASH),
+        ('z', Self::ZSET),
+        ('x', Self::EXPIRED),
+        ('e', Self::EVICTED),
+        ('t', Self::STREAM),
+        ('K', Self::KEYSPACE),
+        ('E', Self::KEYEVENT),
+    ];
+
+    // Parses flags the way Redis does, e.g. "KEA" or "Elg". None for unknown letters.
+    pub fn parse(flags: &str) -> Option<Self> {
+        let mut parsed = Self::default();
+        for letter in flags.chars() {
+            let flag = match letter {
+                'A' => Self::ALL,
+                x => Self::LETTERS.iter().find(|(y, _)| *y == x)?.1,
+            };
+            parsed.0 |= flag.0;
+        }
+        Some(parsed)
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    // Nothing's published without a class of event and a channel to publish it on
+    pub fn is_on(self) -> bool {
+        self.0 & Self::ALL.0 != 0 && self.0 & (Self::KEYSPACE.0 | Self::KEYEVENT.0) != 0
+    }
+}
+
+// Classes first, as A when they're all on, then the channels, like CONFIG GET in Redis
+impl fmt::Display for NotifyFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (classes, channels) = Self::LETTERS.split_at(9);
+        if self.contains(Self::ALL) {
+            write!(f, "A")?;
+        } else {
+            for (letter, flag) in classes {
+                if self.contains(*flag) {
+                    write!(f, "{}", letter)?;
+                }
+            }
+        }
+        for (letter, flag) in channels {
+            if self.contains(*flag) {
+                write!(f, "{}", letter)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Something that happened to a key, e.g. ("lpush", "mylist")
+#[derive(Debug, PartialEq)]
+pub struct KeyEvent {
+    pub class: NotifyFlags,
+    pub event: &'static str,
+    pub key: String,
+}
+
+// A database's events waiting to be published. Handlers can't publish themselves since they
+// don't know the database's index, so the dispatcher does it once the command has run.
+#[derive(Default)]
+pub struct Notifications {
+    flags: AtomicU16,
+    pending: Mutex<Vec<KeyEvent>>,
+}
+
+impl Notifications {
+    pub fn set_flags(&self, flags: NotifyFlags) {
+        self.flags.store(flags.0, Ordering::Relaxed);
+    }
+
+    // Events of classes that aren't published aren't kept either
+    pub fn notify(&self, class: NotifyFlags, event: &'static str, key: &str) {
+        let flags = NotifyFlags(self.flags.load(Ordering::Relaxed));
+        if flags.is_on() && flags.contains(class) {
+            self.pending.lock().unwrap().push(KeyEvent {
+                class,
+                event,
+                key: key.to_string(),
+            });
+        }
+    }
+
+    pub fn take(&self) -> Vec<KeyEvent> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+}
+
+// Publishes `event` for `key` to __keyspace@<db>__:<key> and __keyevent@<db>__:<event>, as
+// `flags` asks
+pub fn publish(
+    pubsub: &PubSub,
+    flags: NotifyFlags,
+    class: NotifyFlags,
+    db: usize,
+    event: &str,
+    key: &str,
+) {
+    if !flags.is_on() || !flags.contains(class) {
+        return;
+    }
+    if flags.contains(NotifyFlags::KEYSPACE) {
+        let channel = Bytes::from(format!("__keyspace@{}__:{}", db, key));
+        pubsub.publish(&channel, &Bytes::copy_from_slice(event.as_bytes()));
+    }
+    if flags.contains(NotifyFlags::KEYEVENT) {
+        let channel = Bytes::from(format!("__keyevent@{}__:{}", db, event));
+        pubsub.publish(&channel, &Bytes::copy_from_slice(key.as_bytes()));
+    }
+}
+
+// Publishes the events a command left in database `db`'s Notifications
+pub fn publish_all(pubsub: &PubSub, flags: NotifyFlags, db: usize, events: Vec<KeyEvent>) {
+    for KeyEvent { class, event, key } in events {
+        publish(pubsub, flags, class, db, event, &key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_are_parsed_and_printed_like_redis() {
+        let parse = |x| NotifyFlags::parse(x).unwrap();
+        assert_eq!(parse("").to_string(), "");
+        assert_eq!(parse("KEA").to_string(), "AKE");
+        assert_eq!(parse("EKlg$").to_string(), "g$lKE");
+        assert_eq!(parse("g$lshzxetK"), parse("AK"));
+        assert_eq!(NotifyFlags::parse("Km"), None);
+        assert!(!parse("A").is_on());
+        assert!(!parse("KE").is_on());
+        assert!(parse("Kx").is_on());
+
+        let notifications = Notifications::default();
+        notifications.notify(NotifyFlags::LIST, "lpush", "list");
+        notifications.set_flags(parse("El"));
+        notifications.notify(NotifyFlags::LIST, "lpush", "list");
+        notifications.notify(NotifyFlags::STRING, "set", "string");
+        assert_eq!(
+            notifications.take(),
+            vec![KeyEvent {
+                class: NotifyFlags::LIST,
+                event: "lpush",
+                key: String::from("list")
+            }]
+        );
+        assert!(notifications.take().is_empty());
+    }
+}
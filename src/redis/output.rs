@@ -0,0 +1,397 @@
+use super::connection::Socket;
+use bytes::{Buf, Bytes, BytesMut};
+use core::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::config::parse_memory;
+use crate::resp::resp_serializer::serialize_resp_data;
+use crate::resp::RespType;
+
+// Bulk string replies at least this long are written straight from the stored value, a chunk at
+// a time, rather than copied into the serialized reply
+pub const STREAMED_BULK_LENGTH: usize = 64 * 1024;
+const STREAMED_CHUNK_SIZE: usize = 64 * 1024;
+
+// The reply to a command, ready to be written to the connection
+#[derive(Debug)]
+pub enum Reply {
+    Serialized(Vec<u8>),
+    // The contents of a large bulk string, sharing the stored value's allocation
+    Bulk(Bytes),
+}
+
+impl Reply {
+    pub fn bulk(data: Bytes) -> Self {
+        if data.len() >= STREAMED_BULK_LENGTH {
+            return Reply::Bulk(data);
+        }
+        Reply::Serialized(serialize_resp_data(RespType::BulkString(Some(data))))
+    }
+
+    // The reply serialized in full, for callers that aren't writing to a socket
+    pub fn into_vec(self) -> Vec<u8> {
+        match self {
+            Reply::Serialized(x) => x,
+            Reply::Bulk(x) => serialize_resp_data(RespType::BulkString(Some(x))),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Reply {
+    fn from(serialized: Vec<u8>) -> Self {
+        Reply::Serialized(serialized)
+    }
+}
+
+// Connections are limited according to what they're used for, as with Redis's
+// client-output-buffer-limit classes
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ClientClass {
+    Normal,
+    Replica,
+    Pubsub,
+}
+
+impl ClientClass {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "normal" => Some(ClientClass::Normal),
+            // Older Redis versions call replicas slaves
+            "replica" | "slave" => Some(ClientClass::Replica),
+            "pubsub" => Some(ClientClass::Pubsub),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ClientClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientClass::Normal => write!(f, "normal"),
+            ClientClass::Replica => write!(f, "replica"),
+            ClientClass::Pubsub => write!(f, "pubsub"),
+        }
+    }
+}
+
+// A connection is closed as soon as its pending output goes over `hard`, or once it has stayed
+// over `soft` for `soft_seconds`. Zero disables a limit.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct OutputBufferLimit {
+    pub hard: usize,
+    pub soft: usize,
+    pub soft_seconds: u64,
+}
+
+impl OutputBufferLimit {
+    // Parses the "<hard> <soft> <soft seconds>" part of a client-output-buffer-limit
+    pub fn parse(hard: &str, soft: &str, soft_seconds: &str) -> Option<Self> {
+        Some(OutputBufferLimit {
+            hard: parse_memory(hard)?,
+            soft: parse_memory(soft)?,
+            soft_seconds: soft_seconds.parse().ok()?,
+        })
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct OutputBufferLimits {
+    pub normal: OutputBufferLimit,
+    pub replica: OutputBufferLimit,
+    pub pubsub: OutputBufferLimit,
+}
+
+impl OutputBufferLimits {
+    pub fn set(&mut self, class: ClientClass, limit: OutputBufferLimit) {
+        match class {
+            ClientClass::Normal => self.normal = limit,
+            ClientClass::Replica => self.replica = limit,
+            ClientClass::Pubsub => self.pubsub = limit,
+        }
+    }
+
+    pub fn get(&self, class: ClientClass) -> OutputBufferLimit {
+        match class {
+            ClientClass::Normal => self.normal,
+            ClientClass::Replica => self.replica,
+            ClientClass::Pubsub => self.pubsub,
+        }
+    }
+}
+
+// Redis's defaults
+impl Default for OutputBufferLimits {
+    fn default() -> Self {
+        OutputBufferLimits {
+            normal: OutputBufferLimit {
+                hard: 0,
+                soft: 0,
+                soft_seconds: 0,
+            },
+            replica: OutputBufferLimit {
+                hard: 256 * 1024 * 1024,
+                soft: 64 * 1024 * 1024,
+                soft_seconds: 60,
+            },
+            pubsub: OutputBufferLimit {
+                hard: 32 * 1024 * 1024,
+                soft: 8 * 1024 * 1024,
+                soft_seconds: 60,
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum OutputError {
+    Io(io::Error),
+    // The consumer has fallen too far behind, the connection should be closed
+    LimitReached(String),
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputError::Io(e) => write!(f, "{}", e),
+            OutputError::LimitReached(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+// Checks how much output a consumer has pending against the limits for its class
+pub struct OutputLimiter {
+    class: ClientClass,
+    limit: OutputBufferLimit,
+    // When the pending output went over the soft limit, if it hasn't dropped back under since
+    soft_limit_reached_at: Option<Instant>,
+}
+
+impl OutputLimiter {
+    pub fn new(class: ClientClass, limit: OutputBufferLimit) -> Self {
+        OutputLimiter {
+            class,
+            limit,
+            soft_limit_reached_at: None,
+        }
+    }
+
+    pub fn set_class(&mut self, class: ClientClass, limit: OutputBufferLimit) {
+        self.class = class;
+        self.limit = limit;
+    }
+
+    // Whether the consumer is behind enough to be on the clock for the soft limit
+    pub fn is_backlogged(&self) -> bool {
+        self.soft_limit_reached_at.is_some()
+    }
+
+    // Fails once `pending` bytes are too many for the consumer to keep its connection
+    pub fn check(&mut self, pending: usize) -> Result<(), OutputError> {
+        if self.limit.hard > 0 && pending > self.limit.hard {
+            return Err(self.limit_reached("hard", pending));
+        }
+        if self.limit.soft == 0 || pending <= self.limit.soft {
+            self.soft_limit_reached_at = None;
+            return Ok(());
+        }
+        let reached_at = *self.soft_limit_reached_at.get_or_insert_with(Instant::now);
+        if reached_at.elapsed() >= Duration::from_secs(self.limit.soft_seconds) {
+            return Err(self.limit_reached("soft", pending));
+        }
+        Ok(())
+    }
+
+    // When the soft limit runs out, if the consumer is over it
+    fn soft_deadline(&self) -> Option<Instant> {
+        self.soft_limit_reached_at
+            .map(|x| x + Duration::from_secs(self.limit.soft_seconds))
+    }
+
+    fn limit_reached(&self, kind: &str, pending: usize) -> OutputError {
+        OutputError::LimitReached(format!(
+            "{} output buffer {} limit reached with {} bytes pending",
+            self.class, kind, pending
+        ))
+    }
+}
+
+// Output waiting to be written to a connection. Writes never block: whatever the socket won't
+// take right away stays here until the next write or flush, so one slow consumer can't hold up
+// everybody else.
+pub struct OutputBuffer {
+    limiter: OutputLimiter,
+    pending: BytesMut,
+}
+
+impl OutputBuffer {
+    // ----------------- Public ------------------
+    // |                                         |
+    // -------------------------------------------
+
+    pub fn new(class: ClientClass, limit: OutputBufferLimit) -> Self {
+        OutputBuffer {
+            limiter: OutputLimiter::new(class, limit),
+            pending: BytesMut::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    // For connections that start or stop being subscribers, which Redis limits separately
+    pub fn set_class(&mut self, class: ClientClass, limit: OutputBufferLimit) {
+        self.limiter.set_class(class, limit);
+    }
+
+    pub fn is_backlogged(&self) -> bool {
+        self.limiter.is_backlogged()
+    }
+
+    // Queues `data` behind anything still pending, then writes as much as the socket will take
+    pub fn write(&mut self, stream: &impl Socket, data: &[u8]) -> Result<(), OutputError> {
+        self.pending.extend_from_slice(data);
+        self.check_limits()?;
+        self.flush(stream)
+    }
+
+    // Writes as much pending output as the socket will take without blocking
+    pub fn flush(&mut self, stream: &impl Socket) -> Result<(), OutputError> {
+        while !self.pending.is_empty() {
+            match stream.try_write(&self.pending) {
+                Ok(0) => return Err(OutputError::Io(io::Error::from(io::ErrorKind::WriteZero))),
+                Ok(n) => self.pending.advance(n),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(OutputError::Io(e)),
+            }
+        }
+        self.check_limits()
+    }
+
+    // Like flush, but waits until everything pending has been written, giving up if the limits
+    // are reached in the meantime
+    pub async fn flush_all(&mut self, stream: &impl Socket) -> Result<(), OutputError> {
+        self.flush(stream)?;
+        while !self.pending.is_empty() {
+            let soft_deadline = self
+                .limiter
+                .soft_deadline()
+                .unwrap_or_else(|| Instant::now() + Duration::from_secs(1));
+            let writable = tokio::time::timeout_at(soft_deadline.into(), stream.writable());
+            if let Ok(Err(e)) = writable.await {
+                return Err(OutputError::Io(e));
+            }
+            self.flush(stream)?;
+        }
+        Ok(())
+    }
+
+    // Writes `data` a chunk at a time, waiting for each chunk to be taken by the socket before
+    // queueing the next, so that at most a chunk of it is ever copied into the buffer
+    pub async fn write_streamed(
+        &mut self,
+        stream: &impl Socket,
+        data: &[u8],
+    ) -> Result<(), OutputError> {
+        for chunk in data.chunks(STREAMED_CHUNK_SIZE) {
+            self.write(stream, chunk)?;
+            self.flush_all(stream).await?;
+        }
+        Ok(())
+    }
+
+    // ----------------- Private -----------------
+    // |                                         |
+    // -------------------------------------------
+
+    fn check_limits(&mut self) -> Result<(), OutputError> {
+        self.limiter.check(self.pending.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (server, client)
+    }
+
+    fn limit(hard: usize, soft: usize, soft_seconds: u64) -> OutputBufferLimit {
+        OutputBufferLimit {
+            hard,
+            soft,
+            soft_seconds,
+        }
+    }
+
+    #[test]
+    fn limits_are_parsed_like_redis_config() {
+        assert_eq!(
+            OutputBufferLimit::parse("256mb", "64mb", "60"),
+            Some(limit(256 * 1024 * 1024, 64 * 1024 * 1024, 60))
+        );
+        assert_eq!(OutputBufferLimit::parse("1gb", "x", "60"), None);
+        assert_eq!(ClientClass::parse("slave"), Some(ClientClass::Replica));
+    }
+
+    #[tokio::test]
+    async fn output_reaches_a_consumer_that_keeps_up() {
+        let (server, mut client) = connected_pair().await;
+        let mut output = OutputBuffer::new(ClientClass::Replica, limit(1024, 512, 60));
+        output.write(&server, b"+OK\r\n").unwrap();
+        output.flush_all(&server).await.unwrap();
+        let mut received = [0; 5];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"+OK\r\n");
+    }
+
+    #[tokio::test]
+    async fn streamed_output_arrives_whole() {
+        let (server, mut client) = connected_pair().await;
+        let mut output = OutputBuffer::new(ClientClass::Normal, limit(0, 0, 0));
+        let data: Vec<u8> = (0..STREAMED_CHUNK_SIZE * 3 + 5).map(|x| x as u8).collect();
+        let reader = tokio::spawn(async move {
+            let mut received = vec![0; STREAMED_CHUNK_SIZE * 3 + 5];
+            client.read_exact(&mut received).await.unwrap();
+            received
+        });
+        output.write_streamed(&server, &data).await.unwrap();
+        assert!(output.is_empty());
+        assert_eq!(reader.await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn a_consumer_that_stops_reading_hits_the_hard_limit() {
+        let (server, _client) = connected_pair().await;
+        let mut output = OutputBuffer::new(ClientClass::Replica, limit(1024 * 1024, 0, 0));
+        let chunk = vec![b'x'; 64 * 1024];
+        // The socket buffers take some of it, but not megabytes
+        let result = (0..1024).try_for_each(|_| output.write(&server, &chunk));
+        assert!(matches!(result, Err(OutputError::LimitReached(_))));
+    }
+
+    #[tokio::test]
+    async fn the_soft_limit_allows_a_grace_period() {
+        let (server, _client) = connected_pair().await;
+        let mut output = OutputBuffer::new(ClientClass::Pubsub, limit(0, 1, 60));
+        let chunk = vec![b'x'; 64 * 1024];
+        for _ in 0..256 {
+            output.write(&server, &chunk).unwrap();
+        }
+        assert!(!output.is_empty());
+        assert!(output.is_backlogged());
+
+        let mut output = OutputBuffer::new(ClientClass::Pubsub, limit(0, 1, 0));
+        let result = (0..256).try_for_each(|_| output.write(&server, &chunk));
+        assert!(matches!(result, Err(OutputError::LimitReached(_))));
+    }
+}
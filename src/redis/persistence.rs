@@ -0,0 +1,285 @@
+use super::clock::unix_ms;
+use super::commands::args_to_command;
+use super::dispatch::Dispatcher;
+use super::state::{ClientContext, ServerState};
+use super::store::Entry;
+
+use crate::aof;
+use crate::rdb::writer::{write_rdb_file, write_snapshot};
+use crate::rdb::{self, RdbParser};
+use crate::resp::resp_deserializer::parse_frames;
+use crate::resp::RespType;
+use crate::server::ServerError;
+use crate::{notice, warning};
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::task;
+
+pub const BGSAVE_IN_PROGRESS_ERROR: &str = "ERR Background save already in progress";
+pub const AOF_REWRITE_IN_PROGRESS_ERROR: &str =
+    "ERR Background append only file rewriting already in progress";
+// How long autosave waits after a failed background save before trying again, as in Redis
+const BGSAVE_RETRY_DELAY_SECS: u64 = 5;
+
+// Where saving the dataset to disk stands. Times are UNIX seconds.
+pub struct Persistence {
+    // Only one save runs at a time, as they share a temporary file
+    save_lock: Mutex<()>,
+    bgsave_in_progress: AtomicBool,
+    // The last successful save, or when the server started
+    last_save: AtomicU64,
+    last_bgsave_ok: AtomicBool,
+    last_bgsave_try: AtomicU64,
+    aof_rewrite_in_progress: AtomicBool,
+    last_aof_rewrite_ok: AtomicBool,
+}
+
+impl Default for Persistence {
+    fn default() -> Self {
+        Persistence {
+            save_lock: Mutex::new(()),
+            bgsave_in_progress: AtomicBool::new(false),
+            last_save: AtomicU64::new(unix_secs()),
+            last_bgsave_ok: AtomicBool::new(true),
+            last_bgsave_try: AtomicU64::new(0),
+            aof_rewrite_in_progress: AtomicBool::new(false),
+            last_aof_rewrite_ok: AtomicBool::new(true),
+        }
+    }
+}
+
+impl Persistence {
+    pub fn bgsave_in_progress(&self) -> bool {
+        self.bgsave_in_progress.load(Ordering::Relaxed)
+    }
+
+    pub fn last_save(&self) -> u64 {
+        self.last_save.load(Ordering::Relaxed)
+    }
+
+    pub fn last_bgsave_ok(&self) -> bool {
+        self.last_bgsave_ok.load(Ordering::Relaxed)
+    }
+
+    pub fn aof_rewrite_in_progress(&self) -> bool {
+        self.aof_rewrite_in_progress.load(Ordering::Relaxed)
+    }
+
+    pub fn last_aof_rewrite_ok(&self) -> bool {
+        self.last_aof_rewrite_ok.load(Ordering::Relaxed)
+    }
+}
+
+// Writes the dataset to the RDB file, as SAVE does. Writes that land while the file is being
+// written stay counted as unsaved, since the snapshot was taken before them.
+pub async fn save(server: &ServerState) -> io::Result<()> {
+    let _guard = server.persistence.save_lock.lock().await;
+    let dirty = server.stats.dirty.load(Ordering::Relaxed);
+    let snapshot = server.keyspace.snapshot().await;
+    let path = server.config.current().rdb_path();
+    task::spawn_blocking(move || write_rdb_file(&path, &snapshot))
+        .await
+        .map_err(io::Error::other)??;
+    server.stats.dirty.fetch_sub(dirty, Ordering::Relaxed);
+    server
+        .persistence
+        .last_save
+        .store(unix_secs(), Ordering::Relaxed);
+    Ok(())
+}
+
+// Starts saving in the background, as BGSAVE does, unless a background save is already running
+pub fn background_save(server: &Arc<ServerState>) -> Result<(), &'static str> {
+    let persistence = &server.persistence;
+    if persistence.bgsave_in_progress.swap(true, Ordering::Relaxed) {
+        return Err(BGSAVE_IN_PROGRESS_ERROR);
+    }
+    persistence
+        .last_bgsave_try
+        .store(unix_secs(), Ordering::Relaxed);
+    let server = Arc::clone(server);
+    task::spawn(async move {
+        let result = save(&server).await;
+        match &result {
+            Ok(()) => notice!("Background saving terminated with success"),
+            Err(e) => warning!("Background saving error: {}", e),
+        }
+        let persistence = &server.persistence;
+        persistence
+            .last_bgsave_ok
+            .store(result.is_ok(), Ordering::Relaxed);
+        persistence
+            .bgsave_in_progress
+            .store(false, Ordering::Relaxed);
+    });
+    Ok(())
+}
+
+// Starts a background save once any of the configured save points is reached: enough seconds
+// since the last save with enough changes in them. Called from the cron.
+pub fn autosave_if_due(server: &Arc<ServerState>) {
+    let persistence = &server.persistence;
+    if persistence.bgsave_in_progress() {
+        return;
+    }
+    let now = unix_secs();
+    // Failing saves would otherwise be retried on every tick
+    if !persistence.last_bgsave_ok()
+        && now.saturating_sub(persistence.last_bgsave_try.load(Ordering::Relaxed))
+            < BGSAVE_RETRY_DELAY_SECS
+    {
+        return;
+    }
+    let dirty = server.stats.dirty.load(Ordering::Relaxed);
+    let elapsed = now.saturating_sub(persistence.last_save());
+    let config = server.config.current();
+    let due = config
+        .save
+        .iter()
+        .find(|(seconds, changes)| dirty >= *changes && elapsed >= *seconds);
+    if let Some((seconds, changes)) = due {
+        notice!("{} changes in {} seconds. Saving...", changes, seconds);
+        let _ = background_save(server);
+    }
+}
+
+// Compacts the AOF into a snapshot of the dataset, as BGREWRITEAOF does. The snapshot is written
+// as an RDB preamble, and the writes that come in meanwhile are appended after it. Nothing may
+// run while the snapshot is taken, see Dispatcher::dispatch, so that those writes line up with
+// it. With the AOF off, the file is just written from the snapshot.
+pub async fn background_rewrite_aof(server: &Arc<ServerState>) -> Result<(), &'static str> {
+    let persistence = &server.persistence;
+    if persistence
+        .aof_rewrite_in_progress
+        .swap(true, Ordering::Relaxed)
+    {
+        return Err(AOF_REWRITE_IN_PROGRESS_ERROR);
+    }
+    if let Some(writer) = server.aof.get() {
+        writer.start_rewrite();
+    }
+    let snapshot = server.keyspace.snapshot().await;
+    let server = Arc::clone(server);
+    task::spawn(async move {
+        let path = server.config.current().aof_path();
+        let dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+        let temp_path = dir.join(format!("temp-rewriteaof-bg-{}.aof", std::process::id()));
+        let written_path = temp_path.clone();
+        let written = task::spawn_blocking(move || {
+            let file = write_snapshot(BufWriter::new(File::create(&written_path)?), &snapshot)?;
+            file.into_inner().map_err(|e| e.into_error())?.sync_all()
+        })
+        .await
+        .map_err(io::Error::other)
+        .and_then(|x| x);
+        let result = match (written, server.aof.get()) {
+            (Ok(()), Some(writer)) => writer.finish_rewrite(temp_path.clone()).await,
+            (Ok(()), None) => fs::rename(&temp_path, &path),
+            (Err(e), writer) => {
+                if let Some(writer) = writer {
+                    writer.abort_rewrite();
+                }
+                Err(e)
+            }
+        };
+        match &result {
+            Ok(()) => notice!("Background AOF rewrite terminated with success"),
+            Err(e) => {
+                warning!("Background AOF rewrite error: {}", e);
+                let _ = fs::remove_file(&temp_path);
+            }
+        }
+        let persistence = &server.persistence;
+        persistence
+            .last_aof_rewrite_ok
+            .store(result.is_ok(), Ordering::Relaxed);
+        persistence
+            .aof_rewrite_in_progress
+            .store(false, Ordering::Relaxed);
+    });
+    Ok(())
+}
+
+// Reads the AOF at `path`, returning the databases in its RDB preamble and the commands after
+// it. A command cut short at the end of the file, as a crash mid-write leaves it, is dropped like
+// with Redis's aof-load-truncated. Corruption anywhere else stops the server from starting.
+pub async fn read_append_only_file(
+    path: &Path,
+) -> Result<(Vec<HashMap<String, Entry>>, Bytes), ServerError> {
+    let data = match tokio::fs::read(path).await {
+        Ok(x) => x,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), Bytes::new())),
+        Err(e) => return Err(e.into()),
+    };
+    let check = aof::check(&data);
+    if let Some((offset, message)) = &check.error {
+        let truncated =
+            !check.preamble_corrupt && (*offset == data.len() || message.ends_with("inside MULTI"));
+        if !truncated {
+            return Err(format!(
+                "Bad file format reading the append only file {}: {} at offset {}. Use --check-aof --fix to repair it",
+                path.display(),
+                message,
+                offset
+            )
+            .into());
+        }
+        warning!(
+            "The AOF {} was truncated, loading the first {} of its {} bytes",
+            path.display(),
+            check.valid_up_to,
+            data.len()
+        );
+    }
+    let preamble_length = match data.starts_with(b"REDIS") {
+        true => rdb::rdb_length(&data).map_err(|(_, message)| message)?,
+        false => 0,
+    };
+    let entries = match preamble_length {
+        0 => Vec::new(),
+        length => RdbParser::new(data[..length].to_vec())
+            .rdb_to_db()
+            .map_err(|(_, message)| message)?,
+    };
+    let commands = Bytes::copy_from_slice(&data[preamble_length..check.valid_up_to]);
+    Ok((entries, commands))
+}
+
+// Runs the commands read from the AOF, like a client that isn't subject to ACLs. Loading doesn't
+// count as changing the dataset.
+pub async fn replay_append_only_file(server: &Arc<ServerState>, commands: Bytes) {
+    let frames = parse_frames(commands).expect("Expected the checked AOF to parse");
+    let dispatcher = Dispatcher::new(Arc::clone(server));
+    let mut client = ClientContext::new(0);
+    client.user = None;
+    let count = frames.len();
+    for frame in frames {
+        let mut args = match frame {
+            RespType::Array(x) => x,
+            other => panic!("Expected a command in the AOF, got {:?}", other),
+        };
+        let name = match args.remove(0) {
+            RespType::BulkString(Some(x)) => String::from_utf8_lossy(&x).into_owned(),
+            other => panic!("Expected a command name in the AOF, got {:?}", other),
+        };
+        match args_to_command(&name, args) {
+            Ok(command) => {
+                dispatcher.dispatch(command, &mut client).await;
+            }
+            Err(e) => warning!("Skipping a command in the AOF: {}", e),
+        }
+    }
+    server.stats.dirty.store(0, Ordering::Relaxed);
+    notice!("DB loaded from append only file: {} commands", count);
+}
+
+fn unix_secs() -> u64 {
+    unix_ms() / 1000
+}
@@ -1,218 +1,1748 @@
-use super::commands::Command;
+use super::acl::{Acl, DEFAULT_USER, WRONGPASS_ERROR};
+use super::bitmap::{self, BitOperation, BitUnit};
+use super::clients::{Clients, KillFilter};
+use super::clock;
+use super::command_table::{self, CommandSpec};
+use super::commands::{Expiry, SetCondition, SetOptions};
+use super::eviction::EvictionPolicy;
+use super::glob;
+use super::identity;
+use super::keyspace::Keyspace;
+use super::latency::LatencyStats;
+use super::lazyfree;
+use super::lru;
+use super::notify::NotifyFlags;
+use super::output::Reply;
+use super::pubsub::{Kind, PubSub};
+use super::range;
+use super::replica::{LinkState, ReplicaAck};
+use super::slowlog::SlowLog;
+use super::sorted_set::{ListpackLimits, SortedSet};
+use super::state::{ClientContext, ServerState};
+use super::store::{Entry, Shard, Snapshot, Store};
+use super::stream::{IdSpec, Stream, StreamEntry, StreamId};
+use super::string::{StringValue, NOT_AN_INTEGER_ERROR};
+use super::synchronize::request_acks;
+use super::value::{Value, WrongType, WRONGTYPE_ERROR};
 use super::RedisState;
 
-use crate::config::Config;
+use crate::config::{Config, ConfigSetError};
+use crate::log;
 use crate::resp::{
-    resp_deserializer::RespParser,
-    resp_serializer::{create_null_string, serialize_command, serialize_resp_data},
-    RespType,
+    resp_serializer::{serialize_for, serialize_resp_data},
+    shared, Protocol, RespType,
 };
 
-use std::collections::HashMap;
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::SystemTime;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::MutexGuard;
+use tokio::task::JoinSet;
 use tokio::time::{self, Duration};
 
-pub async fn handle_echo(message: String, stream: Arc<RwLock<TcpStream>>, role: RedisState) {
-    let response = serialize_resp_data(RespType::BulkString(Some(String::from(format!(
-        "{}",
-        message
-    )))));
-    if role == RedisState::Master {
-        let mut stream = stream.write().await;
-        let _ = stream.write_all(response.as_bytes()).await;
+const STRING_TOO_LONG_ERROR: &str = "ERR string exceeds maximum allowed size (proto-max-bulk-len)";
+const BIT_OFFSET_ERROR: &str = "ERR bit offset is not an integer or out of range";
+
+pub async fn handle_echo(message: String) -> Vec<u8> {
+    serialize_resp_data(RespType::BulkString(Some(Bytes::from(message))))
+}
+
+pub async fn handle_ping(client: &ClientContext) -> Vec<u8> {
+    // Subscribed RESP2 clients get a reply shaped like their messages
+    if client.protocol == Protocol::Resp2 && client.subscriptions.is_active() {
+        return serialize_resp_data(RespType::Array(vec![
+            RespType::BulkString(Some(Bytes::from("pong"))),
+            RespType::BulkString(Some(Bytes::new())),
+        ]));
     }
+    shared::PONG.to_vec()
 }
 
-pub async fn handle_ping(stream: Arc<RwLock<TcpStream>>, role: RedisState) {
-    let response = serialize_resp_data(RespType::SimpleString(String::from("PONG")));
-    if role == RedisState::Master {
-        let mut stream = stream.write().await;
-        let _ = stream.write_all(response.as_bytes()).await;
+// Confirms each subscription with how many the client has after it
+pub fn handle_subscribe(
+    pubsub: &PubSub,
+    client: &mut ClientContext,
+    kind: Kind,
+    names: Vec<Bytes>,
+) -> Vec<u8> {
+    let mut reply = Vec::new();
+    for name in names {
+        pubsub.subscribe(client.id, &mut client.subscriptions, kind, name.clone());
+        reply.extend(subscription_reply(client, kind, true, Some(name)));
     }
+    reply
 }
 
-pub async fn handle_set(
-    key: String,
-    value: String,
-    lifespan: Option<u64>,
-    stream: Arc<RwLock<TcpStream>>,
-    db: Arc<Mutex<HashMap<String, String>>>,
-    expiry: Arc<RwLock<HashMap<String, SystemTime>>>,
-    role: RedisState,
-) {
+// Like handle_subscribe, where no names means every channel, or pattern, the client has
+pub fn handle_unsubscribe(
+    pubsub: &PubSub,
+    client: &mut ClientContext,
+    kind: Kind,
+    names: Vec<Bytes>,
+) -> Vec<u8> {
+    let names = match names.is_empty() {
+        true => client.subscriptions.names(kind),
+        false => names,
+    };
+    // Even with nothing to unsubscribe from, there's one reply
+    if names.is_empty() {
+        return subscription_reply(client, kind, false, None);
+    }
+    let mut reply = Vec::new();
+    for name in names {
+        pubsub.unsubscribe(client.id, &mut client.subscriptions, kind, &name);
+        reply.extend(subscription_reply(client, kind, false, Some(name)));
+    }
+    reply
+}
+
+fn subscription_reply(
+    client: &ClientContext,
+    kind: Kind,
+    subscribed: bool,
+    name: Option<Bytes>,
+) -> Vec<u8> {
+    let action = match (kind, subscribed) {
+        (Kind::Channel, true) => "subscribe",
+        (Kind::Channel, false) => "unsubscribe",
+        (Kind::Pattern, true) => "psubscribe",
+        (Kind::Pattern, false) => "punsubscribe",
+    };
+    serialize_for(
+        RespType::Push(vec![
+            RespType::BulkString(Some(Bytes::from(action))),
+            RespType::BulkString(name),
+            RespType::Integer(client.subscriptions.count() as i64),
+        ]),
+        client.protocol,
+    )
+}
+
+pub fn handle_publish(pubsub: &PubSub, channel: Bytes, message: Bytes) -> Vec<u8> {
+    serialize_resp_data(RespType::Integer(pubsub.publish(&channel, &message) as i64))
+}
+
+// Points the client at another database
+pub fn handle_select(index: String, client: &mut ClientContext, databases: usize) -> Vec<u8> {
+    let error = match index.parse::<i64>() {
+        Ok(x) if (0..databases as i64).contains(&x) => {
+            client.db = x as usize;
+            None
+        }
+        Ok(_) => Some("ERR DB index is out of range"),
+        Err(_) => Some("ERR value is not an integer or out of range"),
+    };
+    match error {
+        Some(x) => serialize_resp_data(RespType::Error(String::from(x))),
+        None => serialize_resp_data(RespType::SimpleString(String::from("OK"))),
+    }
+}
+
+// Names show up in CLIENT LIST, which separates its fields with spaces
+pub fn handle_client_setname(name: String, client: &mut ClientContext) -> Vec<u8> {
+    if name.bytes().any(|x| !(b'!'..=b'~').contains(&x)) {
+        return serialize_resp_data(RespType::Error(String::from(
+            "ERR Client names cannot contain spaces, newlines or special characters.",
+        )));
+    }
+    client.name = Some(name).filter(|x| !x.is_empty());
+    shared::OK.to_vec()
+}
+
+pub fn handle_client_getname(client: &ClientContext, protocol: Protocol) -> Vec<u8> {
+    match &client.name {
+        Some(x) => serialize_resp_data(RespType::BulkString(Some(Bytes::from(x.clone())))),
+        None => serialize_for(RespType::Null, protocol),
+    }
+}
+
+// One line per connection, oldest first
+pub fn handle_client_list(clients: &Clients, protocol: Protocol) -> Vec<u8> {
+    let list: String = clients
+        .list()
+        .iter()
+        .map(|x| format!("{}\n", x.describe()))
+        .collect();
+    serialize_for(
+        RespType::VerbatimString(String::from("txt"), Bytes::from(list)),
+        protocol,
+    )
+}
+
+// The connections are closed once they next look up from their commands
+pub fn handle_client_kill(filter: KillFilter, clients: &Clients, caller: u64) -> Vec<u8> {
+    let killed = clients.kill(&filter, caller);
+    let reply = match (filter.old_form, killed) {
+        (true, 0) => RespType::Error(String::from("ERR No such client")),
+        (true, _) => RespType::SimpleString(String::from("OK")),
+        (false, x) => RespType::Integer(x as i64),
+    };
+    serialize_resp_data(reply)
+}
+
+// Switches the connection to the requested protocol, and replies with a description of the
+// server in it
+// Logs the client in as `username`, the default user if not given, see Acl::authenticate
+pub fn handle_auth(
+    username: Option<String>,
+    password: String,
+    client: &mut ClientContext,
+    server: &ServerState,
+) -> Vec<u8> {
+    let config = server.config.current();
+    if username.is_none()
+        && !server
+            .acl
+            .requires_password(DEFAULT_USER, config.requirepass.as_deref())
+    {
+        return serialize_resp_data(RespType::Error(String::from(
+            "ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?",
+        )));
+    }
+    let username = username.unwrap_or_else(|| DEFAULT_USER.to_string());
+    match authenticate(&username, &password, client, server) {
+        Ok(()) => shared::OK.to_vec(),
+        Err(e) => serialize_resp_data(RespType::Error(e.to_string())),
+    }
+}
+
+fn authenticate(
+    username: &str,
+    password: &str,
+    client: &mut ClientContext,
+    server: &ServerState,
+) -> Result<(), &'static str> {
+    let requirepass = server.config.current().requirepass.clone();
+    if !server
+        .acl
+        .authenticate(username, password, requirepass.as_deref())
     {
-        let mut db = db.lock().await;
-        db.insert(key.clone(), value);
-    }
-    if let Some(delay_millis) = lifespan {
-        let mut expiry = expiry.write().await;
-        let lifespan = Duration::from_millis(delay_millis);
-        let now = SystemTime::now();
-        let future_time = now + lifespan;
-        expiry.insert(key.clone(), future_time);
+        return Err(WRONGPASS_ERROR);
+    }
+    client.user = Some(username.to_string());
+    client.authenticated = true;
+    Ok(())
+}
+
+// HELLO is let through before a client authenticates, but only to do so with AUTH
+pub fn handle_hello(
+    version: Option<String>,
+    auth: Option<(String, String)>,
+    client: &mut ClientContext,
+    server: &ServerState,
+) -> Vec<u8> {
+    let authenticated = match auth {
+        Some((username, password)) => authenticate(&username, &password, client, server),
+        None if !client.authenticated => Err(
+            "NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time",
+        ),
+        None => Ok(()),
+    };
+    if let Err(e) = authenticated {
+        return serialize_resp_data(RespType::Error(e.to_string()));
+    }
+    if let Some(version) = version {
+        client.protocol = match (version.parse::<i64>(), Protocol::parse(&version)) {
+            (_, Some(x)) => x,
+            (Ok(_), None) => {
+                return serialize_resp_data(RespType::Error(String::from(
+                    "NOPROTO unsupported protocol version",
+                )))
+            }
+            (Err(_), None) => {
+                return serialize_resp_data(RespType::Error(String::from(
+                    "ERR Protocol version is not an integer or out of range",
+                )))
+            }
+        };
+    }
+    let bulk = |value: &'static str| RespType::BulkString(Some(Bytes::from(value)));
+    let proto = match client.protocol {
+        Protocol::Resp2 => 2,
+        Protocol::Resp3 => 3,
+    };
+    let role = match server.config.current().role {
+        RedisState::Master => "master",
+        RedisState::Replica => "replica",
+    };
+    serialize_for(
+        RespType::Map(vec![
+            (bulk("server"), bulk("redis")),
+            (bulk("version"), bulk(identity::VERSION)),
+            (bulk("proto"), RespType::Integer(proto)),
+            (bulk("mode"), bulk("standalone")),
+            (bulk("role"), bulk(role)),
+            (bulk("modules"), RespType::Array(Vec::new())),
+        ]),
+        client.protocol,
+    )
+}
+
+pub fn handle_set(
+    key: String,
+    value: Bytes,
+    options: SetOptions,
+    db: &Store,
+    protocol: Protocol,
+) -> Vec<u8> {
+    let mut shard = db.write(&key);
+    let previous = shard.peek(&key);
+    // GET won't overwrite anything but a string, as it couldn't reply with the old value
+    let old_value = match previous.map(|x| x.value.as_str()) {
+        Some(Ok(x)) => Some(x.to_bytes()),
+        Some(Err(WrongType)) if options.get => {
+            return serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string()));
+        }
+        _ => None,
+    };
+    let applies = match options.condition {
+        Some(SetCondition::IfMissing) => previous.is_none(),
+        Some(SetCondition::IfExists) => previous.is_some(),
+        None => true,
+    };
+    if applies {
+        let mut entry = Entry::new(Value::Str(value.into()));
+        entry.expires_at = match options.keep_ttl {
+            true => previous.and_then(|x| x.expires_at),
+            false => options.expiry.map(|x| x.deadline()),
+        };
+        db.notify(NotifyFlags::STRING, "set", &key);
+        if options.expiry.is_some() {
+            db.notify(NotifyFlags::GENERIC, "expire", &key);
+        }
+        shard.insert(key, entry);
+    }
+    match (options.get, applies) {
+        (true, _) => serialize_resp_data(RespType::BulkString(old_value)),
+        (false, true) => shared::OK.to_vec(),
+        (false, false) => serialize_for(RespType::Null, protocol),
+    }
+}
+
+// The time the key has left in units of `unit_ms`, rounded to the nearest. -1 if it doesn't
+// expire, and -2 if it doesn't exist.
+pub fn handle_ttl(key: String, unit_ms: u64, db: &Store) -> Vec<u8> {
+    let shard = db.read(&key);
+    let ttl = match shard.peek(&key).map(|x| x.expires_at) {
+        Some(Some(expires_at)) => {
+            let remaining = expires_at.saturating_sub(clock::mstime());
+            ((remaining + unit_ms / 2) / unit_ms) as i64
+        }
+        Some(None) => -1,
+        None => -2,
+    };
+    serialize_resp_data(RespType::Integer(ttl))
+}
+
+// EXPIRE, or PERSIST when `expiry` is None. Replies 1 if the key's expiration changed, which
+// PERSIST only does for keys that had one.
+pub fn handle_expire(key: String, expiry: Option<Expiry>, db: &Store) -> Vec<u8> {
+    let event = if expiry.is_some() {
+        "expire"
+    } else {
+        "persist"
+    };
+    let mut shard = db.write(&key);
+    let changed = match (expiry, shard.peek(&key)) {
+        (_, None)
+        | (
+            None,
+            Some(Entry {
+                expires_at: None, ..
+            }),
+        ) => false,
+        (expiry, Some(_)) => shard.set_expiry(&key, expiry.map(|x| x.deadline())),
+    };
+    if changed {
+        db.notify(NotifyFlags::GENERIC, event, &key);
+    }
+    serialize_resp_data(RespType::Integer(changed as i64))
+}
+
+// Adds `increment` to the integer stored at the key, starting from 0 if there isn't one. It's an
+// i128 so that DECRBY can negate any i64, leaving overflow to be caught on the sum.
+pub fn handle_incr_by(key: String, increment: i128, db: &Store) -> Vec<u8> {
+    let mut shard = db.write(&key);
+    let (current, exists) = match shard.peek(&key).map(|x| x.value.as_str()) {
+        Some(Ok(x)) => match x.as_int() {
+            Some(x) => (x, true),
+            None => return serialize_resp_data(RespType::Error(NOT_AN_INTEGER_ERROR.to_string())),
+        },
+        Some(Err(WrongType)) => {
+            return serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string()))
+        }
+        None => (0, false),
+    };
+    let value = match i64::try_from(current as i128 + increment) {
+        Ok(x) => x,
+        Err(_) => {
+            return serialize_resp_data(RespType::Error(String::from(
+                "ERR increment or decrement would overflow",
+            )))
+        }
+    };
+    // The expiration, if any, is kept
+    let value_to_store = Value::Str(StringValue::Int(value));
+    db.notify(NotifyFlags::STRING, "incrby", &key);
+    if exists {
+        if let Some(mut entry) = shard.get_mut(&key) {
+            entry.value = value_to_store;
+        }
     } else {
-        let mut expiry = expiry.write().await;
-        expiry.remove(&key);
+        shard.insert(key, Entry::new(value_to_store));
+    }
+    serialize_resp_data(RespType::Integer(value))
+}
+
+// Reads every key under one lock acquisition, so that no write lands in between. Keys that are
+// missing or don't hold strings come back as nulls.
+pub fn handle_mget(keys: Vec<String>, db: &Store, protocol: Protocol) -> Vec<u8> {
+    let locked: Vec<&str> = keys.iter().map(String::as_str).collect();
+    let mut guard = db.write_keys(&locked);
+    let values = keys
+        .iter()
+        .map(
+            |key| match guard.shard(key).get(key).map(|x| x.value.as_str()) {
+                Some(Ok(x)) => RespType::BulkString(Some(x.to_bytes())),
+                _ => RespType::Null,
+            },
+        )
+        .collect();
+    serialize_for(RespType::Array(values), protocol)
+}
+
+// Sets every key at once, dropping their expirations as SET does. With `if_none_exist`, as for
+// MSETNX, nothing is set if any of the keys exists. A key given more than once ends up with its
+// last value.
+pub fn handle_mset(pairs: Vec<(String, Bytes)>, if_none_exist: bool, db: &Store) -> Vec<u8> {
+    let locked: Vec<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+    let mut guard = db.write_keys(&locked);
+    if if_none_exist
+        && pairs
+            .iter()
+            .any(|(key, _)| guard.shard(key).peek(key).is_some())
+    {
+        return serialize_resp_data(RespType::Integer(0));
+    }
+    for (key, value) in pairs {
+        let entry = Entry::new(Value::Str(value.into()));
+        db.notify(NotifyFlags::STRING, "set", &key);
+        guard.shard(&key).insert(key, entry);
+    }
+    match if_none_exist {
+        true => serialize_resp_data(RespType::Integer(1)),
+        false => shared::OK.to_vec(),
+    }
+}
+
+// Replies with the string's new length. A missing key starts out empty.
+pub fn handle_append(key: String, value: Bytes, max_len: usize, db: &Store) -> Vec<u8> {
+    let mut shard = db.write(&key);
+    let mut current = match shard.peek(&key).map(|x| x.value.as_str()) {
+        Some(Ok(x)) => x.to_bytes().to_vec(),
+        Some(Err(WrongType)) => {
+            return serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string()))
+        }
+        None => Vec::new(),
+    };
+    if current.len() + value.len() > max_len {
+        return serialize_resp_data(RespType::Error(STRING_TOO_LONG_ERROR.to_string()));
     }
-    let response = serialize_resp_data(RespType::SimpleString(String::from("OK")));
-    if role == RedisState::Master {
-        let mut stream = stream.write().await;
-        let _ = stream.write_all(response.as_bytes()).await;
+    current.extend_from_slice(&value);
+    let len = current.len();
+    db.notify(NotifyFlags::STRING, "append", &key);
+    store_string(&mut shard, key, current);
+    serialize_resp_data(RespType::Integer(len as i64))
+}
+
+pub fn handle_getrange(key: String, start: i64, end: i64, db: &Store) -> Vec<u8> {
+    let shard = db.read(&key);
+    let value = match shard.get(&key).map(|x| x.value.as_str()) {
+        Some(Ok(x)) => x.to_bytes(),
+        Some(Err(WrongType)) => {
+            return serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string()))
+        }
+        None => Bytes::new(),
+    };
+    let range = range::normalize(start, end, value.len());
+    serialize_resp_data(RespType::BulkString(Some(value.slice(range))))
+}
+
+// Overwrites the string from `offset` on, padding it with zero bytes to reach the offset if it's
+// too short. Replies with the string's new length. Writing nothing leaves the key as it is,
+// missing keys included.
+pub fn handle_setrange(
+    key: String,
+    offset: usize,
+    value: Bytes,
+    max_len: usize,
+    db: &Store,
+) -> Vec<u8> {
+    let mut shard = db.write(&key);
+    let mut current = match shard.peek(&key).map(|x| x.value.as_str()) {
+        Some(Ok(x)) => x.to_bytes().to_vec(),
+        Some(Err(WrongType)) => {
+            return serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string()))
+        }
+        None => Vec::new(),
+    };
+    if value.is_empty() {
+        return serialize_resp_data(RespType::Integer(current.len() as i64));
+    }
+    if offset.saturating_add(value.len()) > max_len {
+        return serialize_resp_data(RespType::Error(STRING_TOO_LONG_ERROR.to_string()));
+    }
+    let end = offset + value.len();
+    if current.len() < end {
+        current.resize(end, 0);
+    }
+    current[offset..end].copy_from_slice(&value);
+    let len = current.len();
+    db.notify(NotifyFlags::STRING, "setrange", &key);
+    store_string(&mut shard, key, current);
+    serialize_resp_data(RespType::Integer(len as i64))
+}
+
+// Replies with the bit as it was. Bits can only be set within a string of proto-max-bulk-len
+// bytes.
+pub fn handle_setbit(key: String, offset: u64, on: bool, max_len: usize, db: &Store) -> Vec<u8> {
+    if offset / 8 >= max_len as u64 {
+        return serialize_resp_data(RespType::Error(BIT_OFFSET_ERROR.to_string()));
+    }
+    let mut shard = db.write(&key);
+    let mut current = match shard.peek(&key).map(|x| x.value.as_str()) {
+        Some(Ok(x)) => x.to_bytes().to_vec(),
+        Some(Err(WrongType)) => {
+            return serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string()))
+        }
+        None => Vec::new(),
+    };
+    let was_on = bitmap::set_bit(&mut current, offset, on);
+    db.notify(NotifyFlags::STRING, "setbit", &key);
+    store_string(&mut shard, key, current);
+    serialize_resp_data(RespType::Integer(was_on as i64))
+}
+
+pub fn handle_getbit(key: String, offset: u64, max_len: usize, db: &Store) -> Vec<u8> {
+    if offset / 8 >= max_len as u64 {
+        return serialize_resp_data(RespType::Error(BIT_OFFSET_ERROR.to_string()));
+    }
+    let shard = db.read(&key);
+    let reply = match shard.get(&key).map(|x| x.value.as_str()) {
+        Some(Ok(x)) => RespType::Integer(bitmap::get_bit(&x.to_bytes(), offset) as i64),
+        Some(Err(WrongType)) => RespType::Error(WRONGTYPE_ERROR.to_string()),
+        None => RespType::Integer(0),
+    };
+    serialize_resp_data(reply)
+}
+
+pub fn handle_bitcount(key: String, range: Option<(i64, i64, BitUnit)>, db: &Store) -> Vec<u8> {
+    let shard = db.read(&key);
+    let reply = match shard.get(&key).map(|x| x.value.as_str()) {
+        Some(Ok(x)) => RespType::Integer(bitmap::count(&x.to_bytes(), range) as i64),
+        Some(Err(WrongType)) => RespType::Error(WRONGTYPE_ERROR.to_string()),
+        None => RespType::Integer(0),
+    };
+    serialize_resp_data(reply)
+}
+
+// Stores the sources combined into the destination, replacing whatever it held, and replies with
+// the result's length. Missing sources count as empty strings, and an empty result deletes the
+// destination.
+pub fn handle_bitop(
+    operation: BitOperation,
+    destination: String,
+    keys: Vec<String>,
+    db: &Store,
+) -> Vec<u8> {
+    let locked: Vec<&str> = keys
+        .iter()
+        .chain([&destination])
+        .map(String::as_str)
+        .collect();
+    let mut guard = db.write_keys(&locked);
+    let mut sources = Vec::new();
+    for key in &keys {
+        match guard.shard(key).get(key).map(|x| x.value.as_str()) {
+            Some(Ok(x)) => sources.push(x.to_bytes()),
+            Some(Err(WrongType)) => {
+                return serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string()))
+            }
+            None => sources.push(Bytes::new()),
+        }
+    }
+    let result = bitmap::combine(operation, &sources);
+    let len = result.len();
+    let shard = guard.shard(&destination);
+    match result.is_empty() {
+        true => {
+            if shard.remove(&destination).is_some() {
+                db.notify(NotifyFlags::GENERIC, "del", &destination);
+            }
+        }
+        false => {
+            db.notify(NotifyFlags::STRING, "set", &destination);
+            let value = Value::Str(StringValue::from_bytes(Bytes::from(result)));
+            shard.insert(destination, Entry::new(value));
+        }
+    }
+    serialize_resp_data(RespType::Integer(len as i64))
+}
+
+// Replaces the string at the key keeping its expiration, or creates it
+fn store_string(shard: &mut Shard, key: String, bytes: Vec<u8>) {
+    let value = Value::Str(StringValue::from_bytes(Bytes::from(bytes)));
+    if let Some(mut entry) = shard.get_mut(&key) {
+        entry.value = value;
+        return;
+    }
+    shard.insert(key, Entry::new(value));
+}
+
+// Which end of a list a command works on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum End {
+    Front,
+    Back,
+}
+
+// LPUSH and RPUSH, which add the values one at a time, so LPUSH leaves them in reverse order.
+// Replies with the list's new length.
+pub fn handle_push(key: String, values: Vec<Bytes>, end: End, db: &Store) -> Vec<u8> {
+    let mut shard = db.write(&key);
+    if shard.peek(&key).is_none() {
+        shard.insert(key.clone(), Entry::new(Value::List(VecDeque::new())));
+    }
+    let mut entry = shard.get_mut(&key).expect("The list was just created");
+    let list = match entry.value.as_list_mut() {
+        Ok(x) => x,
+        Err(WrongType) => return serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string())),
+    };
+    for value in values {
+        match end {
+            End::Front => list.push_front(value),
+            End::Back => list.push_back(value),
+        }
     }
+    let len = list.len();
+    let event = match end {
+        End::Front => "lpush",
+        End::Back => "rpush",
+    };
+    db.notify(NotifyFlags::LIST, event, &key);
+    serialize_resp_data(RespType::Integer(len as i64))
 }
 
-pub async fn handle_get(
+pub fn handle_lrange(key: String, start: i64, end: i64, db: &Store) -> Vec<u8> {
+    let shard = db.read(&key);
+    let list = match shard.get(&key).map(|x| x.value.as_list()) {
+        Some(Ok(x)) => x,
+        Some(Err(WrongType)) => {
+            return serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string()))
+        }
+        None => return serialize_resp_data(RespType::Array(Vec::new())),
+    };
+    let elements = list
+        .range(range::normalize(start, end, list.len()))
+        .map(|x| RespType::BulkString(Some(x.clone())))
+        .collect();
+    serialize_resp_data(RespType::Array(elements))
+}
+
+// LPOP and RPOP. Without a count the reply is the element itself, with one it's an array of up to
+// that many. A list is deleted once its last element is popped, as Redis never keeps empty ones.
+pub fn handle_pop(
     key: String,
-    stream: Arc<RwLock<TcpStream>>,
-    db: Arc<Mutex<HashMap<String, String>>>,
-    expiry: Arc<RwLock<HashMap<String, SystemTime>>>,
-) {
-    let db = db.lock().await;
-    let expiry = expiry.read().await;
-    let mut response = create_null_string();
-    match db.get(&key) {
-        Some(value) => {
-            response = serialize_resp_data(RespType::BulkString(Some(String::from(value))));
-            if let Some(expiration) = expiry.get(&key) {
-                if SystemTime::now() > *expiration {
-                    response = create_null_string();
+    count: Option<usize>,
+    end: End,
+    db: &Store,
+    protocol: Protocol,
+) -> Vec<u8> {
+    let mut shard = db.write(&key);
+    let (popped, now_empty) = match shard.get_mut(&key) {
+        Some(mut entry) => match entry.value.as_list_mut() {
+            Ok(list) => {
+                let popped: Vec<Bytes> = (0..count.unwrap_or(1))
+                    .map_while(|_| match end {
+                        End::Front => list.pop_front(),
+                        End::Back => list.pop_back(),
+                    })
+                    .collect();
+                (Some(popped), list.is_empty())
+            }
+            Err(WrongType) => {
+                return serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string()))
+            }
+        },
+        None => (None, false),
+    };
+    if popped.as_ref().is_some_and(|x| !x.is_empty()) {
+        notify_popped(db, &key, end, now_empty);
+    }
+    if now_empty {
+        shard.remove(&key);
+    }
+    match (popped, count) {
+        (Some(popped), Some(_)) => serialize_resp_data(RespType::Array(
+            popped
+                .into_iter()
+                .map(|x| RespType::BulkString(Some(x)))
+                .collect(),
+        )),
+        (Some(mut popped), None) => serialize_resp_data(RespType::BulkString(popped.pop())),
+        (None, Some(_)) => match protocol {
+            Protocol::Resp2 => serialize_resp_data(RespType::NullArray),
+            Protocol::Resp3 => serialize_resp_data(RespType::Null),
+        },
+        (None, None) => serialize_for(RespType::Null, protocol),
+    }
+}
+
+// BLPOP and BRPOP. Pops from the first of the keys that holds a list, or waits until one of them
+// does or the timeout passes. Clients waiting on a key are served in the order they started
+// waiting, so one that isn't first in line for a key leaves it to the others. Also returns the
+// key popped from, along with replication's applying lock, for the pop to be propagated under.
+pub async fn handle_blocking_pop<'a>(
+    server: &'a ServerState,
+    keyspace: &Keyspace,
+    keys: Vec<String>,
+    timeout: Option<u64>,
+    end: End,
+    protocol: Protocol,
+) -> (Vec<u8>, Option<(String, MutexGuard<'a, ()>)>) {
+    // Watching starts before the first pop, so that nothing pushed in between is missed
+    let watch = server.blocking.watch(&keys);
+    let deadline = timeout.map(|x| time::Instant::now() + Duration::from_millis(x));
+    loop {
+        let turns: Vec<(String, bool)> = keys
+            .iter()
+            .map(|key| (key.clone(), watch.is_first(key)))
+            .collect();
+        let applying = server.replication.applying.lock().await;
+        match keyspace.run(move |db| pop_first(&turns, end, db)).await {
+            Ok(Some((key, value))) => {
+                let reply = RespType::Array(vec![
+                    RespType::BulkString(Some(Bytes::from(key.clone()))),
+                    RespType::BulkString(Some(value)),
+                ]);
+                return (serialize_resp_data(reply), Some((key, applying)));
+            }
+            Ok(None) => drop(applying),
+            Err(WrongType) => {
+                return (
+                    serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string())),
+                    None,
+                )
+            }
+        }
+        let timed_out = match deadline {
+            None => {
+                watch.changed().await;
+                false
+            }
+            Some(deadline) => time::timeout_at(deadline, watch.changed()).await.is_err(),
+        };
+        if timed_out {
+            let reply = match protocol {
+                Protocol::Resp2 => serialize_resp_data(RespType::NullArray),
+                Protocol::Resp3 => serialize_resp_data(RespType::Null),
+            };
+            return (reply, None);
+        }
+    }
+}
+
+// Pops an element from the first list among `keys` that it's this client's turn for
+fn pop_first(
+    keys: &[(String, bool)],
+    end: End,
+    db: &Store,
+) -> Result<Option<(String, Bytes)>, WrongType> {
+    for (key, is_turn) in keys {
+        let mut shard = db.write(key);
+        let (popped, now_empty) = match shard.get_mut(key) {
+            Some(mut entry) => {
+                let list = entry.value.as_list_mut()?;
+                if !is_turn {
+                    continue;
                 }
+                let popped = match end {
+                    End::Front => list.pop_front(),
+                    End::Back => list.pop_back(),
+                };
+                (popped, list.is_empty())
             }
+            None => continue,
+        };
+        if now_empty {
+            shard.remove(key);
+        }
+        if let Some(value) = popped {
+            notify_popped(db, key, end, now_empty);
+            return Ok(Some((key.clone(), value)));
         }
-        None => (),
     }
-    let mut stream = stream.write().await;
-    let _ = stream.write_all(response.as_bytes()).await;
+    Ok(None)
 }
 
-pub async fn handle_info(_arg: String, config: Arc<Config>, stream: Arc<RwLock<TcpStream>>) {
-    let response = match config.role {
-        RedisState::Master => serialize_resp_data(RespType::BulkString(Some(format!(
-            "role:{}\nmaster_replid:{}\nmaster_repl_offset:{}\n",
-            config.role,
-            config.master_replid.as_ref().unwrap(),
-            config.master_repl_offset.as_ref().unwrap()
-        )))),
-        RedisState::Replica => {
-            serialize_resp_data(RespType::BulkString(format!("role:{}", config.role).into()))
-        }
-    };
-    let mut stream = stream.write().await;
-    let _ = stream.write_all(response.as_bytes()).await;
-}
-
-pub async fn handle_config_get(
-    stream: Arc<RwLock<TcpStream>>,
-    config: Arc<Config>,
-    path_type: String,
-) {
-    let path: String = match path_type.to_lowercase().as_str() {
-        "dir" => config
-            .rdb_dir
-            .as_ref()
-            .and_then(|p| p.to_str())
-            .expect("Failed to convert path to string")
-            .to_string(),
-        "dbfilename" => config
-            .rdb_filename
-            .as_ref()
-            .and_then(|p| p.to_str())
-            .expect("Failed to convert path to string")
-            .to_string(),
-        other @ _ => panic!("Unsupported argument for CONFIG GET: {}", other),
-    };
-    let response = serialize_resp_data(RespType::Array(vec![
-        RespType::BulkString(Some(path_type)),
-        RespType::BulkString(Some(path)),
-    ]));
-    let mut stream = stream.write().await;
-    let _ = stream.write_all(response.as_bytes()).await;
-}
-
-pub async fn handle_keys(
-    stream: Arc<RwLock<TcpStream>>,
-    db: Arc<Mutex<HashMap<String, String>>>,
-    _arg: String,
-) {
-    // NOTE: Assuming arg is always *
-    let db = db.lock().await;
-    let keys: Vec<String> = db.keys().cloned().collect();
-    let resp_keys: Vec<RespType> = keys
+// Popping the last element deletes the list, which is an event of its own
+fn notify_popped(db: &Store, key: &str, end: End, now_empty: bool) {
+    let event = match end {
+        End::Front => "lpop",
+        End::Back => "rpop",
+    };
+    db.notify(NotifyFlags::LIST, event, key);
+    if now_empty {
+        db.notify(NotifyFlags::GENERIC, "del", key);
+    }
+}
+
+// Replies with how many of the fields are new to the hash
+pub fn handle_hset(key: String, pairs: Vec<(Bytes, Bytes)>, db: &Store) -> Vec<u8> {
+    let mut shard = db.write(&key);
+    if shard.peek(&key).is_none() {
+        shard.insert(key.clone(), Entry::new(Value::Hash(HashMap::new())));
+    }
+    let mut entry = shard.get_mut(&key).expect("The hash was just created");
+    let hash = match entry.value.as_hash_mut() {
+        Ok(x) => x,
+        Err(WrongType) => return serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string())),
+    };
+    let added = pairs
         .into_iter()
-        .map(|key| RespType::BulkString(Some(key)))
-        .collect();
-    let response = serialize_resp_data(RespType::Array(resp_keys));
-    let mut stream = stream.write().await;
-    let _ = stream.write_all(response.as_bytes()).await;
-}
-
-pub async fn handle_wait(
-    replica_connections: Arc<RwLock<Option<HashMap<i32, Arc<RwLock<TcpStream>>>>>>,
-    stream: Arc<RwLock<TcpStream>>,
-    timeout: i32,
-    _replicas_to_wait_for: i32,
-    write_bytes_processed: usize,
-    write_commands_to_process: usize,
-) {
-    let get_ack_command = serialize_command(&Command::ReplConf(
-        String::from("GETACK"),
-        Some(String::from("*")),
-    ));
-    let replica_connections = replica_connections.read().await;
-    let mut up_to_date_replicas: usize = 0;
-    if write_commands_to_process == 0 {
-        up_to_date_replicas = replica_connections.as_ref().unwrap().values().len();
-        let response = serialize_resp_data(RespType::Integer(up_to_date_replicas as i64));
-        let mut stream = stream.write().await;
-        let _ = stream.write_all(response.as_bytes()).await;
-        return;
+        .filter(|(field, value)| hash.insert(field.clone(), value.clone()).is_none())
+        .count();
+    db.notify(NotifyFlags::HASH, "hset", &key);
+    serialize_resp_data(RespType::Integer(added as i64))
+}
+
+pub fn handle_hget(key: String, field: Bytes, db: &Store, protocol: Protocol) -> Vec<u8> {
+    let shard = db.read(&key);
+    match shard.get(&key).map(|x| x.value.as_hash()) {
+        Some(Ok(hash)) => serialize_resp_data(RespType::BulkString(hash.get(&field).cloned())),
+        Some(Err(WrongType)) => serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string())),
+        None => serialize_for(RespType::Null, protocol),
     }
+}
 
-    let timeout = Duration::from_millis(timeout as u64);
-    let connections = replica_connections
-        .as_ref()
-        .expect("Master didn't have replica_connections while processing WAIT");
-    let mut replica_fds: Vec<i32> = connections.keys().copied().collect();
-    replica_fds.sort();
+// Replies with how many of the fields were there to remove. Removing the last deletes the hash.
+pub fn handle_hdel(key: String, fields: Vec<Bytes>, db: &Store) -> Vec<u8> {
+    let mut shard = db.write(&key);
+    let (removed, now_empty) = match shard.get_mut(&key) {
+        Some(mut entry) => match entry.value.as_hash_mut() {
+            Ok(hash) => {
+                let removed = fields.iter().filter(|x| hash.remove(*x).is_some()).count();
+                (removed, hash.is_empty())
+            }
+            Err(WrongType) => {
+                return serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string()))
+            }
+        },
+        None => (0, false),
+    };
+    if removed > 0 {
+        db.notify(NotifyFlags::HASH, "hdel", &key);
+    }
+    if now_empty {
+        db.notify(NotifyFlags::GENERIC, "del", &key);
+        shard.remove(&key);
+    }
+    serialize_resp_data(RespType::Integer(removed as i64))
+}
 
-    for fd in replica_fds {
-        let replica_stream = connections.get(&fd).unwrap();
-        let mut parser = RespParser::new(String::from(""), Arc::clone(&replica_stream));
-        {
-            let mut replica_stream = replica_stream.write().await;
-            let _ = replica_stream.write_all(get_ack_command.as_bytes()).await;
+// A map for RESP3 clients, and fields alternating with their values for RESP2 ones
+pub fn handle_hgetall(key: String, db: &Store, protocol: Protocol) -> Vec<u8> {
+    let shard = db.read(&key);
+    let pairs = match shard.get(&key).map(|x| x.value.as_hash()) {
+        Some(Ok(hash)) => hash
+            .iter()
+            .map(|(field, value)| {
+                (
+                    RespType::BulkString(Some(field.clone())),
+                    RespType::BulkString(Some(value.clone())),
+                )
+            })
+            .collect(),
+        Some(Err(WrongType)) => {
+            return serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string()))
+        }
+        None => Vec::new(),
+    };
+    serialize_for(RespType::Map(pairs), protocol)
+}
+
+// Replies with how many of the members are new to the set
+pub fn handle_zadd(
+    key: String,
+    pairs: Vec<(f64, Bytes)>,
+    limits: &ListpackLimits,
+    db: &Store,
+) -> Vec<u8> {
+    let mut shard = db.write(&key);
+    if shard.peek(&key).is_none() {
+        shard.insert(key.clone(), Entry::new(Value::ZSet(SortedSet::new())));
+    }
+    let mut entry = shard
+        .get_mut(&key)
+        .expect("The sorted set was just created");
+    let set = match entry.value.as_zset_mut() {
+        Ok(x) => x,
+        Err(WrongType) => return serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string())),
+    };
+    let added = pairs
+        .into_iter()
+        .filter(|(score, member)| set.insert(member.clone(), *score, limits))
+        .count();
+    db.notify(NotifyFlags::ZSET, "zadd", &key);
+    serialize_resp_data(RespType::Integer(added as i64))
+}
+
+// WITHSCORES follows each member with its score for RESP2 clients, and pairs them up for RESP3
+pub fn handle_zrange(
+    key: String,
+    start: i64,
+    end: i64,
+    with_scores: bool,
+    db: &Store,
+    protocol: Protocol,
+) -> Vec<u8> {
+    let shard = db.read(&key);
+    let set = match shard.get(&key).map(|x| x.value.as_zset()) {
+        Some(Ok(x)) => x,
+        Some(Err(WrongType)) => {
+            return serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string()))
+        }
+        None => return serialize_resp_data(RespType::Array(Vec::new())),
+    };
+    let range = range::normalize(start, end, set.len());
+    let members = set.iter().skip(range.start).take(range.len());
+    let elements = match (with_scores, protocol) {
+        (false, _) => members
+            .map(|(member, _)| RespType::BulkString(Some(member.clone())))
+            .collect(),
+        (true, Protocol::Resp2) => members
+            .flat_map(|(member, score)| {
+                [
+                    RespType::BulkString(Some(member.clone())),
+                    RespType::Double(score),
+                ]
+            })
+            .collect(),
+        (true, Protocol::Resp3) => members
+            .map(|(member, score)| {
+                RespType::Array(vec![
+                    RespType::BulkString(Some(member.clone())),
+                    RespType::Double(score),
+                ])
+            })
+            .collect(),
+    };
+    serialize_for(RespType::Array(elements), protocol)
+}
+
+pub fn handle_zscore(key: String, member: Bytes, db: &Store, protocol: Protocol) -> Vec<u8> {
+    let shard = db.read(&key);
+    match shard.get(&key).map(|x| x.value.as_zset()) {
+        Some(Ok(set)) => match set.score(&member) {
+            Some(score) => serialize_for(RespType::Double(score), protocol),
+            None => serialize_for(RespType::Null, protocol),
+        },
+        Some(Err(WrongType)) => serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string())),
+        None => serialize_for(RespType::Null, protocol),
+    }
+}
+
+pub fn handle_zrank(key: String, member: Bytes, db: &Store, protocol: Protocol) -> Vec<u8> {
+    let shard = db.read(&key);
+    match shard.get(&key).map(|x| x.value.as_zset()) {
+        Some(Ok(set)) => match set.rank(&member) {
+            Some(rank) => serialize_resp_data(RespType::Integer(rank as i64)),
+            None => serialize_for(RespType::Null, protocol),
+        },
+        Some(Err(WrongType)) => serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string())),
+        None => serialize_for(RespType::Null, protocol),
+    }
+}
+
+// Also returns the ID the entry was given, for the master to propagate and to wake up readers
+pub fn handle_xadd(
+    key: String,
+    id: IdSpec,
+    fields: Vec<(Bytes, Bytes)>,
+    db: &Store,
+) -> (Vec<u8>, Option<StreamId>) {
+    let mut shard = db.write(&key);
+    let created = shard.peek(&key).is_none();
+    if created {
+        shard.insert(key.clone(), Entry::new(Value::Stream(Stream::new())));
+    }
+    let mut entry = shard.get_mut(&key).expect("The stream was just created");
+    let result = match entry.value.as_stream_mut() {
+        Ok(stream) => stream
+            .add(id, fields, clock::unix_ms())
+            .map_err(|e| e.to_string()),
+        Err(WrongType) => Err(WRONGTYPE_ERROR.to_string()),
+    };
+    drop(entry);
+    let added = match result {
+        Ok(x) => x,
+        Err(e) => {
+            // A stream is only created by a successful XADD
+            if created {
+                shard.remove(&key);
+            }
+            return (serialize_resp_data(RespType::Error(e)), None);
+        }
+    };
+    db.notify(NotifyFlags::STREAM, "xadd", &key);
+    let reply = RespType::BulkString(Some(Bytes::from(added.to_string())));
+    (serialize_resp_data(reply), Some(added))
+}
+
+// An entry as XRANGE and XREAD reply with it, its ID and then its fields alternating with values
+fn stream_entry_to_resp(id: &StreamId, fields: &StreamEntry) -> RespType {
+    let fields = fields
+        .iter()
+        .flat_map(|(field, value)| {
+            [
+                RespType::BulkString(Some(field.clone())),
+                RespType::BulkString(Some(value.clone())),
+            ]
+        })
+        .collect();
+    RespType::Array(vec![
+        RespType::BulkString(Some(Bytes::from(id.to_string()))),
+        RespType::Array(fields),
+    ])
+}
+
+pub fn handle_xrange(
+    key: String,
+    start: StreamId,
+    end: StreamId,
+    count: Option<usize>,
+    db: &Store,
+) -> Vec<u8> {
+    let shard = db.read(&key);
+    let stream = match shard.get(&key).map(|x| x.value.as_stream()) {
+        Some(Ok(x)) => x,
+        Some(Err(WrongType)) => {
+            return serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string()))
         }
+        None => return serialize_resp_data(RespType::Array(Vec::new())),
+    };
+    let entries = stream
+        .range(start, end)
+        .take(count.unwrap_or(usize::MAX))
+        .map(|(id, fields)| stream_entry_to_resp(id, fields))
+        .collect();
+    serialize_resp_data(RespType::Array(entries))
+}
 
-        // If we don't recieve a response within timeout, continue
-        let response = match time::timeout(timeout, parser.parse_command()).await {
-            Ok(Some((command, _bytes_read))) => command,
-            Ok(None) => panic!("Didn't recieve response to GETACK"),
-            Err(_) => continue,
+// Each stream's entries after its ID, leaving out the streams that have none
+fn read_streams(
+    streams: &[(String, StreamId)],
+    count: Option<usize>,
+    db: &Store,
+) -> Result<Vec<(String, Vec<RespType>)>, WrongType> {
+    let mut found = Vec::new();
+    for (key, after) in streams {
+        let shard = db.read(key);
+        let stream = match shard.get(key).map(|x| x.value.as_stream()) {
+            Some(x) => x?,
+            None => continue,
         };
+        let entries: Vec<RespType> = stream
+            .after(*after)
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| stream_entry_to_resp(id, fields))
+            .collect();
+        if !entries.is_empty() {
+            found.push((key.clone(), entries));
+        }
+    }
+    Ok(found)
+}
 
-        match response {
-            Command::ReplConf(arg1, x) => {
-                if arg1.as_str() == "ACK" {
-                    if let Some(byte_processed_by_repliac) = x {
-                        if byte_processed_by_repliac.parse::<usize>().unwrap()
-                            >= write_bytes_processed
-                        {
-                            up_to_date_replicas += 1;
+// With BLOCK, waits until one of the streams gets new entries or the timeout passes, 0 waiting
+// for as long as it takes. "$" reads only the entries added after the command was run.
+pub async fn handle_xread(
+    server: &ServerState,
+    keyspace: &Keyspace,
+    count: Option<usize>,
+    block: Option<u64>,
+    streams: Vec<(String, Option<StreamId>)>,
+    protocol: Protocol,
+) -> Vec<u8> {
+    let keys: Vec<String> = streams.iter().map(|(key, _)| key.clone()).collect();
+    // Watching starts before the first read, so that nothing added in between is missed
+    let watch = server.blocking.watch(&keys);
+    let streams: Vec<(String, StreamId)> = keyspace
+        .run(move |db| {
+            streams
+                .into_iter()
+                .map(|(key, id)| {
+                    let id = id.unwrap_or_else(|| {
+                        let shard = db.read(&key);
+                        match shard.peek(&key).map(|x| x.value.as_stream()) {
+                            Some(Ok(stream)) => stream.last_id,
+                            _ => StreamId::default(),
                         }
-                    }
-                } else {
-                    panic!("Expected REPLCONF Response to GETACK to be ACK");
+                    });
+                    (key, id)
+                })
+                .collect()
+        })
+        .await;
+    let deadline = block
+        .filter(|x| *x > 0)
+        .map(|x| time::Instant::now() + Duration::from_millis(x));
+    loop {
+        let job_streams = streams.clone();
+        match keyspace
+            .run(move |db| read_streams(&job_streams, count, db))
+            .await
+        {
+            Ok(found) if !found.is_empty() => {
+                let found = found.into_iter().map(|(key, entries)| {
+                    (
+                        RespType::BulkString(Some(Bytes::from(key))),
+                        RespType::Array(entries),
+                    )
+                });
+                return match protocol {
+                    Protocol::Resp2 => serialize_resp_data(RespType::Array(
+                        found
+                            .map(|(key, entries)| RespType::Array(vec![key, entries]))
+                            .collect(),
+                    )),
+                    Protocol::Resp3 => serialize_for(RespType::Map(found.collect()), protocol),
+                };
+            }
+            Ok(_) => (),
+            Err(WrongType) => {
+                return serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string()))
+            }
+        }
+        let timed_out = match (block, deadline) {
+            (None, _) => true,
+            (Some(_), None) => {
+                watch.changed().await;
+                false
+            }
+            (Some(_), Some(deadline)) => time::timeout_at(deadline, watch.changed()).await.is_err(),
+        };
+        if timed_out {
+            return match protocol {
+                Protocol::Resp2 => serialize_resp_data(RespType::NullArray),
+                Protocol::Resp3 => serialize_resp_data(RespType::Null),
+            };
+        }
+    }
+}
+
+pub fn handle_get(key: String, db: &Store, protocol: Protocol) -> Reply {
+    let shard = db.read(&key);
+    match shard.get(&key) {
+        Some(entry) => match entry.value.as_str() {
+            Ok(x) => Reply::bulk(x.to_bytes()),
+            Err(WrongType) => {
+                serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string())).into()
+            }
+        },
+        None => serialize_for(RespType::Null, protocol).into(),
+    }
+}
+
+pub fn handle_del(keys: Vec<String>, db: &Store, lazy: bool) -> Vec<u8> {
+    let mut num_deleted = 0;
+    for key in keys {
+        let removed = db.write(&key).remove(&key);
+        if let Some(entry) = removed {
+            lazyfree::free(entry, lazy);
+            db.notify(NotifyFlags::GENERIC, "del", &key);
+            num_deleted += 1;
+        }
+    }
+    serialize_resp_data(RespType::Integer(num_deleted))
+}
+
+// A key given more than once is counted every time, as in Redis
+pub fn handle_exists(keys: Vec<String>, db: &Store) -> Vec<u8> {
+    let count = keys
+        .iter()
+        .filter(|key| db.read(key).peek(key).is_some())
+        .count();
+    serialize_resp_data(RespType::Integer(count as i64))
+}
+
+// Moves the value, and its expiration, over whatever `new_key` held
+pub fn handle_rename(key: String, new_key: String, db: &Store) -> Vec<u8> {
+    let mut guard = db.write_keys(&[&key, &new_key]);
+    let reply = match guard.shard(&key).remove(&key) {
+        Some(entry) => {
+            let entry = Arc::unwrap_or_clone(entry);
+            db.notify(NotifyFlags::GENERIC, "rename_from", &key);
+            db.notify(NotifyFlags::GENERIC, "rename_to", &new_key);
+            guard.shard(&new_key).insert(new_key, entry);
+            RespType::SimpleString(String::from("OK"))
+        }
+        None => RespType::Error(String::from("ERR no such key")),
+    };
+    serialize_resp_data(reply)
+}
+
+pub fn handle_randomkey(db: &Store, protocol: Protocol) -> Vec<u8> {
+    match db.random_key() {
+        Some(key) => serialize_resp_data(RespType::BulkString(Some(Bytes::from(key)))),
+        None => serialize_for(RespType::Null, protocol),
+    }
+}
+
+// Deletes every key in `databases`, which is just the selected one for FLUSHDB
+pub fn handle_flush(databases: &[Store], lazy: bool) -> Vec<u8> {
+    for db in databases {
+        db.flush(lazy);
+    }
+    serialize_resp_data(RespType::SimpleString(String::from("OK")))
+}
+
+pub fn handle_swapdb(first: String, second: String, databases: &[Store]) -> Vec<u8> {
+    let in_range = |x: i64| usize::try_from(x).ok().filter(|x| *x < databases.len());
+    let reply = match (first.parse::<i64>(), second.parse::<i64>()) {
+        (Err(_), _) => RespType::Error(String::from("ERR invalid first DB index")),
+        (_, Err(_)) => RespType::Error(String::from("ERR invalid second DB index")),
+        (Ok(first), Ok(second)) => match (in_range(first), in_range(second)) {
+            (Some(first), Some(second)) => {
+                if first != second {
+                    databases[first.min(second)].swap(&databases[first.max(second)]);
                 }
+                RespType::SimpleString(String::from("OK"))
             }
-            _ => panic!("Expected REPLCONF response to GETACK"),
+            _ => RespType::Error(String::from("ERR DB index is out of range")),
+        },
+    };
+    serialize_resp_data(reply)
+}
+
+// The LRU and LFU bookkeeping share an entry's access stamp, so only the one the eviction policy
+// keeps up is reported, as in Redis
+pub fn handle_object(
+    subcommand: String,
+    key: String,
+    policy: EvictionPolicy,
+    db: &Store,
+    protocol: Protocol,
+) -> Vec<u8> {
+    let shard = db.read(&key);
+    // Introspection mustn't count as an access
+    let entry = match shard.peek(&key) {
+        Some(x) => x,
+        None => return serialize_for(RespType::Null, protocol),
+    };
+    let response = match subcommand.to_lowercase().as_str() {
+        "idletime" if policy.is_lfu() => RespType::Error(format!(
+            "ERR An LFU maxmemory policy is selected, idle time not tracked. {}",
+            POLICY_SWITCH_NOTE
+        )),
+        "idletime" => RespType::Integer((entry.idle_ms() / 1000) as i64),
+        "freq" if !policy.is_lfu() => RespType::Error(format!(
+            "ERR An LFU maxmemory policy is not selected, access frequency not tracked. {}",
+            POLICY_SWITCH_NOTE
+        )),
+        "freq" => RespType::Integer(entry.access_frequency() as i64),
+        "encoding" => RespType::BulkString(Some(Bytes::from(entry.value.encoding()))),
+        // Values are never shared between keys
+        "refcount" => RespType::Integer(1),
+        other => RespType::Error(format!("ERR unknown subcommand '{}'", other)),
+    };
+    serialize_resp_data(response)
+}
+
+const POLICY_SWITCH_NOTE: &str = "Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.";
+
+pub fn handle_type(key: String, db: &Store) -> Vec<u8> {
+    let type_name = match db.read(&key).get(&key) {
+        Some(entry) => entry.value.type_name(),
+        None => "none",
+    };
+    serialize_resp_data(RespType::SimpleString(String::from(type_name)))
+}
+
+// STRLEN, LLEN and the like, which are all 0 for missing keys
+pub fn handle_len(key: String, type_name: &'static str, db: &Store) -> Vec<u8> {
+    let len = match db.read(&key).get(&key) {
+        Some(entry) if entry.value.type_name() != type_name => {
+            return serialize_resp_data(RespType::Error(WRONGTYPE_ERROR.to_string()))
         }
+        Some(entry) => entry.value.len(),
+        None => 0,
+    };
+    serialize_resp_data(RespType::Integer(len as i64))
+}
+
+pub fn handle_memory_usage(key: String, db: &Store, protocol: Protocol) -> Vec<u8> {
+    match db.read(&key).peek(&key) {
+        Some(entry) => serialize_resp_data(RespType::Integer(entry.size() as i64)),
+        None => serialize_for(RespType::Null, protocol),
     }
+}
 
-    let response = serialize_resp_data(RespType::Integer(up_to_date_replicas as i64));
-    let mut stream = stream.write().await;
-    let _ = stream.write_all(response.as_bytes()).await;
+pub fn handle_latency_histogram(
+    latency: &LatencyStats,
+    commands: Vec<String>,
+    protocol: Protocol,
+) -> Vec<u8> {
+    let bulk = |value: &str| RespType::BulkString(Some(Bytes::from(value.to_string())));
+    let histograms = latency
+        .called(&commands)
+        .into_iter()
+        .map(|(name, histogram)| {
+            let buckets = histogram
+                .cumulative()
+                .into_iter()
+                .map(|(usec, calls)| {
+                    (
+                        RespType::Integer(usec as i64),
+                        RespType::Integer(calls as i64),
+                    )
+                })
+                .collect();
+            (
+                bulk(name),
+                RespType::Map(vec![
+                    (bulk("calls"), RespType::Integer(histogram.calls() as i64)),
+                    (bulk("histogram_usec"), RespType::Map(buckets)),
+                ]),
+            )
+        })
+        .collect();
+    serialize_for(RespType::Map(histograms), protocol)
+}
+
+// What COMMAND and COMMAND INFO say about a command, in Redis's shape: its name, arity, flags,
+// first key, last key and step, ACL categories, tips, key specs and subcommands
+fn command_reply(name: &str, spec: Option<&CommandSpec>, subcommands: &[&CommandSpec]) -> RespType {
+    let bulk = |value: &str| RespType::BulkString(Some(Bytes::from(value.to_string())));
+    let status = |values: Vec<&str>| {
+        RespType::Set(
+            values
+                .into_iter()
+                .map(|x| RespType::SimpleString(x.to_string()))
+                .collect(),
+        )
+    };
+    // Containers that can't be run on their own need a subcommand
+    let (first, last, step) = spec.map_or((0, 0, 0), |x| x.keys.range());
+    RespType::Array(vec![
+        bulk(name),
+        RespType::Integer(spec.map_or(-2, |x| x.arity as i64)),
+        status(spec.map_or(Vec::new(), |x| x.flag_names())),
+        RespType::Integer(first),
+        RespType::Integer(last),
+        RespType::Integer(step),
+        status(spec.map_or(Vec::new(), |x| x.acl_categories())),
+        RespType::Array(Vec::new()),
+        RespType::Array(Vec::new()),
+        RespType::Array(
+            subcommands
+                .iter()
+                .map(|x| command_reply(x.name, Some(x), &[]))
+                .collect(),
+        ),
+    ])
+}
+
+pub fn handle_command_list(protocol: Protocol) -> Vec<u8> {
+    let commands = command_table::top_level()
+        .iter()
+        .map(|x| command_reply(x.name, x.spec, &x.subcommands))
+        .collect();
+    serialize_for(RespType::Array(commands), protocol)
+}
+
+pub fn handle_command_count() -> Vec<u8> {
+    serialize_resp_data(RespType::Integer(command_table::top_level().len() as i64))
+}
+
+// Each command named, be it a top level one or a subcommand like "config|get", or every command
+// if none are
+pub fn handle_command_info(names: Vec<String>, protocol: Protocol) -> Vec<u8> {
+    if names.is_empty() {
+        return handle_command_list(protocol);
+    }
+    let commands = command_table::top_level();
+    let infos = names
+        .iter()
+        .map(
+            |name| match commands.iter().find(|x| x.name.eq_ignore_ascii_case(name)) {
+                Some(x) => command_reply(x.name, x.spec, &x.subcommands),
+                None => match command_table::lookup(name) {
+                    Some(x) => command_reply(x.name, Some(x), &[]),
+                    None => RespType::Null,
+                },
+            },
+        )
+        .collect();
+    serialize_for(RespType::Array(infos), protocol)
+}
+
+// What's known of each command beyond COMMAND INFO, by name: its group, and its subcommands'.
+// Unknown commands are left out.
+pub fn handle_command_docs(names: Vec<String>, protocol: Protocol) -> Vec<u8> {
+    let bulk = |value: &str| RespType::BulkString(Some(Bytes::from(value.to_string())));
+    let doc = |spec: &CommandSpec| {
+        (
+            bulk(spec.name),
+            RespType::Map(vec![(bulk("group"), bulk(spec.group))]),
+        )
+    };
+    let docs = command_table::top_level()
+        .into_iter()
+        .filter(|x| names.is_empty() || names.iter().any(|y| y.eq_ignore_ascii_case(x.name)))
+        .map(|x| {
+            let group = x.spec.or(x.subcommands.first().copied()).map(|x| x.group);
+            let mut fields = vec![(bulk("group"), bulk(group.unwrap_or("server")))];
+            if !x.subcommands.is_empty() {
+                fields.push((
+                    bulk("subcommands"),
+                    RespType::Map(x.subcommands.iter().map(|x| doc(x)).collect()),
+                ));
+            }
+            (bulk(x.name), RespType::Map(fields))
+        })
+        .collect();
+    serialize_for(RespType::Map(docs), protocol)
+}
+
+// The keys in a full command, found the way the command table says to find them
+pub fn handle_command_getkeys(argv: Vec<Bytes>) -> Vec<u8> {
+    let error = |x: &str| RespType::Error(x.to_string());
+    let reply = match command_table::lookup_argv(&argv) {
+        None => error("ERR Invalid command specified"),
+        Some(spec) if !spec.accepts(argv.len()) => {
+            error("ERR Invalid number of arguments specified for command")
+        }
+        Some(spec) => match spec.keys.positions(&argv) {
+            keys if keys.is_empty() => error("ERR The command has no key arguments"),
+            keys => RespType::Array(
+                keys.into_iter()
+                    .map(|x| RespType::BulkString(Some(argv[x].clone())))
+                    .collect(),
+            ),
+        },
+    };
+    serialize_resp_data(reply)
+}
+
+// The role along with where replication stands, in ROLE's fixed shape
+pub async fn handle_role(server: &ServerState) -> Vec<u8> {
+    let replication = &server.replication;
+    let bulk = |value: &str| RespType::BulkString(Some(Bytes::from(value.to_string())));
+    let role = match server.config.current().role {
+        RedisState::Master => {
+            let replicas = replication
+                .replicas
+                .read()
+                .await
+                .values()
+                .map(|link| {
+                    RespType::Array(vec![
+                        bulk(&link.ip),
+                        bulk(&link.listening_port.unwrap_or(0).to_string()),
+                        bulk(&link.ack.offset().to_string()),
+                    ])
+                })
+                .collect();
+            vec![
+                bulk("master"),
+                RespType::Integer(replication.master_offset.load(Ordering::SeqCst) as i64),
+                RespType::Array(replicas),
+            ]
+        }
+        RedisState::Replica => {
+            let config = &server.config.current();
+            let state = match replication.link.state() {
+                LinkState::Down => "connect",
+                LinkState::Syncing => "sync",
+                LinkState::Up => "connected",
+            };
+            vec![
+                bulk("slave"),
+                bulk(config.master_host.as_deref().unwrap_or("")),
+                RespType::Integer(
+                    config
+                        .master_port
+                        .as_ref()
+                        .and_then(|x| x.parse().ok())
+                        .unwrap_or(0),
+                ),
+                bulk(state),
+                RespType::Integer(replication.offset.get() as i64),
+            ]
+        }
+    };
+    serialize_resp_data(RespType::Array(role))
+}
+
+// Every parameter matching any of the patterns, each listed once
+pub fn handle_config_get(config: &Config, patterns: Vec<String>, protocol: Protocol) -> Vec<u8> {
+    let mut names: Vec<&str> = Vec::new();
+    for pattern in &patterns {
+        for name in Config::matching_parameters(pattern) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    let bulk = |value: &str| RespType::BulkString(Some(Bytes::from(value.to_string())));
+    let pairs = names
+        .into_iter()
+        .map(|name| {
+            let value = config
+                .get(name)
+                .expect("Expected every parameter to have a value");
+            (bulk(name), bulk(&value))
+        })
+        .collect();
+    serialize_for(RespType::Map(pairs), protocol)
+}
+
+// Sets every parameter or, if any of them can't be set, none of them
+pub fn handle_config_set(server: &ServerState, pairs: Vec<(String, String)>) -> Vec<u8> {
+    let result = server.config.update(|config| {
+        for (index, (name, value)) in pairs.iter().enumerate() {
+            let failed = |reason: &str| {
+                format!(
+                    "ERR CONFIG SET failed (possibly related to argument '{}') - {}",
+                    name, reason
+                )
+            };
+            if pairs[..index].iter().any(|(x, _)| x == name) {
+                return Err(failed("duplicate parameter"));
+            }
+            match config.set(name, value) {
+                Ok(()) => (),
+                Err(ConfigSetError::Unknown) => {
+                    return Err(format!(
+                        "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                        name
+                    ))
+                }
+                Err(ConfigSetError::Immutable) => return Err(failed("can't set immutable config")),
+                Err(ConfigSetError::Invalid(reason)) => return Err(failed(&reason)),
+            }
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        return serialize_resp_data(RespType::Error(e));
+    }
+    // Most parameters are read as they're needed, these are held elsewhere
+    let config = server.config.current();
+    lru::set_lfu_params(config.lfu_log_factor, config.lfu_decay_time);
+    log::set_level(config.loglevel);
+    server
+        .replication
+        .backlog
+        .lock()
+        .unwrap()
+        .resize(config.repl_backlog_size);
+    if let Some(writer) = server.aof.get() {
+        writer.set_fsync(config.appendfsync);
+    }
+    shared::OK.to_vec()
+}
+
+pub fn handle_acl_setuser(acl: &Acl, username: String, rules: Vec<String>) -> Vec<u8> {
+    match acl.set_user(&username, &rules) {
+        Ok(()) => shared::OK.to_vec(),
+        Err(e) => serialize_resp_data(RespType::Error(e)),
+    }
+}
+
+pub fn handle_acl_list(acl: &Acl) -> Vec<u8> {
+    let users = acl
+        .list()
+        .into_iter()
+        .map(|x| RespType::BulkString(Some(Bytes::from(x))))
+        .collect();
+    serialize_resp_data(RespType::Array(users))
+}
+
+const DEFAULT_ACL_LOG_COUNT: usize = 10;
+
+pub fn handle_acl_log(acl: &Acl, arg: Option<String>, protocol: Protocol) -> Vec<u8> {
+    let count = match arg.map(|x| (x.parse::<i64>(), x)) {
+        None => DEFAULT_ACL_LOG_COUNT,
+        Some((_, x)) if x.eq_ignore_ascii_case("reset") => {
+            acl.reset_log();
+            return shared::OK.to_vec();
+        }
+        Some((Ok(x), _)) if x >= 0 => x as usize,
+        Some((Ok(_), _)) => {
+            return serialize_resp_data(RespType::Error(String::from(
+                "ERR value is out of range, must be positive",
+            )))
+        }
+        Some((Err(_), _)) => {
+            return serialize_resp_data(RespType::Error(String::from(
+                "ERR value is not an integer or out of range",
+            )))
+        }
+    };
+    let bulk = |value: &str| RespType::BulkString(Some(Bytes::from(value.to_string())));
+    let now = clock::unix_ms();
+    let entries = acl
+        .log(count)
+        .into_iter()
+        .map(|x| {
+            RespType::Map(vec![
+                (bulk("count"), RespType::Integer(x.count as i64)),
+                (bulk("reason"), bulk(&x.reason.to_string())),
+                (bulk("context"), bulk(&x.context.to_string())),
+                (bulk("object"), bulk(&x.object)),
+                (bulk("username"), bulk(&x.username)),
+                (
+                    bulk("age-seconds"),
+                    RespType::Double(now.saturating_sub(x.created) as f64 / 1000.0),
+                ),
+                (bulk("client-info"), bulk(&x.client_info)),
+                (bulk("entry-id"), RespType::Integer(x.entry_id as i64)),
+                (
+                    bulk("timestamp-created"),
+                    RespType::Integer(x.created as i64),
+                ),
+                (
+                    bulk("timestamp-last-updated"),
+                    RespType::Integer(x.updated as i64),
+                ),
+            ])
+        })
+        .collect();
+    serialize_for(RespType::Array(entries), protocol)
+}
+
+const DEFAULT_SLOWLOG_COUNT: usize = 10;
+
+pub fn handle_slowlog_get(slowlog: &SlowLog, arg: Option<String>) -> Vec<u8> {
+    let count = match arg.map(|x| x.parse::<i64>()) {
+        None => DEFAULT_SLOWLOG_COUNT,
+        Some(Ok(-1)) => usize::MAX,
+        Some(Ok(x)) if x >= 0 => x as usize,
+        Some(Ok(_)) => {
+            return serialize_resp_data(RespType::Error(String::from(
+                "ERR count should be greater than or equal to -1",
+            )))
+        }
+        Some(Err(_)) => {
+            return serialize_resp_data(RespType::Error(String::from(
+                "ERR value is not an integer or out of range",
+            )))
+        }
+    };
+    let bulk = |value: Bytes| RespType::BulkString(Some(value));
+    let entries = slowlog
+        .get(count)
+        .into_iter()
+        .map(|x| {
+            RespType::Array(vec![
+                RespType::Integer(x.id as i64),
+                RespType::Integer(x.timestamp as i64),
+                RespType::Integer(x.duration_us as i64),
+                RespType::Array(x.args.into_iter().map(bulk).collect()),
+                bulk(Bytes::from(x.peer)),
+                bulk(Bytes::from(x.client_name)),
+            ])
+        })
+        .collect();
+    serialize_resp_data(RespType::Array(entries))
+}
+
+// Works off a snapshot, so listing a big keyspace doesn't hold up writes
+pub fn handle_keys(snapshot: &Snapshot, pattern: String) -> Vec<u8> {
+    let resp_keys: Vec<RespType> = snapshot
+        .iter()
+        .filter(|(key, _)| glob::matches(pattern.as_bytes(), key.as_bytes()))
+        .map(|(key, _)| RespType::BulkString(Some(Bytes::from(key.clone()))))
+        .collect();
+    serialize_resp_data(RespType::Array(resp_keys))
+}
+
+pub fn handle_scan(
+    cursor: u64,
+    count: usize,
+    pattern: Option<String>,
+    type_name: Option<String>,
+    db: &Store,
+) -> Vec<u8> {
+    let (next_cursor, keys) = db.scan(cursor, count);
+    // Like Redis, the filters are applied after a batch is chosen, so it may come back short or
+    // even empty with the iteration still going
+    let resp_keys: Vec<RespType> = keys
+        .into_iter()
+        .filter(|key| {
+            pattern
+                .as_ref()
+                .is_none_or(|x| glob::matches(x.as_bytes(), key.as_bytes()))
+        })
+        .filter(|key| match &type_name {
+            Some(x) => db
+                .read(key)
+                .peek(key)
+                .is_some_and(|entry| entry.value.type_name() == x),
+            None => true,
+        })
+        .map(|key| RespType::BulkString(Some(Bytes::from(key))))
+        .collect();
+    serialize_resp_data(RespType::Array(vec![
+        RespType::BulkString(Some(Bytes::from(next_cursor.to_string()))),
+        RespType::Array(resp_keys),
+    ]))
+}
+
+// Waits until `replicas_to_wait_for` replicas have acknowledged every write propagated so far, by
+// any connection, asking the ones that haven't for their offset. A timeout of 0 waits
+// forever, as in Redis.
+pub async fn handle_wait(server: &ServerState, replicas_to_wait_for: i32, timeout: i32) -> Vec<u8> {
+    let replication = &server.replication;
+    let (target, acks): (usize, Vec<Arc<ReplicaAck>>) = {
+        let connections = replication.replicas.read().await;
+        (
+            replication.write_offset.load(Ordering::SeqCst),
+            connections
+                .values()
+                .map(|link| Arc::clone(&link.ack))
+                .collect(),
+        )
+    };
+    let lagging: Vec<Arc<ReplicaAck>> = acks
+        .iter()
+        .filter(|x| x.offset() < target)
+        .cloned()
+        .collect();
+    let up_to_date = acks.len() - lagging.len();
+    if lagging.is_empty() || up_to_date >= replicas_to_wait_for.max(0) as usize {
+        return serialize_resp_data(RespType::Integer(up_to_date as i64));
+    }
+
+    request_acks(replication).await;
+    let mut waiting = JoinSet::new();
+    for ack in lagging {
+        waiting.spawn(async move { ack.wait_for(target).await });
+    }
+    let enough = async {
+        let mut acked = up_to_date;
+        while acked < replicas_to_wait_for as usize && waiting.join_next().await.is_some() {
+            acked += 1;
+        }
+    };
+    if timeout > 0 {
+        let _ = time::timeout(Duration::from_millis(timeout as u64), enough).await;
+    } else {
+        enough.await;
+    }
+    let up_to_date = acks.iter().filter(|x| x.offset() >= target).count();
+    serialize_resp_data(RespType::Integer(up_to_date as i64))
 }
@@ -0,0 +1,238 @@
+use super::glob;
+
+use crate::resp::resp_serializer::serialize_for;
+use crate::resp::{Protocol, RespType};
+
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet};
+use std::future;
+use std::sync::Mutex;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+// SUBSCRIBE's channels as opposed to PSUBSCRIBE's patterns
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Kind {
+    Channel,
+    Pattern,
+}
+
+// A published message on its way to one subscriber
+#[derive(Debug, Clone)]
+pub struct Message {
+    // The pattern the subscriber matched the channel with, for PSUBSCRIBE
+    pub pattern: Option<Bytes>,
+    pub channel: Bytes,
+    pub payload: Bytes,
+}
+
+impl Message {
+    // Pushed to RESP3 clients, while RESP2 ones get the same as an array
+    pub fn serialize(self, protocol: Protocol) -> Vec<u8> {
+        let bulk = |x: Bytes| RespType::BulkString(Some(x));
+        let frame = match self.pattern {
+            Some(pattern) => vec![
+                bulk(Bytes::from("pmessage")),
+                bulk(pattern),
+                bulk(self.channel),
+                bulk(self.payload),
+            ],
+            None => vec![
+                bulk(Bytes::from("message")),
+                bulk(self.channel),
+                bulk(self.payload),
+            ],
+        };
+        serialize_for(RespType::Push(frame), protocol)
+    }
+}
+
+type Subscribers = HashMap<Bytes, HashMap<u64, UnboundedSender<Message>>>;
+
+// Who's subscribed to what, shared by every connection. Subscribers are reached through their
+// client's message queue, see Subscriptions, under the client's id.
+#[derive(Default)]
+pub struct PubSub {
+    channels: Mutex<Subscribers>,
+    patterns: Mutex<Subscribers>,
+}
+
+impl PubSub {
+    // Sends `payload` to every subscriber of `channel`, and to every subscriber of a pattern
+    // matching it, returning how many messages were sent. Clients that have gone away without
+    // unsubscribing are dropped along the way.
+    pub fn publish(&self, channel: &Bytes, payload: &Bytes) -> usize {
+        let mut receivers = 0;
+        let mut send = |subscribers: &mut HashMap<u64, UnboundedSender<Message>>,
+                        pattern: Option<&Bytes>| {
+            subscribers.retain(|_, sender| {
+                let message = Message {
+                    pattern: pattern.cloned(),
+                    channel: channel.clone(),
+                    payload: payload.clone(),
+                };
+                let sent = sender.send(message).is_ok();
+                receivers += sent as usize;
+                sent
+            });
+        };
+
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(subscribers) = channels.get_mut(channel) {
+            send(subscribers, None);
+            if subscribers.is_empty() {
+                channels.remove(channel);
+            }
+        }
+        drop(channels);
+
+        let mut patterns = self.patterns.lock().unwrap();
+        for (pattern, subscribers) in patterns.iter_mut() {
+            if glob::matches(pattern, channel) {
+                send(subscribers, Some(pattern));
+            }
+        }
+        patterns.retain(|_, subscribers| !subscribers.is_empty());
+        receivers
+    }
+
+    pub fn subscribe(
+        &self,
+        client: u64,
+        subscriptions: &mut Subscriptions,
+        kind: Kind,
+        name: Bytes,
+    ) {
+        let sender = subscriptions.sender();
+        if subscriptions.of_kind(kind).insert(name.clone()) {
+            self.registry(kind)
+                .lock()
+                .unwrap()
+                .entry(name)
+                .or_default()
+                .insert(client, sender);
+        }
+    }
+
+    pub fn unsubscribe(
+        &self,
+        client: u64,
+        subscriptions: &mut Subscriptions,
+        kind: Kind,
+        name: &Bytes,
+    ) {
+        if !subscriptions.of_kind(kind).remove(name) {
+            return;
+        }
+        let mut registry = self.registry(kind).lock().unwrap();
+        if let Some(subscribers) = registry.get_mut(name) {
+            subscribers.remove(&client);
+            if subscribers.is_empty() {
+                registry.remove(name);
+            }
+        }
+    }
+
+    // For clients that are going away
+    pub fn unsubscribe_all(&self, client: u64, subscriptions: &mut Subscriptions) {
+        for kind in [Kind::Channel, Kind::Pattern] {
+            for name in subscriptions.names(kind) {
+                self.unsubscribe(client, subscriptions, kind, &name);
+            }
+        }
+    }
+
+    fn registry(&self, kind: Kind) -> &Mutex<Subscribers> {
+        match kind {
+            Kind::Channel => &self.channels,
+            Kind::Pattern => &self.patterns,
+        }
+    }
+}
+
+// A client's side of pub/sub: what it's subscribed to, and the queue its messages wait in until
+// the client gets to write them out
+#[derive(Default)]
+pub struct Subscriptions {
+    channels: HashSet<Bytes>,
+    patterns: HashSet<Bytes>,
+    // Made on the first subscription
+    queue: Option<(UnboundedSender<Message>, UnboundedReceiver<Message>)>,
+}
+
+impl Subscriptions {
+    // Channels and patterns together, which is the count subscription replies carry
+    pub fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    // Whether the client is in subscriber mode, which in RESP2 restricts what it may run
+    pub fn is_active(&self) -> bool {
+        self.count() > 0
+    }
+
+    pub fn names(&self, kind: Kind) -> Vec<Bytes> {
+        match kind {
+            Kind::Channel => self.channels.iter().cloned().collect(),
+            Kind::Pattern => self.patterns.iter().cloned().collect(),
+        }
+    }
+
+    // Resolves with the next message published to the client. Never resolves for clients that
+    // haven't subscribed to anything yet.
+    pub async fn next_message(&mut self) -> Message {
+        match &mut self.queue {
+            Some((_, receiver)) => receiver
+                .recv()
+                .await
+                .expect("Expected the queue to outlive its own sender"),
+            None => future::pending().await,
+        }
+    }
+
+    fn sender(&mut self) -> UnboundedSender<Message> {
+        self.queue
+            .get_or_insert_with(mpsc::unbounded_channel)
+            .0
+            .clone()
+    }
+
+    fn of_kind(&mut self, kind: Kind) -> &mut HashSet<Bytes> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn messages_reach_channel_and_pattern_subscribers() {
+        let pubsub = PubSub::default();
+        let (mut first, mut second) = (Subscriptions::default(), Subscriptions::default());
+        pubsub.subscribe(1, &mut first, Kind::Channel, Bytes::from("news"));
+        pubsub.subscribe(1, &mut first, Kind::Channel, Bytes::from("news"));
+        pubsub.subscribe(2, &mut second, Kind::Pattern, Bytes::from("n*"));
+        assert_eq!(first.count(), 1);
+
+        let payload = Bytes::from("hi");
+        assert_eq!(pubsub.publish(&Bytes::from("news"), &payload), 2);
+        assert_eq!(pubsub.publish(&Bytes::from("nope"), &payload), 1);
+        assert_eq!(pubsub.publish(&Bytes::from("other"), &payload), 0);
+        assert_eq!(first.next_message().await.pattern, None);
+        let message = second.next_message().await;
+        assert_eq!(message.pattern, Some(Bytes::from("n*")));
+        assert_eq!(message.channel, Bytes::from("news"));
+        assert_eq!(second.next_message().await.channel, Bytes::from("nope"));
+
+        pubsub.unsubscribe_all(1, &mut first);
+        assert!(!first.is_active());
+        assert_eq!(pubsub.publish(&Bytes::from("news"), &payload), 1);
+        // Clients that went away without unsubscribing are forgotten on the next publish
+        drop(second);
+        assert_eq!(pubsub.publish(&Bytes::from("news"), &payload), 0);
+        assert!(pubsub.patterns.lock().unwrap().is_empty());
+    }
+}
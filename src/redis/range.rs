@@ -0,0 +1,60 @@
+use std::ops::Range;
+
+// Resolves a command's inclusive start and end indices against a value of `len` elements, as
+// GETRANGE, LRANGE, LTRIM and ZRANGE all do. Negative indices count back from the end, -1 being
+// the last element, and indices past either end are clamped to it. The result can be used to
+// slice the value directly, and is empty when the indices select nothing.
+pub fn normalize(start: i64, end: i64, len: usize) -> Range<usize> {
+    let len = len as i64;
+    let start = if start < 0 { start + len } else { start }.max(0);
+    let end = if end < 0 { end + len } else { end }.min(len - 1);
+    if start > end {
+        return 0..0;
+    }
+    start as usize..end as usize + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indices_are_resolved_like_redis() {
+        let cases = [
+            // In bounds
+            (0, 4, 5, 0..5),
+            (1, 2, 5, 1..3),
+            (2, 2, 5, 2..3),
+            // Negative indices count from the end
+            (0, -1, 5, 0..5),
+            (-2, -1, 5, 3..5),
+            (-5, -5, 5, 0..1),
+            (1, -2, 5, 1..4),
+            // Out of range indices are clamped
+            (0, 100, 5, 0..5),
+            (-100, 1, 5, 0..2),
+            (-100, -100, 5, 0..0),
+            (-100, 100, 5, 0..5),
+            // Nothing is selected once start passes end, before or after resolving them
+            (3, 1, 5, 0..0),
+            (-1, -2, 5, 0..0),
+            (4, -3, 5, 0..0),
+            (5, 10, 5, 0..0),
+            (100, -1, 5, 0..0),
+            // Or when there's nothing to select from
+            (0, -1, 0, 0..0),
+            (0, 0, 0, 0..0),
+            (i64::MIN, i64::MAX, 5, 0..5),
+        ];
+        for (start, end, len, expected) in cases {
+            assert_eq!(
+                normalize(start, end, len),
+                expected,
+                "start {} end {} len {}",
+                start,
+                end,
+                len
+            );
+        }
+    }
+}
@@ -1,138 +1,801 @@
-use std::collections::HashMap;
-use std::sync::Arc;
+use bytes::{Bytes, BytesMut};
+use core::fmt;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration};
 
-use super::construct_rdb;
-use crate::resp::{resp_deserializer::RespParser, resp_serializer::serialize_resp_data, RespType};
-use crate::Redis;
+use super::connect_to_master;
+use super::connection::{Connection, ConnectionWriter};
+use super::identity::random_id;
+use super::output::{ClientClass, OutputBufferLimit, OutputError, OutputLimiter};
+use super::state::{Replication, ServerState, NO_DB_SELECTED};
+use super::store::Store;
+use super::synchronize::{stream_rdb, RDB_CHUNK_SIZE};
+use super::RedisState;
+use crate::log;
+use crate::rdb::RdbParser;
+use crate::resp::{
+    resp_deserializer::{FrameDecoder, RespParser},
+    resp_serializer::serialize_resp_data,
+    shared, RespType,
+};
+use crate::server::ServerError;
+use crate::{debug, notice, verbose, warning};
 
-pub async fn handle_replconf(stream: Arc<RwLock<TcpStream>>) {
-    let response = String::from("+OK\r\n");
-    let mut stream = stream.write().await;
-    let _ = stream.write_all(response.as_bytes()).await;
+pub const MASTERDOWN_ERROR: &str =
+    "MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'.";
+pub const READONLY_ERROR: &str = "READONLY You can't write against a read only replica.";
+pub const WAIT_ON_REPLICA_ERROR: &str =
+    "ERR WAIT cannot be used with replica instances. Please also note that writes to replicas are just local and are not propagated.";
+
+// How much of its master's replication stream a replica has applied, in bytes. Only the master
+// link moves it: it starts at the offset the master gave with FULLRESYNC, and goes up by exactly
+// the bytes of every command applied from the stream after that.
+#[derive(Default)]
+pub struct ReplicaOffset(AtomicUsize);
+
+impl ReplicaOffset {
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn set(&self, offset: usize) {
+        self.0.store(offset, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, bytes: usize) {
+        self.0.fetch_add(bytes, Ordering::SeqCst);
+    }
 }
 
-pub async fn handle_replconf_getack(stream: Arc<RwLock<TcpStream>>, bytes_processed: usize) {
-    let response = RespType::Array(vec![
-        RespType::BulkString(Some(String::from("REPLCONF"))),
-        RespType::BulkString(Some(String::from("ACK"))),
-        RespType::BulkString(Some(bytes_processed.to_string())),
-    ]);
+// Where a replica's connection to its master stands
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum LinkState {
+    // Not connected, or connected but not yet through the handshake
+    Down,
+    // Receiving the RDB from a full resync
+    Syncing,
+    // Applying the replication stream
+    Up,
+}
+
+impl fmt::Display for LinkState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkState::Up => write!(f, "up"),
+            LinkState::Down | LinkState::Syncing => write!(f, "down"),
+        }
+    }
+}
 
-    let serialized_response = serialize_resp_data(response);
+// A replica's link to its master, along with when it last changed state
+pub struct MasterLink(Mutex<(LinkState, Instant)>);
 
-    let mut stream = stream.write().await;
-    let _ = stream.write_all(serialized_response.as_bytes()).await;
+impl Default for MasterLink {
+    fn default() -> Self {
+        MasterLink(Mutex::new((LinkState::Down, Instant::now())))
+    }
 }
 
-pub async fn handle_psync(
-    _replication_id: String,
-    _offset: String,
-    stream: Arc<RwLock<TcpStream>>,
-    db: Arc<Mutex<HashMap<String, String>>>,
+impl MasterLink {
+    pub fn state(&self) -> LinkState {
+        self.0.lock().unwrap().0
+    }
+
+    pub fn set_state(&self, state: LinkState) {
+        let mut link = self.0.lock().unwrap();
+        if link.0 != state {
+            *link = (state, Instant::now());
+        }
+    }
+
+    // Seconds since the link went down, if it isn't up
+    pub fn down_for(&self) -> Option<u64> {
+        match *self.0.lock().unwrap() {
+            (LinkState::Up, _) => None,
+            (_, since) => Some(since.elapsed().as_secs()),
+        }
+    }
+}
+
+// A connected replica. Propagation only queues the stream for it: a task of its own writes the
+// queue to the socket, and another reads the offsets it acknowledges, see register_replica.
+// Dropping the link stops both, closing the connection.
+pub struct ReplicaLink {
+    queue: mpsc::UnboundedSender<Bytes>,
+    // Bytes queued that the writer hasn't handed to the socket yet
+    queued: Arc<AtomicUsize>,
+    limiter: OutputLimiter,
+    tasks: Vec<JoinHandle<()>>,
+    pub ip: String,
+    // The port the replica serves clients on, as it announced with REPLCONF listening-port
+    pub listening_port: Option<u16>,
+    pub ack: Arc<ReplicaAck>,
+}
+
+// The latest replication offset a replica has acknowledged with REPLCONF ACK
+pub struct ReplicaAck {
+    offset: AtomicUsize,
+    received: Notify,
+    // Where each GETACK the replica hasn't answered yet sits in the stream. A replica in sync
+    // answers each with exactly that offset, having applied everything before it and nothing
+    // after.
+    requested: Mutex<VecDeque<usize>>,
+}
+
+impl ReplicaAck {
+    // A replica starts out with everything up to the offset its full resync began at
+    pub fn new(offset: usize) -> Self {
+        ReplicaAck {
+            offset: AtomicUsize::new(offset),
+            received: Notify::new(),
+            requested: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset.load(Ordering::SeqCst)
+    }
+
+    // Resolves once the replica has acknowledged at least `offset`
+    pub async fn wait_for(&self, offset: usize) {
+        loop {
+            // Created before checking, so that an ack arriving in between isn't missed
+            let received = self.received.notified();
+            if self.offset() >= offset {
+                return;
+            }
+            received.await;
+        }
+    }
+
+    // Called with the replicas locked, just before a GETACK at `offset` is appended to the stream
+    pub fn expect_answer_at(&self, offset: usize) {
+        self.requested.lock().unwrap().push_back(offset);
+    }
+
+    // Records an ack, unless it shows that the replica has applied a different stream than the
+    // one it was sent. `master_offset` is how much has been sent to it. Acks the replica sends
+    // of its own accord may come in between the answers to GETACKs, but never jump past one.
+    fn record(&self, offset: usize, master_offset: usize) -> Result<(), String> {
+        if offset > master_offset {
+            return Err(format!(
+                "acked offset {} when only {} has been sent",
+                offset, master_offset
+            ));
+        }
+        if offset < self.offset() {
+            return Err(format!(
+                "acked offset {} after having acked {}",
+                offset,
+                self.offset()
+            ));
+        }
+        {
+            let mut requested = self.requested.lock().unwrap();
+            match requested.front() {
+                Some(x) if offset > *x => {
+                    return Err(format!(
+                        "acked offset {} without answering the GETACK at {}",
+                        offset, x
+                    ))
+                }
+                Some(x) if offset == *x => {
+                    requested.pop_front();
+                }
+                _ => (),
+            }
+        }
+        self.offset.fetch_max(offset, Ordering::SeqCst);
+        self.received.notify_waiters();
+        Ok(())
+    }
+}
+
+impl ReplicaLink {
+    // Queues `data` without waiting on the replica. Fails once the replica has gone away or has
+    // fallen too far behind, see OutputLimiter. An empty `data` just checks on it.
+    pub fn write(&mut self, data: Bytes) -> Result<(), OutputError> {
+        if !data.is_empty() {
+            self.queued.fetch_add(data.len(), Ordering::SeqCst);
+            if self.queue.send(data).is_err() {
+                return Err(OutputError::Io(io::Error::from(io::ErrorKind::BrokenPipe)));
+            }
+        }
+        self.limiter.check(self.queued.load(Ordering::SeqCst))
+    }
+}
+
+impl Drop for ReplicaLink {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+// Takes over a connection that has just sent PSYNC. From here on it only carries the replication
+// stream one way and acks the other, so the connection task is done with it. `buffered` is
+// whatever the connection had already read past the PSYNC. A replica asking to carry on from an
+// offset the backlog still covers gets the rest of the stream from there, any other gets a
+// snapshot of the dataset first.
+pub async fn serve_psync(
+    server: &Arc<ServerState>,
+    stream: TcpStream,
+    buffered: BytesMut,
+    replid: String,
+    offset: String,
+    listening_port: Option<u16>,
+    capa_eof: bool,
+) -> Result<(), ServerError> {
+    let config = server.config.current();
+    let limit = config.client_output_buffer_limits.replica;
+    // Offsets in PSYNC count from 1, so a replica asks for the byte after the last one it applied
+    let continued_from = match offset.parse::<usize>() {
+        Ok(next) if next > 0 && replid == *server.replication.replid.lock().unwrap() => {
+            Some(next - 1)
+        }
+        _ => None,
+    };
+    let (mut stream, buffered) = match continued_from {
+        Some(offset) => {
+            let reply = serialize_resp_data(RespType::SimpleString(format!("CONTINUE {}", replid)));
+            match register_replica(
+                server,
+                stream,
+                buffered,
+                offset,
+                &reply,
+                listening_port,
+                limit,
+            )
+            .await
+            {
+                Ok(()) => {
+                    notice!(
+                        "Partial resync with replica accepted from offset {}",
+                        offset
+                    );
+                    return Ok(());
+                }
+                Err(x) => x,
+            }
+        }
+        None => (stream, buffered),
+    };
+    let timeout = Duration::from_secs(config.repl_timeout);
+    let offset = handle_psync(&mut stream, server, capa_eof, timeout).await?;
+    // Writes made while the snapshot was on its way follow it from the backlog
+    match register_replica(server, stream, buffered, offset, &[], listening_port, limit).await {
+        Ok(()) => Ok(()),
+        Err(_) => Err("the backlog no longer covers the writes made during the transfer".into()),
+    }
+}
+
+// Starts streaming to a replica that has everything up to `offset`, sending it `reply` followed
+// by whatever the backlog holds after that. The connection comes back if the backlog doesn't go
+// back that far.
+async fn register_replica(
+    server: &Arc<ServerState>,
+    stream: TcpStream,
+    buffered: BytesMut,
+    offset: usize,
+    reply: &[u8],
+    listening_port: Option<u16>,
+    limit: OutputBufferLimit,
+) -> Result<(), (TcpStream, BytesMut)> {
+    let replication = &server.replication;
+    let mut connections = replication.replicas.write().await;
+    let missed = match replication.backlog.lock().unwrap().since(offset) {
+        Some(x) => x,
+        None => return Err((stream, buffered)),
+    };
+    let fd = stream.as_raw_fd();
+    let ip = stream
+        .peer_addr()
+        .map_or(String::from("unknown"), |x| x.ip().to_string());
+    let (reader, writer) = stream.into_split();
+    let ack = Arc::new(ReplicaAck::new(offset));
+    let (queue, queue_receiver) = mpsc::unbounded_channel();
+    let queued = Arc::new(AtomicUsize::new(0));
+    // Neither task can get at the replicas before they're unlocked, by which time the link is in
+    let log_context = format!("replica fd={} ip={}", fd, ip);
+    let tasks = vec![
+        tokio::spawn(log::scope(
+            log_context.clone(),
+            write_queued(
+                Arc::clone(server),
+                fd,
+                writer,
+                queue_receiver,
+                Arc::clone(&queued),
+                Arc::clone(&ack),
+            ),
+        )),
+        tokio::spawn(log::scope(
+            log_context,
+            read_acks(Arc::clone(server), fd, reader, buffered, Arc::clone(&ack)),
+        )),
+    ];
+    let mut link = ReplicaLink {
+        queue,
+        queued,
+        limiter: OutputLimiter::new(ClientClass::Replica, limit),
+        tasks,
+        ip,
+        listening_port,
+        ack,
+    };
+    if let Err(e) = link.write(Bytes::from([reply, &missed].concat())) {
+        warning!("Disconnecting replica {}: {}", fd, e);
+        return Ok(());
+    }
+    let _ = connections.insert(fd, link);
+    Ok(())
+}
+
+// Writes whatever is queued for the replica to its socket, in order, until the socket fails or
+// the link is dropped
+async fn write_queued(
+    server: Arc<ServerState>,
+    fd: i32,
+    mut writer: OwnedWriteHalf,
+    mut queue: mpsc::UnboundedReceiver<Bytes>,
+    queued: Arc<AtomicUsize>,
+    ack: Arc<ReplicaAck>,
 ) {
-    {
-        let mut stream = stream.write().await;
+    while let Some(data) = queue.recv().await {
+        if let Err(e) = writer.write_all(&data).await {
+            warning!("Error writing to replica {}: {}", fd, e);
+            break;
+        }
+        queued.fetch_sub(data.len(), Ordering::SeqCst);
+    }
+    remove_replica(&server, fd, &ack).await;
+}
 
-        let repl_id = "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb";
-        let response =
-            serialize_resp_data(RespType::SimpleString(format!("FULLRESYNC {} 0", repl_id)));
-        let (length, binary) = construct_rdb(Arc::clone(&db));
+// Records every REPLCONF ACK the replica sends, until it disconnects or turns out to be out of
+// sync. A desynced replica is dropped rather than left to diverge. Carrying on from an offset it
+// has wrong puts it mid-command in the stream, which makes it give up on the stream and resync
+// in full, see handle_conn.
+async fn read_acks(
+    server: Arc<ServerState>,
+    fd: i32,
+    mut reader: OwnedReadHalf,
+    mut buffer: BytesMut,
+    ack: Arc<ReplicaAck>,
+) {
+    let mut decoder = FrameDecoder::new();
+    loop {
+        match decoder.decode(&mut buffer) {
+            Ok(Some((frame, _))) => match parse_ack(&frame) {
+                Some(offset) => {
+                    let master_offset = server.replication.master_offset.load(Ordering::SeqCst);
+                    if let Err(e) = ack.record(offset, master_offset) {
+                        warning!("Replica {} is out of sync, disconnecting it: {}", fd, e);
+                        break;
+                    }
+                }
+                None => verbose!(
+                    "Ignoring unexpected message from replica {}: {:?}",
+                    fd,
+                    frame
+                ),
+            },
+            Ok(None) => match reader.read_buf(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => (),
+            },
+            Err(e) => {
+                warning!("Replica {} sent a malformed message: {}", fd, e);
+                break;
+            }
+        }
+    }
+    notice!("Replica {} disconnected", fd);
+    remove_replica(&server, fd, &ack).await;
+}
 
-        let _ = stream.write_all(response.as_bytes()).await;
-        let _ = stream.write_all(length.as_bytes()).await;
-        let _ = stream.write_all(&binary).await;
+// Drops the link of the replica at `fd`, identified by its `ack`, if it's still connected
+async fn remove_replica(server: &ServerState, fd: i32, ack: &Arc<ReplicaAck>) {
+    let mut connections = server.replication.replicas.write().await;
+    // The fd may have been reused by a newer replica already, which has a link of its own
+    if connections
+        .get(&fd)
+        .is_some_and(|link| Arc::ptr_eq(&link.ack, ack))
+    {
+        connections.remove(&fd);
     }
 }
 
-// TODO: Add memoization for efficiency as we scale
-pub async fn is_stream_replica(
-    replica_connections: Arc<RwLock<Option<HashMap<i32, Arc<RwLock<TcpStream>>>>>>,
-    stream: Arc<RwLock<TcpStream>>,
-) -> bool {
-    use std::os::unix::io::AsRawFd;
+// The offset in a REPLCONF ACK <offset>
+fn parse_ack(frame: &RespType) -> Option<usize> {
+    let args = match frame {
+        RespType::Array(x) if x.len() >= 3 => x,
+        _ => return None,
+    };
+    match (&args[0], &args[1], &args[2]) {
+        (
+            RespType::BulkString(Some(command)),
+            RespType::BulkString(Some(subcommand)),
+            RespType::BulkString(Some(offset)),
+        ) if command.eq_ignore_ascii_case(b"replconf")
+            && subcommand.eq_ignore_ascii_case(b"ack") =>
+        {
+            std::str::from_utf8(offset).ok()?.parse().ok()
+        }
+        _ => None,
+    }
+}
 
-    let replica_connections = replica_connections.write().await;
-    if let Some(ref connections) = *replica_connections {
-        let stream = stream.read().await;
-        let stream_fd = stream.as_raw_fd();
-        for (replica_fd, _replica_stream) in connections.iter() {
-            if stream_fd == *replica_fd {
-                return true;
+// Queues `data` for every replica, dropping the ones that have gone away or fallen too far
+// behind. An empty `data` just checks on them.
+pub fn send_to_replicas(connections: &mut HashMap<i32, ReplicaLink>, data: &[u8]) {
+    let data = Bytes::copy_from_slice(data);
+    connections.retain(|fd, link| match link.write(data.clone()) {
+        Ok(()) => true,
+        Err(e) => {
+            warning!("Disconnecting replica {}: {}", fd, e);
+            false
+        }
+    });
+}
+
+// Makes the server a replica of another, or a master again with NO ONE, while it keeps running.
+// A promoted replica carries on the stream from the offset it had reached.
+pub async fn handle_replicaof(server: &Arc<ServerState>, host: String, port: String) -> Vec<u8> {
+    let replication = &server.replication;
+    let mut link_task = replication.link_task.lock().await;
+    let config = server.config.current();
+    if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+        if config.role == RedisState::Replica {
+            if let Some(stop) = link_task.take() {
+                stop.send_replace(true);
             }
+            // Our own replicas stay connected, and the stream they follow goes on with our
+            // writes
+            let _applying = replication.applying.lock().await;
+            let _replicas = replication.replicas.write().await;
+            let offset = replication.offset.get();
+            replication.master_offset.store(offset, Ordering::SeqCst);
+            replication.write_offset.store(offset, Ordering::SeqCst);
+            replication
+                .selected_db
+                .store(NO_DB_SELECTED, Ordering::SeqCst);
+            *replication.replid.lock().unwrap() = random_id();
+            *replication.master_replid.lock().unwrap() = None;
+            server.config.replace(|config| config.set_master());
+            notice!("MASTER MODE enabled");
+        }
+        return shared::OK.to_vec();
+    }
+    let port = match port.parse::<u16>() {
+        Ok(x) => x.to_string(),
+        Err(_) => {
+            return serialize_resp_data(RespType::Error(String::from("ERR Invalid master port")))
+        }
+    };
+    if config.role == RedisState::Replica
+        && config.master_host.as_ref() == Some(&host)
+        && config.master_port.as_ref() == Some(&port)
+    {
+        return serialize_resp_data(RespType::SimpleString(String::from(
+            "OK Already connected to specified master",
+        )));
+    }
+    if let Some(stop) = link_task.take() {
+        stop.send_replace(true);
+    }
+    // Our own replicas were following a stream that ends here, and the new master's stream has
+    // nothing to do with the one we may have followed before
+    replication.replicas.write().await.clear();
+    *replication.master_replid.lock().unwrap() = None;
+    notice!("Connecting to MASTER {}:{}", host, port);
+    server
+        .config
+        .replace(|config| config.set_replica_of(host, port));
+    replication.link.set_state(LinkState::Down);
+    *link_task = Some(connect_to_master(server));
+    shared::OK.to_vec()
+}
+
+pub async fn handle_replconf() -> Vec<u8> {
+    shared::OK.to_vec()
+}
+
+pub async fn handle_replconf_getack(bytes_processed: usize) -> Vec<u8> {
+    let response = RespType::Array(vec![
+        RespType::BulkString(Some(Bytes::from("REPLCONF"))),
+        RespType::BulkString(Some(Bytes::from("ACK"))),
+        RespType::BulkString(Some(Bytes::from(bytes_processed.to_string()))),
+    ]);
+
+    serialize_resp_data(response)
+}
+
+// Sends the replica a snapshot of the dataset, returning the replication offset it was taken at.
+// Replicas that announced capa eof get the RDB file as it's serialized, between two copies of a
+// random mark, as with Redis's diskless sync. Others need its length up front, so the whole file
+// is built before any of it is sent.
+async fn handle_psync(
+    stream: &mut TcpStream,
+    server: &ServerState,
+    capa_eof: bool,
+    timeout: Duration,
+) -> Result<usize, ServerError> {
+    let replication = &server.replication;
+    // Nothing can be applied while applying is held, nor propagated while the replicas are
+    // locked, so the snapshot and the offset line up. The RDB is built from the snapshot once writes are
+    // flowing again.
+    let (offset, snapshot) = {
+        let _applying = replication.applying.lock().await;
+        let _replicas = replication.replicas.write().await;
+        // The replica starts out with no database selected, so the stream selects one again
+        // before the next write
+        replication
+            .selected_db
+            .store(NO_DB_SELECTED, Ordering::SeqCst);
+        let offset = replication.master_offset.load(Ordering::SeqCst);
+        (offset, server.keyspace.snapshot().await)
+    };
+    notice!(
+        "Full resync with replica, sending the RDB taken at offset {}",
+        offset
+    );
+    let mut chunks = stream_rdb(snapshot);
+    let replid = replication.replid.lock().unwrap().clone();
+    let mut header = serialize_resp_data(RespType::SimpleString(format!(
+        "FULLRESYNC {} {}",
+        replid, offset
+    )));
+
+    if capa_eof {
+        let mark = random_id();
+        header.extend_from_slice(format!("$EOF:{}\r\n", mark).as_bytes());
+        send_rdb_chunk(stream, &header, timeout).await?;
+        while let Some(chunk) = chunks.recv().await {
+            send_rdb_chunk(stream, &chunk, timeout).await?;
         }
+        send_rdb_chunk(stream, mark.as_bytes(), timeout).await?;
+    } else {
+        let mut rdb = Vec::new();
+        while let Some(chunk) = chunks.recv().await {
+            rdb.extend_from_slice(&chunk);
+        }
+        // Like a bulk string, minus the trailing CRLF
+        shared::write_bulk_header(rdb.len(), &mut header);
+        send_rdb_chunk(stream, &header, timeout).await?;
+        for chunk in rdb.chunks(RDB_CHUNK_SIZE) {
+            send_rdb_chunk(stream, chunk, timeout).await?;
+        }
+    }
+    Ok(offset)
+}
+
+async fn send_rdb_chunk(
+    stream: &mut TcpStream,
+    chunk: &[u8],
+    timeout: Duration,
+) -> Result<(), ServerError> {
+    match time::timeout(timeout, stream.write_all(chunk)).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err("timed out sending the RDB file".into()),
     }
-    false
 }
 
+// Sends one step of the handshake and waits for the master's answer
 async fn send_and_recieve(
-    stream: Arc<RwLock<TcpStream>>,
-    message: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let mut stream = stream.write().await;
-    // Write the message to the stream
-    stream.write_all(message.as_bytes()).await?;
-    stream.flush().await?;
-
-    // Buffer to store the response
-    let mut buf = [0; 1024];
-    let n = stream.read(&mut buf).await?;
-
-    // Convert the response to a String
-    let response = String::from_utf8_lossy(&buf[..n]).to_string();
-    Ok(response)
-}
-pub async fn perform_handshake(redis: &mut Redis) -> RespParser {
-    let ping: RespType = RespType::Array(vec![RespType::BulkString(Some(String::from("PING")))]);
+    stream: &mut TcpStream,
+    message: &[u8],
+    timeout: Duration,
+) -> Result<BytesMut, ServerError> {
+    let exchange = async {
+        // Write the message to the stream
+        stream.write_all(message).await?;
+        stream.flush().await?;
+
+        // Buffer to store the response
+        let mut buf = BytesMut::with_capacity(1024);
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(ServerError::from("master closed the connection"));
+        }
+        debug!(
+            "Handshake sent {:?}, master replied {:?}",
+            String::from_utf8_lossy(message),
+            String::from_utf8_lossy(&buf)
+        );
+        Ok(buf)
+    };
+    match time::timeout(timeout, exchange).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("timed out after {}s", timeout.as_secs()).into()),
+    }
+}
+
+// Connects to the master and goes through the handshake up to receiving the RDB file. Every step
+// has to complete within the replication timeout, and so does every read of the RDB transfer.
+// The replica's offset is reset to the one the master starts the stream at.
+pub async fn perform_handshake(
+    server: &ServerState,
+) -> Result<(ConnectionWriter, RespParser), ServerError> {
+    let (config, replication) = (&server.config.current(), &server.replication);
+    let offset = &replication.offset;
+    let timeout = Duration::from_secs(config.repl_timeout);
+    let ping: RespType = RespType::Array(vec![RespType::BulkString(Some(Bytes::from("PING")))]);
     let repl_port = RespType::Array(vec![
-        RespType::BulkString(Some(String::from("REPLCONF"))),
-        RespType::BulkString(Some(String::from("listening-port"))),
-        RespType::BulkString(Some(String::from(&format!("{}", redis.config.port)))),
-    ]);
-    let repl_capa = RespType::Array(vec![
-        RespType::BulkString(Some(String::from("REPLCONF"))),
-        RespType::BulkString(Some(String::from("capa"))),
-        RespType::BulkString(Some(String::from("psync2"))),
+        RespType::BulkString(Some(Bytes::from("REPLCONF"))),
+        RespType::BulkString(Some(Bytes::from("listening-port"))),
+        RespType::BulkString(Some(Bytes::from(config.port.clone()))),
     ]);
+    // eof lets the master stream the RDB file without knowing its length up front
+    let repl_capa = RespType::Array(
+        ["REPLCONF", "capa", "eof", "capa", "psync2"]
+            .into_iter()
+            .map(|x| RespType::BulkString(Some(Bytes::from(x))))
+            .collect(),
+    );
+    // A replica that has synced before asks to carry on after the last byte it applied
+    let (replid, next) = match replication.master_replid.lock().unwrap().clone() {
+        Some(x) => (x, (offset.get() + 1).to_string()),
+        None => (String::from("?"), String::from("-1")),
+    };
     let psync = RespType::Array(vec![
-        RespType::BulkString(Some(String::from("PSYNC"))),
-        RespType::BulkString(Some(String::from("?"))),
-        RespType::BulkString(Some(String::from("-1"))),
+        RespType::BulkString(Some(Bytes::from("PSYNC"))),
+        RespType::BulkString(Some(Bytes::from(replid.clone()))),
+        RespType::BulkString(Some(Bytes::from(next))),
     ]);
 
     let serialized_ping = serialize_resp_data(ping);
     let serialized_repl_port = serialize_resp_data(repl_port);
     let serialized_repl_capa = serialize_resp_data(repl_capa);
     let serialized_psync = serialize_resp_data(psync);
-    redis.master_connection = match TcpStream::connect(format!(
+    let address = format!(
         "{}:{}",
-        redis.config.master_host.as_ref().unwrap(),
-        redis.config.master_port.as_ref().unwrap()
-    ))
-    .await
-    {
-        Ok(x) => Some(Arc::new(RwLock::new(x))),
-        Err(e) => panic!("{}", e),
+        config.master_host.as_ref().unwrap(),
+        config.master_port.as_ref().unwrap()
+    );
+    let mut stream = match time::timeout(timeout, TcpStream::connect(address)).await {
+        Ok(x) => x?,
+        Err(_) => return Err("timed out connecting to master".into()),
     };
+    notice!("MASTER <-> REPLICA sync started");
 
-    let stream = Arc::clone(&redis.master_connection.as_ref().unwrap());
-    let _ = send_and_recieve(Arc::clone(&stream), &serialized_ping).await;
-    let _ = send_and_recieve(Arc::clone(&stream), &serialized_repl_port).await;
-    let _ = send_and_recieve(Arc::clone(&stream), &serialized_repl_capa).await;
-    let stream_data = send_and_recieve(Arc::clone(&stream), &serialized_psync)
-        .await
-        .expect("Failed to recieve repl_id from master at the end of handshake");
+    send_and_recieve(&mut stream, &serialized_ping, timeout).await?;
+    // A master with requirepass set answers the PING with NOAUTH, and nothing else until then
+    if let Some(password) = &config.masterauth {
+        let auth = serialize_resp_data(RespType::Array(vec![
+            RespType::BulkString(Some(Bytes::from("AUTH"))),
+            RespType::BulkString(Some(Bytes::from(password.clone()))),
+        ]));
+        let reply = send_and_recieve(&mut stream, &auth, timeout).await?;
+        if !reply.starts_with(b"+OK") {
+            return Err(format!(
+                "master refused AUTH: {}",
+                String::from_utf8_lossy(&reply).trim_end()
+            )
+            .into());
+        }
+    }
+    send_and_recieve(&mut stream, &serialized_repl_port, timeout).await?;
+    send_and_recieve(&mut stream, &serialized_repl_capa, timeout).await?;
+    let stream_data = send_and_recieve(&mut stream, &serialized_psync, timeout).await?;
+    replication.link.set_state(LinkState::Syncing);
     // We read up to CRLF and then everything after is the contents of the RDB file
     // if we don't read as much as we expect, we read again, until we do
     // then the stream is empty enough
-    println!("====== Recieiving Psync Response from Master ======");
-    println!("{}", stream_data);
-    println!("====== End of Psync Response from Master ==========");
-    let mut parser = RespParser::new(stream_data, Arc::clone(&stream));
-    let resync: String;
-    let rdb: Vec<u8>;
-    (resync, rdb) = parser.parse_handshake().await;
-    parser
+    let (reader, writer) = Connection::Tcp(stream).into_split();
+    let mut parser = RespParser::new(stream_data, reader);
+    let (resync, rdb) = parser.parse_handshake(timeout).await?;
+    if let Some(rdb) = rdb {
+        notice!("{} with RDB of {} bytes", resync, rdb.len());
+        load_rdb(server, rdb).await?;
+    }
+    let (replid, full_resync) = match resync.split(' ').collect::<Vec<_>>().as_slice() {
+        ["FULLRESYNC", replid, start] => match start.parse() {
+            Ok(x) => {
+                offset.set(x);
+                replication.master_db.store(0, Ordering::SeqCst);
+                (replid.to_string(), true)
+            }
+            Err(_) => return Err(format!("invalid offset in {}", resync).into()),
+        },
+        ["CONTINUE", rest @ ..] => {
+            notice!("Partial resync with master from offset {}", offset.get());
+            // The master may have moved on to a stream of its own that carries on from ours
+            match rest {
+                [replid] => (replid.to_string(), false),
+                _ => (replid, false),
+            }
+        }
+        _ => return Err(format!("expected FULLRESYNC in reply to PSYNC, got {}", resync).into()),
+    };
+    *replication.master_replid.lock().unwrap() = Some(replid.clone());
+    follow_stream(replication, replid, full_resync).await;
+    Ok((writer, parser))
+}
+
+// Replaces the whole dataset with the one in the RDB file from a full resync, leaving it as it
+// was if the file can't be loaded
+async fn load_rdb(server: &ServerState, rdb: Bytes) -> Result<(), ServerError> {
+    let loaded = RdbParser::new(rdb.into())
+        .rdb_to_db()
+        .map_err(|(offset, message)| {
+            format!(
+                "the RDB file from master is corrupt: {} at offset {}",
+                message, offset
+            )
+        })?;
+    let keys: usize = loaded.iter().map(|x| x.len()).sum();
+    let databases = server.config.current().databases;
+    if loaded.len() > databases {
+        return Err(format!(
+            "the RDB file from master has more than {} databases",
+            databases
+        )
+        .into());
+    }
+    server
+        .keyspace
+        .run_all(move |databases| {
+            let mut loaded = loaded.into_iter();
+            for db in databases {
+                let entries = loaded.next().unwrap_or_default();
+                db.swap(&Store::from_entries(entries));
+            }
+        })
+        .await;
+    notice!(
+        "MASTER <-> REPLICA sync: Finished with success, {} keys loaded",
+        keys
+    );
+    Ok(())
+}
+
+// Takes on the master's stream as the one we pass on to our own replicas. They're dropped
+// unless it carries on from the one they were following, so that they sync with it afresh.
+async fn follow_stream(replication: &Replication, replid: String, full_resync: bool) {
+    let mut connections = replication.replicas.write().await;
+    let mut own_replid = replication.replid.lock().unwrap();
+    if full_resync || *own_replid != replid {
+        connections.clear();
+    }
+    if full_resync {
+        let offset = replication.offset.get();
+        replication.master_offset.store(offset, Ordering::SeqCst);
+        replication.write_offset.store(offset, Ordering::SeqCst);
+        replication.backlog.lock().unwrap().reset(offset);
+    }
+    *own_replid = replid;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acks_that_dont_add_up_are_desyncs() {
+        let ack = ReplicaAck::new(100);
+        assert!(ack.record(90, 200).is_err());
+        assert!(ack.record(201, 200).is_err());
+
+        // Acks of its own accord may come before the answer to a GETACK
+        ack.expect_answer_at(150);
+        ack.expect_answer_at(180);
+        assert!(ack.record(120, 200).is_ok());
+        assert!(ack.record(150, 200).is_ok());
+        assert_eq!(ack.offset(), 150);
+        // But not past it
+        assert!(ack.record(190, 200).is_err());
+        assert!(ack.record(180, 200).is_ok());
+        assert!(ack.record(190, 200).is_ok());
+    }
 }
@@ -0,0 +1,157 @@
+use super::acl::LogContext;
+use super::commands::args_to_command;
+use super::dispatch::Dispatcher;
+use super::sha1;
+use super::state::ClientContext;
+
+use crate::resp::resp_deserializer::parse_frames;
+use crate::resp::RespType;
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+pub const NOSCRIPT_ERROR: &str = "NOSCRIPT No matching script. Please use EVAL.";
+
+// What a script returns: the reply EVAL sends, converted for the caller's protocol, or an error
+// message like "ERR something went wrong"
+pub type ScriptFuture<'a> = Pin<Box<dyn Future<Output = Result<RespType, String>> + Send + 'a>>;
+
+// A script, a Rust function run with the keys and arguments EVAL was given. Written as a fn
+// item, like fn script<'a>(call: &'a mut ScriptCall<'_>) -> ScriptFuture<'a>.
+pub type Script = Arc<dyn for<'a, 'b> Fn(&'a mut ScriptCall<'b>) -> ScriptFuture<'a> + Send + Sync>;
+
+// The scripts the server can run. Rather than embedding an interpreter, scripts are registered
+// with the server builder under a name, and that name is the body EVAL and SCRIPT LOAD take.
+// EVALSHA finds a script by the SHA1 of its name once it's been loaded.
+#[derive(Default)]
+pub struct Scripts {
+    registered: HashMap<String, Script>,
+    // The names of the loaded scripts, by SHA1
+    loaded: Mutex<HashMap<String, String>>,
+}
+
+impl Scripts {
+    pub fn register(&mut self, name: &str, script: Script) {
+        self.registered.insert(name.to_string(), script);
+    }
+
+    // Loads the script registered as `body`, returning its SHA1
+    pub fn load(&self, body: &str) -> Result<String, String> {
+        if !self.registered.contains_key(body) {
+            return Err(format!(
+                "ERR Error compiling script, no script is registered as '{}'",
+                body
+            ));
+        }
+        let sha = sha1::hex_digest(body.as_bytes());
+        let mut loaded = self.loaded.lock().unwrap();
+        loaded.insert(sha.clone(), body.to_string());
+        Ok(sha)
+    }
+
+    // A loaded script, by its SHA1 in either case
+    pub fn get(&self, sha: &str) -> Option<Script> {
+        let loaded = self.loaded.lock().unwrap();
+        let body = loaded.get(&sha.to_ascii_lowercase())?;
+        self.registered.get(body).cloned()
+    }
+
+    pub fn exists(&self, sha: &str) -> bool {
+        let loaded = self.loaded.lock().unwrap();
+        loaded.contains_key(&sha.to_ascii_lowercase())
+    }
+
+    // Forgets every loaded script, as SCRIPT FLUSH does. They stay registered, to be loaded again.
+    pub fn flush(&self) {
+        self.loaded.lock().unwrap().clear();
+    }
+}
+
+// A script's view of the server while it runs: its KEYS and ARGV, and redis.call. No other
+// client's commands run until it's done.
+pub struct ScriptCall<'a> {
+    pub keys: Vec<String>,
+    pub argv: Vec<Bytes>,
+    dispatcher: &'a Dispatcher,
+    client: &'a mut ClientContext,
+}
+
+impl<'a> ScriptCall<'a> {
+    pub(crate) fn new(
+        dispatcher: &'a Dispatcher,
+        client: &'a mut ClientContext,
+        keys: Vec<String>,
+        argv: Vec<Bytes>,
+    ) -> Self {
+        Self {
+            keys,
+            argv,
+            dispatcher,
+            client,
+        }
+    }
+
+    // Runs a command as the client running the script, e.g. ["SET", "key", "value"], like
+    // redis.call. Error replies come back as Err, so that ? ends the script with them. Blocking
+    // commands don't block, as in a transaction.
+    pub async fn call<A: AsRef<[u8]>>(&mut self, args: &[A]) -> Result<RespType, String> {
+        let mut args: Vec<RespType> = args
+            .iter()
+            .map(|x| RespType::BulkString(Some(Bytes::copy_from_slice(x.as_ref()))))
+            .collect();
+        if args.is_empty() {
+            return Err(String::from(
+                "ERR Please specify at least one argument for this redis lib call",
+            ));
+        }
+        let name = match args.remove(0) {
+            RespType::BulkString(Some(x)) => String::from_utf8_lossy(&x).into_owned(),
+            _ => unreachable!(),
+        };
+        let command = args_to_command(&name, args)?;
+        if command.is_noscript() {
+            return Err(String::from(
+                "ERR This Redis command is not allowed from script",
+            ));
+        }
+        let reply = self
+            .dispatcher
+            .execute(command.without_blocking(), self.client, LogContext::Lua)
+            .await
+            .into_vec();
+        match parse_frames(Bytes::from(reply))
+            .expect("Expected the server's reply to be well formed")
+            .pop()
+        {
+            Some(RespType::Error(e)) => Err(e),
+            Some(x) => Ok(x),
+            None => Ok(RespType::Null),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ping<'a>(call: &'a mut ScriptCall<'_>) -> ScriptFuture<'a> {
+        Box::pin(async move { call.call(&["PING"]).await })
+    }
+
+    #[test]
+    fn only_loaded_scripts_are_found_by_sha() {
+        let mut scripts = Scripts::default();
+        scripts.register("ping", Arc::new(ping));
+        assert!(scripts.load("pong").is_err());
+        let sha = sha1::hex_digest(b"ping");
+        assert!(scripts.get(&sha).is_none());
+        assert_eq!(scripts.load("ping"), Ok(sha.clone()));
+        assert!(scripts.get(&sha.to_uppercase()).is_some());
+        assert!(scripts.exists(&sha));
+        scripts.flush();
+        assert!(!scripts.exists(&sha));
+    }
+}
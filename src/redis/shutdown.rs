@@ -0,0 +1,63 @@
+use super::persistence;
+use super::replica::ReplicaAck;
+use super::state::{Replication, ServerState};
+use super::synchronize::request_acks;
+use super::RedisState;
+use crate::{notice, warning};
+
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use tokio::time::{self, Duration};
+
+pub const SHUTDOWN_ERROR: &str = "ERR Errors trying to SHUTDOWN. Check logs.";
+// How long a master gives its replicas to take in the last writes, as Redis's shutdown-timeout
+const REPLICA_CATCH_UP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Gets the server ready to stop, as SHUTDOWN does before it goes down. Lagging replicas get a
+// chance to catch up, then the RDB file is saved if SAVE says so, or if save points are
+// configured and NOSAVE doesn't say otherwise, and the AOF is fsynced. An error means the server
+// should keep running, since stopping would lose data.
+pub async fn prepare_for_shutdown(server: &ServerState, save: Option<bool>) -> io::Result<()> {
+    let config = server.config.current();
+    if config.role == RedisState::Master {
+        wait_for_replicas(&server.replication).await;
+    }
+    if save.unwrap_or(!config.save.is_empty()) {
+        notice!("Saving the final RDB snapshot before exiting");
+        persistence::save(server).await?;
+    }
+    if let Some(writer) = server.aof.get() {
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+// Waits until every replica has acked everything written so far, or for the timeout. A replica
+// only passes its master's stream on, so it has nothing to wait for.
+async fn wait_for_replicas(replication: &Replication) {
+    let (target, lagging): (usize, Vec<Arc<ReplicaAck>>) = {
+        let connections = replication.replicas.read().await;
+        let target = replication.write_offset.load(Ordering::SeqCst);
+        let lagging = connections
+            .values()
+            .filter(|link| link.ack.offset() < target)
+            .map(|link| Arc::clone(&link.ack))
+            .collect();
+        (target, lagging)
+    };
+    if lagging.is_empty() {
+        return;
+    }
+    notice!("Waiting for {} replicas to catch up", lagging.len());
+    request_acks(replication).await;
+    let mut waiting = JoinSet::new();
+    for ack in lagging {
+        waiting.spawn(async move { ack.wait_for(target).await });
+    }
+    let all = async { while waiting.join_next().await.is_some() {} };
+    if time::timeout(REPLICA_CATCH_UP_TIMEOUT, all).await.is_err() {
+        warning!("Some replicas are still lagging, shutting down anyway");
+    }
+}
@@ -0,0 +1,139 @@
+use super::clock;
+use super::state::ClientContext;
+
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Like Redis, entries keep at most this many arguments, each cut to at most this many bytes
+const MAX_ARGS: usize = 32;
+const MAX_ARG_LEN: usize = 128;
+
+// A command that took longer than slowlog-log-slower-than
+#[derive(Debug, Clone)]
+pub struct SlowLogEntry {
+    pub id: u64,
+    // UNIX time in seconds
+    pub timestamp: u64,
+    pub duration_us: u64,
+    pub args: Vec<Bytes>,
+    pub peer: String,
+    pub client_name: String,
+}
+
+#[derive(Default)]
+pub struct SlowLog {
+    // Newest first, and the ID the next entry gets
+    entries: Mutex<(VecDeque<SlowLogEntry>, u64)>,
+}
+
+impl SlowLog {
+    // Logs the command if it took longer than `threshold_us`, keeping at most `max_len` entries.
+    // A negative threshold turns the log off, and 0 logs every command.
+    pub fn record(
+        &self,
+        threshold_us: i64,
+        max_len: usize,
+        duration: Duration,
+        args: Vec<Bytes>,
+        client: &ClientContext,
+    ) {
+        let duration_us = duration.as_micros() as u64;
+        if threshold_us < 0 || duration_us < threshold_us as u64 {
+            return;
+        }
+        let mut guard = self.entries.lock().unwrap();
+        let (entries, next_id) = &mut *guard;
+        entries.push_front(SlowLogEntry {
+            id: *next_id,
+            timestamp: clock::unix_ms() / 1000,
+            duration_us,
+            args: truncate(args),
+            peer: client.addr.clone().unwrap_or_default(),
+            client_name: client.name.clone().unwrap_or_default(),
+        });
+        *next_id += 1;
+        entries.truncate(max_len);
+    }
+
+    pub fn get(&self, count: usize) -> Vec<SlowLogEntry> {
+        let entries = &self.entries.lock().unwrap().0;
+        entries.iter().take(count).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // IDs carry on where they were, as in Redis
+    pub fn reset(&self) {
+        self.entries.lock().unwrap().0.clear();
+    }
+}
+
+// The last argument kept says how many more there were, and a long argument how much of it is
+// missing
+fn truncate(mut args: Vec<Bytes>) -> Vec<Bytes> {
+    if args.len() > MAX_ARGS {
+        let more = args.len() - (MAX_ARGS - 1);
+        args.truncate(MAX_ARGS - 1);
+        args.push(Bytes::from(format!("... ({} more arguments)", more)));
+    }
+    for arg in &mut args {
+        if arg.len() > MAX_ARG_LEN {
+            let mut cut = arg[..MAX_ARG_LEN].to_vec();
+            cut.extend_from_slice(
+                format!("... ({} more bytes)", arg.len() - MAX_ARG_LEN).as_bytes(),
+            );
+            *arg = Bytes::from(cut);
+        }
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slow_commands_are_logged_newest_first() {
+        let log = SlowLog::default();
+        let client = ClientContext::new(1);
+        let args = || vec![Bytes::from("GET"), Bytes::from("k")];
+        let ms = Duration::from_millis;
+        log.record(10_000, 2, ms(5), args(), &client);
+        log.record(-1, 2, ms(50), args(), &client);
+        assert!(log.is_empty());
+        for (id, duration) in [20, 30, 40].into_iter().enumerate() {
+            log.record(10_000, 2, ms(duration), args(), &client);
+            assert_eq!(log.get(1)[0].id, id as u64);
+        }
+        let entries = log.get(10);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            (entries[0].duration_us, entries[1].duration_us),
+            (40_000, 30_000)
+        );
+        assert_eq!(entries[0].args, args());
+        log.reset();
+        assert_eq!(log.len(), 0);
+        log.record(0, 2, Duration::ZERO, args(), &client);
+        assert_eq!(log.get(10)[0].id, 3);
+    }
+
+    #[test]
+    fn long_commands_are_cut_short() {
+        let mut args: Vec<Bytes> = (0..40).map(|x| Bytes::from(x.to_string())).collect();
+        args[1] = Bytes::from(vec![b'a'; 200]);
+        let args = truncate(args);
+        assert_eq!(args.len(), MAX_ARGS);
+        assert_eq!(args[31], Bytes::from("... (9 more arguments)"));
+        assert!(args[1].ends_with(b"a... (72 more bytes)"));
+        assert_eq!(args[1].len(), MAX_ARG_LEN + "... (72 more bytes)".len());
+    }
+}
@@ -0,0 +1,255 @@
+use bytes::Bytes;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+
+// Small sets are kept as one flat, sorted vector like Redis's listpack, which is far more compact
+// than the hash and tree and just as fast at that size. A set that outgrows either limit is
+// converted to the skiplist encoding for good.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ListpackLimits {
+    // zset-max-listpack-entries
+    pub max_entries: usize,
+    // zset-max-listpack-value, the longest member in bytes
+    pub max_value: usize,
+}
+
+impl Default for ListpackLimits {
+    fn default() -> Self {
+        ListpackLimits {
+            max_entries: 128,
+            max_value: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SortedSet {
+    encoding: Encoding,
+}
+
+#[derive(Debug, Clone)]
+enum Encoding {
+    // Members in ascending (score, member) order
+    Listpack(Vec<ScoredMember>),
+    // Members are kept both in a hash for O(1) score lookups and in an ordered set for range
+    // queries
+    Skiplist {
+        scores: HashMap<Bytes, f64>,
+        ordered: BTreeSet<ScoredMember>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ScoredMember {
+    score: f64,
+    member: Bytes,
+}
+
+impl Eq for ScoredMember {}
+
+impl PartialOrd for ScoredMember {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Ties on score are broken lexicographically by member, as in Redis
+impl Ord for ScoredMember {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then_with(|| self.member.cmp(&other.member))
+    }
+}
+
+impl Default for SortedSet {
+    fn default() -> Self {
+        SortedSet {
+            encoding: Encoding::Listpack(Vec::new()),
+        }
+    }
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.encoding {
+            Encoding::Listpack(x) => x.len(),
+            Encoding::Skiplist { scores, .. } => scores.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The name reported by OBJECT ENCODING
+    pub fn encoding(&self) -> &'static str {
+        match self.encoding {
+            Encoding::Listpack(_) => "listpack",
+            Encoding::Skiplist { .. } => "skiplist",
+        }
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        match &self.encoding {
+            Encoding::Listpack(x) => x.iter().find(|x| x.member == member).map(|x| x.score),
+            Encoding::Skiplist { scores, .. } => scores.get(member).copied(),
+        }
+    }
+
+    // The member's position in ascending order, counting from 0
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        match &self.encoding {
+            Encoding::Listpack(x) => x.iter().position(|x| x.member == member),
+            Encoding::Skiplist { scores, ordered } => {
+                let entry = ScoredMember {
+                    score: *scores.get(member)?,
+                    member: Bytes::copy_from_slice(member),
+                };
+                Some(ordered.range(..entry).count())
+            }
+        }
+    }
+
+    // Returns true if the member was newly added rather than updated. Converts the set to the
+    // skiplist encoding if the member takes it past `limits`.
+    pub fn insert(&mut self, member: Bytes, score: f64, limits: &ListpackLimits) -> bool {
+        if let Encoding::Listpack(entries) = &self.encoding {
+            let exists = entries.iter().any(|x| x.member == member);
+            if member.len() > limits.max_value
+                || (!exists && entries.len() + 1 > limits.max_entries)
+            {
+                self.convert_to_skiplist();
+            }
+        }
+        match &mut self.encoding {
+            Encoding::Listpack(entries) => {
+                let is_new = match entries.iter().position(|x| x.member == member) {
+                    Some(index) => {
+                        entries.remove(index);
+                        false
+                    }
+                    None => true,
+                };
+                let entry = ScoredMember { score, member };
+                let index = entries.partition_point(|x| *x < entry);
+                entries.insert(index, entry);
+                is_new
+            }
+            Encoding::Skiplist { scores, ordered } => {
+                let is_new = match scores.insert(member.clone(), score) {
+                    Some(old_score) => {
+                        ordered.remove(&ScoredMember {
+                            score: old_score,
+                            member: member.clone(),
+                        });
+                        false
+                    }
+                    None => true,
+                };
+                ordered.insert(ScoredMember { score, member });
+                is_new
+            }
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match &mut self.encoding {
+            Encoding::Listpack(entries) => match entries.iter().position(|x| x.member == member) {
+                Some(index) => {
+                    entries.remove(index);
+                    true
+                }
+                None => false,
+            },
+            Encoding::Skiplist { scores, ordered } => match scores.remove_entry(member) {
+                Some((member, score)) => {
+                    ordered.remove(&ScoredMember { score, member });
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
+    // Iterates members in ascending (score, member) order
+    pub fn iter(&self) -> Box<dyn DoubleEndedIterator<Item = (&Bytes, f64)> + '_> {
+        match &self.encoding {
+            Encoding::Listpack(x) => Box::new(x.iter().map(|x| (&x.member, x.score))),
+            Encoding::Skiplist { ordered, .. } => {
+                Box::new(ordered.iter().map(|x| (&x.member, x.score)))
+            }
+        }
+    }
+
+    fn convert_to_skiplist(&mut self) {
+        if let Encoding::Listpack(entries) = &mut self.encoding {
+            let entries = std::mem::take(entries);
+            let scores = entries
+                .iter()
+                .map(|x| (x.member.clone(), x.score))
+                .collect();
+            self.encoding = Encoding::Skiplist {
+                scores,
+                ordered: entries.into_iter().collect(),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(set: &SortedSet) -> Vec<(String, f64)> {
+        set.iter()
+            .map(|(member, score)| (String::from_utf8_lossy(member).into_owned(), score))
+            .collect()
+    }
+
+    #[test]
+    fn small_sets_are_upgraded_once_they_outgrow_the_listpack() {
+        let limits = ListpackLimits {
+            max_entries: 3,
+            max_value: 8,
+        };
+        let mut set = SortedSet::new();
+        assert!(set.insert(Bytes::from("b"), 2.0, &limits));
+        assert!(set.insert(Bytes::from("c"), 1.0, &limits));
+        assert!(set.insert(Bytes::from("a"), 2.0, &limits));
+        assert!(!set.insert(Bytes::from("c"), 3.0, &limits));
+        assert_eq!(set.encoding(), "listpack");
+        let expected = vec![
+            (String::from("a"), 2.0),
+            (String::from("b"), 2.0),
+            (String::from("c"), 3.0),
+        ];
+        assert_eq!(members(&set), expected);
+        assert_eq!(set.rank(b"b"), Some(1));
+
+        // One entry too many converts it, without losing the order
+        assert!(set.insert(Bytes::from("d"), 0.5, &limits));
+        assert_eq!(set.encoding(), "skiplist");
+        assert_eq!(members(&set)[1..], expected[..]);
+        assert_eq!(set.score(b"c"), Some(3.0));
+        assert_eq!(set.rank(b"b"), Some(2));
+        assert_eq!(set.rank(b"none"), None);
+
+        // Shrinking back doesn't convert it back
+        assert!(set.remove(b"d"));
+        assert!(set.remove(b"a"));
+        assert_eq!(set.encoding(), "skiplist");
+        assert_eq!(set.len(), 2);
+
+        // A member that's too long converts it as well
+        let mut set = SortedSet::new();
+        set.insert(Bytes::from("short"), 1.0, &limits);
+        set.insert(Bytes::from("much too long"), 1.0, &limits);
+        assert_eq!(set.encoding(), "skiplist");
+        assert_eq!(set.score(b"short"), Some(1.0));
+    }
+}
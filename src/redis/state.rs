@@ -0,0 +1,221 @@
+use super::acl::{Acl, DEFAULT_USER};
+use super::admission::Admission;
+use super::backlog::Backlog;
+use super::blocking::Blocking;
+use super::clients::Clients;
+use super::commands::Command;
+use super::connection::Transport;
+use super::identity::Identity;
+use super::keyspace::Keyspace;
+use super::latency::LatencyStats;
+use super::monitor::{Monitoring, Monitors};
+use super::persistence::Persistence;
+use super::pubsub::{PubSub, Subscriptions};
+use super::replica::{MasterLink, ReplicaOffset};
+use super::scripting::Scripts;
+use super::slowlog::SlowLog;
+use super::ReplicaConnections;
+
+use crate::aof::writer::AofWriter;
+use crate::config::LiveConfig;
+use crate::resp::Protocol;
+use crate::server::WeakShutdownHandle;
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::{self, watch, RwLock};
+
+// Everything the server's connections share. Handlers reach the keyspace, configuration and
+// replication through this instead of being handed each of them separately.
+pub struct ServerState {
+    pub keyspace: Keyspace,
+    // Swapped out whole by CONFIG SET, so a handler reads one consistent configuration
+    pub config: LiveConfig,
+    // Fires once the server shuts down, for tasks started while it runs
+    pub shutdown: watch::Receiver<bool>,
+    // Fires it, as SHUTDOWN does
+    pub(crate) shutdown_handle: WeakShutdownHandle,
+    pub replication: Replication,
+    pub stats: Stats,
+    pub admission: Admission,
+    pub clients: Clients,
+    pub acl: Acl,
+    pub identity: Identity,
+    pub latency: LatencyStats,
+    pub slowlog: SlowLog,
+    // What EVAL and EVALSHA can run
+    pub scripts: Scripts,
+    // Clients waiting for keys to be written to, like XREAD BLOCK
+    pub blocking: Blocking,
+    // Every command runs holding this for reading, and EXEC for writing, so that nothing runs in
+    // the middle of a transaction
+    pub exec_lock: RwLock<()>,
+    pub pubsub: PubSub,
+    pub monitors: Monitors,
+    pub persistence: Persistence,
+    // Set once the AOF has been loaded, when appendonly is on
+    pub aof: OnceLock<AofWriter>,
+}
+
+pub struct Replication {
+    // The connected replicas. A replica can have replicas of its own, which it passes its
+    // master's stream on to.
+    pub replicas: ReplicaConnections,
+    // Bytes written to the replication stream so far, by every connection on a master, or passed
+    // on from its master by a replica. Only ever changed with the replicas locked, see
+    // synchronize::propagate.
+    pub master_offset: AtomicUsize,
+    // Where the stream stood after the last write was propagated, which is what WAIT waits for.
+    // Unlike master_offset it doesn't move for PINGs and GETACKs.
+    pub write_offset: AtomicUsize,
+    // The database the replication stream last selected, NO_DB_SELECTED if none since the last
+    // replica connected. Changed alongside master_offset.
+    pub selected_db: AtomicUsize,
+    // The ID of the stream a master writes, new every time it starts or is promoted. A replica
+    // takes on its master's once it syncs, as it passes the same stream on.
+    pub replid: Mutex<String>,
+    // The tail of that stream, also changed alongside master_offset
+    pub backlog: Mutex<Backlog>,
+    // How much of the master's stream has been applied, on a replica
+    pub offset: ReplicaOffset,
+    // The master's stream a replica follows, and the database it last selected, so that it can
+    // ask to carry on from its offset when it reconnects. None until it first syncs, and again
+    // once the stream stops making sense.
+    pub master_replid: Mutex<Option<String>>,
+    pub master_db: AtomicUsize,
+    // The replica's connection to its master
+    pub link: MasterLink,
+    // Held from applying a write to propagating it: by each write on a master, and by the master
+    // link on a replica. Writes go down the stream and into the AOF in the order they were
+    // applied, and the snapshot a replica syncs from lines up with the offset.
+    pub applying: sync::Mutex<()>,
+    // Stops the task keeping that connection up. Held while the role changes, see
+    // replica::handle_replicaof.
+    pub link_task: sync::Mutex<Option<Arc<watch::Sender<bool>>>>,
+}
+
+pub const NO_DB_SELECTED: usize = usize::MAX;
+
+#[derive(Default)]
+pub struct Stats {
+    last_client_id: AtomicU64,
+    pub connections_received: AtomicU64,
+    // Connections turned away by Admission
+    pub rejected_connections: AtomicU64,
+    pub commands_processed: AtomicU64,
+    // Keys deleted because their TTL ran out, whether a command or the cron found them
+    pub expired_keys: AtomicU64,
+    // Writes applied since the dataset was last saved
+    pub dirty: AtomicU64,
+    // Connections closed because serving them panicked, see crash::report
+    pub connection_panics: AtomicU64,
+}
+
+impl Stats {
+    // Ids start at 1 and are never reused, as in Redis
+    pub fn next_client_id(&self) -> u64 {
+        self.last_client_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+// Everything that belongs to a single client, be it a connection or an in-process Client
+pub struct ClientContext {
+    pub id: u64,
+    pub name: Option<String>,
+    // The peer's address and what it's connected over, for connections
+    pub addr: Option<String>,
+    pub transport: Option<Transport>,
+    // Whose permissions commands are checked against. None for the link to our master and for
+    // loading the AOF, which like in Redis aren't subject to ACLs.
+    pub user: Option<String>,
+    // Cleared for connections that have to AUTH first, because requirepass is set
+    pub authenticated: bool,
+    // The logical database commands run against, chosen with SELECT
+    pub db: usize,
+    // Chosen with HELLO, RESP2 until then
+    pub protocol: Protocol,
+    // The port a replica announced with REPLCONF listening-port, ahead of its PSYNC
+    pub listening_port: Option<u16>,
+    // Whether a replica announced REPLCONF capa eof, and so can take an RDB file sent without its
+    // length up front
+    pub capa_eof: bool,
+    // The commands queued since MULTI, until EXEC or DISCARD
+    pub transaction: Option<Transaction>,
+    pub subscriptions: Subscriptions,
+    pub monitoring: Monitoring,
+}
+
+#[derive(Default)]
+pub struct Transaction {
+    pub commands: Vec<Command>,
+    // Set when a command was refused while queuing, which makes EXEC discard the transaction
+    pub aborted: bool,
+}
+
+impl ClientContext {
+    pub fn new(id: u64) -> Self {
+        ClientContext {
+            id,
+            name: None,
+            addr: None,
+            transport: None,
+            user: Some(DEFAULT_USER.to_string()),
+            authenticated: true,
+            db: 0,
+            protocol: Protocol::default(),
+            listening_port: None,
+            capa_eof: false,
+            transaction: None,
+            subscriptions: Subscriptions::default(),
+            monitoring: Monitoring::default(),
+        }
+    }
+
+    // Our master and the AOF loader, the clients without a user, have their commands applied no
+    // matter what. They're the only ones a replica takes writes from.
+    pub fn is_obeyed(&self) -> bool {
+        self.user.is_none()
+    }
+
+    // Whether the client is on this machine: over the unix socket or loopback, or in-process
+    pub fn is_local(&self) -> bool {
+        match self.transport {
+            None | Some(Transport::Unix) => true,
+            Some(Transport::Tcp) => self
+                .addr
+                .as_deref()
+                .and_then(|x| x.parse::<SocketAddr>().ok())
+                .is_some_and(|x| x.ip().is_loopback()),
+        }
+    }
+
+    // Describes the client in the manner of CLIENT LIST, as of running `command`
+    pub fn info(&self, command: &str) -> String {
+        format!(
+            "id={} addr={} transport={} name={} db={} resp={} user={} cmd={}",
+            self.id,
+            self.addr.as_deref().unwrap_or(""),
+            self.transport
+                .map_or(String::from("local"), |x| x.to_string()),
+            self.name.as_deref().unwrap_or(""),
+            self.db,
+            self.protocol,
+            self.user.as_deref().unwrap_or(""),
+            command
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_ids_start_at_one_and_are_never_reused() {
+        let stats = Stats::default();
+        let ids: Vec<u64> = (0..3).map(|_| stats.next_client_id()).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert_eq!(ClientContext::new(ids[0]).protocol, Protocol::Resp2);
+    }
+}
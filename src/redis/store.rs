@@ -0,0 +1,793 @@
+use super::clock::mstime;
+use super::eviction::EvictionPool;
+use super::lazyfree;
+use super::lru::{estimate_idle_ms, lfu_access, lfu_decayed_counter, lfu_initial, lru_clock};
+use super::notify::{Notifications, NotifyFlags};
+use super::value::Value;
+
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+const NUM_SHARDS: usize = 16;
+// How many keys RANDOMKEY draws before giving up on finding one that hasn't expired
+const RANDOM_KEY_ATTEMPTS: usize = 100;
+// Approximate cost of the hash table slot, key indices and entry metadata
+const ENTRY_OVERHEAD: usize = 96;
+
+// Expiration lives next to the value so both are always read and written under the same lock
+#[derive(Debug)]
+pub struct Entry {
+    pub value: Value,
+    // When the entry expires, in clock::mstime milliseconds
+    pub expires_at: Option<u64>,
+    // LRU clock reading from when the entry was last read or written
+    lru: AtomicU32,
+    // Logarithmic access frequency counter, see lru::lfu_access
+    lfu: AtomicU32,
+    // Cached estimate of the bytes used by the entry and its key, refreshed on every mutation
+    size: usize,
+}
+
+impl Entry {
+    pub fn new(value: Value) -> Self {
+        Self {
+            value,
+            expires_at: None,
+            lru: AtomicU32::new(lru_clock()),
+            lfu: AtomicU32::new(lfu_initial()),
+            size: 0,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.expires_at {
+            // Reached rather than passed, so that times already in the past are always expired
+            Some(expiration) => mstime() >= expiration,
+            None => false,
+        }
+    }
+
+    pub fn idle_ms(&self) -> u64 {
+        estimate_idle_ms(self.lru.load(Ordering::Relaxed))
+    }
+
+    pub fn access_frequency(&self) -> u8 {
+        lfu_decayed_counter(self.lfu.load(Ordering::Relaxed))
+    }
+
+    // Makes the entry look as if it was last accessed `ms` ago
+    #[cfg(test)]
+    pub fn set_idle_ms(&self, ms: u64) {
+        use super::lru::{LRU_CLOCK_MAX, LRU_CLOCK_RESOLUTION_MS};
+        let ticks = (ms / LRU_CLOCK_RESOLUTION_MS) as u32;
+        let lru = lru_clock().wrapping_sub(ticks) & LRU_CLOCK_MAX;
+        self.lru.store(lru, Ordering::Relaxed);
+    }
+
+    fn touch(&self) {
+        self.lru.store(lru_clock(), Ordering::Relaxed);
+        let lfu = lfu_access(self.lfu.load(Ordering::Relaxed));
+        self.lfu.store(lfu, Ordering::Relaxed);
+    }
+
+    // Rough number of bytes this entry occupies, including its key and bookkeeping
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn compute_size(&self, key_len: usize) -> usize {
+        ENTRY_OVERHEAD + key_len + self.value.estimated_size()
+    }
+}
+
+impl Clone for Entry {
+    fn clone(&self) -> Self {
+        Self {
+            value: self.value.clone(),
+            expires_at: self.expires_at,
+            lru: AtomicU32::new(self.lru.load(Ordering::Relaxed)),
+            lfu: AtomicU32::new(self.lfu.load(Ordering::Relaxed)),
+            size: self.size,
+        }
+    }
+}
+
+// Mutable access to an entry that keeps the shard's memory accounting up to date
+pub struct EntryMut<'a> {
+    entry: &'a mut Entry,
+    key_len: usize,
+    used_memory: &'a mut usize,
+}
+
+impl Deref for EntryMut<'_> {
+    type Target = Entry;
+
+    fn deref(&self) -> &Entry {
+        self.entry
+    }
+}
+
+impl DerefMut for EntryMut<'_> {
+    fn deref_mut(&mut self) -> &mut Entry {
+        self.entry
+    }
+}
+
+impl Drop for EntryMut<'_> {
+    fn drop(&mut self) {
+        let size = self.entry.compute_size(self.key_len);
+        *self.used_memory = *self.used_memory - self.entry.size + size;
+        self.entry.size = size;
+    }
+}
+
+type ShardData = HashMap<String, Arc<Entry>>;
+
+// Entries are only reachable through methods so that the key indices and memory accounting
+// can't drift out of sync with the data. Lookups never return logically expired entries.
+//
+// The data is copy-on-write: a Snapshot shares it until the next write, which copies the map
+// (but not the entries) if the snapshot is still around, and then each entry it modifies.
+#[derive(Default)]
+pub struct Shard {
+    data: Arc<ShardData>,
+    // Every key ordered by its hash, which gives cheap random sampling
+    key_order: BTreeSet<(u64, String)>,
+    // Keys with an expiration, kept in a Vec so they can be sampled at random
+    volatile_keys: Vec<String>,
+    volatile_positions: HashMap<String, usize>,
+    used_memory: usize,
+    lookups: Lookups,
+}
+
+// Reads that found their key and ones that didn't, reported as keyspace_hits and
+// keyspace_misses. They outlive FLUSHDB, which only empties the shard.
+#[derive(Default)]
+struct Lookups {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Shard {
+    // Looks up a key for a command that reads it, which counts as an access
+    pub fn get(&self, key: &str) -> Option<&Entry> {
+        let entry = match self.peek(key) {
+            Some(x) => x,
+            None => {
+                self.lookups.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        self.lookups.hits.fetch_add(1, Ordering::Relaxed);
+        entry.touch();
+        Some(entry)
+    }
+
+    // Looks up an entry without counting it as an access for eviction purposes
+    pub fn peek(&self, key: &str) -> Option<&Entry> {
+        self.data.get(key).map(|x| &**x).filter(|x| !x.is_expired())
+    }
+
+    // Expiration must be changed through set_expiry rather than through the returned entry. The
+    // entry's size is re-estimated once the returned guard is dropped.
+    pub fn get_mut(&mut self, key: &str) -> Option<EntryMut<'_>> {
+        self.peek(key)?;
+        let entry = Arc::make_mut(Arc::make_mut(&mut self.data).get_mut(key)?);
+        entry.touch();
+        Some(EntryMut {
+            entry,
+            key_len: key.len(),
+            used_memory: &mut self.used_memory,
+        })
+    }
+
+    pub fn insert(&mut self, key: String, mut entry: Entry) -> Option<Arc<Entry>> {
+        let previous = self.remove_entry(&key);
+        if entry.expires_at.is_some() {
+            self.track_volatile(&key);
+        }
+        entry.size = entry.compute_size(key.len());
+        self.used_memory += entry.size;
+        self.key_order.insert((hash_key(&key), key.clone()));
+        Arc::make_mut(&mut self.data).insert(key, Arc::new(entry));
+        previous.filter(|x| !x.is_expired())
+    }
+
+    // The removed entry is shared with any snapshot still holding it, so dropping it is cheap
+    // unless it was the last reference
+    pub fn remove(&mut self, key: &str) -> Option<Arc<Entry>> {
+        self.remove_entry(key).filter(|x| !x.is_expired())
+    }
+
+    // Returns false if the key doesn't exist
+    pub fn set_expiry(&mut self, key: &str, expires_at: Option<u64>) -> bool {
+        match self.get_mut(key) {
+            Some(mut entry) => entry.expires_at = expires_at,
+            None => return false,
+        }
+        match expires_at {
+            Some(_) => self.track_volatile(key),
+            None => self.untrack_volatile(key),
+        }
+        true
+    }
+
+    // Whether the key is still there, but logically expired
+    pub fn has_expired(&self, key: &str) -> bool {
+        self.data.get(key).is_some_and(|x| x.is_expired())
+    }
+
+    // Physically deletes the key if it's logically expired, returning whether it did. `lazy`
+    // frees the entry on the background thread, see lazyfree::free.
+    pub fn remove_if_expired(&mut self, key: &str, lazy: bool) -> bool {
+        if self.has_expired(key) {
+            if let Some(entry) = self.remove_entry(key) {
+                lazyfree::free(entry, lazy);
+            }
+            return true;
+        }
+        false
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Entry)> {
+        self.data
+            .iter()
+            .map(|(key, entry)| (key, &**entry))
+            .filter(|(_, entry)| !entry.is_expired())
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn volatile_len(&self) -> usize {
+        self.volatile_keys.len()
+    }
+
+    // Keyspace hits and misses
+    pub fn lookups(&self) -> (u64, u64) {
+        (
+            self.lookups.hits.load(Ordering::Relaxed),
+            self.lookups.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn used_memory(&self) -> usize {
+        self.used_memory
+    }
+
+    pub fn random_key(&self) -> Option<&String> {
+        let start = (random_u64(), String::new());
+        self.key_order
+            .range(start..)
+            .next()
+            .or_else(|| self.key_order.iter().next())
+            .map(|(_, key)| key)
+    }
+
+    pub fn random_volatile_key(&self) -> Option<&String> {
+        if self.volatile_keys.is_empty() {
+            return None;
+        }
+        self.volatile_keys
+            .get(random_u64() as usize % self.volatile_keys.len())
+    }
+
+    // Live keys whose hash is at least `from`, in hash order. Stops after `count` keys, but never
+    // between two keys with the same hash. Also returns whether the end of the shard was reached.
+    fn scan_from(&self, from: u64, count: usize) -> (Vec<(u64, String)>, bool) {
+        let mut keys: Vec<(u64, String)> = Vec::new();
+        let live = self
+            .key_order
+            .range((from, String::new())..)
+            .filter(|(_, key)| self.peek(key).is_some());
+        for (hash, key) in live {
+            if keys.len() >= count && keys.last().is_some_and(|(x, _)| x != hash) {
+                return (keys, false);
+            }
+            keys.push((*hash, key.clone()));
+        }
+        (keys, true)
+    }
+
+    // Checks up to `count` randomly chosen keys with a TTL, deleting the ones that have expired.
+    // Returns the deleted keys.
+    pub fn expire_sample(&mut self, count: usize, lazy: bool) -> Vec<String> {
+        let mut expired = Vec::new();
+        for _ in 0..count.min(self.volatile_keys.len()) {
+            let key = match self.random_volatile_key() {
+                Some(x) => x.clone(),
+                None => break,
+            };
+            if self.remove_if_expired(&key, lazy) {
+                expired.push(key);
+            }
+        }
+        expired
+    }
+
+    // Removes the entry whether or not it's expired, keeping the indices and accounting in sync
+    fn remove_entry(&mut self, key: &str) -> Option<Arc<Entry>> {
+        let entry = Arc::make_mut(&mut self.data).remove(key)?;
+        self.untrack_volatile(key);
+        self.key_order.remove(&(hash_key(key), key.to_string()));
+        self.used_memory -= entry.size;
+        Some(entry)
+    }
+
+    fn track_volatile(&mut self, key: &str) {
+        if !self.volatile_positions.contains_key(key) {
+            self.volatile_positions
+                .insert(key.to_string(), self.volatile_keys.len());
+            self.volatile_keys.push(key.to_string());
+        }
+    }
+
+    fn untrack_volatile(&mut self, key: &str) {
+        if let Some(index) = self.volatile_positions.remove(key) {
+            self.volatile_keys.swap_remove(index);
+            if let Some(moved) = self.volatile_keys.get(index) {
+                self.volatile_positions.insert(moved.clone(), index);
+            }
+        }
+    }
+}
+
+// The keyspace is partitioned into shards selected by key hash so that commands on unrelated
+// keys don't contend on a single lock. Locks are never held across an await point.
+pub struct Store {
+    shards: Vec<RwLock<Shard>>,
+    // Best eviction candidates seen so far, kept between evictions
+    eviction_pool: Mutex<EvictionPool>,
+    // Keyspace events from the commands run so far, for the dispatcher to publish
+    pub notifications: Notifications,
+}
+
+// A panic while a shard is locked only ends the connection that panicked, see crash, so the
+// shard carries on being used rather than staying poisoned for everyone else
+fn read_lock(shard: &RwLock<Shard>) -> RwLockReadGuard<'_, Shard> {
+    shard.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write_lock(shard: &RwLock<Shard>) -> RwLockWriteGuard<'_, Shard> {
+    shard.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Store {
+    // Public
+    pub fn new() -> Self {
+        let shards = (0..NUM_SHARDS).map(|_| RwLock::default()).collect();
+        Self {
+            shards,
+            eviction_pool: Mutex::new(EvictionPool::new()),
+            notifications: Notifications::default(),
+        }
+    }
+
+    pub fn from_entries(entries: HashMap<String, Entry>) -> Self {
+        let store = Store::new();
+        for (key, entry) in entries {
+            store.write(&key).insert(key, entry);
+        }
+        store
+    }
+
+    pub fn read(&self, key: &str) -> RwLockReadGuard<'_, Shard> {
+        read_lock(&self.shards[self.shard_index(key)])
+    }
+
+    pub fn write(&self, key: &str) -> RwLockWriteGuard<'_, Shard> {
+        write_lock(&self.shards[self.shard_index(key)])
+    }
+
+    // Multi-key commands must go through here so that every caller acquires shard locks in
+    // ascending index order, which rules out lock-order deadlocks between them
+    pub fn write_keys(&self, keys: &[&str]) -> MultiShardGuard<'_> {
+        let mut indices: Vec<usize> = keys.iter().map(|key| self.shard_index(key)).collect();
+        indices.sort_unstable();
+        indices.dedup();
+        let guards = indices
+            .into_iter()
+            .map(|index| (index, write_lock(&self.shards[index])))
+            .collect();
+        MultiShardGuard {
+            store: self,
+            guards,
+        }
+    }
+
+    pub fn eviction_pool(&self) -> MutexGuard<'_, EvictionPool> {
+        self.eviction_pool
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn notify(&self, class: NotifyFlags, event: &'static str, key: &str) {
+        self.notifications.notify(class, event, key);
+    }
+
+    // Whether any of `keys` has expired without being deleted yet
+    pub fn any_expired(&self, keys: &[String]) -> bool {
+        keys.iter().any(|key| self.read(key).has_expired(key))
+    }
+
+    // Deletes whichever of `keys` have expired, returning the ones that were removed
+    pub fn expire_keys_if_needed(&self, keys: &[String], lazy: bool) -> Vec<String> {
+        keys.iter()
+            .filter(|key| self.write(key).remove_if_expired(key, lazy))
+            .cloned()
+            .collect()
+    }
+
+    // Read locks every shard, in the same ascending order as write_keys
+    pub fn read_all(&self) -> Vec<RwLockReadGuard<'_, Shard>> {
+        self.shards.iter().map(read_lock).collect()
+    }
+
+    pub fn shard_index(&self, key: &str) -> usize {
+        (hash_key(key) as usize) % self.shards.len()
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    // Write locks a single shard by index, for work that walks the keyspace shard by shard
+    pub fn write_shard(&self, index: usize) -> RwLockWriteGuard<'_, Shard> {
+        write_lock(&self.shards[index])
+    }
+
+    pub fn read_shard(&self, index: usize) -> RwLockReadGuard<'_, Shard> {
+        read_lock(&self.shards[index])
+    }
+
+    // Returns roughly `count` keys starting at `cursor`, along with the cursor to continue from,
+    // which is 0 once the iteration is complete.
+    //
+    // The cursor is a position in hash space: every key is visited in order of its hash, which
+    // never changes while the key exists. So a key that is present for the whole iteration is
+    // always returned exactly once, however much the keyspace is mutated in between calls. Keys
+    // added or removed during the iteration may or may not be returned. Each shard is only
+    // locked while it's being read, never for the whole iteration.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<String>) {
+        let count = count.max(1);
+        let mut candidates = Vec::new();
+        let mut exhausted = true;
+        for index in 0..self.shards.len() {
+            let (keys, done) = self.read_shard(index).scan_from(cursor, count);
+            candidates.extend(keys);
+            exhausted &= done;
+        }
+        candidates.sort_unstable();
+
+        // The count-th smallest hash overall is at most every shard's last collected hash, so
+        // each shard has contributed every key up to and including it
+        let mut taken = candidates.len().min(count);
+        if let Some((boundary, _)) = taken.checked_sub(1).map(|x| &candidates[x]) {
+            taken += candidates[taken..]
+                .iter()
+                .take_while(|(x, _)| x == boundary)
+                .count();
+        }
+        let next_cursor = match candidates.get(taken) {
+            Some((hash, _)) => *hash,
+            None if exhausted => 0,
+            None => candidates[taken - 1].0.wrapping_add(1),
+        };
+        candidates.truncate(taken);
+        (
+            next_cursor,
+            candidates.into_iter().map(|(_, key)| key).collect(),
+        )
+    }
+
+    // Keys that have expired but haven't been deleted yet are counted, as in Redis's DBSIZE
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|x| read_lock(x).len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn volatile_len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|x| read_lock(x).volatile_len())
+            .sum()
+    }
+
+    // Keyspace hits and misses in this database
+    pub fn lookups(&self) -> (u64, u64) {
+        self.shards
+            .iter()
+            .map(|x| read_lock(x).lookups())
+            .fold((0, 0), |(hits, misses), (x, y)| (hits + x, misses + y))
+    }
+
+    // A live key picked at random, each shard's chance being proportional to its size
+    pub fn random_key(&self) -> Option<String> {
+        for _ in 0..RANDOM_KEY_ATTEMPTS {
+            let shards = self.read_all();
+            let total: usize = shards.iter().map(|x| x.len()).sum();
+            if total == 0 {
+                return None;
+            }
+            let mut target = random_u64() as usize % total;
+            let shard = shards
+                .iter()
+                .find(|shard| match target.checked_sub(shard.len()) {
+                    Some(rest) => {
+                        target = rest;
+                        false
+                    }
+                    None => true,
+                })?;
+            if let Some(key) = shard.random_key().filter(|x| shard.peek(x).is_some()) {
+                return Some(key.clone());
+            }
+        }
+        None
+    }
+
+    pub fn used_memory(&self) -> usize {
+        self.shards.iter().map(|x| read_lock(x).used_memory()).sum()
+    }
+
+    // Deletes every key, as FLUSHDB does. `lazy` frees them on the background thread, see
+    // lazyfree::free.
+    pub fn flush(&self, lazy: bool) {
+        for shard in &self.shards {
+            // Taken out under the lock, but only freed once it's released
+            let data = {
+                let mut shard = write_lock(shard);
+                let emptied = Shard {
+                    lookups: std::mem::take(&mut shard.lookups),
+                    ..Shard::default()
+                };
+                std::mem::replace(&mut *shard, emptied).data
+            };
+            for entry in Arc::unwrap_or_clone(data).into_values() {
+                lazyfree::free(entry, lazy);
+            }
+        }
+        *self.eviction_pool() = EvictionPool::new();
+    }
+
+    // Exchanges every key with `other`, as SWAPDB does. Both are locked as a whole, this one
+    // first, so callers always pass the lower-numbered database as `self` to keep concurrent
+    // swaps from deadlocking.
+    pub fn swap(&self, other: &Store) {
+        // Eviction locks the pool before the shards it samples, so the pools are swapped first,
+        // and on their own
+        std::mem::swap(&mut *self.eviction_pool(), &mut *other.eviction_pool());
+        let mut ours: Vec<_> = self.shards.iter().map(write_lock).collect();
+        let mut theirs: Vec<_> = other.shards.iter().map(write_lock).collect();
+        for (a, b) in ours.iter_mut().zip(theirs.iter_mut()) {
+            std::mem::swap(&mut **a, &mut **b);
+        }
+    }
+
+    // A point-in-time view of every shard. The shards are only locked while their data is
+    // shared, so reading the snapshot doesn't hold up writes.
+    pub fn snapshot(&self) -> Snapshot {
+        let shards = self
+            .read_all()
+            .iter()
+            .map(|shard| Arc::clone(&shard.data))
+            .collect();
+        Snapshot {
+            shards,
+            taken_at: mstime(),
+        }
+    }
+}
+
+pub struct Snapshot {
+    shards: Vec<Arc<ShardData>>,
+    taken_at: u64,
+}
+
+impl Snapshot {
+    // Entries that were live when the snapshot was taken. Ones that have expired since are
+    // still returned, as they would be from a Redis fork.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Entry)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.iter())
+            .map(|(key, entry)| (key, &**entry))
+            .filter(|(_, entry)| entry.expires_at.is_none_or(|x| x > self.taken_at))
+    }
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn hash_key(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+// std's RandomState is seeded randomly per instance, which is plenty for sampling keys
+pub fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+pub struct MultiShardGuard<'a> {
+    store: &'a Store,
+    guards: Vec<(usize, RwLockWriteGuard<'a, Shard>)>,
+}
+
+impl MultiShardGuard<'_> {
+    pub fn shard(&mut self, key: &str) -> &mut Shard {
+        let index = self.store.shard_index(key);
+        match self.guards.iter_mut().find(|(x, _)| *x == index) {
+            Some((_, guard)) => guard,
+            None => panic!("Shard for key {} was not locked by this guard", key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::collections::HashSet;
+
+    fn insert(store: &Store, key: &str) {
+        let entry = Entry::new(Value::Str(Bytes::from("value").into()));
+        store.write(key).insert(key.to_string(), entry);
+    }
+
+    fn remove(store: &Store, key: &str) {
+        store.write(key).remove(key);
+    }
+
+    #[test]
+    fn scan_returns_every_key_once() {
+        let store = Store::new();
+        for i in 0..1000 {
+            insert(&store, &format!("key:{}", i));
+        }
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        loop {
+            let (next, keys) = store.scan(cursor, 7);
+            for key in keys {
+                assert!(seen.insert(key), "key returned twice");
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert_eq!(seen.len(), 1000);
+    }
+
+    #[test]
+    fn scan_returns_stable_keys_while_keyspace_changes() {
+        let store = Store::new();
+        for i in 0..500 {
+            insert(&store, &format!("stable:{}", i));
+            insert(&store, &format!("churn:{}", i));
+        }
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        let mut round = 0;
+        loop {
+            let (next, keys) = store.scan(cursor, 5);
+            for key in keys {
+                assert!(seen.insert(key), "key returned twice");
+            }
+            // Delete and add keys on both sides of the cursor between calls
+            remove(&store, &format!("churn:{}", round));
+            for i in 0..3 {
+                insert(&store, &format!("new:{}:{}", round, i));
+            }
+            round += 1;
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        for i in 0..500 {
+            assert!(seen.contains(&format!("stable:{}", i)));
+        }
+    }
+
+    #[test]
+    fn snapshots_are_unaffected_by_later_writes() {
+        let store = Store::new();
+        for i in 0..100 {
+            insert(&store, &format!("key:{}", i));
+        }
+        let snapshot = store.snapshot();
+        for i in 0..50 {
+            remove(&store, &format!("key:{}", i));
+            insert(&store, &format!("new:{}", i));
+        }
+        match store.write("key:99").get_mut("key:99") {
+            Some(mut entry) => entry.value = Value::Str(Bytes::from("changed").into()),
+            None => panic!("key:99 should exist"),
+        }
+
+        let mut keys: Vec<&String> = snapshot.iter().map(|(key, _)| key).collect();
+        keys.sort();
+        let mut expected: Vec<String> = (0..100).map(|i| format!("key:{}", i)).collect();
+        expected.sort();
+        assert_eq!(keys, expected.iter().collect::<Vec<_>>());
+        let (_, entry) = snapshot.iter().find(|(key, _)| *key == "key:99").unwrap();
+        assert!(matches!(&entry.value, Value::Str(x) if x.to_bytes() == "value"));
+    }
+
+    #[test]
+    fn scan_skips_expired_keys() {
+        let store = Store::new();
+        insert(&store, "live");
+        let mut entry = Entry::new(Value::Str(Bytes::from("value").into()));
+        entry.expires_at = Some(mstime());
+        store.write("dead").insert("dead".to_string(), entry);
+        assert_eq!(store.scan(0, 10), (0, vec!["live".to_string()]));
+    }
+
+    #[test]
+    fn multi_key_writes_in_opposite_orders_dont_deadlock() {
+        let store = Arc::new(Store::new());
+        let keys: Vec<String> = (0..64).map(|i| format!("key:{}", i)).collect();
+        let threads: Vec<_> = (0..8)
+            .map(|thread| {
+                let (store, mut keys) = (Arc::clone(&store), keys.clone());
+                if thread % 2 == 1 {
+                    keys.reverse();
+                }
+                std::thread::spawn(move || {
+                    for _ in 0..200 {
+                        let names: Vec<&str> = keys.iter().map(|x| x.as_str()).collect();
+                        let mut guard = store.write_keys(&names);
+                        for key in &names {
+                            let entry = Entry::new(Value::Str(Bytes::from("value").into()));
+                            guard.shard(key).insert(key.to_string(), entry);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let total: usize = store.read_all().iter().map(|x| x.len()).sum();
+        assert_eq!(total, keys.len());
+    }
+
+    #[test]
+    fn swapped_stores_exchange_keys_and_memory() {
+        let (first, second) = (Store::new(), Store::new());
+        for i in 0..10 {
+            insert(&first, &format!("key:{}", i));
+        }
+        insert(&second, "other");
+        let memory = (first.used_memory(), second.used_memory());
+        first.swap(&second);
+        assert_eq!((first.len(), second.len()), (1, 10));
+        assert_eq!((second.used_memory(), first.used_memory()), memory);
+        assert!(first.read("other").peek("other").is_some());
+        assert_eq!(second.scan(0, 100).1.len(), 10);
+
+        second.flush(false);
+        assert!(second.is_empty());
+        assert_eq!(second.used_memory(), 0);
+        assert_eq!(second.random_key(), None);
+    }
+}
@@ -0,0 +1,193 @@
+use bytes::Bytes;
+use core::fmt;
+use std::collections::BTreeMap;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Default)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    // Parses "ms-seq", or a bare "ms" taking `missing_seq` as its sequence number, which is how
+    // range ends include everything in their millisecond
+    pub fn parse(id: &str, missing_seq: u64) -> Option<Self> {
+        let (ms, seq) = match id.split_once('-') {
+            Some((ms, seq)) => (ms, seq.parse().ok()?),
+            None => (id, missing_seq),
+        };
+        Some(StreamId {
+            ms: ms.parse().ok()?,
+            seq,
+        })
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+// The ID XADD was given for its entry
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IdSpec {
+    // "*", the current time and the next free sequence number in it
+    Auto,
+    // "ms-*", the next free sequence number in that millisecond
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+impl IdSpec {
+    pub fn parse(id: &str) -> Option<Self> {
+        if id == "*" {
+            return Some(IdSpec::Auto);
+        }
+        match id.strip_suffix("-*") {
+            Some(ms) => ms.parse().ok().map(IdSpec::AutoSeq),
+            None => StreamId::parse(id, 0).map(IdSpec::Explicit),
+        }
+    }
+}
+
+// Why XADD refused an ID, as the error it replies with
+#[derive(Debug, PartialEq)]
+pub enum AddError {
+    Zero,
+    NotGreater,
+}
+
+impl fmt::Display for AddError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddError::Zero => write!(f, "ERR The ID specified in XADD must be greater than 0-0"),
+            AddError::NotGreater => write!(
+                f,
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+            ),
+        }
+    }
+}
+
+pub type StreamEntry = Vec<(Bytes, Bytes)>;
+
+#[derive(Default, Debug, Clone)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, StreamEntry>,
+    pub last_id: StreamId,
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Appends an entry, returning the ID it was given. IDs only ever grow, so that readers can
+    // pick up where they left off. `now_ms` is the UNIX time "*" is based on.
+    pub fn add(
+        &mut self,
+        id: IdSpec,
+        fields: StreamEntry,
+        now_ms: u64,
+    ) -> Result<StreamId, AddError> {
+        let id = match id {
+            IdSpec::Explicit(id) => id,
+            // Time going backwards mustn't make IDs do the same
+            IdSpec::Auto => self.next_id(now_ms.max(self.last_id.ms))?,
+            IdSpec::AutoSeq(ms) => self.next_id(ms)?,
+        };
+        if id == StreamId::default() {
+            return Err(AddError::Zero);
+        }
+        if id <= self.last_id {
+            return Err(AddError::NotGreater);
+        }
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        Ok(id)
+    }
+
+    fn next_id(&self, ms: u64) -> Result<StreamId, AddError> {
+        let seq = match ms.cmp(&self.last_id.ms) {
+            std::cmp::Ordering::Less => return Err(AddError::NotGreater),
+            std::cmp::Ordering::Equal => match self.last_id.seq.checked_add(1) {
+                Some(seq) => seq,
+                None => return Err(AddError::NotGreater),
+            },
+            // 0-0 is never a valid ID, so the first in millisecond 0 is 0-1
+            std::cmp::Ordering::Greater => (ms == 0) as u64,
+        };
+        Ok(StreamId { ms, seq })
+    }
+
+    // Entries from `start` to `end`, both inclusive
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+    ) -> impl Iterator<Item = (&StreamId, &StreamEntry)> {
+        // BTreeMap panics on ranges that end before they start, which select nothing in Redis
+        let entries = (start <= end).then(|| self.entries.range(start..=end));
+        entries.into_iter().flatten()
+    }
+
+    // Entries with an ID greater than `id`, as XREAD reads them
+    pub fn after(&self, id: StreamId) -> impl Iterator<Item = (&StreamId, &StreamEntry)> {
+        self.entries
+            .range((std::ops::Bound::Excluded(id), std::ops::Bound::Unbounded))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    #[test]
+    fn ids_only_ever_grow() {
+        let mut stream = Stream::new();
+        let add = |stream: &mut Stream, spec: &str, now_ms: u64| {
+            stream.add(IdSpec::parse(spec).unwrap(), Vec::new(), now_ms)
+        };
+        assert_eq!(add(&mut stream, "0-0", 0), Err(AddError::Zero));
+        assert_eq!(add(&mut stream, "0-*", 0), Ok(id(0, 1)));
+        assert_eq!(add(&mut stream, "5", 0), Ok(id(5, 0)));
+        assert_eq!(add(&mut stream, "5-*", 0), Ok(id(5, 1)));
+        assert_eq!(add(&mut stream, "5-1", 0), Err(AddError::NotGreater));
+        assert_eq!(add(&mut stream, "4-*", 0), Err(AddError::NotGreater));
+        assert_eq!(add(&mut stream, "*", 100), Ok(id(100, 0)));
+        assert_eq!(add(&mut stream, "*", 100), Ok(id(100, 1)));
+        // A clock that went backwards keeps using the last millisecond
+        assert_eq!(add(&mut stream, "*", 50), Ok(id(100, 2)));
+        assert!(IdSpec::parse("x-1").is_none());
+
+        let ids = |entries: Vec<(&StreamId, &StreamEntry)>| {
+            entries.into_iter().map(|(id, _)| *id).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ids(stream
+                .range(id(5, 0), StreamId::parse("100", u64::MAX).unwrap())
+                .collect()),
+            vec![id(5, 0), id(5, 1), id(100, 0), id(100, 1), id(100, 2)]
+        );
+        assert!(stream.range(id(100, 0), id(5, 0)).next().is_none());
+        assert_eq!(ids(stream.after(id(100, 1)).collect()), vec![id(100, 2)]);
+    }
+}
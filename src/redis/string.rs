@@ -0,0 +1,97 @@
+use bytes::Bytes;
+
+// Longest string stored inline in the entry rather than in its own allocation, as in Redis
+const EMBSTR_MAX_LEN: usize = 44;
+// i64::MIN is the longest integer, at 20 bytes
+const INT_MAX_LEN: usize = 20;
+
+pub const NOT_AN_INTEGER_ERROR: &str = "ERR value is not an integer or out of range";
+
+// String values are stored in the most compact of three encodings, chosen when they are written.
+// Commands only ever see the original bytes.
+#[derive(Debug, Clone)]
+pub enum StringValue {
+    Int(i64),
+    Embstr { len: u8, buf: [u8; EMBSTR_MAX_LEN] },
+    Raw(Bytes),
+}
+
+impl StringValue {
+    pub fn from_bytes(bytes: Bytes) -> Self {
+        if let Some(x) = parse_canonical_int(&bytes) {
+            return StringValue::Int(x);
+        }
+        if bytes.len() <= EMBSTR_MAX_LEN {
+            let mut buf = [0; EMBSTR_MAX_LEN];
+            buf[..bytes.len()].copy_from_slice(&bytes);
+            return StringValue::Embstr {
+                len: bytes.len() as u8,
+                buf,
+            };
+        }
+        StringValue::Raw(bytes)
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        match self {
+            StringValue::Int(x) => Bytes::from(x.to_string()),
+            StringValue::Embstr { len, buf } => Bytes::copy_from_slice(&buf[..*len as usize]),
+            StringValue::Raw(x) => x.clone(),
+        }
+    }
+
+    // The value as an integer, if it's the canonical representation of one
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            StringValue::Int(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            StringValue::Int(x) => x.to_string().len(),
+            StringValue::Embstr { len, .. } => *len as usize,
+            StringValue::Raw(x) => x.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The name reported by OBJECT ENCODING
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            StringValue::Int(_) => "int",
+            StringValue::Embstr { .. } => "embstr",
+            StringValue::Raw(_) => "raw",
+        }
+    }
+
+    // Bytes used beyond the entry itself. Only raw strings have a separate allocation.
+    pub fn estimated_size(&self) -> usize {
+        match self {
+            StringValue::Int(_) | StringValue::Embstr { .. } => 0,
+            StringValue::Raw(x) => x.len() + 16,
+        }
+    }
+}
+
+impl From<Bytes> for StringValue {
+    fn from(bytes: Bytes) -> Self {
+        StringValue::from_bytes(bytes)
+    }
+}
+
+// Only strings that print back identically become integers, so "007" or "+1" keep their bytes
+pub fn parse_canonical_int(bytes: &[u8]) -> Option<i64> {
+    if bytes.is_empty() || bytes.len() > INT_MAX_LEN {
+        return None;
+    }
+    let value: i64 = std::str::from_utf8(bytes).ok()?.parse().ok()?;
+    if value.to_string().as_bytes() != bytes {
+        return None;
+    }
+    Some(value)
+}
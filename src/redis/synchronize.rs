@@ -1,28 +1,129 @@
+use crate::debug;
+use crate::rdb::writer::write_snapshot;
 use crate::redis::commands::Command;
+use crate::redis::replica::{send_to_replicas, ReplicaLink};
+use crate::redis::state::Replication;
+use crate::redis::store::Snapshot;
 use crate::resp::resp_serializer::serialize_command;
+
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
-use tokio::sync::{Mutex, RwLock};
+use std::io::{self, Write};
+use std::sync::atomic::Ordering;
+use tokio::sync::mpsc;
+use tokio::task;
+
+// How much of the RDB file is handed over at a time, and how many pieces can be waiting to be
+// sent before serializing stops to let the connection catch up. The transfer has to make
+// progress at least once per replication timeout, one piece at a time.
+pub const RDB_CHUNK_SIZE: usize = 16 * 1024;
+const RDB_CHUNKS_IN_FLIGHT: usize = 4;
 
-extern crate base64;
+// Propagates a write to database `db`. Replicas apply the stream to whichever database it last
+// selected, so a SELECT goes out first whenever that changes.
+pub async fn propagate_command_to_replicas(
+    replication: &Replication,
+    db: usize,
+    command: &Command,
+) {
+    let mut data = Vec::new();
+    let mut connections = replication.replicas.write().await;
+    if replication.selected_db.swap(db, Ordering::SeqCst) != db {
+        data = serialize_command(&Command::Select(db.to_string()));
+    }
+    data.extend_from_slice(&serialize_command(command));
+    let offset = append(replication, &mut connections, &data);
+    debug!(
+        "Propagated {} to {} replicas, offset {}",
+        command.name(),
+        connections.len(),
+        offset
+    );
+    replication.write_offset.fetch_max(offset, Ordering::SeqCst);
+}
 
-const RDB_B64: &str = "UkVESVMwMDEx+glyZWRpcy12ZXIFNy4yLjD6CnJlZGlzLWJpdHPAQPoFY3RpbWXCbQi8ZfoIdXNlZC1tZW3CsMQQAPoIYW9mLWJhc2XAAP/wbjv+wP9aog==";
+// Appends `data` to the replication stream, moving the master's offset along with it. Every byte
+// sent to replicas has to go through here, or their acks won't line up with the offset. An empty
+// `data` just retries whatever replicas haven't taken yet. Returns the offset after `data`.
+pub async fn propagate(replication: &Replication, data: &[u8]) -> usize {
+    let mut connections = replication.replicas.write().await;
+    append(replication, &mut connections, data)
+}
 
-pub async fn propagate_command_to_replica(stream: Arc<RwLock<TcpStream>>, command: &Command) {
-    let serialized_command = serialize_command(command);
-    let mut stream = stream.write().await;
-    if let Err(e) = stream.write_all(serialized_command.as_bytes()).await {
-        println!("Failed to write to stream: {}", e);
+// Asks every replica for its offset. How each answers is checked against where the GETACK sits
+// in the stream, see ReplicaAck::record.
+pub async fn request_acks(replication: &Replication) {
+    let get_ack = serialize_command(&Command::ReplConf(
+        String::from("GETACK"),
+        vec![String::from("*")],
+    ));
+    let mut connections = replication.replicas.write().await;
+    let offset = replication.master_offset.load(Ordering::SeqCst);
+    for link in connections.values() {
+        link.ack.expect_answer_at(offset);
     }
-    if let Err(e) = stream.flush().await {
-        println!("Failed to flush stream: {}", e);
+    append(replication, &mut connections, &get_ack);
+}
+
+// The offset moves before anything is sent, so that no replica can ack bytes it doesn't cover.
+// The backlog moves along with it, so that it always ends at the master's offset.
+fn append(
+    replication: &Replication,
+    connections: &mut HashMap<i32, ReplicaLink>,
+    data: &[u8],
+) -> usize {
+    replication.backlog.lock().unwrap().append(data);
+    let offset = replication
+        .master_offset
+        .fetch_add(data.len(), Ordering::SeqCst)
+        + data.len();
+    send_to_replicas(connections, data);
+    offset
+}
+
+// Serializes `snapshot` as an RDB file on a blocking thread, handing it over a piece at a time
+// as it's written, so that only a few pieces of the file are ever in memory however big the
+// dataset is. The snapshot holds no locks, so writes carry on meanwhile. Serializing stops early
+// if the receiver is dropped.
+pub fn stream_rdb(snapshot: Vec<Snapshot>) -> mpsc::Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel(RDB_CHUNKS_IN_FLIGHT);
+    task::spawn_blocking(move || {
+        let out = ChunkWriter {
+            chunk: Vec::with_capacity(RDB_CHUNK_SIZE),
+            sender,
+        };
+        let _ = write_snapshot(out, &snapshot);
+    });
+    receiver
+}
+
+struct ChunkWriter {
+    chunk: Vec<u8>,
+    sender: mpsc::Sender<Vec<u8>>,
+}
+
+impl ChunkWriter {
+    fn send(&mut self) -> io::Result<()> {
+        let chunk = std::mem::replace(&mut self.chunk, Vec::with_capacity(RDB_CHUNK_SIZE));
+        self.sender
+            .blocking_send(chunk)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
     }
 }
 
-pub fn construct_rdb(_database: Arc<Mutex<HashMap<String, String>>>) -> (String, Vec<u8>) {
-    let binary_data = base64::decode(RDB_B64).expect("Failed to decode base64");
-    let length = binary_data.len();
-    (format!("${}\r\n", length), binary_data)
+impl Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.chunk.extend_from_slice(data);
+        if self.chunk.len() >= RDB_CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(data.len())
+    }
+
+    // RdbWriter flushes once the file is complete, which sends whatever's left
+    fn flush(&mut self) -> io::Result<()> {
+        match self.chunk.is_empty() {
+            true => Ok(()),
+            false => self.send(),
+        }
+    }
 }
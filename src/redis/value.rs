@@ -0,0 +1,180 @@
+use super::sorted_set::SortedSet;
+use super::stream::Stream;
+use super::string::StringValue;
+
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+pub const WRONGTYPE_ERROR: &str =
+    "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Str(StringValue),
+    List(VecDeque<Bytes>),
+    Hash(HashMap<Bytes, Bytes>),
+    Set(HashSet<Bytes>),
+    ZSet(SortedSet),
+    Stream(Stream),
+}
+
+// Returned when a command is run against a key holding a different type of value
+#[derive(Debug)]
+pub struct WrongType;
+
+impl Value {
+    // The name reported by the TYPE command
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    // Bytes for strings and elements for everything else, as the length commands report
+    pub fn len(&self) -> usize {
+        match self {
+            Value::Str(x) => x.len(),
+            Value::List(x) => x.len(),
+            Value::Hash(x) => x.len(),
+            Value::Set(x) => x.len(),
+            Value::ZSet(x) => x.len(),
+            Value::Stream(x) => x.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Rough number of bytes the value's contents occupy in memory. Collections are extrapolated
+    // from their first few elements so that this stays cheap to redo after every mutation.
+    pub fn estimated_size(&self) -> usize {
+        match self {
+            Value::Str(x) => x.estimated_size(),
+            Value::List(x) => extrapolate(x.len(), x.iter().map(|y| y.len() + 16)),
+            Value::Hash(x) => extrapolate(x.len(), x.iter().map(|(k, v)| k.len() + v.len() + 32)),
+            Value::Set(x) => extrapolate(x.len(), x.iter().map(|y| y.len() + 16)),
+            Value::ZSet(x) => extrapolate(x.len(), x.iter().map(|(member, _)| member.len() + 48)),
+            Value::Stream(x) => extrapolate(
+                x.len(),
+                x.entries.values().map(|fields| {
+                    fields
+                        .iter()
+                        .map(|(k, v)| k.len() + v.len() + 16)
+                        .sum::<usize>()
+                        + 32
+                }),
+            ),
+        }
+    }
+
+    // The name reported by OBJECT ENCODING
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::Str(x) => x.encoding(),
+            Value::List(_) => "quicklist",
+            Value::Hash(_) | Value::Set(_) => "hashtable",
+            Value::ZSet(x) => x.encoding(),
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    pub fn as_str(&self) -> Result<&StringValue, WrongType> {
+        match self {
+            Value::Str(x) => Ok(x),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_str_mut(&mut self) -> Result<&mut StringValue, WrongType> {
+        match self {
+            Value::Str(x) => Ok(x),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_list(&self) -> Result<&VecDeque<Bytes>, WrongType> {
+        match self {
+            Value::List(x) => Ok(x),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_list_mut(&mut self) -> Result<&mut VecDeque<Bytes>, WrongType> {
+        match self {
+            Value::List(x) => Ok(x),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_hash(&self) -> Result<&HashMap<Bytes, Bytes>, WrongType> {
+        match self {
+            Value::Hash(x) => Ok(x),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_hash_mut(&mut self) -> Result<&mut HashMap<Bytes, Bytes>, WrongType> {
+        match self {
+            Value::Hash(x) => Ok(x),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_set(&self) -> Result<&HashSet<Bytes>, WrongType> {
+        match self {
+            Value::Set(x) => Ok(x),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_set_mut(&mut self) -> Result<&mut HashSet<Bytes>, WrongType> {
+        match self {
+            Value::Set(x) => Ok(x),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_zset(&self) -> Result<&SortedSet, WrongType> {
+        match self {
+            Value::ZSet(x) => Ok(x),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_zset_mut(&mut self) -> Result<&mut SortedSet, WrongType> {
+        match self {
+            Value::ZSet(x) => Ok(x),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_stream(&self) -> Result<&Stream, WrongType> {
+        match self {
+            Value::Stream(x) => Ok(x),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_stream_mut(&mut self) -> Result<&mut Stream, WrongType> {
+        match self {
+            Value::Stream(x) => Ok(x),
+            _ => Err(WrongType),
+        }
+    }
+}
+
+const SIZE_SAMPLES: usize = 8;
+
+fn extrapolate(len: usize, element_sizes: impl Iterator<Item = usize>) -> usize {
+    let samples: Vec<usize> = element_sizes.take(SIZE_SAMPLES).collect();
+    if samples.is_empty() {
+        return 0;
+    }
+    samples.iter().sum::<usize>() * len / samples.len()
+}
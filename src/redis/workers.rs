@@ -0,0 +1,60 @@
+use crate::server::wait_for_shutdown;
+
+use std::cell::Cell;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::thread;
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::runtime::{self, Handle};
+use tokio::sync::watch;
+
+const LISTEN_BACKLOG: u32 = 1024;
+
+thread_local! {
+    static CURRENT_WORKER: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+// Starts `count` worker threads for thread-per-core mode. Each drives a single threaded runtime
+// of its own until shutdown, and is given work by spawning onto its handle.
+pub fn start_workers(count: usize, shutdown: watch::Receiver<bool>) -> Vec<Handle> {
+    (0..count)
+        .map(|id| {
+            let (sender, receiver) = mpsc::channel();
+            let mut shutdown = shutdown.clone();
+            thread::Builder::new()
+                .name(format!("worker-{}", id))
+                .spawn(move || {
+                    let runtime = runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .expect("Failed to build worker runtime");
+                    CURRENT_WORKER.with(|x| x.set(Some(id)));
+                    let _ = sender.send(runtime.handle().clone());
+                    runtime.block_on(wait_for_shutdown(&mut shutdown));
+                })
+                .expect("Failed to spawn worker thread");
+            receiver
+                .recv()
+                .expect("Worker thread exited while starting")
+        })
+        .collect()
+}
+
+// The worker running on this thread, if any
+pub fn current_worker() -> Option<usize> {
+    CURRENT_WORKER.with(|x| x.get())
+}
+
+// Every worker listens on the same port, and the kernel spreads incoming connections between
+// them
+pub fn bind_reuseport(address: SocketAddr) -> io::Result<TcpListener> {
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(address)?;
+    socket.listen(LISTEN_BACKLOG)
+}
@@ -1,11 +1,58 @@
+use bytes::Bytes;
+use core::fmt;
+
+pub mod inline;
 pub mod resp_deserializer;
 pub mod resp_serializer;
+pub mod shared;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum RespType {
     Integer(i64),
     SimpleString(String),
     Error(String),
-    BulkString(Option<String>),
+    BulkString(Option<Bytes>),
     Array(Vec<RespType>),
+    // *-1, which RESP2 uses for a missing array, as opposed to an empty one
+    NullArray,
+    // The types below were added in RESP3, and are only sent to connections that asked for it
+    // with HELLO. Elsewhere they're downgraded by resp_serializer::to_resp2.
+    Map(Vec<(RespType, RespType)>),
+    Set(Vec<RespType>),
+    Double(f64),
+    Boolean(bool),
+    // Arbitrary precision integers, kept as their decimal digits
+    BigNumber(String),
+    // A bulk string with a three character format, like "txt" or "mkd"
+    VerbatimString(String, Bytes),
+    Null,
+    // Out of band data, such as pub/sub messages
+    Push(Vec<RespType>),
+}
+
+// The protocol version a connection speaks, chosen with HELLO
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+impl Protocol {
+    pub fn parse(version: &str) -> Option<Self> {
+        match version {
+            "2" => Some(Protocol::Resp2),
+            "3" => Some(Protocol::Resp3),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Resp2 => write!(f, "2"),
+            Protocol::Resp3 => write!(f, "3"),
+        }
+    }
 }
@@ -0,0 +1,165 @@
+use super::resp_deserializer::ProtocolError;
+use super::RespType;
+
+use bytes::{Buf, Bytes, BytesMut};
+
+// The inline protocol: a command written as plain space separated words ending in a newline,
+// which is what typing into telnet or nc produces. Redis treats any request that doesn't start
+// with '*' this way.
+
+const MAX_INLINE_LENGTH: usize = 64 * 1024;
+
+// Consumes one line from the front of `buffer`, returning it as an array of bulk strings along
+// with its length in bytes, line ending included. Blank lines come back as an empty array.
+// Returns Ok(None) if the line hasn't been fully received yet.
+pub fn decode_inline(buffer: &mut BytesMut) -> Result<Option<(RespType, usize)>, ProtocolError> {
+    let line_end = match buffer.iter().position(|x| *x == b'\n') {
+        Some(x) if x <= MAX_INLINE_LENGTH => x,
+        None if buffer.len() <= MAX_INLINE_LENGTH => return Ok(None),
+        _ => return Err(ProtocolError(String::from("too big inline request"))),
+    };
+    let mut line = &buffer[..line_end];
+    if let Some(stripped) = line.strip_suffix(b"\r") {
+        line = stripped;
+    }
+    let args = split_args(line)
+        .ok_or_else(|| ProtocolError(String::from("unbalanced quotes in request")))?;
+    buffer.advance(line_end + 1);
+    let args = args
+        .into_iter()
+        .map(|x| RespType::BulkString(Some(Bytes::from(x))))
+        .collect();
+    Ok(Some((RespType::Array(args), line_end + 1)))
+}
+
+// Splits a line into arguments the way Redis does for inline commands and redis-cli for its
+// prompt. Double quoted arguments understand \n, \r, \t, \b, \a, \xHH and escaped quotes,
+// single quoted ones only \'. Returns None on unbalanced quotes.
+pub fn split_args(bytes: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut index = 0;
+    loop {
+        while index < bytes.len() && bytes[index].is_ascii_whitespace() {
+            index += 1;
+        }
+        if index >= bytes.len() {
+            return Some(args);
+        }
+        let mut arg = Vec::new();
+        match bytes[index] {
+            b'"' => {
+                index += 1;
+                loop {
+                    match *bytes.get(index)? {
+                        b'"' => break,
+                        b'\\' => {
+                            let escaped = *bytes.get(index + 1)?;
+                            index += 1;
+                            match escaped {
+                                b'n' => arg.push(b'\n'),
+                                b'r' => arg.push(b'\r'),
+                                b't' => arg.push(b'\t'),
+                                b'b' => arg.push(8),
+                                b'a' => arg.push(7),
+                                b'x' => {
+                                    let hex = bytes.get(index + 1..index + 3)?;
+                                    let value = std::str::from_utf8(hex)
+                                        .ok()
+                                        .and_then(|x| u8::from_str_radix(x, 16).ok());
+                                    match value {
+                                        Some(x) => {
+                                            arg.push(x);
+                                            index += 2;
+                                        }
+                                        None => arg.push(b'x'),
+                                    }
+                                }
+                                other => arg.push(other),
+                            }
+                        }
+                        other => arg.push(other),
+                    }
+                    index += 1;
+                }
+                index += 1;
+            }
+            b'\'' => {
+                index += 1;
+                loop {
+                    match *bytes.get(index)? {
+                        b'\'' => break,
+                        b'\\' if bytes.get(index + 1) == Some(&b'\'') => {
+                            arg.push(b'\'');
+                            index += 1;
+                        }
+                        other => arg.push(other),
+                    }
+                    index += 1;
+                }
+                index += 1;
+            }
+            _ => {
+                while index < bytes.len() && !bytes[index].is_ascii_whitespace() {
+                    arg.push(bytes[index]);
+                    index += 1;
+                }
+            }
+        }
+        // A closing quote must be followed by a space or the end of the line
+        if index < bytes.len() && !bytes[index].is_ascii_whitespace() {
+            return None;
+        }
+        args.push(arg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Option<Vec<String>> {
+        split_args(line.as_bytes()).map(|x| {
+            x.into_iter()
+                .map(|y| String::from_utf8(y).unwrap())
+                .collect()
+        })
+    }
+
+    #[test]
+    fn splits_on_whitespace_and_honours_quotes() {
+        assert_eq!(
+            args("  SET foo   bar "),
+            Some(vec!["SET".into(), "foo".into(), "bar".into()])
+        );
+        assert_eq!(
+            args(r#"SET "hello world" 'it\'s' "a\x41\n""#),
+            Some(vec![
+                "SET".into(),
+                "hello world".into(),
+                "it's".into(),
+                "aA\n".into()
+            ])
+        );
+        assert_eq!(args("GET \"unterminated"), None);
+        assert_eq!(args("GET \"closed\"trailing"), None);
+    }
+
+    #[test]
+    fn decodes_complete_lines_only() {
+        let mut buffer = BytesMut::from("PING\r\nECHO hi");
+        let (command, length) = decode_inline(&mut buffer).unwrap().unwrap();
+        assert_eq!(length, 6);
+        assert_eq!(
+            command,
+            RespType::Array(vec![RespType::BulkString(Some("PING".into()))])
+        );
+        assert_eq!(decode_inline(&mut buffer), Ok(None));
+        assert_eq!(&buffer[..], b"ECHO hi");
+    }
+
+    #[test]
+    fn rejects_oversized_lines() {
+        let mut buffer = BytesMut::from(&vec![b'a'; MAX_INLINE_LENGTH + 1][..]);
+        assert!(decode_inline(&mut buffer).is_err());
+    }
+}
@@ -1,15 +1,69 @@
+use super::inline::decode_inline;
+use super::resp_serializer::serialize_resp_data;
 use super::RespType;
 use crate::redis::commands::{self, Command};
+use crate::redis::connection::ConnectionReader;
+use crate::server::ServerError;
+use crate::{debug, verbose};
 
-use std::sync::Arc;
+use bytes::{Buf, Bytes, BytesMut};
+use core::fmt;
 use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
-use tokio::sync::RwLock;
+use tokio::time::{self, Duration};
+
+const READ_CHUNK_SIZE: usize = 4096;
+// Bulk strings at least this long are read straight into a buffer of exactly their size, as
+// Redis does for big arguments
+const BIG_BULK_LENGTH: usize = 32 * 1024;
+// Limits on what a peer may send, matching Redis's defaults where it has one
+pub const DEFAULT_MAX_BULK_LENGTH: usize = 512 * 1024 * 1024;
+pub const DEFAULT_MAX_MULTIBULK_LENGTH: usize = 1024 * 1024;
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+const MAX_NESTING_DEPTH: usize = 32;
+// Element counts come from the peer, so they're never trusted for more than this up front
+const MAX_PREALLOCATED_ELEMENTS: usize = 1024;
+// The marks around an RDB file streamed with $EOF:, 40 random characters like a run ID
+const RDB_EOF_MARK_LENGTH: usize = 40;
+
+// Malformed input. The stream can't be resynchronised afterwards, so the connection should be
+// closed.
+#[derive(Debug, PartialEq)]
+pub struct ProtocolError(pub String);
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Protocol error: {}", self.0)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+// The configurable limits, checked as soon as a header declares a length so that nothing is
+// buffered or allocated for an oversized frame
+#[derive(Clone, Copy, Debug)]
+pub struct FrameLimits {
+    pub max_bulk_length: usize,
+    pub max_multibulk_length: usize,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        FrameLimits {
+            max_bulk_length: DEFAULT_MAX_BULK_LENGTH,
+            max_multibulk_length: DEFAULT_MAX_MULTIBULK_LENGTH,
+        }
+    }
+}
 
 pub struct RespParser {
-    data: String,
-    index: usize,
-    stream: Arc<RwLock<TcpStream>>,
+    buffer: BytesMut,
+    stream: ConnectionReader,
+    decoder: FrameDecoder,
+    // A frame decoded by has_buffered_command, waiting to be returned by parse_command
+    peeked: Option<Result<(RespType, usize), ProtocolError>>,
+    // Blank lines consumed since the last command, which count as part of the next one so that
+    // the lengths returned add up to everything read
+    skipped: usize,
 }
 
 impl RespParser {
@@ -17,228 +71,749 @@ impl RespParser {
     // |                                         |
     // -------------------------------------------
 
-    pub fn new(data: String, stream: Arc<RwLock<TcpStream>>) -> RespParser {
+    pub fn new(buffer: BytesMut, stream: ConnectionReader) -> RespParser {
         RespParser {
-            data,
-            index: 0,
+            buffer,
             stream,
+            decoder: FrameDecoder::new(),
+            peeked: None,
+            skipped: 0,
         }
     }
 
-    pub async fn parse_command(&mut self) -> Option<(Command, usize)> {
-        assert!(self.index == 0);
-        let num_args = match self.find_num_args_in_array().await {
+    pub fn with_limits(mut self, limits: FrameLimits) -> RespParser {
+        self.decoder = FrameDecoder::with_limits(limits);
+        self
+    }
+
+    // Returns Ok(None) once the other side has closed the connection. A well formed frame that
+    // isn't a valid command comes back as the error to reply with, and the connection goes on.
+    pub async fn parse_command(
+        &mut self,
+    ) -> Result<Option<(Result<Command, String>, usize)>, ProtocolError> {
+        let (frame, bytes_processed) = match self.next_frame().await? {
             Some(x) => x,
-            None => return None,
+            None => return Ok(None),
         };
+        Ok(Some((frame_to_command(frame)?, bytes_processed)))
+    }
 
-        if !self.validate_array_length(num_args).await {
-            return None;
+    // Like parse_command, along with the command exactly as it was read, for a replica to pass
+    // its master's stream on unchanged. Masters send commands as arrays of bulk strings, which
+    // serialize back to the same bytes.
+    pub async fn parse_forwarded_command(
+        &mut self,
+    ) -> Result<Option<(Result<Command, String>, Vec<u8>)>, ProtocolError> {
+        let (frame, bytes_processed) = match self.next_frame().await? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let raw = serialize_resp_data(frame.clone());
+        if raw.len() != bytes_processed {
+            return Err(ProtocolError(String::from(
+                "expected the replication stream in its canonical form",
+            )));
         }
-        let data_before_processing = self.data.clone();
+        Ok(Some((frame_to_command(frame)?, raw)))
+    }
 
-        // Assume command_name will always be provided as a bulk string
-        let command_name = self.parse_bulk_string();
-        let mut command_data: Vec<RespType> = Vec::new();
-        for _ in 1..=(num_args - 1) {
-            if self.index > self.data.len() {
-                panic!("Index is >= length of data while parsing arguments");
-            }
-            match self.data.chars().nth(self.index).unwrap() {
-                '$' => command_data.push(self.parse_bulk_string()),
-                '+' => command_data.push(self.parse_bulk_string()),
-                other @ _ => panic!("Unsupported RESP data type encountered: {}", other),
-            }
+    // Whether another complete frame can be parsed without reading from the stream. Malformed
+    // input counts, so that parse_command gets to report it.
+    pub fn has_buffered_command(&mut self) -> bool {
+        if self.peeked.is_none() {
+            self.peeked = self.decode_buffered().transpose();
         }
-        let command = match command_name {
-            RespType::BulkString(x) => commands::args_to_command(&x.unwrap(), command_data),
-            _ => panic!("Expected Command Name to be provided as a bulk string"),
-        };
-        self.reset_data();
-        let bytes_processed = data_before_processing.len() - self.data.len();
-        return Some((command, bytes_processed));
+        self.peeked.is_some()
     }
 
-    pub async fn parse_handshake(&mut self) -> (String, Vec<u8>) {
-        assert!(self.index == 0);
-        // First parse the simple string
-        while !self.data.contains("\r\n") {
-            self.read_data_from_stream().await;
-        }
-        let simple = self.parse_simple_string();
-        self.reset_data();
+    // Gives up the connection, along with whatever had been read from it but not parsed
+    pub fn into_parts(self) -> (ConnectionReader, BytesMut) {
+        (self.stream, self.buffer)
+    }
 
-        let resync = match simple {
-            RespType::SimpleString(x) => x,
-            _ => panic!("Expected parse_simple_string to return a simple string"),
+    // Reads the master's reply to PSYNC and the RDB file that follows it, giving up if any read
+    // takes longer than `timeout`. A CONTINUE comes without one, as the stream just goes on.
+    pub async fn parse_handshake(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<(String, Option<Bytes>), ServerError> {
+        // First parse the simple string
+        let resync = loop {
+            if let Some(line_end) = find_crlf(&self.buffer, 0) {
+                let line = self.buffer.split_to(line_end + 2);
+                match line.first() {
+                    Some(b'+') => break String::from_utf8_lossy(&line[1..line_end]).into_owned(),
+                    _ => return Err("expected a simple string in reply to PSYNC".into()),
+                }
+            }
+            self.read_handshake_data(timeout).await?;
         };
-        let rdb = self.parse_rdb_file().await;
-        self.reset_data();
-        println!("Data after parsing RDB: {}", self.data);
-        println!("Length of data after parsing RDB: {}", self.data.len());
-        (resync, rdb)
+        if resync.starts_with("CONTINUE") {
+            return Ok((resync, None));
+        }
+        let rdb = self.parse_rdb_file(timeout).await?;
+        debug!("Length of data after parsing RDB: {}", self.buffer.len());
+        Ok((resync, Some(rdb)))
     }
 
     // ----------------- Private -----------------
     // |                                         |
     // -------------------------------------------
 
-    async fn read_data_from_stream(&mut self) -> Option<usize> {
-        let mut stream = self.stream.write().await;
-        let mut buffer: [u8; 1024] = [0; 1024];
-        match stream.read(&mut buffer).await {
-            // This should return none in future, panic is for debugging
-            Ok(0) => panic!("Stream was closed"),
-            Ok(bytes_read) => {
-                let valid_data = &buffer[..bytes_read];
-                let string_data = String::from_utf8_lossy(valid_data).to_string();
-                self.data += &string_data;
-                println!("========Recieved New Transmission========");
-                println!("{}", string_data);
-                println!("========End of New Transmission========");
-                Some(bytes_read)
+    // Decodes the next complete frame, reading from the stream until one is available. Bulk
+    // strings in the returned frame share the buffer's allocation.
+    async fn next_frame(&mut self) -> Result<Option<(RespType, usize)>, ProtocolError> {
+        if let Some(peeked) = self.peeked.take() {
+            return peeked.map(Some);
+        }
+        loop {
+            if let Some(frame) = self.decode_buffered()? {
+                return Ok(Some(frame));
+            }
+            if self.read_data_from_stream().await == 0 {
+                // other side has ended connection
+                return Ok(None);
             }
-            Err(_) => {
-                // In future, this function should return a result type
-                panic!("Error reading from stream");
+        }
+    }
+
+    // Decodes the next command already in the buffer. Like Redis, a command that doesn't start
+    // with '*' is read as an inline command, and turned into the array it stands for.
+    fn decode_buffered(&mut self) -> Result<Option<(RespType, usize)>, ProtocolError> {
+        let decoded = loop {
+            if !self.decoder.is_idle() || self.buffer.first().is_none_or(|x| *x == b'*') {
+                break self.decoder.decode(&mut self.buffer)?;
+            }
+            match decode_inline(&mut self.buffer)? {
+                // Blank lines are skipped, which lets clients use them as keepalives
+                Some((RespType::Array(args), length)) if args.is_empty() => self.skipped += length,
+                other => break other,
+            }
+        };
+        Ok(decoded.map(|(frame, length)| (frame, length + std::mem::take(&mut self.skipped))))
+    }
+
+    async fn read_data_from_stream(&mut self) -> usize {
+        // Frames are split off the front of the buffer. Once they've all been dropped, which
+        // happens as soon as their commands are built, this gets the same allocation back
+        // instead of making a new one.
+        //
+        // A big bulk string gets all the room it still needs at once, so its body is read into
+        // the allocation it will be stored in without the buffer growing, and copying it, along
+        // the way.
+        let wanted = match self.decoder.pending_blob_length() {
+            Some(x) if x >= BIG_BULK_LENGTH => x.saturating_sub(self.buffer.len()),
+            _ => READ_CHUNK_SIZE,
+        };
+        self.buffer.reserve(wanted.max(READ_CHUNK_SIZE));
+        match self.stream.read_buf(&mut self.buffer).await {
+            Ok(bytes_read) => bytes_read,
+            Err(e) => {
+                // A reset connection is as good as a closed one
+                verbose!("Error reading from stream: {}", e);
+                0
             }
         }
     }
 
-    async fn parse_rdb_file(&mut self) -> Vec<u8> {
-        while !self.data.contains("\r\n") {
-            self.read_data_from_stream().await;
+    async fn read_handshake_data(&mut self, timeout: Duration) -> Result<(), ServerError> {
+        match time::timeout(timeout, self.read_data_from_stream()).await {
+            Ok(0) => Err("master closed the connection during the handshake".into()),
+            Ok(_) => Ok(()),
+            Err(_) => Err(format!("no data from master for {}s", timeout.as_secs()).into()),
+        }
+    }
+
+    // The RDB file is sent like a bulk string, but without the trailing CRLF. A master streaming
+    // it without knowing its length sends $EOF:<mark> instead, and the mark again once it's done.
+    // Either way it's read as it arrives, and the buffer only grows to fit it.
+    async fn parse_rdb_file(&mut self, timeout: Duration) -> Result<Bytes, ServerError> {
+        let line = loop {
+            if let Some(line_end) = find_crlf(&self.buffer, 0) {
+                let line = self.buffer.split_to(line_end + 2);
+                break line.freeze().slice(..line_end);
+            }
+            self.read_handshake_data(timeout).await?;
+        };
+        let max_length = self.decoder.limits.max_bulk_length;
+        if let Some(mark) = line.strip_prefix(b"$EOF:") {
+            if mark.len() != RDB_EOF_MARK_LENGTH {
+                return Err("invalid RDB end of file mark".into());
+            }
+            // Only what's arrived since the last look needs searching, plus enough before it to
+            // catch a mark split between reads
+            let mut searched = 0;
+            loop {
+                if let Some(end) = find(&self.buffer[searched..], mark) {
+                    let rdb = self.buffer.split_to(searched + end).freeze();
+                    let _ = self.buffer.split_to(mark.len());
+                    return Ok(rdb);
+                }
+                if self.buffer.len() > max_length {
+                    return Err("RDB file longer than proto-max-bulk-len".into());
+                }
+                searched = self.buffer.len().saturating_sub(mark.len() - 1);
+                self.read_handshake_data(timeout).await?;
+            }
         }
-        if !self.check_next_substring("$") {
-            panic!("Expected bulk string indicator byte before RDB file");
+        if line.first() != Some(&b'$') {
+            return Err("expected a bulk string length before the RDB file".into());
+        }
+        let length = parse_integer(&line[1..], "RDB length")?;
+        let length = match usize::try_from(length) {
+            Ok(x) if x <= max_length => x,
+            _ => return Err(format!("invalid RDB length {}", length).into()),
+        };
+        self.buffer
+            .reserve(length.saturating_sub(self.buffer.len()) + READ_CHUNK_SIZE);
+        while self.buffer.len() < length {
+            self.read_handshake_data(timeout).await?;
         }
-        self.index += 1;
-        let length: usize = self
-            .read_to_crlf()
-            .parse()
-            .expect("Failed to convert RDB length to usize");
-        let mut remainder = &self.data[self.index..];
+        Ok(self.buffer.split_to(length).freeze())
+    }
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).position(|x| x == needle)
+}
 
-        println!("Length of RDB: {}", length);
-        println!("Length of remainder: {}", remainder.len());
-        // Now we must read the next x chars
-        // TODO: Stop hardcoding RDB size
-        let num_bytes: usize = 120;
-        while remainder.len() < length {
-            self.read_data_from_stream().await;
-            remainder = &self.data[self.index..];
+// The aggregate types, which differ only in the value they're turned into once every element has
+// arrived
+#[derive(Clone, Copy, Debug)]
+enum Aggregate {
+    Array,
+    Map,
+    Set,
+    Push,
+}
+
+// The types whose body follows their header line
+#[derive(Clone, Copy, Debug)]
+enum Blob {
+    Bulk,
+    Verbatim,
+}
+
+fn frame_to_command(frame: RespType) -> Result<Result<Command, String>, ProtocolError> {
+    let mut args = match frame {
+        RespType::Array(x) if !x.is_empty() => x,
+        _ => {
+            return Err(ProtocolError(String::from(
+                "expected a command as a non-empty array",
+            )))
+        }
+    };
+    let command_name = match args.remove(0) {
+        RespType::BulkString(Some(x)) => x,
+        _ => {
+            return Err(ProtocolError(String::from(
+                "expected the command name as a bulk string",
+            )))
         }
+    };
+    Ok(commands::args_to_command(
+        &String::from_utf8_lossy(&command_name),
+        args,
+    ))
+}
+
+// Incremental RESP decoder. Input can be fed in arbitrarily small pieces: whatever has been
+// decoded is consumed from the buffer and remembered, so no byte is examined twice, and nothing
+// the peer sends can make it panic or allocate more than the limits above allow.
+#[derive(Default)]
+pub struct FrameDecoder {
+    // Aggregates still being filled, innermost last, with the number of elements each still
+    // needs. Maps count their keys and values separately.
+    aggregates: Vec<(Aggregate, Vec<RespType>, usize)>,
+    // A blob whose header has been consumed but whose body hasn't arrived yet
+    blob: Option<(Blob, usize)>,
+    // Bytes of the current frame consumed so far
+    consumed: usize,
+    limits: FrameLimits,
+}
 
-        let rdb = remainder[0..num_bytes].as_bytes().to_vec();
-        self.index += num_bytes;
-        rdb
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    // Function assumes the data has been reset and leads with an asterisk
-    async fn find_num_args_in_array(&mut self) -> Option<usize> {
-        let mut remainder = self.data[self.index..].to_owned();
-        while !remainder.contains("\r\n") {
-            self.read_data_from_stream().await;
-            remainder = self.data[self.index..].to_owned();
+    pub fn with_limits(limits: FrameLimits) -> Self {
+        FrameDecoder {
+            limits,
+            ..Self::default()
         }
-        if !self.check_next_substring("*") {
-            panic!("Expected first character to be * while finding num args");
+    }
+
+    // Whether the decoder is between frames, rather than part way through one
+    pub fn is_idle(&self) -> bool {
+        self.aggregates.is_empty() && self.blob.is_none() && self.consumed == 0
+    }
+
+    // Bytes still needed to finish a bulk string whose header has been decoded, including the
+    // trailing CRLF
+    pub fn pending_blob_length(&self) -> Option<usize> {
+        self.blob.map(|(_, length)| length + 2)
+    }
+
+    // Consumes the next complete frame from the front of `buffer`, returning it along with its
+    // length in bytes. Returns Ok(None) if more input is needed.
+    pub fn decode(
+        &mut self,
+        buffer: &mut BytesMut,
+    ) -> Result<Option<(RespType, usize)>, ProtocolError> {
+        loop {
+            let mut value = match self.blob {
+                Some((kind, length)) => {
+                    if buffer.len() < length + 2 {
+                        return Ok(None);
+                    }
+                    if &buffer[length..length + 2] != b"\r\n" {
+                        return Err(ProtocolError(String::from(
+                            "bulk string not terminated by CRLF",
+                        )));
+                    }
+                    let data = buffer.split_to(length).freeze();
+                    buffer.advance(2);
+                    self.consumed += length + 2;
+                    self.blob = None;
+                    match kind {
+                        Blob::Bulk => RespType::BulkString(Some(data)),
+                        Blob::Verbatim => verbatim(data)?,
+                    }
+                }
+                None => match self.decode_line(buffer)? {
+                    Some(x) => x,
+                    None if self.blob.is_some() => continue,
+                    None => return Ok(None),
+                },
+            };
+            // decode_line only returns a value once it's complete, so it can be added to the
+            // innermost aggregate, which may complete that aggregate in turn
+            loop {
+                match self.aggregates.last_mut() {
+                    None => {
+                        let length = std::mem::take(&mut self.consumed);
+                        return Ok(Some((value, length)));
+                    }
+                    Some((_, elements, remaining)) => {
+                        elements.push(value);
+                        *remaining -= 1;
+                        if *remaining > 0 {
+                            break;
+                        }
+                    }
+                }
+                let (kind, elements, _) = self.aggregates.pop().expect("Checked above");
+                value = build_aggregate(kind, elements);
+            }
         }
-        self.index += 1;
-        let num_args: i32 = self
-            .read_to_crlf()
-            .parse()
-            .expect("Could not parse num args into usize");
-        if num_args <= 0 {
-            panic!("Expected num_args to be > 0");
+    }
+
+    // Consumes header lines up to the first complete value, or the first blob header, or until
+    // the buffer runs out. Aggregate headers just open a new aggregate and move on to the next
+    // line.
+    fn decode_line(&mut self, buffer: &mut BytesMut) -> Result<Option<RespType>, ProtocolError> {
+        loop {
+            let line_end = match find_crlf(buffer, 0) {
+                Some(x) if x <= MAX_LINE_LENGTH => x,
+                Some(_) => return Err(ProtocolError(String::from("line too long"))),
+                None if buffer.len() > MAX_LINE_LENGTH => {
+                    return Err(ProtocolError(String::from("line too long")))
+                }
+                None => return Ok(None),
+            };
+            let line = buffer.split_to(line_end + 2);
+            self.consumed += line_end + 2;
+            let body = &line[1.min(line_end)..line_end];
+            let aggregate = match line[0] {
+                b'+' => return Ok(Some(RespType::SimpleString(lossy(body)))),
+                b'-' => return Ok(Some(RespType::Error(lossy(body)))),
+                b':' => return Ok(Some(RespType::Integer(parse_integer(body, "integer")?))),
+                b'_' if body.is_empty() => return Ok(Some(RespType::Null)),
+                b'#' => match body {
+                    b"t" => return Ok(Some(RespType::Boolean(true))),
+                    b"f" => return Ok(Some(RespType::Boolean(false))),
+                    _ => return Err(ProtocolError(String::from("invalid boolean"))),
+                },
+                b',' => return Ok(Some(RespType::Double(parse_double(body)?))),
+                b'(' => return Ok(Some(RespType::BigNumber(parse_big_number(body)?))),
+                b'$' | b'=' => {
+                    let kind = if line[0] == b'$' {
+                        Blob::Bulk
+                    } else {
+                        Blob::Verbatim
+                    };
+                    match parse_integer(body, "bulk length")? {
+                        -1 if line[0] == b'$' => return Ok(Some(RespType::BulkString(None))),
+                        length if within(length, 0, self.limits.max_bulk_length) => {
+                            self.blob = Some((kind, length as usize));
+                            return Ok(None);
+                        }
+                        _ => return Err(ProtocolError(String::from("invalid bulk length"))),
+                    }
+                }
+                b'*' => Aggregate::Array,
+                b'%' => Aggregate::Map,
+                b'~' => Aggregate::Set,
+                b'>' => Aggregate::Push,
+                other => {
+                    return Err(ProtocolError(format!(
+                        "expected '$', got '{}'",
+                        other as char
+                    )))
+                }
+            };
+            let length = match (parse_integer(body, "multibulk length")?, aggregate) {
+                (-1, Aggregate::Array) => return Ok(Some(RespType::NullArray)),
+                (0, kind) => return Ok(Some(build_aggregate(kind, Vec::new()))),
+                (length, _) if within(length, 1, self.limits.max_multibulk_length) => {
+                    length as usize
+                }
+                _ => return Err(ProtocolError(String::from("invalid multibulk length"))),
+            };
+            if self.aggregates.len() >= MAX_NESTING_DEPTH {
+                return Err(ProtocolError(String::from("arrays nested too deeply")));
+            }
+            let elements = match aggregate {
+                Aggregate::Map => length * 2,
+                _ => length,
+            };
+            let capacity = elements.min(MAX_PREALLOCATED_ELEMENTS);
+            self.aggregates
+                .push((aggregate, Vec::with_capacity(capacity), elements));
+        }
+    }
+}
+
+// Decodes a buffer made up entirely of complete frames, such as the replies to a batch of
+// commands
+pub fn parse_frames(data: Bytes) -> Result<Vec<RespType>, ProtocolError> {
+    let mut buffer = BytesMut::from(&data[..]);
+    let mut decoder = FrameDecoder::new();
+    let mut frames = Vec::new();
+    while let Some((frame, _)) = decoder.decode(&mut buffer)? {
+        frames.push(frame);
+    }
+    if !buffer.is_empty() {
+        return Err(ProtocolError(String::from("incomplete frame")));
+    }
+    Ok(frames)
+}
+
+fn find_crlf(data: &[u8], start: usize) -> Option<usize> {
+    if start >= data.len() {
+        return None;
+    }
+    data[start..]
+        .windows(2)
+        .position(|window| window == b"\r\n")
+        .map(|x| x + start)
+}
+
+fn parse_integer(data: &[u8], what: &str) -> Result<i64, ProtocolError> {
+    std::str::from_utf8(data)
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| ProtocolError(format!("invalid {}", what)))
+}
+
+fn parse_double(data: &[u8]) -> Result<f64, ProtocolError> {
+    std::str::from_utf8(data)
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| ProtocolError(String::from("invalid double")))
+}
+
+fn parse_big_number(data: &[u8]) -> Result<String, ProtocolError> {
+    let digits = data.strip_prefix(b"-").unwrap_or(data);
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return Err(ProtocolError(String::from("invalid big number")));
+    }
+    Ok(lossy(data))
+}
+
+// Verbatim strings start with their format and a colon, as in "txt:Some text"
+fn verbatim(mut data: Bytes) -> Result<RespType, ProtocolError> {
+    if data.len() < 4 || data[3] != b':' {
+        return Err(ProtocolError(String::from("invalid verbatim string")));
+    }
+    let format = lossy(&data.split_to(4)[..3]);
+    Ok(RespType::VerbatimString(format, data))
+}
+
+fn build_aggregate(kind: Aggregate, elements: Vec<RespType>) -> RespType {
+    match kind {
+        Aggregate::Array => RespType::Array(elements),
+        Aggregate::Set => RespType::Set(elements),
+        Aggregate::Push => RespType::Push(elements),
+        Aggregate::Map => {
+            let mut elements = elements.into_iter();
+            let mut pairs = Vec::with_capacity(elements.len() / 2);
+            while let (Some(key), Some(value)) = (elements.next(), elements.next()) {
+                pairs.push((key, value));
+            }
+            RespType::Map(pairs)
         }
-        Some(num_args as usize)
     }
+}
+
+fn within(length: i64, min: i64, max: usize) -> bool {
+    length >= min && (length as u64) <= max as u64
+}
+
+fn lossy(data: &[u8]) -> String {
+    String::from_utf8_lossy(data).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis::connection::Connection;
+    use crate::resp::resp_serializer::serialize_resp_data;
+    use tokio::io::AsyncWriteExt;
+
+    const CASES: u64 = 256;
+
+    // A xorshift generator, so every case can be reproduced from its seed
+    struct Rng(u64);
 
-    async fn validate_array_length(&mut self, num_args: usize) -> bool {
-        if num_args == 0 {
-            panic!("Expected num_args to be greater than zero");
+    impl Rng {
+        fn new(seed: u64) -> Rng {
+            Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
         }
-        let mut num_crlfs = num_args;
-        let mut remainder = self.data[self.index..].to_owned();
-        let mut validating_index: usize = self.index;
-        // Index might be greater than length after parsing num_args
-        while num_crlfs > 0 || self.index >= self.data.len() {
-            if self.check_next_substring("*") {
-                match self.find_num_args_in_array().await {
-                    Some(x) => num_crlfs += x,
-                    None => return false,
+
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn bytes(&mut self, max: u64) -> Vec<u8> {
+            (0..self.below(max)).map(|_| self.next() as u8).collect()
+        }
+
+        // Simple strings and errors can't contain CR or LF
+        fn line(&mut self) -> String {
+            (0..self.below(16))
+                .filter_map(|_| char::from_u32(self.below(0x800) as u32))
+                .filter(|x| *x != '\r' && *x != '\n')
+                .collect()
+        }
+
+        fn digits(&mut self) -> String {
+            let sign = if self.below(2) == 0 { "" } else { "-" };
+            let digits: String = (0..1 + self.below(40))
+                .map(|_| char::from(b'0' + self.below(10) as u8))
+                .collect();
+            format!("{}{}", sign, digits)
+        }
+
+        fn resp_type(&mut self, depth: u32) -> RespType {
+            let kinds = if depth == 0 { 10 } else { 14 };
+            match self.below(kinds) {
+                0 => RespType::Integer(self.next() as i64),
+                1 => RespType::SimpleString(self.line()),
+                2 => RespType::Error(self.line()),
+                3 => RespType::NullArray,
+                4 => RespType::Null,
+                5 if self.below(4) == 0 => RespType::BulkString(None),
+                5 => RespType::BulkString(Some(Bytes::from(self.bytes(32)))),
+                6 => RespType::Boolean(self.below(2) == 0),
+                // NaN isn't equal to itself, so it can't be compared after a round trip
+                7 => match f64::from_bits(self.next()) {
+                    x if x.is_nan() => RespType::Double(f64::INFINITY),
+                    x => RespType::Double(x),
+                },
+                8 => RespType::BigNumber(self.digits()),
+                9 => {
+                    let format = (0..3).map(|_| char::from(b'a' + self.below(26) as u8));
+                    RespType::VerbatimString(format.collect(), Bytes::from(self.bytes(32)))
                 }
+                10 => RespType::Array(self.elements(depth - 1)),
+                11 => RespType::Set(self.elements(depth - 1)),
+                12 => RespType::Push(self.elements(depth - 1)),
+                _ => RespType::Map(
+                    (0..self.below(4))
+                        .map(|_| (self.resp_type(depth - 1), self.resp_type(depth - 1)))
+                        .collect(),
+                ),
             }
-            if let Some(x) = remainder.find("\r\n") {
-                num_crlfs -= 1;
-                validating_index += x + 2;
-                remainder = self.data[validating_index..].to_owned();
-            } else {
-                self.read_data_from_stream().await;
+        }
+
+        fn elements(&mut self, depth: u32) -> Vec<RespType> {
+            (0..self.below(8)).map(|_| self.resp_type(depth)).collect()
+        }
+    }
+
+    // Feeds `data` to a fresh decoder `chunk_size` bytes at a time
+    fn decode_in_chunks(data: &[u8], chunk_size: usize) -> Result<Vec<RespType>, ProtocolError> {
+        let mut decoder = FrameDecoder::new();
+        let mut buffer = BytesMut::new();
+        let mut frames = Vec::new();
+        let mut consumed = 0;
+        for chunk in data.chunks(chunk_size.max(1)) {
+            buffer.extend_from_slice(chunk);
+            while let Some((frame, length)) = decoder.decode(&mut buffer)? {
+                consumed += length;
+                frames.push(frame);
             }
         }
-        true
-    }
-
-    fn parse_simple_string(&mut self) -> RespType {
-        if !self.check_next_substring("+") {
-            panic!("Failed to find simple string indicator byte (+)");
-        }
-        self.index += 1;
-        let simple_string = self.read_to_crlf();
-        RespType::SimpleString(simple_string)
-    }
-
-    fn parse_bulk_string(&mut self) -> RespType {
-        if self.data.chars().nth(self.index).unwrap() != '$' {
-            panic!("Failed to find bulk string indicator byte $");
-        }
-        self.index += 1;
-        let length: i32 = self
-            .read_to_crlf()
-            .parse()
-            .expect("Could not convert Bulk String length to i32");
-        if length == -1 {
-            return RespType::BulkString(None);
-        } else if length <= 0 {
-            panic!("Expected bulk string length to be greater than 0");
-        }
-        let bulk_string = self.read_to_crlf();
-        if bulk_string.len() != length as usize {
-            panic!(
-                "{}",
-                format!(
-                    "Bulk string length: {} did not match provided length: {}",
-                    bulk_string.len(),
-                    length
-                )
+        // A frame cut short at the end is held by the decoder until the rest arrives
+        assert_eq!(consumed + decoder.consumed + buffer.len(), data.len());
+        Ok(frames)
+    }
+
+    #[test]
+    fn serialized_frames_round_trip() {
+        for seed in 0..CASES {
+            let mut rng = Rng::new(seed);
+            let frames: Vec<RespType> = (0..1 + rng.below(3)).map(|_| rng.resp_type(3)).collect();
+            let data: Vec<u8> = frames
+                .iter()
+                .cloned()
+                .flat_map(serialize_resp_data)
+                .collect();
+            assert_eq!(parse_frames(Bytes::from(data)), Ok(frames), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn chunking_doesnt_change_the_result() {
+        for seed in 0..CASES {
+            let mut rng = Rng::new(seed);
+            let frame = rng.resp_type(3);
+            let data = serialize_resp_data(frame.clone());
+            let chunk_size = 1 + rng.below(15) as usize;
+            assert_eq!(
+                decode_in_chunks(&data, chunk_size),
+                Ok(vec![frame]),
+                "seed {}",
+                seed
             );
         }
-        RespType::BulkString(Some(bulk_string))
     }
 
-    fn check_next_substring(&self, sequence: &str) -> bool {
-        let remainder = &self.data[self.index..];
-        if sequence.len() > remainder.len() {
-            return false;
-        } else if &remainder[self.index..(self.index + sequence.len())] == sequence {
-            return true;
+    #[test]
+    fn arbitrary_input_never_panics() {
+        for seed in 0..CASES {
+            let mut rng = Rng::new(seed);
+            let _ = decode_in_chunks(&rng.bytes(256), 7);
+            // Corrupting a byte of a valid frame reaches further into the decoder than noise does
+            let mut data = serialize_resp_data(rng.resp_type(3));
+            let index = rng.below(data.len() as u64) as usize;
+            data[index] = rng.next() as u8;
+            let _ = decode_in_chunks(&data, 7);
         }
-        false
     }
 
-    fn reset_data(&mut self) {
-        self.data = self.data[self.index..].to_string();
-        self.index = 0;
+    #[test]
+    fn truncated_frames_wait_for_more_input() {
+        for seed in 0..CASES {
+            let data = serialize_resp_data(Rng::new(seed).resp_type(3));
+            for cut in 0..data.len() {
+                let mut buffer = BytesMut::from(&data[..cut]);
+                assert_eq!(
+                    FrameDecoder::new().decode(&mut buffer),
+                    Ok(None),
+                    "seed {}",
+                    seed
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn inline_commands_and_blank_lines_are_counted_in_full() {
+        let (ours, mut theirs) = tokio::net::UnixStream::pair().unwrap();
+        let (stream, _) = Connection::Unix(ours).into_split();
+        let mut parser = RespParser::new(BytesMut::new(), stream);
+        theirs
+            .write_all(b"\r\n\nPING\r\n*1\r\n$4\r\nPING\r\n\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            parser.parse_command().await,
+            Ok(Some((Ok(Command::Ping), 9)))
+        );
+        assert_eq!(
+            parser.parse_command().await,
+            Ok(Some((Ok(Command::Ping), 14)))
+        );
+        theirs.write_all(b"\nECHO hi\n").await.unwrap();
+        assert_eq!(
+            parser.parse_command().await,
+            Ok(Some((Ok(Command::Echo(String::from("hi"))), 10)))
+        );
+    }
+
+    #[test]
+    fn malformed_input_is_rejected() {
+        let cases: [&[u8]; 13] = [
+            b"?foo\r\n",
+            b"#x\r\n",
+            b",1.2.3\r\n",
+            b"(12a\r\n",
+            b"=2\r\nab\r\n",
+            b"%-1\r\n",
+            b"$abc\r\n",
+            b"$-2\r\n",
+            b"$3\r\nfoobar\r\n",
+            b"*-5\r\n",
+            b"*99999999999\r\n",
+            b":12x\r\n",
+            b"\r\n",
+        ];
+        for case in cases {
+            let mut buffer = BytesMut::from(case);
+            assert!(
+                FrameDecoder::new().decode(&mut buffer).is_err(),
+                "{:?} was accepted",
+                String::from_utf8_lossy(case)
+            );
+        }
+    }
+
+    #[test]
+    fn oversized_lines_are_rejected_without_a_crlf() {
+        let mut buffer = BytesMut::from(&vec![b'+'; MAX_LINE_LENGTH + 2][..]);
+        assert!(FrameDecoder::new().decode(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn configured_limits_are_enforced_before_reading_the_body() {
+        let limits = FrameLimits {
+            max_bulk_length: 4,
+            max_multibulk_length: 2,
+        };
+        let mut buffer = BytesMut::from(&b"$4\r\nabcd\r\n*2\r\n:1\r\n:2\r\n"[..]);
+        let mut decoder = FrameDecoder::with_limits(limits);
+        assert!(decoder.decode(&mut buffer).unwrap().is_some());
+        assert!(decoder.decode(&mut buffer).unwrap().is_some());
+
+        // Only the header of the oversized bulk string has arrived
+        let mut buffer = BytesMut::from(&b"$5\r\n"[..]);
+        assert_eq!(
+            FrameDecoder::with_limits(limits).decode(&mut buffer),
+            Err(ProtocolError(String::from("invalid bulk length")))
+        );
+        let mut buffer = BytesMut::from(&b"*3\r\n"[..]);
+        assert_eq!(
+            FrameDecoder::with_limits(limits).decode(&mut buffer),
+            Err(ProtocolError(String::from("invalid multibulk length")))
+        );
+        let mut buffer = BytesMut::from(&b"$10737418240\r\n"[..]);
+        assert!(FrameDecoder::new().decode(&mut buffer).is_err());
     }
 
-    fn read_to_crlf(&mut self) -> String {
-        let remainder = &self.data[self.index..];
-        let crlf_index: usize = remainder
-            .find("\r\n")
-            .expect("Could not find CRLF while reading to CRLF");
-        self.index += crlf_index + 2;
-        remainder[0..crlf_index].to_owned()
+    #[test]
+    fn deep_nesting_is_rejected() {
+        let data = "*1\r\n".repeat(MAX_NESTING_DEPTH + 1);
+        let mut buffer = BytesMut::from(data.as_bytes());
+        assert!(FrameDecoder::new().decode(&mut buffer).is_err());
     }
 }
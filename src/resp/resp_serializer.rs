@@ -1,68 +1,372 @@
-use super::RespType;
+use super::shared;
+use super::{Protocol, RespType};
 use crate::redis::commands::Command;
 
-fn serialize_bulk_string(data: String) -> String {
-    let str_len = data.len();
-    String::from(&format!("${}\r\n{}\r\n", str_len, data))
+use bytes::Bytes;
+
+pub fn serialize_resp_data(data: RespType) -> Vec<u8> {
+    let mut serialized = Vec::new();
+    serialize_into(&data, &mut serialized);
+    serialized
 }
 
-fn serialize_simple_string(data: String) -> String {
-    String::from(&format!("+{}\r\n", data))
+// Appends `data` to `out`, so that nested frames all go into one allocation
+pub fn serialize_into(data: &RespType, out: &mut Vec<u8>) {
+    match data {
+        RespType::BulkString(Some(x)) => {
+            shared::write_bulk_header(x.len(), out);
+            out.extend_from_slice(x);
+            out.extend_from_slice(b"\r\n");
+        }
+        RespType::BulkString(None) => out.extend_from_slice(shared::NULL_BULK),
+        RespType::Array(x) => {
+            shared::write_array_header(x.len(), out);
+            for element in x {
+                serialize_into(element, out);
+            }
+        }
+        RespType::NullArray => out.extend_from_slice(shared::NULL_ARRAY),
+        RespType::SimpleString(x) => write_line(b'+', x, out),
+        RespType::Error(x) => write_line(b'-', x, out),
+        RespType::Integer(x) => {
+            out.push(b':');
+            shared::write_decimal(*x, out);
+            out.extend_from_slice(b"\r\n");
+        }
+        RespType::Map(x) => {
+            shared::write_length_header(b'%', x.len(), out);
+            for (key, value) in x {
+                serialize_into(key, out);
+                serialize_into(value, out);
+            }
+        }
+        RespType::Set(x) => write_aggregate(b'~', x, out),
+        RespType::Push(x) => write_aggregate(b'>', x, out),
+        RespType::Double(x) => write_line(b',', &format_double(*x), out),
+        RespType::Boolean(x) => write_line(b'#', if *x { "t" } else { "f" }, out),
+        RespType::BigNumber(x) => write_line(b'(', x, out),
+        RespType::VerbatimString(format, x) => {
+            shared::write_length_header(b'=', format.len() + 1 + x.len(), out);
+            out.extend_from_slice(format.as_bytes());
+            out.push(b':');
+            out.extend_from_slice(x);
+            out.extend_from_slice(b"\r\n");
+        }
+        RespType::Null => out.extend_from_slice(shared::NULL),
+    }
 }
 
-fn serialize_integer(data: i64) -> String {
-    String::from(&format!(":{}\r\n", data))
+// Serializes a reply for a connection speaking `protocol`
+pub fn serialize_for(data: RespType, protocol: Protocol) -> Vec<u8> {
+    match protocol {
+        Protocol::Resp2 => serialize_resp_data(to_resp2(data)),
+        Protocol::Resp3 => serialize_resp_data(data),
+    }
 }
 
-fn serialize_array(data: Vec<RespType>) -> String {
-    let length = data.len();
-    let mut serialized = String::from(&format!("*{}\r\n", length));
-    for x in data {
-        serialized.push_str(&serialize_resp_data(x));
+// Replaces the RESP3 only types with what Redis sends RESP2 clients in their place
+pub fn to_resp2(data: RespType) -> RespType {
+    match data {
+        RespType::Array(x) | RespType::Set(x) | RespType::Push(x) => {
+            RespType::Array(x.into_iter().map(to_resp2).collect())
+        }
+        RespType::Map(x) => RespType::Array(
+            x.into_iter()
+                .flat_map(|(key, value)| [to_resp2(key), to_resp2(value)])
+                .collect(),
+        ),
+        RespType::Double(x) => RespType::BulkString(Some(Bytes::from(format_double(x)))),
+        RespType::Boolean(x) => RespType::Integer(x as i64),
+        RespType::BigNumber(x) => RespType::BulkString(Some(Bytes::from(x))),
+        RespType::VerbatimString(_, x) => RespType::BulkString(Some(x)),
+        RespType::Null => RespType::BulkString(None),
+        other => other,
     }
-    serialized
 }
 
-pub fn serialize_resp_data(data: RespType) -> String {
-    let serialized = match data {
-        RespType::BulkString(x) => serialize_bulk_string(x.as_ref().unwrap().to_string()),
-        RespType::Array(x) => serialize_array(x),
-        RespType::SimpleString(x) => serialize_simple_string(x.to_string()),
-        RespType::Integer(x) => serialize_integer(x),
-        other @ _ => panic!("Serialization isn't support for {:?}", other),
-    };
-    serialized
+pub fn create_null_string() -> Vec<u8> {
+    shared::NULL_BULK.to_vec()
 }
 
-pub fn create_null_string() -> String {
-    String::from("$-1\r\n")
+fn write_aggregate(prefix: u8, elements: &[RespType], out: &mut Vec<u8>) {
+    shared::write_length_header(prefix, elements.len(), out);
+    for element in elements {
+        serialize_into(element, out);
+    }
 }
 
-// TODO: Eventually I should be able to use this function for all commands
-pub fn serialize_command(command: &Command) -> String {
-    match command {
-        Command::Set(key, value, expiry) => {
-            let mut serialized: Vec<RespType> = vec![
-                RespType::BulkString(Some(String::from("SET"))),
-                RespType::BulkString(Some(String::from(format!("{}", key)))),
-                RespType::BulkString(Some(String::from(format!("{}", value)))),
-            ];
-            if let Some(x) = expiry {
-                serialized.push(RespType::BulkString(Some(String::from(format!("{}", x)))));
+// RESP3 spells out infinities and NaN in lowercase
+fn format_double(value: f64) -> String {
+    if value.is_nan() {
+        return String::from("nan");
+    }
+    value.to_string()
+}
+
+fn write_line(prefix: u8, line: &str, out: &mut Vec<u8>) {
+    out.push(prefix);
+    out.extend_from_slice(line.as_bytes());
+    out.extend_from_slice(b"\r\n");
+}
+
+// The command as a RESP array, the way masters send it down the replication stream
+pub fn serialize_command(command: &Command) -> Vec<u8> {
+    let args = command
+        .to_args()
+        .into_iter()
+        .map(|x| RespType::BulkString(Some(x)))
+        .collect();
+    serialize_resp_data(RespType::Array(args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis::command_table;
+    use crate::redis::commands::{args_to_command, Expiry, SetCondition, SetOptions};
+    use crate::resp::resp_deserializer::parse_frames;
+
+    fn parse(serialized: Vec<u8>) -> Command {
+        let mut args = match parse_frames(Bytes::from(serialized)).unwrap().pop() {
+            Some(RespType::Array(x)) => x,
+            other => panic!("Expected an array, got {:?}", other),
+        };
+        match args.remove(0) {
+            RespType::BulkString(Some(name)) => {
+                args_to_command(&String::from_utf8_lossy(&name), args).unwrap()
             }
-            let x = serialize_resp_data(RespType::Array(serialized));
-            x
+            other => panic!("Expected a command name, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn every_command_survives_a_round_trip() {
+        let requests = [
+            "PING",
+            "ECHO hello",
+            "SET k v EX 10 NX GET",
+            "SET k v PXAT 1700000000000 XX",
+            "SET k v KEEPTTL",
+            "GET k",
+            "INFO",
+            "INFO replication",
+            "INFO server clients",
+            "REPLCONF GETACK *",
+            "REPLCONF capa",
+            "REPLCONF capa eof capa psync2",
+            "PSYNC ? -1",
+            "WAIT 1 500",
+            "CONFIG GET dir",
+            "CONFIG GET maxmemory* save",
+            "CONFIG SET maxmemory 1mb appendfsync always",
+            "KEYS *",
+            "DEL a b",
+            "EXISTS a a",
+            "RENAME a b",
+            "RANDOMKEY",
+            "DBSIZE",
+            "FLUSHDB",
+            "FLUSHDB ASYNC",
+            "FLUSHALL SYNC",
+            "SHUTDOWN",
+            "SHUTDOWN NOSAVE",
+            "MONITOR",
+            "DEBUG PANIC",
+            "SLOWLOG GET",
+            "SLOWLOG GET -1",
+            "SLOWLOG LEN",
+            "SLOWLOG RESET",
+            "SWAPDB 0 1",
+            "OBJECT encoding k",
+            "MEMORY USAGE k",
+            "SCAN 0 TYPE string",
+            "SCAN 0 MATCH user:* COUNT 5",
+            "HELLO 3",
+            "HELLO 3 AUTH default secret",
+            "AUTH secret",
+            "AUTH default secret",
+            "QUIT",
+            "SELECT 0",
+            "TYPE k",
+            "STRLEN k",
+            "LLEN k",
+            "HLEN k",
+            "SCARD k",
+            "ZCARD k",
+            "XLEN k",
+            "ACL SETUSER alice on >secret ~* +@all",
+            "ACL LIST",
+            "ACL LOG RESET",
+            "ROLE",
+            "CLIENT ID",
+            "CLIENT SETNAME worker",
+            "CLIENT GETNAME",
+            "CLIENT LIST",
+            "CLIENT KILL 127.0.0.1:6380",
+            "CLIENT KILL ID 5 USER alice SKIPME no",
+            "REPLICAOF 127.0.0.1 6380",
+            "REPLICAOF NO ONE",
+            "LATENCY HISTOGRAM set get",
+            "TTL k",
+            "PTTL k",
+            "PERSIST k",
+            "EXPIRE k 10",
+            "PEXPIREAT k 1700000000000",
+            "INCR k",
+            "DECR k",
+            "INCRBY k 5",
+            "DECRBY k -5",
+            "MGET a b a",
+            "MSET a 1 b 2",
+            "MSETNX a 1",
+            "APPEND k v",
+            "GETRANGE k 0 -1",
+            "SETRANGE k 5 v",
+            "SETBIT k 7 1",
+            "GETBIT k 7",
+            "BITCOUNT k",
+            "BITCOUNT k 1 -2 BIT",
+            "BITOP AND d a b",
+            "BITOP NOT d a",
+            "LPUSH k a b",
+            "RPUSH k a",
+            "LRANGE k 0 -1",
+            "LPOP k 2",
+            "RPOP k",
+            "BLPOP a b 1.5",
+            "BRPOP k 0",
+            "HSET k f v g w",
+            "HGET k f",
+            "HDEL k f g",
+            "HGETALL k",
+            "ZADD k 1.5 a -inf b",
+            "ZRANGE k 0 -1 WITHSCORES",
+            "ZSCORE k a",
+            "ZRANK k a",
+            "XADD k 5-* f v",
+            "XADD k * f v",
+            "XRANGE k - + COUNT 2",
+            "XREAD COUNT 2 BLOCK 0 STREAMS a b 1-1 $",
+            "MULTI",
+            "EXEC",
+            "DISCARD",
+            "SUBSCRIBE a b",
+            "UNSUBSCRIBE",
+            "PSUBSCRIBE a*",
+            "PUNSUBSCRIBE a*",
+            "PUBLISH a hello",
+            "SAVE",
+            "BGSAVE",
+            "BGREWRITEAOF",
+            "EVAL script 2 a b arg",
+            "EVALSHA 6b1bf486c81ceb7edf3c093f4c48582e38c0e791 0",
+            "SCRIPT LOAD script",
+            "SCRIPT EXISTS 6b1bf486c81ceb7edf3c093f4c48582e38c0e791 abc",
+            "SCRIPT FLUSH",
+            "SCRIPT FLUSH ASYNC",
+            "COMMAND",
+            "COMMAND COUNT",
+            "COMMAND INFO get config",
+            "COMMAND DOCS",
+            "COMMAND GETKEYS SET k v",
+        ];
+        let mut covered = Vec::new();
+        for request in requests {
+            let mut args = request.split(' ');
+            let name = args.next().unwrap();
+            let args = args
+                .map(|x| RespType::BulkString(Some(Bytes::from(x.to_string()))))
+                .collect();
+            let command = args_to_command(name, args).unwrap();
+            assert_eq!(parse(serialize_command(&command)), command, "{}", request);
+            covered.push(command.name());
+        }
+        for spec in command_table::all() {
+            assert!(covered.contains(&spec.name), "{} isn't covered", spec.name);
         }
-        Command::ReplConf(arg1, arg2_optional) => {
-            let mut serialized: Vec<RespType> = vec![
-                RespType::BulkString(Some(String::from("REPLCONF"))),
-                RespType::BulkString(Some(arg1.to_owned())),
-            ];
-            if let Some(arg2) = arg2_optional {
-                serialized.push(RespType::BulkString(Some(String::from(arg2))));
-            };
-            serialize_resp_data(RespType::Array(serialized))
+    }
+
+    #[test]
+    fn every_variant_is_serialized() {
+        let cases = [
+            (RespType::Integer(-42), ":-42\r\n"),
+            (RespType::SimpleString(String::from("OK")), "+OK\r\n"),
+            (RespType::Error(String::from("ERR bad")), "-ERR bad\r\n"),
+            (
+                RespType::BulkString(Some(Bytes::from("hi"))),
+                "$2\r\nhi\r\n",
+            ),
+            (RespType::BulkString(Some(Bytes::new())), "$0\r\n\r\n"),
+            (RespType::BulkString(None), "$-1\r\n"),
+            (RespType::Array(Vec::new()), "*0\r\n"),
+            (RespType::NullArray, "*-1\r\n"),
+            (RespType::Null, "_\r\n"),
+            (RespType::Boolean(false), "#f\r\n"),
+            (RespType::Double(f64::NAN), ",nan\r\n"),
+            (RespType::BigNumber(String::from("-123")), "(-123\r\n"),
+            (RespType::Push(vec![RespType::Integer(1)]), ">1\r\n:1\r\n"),
+            (
+                RespType::Array(vec![
+                    RespType::Integer(1),
+                    RespType::Array(vec![RespType::BulkString(None)]),
+                ]),
+                "*2\r\n:1\r\n*1\r\n$-1\r\n",
+            ),
+        ];
+        for (frame, expected) in cases {
+            assert_eq!(serialize_resp_data(frame), expected.as_bytes());
         }
-        other @ _ => panic!("Serialization unsupported for {:?}", other),
+    }
+
+    #[test]
+    fn absolute_expirations_are_propagated_as_pxat() {
+        let set = Command::Set(
+            String::from("k"),
+            Bytes::from("v"),
+            SetOptions {
+                expiry: Some(Expiry::At(1700000000000)),
+                condition: Some(SetCondition::IfExists),
+                ..SetOptions::default()
+            },
+        );
+        assert_eq!(
+            serialize_command(&set),
+            b"*6\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n$4\r\nPXAT\r\n$13\r\n1700000000000\r\n$2\r\nXX\r\n"
+        );
+        let expire = Command::Expire(String::from("k"), Expiry::At(1700000000000));
+        assert_eq!(
+            serialize_command(&expire),
+            b"*3\r\n$9\r\nPEXPIREAT\r\n$1\r\nk\r\n$13\r\n1700000000000\r\n"
+        );
+    }
+
+    #[test]
+    fn resp3_types_are_downgraded_for_resp2() {
+        let reply = RespType::Map(vec![
+            (
+                RespType::SimpleString(String::from("pi")),
+                RespType::Double(3.5),
+            ),
+            (
+                RespType::SimpleString(String::from("flags")),
+                RespType::Set(vec![RespType::Boolean(true), RespType::Null]),
+            ),
+            (
+                RespType::SimpleString(String::from("text")),
+                RespType::VerbatimString(String::from("txt"), Bytes::from("hi")),
+            ),
+        ]);
+        assert_eq!(
+            serialize_for(reply.clone(), Protocol::Resp3),
+            b"%3\r\n+pi\r\n,3.5\r\n+flags\r\n~2\r\n#t\r\n_\r\n+text\r\n=6\r\ntxt:hi\r\n"
+        );
+        assert_eq!(
+            serialize_for(reply, Protocol::Resp2),
+            b"*6\r\n+pi\r\n$3\r\n3.5\r\n+flags\r\n*2\r\n:1\r\n$-1\r\n+text\r\n$2\r\nhi\r\n"
+        );
+        assert_eq!(
+            serialize_resp_data(RespType::Double(f64::NEG_INFINITY)),
+            b",-inf\r\n"
+        );
     }
 }
@@ -0,0 +1,85 @@
+use std::sync::LazyLock;
+
+// Replies and reply fragments that come up often enough to keep serialized once, like the
+// shared objects in Redis
+
+pub const OK: &[u8] = b"+OK\r\n";
+pub const PONG: &[u8] = b"+PONG\r\n";
+pub const NULL_BULK: &[u8] = b"$-1\r\n";
+pub const NULL_ARRAY: &[u8] = b"*-1\r\n";
+pub const NULL: &[u8] = b"_\r\n";
+
+// Lengths below this get a prebuilt bulk string or array header
+const SHARED_HEADERS: usize = 32;
+
+static BULK_HEADERS: LazyLock<Vec<Vec<u8>>> = LazyLock::new(|| headers(b'$'));
+static ARRAY_HEADERS: LazyLock<Vec<Vec<u8>>> = LazyLock::new(|| headers(b'*'));
+
+fn headers(prefix: u8) -> Vec<Vec<u8>> {
+    (0..SHARED_HEADERS)
+        .map(|x| format!("{}{}\r\n", prefix as char, x).into_bytes())
+        .collect()
+}
+
+pub fn write_bulk_header(length: usize, out: &mut Vec<u8>) {
+    write_header(&BULK_HEADERS, b'$', length, out);
+}
+
+pub fn write_array_header(length: usize, out: &mut Vec<u8>) {
+    write_header(&ARRAY_HEADERS, b'*', length, out);
+}
+
+// For the less common RESP3 aggregates, which don't get prebuilt headers
+pub fn write_length_header(prefix: u8, length: usize, out: &mut Vec<u8>) {
+    write_header(&[], prefix, length, out);
+}
+
+fn write_header(shared: &[Vec<u8>], prefix: u8, length: usize, out: &mut Vec<u8>) {
+    match shared.get(length) {
+        Some(header) => out.extend_from_slice(header),
+        None => {
+            out.push(prefix);
+            write_decimal(length as i64, out);
+            out.extend_from_slice(b"\r\n");
+        }
+    }
+}
+
+// Formats `value` straight into `out`, without going through a String
+pub fn write_decimal(value: i64, out: &mut Vec<u8>) {
+    let mut digits = [0u8; 20];
+    let mut remaining = value.unsigned_abs();
+    let mut start = digits.len();
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (remaining % 10) as u8;
+        remaining /= 10;
+        if remaining == 0 {
+            break;
+        }
+    }
+    if value < 0 {
+        out.push(b'-');
+    }
+    out.extend_from_slice(&digits[start..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_match_formatted_ones() {
+        for length in [0, 1, 31, 32, 1000] {
+            let mut out = Vec::new();
+            write_bulk_header(length, &mut out);
+            write_array_header(length, &mut out);
+            assert_eq!(out, format!("${}\r\n*{}\r\n", length, length).into_bytes());
+        }
+        for value in [0, 7, -7, 1234567890, i64::MIN, i64::MAX] {
+            let mut out = Vec::new();
+            write_decimal(value, &mut out);
+            assert_eq!(out, value.to_string().into_bytes());
+        }
+    }
+}
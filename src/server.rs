@@ -0,0 +1,364 @@
+use crate::aof::writer::AppendFsync;
+use crate::client::Client;
+use crate::config::{Config, ProtectedAccess};
+use crate::log::LogLevel;
+use crate::redis::connection::Listener;
+use crate::redis::eviction::EvictionPolicy;
+use crate::redis::keyspace::KeyspaceMode;
+use crate::redis::notify::NotifyFlags;
+use crate::redis::output::{ClientClass, OutputBufferLimit};
+use crate::redis::scripting::{Script, ScriptCall, ScriptFuture, Scripts};
+use crate::redis::workers::bind_reuseport;
+use crate::redis::Redis;
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+use tokio::net::{lookup_host, TcpListener};
+use tokio::sync::watch;
+
+pub type ServerError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+// An embeddable server. Built with Server::builder(), then driven to completion with run(),
+// which returns once shutdown() is called on the server or one of its ShutdownHandles.
+pub struct Server {
+    redis: Redis,
+    local_addr: SocketAddr,
+    shutdown: ShutdownHandle,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            config: Config::default(),
+            host: String::from("127.0.0.1"),
+            scripts: Scripts::default(),
+        }
+    }
+
+    // Builds a server from an already assembled config, such as one parsed from the command line
+    pub async fn from_config(config: Config) -> Result<Self, ServerError> {
+        ServerBuilder {
+            config,
+            host: String::from("127.0.0.1"),
+            scripts: Scripts::default(),
+        }
+        .build()
+        .await
+    }
+
+    // The address actually bound, which is how to find the port when building with port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // A client that talks to this server in process. Take as many as needed before calling run.
+    pub fn client(&self) -> Client {
+        Client::new(self.redis.dispatcher())
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    pub fn shutdown(&self) {
+        self.shutdown.shutdown();
+    }
+
+    pub async fn run(mut self) -> Result<(), ServerError> {
+        self.redis.listen().await
+    }
+}
+
+pub struct ServerBuilder {
+    config: Config,
+    host: String,
+    scripts: Scripts,
+}
+
+impl ServerBuilder {
+    pub fn host(mut self, host: &str) -> Self {
+        self.host = host.to_string();
+        self
+    }
+
+    // Port 0 binds an ephemeral port, see Server::local_addr
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port.to_string();
+        self
+    }
+
+    pub fn replica_of(mut self, host: &str, port: u16) -> Self {
+        self.config
+            .set_replica_of(host.to_string(), port.to_string());
+        self
+    }
+
+    pub fn dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.rdb_dir = Some(dir.into());
+        self
+    }
+
+    pub fn dbfilename(mut self, filename: impl Into<PathBuf>) -> Self {
+        self.config.rdb_filename = Some(filename.into());
+        self
+    }
+
+    // Save points as (seconds, changes), none turning automatic saving off
+    pub fn save(mut self, points: &[(u64, u64)]) -> Self {
+        self.config.save = points.to_vec();
+        self
+    }
+
+    // Log writes to the AOF, and load the dataset from it, fsyncing as `fsync` says
+    pub fn appendonly(mut self, fsync: AppendFsync) -> Self {
+        self.config.appendonly = true;
+        self.config.appendfsync = fsync;
+        self
+    }
+
+    // Also accept connections on a unix socket at `path`
+    pub fn unixsocket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.unixsocket = Some(path.into());
+        self
+    }
+
+    // Permissions for the unix socket, like 0o700
+    pub fn unixsocketperm(mut self, mode: u32) -> Self {
+        self.config.unixsocketperm = mode;
+        self
+    }
+
+    pub fn keyspace_mode(mut self, mode: KeyspaceMode) -> Self {
+        self.config.keyspace_mode = mode;
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.config.threads = threads;
+        self
+    }
+
+    pub fn maxmemory(mut self, bytes: usize) -> Self {
+        self.config.maxmemory = bytes;
+        self
+    }
+
+    pub fn maxmemory_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.config.maxmemory_policy = policy;
+        self
+    }
+
+    pub fn databases(mut self, databases: usize) -> Self {
+        self.config.databases = databases;
+        self
+    }
+
+    pub fn hz(mut self, hz: u64) -> Self {
+        self.config.hz = hz;
+        self
+    }
+
+    // How long each step of the replication handshake, and each read or write of the RDB
+    // transfer, may take before the link is given up on
+    pub fn repl_timeout(mut self, seconds: u64) -> Self {
+        self.config.repl_timeout = seconds;
+        self
+    }
+
+    // Bytes of the replication stream kept for replicas that reconnect
+    pub fn repl_backlog_size(mut self, bytes: usize) -> Self {
+        self.config.repl_backlog_size = bytes;
+        self
+    }
+
+    pub fn replica_serve_stale_data(mut self, serve: bool) -> Self {
+        self.config.replica_serve_stale_data = serve;
+        self
+    }
+
+    // 0 leaves the number of connections from one IP unlimited
+    pub fn max_connections_per_ip(mut self, limit: usize) -> Self {
+        self.config.max_connections_per_ip = limit;
+        self
+    }
+
+    // New connections accepted per second, 0 for no limit
+    pub fn max_accept_rate(mut self, rate: usize) -> Self {
+        self.config.max_accept_rate = rate;
+        self
+    }
+
+    pub fn maxclients(mut self, limit: usize) -> Self {
+        self.config.maxclients = limit;
+        self
+    }
+
+    // Seconds a client may sit idle before it's disconnected, 0 for never
+    pub fn timeout(mut self, seconds: u64) -> Self {
+        self.config.timeout = seconds;
+        self
+    }
+
+    // 0 turns keepalive off for accepted connections
+    pub fn tcp_keepalive(mut self, seconds: u64) -> Self {
+        self.config.tcp_keepalive = seconds;
+        self
+    }
+
+    pub fn latency_tracking(mut self, track: bool) -> Self {
+        self.config.latency_tracking = track;
+        self
+    }
+
+    pub fn latency_tracking_info_percentiles(mut self, percentiles: &[f64]) -> Self {
+        self.config.latency_tracking_info_percentiles = percentiles.to_vec();
+        self
+    }
+
+    pub fn acllog_max_len(mut self, length: usize) -> Self {
+        self.config.acllog_max_len = length;
+        self
+    }
+
+    // Negative turns the slow log off, 0 logs every command
+    pub fn slowlog_log_slower_than(mut self, usec: i64) -> Self {
+        self.config.slowlog_log_slower_than = usec;
+        self
+    }
+
+    pub fn slowlog_max_len(mut self, length: usize) -> Self {
+        self.config.slowlog_max_len = length;
+        self
+    }
+
+    // Which keyspace events are published over pub/sub, see NotifyFlags::parse
+    pub fn notify_keyspace_events(mut self, flags: NotifyFlags) -> Self {
+        self.config.notify_keyspace_events = flags;
+        self
+    }
+
+    pub fn requirepass(mut self, password: &str) -> Self {
+        self.config.requirepass = Some(password.to_string());
+        self
+    }
+
+    pub fn masterauth(mut self, password: &str) -> Self {
+        self.config.masterauth = Some(password.to_string());
+        self
+    }
+
+    pub fn proto_max_bulk_len(mut self, bytes: usize) -> Self {
+        self.config.proto_max_bulk_len = bytes;
+        self
+    }
+
+    pub fn proto_max_multibulk_len(mut self, length: usize) -> Self {
+        self.config.proto_max_multibulk_len = length;
+        self
+    }
+
+    pub fn client_output_buffer_limit(
+        mut self,
+        class: ClientClass,
+        limit: OutputBufferLimit,
+    ) -> Self {
+        self.config.client_output_buffer_limits.set(class, limit);
+        self
+    }
+
+    // The log is the process's, so this is the level for every server in it
+    pub fn loglevel(mut self, level: LogLevel) -> Self {
+        self.config.loglevel = level;
+        self
+    }
+
+    pub fn enable_debug_command(mut self, access: ProtectedAccess) -> Self {
+        self.config.enable_debug_command = access;
+        self
+    }
+
+    // Binds the listener and loads the RDB file, if one is configured
+    // Makes `script` available to EVAL under `name`, see Scripts
+    pub fn script<F>(mut self, name: &str, script: F) -> Self
+    where
+        F: for<'a, 'b> Fn(&'a mut ScriptCall<'b>) -> ScriptFuture<'a> + Send + Sync + 'static,
+    {
+        let script: Script = Arc::new(script);
+        self.scripts.register(name, script);
+        self
+    }
+
+    pub async fn build(mut self) -> Result<Server, ServerError> {
+        let address = format!("{}:{}", self.host, self.config.port);
+        let listener = match self.config.keyspace_mode {
+            // The workers' listeners will share the port
+            KeyspaceMode::ThreadPerCore => match lookup_host(&address).await?.next() {
+                Some(x) => bind_reuseport(x)?,
+                None => return Err(format!("Could not resolve {}", address).into()),
+            },
+            _ => TcpListener::bind(address).await?,
+        };
+        let local_addr = listener.local_addr()?;
+        let mut other_listeners = Vec::new();
+        if let Some(path) = &self.config.unixsocket {
+            other_listeners.push(Listener::bind_unix(path, self.config.unixsocketperm)?);
+        }
+        // Replicas announce their port to the master, so it has to be the real one
+        self.config.port = local_addr.port().to_string();
+        let shutdown = ShutdownHandle(Arc::new(watch::channel(false).0));
+        let redis = Redis::new(
+            self.config,
+            listener,
+            other_listeners,
+            self.scripts,
+            &shutdown,
+        )
+        .await?;
+        Ok(Server {
+            redis,
+            local_addr,
+            shutdown,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<watch::Sender<bool>>);
+
+impl ShutdownHandle {
+    // Stops accepting connections, closes the open ones and ends background tasks
+    pub fn shutdown(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub(crate) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.0.subscribe()
+    }
+
+    pub(crate) fn downgrade(&self) -> WeakShutdownHandle {
+        WeakShutdownHandle(Arc::downgrade(&self.0))
+    }
+}
+
+// How the server shuts itself down, as SHUTDOWN does. Unlike a ShutdownHandle it doesn't count
+// as a handle to the server, which would otherwise keep itself running once every real one is
+// gone.
+pub(crate) struct WeakShutdownHandle(Weak<watch::Sender<bool>>);
+
+impl WeakShutdownHandle {
+    pub(crate) fn shutdown(&self) {
+        if let Some(sender) = self.0.upgrade() {
+            sender.send_replace(true);
+        }
+    }
+}
+
+// Resolves once shutdown has been requested, or once every handle to the server is gone
+pub(crate) async fn wait_for_shutdown(receiver: &mut watch::Receiver<bool>) {
+    while !*receiver.borrow_and_update() {
+        if receiver.changed().await.is_err() {
+            return;
+        }
+    }
+}
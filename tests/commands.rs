@@ -0,0 +1,1637 @@
+use redis_starter_rust::aof::writer::AppendFsync;
+use redis_starter_rust::redis::scripting::{ScriptCall, ScriptFuture};
+use redis_starter_rust::resp::RespType;
+use redis_starter_rust::Server;
+
+use bytes::Bytes;
+
+#[tokio::test]
+async fn command_latencies_are_tracked_per_command() {
+    let server = Server::builder()
+        .port(0)
+        .latency_tracking_info_percentiles(&[50.0, 99.9])
+        .build()
+        .await
+        .unwrap();
+    let mut client = server.client();
+    for _ in 0..3 {
+        client.command(&["SET", "k", "v"]).await;
+    }
+    client.command(&["GET", "k"]).await;
+
+    let bulk = |x: &str| RespType::BulkString(Some(Bytes::from(x.to_string())));
+    match client.command(&["LATENCY", "HISTOGRAM", "set"]).await {
+        Some(RespType::Array(x)) => {
+            assert_eq!(x[..1], [bulk("set")]);
+            match &x[1] {
+                RespType::Array(x) => {
+                    assert_eq!(x[..2], [bulk("calls"), RespType::Integer(3)]);
+                    assert_eq!(x[2], bulk("histogram_usec"));
+                }
+                other => panic!("Expected an array, got {:?}", other),
+            }
+        }
+        other => panic!("Expected an array, got {:?}", other),
+    }
+    match client.command(&["INFO", "latencystats"]).await {
+        Some(RespType::BulkString(Some(x))) => {
+            let info = String::from_utf8_lossy(&x);
+            assert!(info.contains("latency_percentiles_usec_set:p50="));
+            assert!(info.contains(",p99.9="));
+            assert!(info.contains("latency_percentiles_usec_get:"));
+        }
+        other => panic!("Expected a bulk string, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn set_options_decide_whether_and_how_the_key_is_set() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let mut client = server.client();
+    let bulk = |x: &str| Some(RespType::BulkString(Some(Bytes::from(x.to_string()))));
+    let ok = Some(RespType::SimpleString(String::from("OK")));
+    let nil = Some(RespType::BulkString(None));
+
+    assert_eq!(client.command(&["SET", "k", "1", "XX"]).await, nil);
+    assert_eq!(
+        client.command(&["SET", "k", "1", "NX", "EX", "100"]).await,
+        ok
+    );
+    assert_eq!(client.command(&["SET", "k", "2", "NX"]).await, nil);
+    assert_eq!(
+        client
+            .command(&["SET", "k", "2", "XX", "GET", "KEEPTTL"])
+            .await,
+        bulk("1")
+    );
+    assert_eq!(client.command(&["GET", "k"]).await, bulk("2"));
+    assert_eq!(client.command(&["SET", "k", "3", "GET"]).await, bulk("2"));
+    assert_eq!(client.command(&["SET", "new", "v", "GET"]).await, nil);
+
+    // An absolute time in the past expires the key straight away
+    assert_eq!(client.command(&["SET", "k", "4", "PXAT", "1"]).await, ok);
+    assert_eq!(client.command(&["GET", "k"]).await, nil);
+    assert_eq!(client.command(&["SET", "k", "5", "EXAT", "1"]).await, ok);
+    assert_eq!(client.command(&["SET", "k", "6", "NX"]).await, ok);
+
+    let error = |x: &str| Some(RespType::Error(x.to_string()));
+    assert_eq!(
+        client.command(&["SET", "k", "7", "EX", "abc"]).await,
+        error("ERR value is not an integer or out of range")
+    );
+    assert_eq!(
+        client.command(&["SET", "k", "7", "PX", "0"]).await,
+        error("ERR invalid expire time in 'set' command")
+    );
+    for options in [
+        &["NX", "XX"][..],
+        &["EX", "1", "KEEPTTL"],
+        &["EX"],
+        &["FOO"],
+    ] {
+        let mut args = vec!["SET", "k", "7"];
+        args.extend_from_slice(options);
+        assert_eq!(client.command(&args).await, error("ERR syntax error"));
+    }
+    assert_eq!(client.command(&["GET", "k"]).await, bulk("6"));
+}
+
+#[tokio::test]
+async fn expirations_can_be_inspected_and_changed() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let mut client = server.client();
+    let int = |x: i64| Some(RespType::Integer(x));
+
+    assert_eq!(client.command(&["TTL", "k"]).await, int(-2));
+    assert_eq!(client.command(&["EXPIRE", "k", "10"]).await, int(0));
+    client.command(&["SET", "k", "v"]).await;
+    assert_eq!(client.command(&["TTL", "k"]).await, int(-1));
+    assert_eq!(client.command(&["PERSIST", "k"]).await, int(0));
+
+    assert_eq!(client.command(&["EXPIRE", "k", "100"]).await, int(1));
+    assert_eq!(client.command(&["TTL", "k"]).await, int(100));
+    match client.command(&["PTTL", "k"]).await {
+        Some(RespType::Integer(x)) => assert!((99_000..=100_000).contains(&x), "{}", x),
+        other => panic!("Expected an integer, got {:?}", other),
+    }
+    assert_eq!(client.command(&["PERSIST", "k"]).await, int(1));
+    assert_eq!(client.command(&["TTL", "k"]).await, int(-1));
+
+    // A time that's already passed expires the key
+    assert_eq!(client.command(&["EXPIRE", "k", "-1"]).await, int(1));
+    assert_eq!(client.command(&["TTL", "k"]).await, int(-2));
+}
+
+#[tokio::test]
+async fn counters_are_incremented_and_decremented() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let mut client = server.client();
+    let int = |x: i64| Some(RespType::Integer(x));
+    let error = |x: &str| Some(RespType::Error(x.to_string()));
+
+    assert_eq!(client.command(&["INCR", "n"]).await, int(1));
+    assert_eq!(client.command(&["INCRBY", "n", "41"]).await, int(42));
+    assert_eq!(client.command(&["DECR", "n"]).await, int(41));
+    assert_eq!(client.command(&["DECRBY", "n", "-9"]).await, int(50));
+    assert_eq!(
+        client.command(&["OBJECT", "ENCODING", "n"]).await,
+        Some(RespType::BulkString(Some(Bytes::from("int"))))
+    );
+
+    // The expiration survives the increment
+    client.command(&["EXPIRE", "n", "100"]).await;
+    client.command(&["INCR", "n"]).await;
+    assert_eq!(client.command(&["TTL", "n"]).await, int(100));
+
+    client.command(&["SET", "s", "abc"]).await;
+    assert_eq!(
+        client.command(&["INCR", "s"]).await,
+        error("ERR value is not an integer or out of range")
+    );
+    assert_eq!(
+        client.command(&["INCRBY", "n", "abc"]).await,
+        error("ERR value is not an integer or out of range")
+    );
+    assert_eq!(
+        client.command(&["DECRBY", "n", "1.5"]).await,
+        error("ERR value is not an integer or out of range")
+    );
+    client.command(&["SET", "max", &i64::MAX.to_string()]).await;
+    assert_eq!(
+        client.command(&["INCR", "max"]).await,
+        error("ERR increment or decrement would overflow")
+    );
+    assert_eq!(
+        client
+            .command(&["DECRBY", "zero", &i64::MIN.to_string()])
+            .await,
+        error("ERR increment or decrement would overflow")
+    );
+}
+
+#[tokio::test]
+async fn keys_are_introspected_with_object_and_memory() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let mut client = server.client();
+    let bulk = |x: &str| Some(RespType::BulkString(Some(Bytes::from(x.to_string()))));
+    let int = |x: i64| Some(RespType::Integer(x));
+    let usage = |x: Option<RespType>| match x {
+        Some(RespType::Integer(x)) => x,
+        other => panic!("Expected MEMORY USAGE to be an integer, got {:?}", other),
+    };
+
+    client.command(&["SET", "short", "abc"]).await;
+    client.command(&["SET", "long", &"a".repeat(100)]).await;
+    client.command(&["RPUSH", "list", "a"]).await;
+    client.command(&["HSET", "hash", "f", "v"]).await;
+    client.command(&["ZADD", "zset", "1", "a"]).await;
+    for (key, encoding) in [
+        ("short", "embstr"),
+        ("long", "raw"),
+        ("list", "quicklist"),
+        ("hash", "hashtable"),
+        ("zset", "listpack"),
+    ] {
+        assert_eq!(
+            client.command(&["OBJECT", "ENCODING", key]).await,
+            bulk(encoding)
+        );
+    }
+    assert_eq!(
+        client.command(&["OBJECT", "REFCOUNT", "short"]).await,
+        int(1)
+    );
+    assert_eq!(
+        client.command(&["OBJECT", "ENCODING", "missing"]).await,
+        Some(RespType::BulkString(None))
+    );
+
+    // Only the bookkeeping the eviction policy keeps up is reported
+    assert_eq!(
+        client.command(&["OBJECT", "IDLETIME", "short"]).await,
+        int(0)
+    );
+    assert!(matches!(
+        client.command(&["OBJECT", "FREQ", "short"]).await,
+        Some(RespType::Error(x)) if x.starts_with("ERR An LFU maxmemory policy is not selected")
+    ));
+    client
+        .command(&["CONFIG", "SET", "maxmemory-policy", "allkeys-lfu"])
+        .await;
+    for _ in 0..10 {
+        client.command(&["GET", "short"]).await;
+    }
+    assert!(usage(client.command(&["OBJECT", "FREQ", "short"]).await) >= 5);
+    assert!(matches!(
+        client.command(&["OBJECT", "IDLETIME", "short"]).await,
+        Some(RespType::Error(x)) if x.starts_with("ERR An LFU maxmemory policy is selected")
+    ));
+
+    let short = usage(client.command(&["MEMORY", "USAGE", "short"]).await);
+    let long = usage(client.command(&["MEMORY", "USAGE", "long"]).await);
+    assert!(short > 0 && long >= short + 100);
+    assert_eq!(
+        client.command(&["MEMORY", "USAGE", "missing"]).await,
+        Some(RespType::BulkString(None))
+    );
+}
+
+#[tokio::test]
+async fn many_keys_are_read_and_written_at_once() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let mut client = server.client();
+    let int = |x: i64| Some(RespType::Integer(x));
+    let bulk = |x: &str| RespType::BulkString(Some(Bytes::from(x.to_string())));
+    let ok = Some(RespType::SimpleString(String::from("OK")));
+
+    client.command(&["SET", "a", "old", "EX", "100"]).await;
+    assert_eq!(
+        client
+            .command(&["MSET", "a", "1", "b", "2", "a", "3"])
+            .await,
+        ok
+    );
+    assert_eq!(client.command(&["TTL", "a"]).await, int(-1));
+    client.command(&["RPUSH", "list", "x"]).await;
+    assert_eq!(
+        client.command(&["MGET", "a", "missing", "b", "list"]).await,
+        Some(RespType::Array(vec![
+            bulk("3"),
+            RespType::BulkString(None),
+            bulk("2"),
+            RespType::BulkString(None),
+        ]))
+    );
+
+    // MSETNX sets nothing if any key exists
+    assert_eq!(
+        client.command(&["MSETNX", "c", "1", "b", "1"]).await,
+        int(0)
+    );
+    assert_eq!(client.command(&["EXISTS", "c"]).await, int(0));
+    assert_eq!(
+        client.command(&["MSETNX", "c", "1", "d", "2"]).await,
+        int(1)
+    );
+    assert_eq!(
+        client.command(&["MGET", "c", "d"]).await,
+        Some(RespType::Array(vec![bulk("1"), bulk("2")]))
+    );
+}
+
+#[tokio::test]
+async fn strings_are_appended_to_and_edited_in_place() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let mut client = server.client();
+    let int = |x: i64| Some(RespType::Integer(x));
+    let bulk = |x: &[u8]| Some(RespType::BulkString(Some(Bytes::copy_from_slice(x))));
+
+    assert_eq!(client.command(&["APPEND", "s", "Hello"]).await, int(5));
+    assert_eq!(client.command(&["APPEND", "s", " World"]).await, int(11));
+    assert_eq!(client.command(&["STRLEN", "s"]).await, int(11));
+    assert_eq!(
+        client.command(&["GETRANGE", "s", "0", "4"]).await,
+        bulk(b"Hello")
+    );
+    assert_eq!(
+        client.command(&["GETRANGE", "s", "-5", "-1"]).await,
+        bulk(b"World")
+    );
+    assert_eq!(
+        client.command(&["GETRANGE", "s", "5", "2"]).await,
+        bulk(b"")
+    );
+    assert_eq!(
+        client.command(&["GETRANGE", "missing", "0", "-1"]).await,
+        bulk(b"")
+    );
+
+    // The expiration survives the edit
+    client.command(&["EXPIRE", "s", "100"]).await;
+    assert_eq!(
+        client.command(&["SETRANGE", "s", "6", "Redis"]).await,
+        int(11)
+    );
+    assert_eq!(client.command(&["GET", "s"]).await, bulk(b"Hello Redis"));
+    assert_eq!(client.command(&["TTL", "s"]).await, int(100));
+
+    // Offsets past the end are reached by padding with zero bytes
+    assert_eq!(client.command(&["SETRANGE", "p", "3", "ab"]).await, int(5));
+    assert_eq!(client.command(&["GET", "p"]).await, bulk(b"\0\0\0ab"));
+    assert_eq!(client.command(&["SETRANGE", "none", "3", ""]).await, int(0));
+    assert_eq!(client.command(&["EXISTS", "none"]).await, int(0));
+
+    // Integers are edited as their digits
+    client.command(&["SET", "n", "10"]).await;
+    assert_eq!(client.command(&["APPEND", "n", "5"]).await, int(3));
+    assert_eq!(client.command(&["INCR", "n"]).await, int(106));
+
+    client.command(&["RPUSH", "list", "a"]).await;
+    assert_eq!(
+        client.command(&["APPEND", "list", "a"]).await,
+        Some(RespType::Error(String::from(
+            "WRONGTYPE Operation against a key holding the wrong kind of value"
+        )))
+    );
+}
+
+#[tokio::test]
+async fn bitmaps_are_set_counted_and_combined() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let mut client = server.client();
+    let int = |x: i64| Some(RespType::Integer(x));
+    let bulk = |x: &[u8]| Some(RespType::BulkString(Some(Bytes::copy_from_slice(x))));
+
+    assert_eq!(client.command(&["SETBIT", "a", "1", "1"]).await, int(0));
+    assert_eq!(client.command(&["SETBIT", "a", "1", "1"]).await, int(1));
+    assert_eq!(client.command(&["SETBIT", "a", "15", "1"]).await, int(0));
+    assert_eq!(client.command(&["GET", "a"]).await, bulk(&[0x40, 0x01]));
+    assert_eq!(client.command(&["GETBIT", "a", "15"]).await, int(1));
+    assert_eq!(client.command(&["GETBIT", "a", "100"]).await, int(0));
+    assert_eq!(client.command(&["GETBIT", "missing", "0"]).await, int(0));
+    assert_eq!(client.command(&["BITCOUNT", "a"]).await, int(2));
+    assert_eq!(client.command(&["BITCOUNT", "a", "-1", "-1"]).await, int(1));
+    assert_eq!(
+        client.command(&["BITCOUNT", "a", "0", "7", "BIT"]).await,
+        int(1)
+    );
+    assert_eq!(client.command(&["BITCOUNT", "missing"]).await, int(0));
+
+    client.command(&["SETBIT", "b", "0", "1"]).await;
+    client.command(&["SETBIT", "b", "7", "1"]).await;
+    assert_eq!(
+        client
+            .command(&["BITOP", "OR", "d", "a", "b", "missing"])
+            .await,
+        int(2)
+    );
+    assert_eq!(client.command(&["GET", "d"]).await, bulk(&[0xc1, 0x01]));
+    assert_eq!(
+        client.command(&["BITOP", "AND", "d", "a", "missing"]).await,
+        int(2)
+    );
+    assert_eq!(client.command(&["GET", "d"]).await, bulk(&[0, 0]));
+    assert_eq!(client.command(&["BITOP", "NOT", "d", "a"]).await, int(2));
+    assert_eq!(client.command(&["GET", "d"]).await, bulk(&[0xbf, 0xfe]));
+    // An empty result leaves no key behind
+    assert_eq!(
+        client.command(&["BITOP", "XOR", "d", "missing"]).await,
+        int(0)
+    );
+    assert_eq!(client.command(&["EXISTS", "d"]).await, int(0));
+
+    client.command(&["RPUSH", "list", "a"]).await;
+    let wrongtype = Some(RespType::Error(String::from(
+        "WRONGTYPE Operation against a key holding the wrong kind of value",
+    )));
+    assert_eq!(
+        client.command(&["SETBIT", "list", "0", "1"]).await,
+        wrongtype
+    );
+    assert_eq!(
+        client.command(&["BITOP", "AND", "d", "a", "list"]).await,
+        wrongtype
+    );
+    assert_eq!(
+        client.command(&["SETBIT", "a", "4294967296", "1"]).await,
+        Some(RespType::Error(String::from(
+            "ERR bit offset is not an integer or out of range"
+        )))
+    );
+}
+
+#[tokio::test]
+async fn lists_are_pushed_ranged_and_popped_from_either_end() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let mut client = server.client();
+    let bulk = |x: &str| RespType::BulkString(Some(Bytes::from(x.to_string())));
+    let array = |x: &[&str]| Some(RespType::Array(x.iter().map(|y| bulk(y)).collect()));
+
+    assert_eq!(
+        client.command(&["RPUSH", "l", "c", "d"]).await,
+        Some(RespType::Integer(2))
+    );
+    assert_eq!(
+        client.command(&["LPUSH", "l", "b", "a"]).await,
+        Some(RespType::Integer(4))
+    );
+    assert_eq!(
+        client.command(&["LRANGE", "l", "0", "-1"]).await,
+        array(&["a", "b", "c", "d"])
+    );
+    assert_eq!(
+        client.command(&["LRANGE", "l", "-3", "1"]).await,
+        array(&["b"])
+    );
+    assert_eq!(
+        client.command(&["LRANGE", "none", "0", "-1"]).await,
+        array(&[])
+    );
+
+    assert_eq!(client.command(&["LPOP", "l"]).await, Some(bulk("a")));
+    assert_eq!(
+        client.command(&["RPOP", "l", "2"]).await,
+        array(&["d", "c"])
+    );
+    assert_eq!(
+        client.command(&["LLEN", "l"]).await,
+        Some(RespType::Integer(1))
+    );
+    // Popping the last element deletes the list
+    assert_eq!(client.command(&["RPOP", "l", "5"]).await, array(&["b"]));
+    assert_eq!(
+        client.command(&["TYPE", "l"]).await,
+        Some(RespType::SimpleString(String::from("none")))
+    );
+    assert_eq!(
+        client.command(&["LPOP", "l"]).await,
+        Some(RespType::BulkString(None))
+    );
+    assert_eq!(
+        client.command(&["LPOP", "l", "1"]).await,
+        Some(RespType::NullArray)
+    );
+
+    client.command(&["SET", "s", "v"]).await;
+    assert!(matches!(
+        client.command(&["LPUSH", "s", "v"]).await,
+        Some(RespType::Error(x)) if x.starts_with("WRONGTYPE")
+    ));
+}
+
+#[tokio::test]
+async fn hashes_hold_fields_and_refuse_other_types() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let mut client = server.client();
+    let bulk = |x: &str| RespType::BulkString(Some(Bytes::from(x.to_string())));
+    let int = |x: i64| Some(RespType::Integer(x));
+
+    assert_eq!(
+        client.command(&["HSET", "h", "a", "1", "b", "2"]).await,
+        int(2)
+    );
+    assert_eq!(
+        client.command(&["HSET", "h", "a", "3", "c", "4"]).await,
+        int(1)
+    );
+    assert_eq!(client.command(&["HGET", "h", "a"]).await, Some(bulk("3")));
+    assert_eq!(
+        client.command(&["HGET", "h", "none"]).await,
+        Some(RespType::BulkString(None))
+    );
+    assert_eq!(client.command(&["HLEN", "h"]).await, int(3));
+    match client.command(&["HGETALL", "h"]).await {
+        Some(RespType::Array(x)) => {
+            let mut pairs: Vec<_> = x.chunks(2).map(|y| y.to_vec()).collect();
+            pairs.sort_by_key(|y| format!("{:?}", y));
+            assert_eq!(
+                pairs,
+                vec![
+                    vec![bulk("a"), bulk("3")],
+                    vec![bulk("b"), bulk("2")],
+                    vec![bulk("c"), bulk("4")],
+                ]
+            );
+        }
+        other => panic!("Expected an array, got {:?}", other),
+    }
+
+    assert_eq!(client.command(&["HDEL", "h", "a", "b", "x"]).await, int(2));
+    // Removing the last field deletes the hash
+    assert_eq!(client.command(&["HDEL", "h", "c"]).await, int(1));
+    assert_eq!(
+        client.command(&["HGETALL", "h"]).await,
+        Some(RespType::Array(Vec::new()))
+    );
+    assert_eq!(
+        client.command(&["TYPE", "h"]).await,
+        Some(RespType::SimpleString(String::from("none")))
+    );
+
+    client.command(&["SET", "s", "v"]).await;
+    for args in [
+        &["HSET", "s", "f", "v"][..],
+        &["HGET", "s", "f"],
+        &["HGETALL", "s"],
+    ] {
+        assert!(matches!(
+            client.command(args).await,
+            Some(RespType::Error(x)) if x.starts_with("WRONGTYPE")
+        ));
+    }
+}
+
+#[tokio::test]
+async fn sorted_sets_are_ordered_by_score_then_member() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let mut client = server.client();
+    let bulk = |x: &str| RespType::BulkString(Some(Bytes::from(x.to_string())));
+    let array = |x: &[&str]| Some(RespType::Array(x.iter().map(|y| bulk(y)).collect()));
+
+    assert_eq!(
+        client
+            .command(&["ZADD", "z", "2", "b", "1", "a", "2", "aa", "-inf", "low"])
+            .await,
+        Some(RespType::Integer(4))
+    );
+    assert_eq!(
+        client.command(&["ZADD", "z", "3", "a"]).await,
+        Some(RespType::Integer(0))
+    );
+    assert_eq!(
+        client.command(&["ZRANGE", "z", "0", "-1"]).await,
+        array(&["low", "aa", "b", "a"])
+    );
+    assert_eq!(
+        client
+            .command(&["ZRANGE", "z", "1", "2", "WITHSCORES"])
+            .await,
+        array(&["aa", "2", "b", "2"])
+    );
+    assert_eq!(
+        client.command(&["ZRANGE", "z", "5", "10"]).await,
+        array(&[])
+    );
+    assert_eq!(client.command(&["ZSCORE", "z", "a"]).await, Some(bulk("3")));
+    assert_eq!(
+        client.command(&["ZSCORE", "z", "low"]).await,
+        Some(bulk("-inf"))
+    );
+    assert_eq!(
+        client.command(&["ZSCORE", "z", "none"]).await,
+        Some(RespType::BulkString(None))
+    );
+    assert_eq!(
+        client.command(&["ZRANK", "z", "b"]).await,
+        Some(RespType::Integer(2))
+    );
+    assert_eq!(
+        client.command(&["ZRANK", "z", "none"]).await,
+        Some(RespType::BulkString(None))
+    );
+
+    // RESP3 clients get real doubles, and WITHSCORES pairs
+    client.command(&["HELLO", "3"]).await;
+    assert_eq!(
+        client
+            .command(&["ZRANGE", "z", "-1", "-1", "WITHSCORES"])
+            .await,
+        Some(RespType::Array(vec![RespType::Array(vec![
+            bulk("a"),
+            RespType::Double(3.0)
+        ])]))
+    );
+}
+
+#[tokio::test]
+async fn stream_entries_are_added_ranged_and_read() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let mut client = server.client();
+    let bulk = |x: &str| RespType::BulkString(Some(Bytes::from(x.to_string())));
+    let entry = |id: &str, field: &str, value: &str| {
+        RespType::Array(vec![
+            bulk(id),
+            RespType::Array(vec![bulk(field), bulk(value)]),
+        ])
+    };
+
+    assert_eq!(
+        client.command(&["XADD", "s", "1-1", "a", "1"]).await,
+        Some(bulk("1-1"))
+    );
+    assert_eq!(
+        client.command(&["XADD", "s", "1-*", "b", "2"]).await,
+        Some(bulk("1-2"))
+    );
+    assert_eq!(
+        client.command(&["XADD", "s", "1-2", "c", "3"]).await,
+        Some(RespType::Error(String::from(
+            "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+        )))
+    );
+    assert_eq!(
+        client.command(&["XADD", "new", "0-0", "c", "3"]).await,
+        Some(RespType::Error(String::from(
+            "ERR The ID specified in XADD must be greater than 0-0"
+        )))
+    );
+    // A failed XADD doesn't leave an empty stream behind
+    assert_eq!(
+        client.command(&["TYPE", "new"]).await,
+        Some(RespType::SimpleString(String::from("none")))
+    );
+    assert_eq!(
+        client.command(&["XRANGE", "s", "-", "1"]).await,
+        Some(RespType::Array(vec![
+            entry("1-1", "a", "1"),
+            entry("1-2", "b", "2")
+        ]))
+    );
+    assert_eq!(
+        client
+            .command(&["XRANGE", "s", "1-2", "+", "COUNT", "1"])
+            .await,
+        Some(RespType::Array(vec![entry("1-2", "b", "2")]))
+    );
+    assert_eq!(
+        client
+            .command(&["XREAD", "STREAMS", "s", "none", "1-1", "0"])
+            .await,
+        Some(RespType::Array(vec![RespType::Array(vec![
+            bulk("s"),
+            RespType::Array(vec![entry("1-2", "b", "2")])
+        ])]))
+    );
+    assert_eq!(
+        client.command(&["XREAD", "STREAMS", "s", "$"]).await,
+        Some(RespType::NullArray)
+    );
+
+    // Generated IDs use the current time
+    match client.command(&["XADD", "s", "*", "c", "3"]).await {
+        Some(RespType::BulkString(Some(x))) => assert!(x.ends_with(b"-0") && x.len() > 10),
+        other => panic!("Expected a bulk string, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn blocking_xread_waits_for_new_entries() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let mut reader = server.client();
+    let mut writer = server.client();
+    let bulk = |x: &str| RespType::BulkString(Some(Bytes::from(x.to_string())));
+
+    // Nothing arrives before the timeout
+    assert_eq!(
+        reader
+            .command(&["XREAD", "BLOCK", "20", "STREAMS", "s", "$"])
+            .await,
+        Some(RespType::NullArray)
+    );
+
+    writer.command(&["XADD", "s", "1-1", "old", "1"]).await;
+    let read = tokio::spawn(async move {
+        reader
+            .command(&["XREAD", "BLOCK", "0", "STREAMS", "other", "s", "0", "$"])
+            .await
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    assert!(!read.is_finished());
+    writer.command(&["XADD", "s", "2-1", "new", "2"]).await;
+    assert_eq!(
+        read.await.unwrap(),
+        Some(RespType::Array(vec![RespType::Array(vec![
+            bulk("s"),
+            RespType::Array(vec![RespType::Array(vec![
+                bulk("2-1"),
+                RespType::Array(vec![bulk("new"), bulk("2")])
+            ])])
+        ])]))
+    );
+}
+
+#[tokio::test]
+async fn blocked_list_pops_are_served_in_order() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let (mut first, mut second, mut pusher) = (server.client(), server.client(), server.client());
+    let bulk = |x: &str| RespType::BulkString(Some(Bytes::from(x.to_string())));
+    let popped = |key: &str, value: &str| Some(RespType::Array(vec![bulk(key), bulk(value)]));
+
+    // A list already there is popped from at once, the first key holding one going first
+    pusher.command(&["RPUSH", "b", "1", "2"]).await;
+    assert_eq!(
+        first.command(&["BLPOP", "a", "b", "0"]).await,
+        popped("b", "1")
+    );
+    assert_eq!(
+        first.command(&["BRPOP", "a", "b", "0"]).await,
+        popped("b", "2")
+    );
+    assert_eq!(
+        first.command(&["BLPOP", "a", "0.02"]).await,
+        Some(RespType::NullArray)
+    );
+
+    let first = tokio::spawn(async move { first.command(&["BLPOP", "list", "0"]).await });
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    let second =
+        tokio::spawn(async move { second.command(&["BLPOP", "other", "list", "0"]).await });
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    assert!(!first.is_finished() && !second.is_finished());
+    pusher.command(&["RPUSH", "list", "x", "y"]).await;
+    assert_eq!(first.await.unwrap(), popped("list", "x"));
+    assert_eq!(second.await.unwrap(), popped("list", "y"));
+    assert_eq!(
+        pusher.command(&["EXISTS", "list"]).await,
+        Some(RespType::Integer(0))
+    );
+
+    // Nor do they block inside a transaction
+    pusher.command(&["MULTI"]).await;
+    pusher.command(&["BLPOP", "list", "0"]).await;
+    assert_eq!(
+        pusher.command(&["EXEC"]).await,
+        Some(RespType::Array(vec![RespType::NullArray]))
+    );
+}
+
+#[tokio::test]
+async fn transactions_queue_commands_until_exec() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let (mut client, mut other) = (server.client(), server.client());
+    let bulk = |x: &str| RespType::BulkString(Some(Bytes::from(x.to_string())));
+    let ok = Some(RespType::SimpleString(String::from("OK")));
+    let queued = Some(RespType::SimpleString(String::from("QUEUED")));
+    let error = |x: &str| Some(RespType::Error(x.to_string()));
+
+    assert_eq!(
+        client.command(&["EXEC"]).await,
+        error("ERR EXEC without MULTI")
+    );
+    assert_eq!(
+        client.command(&["DISCARD"]).await,
+        error("ERR DISCARD without MULTI")
+    );
+
+    assert_eq!(client.command(&["MULTI"]).await, ok);
+    assert_eq!(
+        client.command(&["MULTI"]).await,
+        error("ERR MULTI calls can not be nested")
+    );
+    assert_eq!(client.command(&["SET", "k", "1"]).await, queued);
+    assert_eq!(client.command(&["INCR", "k"]).await, queued);
+    assert_eq!(client.command(&["LPUSH", "k", "x"]).await, queued);
+    assert_eq!(client.command(&["GET", "k"]).await, queued);
+    // Nothing has run yet
+    assert_eq!(
+        other.command(&["GET", "k"]).await,
+        Some(RespType::BulkString(None))
+    );
+    assert_eq!(
+        client.command(&["EXEC"]).await,
+        Some(RespType::Array(vec![
+            RespType::SimpleString(String::from("OK")),
+            RespType::Integer(2),
+            RespType::Error(String::from(
+                "WRONGTYPE Operation against a key holding the wrong kind of value"
+            )),
+            bulk("2"),
+        ]))
+    );
+    assert_eq!(
+        client.command(&["EXEC"]).await,
+        error("ERR EXEC without MULTI")
+    );
+
+    assert_eq!(client.command(&["MULTI"]).await, ok);
+    assert_eq!(client.command(&["INCR", "k"]).await, queued);
+    assert_eq!(client.command(&["DISCARD"]).await, ok);
+    assert_eq!(client.command(&["GET", "k"]).await, Some(bulk("2")));
+
+    // A command with bad arguments is refused straight away, and discards the transaction
+    assert_eq!(client.command(&["MULTI"]).await, ok);
+    assert_eq!(client.command(&["INCR", "k"]).await, queued);
+    assert_eq!(
+        client.command(&["ZADD", "z", "x", "m"]).await,
+        error("ERR value is not a valid float")
+    );
+    assert_eq!(
+        client.command(&["EXEC"]).await,
+        error("EXECABORT Transaction discarded because of previous errors.")
+    );
+    assert_eq!(client.command(&["GET", "k"]).await, Some(bulk("2")));
+
+    // Blocking commands don't block inside a transaction
+    assert_eq!(client.command(&["MULTI"]).await, ok);
+    client
+        .command(&["XREAD", "BLOCK", "0", "STREAMS", "s", "$"])
+        .await;
+    assert_eq!(
+        client.command(&["EXEC"]).await,
+        Some(RespType::Array(vec![RespType::NullArray]))
+    );
+}
+
+#[tokio::test]
+async fn unsubscribing_leaves_subscriber_mode() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let (mut subscriber, mut publisher) = (server.client(), server.client());
+    let bulk = |x: &str| RespType::BulkString(Some(Bytes::from(x.to_string())));
+
+    subscriber.command(&["SUBSCRIBE", "a", "b"]).await;
+    subscriber.command(&["PSUBSCRIBE", "c*"]).await;
+    assert_eq!(
+        publisher.command(&["PUBLISH", "cat", "meow"]).await,
+        Some(RespType::Integer(1))
+    );
+    assert_eq!(
+        subscriber.message().await,
+        RespType::Array(vec![
+            bulk("pmessage"),
+            bulk("c*"),
+            bulk("cat"),
+            bulk("meow")
+        ])
+    );
+
+    // The reply for the last channel unsubscribed from
+    subscriber.command(&["UNSUBSCRIBE"]).await;
+    assert_eq!(
+        subscriber.command(&["PUNSUBSCRIBE"]).await,
+        Some(RespType::Array(vec![
+            bulk("punsubscribe"),
+            bulk("c*"),
+            RespType::Integer(0)
+        ]))
+    );
+    assert_eq!(
+        subscriber.command(&["UNSUBSCRIBE"]).await,
+        Some(RespType::Array(vec![
+            bulk("unsubscribe"),
+            RespType::BulkString(None),
+            RespType::Integer(0)
+        ]))
+    );
+    assert_eq!(
+        publisher.command(&["PUBLISH", "a", "x"]).await,
+        Some(RespType::Integer(0))
+    );
+    assert_eq!(
+        subscriber.command(&["SET", "k", "v"]).await,
+        Some(RespType::SimpleString(String::from("OK")))
+    );
+
+    // RESP3 clients may run anything while subscribed, and get their messages pushed
+    subscriber.command(&["HELLO", "3"]).await;
+    subscriber.command(&["SUBSCRIBE", "a"]).await;
+    assert_eq!(subscriber.command(&["GET", "k"]).await, Some(bulk("v")));
+    publisher.command(&["PUBLISH", "a", "x"]).await;
+    assert_eq!(
+        subscriber.message().await,
+        RespType::Push(vec![bulk("message"), bulk("a"), bulk("x")])
+    );
+    drop(subscriber);
+    assert_eq!(
+        publisher.command(&["PUBLISH", "a", "x"]).await,
+        Some(RespType::Integer(0))
+    );
+}
+
+#[tokio::test]
+async fn saved_datasets_are_loaded_on_restart() {
+    let dir = std::env::temp_dir().join(format!("redis-save-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let bulk = |x: &str| Some(RespType::BulkString(Some(Bytes::from(x.to_string()))));
+    let ok = Some(RespType::SimpleString(String::from("OK")));
+    let server = Server::builder()
+        .port(0)
+        .dir(&dir)
+        .dbfilename("dump.rdb")
+        .build()
+        .await
+        .unwrap();
+    let mut client = server.client();
+    client.command(&["SET", "foo", "bar"]).await;
+    client.command(&["SELECT", "2"]).await;
+    client.command(&["SET", "foo", "two"]).await;
+    client.command(&["SELECT", "0"]).await;
+    assert_eq!(client.command(&["SAVE"]).await, ok);
+    let info = |x: Option<RespType>| match x {
+        Some(RespType::BulkString(Some(x))) => String::from_utf8_lossy(&x).to_string(),
+        other => panic!("Expected a bulk string, got {:?}", other),
+    };
+    assert!(info(client.command(&["INFO", "persistence"]).await)
+        .contains("rdb_changes_since_last_save:0\r\n"));
+
+    client.command(&["RPUSH", "list", "a", "7"]).await;
+    client.command(&["XADD", "stream", "1-1", "f", "v"]).await;
+    assert_eq!(
+        client.command(&["BGSAVE"]).await,
+        Some(RespType::SimpleString(String::from(
+            "Background saving started"
+        )))
+    );
+    loop {
+        let info = info(client.command(&["INFO", "persistence"]).await);
+        if info.contains("rdb_bgsave_in_progress:0\r\n") {
+            assert!(info.contains("rdb_last_bgsave_status:ok\r\n"));
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    let file = std::fs::read(dir.join("dump.rdb")).unwrap();
+    assert_eq!(redis_starter_rust::rdb::rdb_length(&file), Ok(file.len()));
+    drop(client);
+    drop(server);
+
+    let server = Server::builder()
+        .port(0)
+        .dir(&dir)
+        .dbfilename("dump.rdb")
+        .build()
+        .await
+        .unwrap();
+    let mut client = server.client();
+    assert_eq!(client.command(&["GET", "foo"]).await, bulk("bar"));
+    assert_eq!(
+        client.command(&["LRANGE", "list", "0", "-1"]).await,
+        Some(RespType::Array(vec![
+            bulk("a").unwrap(),
+            bulk("7").unwrap()
+        ]))
+    );
+    assert_eq!(
+        client.command(&["XLEN", "stream"]).await,
+        Some(RespType::Integer(1))
+    );
+    client.command(&["SELECT", "2"]).await;
+    assert_eq!(client.command(&["GET", "foo"]).await, bulk("two"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn the_aof_is_replayed_on_restart() {
+    let dir = std::env::temp_dir().join(format!("redis-aof-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let bulk = |x: &str| Some(RespType::BulkString(Some(Bytes::from(x.to_string()))));
+    let start = || {
+        Server::builder()
+            .port(0)
+            .dir(&dir)
+            .appendonly(AppendFsync::Always)
+            .build()
+    };
+    let server = start().await.unwrap();
+    let mut client = server.client();
+    client.command(&["SET", "foo", "bar"]).await;
+    client.command(&["SET", "gone", "soon", "PX", "1"]).await;
+    client.command(&["RPUSH", "list", "a", "b"]).await;
+    client.command(&["XADD", "stream", "*", "f", "v"]).await;
+    client.command(&["SELECT", "1"]).await;
+    client.command(&["SET", "foo", "one"]).await;
+    drop(client);
+    drop(server);
+
+    let server = start().await.unwrap();
+    let mut client = server.client();
+    client.command(&["SELECT", "1"]).await;
+    assert_eq!(client.command(&["GET", "foo"]).await, bulk("one"));
+    client.command(&["SELECT", "0"]).await;
+    assert_eq!(client.command(&["GET", "foo"]).await, bulk("bar"));
+    assert_eq!(
+        client.command(&["GET", "gone"]).await,
+        Some(RespType::BulkString(None))
+    );
+    assert_eq!(
+        client.command(&["LLEN", "list"]).await,
+        Some(RespType::Integer(2))
+    );
+    assert_eq!(
+        client.command(&["XLEN", "stream"]).await,
+        Some(RespType::Integer(1))
+    );
+
+    // Compacting leaves only what's needed to rebuild the dataset, with later writes after it
+    client.command(&["DEL", "list", "stream"]).await;
+    for _ in 0..100 {
+        client.command(&["SET", "foo", "baz"]).await;
+    }
+    let size = std::fs::metadata(dir.join("appendonly.aof")).unwrap().len();
+    assert_eq!(
+        client.command(&["BGREWRITEAOF"]).await,
+        Some(RespType::SimpleString(String::from(
+            "Background append only file rewriting started"
+        )))
+    );
+    client.command(&["RPUSH", "list", "c"]).await;
+    loop {
+        match client.command(&["INFO", "persistence"]).await {
+            Some(RespType::BulkString(Some(x))) => {
+                let info = String::from_utf8_lossy(&x).to_string();
+                if info.contains("aof_rewrite_in_progress:0\r\n") {
+                    assert!(info.contains("aof_last_bgrewrite_status:ok\r\n"));
+                    break;
+                }
+            }
+            other => panic!("Expected a bulk string, got {:?}", other),
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    client.command(&["RPUSH", "list", "d"]).await;
+    assert!(std::fs::metadata(dir.join("appendonly.aof")).unwrap().len() < size);
+    drop(client);
+    drop(server);
+
+    let server = start().await.unwrap();
+    let mut client = server.client();
+    assert_eq!(client.command(&["GET", "foo"]).await, bulk("baz"));
+    // The other database made it through the rewrite, in the RDB preamble
+    client.command(&["SELECT", "1"]).await;
+    assert_eq!(client.command(&["GET", "foo"]).await, bulk("one"));
+    client.command(&["SELECT", "0"]).await;
+    assert_eq!(
+        client.command(&["LRANGE", "list", "0", "-1"]).await,
+        Some(RespType::Array(vec![
+            RespType::BulkString(Some(Bytes::from("c"))),
+            RespType::BulkString(Some(Bytes::from("d")))
+        ]))
+    );
+    drop(client);
+    drop(server);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_saves_unless_told_not_to() {
+    let dir = std::env::temp_dir().join(format!("redis-shutdown-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let start = |save: &[(u64, u64)]| {
+        Server::builder()
+            .port(0)
+            .dir(&dir)
+            .dbfilename("dump.rdb")
+            .save(save)
+            .build()
+    };
+    let server = start(&[(3600, 1)]).await.unwrap();
+    let mut client = server.client();
+    client.command(&["SET", "foo", "bar"]).await;
+    assert_eq!(client.command(&["SHUTDOWN", "NOSAVE"]).await, None);
+    // The server stops by itself
+    server.run().await.unwrap();
+    assert!(!dir.join("dump.rdb").exists());
+
+    // With save points configured, SHUTDOWN saves without being told to
+    let server = start(&[(3600, 1)]).await.unwrap();
+    let mut client = server.client();
+    client.command(&["SET", "foo", "bar"]).await;
+    assert_eq!(client.command(&["SHUTDOWN"]).await, None);
+    server.run().await.unwrap();
+
+    // Without any, only SHUTDOWN SAVE does
+    let server = start(&[]).await.unwrap();
+    let mut client = server.client();
+    assert_eq!(
+        client.command(&["GET", "foo"]).await,
+        Some(RespType::BulkString(Some(Bytes::from("bar"))))
+    );
+    client.command(&["SET", "foo", "baz"]).await;
+    assert_eq!(client.command(&["SHUTDOWN"]).await, None);
+    server.run().await.unwrap();
+    let server = start(&[]).await.unwrap();
+    let mut client = server.client();
+    client.command(&["SET", "foo", "qux"]).await;
+    assert_eq!(client.command(&["SHUTDOWN", "SAVE"]).await, None);
+    server.run().await.unwrap();
+
+    let server = start(&[]).await.unwrap();
+    let mut client = server.client();
+    assert_eq!(
+        client.command(&["GET", "foo"]).await,
+        Some(RespType::BulkString(Some(Bytes::from("qux"))))
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn keys_can_be_counted_renamed_and_sampled() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let mut client = server.client();
+    let bulk = |x: &str| Some(RespType::BulkString(Some(Bytes::from(x.to_string()))));
+    let int = |x: i64| Some(RespType::Integer(x));
+
+    assert_eq!(
+        client.command(&["RANDOMKEY"]).await,
+        Some(RespType::BulkString(None))
+    );
+    assert_eq!(client.command(&["DBSIZE"]).await, int(0));
+    client.command(&["SET", "a", "1", "EX", "100"]).await;
+    client.command(&["RPUSH", "b", "x"]).await;
+    assert_eq!(client.command(&["DBSIZE"]).await, int(2));
+    assert_eq!(
+        client.command(&["EXISTS", "a", "a", "b", "c"]).await,
+        int(3)
+    );
+    match client.command(&["RANDOMKEY"]).await {
+        Some(RespType::BulkString(Some(x))) => assert!(x == "a" || x == "b"),
+        other => panic!("Expected a key, got {:?}", other),
+    }
+
+    // The new name takes the value and its TTL, replacing whatever it held
+    assert_eq!(
+        client.command(&["RENAME", "a", "b"]).await,
+        Some(RespType::SimpleString(String::from("OK")))
+    );
+    assert_eq!(client.command(&["GET", "b"]).await, bulk("1"));
+    assert_eq!(client.command(&["TTL", "b"]).await, int(100));
+    assert_eq!(
+        client.command(&["TYPE", "b"]).await,
+        Some(RespType::SimpleString(String::from("string")))
+    );
+    assert_eq!(client.command(&["EXISTS", "a"]).await, int(0));
+    assert_eq!(
+        client.command(&["RENAME", "a", "c"]).await,
+        Some(RespType::Error(String::from("ERR no such key")))
+    );
+    assert_eq!(
+        client.command(&["RENAME", "b", "b"]).await,
+        Some(RespType::SimpleString(String::from("OK")))
+    );
+    assert_eq!(client.command(&["DBSIZE"]).await, int(1));
+}
+
+#[tokio::test]
+async fn keys_and_scan_only_return_matching_keys() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let mut client = server.client();
+    for key in ["user:1", "user:2", "user:10", "order:1", "hello", "hallo"] {
+        client.command(&["SET", key, "v"]).await;
+    }
+    let keys = |reply: Option<RespType>| -> Vec<String> {
+        let mut keys: Vec<String> = match reply {
+            Some(RespType::Array(x)) => x
+                .into_iter()
+                .map(|x| match x {
+                    RespType::BulkString(Some(x)) => String::from_utf8_lossy(&x).into_owned(),
+                    other => panic!("Expected a key, got {:?}", other),
+                })
+                .collect(),
+            other => panic!("Expected an array, got {:?}", other),
+        };
+        keys.sort();
+        keys
+    };
+    assert_eq!(
+        keys(client.command(&["KEYS", "user:?"]).await),
+        ["user:1", "user:2"]
+    );
+    assert_eq!(
+        keys(client.command(&["KEYS", "h[ae]llo"]).await),
+        ["hallo", "hello"]
+    );
+    assert_eq!(keys(client.command(&["KEYS", "*"]).await).len(), 6);
+
+    // Small batches are filtered one at a time, until the cursor comes back around to 0
+    let mut cursor = String::from("0");
+    let mut scanned = Vec::new();
+    loop {
+        let reply = client
+            .command(&["SCAN", &cursor, "MATCH", "user:*", "COUNT", "2"])
+            .await;
+        match reply {
+            Some(RespType::Array(mut x)) if x.len() == 2 => {
+                scanned.extend(keys(x.pop()));
+                cursor = match x.pop() {
+                    Some(RespType::BulkString(Some(x))) => String::from_utf8_lossy(&x).into_owned(),
+                    other => panic!("Expected a cursor, got {:?}", other),
+                };
+            }
+            other => panic!("Expected a cursor and keys, got {:?}", other),
+        }
+        if cursor == "0" {
+            break;
+        }
+    }
+    scanned.sort();
+    assert_eq!(scanned, ["user:1", "user:10", "user:2"]);
+
+    let error = |x: &str| Some(RespType::Error(x.to_string()));
+    assert_eq!(
+        client.command(&["SCAN", "abc"]).await,
+        error("ERR invalid cursor")
+    );
+    assert_eq!(
+        client.command(&["SCAN", "0", "COUNT", "many"]).await,
+        error("ERR value is not an integer or out of range")
+    );
+    for options in [&["COUNT", "0"][..], &["COUNT"], &["LIMIT", "1"]] {
+        let mut args = vec!["SCAN", "0"];
+        args.extend_from_slice(options);
+        assert_eq!(client.command(&args).await, error("ERR syntax error"));
+    }
+}
+
+#[tokio::test]
+async fn databases_are_separate_and_can_be_swapped_and_flushed() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let mut client = server.client();
+    let ok = Some(RespType::SimpleString(String::from("OK")));
+    let error = |x: &str| Some(RespType::Error(x.to_string()));
+    let bulk = |x: &str| Some(RespType::BulkString(Some(Bytes::from(x.to_string()))));
+    let int = |x: i64| Some(RespType::Integer(x));
+
+    client.command(&["SET", "foo", "zero"]).await;
+    assert_eq!(client.command(&["SELECT", "15"]).await, ok);
+    assert_eq!(
+        client.command(&["GET", "foo"]).await,
+        Some(RespType::BulkString(None))
+    );
+    client.command(&["SET", "foo", "fifteen"]).await;
+    client.command(&["SET", "bar", "fifteen"]).await;
+    assert_eq!(client.command(&["DBSIZE"]).await, int(2));
+    assert_eq!(
+        client.command(&["SELECT", "16"]).await,
+        error("ERR DB index is out of range")
+    );
+    assert_eq!(
+        client.command(&["SELECT", "one"]).await,
+        error("ERR value is not an integer or out of range")
+    );
+
+    // Connections only ever see the database they selected, which a swap changes under them
+    let mut other = server.client();
+    assert_eq!(other.command(&["GET", "foo"]).await, bulk("zero"));
+    assert_eq!(client.command(&["SWAPDB", "0", "15"]).await, ok);
+    assert_eq!(other.command(&["GET", "foo"]).await, bulk("fifteen"));
+    assert_eq!(client.command(&["GET", "foo"]).await, bulk("zero"));
+    assert_eq!(
+        client.command(&["SWAPDB", "0", "16"]).await,
+        error("ERR DB index is out of range")
+    );
+    assert_eq!(
+        client.command(&["SWAPDB", "a", "0"]).await,
+        error("ERR invalid first DB index")
+    );
+
+    assert_eq!(other.command(&["FLUSHDB"]).await, ok);
+    assert_eq!(other.command(&["DBSIZE"]).await, int(0));
+    assert_eq!(client.command(&["DBSIZE"]).await, int(1));
+    other.command(&["SET", "foo", "again"]).await;
+    assert_eq!(client.command(&["FLUSHALL", "ASYNC"]).await, ok);
+    assert_eq!(client.command(&["DBSIZE"]).await, int(0));
+    assert_eq!(other.command(&["DBSIZE"]).await, int(0));
+}
+
+#[tokio::test]
+async fn config_set_changes_the_running_server() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let mut client = server.client();
+    let ok = Some(RespType::SimpleString(String::from("OK")));
+    let error = |x: &str| Some(RespType::Error(x.to_string()));
+    let bulks = |x: &[&str]| {
+        Some(RespType::Array(
+            x.iter()
+                .map(|x| RespType::BulkString(Some(Bytes::from(x.to_string()))))
+                .collect(),
+        ))
+    };
+
+    client.command(&["SET", "foo", "bar"]).await;
+    assert_eq!(
+        client
+            .command(&[
+                "CONFIG",
+                "SET",
+                "maxmemory",
+                "1",
+                "maxmemory-policy",
+                "noeviction"
+            ])
+            .await,
+        ok
+    );
+    assert_eq!(
+        client.command(&["SET", "baz", "qux"]).await,
+        error("OOM command not allowed when used memory > 'maxmemory'.")
+    );
+    assert_eq!(
+        client
+            .command(&["CONFIG", "GET", "maxmemory*", "MAXMEMORY"])
+            .await,
+        bulks(&[
+            "maxmemory",
+            "1",
+            "maxmemory-policy",
+            "noeviction",
+            "maxmemory-samples",
+            "5"
+        ])
+    );
+
+    // A change that fails leaves every parameter in it as it was
+    assert_eq!(
+        client
+            .command(&["CONFIG", "SET", "maxmemory", "0", "databases", "4"])
+            .await,
+        error("ERR CONFIG SET failed (possibly related to argument 'databases') - can't set immutable config")
+    );
+    assert_eq!(
+        client
+            .command(&["CONFIG", "SET", "no-such-option", "1"])
+            .await,
+        error("ERR Unknown option or number of arguments for CONFIG SET - 'no-such-option'")
+    );
+    assert_eq!(
+        client.command(&["CONFIG", "GET", "maxmemory"]).await,
+        bulks(&["maxmemory", "1"])
+    );
+    assert_eq!(
+        client.command(&["CONFIG", "GET", "nothing*"]).await,
+        bulks(&[])
+    );
+    assert_eq!(
+        client.command(&["CONFIG", "SET", "maxmemory", "0"]).await,
+        ok
+    );
+    assert_eq!(client.command(&["SET", "baz", "qux"]).await, ok);
+}
+
+#[tokio::test]
+async fn info_reports_the_sections_asked_for() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let mut client = server.client();
+    let text = |reply: Option<RespType>| match reply {
+        Some(RespType::BulkString(Some(x))) => String::from_utf8_lossy(&x).to_string(),
+        other => panic!("Expected a bulk string, got {:?}", other),
+    };
+    client.command(&["SET", "a", "1"]).await;
+    client.command(&["SET", "b", "2", "EX", "100"]).await;
+    client.command(&["GET", "a"]).await;
+    client.command(&["GET", "missing"]).await;
+
+    let info = text(client.command(&["INFO"]).await);
+    for section in [
+        "Server",
+        "Clients",
+        "Memory",
+        "Persistence",
+        "Stats",
+        "Replication",
+    ] {
+        assert!(info.contains(&format!("# {}\r\n", section)), "{}", section);
+    }
+    // In-process clients don't connect
+    assert!(info.contains("connected_clients:0\r\n"));
+    assert!(info.contains("keyspace_hits:1\r\nkeyspace_misses:1\r\n"));
+    assert!(info.contains("# Keyspace\r\ndb0:keys=2,expires=1\r\n"));
+    assert!(!info.contains("# Latencystats"));
+
+    let info = text(client.command(&["INFO", "CLIENTS", "keyspace"]).await);
+    assert_eq!(
+        info,
+        "# Clients\r\nconnected_clients:0\r\nmaxclients:10000\r\n\r\n# Keyspace\r\ndb0:keys=2,expires=1\r\n"
+    );
+    assert!(text(client.command(&["INFO", "all"]).await).contains("# Latencystats\r\n"));
+    assert_eq!(text(client.command(&["INFO", "nothing"]).await), "");
+}
+
+#[tokio::test]
+async fn slow_commands_are_logged_until_reset() {
+    let server = Server::builder()
+        .port(0)
+        .slowlog_log_slower_than(0)
+        .slowlog_max_len(3)
+        .build()
+        .await
+        .unwrap();
+    let mut client = server.client();
+    let bulk = |x: &str| RespType::BulkString(Some(Bytes::from(x.to_string())));
+
+    client.command(&["CLIENT", "SETNAME", "logger"]).await;
+    client.command(&["SET", "k", "v"]).await;
+    client.command(&["AUTH", "secret"]).await;
+    client.command(&["GET", "k"]).await;
+    assert_eq!(
+        client.command(&["SLOWLOG", "LEN"]).await,
+        Some(RespType::Integer(3))
+    );
+    match client.command(&["SLOWLOG", "GET", "2"]).await {
+        Some(RespType::Array(entries)) => {
+            // The SLOWLOG LEN above, then GET, but never AUTH
+            assert_eq!(entries.len(), 2);
+            match (&entries[0], &entries[1]) {
+                (RespType::Array(first), RespType::Array(second)) => {
+                    assert_eq!(first[0], RespType::Integer(3));
+                    assert_eq!(
+                        first[3],
+                        RespType::Array(vec![bulk("SLOWLOG"), bulk("LEN")])
+                    );
+                    assert_eq!(second[0], RespType::Integer(2));
+                    assert_eq!(second[3], RespType::Array(vec![bulk("GET"), bulk("k")]));
+                    assert_eq!(second[5], bulk("logger"));
+                }
+                other => panic!("Expected arrays, got {:?}", other),
+            }
+        }
+        other => panic!("Expected an array, got {:?}", other),
+    }
+    assert_eq!(
+        client.command(&["SLOWLOG", "GET", "-2"]).await,
+        Some(RespType::Error(String::from(
+            "ERR count should be greater than or equal to -1"
+        )))
+    );
+    assert_eq!(
+        client.command(&["SLOWLOG", "RESET"]).await,
+        Some(RespType::SimpleString(String::from("OK")))
+    );
+    // RESET is logged itself once it's run
+    assert_eq!(
+        client.command(&["SLOWLOG", "LEN"]).await,
+        Some(RespType::Integer(1))
+    );
+
+    // Negative turns the log off, leaving what's already in it
+    client
+        .command(&["CONFIG", "SET", "slowlog-log-slower-than", "-1"])
+        .await;
+    client.command(&["GET", "k"]).await;
+    assert_eq!(
+        client.command(&["SLOWLOG", "LEN"]).await,
+        Some(RespType::Integer(2))
+    );
+}
+
+// Increments KEYS[1] unless that would take it past ARGV[1]
+fn capped_incr<'a>(call: &'a mut ScriptCall<'_>) -> ScriptFuture<'a> {
+    Box::pin(async move {
+        let key = call.keys[0].clone();
+        let cap: i64 = String::from_utf8_lossy(&call.argv[0]).parse().unwrap();
+        let current = match call.call(&["GET", &key]).await? {
+            RespType::BulkString(Some(x)) => String::from_utf8_lossy(&x).parse().unwrap(),
+            _ => 0,
+        };
+        if current >= cap {
+            return Err(String::from("ERR capped"));
+        }
+        call.call(&["INCR", &key]).await
+    })
+}
+
+fn subscribes<'a>(call: &'a mut ScriptCall<'_>) -> ScriptFuture<'a> {
+    Box::pin(async move { call.call(&["SUBSCRIBE", "channel"]).await })
+}
+
+#[tokio::test]
+async fn registered_scripts_run_with_eval_and_evalsha() {
+    let server = Server::builder()
+        .port(0)
+        .script("capped_incr", capped_incr)
+        .script("subscribes", subscribes)
+        .build()
+        .await
+        .unwrap();
+    let mut client = server.client();
+    let error = |x: &str| Some(RespType::Error(x.to_string()));
+    let eval = ["EVAL", "capped_incr", "1", "counter", "2"];
+    assert_eq!(client.command(&eval).await, Some(RespType::Integer(1)));
+    assert_eq!(client.command(&eval).await, Some(RespType::Integer(2)));
+    assert_eq!(client.command(&eval).await, error("ERR capped"));
+
+    // EVAL loaded it already, and SCRIPT LOAD gives the same SHA1
+    let sha = match client.command(&["SCRIPT", "LOAD", "capped_incr"]).await {
+        Some(RespType::BulkString(Some(x))) => String::from_utf8(x.to_vec()).unwrap(),
+        other => panic!("Expected a bulk string, got {:?}", other),
+    };
+    assert_eq!(sha.len(), 40);
+    assert_eq!(
+        client
+            .command(&["SCRIPT", "EXISTS", &sha.to_uppercase(), "abc"])
+            .await,
+        Some(RespType::Array(vec![
+            RespType::Integer(1),
+            RespType::Integer(0)
+        ]))
+    );
+    let evalsha = ["EVALSHA", &sha, "1", "other", "5"];
+    assert_eq!(client.command(&evalsha).await, Some(RespType::Integer(1)));
+    client.command(&["SCRIPT", "FLUSH"]).await;
+    assert_eq!(
+        client.command(&evalsha).await,
+        error("NOSCRIPT No matching script. Please use EVAL.")
+    );
+
+    assert_eq!(
+        client.command(&["EVAL", "missing", "0"]).await,
+        error("ERR Error compiling script, no script is registered as 'missing'")
+    );
+    assert_eq!(
+        client.command(&["EVAL", "subscribes", "0"]).await,
+        error("ERR This Redis command is not allowed from script")
+    );
+    // Queued like any other command
+    client.command(&["MULTI"]).await;
+    client.command(&eval).await;
+    assert_eq!(
+        client.command(&["EXEC"]).await,
+        Some(RespType::Array(vec![RespType::Error(String::from(
+            "ERR capped"
+        ))]))
+    );
+}
+
+#[tokio::test]
+async fn acl_log_records_where_refused_commands_were_run_from() {
+    let server = Server::builder()
+        .port(0)
+        .script("capped_incr", capped_incr)
+        .build()
+        .await
+        .unwrap();
+    let mut client = server.client();
+    let bulk = |x: &str| RespType::BulkString(Some(Bytes::from(x.to_string())));
+    client
+        .command(&[
+            "ACL", "SETUSER", "alice", "on", "nopass", "~*", "+@all", "-incr",
+        ])
+        .await;
+    client.command(&["AUTH", "alice", "anything"]).await;
+
+    client.command(&["MULTI"]).await;
+    client.command(&["INCR", "counter"]).await;
+    client.command(&["DISCARD"]).await;
+    assert_eq!(
+        client
+            .command(&["EVAL", "capped_incr", "1", "counter", "5"])
+            .await,
+        Some(RespType::Error(String::from(
+            "NOPERM User alice has no permissions to run the 'incr' command"
+        )))
+    );
+    client.command(&["INCR", "counter"]).await;
+
+    let contexts = match client.command(&["ACL", "LOG"]).await {
+        Some(RespType::Array(entries)) => entries
+            .into_iter()
+            .map(|x| match x {
+                RespType::Array(fields) => fields[5].clone(),
+                other => panic!("Expected an array, got {:?}", other),
+            })
+            .collect::<Vec<_>>(),
+        other => panic!("Expected an array, got {:?}", other),
+    };
+    assert_eq!(contexts, [bulk("toplevel"), bulk("lua"), bulk("multi")]);
+}
+
+#[tokio::test]
+async fn command_describes_commands_from_the_command_table() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let mut client = server.client();
+    let bulk = |x: &str| RespType::BulkString(Some(Bytes::from(x.to_string())));
+    let status = |x: &str| RespType::SimpleString(x.to_string());
+
+    let count = match client.command(&["COMMAND", "COUNT"]).await {
+        Some(RespType::Integer(x)) => x,
+        other => panic!("Expected an integer, got {:?}", other),
+    };
+    match client.command(&["COMMAND"]).await {
+        Some(RespType::Array(x)) => assert_eq!(x.len() as i64, count),
+        other => panic!("Expected an array, got {:?}", other),
+    }
+
+    match client
+        .command(&["COMMAND", "INFO", "SET", "config", "nosuchcommand"])
+        .await
+    {
+        Some(RespType::Array(x)) => {
+            assert_eq!(
+                x[0],
+                RespType::Array(vec![
+                    bulk("set"),
+                    RespType::Integer(-3),
+                    RespType::Array(vec![status("write"), status("denyoom")]),
+                    RespType::Integer(1),
+                    RespType::Integer(1),
+                    RespType::Integer(1),
+                    RespType::Array(vec![status("@write"), status("@string"), status("@slow")]),
+                    RespType::Array(Vec::new()),
+                    RespType::Array(Vec::new()),
+                    RespType::Array(Vec::new()),
+                ])
+            );
+            // CONFIG only runs with a subcommand, which it lists
+            match &x[1] {
+                RespType::Array(config) => {
+                    assert_eq!(config[..2], [bulk("config"), RespType::Integer(-2)]);
+                    match &config[9] {
+                        RespType::Array(x) => assert_eq!(x.len(), 2),
+                        other => panic!("Expected an array, got {:?}", other),
+                    }
+                }
+                other => panic!("Expected an array, got {:?}", other),
+            }
+            assert_eq!(x[2], RespType::BulkString(None));
+        }
+        other => panic!("Expected an array, got {:?}", other),
+    }
+
+    assert_eq!(
+        client.command(&["COMMAND", "DOCS", "get"]).await,
+        Some(RespType::Array(vec![
+            bulk("get"),
+            RespType::Array(vec![bulk("group"), bulk("string")]),
+        ]))
+    );
+    assert_eq!(
+        client
+            .command(&["COMMAND", "GETKEYS", "MSET", "a", "1", "b", "2"])
+            .await,
+        Some(RespType::Array(vec![bulk("a"), bulk("b")]))
+    );
+    assert_eq!(
+        client.command(&["COMMAND", "GETKEYS", "GET"]).await,
+        Some(RespType::Error(String::from(
+            "ERR Invalid number of arguments specified for command"
+        )))
+    );
+}
@@ -0,0 +1,686 @@
+use redis_starter_rust::config::ProtectedAccess;
+use redis_starter_rust::resp::resp_deserializer::FrameDecoder;
+use redis_starter_rust::resp::RespType;
+use redis_starter_rust::Server;
+
+use bytes::{Bytes, BytesMut};
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn start_server() -> SocketAddr {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let address = server.local_addr();
+    tokio::spawn(server.run());
+    address
+}
+
+// Sends `request` and reads until the server closes the connection
+async fn send_and_read_to_end(address: SocketAddr, request: &[u8]) -> String {
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream.write_all(request).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    String::from_utf8(response).unwrap()
+}
+
+#[tokio::test]
+async fn malformed_requests_get_an_error_and_are_disconnected() {
+    let address = start_server().await;
+    let cases: [(&[u8], &str); 4] = [
+        (b"*1\r\n$abc\r\n", "invalid bulk length"),
+        (
+            b"*1\r\n$4\r\nPINGxx\r\n",
+            "bulk string not terminated by CRLF",
+        ),
+        (b"*-3\r\n", "invalid multibulk length"),
+        (b"GET \"foo\r\n", "unbalanced quotes in request"),
+    ];
+    for (request, error) in cases {
+        assert_eq!(
+            send_and_read_to_end(address, request).await,
+            format!("-ERR Protocol error: {}\r\n", error)
+        );
+    }
+}
+
+#[tokio::test]
+async fn oversized_lengths_are_rejected_up_front() {
+    let address = start_server().await;
+    assert_eq!(
+        send_and_read_to_end(address, b"*2\r\n$3\r\nGET\r\n$10737418240\r\n").await,
+        "-ERR Protocol error: invalid bulk length\r\n"
+    );
+
+    let server = Server::builder()
+        .port(0)
+        .proto_max_multibulk_len(2)
+        .build()
+        .await
+        .unwrap();
+    let address = server.local_addr();
+    tokio::spawn(server.run());
+    assert_eq!(
+        send_and_read_to_end(address, b"*3\r\n").await,
+        "-ERR Protocol error: invalid multibulk length\r\n"
+    );
+}
+
+#[tokio::test]
+async fn replies_before_the_error_are_still_sent() {
+    let address = start_server().await;
+    assert_eq!(
+        send_and_read_to_end(address, b"*1\r\n$4\r\nPING\r\n*1\r\n$x\r\n").await,
+        "+PONG\r\n-ERR Protocol error: invalid bulk length\r\n"
+    );
+}
+
+#[tokio::test]
+async fn commands_with_bad_arguments_get_an_error_and_the_connection_goes_on() {
+    let address = start_server().await;
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let request = b"GET\r\nNOSUCH a\r\nCLIENT KILL\r\n\
+        ZADD z x m\r\nBLPOP l -1\r\nCLIENT NOSUCH\r\nPING\r\n";
+    stream.write_all(request).await.unwrap();
+
+    let expected = "-ERR wrong number of arguments for 'get' command\r\n\
+        -ERR unknown command 'NOSUCH'\r\n\
+        -ERR wrong number of arguments for 'client|kill' command\r\n\
+        -ERR value is not a valid float\r\n-ERR timeout is negative\r\n\
+        -ERR unknown subcommand or wrong number of arguments for 'nosuch'. Try CLIENT HELP.\r\n\
+        +PONG\r\n";
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(String::from_utf8(reply).unwrap(), expected);
+}
+
+#[tokio::test]
+async fn other_connections_are_unaffected() {
+    let address = start_server().await;
+    let mut healthy = TcpStream::connect(address).await.unwrap();
+    send_and_read_to_end(address, b"*1\r\n$abc\r\n").await;
+
+    healthy.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+    let mut reply = [0; 7];
+    healthy.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"+PONG\r\n");
+}
+
+#[tokio::test]
+async fn hello_switches_the_connection_to_resp3() {
+    let address = start_server().await;
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let mut decoder = FrameDecoder::new();
+    let mut buffer = BytesMut::new();
+    let mut next_reply = async |stream: &mut TcpStream, request: &[u8]| {
+        stream.write_all(request).await.unwrap();
+        loop {
+            if let Some((reply, _)) = decoder.decode(&mut buffer).unwrap() {
+                return reply;
+            }
+            stream.read_buf(&mut buffer).await.unwrap();
+        }
+    };
+
+    let reply = next_reply(&mut stream, b"HELLO\r\n").await;
+    assert!(matches!(reply, RespType::Array(x) if x.len() == 12));
+    let reply = next_reply(&mut stream, b"HELLO 4\r\n").await;
+    assert_eq!(
+        reply,
+        RespType::Error(String::from("NOPROTO unsupported protocol version"))
+    );
+    match next_reply(&mut stream, b"HELLO 3\r\n").await {
+        RespType::Map(fields) => assert!(fields.contains(&(
+            RespType::BulkString(Some(Bytes::from("proto"))),
+            RespType::Integer(3)
+        ))),
+        other => panic!("Expected a map, got {:?}", other),
+    }
+    // Replies that don't differ between the protocols are unchanged
+    let reply = next_reply(&mut stream, b"PING\r\n").await;
+    assert_eq!(reply, RespType::SimpleString(String::from("PONG")));
+    // Missing values are RESP3's null, and INFO is marked as plain text
+    let reply = next_reply(&mut stream, b"GET missing\r\n").await;
+    assert_eq!(reply, RespType::Null);
+    let reply = next_reply(&mut stream, b"LPOP missing 2\r\n").await;
+    assert_eq!(reply, RespType::Null);
+    match next_reply(&mut stream, b"INFO stats\r\n").await {
+        RespType::VerbatimString(format, text) => {
+            assert_eq!(format, "txt");
+            assert!(text.starts_with(b"# Stats\r\n"));
+        }
+        other => panic!("Expected a verbatim string, got {:?}", other),
+    }
+    let reply = next_reply(&mut stream, b"HELLO 2\r\n").await;
+    assert!(matches!(reply, RespType::Array(_)));
+    let reply = next_reply(&mut stream, b"GET missing\r\n").await;
+    assert_eq!(reply, RespType::BulkString(None));
+}
+
+#[tokio::test]
+async fn clients_have_to_auth_when_a_password_is_required() {
+    let server = Server::builder()
+        .port(0)
+        .requirepass("secret")
+        .build()
+        .await
+        .unwrap();
+    let address = server.local_addr();
+    tokio::spawn(server.run());
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let mut exchange = async |request: &[u8], expected: &str| {
+        stream.write_all(request).await.unwrap();
+        let mut reply = vec![0; expected.len()];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(String::from_utf8(reply).unwrap(), expected);
+    };
+
+    exchange(b"GET foo\r\n", "-NOAUTH Authentication required.\r\n").await;
+    exchange(b"MULTI\r\n", "-NOAUTH Authentication required.\r\n").await;
+    exchange(
+        b"AUTH wrong\r\n",
+        "-WRONGPASS invalid username-password pair or user is disabled.\r\n",
+    )
+    .await;
+    exchange(
+        b"HELLO 2 AUTH alice secret\r\n",
+        "-WRONGPASS invalid username-password pair or user is disabled.\r\n",
+    )
+    .await;
+    exchange(b"AUTH default secret\r\n", "+OK\r\n").await;
+    exchange(b"SET foo bar\r\n", "+OK\r\n").await;
+    exchange(b"ACL SETUSER alice on >pass ~* +@all\r\n", "+OK\r\n").await;
+    exchange(b"AUTH alice pass\r\n", "+OK\r\n").await;
+
+    // HELLO can authenticate too
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream
+        .write_all(b"HELLO 3 AUTH default secret\r\nGET foo\r\nQUIT\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    assert!(response.ends_with(b"$3\r\nbar\r\n+OK\r\n"));
+
+    // As can users other than the default one, with passwords of their own
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream
+        .write_all(b"HELLO 3 AUTH alice pass\r\nGET foo\r\nQUIT\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    assert!(response.ends_with(b"$3\r\nbar\r\n+OK\r\n"));
+}
+
+#[tokio::test]
+async fn pipelined_commands_are_answered_in_order() {
+    let address = start_server().await;
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let mut request = Vec::new();
+    let mut expected = String::new();
+    for i in 1..=1000 {
+        request.extend_from_slice(b"*2\r\n$4\r\nINCR\r\n$1\r\nn\r\n");
+        expected.push_str(&format!(":{}\r\n", i));
+    }
+    // Mixed with inline commands, and ending in a command cut in two
+    request.extend_from_slice(b"GET n\r\n*2\r\n$3\r\nGET");
+    expected.push_str("$4\r\n1000\r\n$4\r\n1000\r\n");
+    stream.write_all(&request).await.unwrap();
+    stream.write_all(b"\r\n$1\r\nn\r\n").await.unwrap();
+
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(String::from_utf8(reply).unwrap(), expected);
+}
+
+#[tokio::test]
+async fn large_values_survive_the_round_trip() {
+    let address = start_server().await;
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let value: Vec<u8> = (0..3 * 1024 * 1024 + 7).map(|x| (x % 251) as u8).collect();
+    let mut request = format!("*3\r\n$3\r\nSET\r\n$3\r\nbig\r\n${}\r\n", value.len()).into_bytes();
+    request.extend_from_slice(&value);
+    request.extend_from_slice(b"\r\n*2\r\n$3\r\nGET\r\n$3\r\nbig\r\n");
+    // Sent in pieces, so the body arrives over several reads
+    for piece in request.chunks(100_000) {
+        stream.write_all(piece).await.unwrap();
+    }
+
+    let mut expected = format!("+OK\r\n${}\r\n", value.len()).into_bytes();
+    expected.extend_from_slice(&value);
+    expected.extend_from_slice(b"\r\n");
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert!(reply == expected);
+}
+
+#[tokio::test]
+async fn debug_is_refused_unless_enabled() {
+    let server = Server::builder().port(0).build().await.unwrap();
+    let mut client = server.client();
+    tokio::spawn(server.run());
+
+    match client.command(&["DEBUG", "PANIC"]).await {
+        Some(RespType::Error(x)) => assert!(x.starts_with("ERR DEBUG command not allowed")),
+        other => panic!("Expected an error, got {:?}", other),
+    }
+    assert_eq!(
+        client.command(&["PING"]).await,
+        Some(RespType::SimpleString(String::from("PONG")))
+    );
+}
+
+#[tokio::test]
+async fn a_panicking_connection_is_closed_without_affecting_others() {
+    let server = Server::builder()
+        .port(0)
+        .enable_debug_command(ProtectedAccess::Local)
+        .build()
+        .await
+        .unwrap();
+    let address = server.local_addr();
+    let mut client = server.client();
+    tokio::spawn(server.run());
+
+    assert_eq!(send_and_read_to_end(address, b"DEBUG PANIC\r\n").await, "");
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream.write_all(b"PING\r\n").await.unwrap();
+    let mut reply = [0; 7];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"+PONG\r\n");
+
+    match client.command(&["INFO", "stats"]).await {
+        Some(RespType::BulkString(Some(x))) => {
+            assert!(String::from_utf8_lossy(&x).contains("connection_panics:1\r\n"))
+        }
+        other => panic!("Expected a bulk string, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn connections_past_the_per_ip_limit_are_turned_away() {
+    let server = Server::builder()
+        .port(0)
+        .max_connections_per_ip(1)
+        .build()
+        .await
+        .unwrap();
+    let address = server.local_addr();
+    let mut client = server.client();
+    tokio::spawn(server.run());
+
+    let mut first = TcpStream::connect(address).await.unwrap();
+    first.write_all(b"PING\r\n").await.unwrap();
+    let mut reply = [0; 7];
+    first.read_exact(&mut reply).await.unwrap();
+    assert_eq!(
+        send_and_read_to_end(address, b"").await,
+        "-ERR max number of clients per IP reached\r\n"
+    );
+    match client.command(&["INFO", "stats"]).await {
+        Some(RespType::BulkString(Some(x))) => {
+            assert!(String::from_utf8_lossy(&x).contains("rejected_connections:1\r\n"))
+        }
+        other => panic!("Expected a bulk string, got {:?}", other),
+    }
+
+    // The slot is given back once the connection closes
+    drop(first);
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    let mut second = TcpStream::connect(address).await.unwrap();
+    second.write_all(b"PING\r\n").await.unwrap();
+    second.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"+PONG\r\n");
+}
+
+#[tokio::test]
+async fn connections_past_maxclients_are_turned_away() {
+    let server = Server::builder()
+        .port(0)
+        .maxclients(1)
+        .build()
+        .await
+        .unwrap();
+    let address = server.local_addr();
+    tokio::spawn(server.run());
+
+    let mut first = TcpStream::connect(address).await.unwrap();
+    first.write_all(b"PING\r\n").await.unwrap();
+    let mut reply = [0; 7];
+    first.read_exact(&mut reply).await.unwrap();
+    assert_eq!(
+        send_and_read_to_end(address, b"").await,
+        "-ERR max number of clients reached\r\n"
+    );
+}
+
+#[tokio::test]
+async fn idle_clients_are_disconnected_but_subscribers_are_not() {
+    let server = Server::builder().port(0).timeout(1).build().await.unwrap();
+    let address = server.local_addr();
+    let mut client = server.client();
+    tokio::spawn(server.run());
+
+    let mut idle = TcpStream::connect(address).await.unwrap();
+    let mut subscriber = TcpStream::connect(address).await.unwrap();
+    subscriber.write_all(b"SUBSCRIBE news\r\n").await.unwrap();
+    let confirmation = "*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n";
+    let mut buffer = vec![0; confirmation.len()];
+    subscriber.read_exact(&mut buffer).await.unwrap();
+
+    // Closed some time after a second of silence
+    let mut rest = Vec::new();
+    tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        idle.read_to_end(&mut rest),
+    )
+    .await
+    .expect("Expected the idle client to be disconnected")
+    .unwrap();
+    assert!(rest.is_empty());
+
+    client.command(&["PUBLISH", "news", "hi"]).await;
+    let message = "*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n";
+    let mut buffer = vec![0; message.len()];
+    subscriber.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(String::from_utf8_lossy(&buffer), message);
+}
+
+#[tokio::test]
+async fn the_unix_socket_is_served_alongside_tcp() {
+    let path = std::env::temp_dir().join(format!("redis-test-{}.sock", std::process::id()));
+    let server = Server::builder()
+        .port(0)
+        .unixsocket(&path)
+        .unixsocketperm(0o700)
+        .build()
+        .await
+        .unwrap();
+    let address = server.local_addr();
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(server.run());
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o700);
+
+    let mut unix = tokio::net::UnixStream::connect(&path).await.unwrap();
+    let mut tcp = TcpStream::connect(address).await.unwrap();
+    unix.write_all(b"SET k v\r\n").await.unwrap();
+    let mut reply = [0; 5];
+    unix.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"+OK\r\n");
+    tcp.write_all(b"GET k\r\n").await.unwrap();
+    let mut reply = [0; 7];
+    tcp.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"$1\r\nv\r\n");
+
+    // Replicas can only sync over TCP, and the connection carries on after being refused
+    unix.write_all(b"PSYNC ? -1\r\nGET k\r\n").await.unwrap();
+    let expected = b"-ERR PSYNC is only supported over TCP\r\n$1\r\nv\r\n";
+    let mut reply = vec![0; expected.len()];
+    unix.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply, expected);
+
+    // The socket goes away along with the server
+    shutdown.shutdown();
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(5);
+    while path.exists() {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "Expected the socket to be removed"
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn subscribers_receive_published_messages() {
+    let address = start_server().await;
+    let mut subscriber = TcpStream::connect(address).await.unwrap();
+    let mut publisher = TcpStream::connect(address).await.unwrap();
+    let mut buffer = BytesMut::new();
+
+    subscriber
+        .write_all(b"SUBSCRIBE news sport\r\nPSUBSCRIBE n*\r\nGET k\r\nPING\r\n")
+        .await
+        .unwrap();
+    let expected = concat!(
+        "*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n",
+        "*3\r\n$9\r\nsubscribe\r\n$5\r\nsport\r\n:2\r\n",
+        "*3\r\n$10\r\npsubscribe\r\n$2\r\nn*\r\n:3\r\n",
+        "-ERR Can't execute 'get': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context\r\n",
+        "*2\r\n$4\r\npong\r\n$0\r\n\r\n",
+    );
+    while buffer.len() < expected.len() {
+        subscriber.read_buf(&mut buffer).await.unwrap();
+    }
+    assert_eq!(String::from_utf8_lossy(&buffer), expected);
+
+    publisher
+        .write_all(b"PUBLISH news hello\r\n")
+        .await
+        .unwrap();
+    let mut reply = [0; 4];
+    publisher.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b":2\r\n");
+
+    buffer.clear();
+    let expected = concat!(
+        "*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n",
+        "*4\r\n$8\r\npmessage\r\n$2\r\nn*\r\n$4\r\nnews\r\n$5\r\nhello\r\n",
+    );
+    while buffer.len() < expected.len() {
+        subscriber.read_buf(&mut buffer).await.unwrap();
+    }
+    assert_eq!(String::from_utf8_lossy(&buffer), expected);
+
+    // Once the subscriber leaves, nobody receives anything
+    drop(subscriber);
+    let mut receivers = 2;
+    while receivers != 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        publisher
+            .write_all(b"PUBLISH news again\r\n")
+            .await
+            .unwrap();
+        publisher.read_exact(&mut reply).await.unwrap();
+        receivers = reply[1] - b'0';
+    }
+}
+
+#[tokio::test]
+async fn clients_are_listed_and_killed() {
+    let address = start_server().await;
+    let mut worker = TcpStream::connect(address).await.unwrap();
+    let mut admin = TcpStream::connect(address).await.unwrap();
+    let mut decoder = FrameDecoder::new();
+    let mut buffer = BytesMut::new();
+    let mut next_reply = async |stream: &mut TcpStream, request: &[u8]| {
+        stream.write_all(request).await.unwrap();
+        loop {
+            if let Some((reply, _)) = decoder.decode(&mut buffer).unwrap() {
+                return reply;
+            }
+            if stream.read_buf(&mut buffer).await.unwrap() == 0 {
+                panic!("Expected a reply before the connection closed");
+            }
+        }
+    };
+
+    let ok = RespType::SimpleString(String::from("OK"));
+    assert_eq!(
+        next_reply(&mut worker, b"CLIENT SETNAME worker\r\n").await,
+        ok
+    );
+    assert_eq!(
+        next_reply(&mut worker, b"CLIENT GETNAME\r\n").await,
+        RespType::BulkString(Some(Bytes::from("worker")))
+    );
+    let worker_id = match next_reply(&mut worker, b"CLIENT ID\r\n").await {
+        RespType::Integer(x) => x,
+        other => panic!("Expected an integer, got {:?}", other),
+    };
+    assert_eq!(
+        next_reply(&mut admin, b"CLIENT SETNAME \"has space\"\r\n").await,
+        RespType::Error(String::from(
+            "ERR Client names cannot contain spaces, newlines or special characters."
+        ))
+    );
+    assert_eq!(
+        next_reply(&mut admin, b"CLIENT GETNAME\r\n").await,
+        RespType::BulkString(None)
+    );
+
+    let list = match next_reply(&mut admin, b"CLIENT LIST\r\n").await {
+        RespType::BulkString(Some(x)) => String::from_utf8(x.to_vec()).unwrap(),
+        other => panic!("Expected a bulk string, got {:?}", other),
+    };
+    let lines: Vec<&str> = list.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with(&format!("id={} ", worker_id)));
+    assert!(lines[0].contains(" name=worker ") && lines[0].ends_with(" cmd=client|id"));
+    assert!(lines[1].ends_with(" cmd=client|list"));
+
+    // The old form needs an exact address, the new one counts what it killed
+    assert_eq!(
+        next_reply(&mut admin, b"CLIENT KILL 127.0.0.1:1\r\n").await,
+        RespType::Error(String::from("ERR No such client"))
+    );
+    assert_eq!(
+        next_reply(&mut admin, b"CLIENT KILL USER default\r\n").await,
+        RespType::Integer(1)
+    );
+    let mut rest = Vec::new();
+    worker.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+    let request = format!("CLIENT KILL ID {}\r\n", worker_id);
+    assert_eq!(
+        next_reply(&mut admin, request.as_bytes()).await,
+        RespType::Integer(0)
+    );
+}
+
+#[tokio::test]
+async fn monitors_see_every_other_clients_commands() {
+    let address = start_server().await;
+    let mut monitor = TcpStream::connect(address).await.unwrap();
+    let mut client = TcpStream::connect(address).await.unwrap();
+    let client_address = client.local_addr().unwrap();
+    let (mut monitor_decoder, mut client_decoder) = (FrameDecoder::new(), FrameDecoder::new());
+    let (mut monitor_buffer, mut client_buffer) = (BytesMut::new(), BytesMut::new());
+    let read_frame =
+        async |stream: &mut TcpStream, decoder: &mut FrameDecoder, buffer: &mut BytesMut| loop {
+            if let Some((frame, _)) = decoder.decode(buffer).unwrap() {
+                return frame;
+            }
+            if stream.read_buf(buffer).await.unwrap() == 0 {
+                panic!("Expected a frame before the connection closed");
+            }
+        };
+    let ok = RespType::SimpleString(String::from("OK"));
+
+    monitor.write_all(b"MONITOR\r\n").await.unwrap();
+    assert_eq!(
+        read_frame(&mut monitor, &mut monitor_decoder, &mut monitor_buffer).await,
+        ok
+    );
+    let requests: [&[u8]; 4] = [
+        b"SET foo \"bar baz\"\r\n",
+        b"CONFIG GET maxmemory\r\n",
+        b"SELECT 2\r\n",
+        b"GET foo\r\n",
+    ];
+    for request in requests {
+        client.write_all(request).await.unwrap();
+        read_frame(&mut client, &mut client_decoder, &mut client_buffer).await;
+    }
+
+    // CONFIG is an admin command, which monitors don't see
+    let expected = [
+        format!("[0 {}] \"SET\" \"foo\" \"bar baz\"", client_address),
+        format!("[0 {}] \"SELECT\" \"2\"", client_address),
+        format!("[2 {}] \"GET\" \"foo\"", client_address),
+    ];
+    for expected in expected {
+        match read_frame(&mut monitor, &mut monitor_decoder, &mut monitor_buffer).await {
+            RespType::SimpleString(line) => {
+                let (time, rest) = line.split_once(' ').unwrap();
+                assert!(time.parse::<f64>().is_ok(), "{}", line);
+                assert_eq!(rest, expected);
+            }
+            other => panic!("Expected a simple string, got {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn keyspace_events_are_published_once_turned_on() {
+    let address = start_server().await;
+    let mut subscriber = TcpStream::connect(address).await.unwrap();
+    let mut client = TcpStream::connect(address).await.unwrap();
+    let (mut subscriber_decoder, mut client_decoder) = (FrameDecoder::new(), FrameDecoder::new());
+    let (mut subscriber_buffer, mut client_buffer) = (BytesMut::new(), BytesMut::new());
+    let read_frame =
+        async |stream: &mut TcpStream, decoder: &mut FrameDecoder, buffer: &mut BytesMut| loop {
+            if let Some((frame, _)) = decoder.decode(buffer).unwrap() {
+                return frame;
+            }
+            if stream.read_buf(buffer).await.unwrap() == 0 {
+                panic!("Expected a frame before the connection closed");
+            }
+        };
+
+    subscriber
+        .write_all(b"PSUBSCRIBE __key*@0__:*\r\n")
+        .await
+        .unwrap();
+    read_frame(
+        &mut subscriber,
+        &mut subscriber_decoder,
+        &mut subscriber_buffer,
+    )
+    .await;
+    // Nothing is published until the config asks for it
+    let requests: [&[u8]; 6] = [
+        b"SET before 1\r\n",
+        b"CONFIG SET notify-keyspace-events Eglx\r\n",
+        b"SET ignored 1\r\n",
+        b"RPUSH list a\r\n",
+        b"LPOP list\r\n",
+        b"SET short 1 PX 1\r\n",
+    ];
+    for request in requests {
+        client.write_all(request).await.unwrap();
+        read_frame(&mut client, &mut client_decoder, &mut client_buffer).await;
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    client.write_all(b"GET short\r\n").await.unwrap();
+    read_frame(&mut client, &mut client_decoder, &mut client_buffer).await;
+
+    let expected = [
+        ("__keyevent@0__:rpush", "list"),
+        ("__keyevent@0__:lpop", "list"),
+        ("__keyevent@0__:del", "list"),
+        ("__keyevent@0__:expire", "short"),
+        ("__keyevent@0__:expired", "short"),
+    ];
+    for (channel, key) in expected {
+        let frame = read_frame(
+            &mut subscriber,
+            &mut subscriber_decoder,
+            &mut subscriber_buffer,
+        )
+        .await;
+        let bulk = |x: &str| RespType::BulkString(Some(Bytes::from(x.to_string())));
+        assert_eq!(
+            frame,
+            RespType::Array(vec![
+                bulk("pmessage"),
+                bulk("__key*@0__:*"),
+                bulk(channel),
+                bulk(key),
+            ])
+        );
+    }
+}
@@ -0,0 +1,969 @@
+mod support;
+
+use redis_starter_rust::resp::RespType;
+use std::time::Duration;
+use support::{bulk, nil, ok, wait_for_reply, Topology, EMPTY_RDB};
+
+#[tokio::test]
+async fn writes_reach_every_replica() {
+    let mut topology = Topology::start(2).await;
+    assert_eq!(topology.master(&["SET", "foo", "bar"]).await, ok());
+    assert_eq!(topology.master(&["SET", "count", "42"]).await, ok());
+    topology
+        .assert_replicated(&["GET", "foo"], bulk("bar"))
+        .await;
+    topology
+        .assert_replicated(&["GET", "count"], bulk("42"))
+        .await;
+    match topology.master(&["INFO", "replication"]).await {
+        RespType::BulkString(Some(x)) => {
+            let info = String::from_utf8_lossy(&x);
+            assert!(info.contains("connected_slaves:2\r\n"));
+            assert!(info.contains("slave1:ip=127.0.0.1,port="));
+        }
+        other => panic!("Expected a bulk string, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn replicas_refuse_writes_from_their_own_clients() {
+    let mut topology = Topology::start(1).await;
+    let readonly = || {
+        Some(RespType::Error(String::from(
+            "READONLY You can't write against a read only replica.",
+        )))
+    };
+    let replica = &mut topology.replicas[0];
+    assert_eq!(replica.command(&["SET", "foo", "bar"]).await, readonly());
+    assert_eq!(
+        replica.command(&["PING"]).await,
+        Some(RespType::SimpleString(String::from("PONG")))
+    );
+    assert_eq!(replica.command(&["MULTI"]).await, Some(ok()));
+    assert_eq!(replica.command(&["DEL", "foo"]).await, readonly());
+    assert_eq!(
+        replica.command(&["EXEC"]).await,
+        Some(RespType::Error(String::from(
+            "EXECABORT Transaction discarded because of previous errors."
+        )))
+    );
+
+    assert_eq!(topology.master(&["SET", "foo", "baz"]).await, ok());
+    topology
+        .assert_replicated(&["GET", "foo"], bulk("baz"))
+        .await;
+}
+
+#[tokio::test]
+async fn deletes_reach_every_replica() {
+    let mut topology = Topology::start(2).await;
+    topology.master(&["SET", "foo", "bar"]).await;
+    topology
+        .assert_replicated(&["GET", "foo"], bulk("bar"))
+        .await;
+    assert_eq!(
+        topology.master(&["DEL", "foo", "missing"]).await,
+        RespType::Integer(1)
+    );
+    topology.assert_replicated(&["GET", "foo"], nil()).await;
+}
+
+#[tokio::test]
+async fn renames_reach_every_replica() {
+    let mut topology = Topology::start(2).await;
+    topology.master(&["SET", "foo", "bar"]).await;
+    assert_eq!(topology.master(&["RENAME", "foo", "baz"]).await, ok());
+    topology
+        .assert_replicated(&["GET", "baz"], bulk("bar"))
+        .await;
+    topology
+        .assert_replicated(&["EXISTS", "foo"], RespType::Integer(0))
+        .await;
+}
+
+#[tokio::test]
+async fn writes_to_other_databases_reach_every_replica() {
+    let mut topology = Topology::start(2).await;
+    topology.master(&["SET", "foo", "zero"]).await;
+    topology.master(&["SELECT", "3"]).await;
+    topology.master(&["SET", "foo", "three"]).await;
+    topology.master(&["SWAPDB", "3", "4"]).await;
+    topology.master(&["SELECT", "0"]).await;
+    topology.master(&["SET", "bar", "zero"]).await;
+    topology
+        .assert_replicated(&["GET", "bar"], bulk("zero"))
+        .await;
+    topology
+        .assert_replicated(&["GET", "foo"], bulk("zero"))
+        .await;
+    topology.assert_replicated(&["SELECT", "4"], ok()).await;
+    topology
+        .assert_replicated(&["GET", "foo"], bulk("three"))
+        .await;
+    topology.master(&["FLUSHALL"]).await;
+    topology
+        .assert_replicated(&["DBSIZE"], RespType::Integer(0))
+        .await;
+}
+
+#[tokio::test]
+async fn expiring_keys_are_deleted_on_replicas() {
+    let mut topology = Topology::start(1).await;
+    topology.master(&["SET", "foo", "bar", "PX", "50"]).await;
+    topology.assert_replicated(&["GET", "foo"], nil()).await;
+    // Replicas only drop expired keys once the master's DEL arrives
+    topology
+        .assert_replicated(
+            &["SCAN", "0"],
+            RespType::Array(vec![bulk("0"), RespType::Array(vec![])]),
+        )
+        .await;
+    // Nothing reads the key on the master, so it's up to the cron to remove it
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(2);
+    loop {
+        match topology.master(&["INFO", "stats"]).await {
+            RespType::BulkString(Some(x))
+                if String::from_utf8_lossy(&x).contains("expired_keys:1\r\n") =>
+            {
+                break
+            }
+            RespType::BulkString(Some(_)) => (),
+            other => panic!("Expected a bulk string, got {:?}", other),
+        }
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "The key was never expired"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn expirations_set_later_reach_every_replica() {
+    let mut topology = Topology::start(1).await;
+    topology.master(&["SET", "foo", "bar", "EX", "100"]).await;
+    topology.master(&["PERSIST", "foo"]).await;
+    topology
+        .assert_replicated(&["TTL", "foo"], RespType::Integer(-1))
+        .await;
+    topology.master(&["EXPIRE", "foo", "100"]).await;
+    topology
+        .assert_replicated(&["TTL", "foo"], RespType::Integer(100))
+        .await;
+}
+
+#[tokio::test]
+async fn counters_reach_every_replica() {
+    let mut topology = Topology::start(1).await;
+    topology.master(&["INCR", "n"]).await;
+    topology.master(&["INCRBY", "n", "10"]).await;
+    topology.master(&["DECR", "n"]).await;
+    topology.master(&["DECRBY", "n", "5"]).await;
+    topology.assert_replicated(&["GET", "n"], bulk("5")).await;
+}
+
+#[tokio::test]
+async fn lists_reach_every_replica() {
+    let mut topology = Topology::start(1).await;
+    topology.master(&["RPUSH", "l", "b", "c", "d"]).await;
+    topology.master(&["LPUSH", "l", "a"]).await;
+    topology.master(&["RPOP", "l", "2"]).await;
+    topology
+        .assert_replicated(
+            &["LRANGE", "l", "0", "-1"],
+            RespType::Array(vec![bulk("a"), bulk("b")]),
+        )
+        .await;
+}
+
+#[tokio::test]
+async fn hashes_reach_every_replica() {
+    let mut topology = Topology::start(1).await;
+    topology.master(&["HSET", "h", "a", "1", "b", "2"]).await;
+    topology.master(&["HDEL", "h", "a"]).await;
+    topology
+        .assert_replicated(
+            &["HGETALL", "h"],
+            RespType::Array(vec![bulk("b"), bulk("2")]),
+        )
+        .await;
+}
+
+#[tokio::test]
+async fn sorted_sets_reach_every_replica() {
+    let mut topology = Topology::start(1).await;
+    topology
+        .master(&["ZADD", "z", "1.5", "a", "-inf", "b"])
+        .await;
+    topology
+        .assert_replicated(&["ZSCORE", "z", "a"], bulk("1.5"))
+        .await;
+    topology
+        .assert_replicated(&["ZRANK", "z", "b"], RespType::Integer(0))
+        .await;
+}
+
+#[tokio::test]
+async fn streams_reach_every_replica_with_the_masters_ids() {
+    let mut topology = Topology::start(1).await;
+    let id = topology.master(&["XADD", "s", "*", "f", "v"]).await;
+    topology
+        .assert_replicated(
+            &["XRANGE", "s", "-", "+"],
+            RespType::Array(vec![RespType::Array(vec![
+                id,
+                RespType::Array(vec![bulk("f"), bulk("v")]),
+            ])]),
+        )
+        .await;
+}
+
+// Writes to the same key from several connections at once have to reach the replica in the
+// order the master applied them
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_writes_reach_replicas_in_the_order_they_were_applied() {
+    let mut topology = Topology::start(1).await;
+    let writers: Vec<_> = (0..8)
+        .map(|i| {
+            let mut writer = topology.master.another();
+            tokio::spawn(async move {
+                for _ in 0..100 {
+                    writer.command(&["APPEND", "log", &i.to_string()]).await;
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.await.unwrap();
+    }
+    let applied = topology.master(&["GET", "log"]).await;
+    topology.assert_replicated(&["GET", "log"], applied).await;
+}
+
+#[tokio::test]
+async fn replicas_load_the_masters_dataset_when_they_sync() {
+    let master = redis_starter_rust::Server::builder()
+        .port(0)
+        .build()
+        .await
+        .unwrap();
+    let mut client = master.client();
+    // Enough for the RDB file to go out in many pieces
+    let value = "v".repeat(100);
+    for i in 0..2000 {
+        client
+            .command(&["SET", &format!("key:{}", i), &value])
+            .await;
+    }
+    client.command(&["RPUSH", "list", "a", "b"]).await;
+    client.command(&["SELECT", "3"]).await;
+    client.command(&["SET", "foo", "three"]).await;
+    let port = master.local_addr().port();
+    let master_shutdown = master.shutdown_handle();
+    tokio::spawn(master.run());
+
+    let replica = redis_starter_rust::Server::builder()
+        .port(0)
+        .replica_of("127.0.0.1", port)
+        .build()
+        .await
+        .unwrap();
+    let mut client = replica.client();
+    let shutdown = replica.shutdown_handle();
+    tokio::spawn(replica.run());
+    wait_for_reply(&mut client, &["DBSIZE"], RespType::Integer(2001)).await;
+    assert_eq!(
+        client.command(&["LRANGE", "list", "0", "-1"]).await,
+        Some(RespType::Array(vec![bulk("a"), bulk("b")]))
+    );
+    assert_eq!(
+        client.command(&["GET", "key:1999"]).await,
+        Some(bulk(&value))
+    );
+    client.command(&["SELECT", "3"]).await;
+    assert_eq!(client.command(&["GET", "foo"]).await, Some(bulk("three")));
+    shutdown.shutdown();
+    master_shutdown.shutdown();
+}
+
+#[tokio::test]
+async fn wait_counts_replicas_that_acknowledged_writes() {
+    let mut topology = Topology::start(3).await;
+    topology.master(&["SET", "foo", "bar"]).await;
+    assert_eq!(
+        topology.master(&["WAIT", "3", "1000"]).await,
+        RespType::Integer(3)
+    );
+}
+
+#[tokio::test]
+async fn wait_counts_writes_from_every_connection() {
+    let mut topology = Topology::start(2).await;
+    let mut writer = topology.master.another();
+    writer.command(&["SET", "foo", "bar"]).await;
+    assert_eq!(
+        topology.master(&["WAIT", "2", "1000"]).await,
+        RespType::Integer(2)
+    );
+    // Acknowledged means applied, so the write is already visible
+    for replica in &mut topology.replicas {
+        assert_eq!(replica.command(&["GET", "foo"]).await, Some(bulk("bar")));
+    }
+    match topology.master(&["INFO", "persistence"]).await {
+        RespType::BulkString(Some(x)) => {
+            assert!(String::from_utf8_lossy(&x).contains("rdb_changes_since_last_save:1\r\n"))
+        }
+        other => panic!("Expected a bulk string, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn replicas_give_up_on_a_hung_master_and_retry() {
+    // Accepts connections but never answers the handshake
+    let hung_master = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = hung_master.local_addr().unwrap().port();
+    let server = redis_starter_rust::Server::builder()
+        .port(0)
+        .replica_of("127.0.0.1", port)
+        .repl_timeout(1)
+        .build()
+        .await
+        .unwrap();
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(server.run());
+
+    let (_first, _) = hung_master.accept().await.unwrap();
+    let retried = tokio::time::timeout(Duration::from_secs(5), hung_master.accept()).await;
+    assert!(retried.is_ok(), "Replica never retried the handshake");
+    shutdown.shutdown();
+}
+
+#[tokio::test]
+async fn replicas_can_refuse_to_serve_stale_data() {
+    let hung_master = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = hung_master.local_addr().unwrap().port();
+    let mut clients = Vec::new();
+    for serve_stale_data in [true, false] {
+        let server = redis_starter_rust::Server::builder()
+            .port(0)
+            .replica_of("127.0.0.1", port)
+            .replica_serve_stale_data(serve_stale_data)
+            .build()
+            .await
+            .unwrap();
+        clients.push(server.client());
+        tokio::spawn(server.run());
+    }
+
+    assert_eq!(clients[0].command(&["GET", "foo"]).await, Some(nil()));
+    assert_eq!(
+        clients[1].command(&["GET", "foo"]).await,
+        Some(RespType::Error(String::from(
+            "MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'."
+        )))
+    );
+    match clients[1].command(&["INFO", "replication"]).await {
+        Some(RespType::BulkString(Some(x))) => {
+            assert!(String::from_utf8_lossy(&x).contains("master_link_status:down\r\n"))
+        }
+        other => panic!("Expected a bulk string, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn replicas_authenticate_with_masterauth() {
+    let master = redis_starter_rust::Server::builder()
+        .port(0)
+        .requirepass("secret")
+        .build()
+        .await
+        .unwrap();
+    let port = master.local_addr().port();
+    let mut master_client = master.client();
+    tokio::spawn(master.run());
+    let mut replicas = Vec::new();
+    for masterauth in ["wrong", "secret"] {
+        let replica = redis_starter_rust::Server::builder()
+            .port(0)
+            .replica_of("127.0.0.1", port)
+            .masterauth(masterauth)
+            .build()
+            .await
+            .unwrap();
+        replicas.push(replica.client());
+        tokio::spawn(replica.run());
+    }
+
+    // Full resyncs don't carry the dataset yet, so only write once the replica is in
+    wait_for_reply(
+        &mut master_client,
+        &["WAIT", "0", "0"],
+        RespType::Integer(1),
+    )
+    .await;
+    master_client.command(&["SET", "foo", "bar"]).await;
+    wait_for_reply(&mut replicas[1], &["GET", "foo"], bulk("bar")).await;
+    assert_eq!(
+        master_client.command(&["WAIT", "2", "200"]).await,
+        Some(RespType::Integer(1))
+    );
+    assert_eq!(replicas[0].command(&["GET", "foo"]).await, Some(nil()));
+}
+
+#[tokio::test]
+async fn replicas_that_disconnect_are_forgotten() {
+    let mut topology = Topology::start(2).await;
+    topology.stop_replica(0);
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while topology.master(&["WAIT", "0", "0"]).await != RespType::Integer(1) {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "Replica wasn't dropped"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn replicas_ack_exactly_the_bytes_they_applied() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let master = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = master.local_addr().unwrap().port();
+    let server = redis_starter_rust::Server::builder()
+        .port(0)
+        .replica_of("127.0.0.1", port)
+        .build()
+        .await
+        .unwrap();
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(server.run());
+
+    // PING, REPLCONF listening-port, REPLCONF capa, then PSYNC, starting the stream at 100
+    let (mut link, _) = master.accept().await.unwrap();
+    let mut buffer = [0; 1024];
+    for reply in ["+PONG\r\n", "+OK\r\n", "+OK\r\n"] {
+        assert!(link.read(&mut buffer).await.unwrap() > 0);
+        link.write_all(reply.as_bytes()).await.unwrap();
+    }
+    assert!(link.read(&mut buffer).await.unwrap() > 0);
+    link.write_all(b"+FULLRESYNC 8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb 100\r\n")
+        .await
+        .unwrap();
+    link.write_all(EMPTY_RDB).await.unwrap();
+
+    let set = b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n";
+    let ping = b"*1\r\n$4\r\nPING\r\n";
+    let getack = b"*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n";
+    let mut expected = 100 + set.len() + ping.len();
+    for _ in 0..2 {
+        link.write_all(set).await.unwrap();
+        link.write_all(ping).await.unwrap();
+        link.write_all(getack).await.unwrap();
+        let ack = format!(
+            "*3\r\n$8\r\nREPLCONF\r\n$3\r\nACK\r\n${}\r\n{}\r\n",
+            expected.to_string().len(),
+            expected
+        );
+        let mut reply = vec![0; ack.len()];
+        link.read_exact(&mut reply).await.unwrap();
+        assert_eq!(String::from_utf8(reply).unwrap(), ack);
+        // The next ack covers this GETACK as well
+        expected += getack.len() + set.len() + ping.len();
+    }
+    shutdown.shutdown();
+}
+
+#[tokio::test]
+async fn masters_refuse_getack_from_clients() {
+    let master = redis_starter_rust::Server::builder()
+        .port(0)
+        .build()
+        .await
+        .unwrap();
+    let mut client = master.client();
+    assert_eq!(
+        client.command(&["REPLCONF", "GETACK", "*"]).await,
+        Some(RespType::Error(String::from(
+            "ERR REPLCONF GETACK is only sent by a master to its replicas"
+        )))
+    );
+    assert_eq!(
+        client.command(&["PING"]).await,
+        Some(RespType::SimpleString(String::from("PONG")))
+    );
+}
+
+#[tokio::test]
+async fn the_stream_selects_a_database_before_the_first_write() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let master = redis_starter_rust::Server::builder()
+        .port(0)
+        .build()
+        .await
+        .unwrap();
+    let mut client = master.client();
+    let mut link = tokio::net::TcpStream::connect(master.local_addr())
+        .await
+        .unwrap();
+    let shutdown = master.shutdown_handle();
+    tokio::spawn(master.run());
+
+    let mut buffer = [0; 1024];
+    for command in [
+        "*1\r\n$4\r\nPING\r\n",
+        "*3\r\n$8\r\nREPLCONF\r\n$14\r\nlistening-port\r\n$4\r\n6380\r\n",
+        "*3\r\n$8\r\nREPLCONF\r\n$4\r\ncapa\r\n$6\r\npsync2\r\n",
+    ] {
+        link.write_all(command.as_bytes()).await.unwrap();
+        assert!(link.read(&mut buffer).await.unwrap() > 0);
+    }
+    link.write_all(b"*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n")
+        .await
+        .unwrap();
+
+    let select = b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n";
+    let set = b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n";
+    let contains = |stream: &[u8], needle: &[u8]| stream.windows(needle.len()).any(|x| x == needle);
+    let mut stream = Vec::new();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    // Writes made before the replica is registered aren't propagated, so keep writing until one is
+    while !contains(&stream, &[&select[..], &set[..]].concat()) {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "Write never arrived"
+        );
+        assert_eq!(client.command(&["SET", "foo", "bar"]).await, Some(ok()));
+        let read = tokio::time::timeout(Duration::from_millis(50), link.read(&mut buffer)).await;
+        if let Ok(read) = read {
+            stream.extend_from_slice(&buffer[..read.unwrap()]);
+        }
+    }
+
+    // Later writes to the same database go out without another SELECT
+    stream.clear();
+    assert_eq!(client.command(&["SET", "foo", "bar"]).await, Some(ok()));
+    while !contains(&stream, set) {
+        let read = link.read(&mut buffer).await.unwrap();
+        assert!(read > 0, "Master closed the link");
+        stream.extend_from_slice(&buffer[..read]);
+    }
+    assert!(!contains(&stream, select));
+    shutdown.shutdown();
+}
+
+#[tokio::test]
+async fn replicas_resync_when_the_stream_stops_making_sense() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let master = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = master.local_addr().unwrap().port();
+    let server = redis_starter_rust::Server::builder()
+        .port(0)
+        .replica_of("127.0.0.1", port)
+        .build()
+        .await
+        .unwrap();
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(server.run());
+
+    let mut buffer = [0; 1024];
+    for attempt in 0..2 {
+        let (mut link, _) = tokio::time::timeout(Duration::from_secs(5), master.accept())
+            .await
+            .expect("Replica didn't reconnect")
+            .unwrap();
+        for reply in ["+PONG\r\n", "+OK\r\n", "+OK\r\n"] {
+            assert!(link.read(&mut buffer).await.unwrap() > 0);
+            link.write_all(reply.as_bytes()).await.unwrap();
+        }
+        assert!(link.read(&mut buffer).await.unwrap() > 0);
+        link.write_all(b"+FULLRESYNC 8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb 0\r\n")
+            .await
+            .unwrap();
+        link.write_all(EMPTY_RDB).await.unwrap();
+        if attempt == 1 {
+            break;
+        }
+        // Masters never send reads, so the replica has lost its place in the stream
+        link.write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n")
+            .await
+            .unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(5), link.read(&mut buffer)).await;
+        assert_eq!(read.expect("Replica kept the link").unwrap(), 0);
+    }
+    shutdown.shutdown();
+}
+
+#[tokio::test]
+async fn role_describes_both_ends_of_the_link() {
+    let mut topology = Topology::start(1).await;
+    match topology.master(&["ROLE"]).await {
+        RespType::Array(role) => {
+            assert_eq!(role[0], bulk("master"));
+            match &role[2] {
+                RespType::Array(replicas) => match &replicas[..] {
+                    [RespType::Array(replica)] => {
+                        assert_eq!(replica[0], bulk("127.0.0.1"));
+                        assert_ne!(replica[1], bulk("0"));
+                    }
+                    other => panic!("Expected one replica, got {:?}", other),
+                },
+                other => panic!("Expected the replicas, got {:?}", other),
+            }
+        }
+        other => panic!("Expected an array, got {:?}", other),
+    }
+    match topology.replicas[0].command(&["ROLE"]).await {
+        Some(RespType::Array(role)) => {
+            assert_eq!(role[..2], [bulk("slave"), bulk("127.0.0.1")]);
+            assert_eq!(role[3], bulk("connected"));
+        }
+        other => panic!("Expected an array, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn transactions_reach_replicas_once_executed() {
+    let mut topology = Topology::start(1).await;
+    topology.master(&["MULTI"]).await;
+    topology.master(&["SET", "a", "1"]).await;
+    topology.master(&["INCR", "a"]).await;
+    topology.master(&["XADD", "s", "*", "f", "v"]).await;
+    topology.master(&["EXEC"]).await;
+    topology.assert_replicated(&["GET", "a"], bulk("2")).await;
+    topology
+        .assert_replicated(&["XLEN", "s"], RespType::Integer(1))
+        .await;
+}
+
+#[tokio::test]
+async fn replicaof_switches_roles_at_runtime() {
+    let mut clients = Vec::new();
+    let mut ports = Vec::new();
+    let mut shutdown_handles = Vec::new();
+    for _ in 0..2 {
+        let server = redis_starter_rust::Server::builder()
+            .port(0)
+            .build()
+            .await
+            .unwrap();
+        clients.push(server.client());
+        ports.push(server.local_addr().port().to_string());
+        shutdown_handles.push(server.shutdown_handle());
+        tokio::spawn(server.run());
+    }
+    let (mut master, mut replica) = (clients.remove(0), clients.remove(0));
+    let replicaof = ["REPLICAOF", "127.0.0.1", &ports[0]];
+    assert_eq!(replica.command(&replicaof).await, Some(ok()));
+    assert_eq!(
+        replica.command(&replicaof).await,
+        Some(RespType::SimpleString(String::from(
+            "OK Already connected to specified master"
+        )))
+    );
+    wait_for_reply(&mut master, &["WAIT", "0", "0"], RespType::Integer(1)).await;
+    master.command(&["SET", "a", "1"]).await;
+    wait_for_reply(&mut replica, &["GET", "a"], bulk("1")).await;
+
+    // Once promoted it stops following its old master, and takes writes of its own
+    assert_eq!(
+        replica.command(&["REPLICAOF", "NO", "ONE"]).await,
+        Some(ok())
+    );
+    wait_for_reply(&mut master, &["WAIT", "0", "0"], RespType::Integer(0)).await;
+    master.command(&["SET", "a", "2"]).await;
+    assert_eq!(replica.command(&["SET", "b", "1"]).await, Some(ok()));
+    assert_eq!(replica.command(&["GET", "a"]).await, Some(bulk("1")));
+    match replica.command(&["ROLE"]).await {
+        Some(RespType::Array(role)) => assert_eq!(role[0], bulk("master")),
+        other => panic!("Expected an array, got {:?}", other),
+    }
+    assert_eq!(
+        replica.command(&["REPLICAOF", "127.0.0.1", "port"]).await,
+        Some(RespType::Error(String::from("ERR Invalid master port")))
+    );
+    for handle in shutdown_handles {
+        handle.shutdown();
+    }
+}
+
+#[tokio::test]
+async fn masters_continue_the_stream_for_replicas_that_reconnect() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let master = redis_starter_rust::Server::builder()
+        .port(0)
+        .build()
+        .await
+        .unwrap();
+    let address = master.local_addr();
+    let mut client = master.client();
+    let shutdown = master.shutdown_handle();
+    tokio::spawn(master.run());
+
+    let psync = |replid: &str, offset: &str| {
+        format!(
+            "*3\r\n$5\r\nPSYNC\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+            replid.len(),
+            replid,
+            offset.len(),
+            offset
+        )
+    };
+    // Reads from the link until `needle` has come through
+    async fn read_until(link: &mut tokio::net::TcpStream, stream: &mut Vec<u8>, needle: &[u8]) {
+        let mut buffer = [0; 1024];
+        while !stream.windows(needle.len()).any(|x| x == needle) {
+            let read = tokio::time::timeout(Duration::from_secs(5), link.read(&mut buffer))
+                .await
+                .expect("Master stopped sending")
+                .unwrap();
+            assert!(read > 0, "Master closed the link");
+            stream.extend_from_slice(&buffer[..read]);
+        }
+    }
+
+    let mut link = tokio::net::TcpStream::connect(address).await.unwrap();
+    link.write_all(psync("?", "-1").as_bytes()).await.unwrap();
+    let mut stream = Vec::new();
+    read_until(&mut link, &mut stream, b"\r\n").await;
+    let line = String::from_utf8_lossy(&stream).into_owned();
+    let (replid, offset) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["+FULLRESYNC", replid, offset, ..] => (replid.to_string(), offset.to_string()),
+        _ => panic!("Expected a full resync, got {:?}", line),
+    };
+
+    // Whatever's written while the replica is away comes through once it carries on
+    let set = b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n";
+    assert_eq!(client.command(&["SET", "foo", "bar"]).await, Some(ok()));
+    read_until(&mut link, &mut stream, set).await;
+    drop(link);
+    assert_eq!(client.command(&["SET", "foo", "bar"]).await, Some(ok()));
+
+    let mut link = tokio::net::TcpStream::connect(address).await.unwrap();
+    let next = (offset.parse::<usize>().unwrap() + 1).to_string();
+    link.write_all(psync(&replid, &next).as_bytes())
+        .await
+        .unwrap();
+    let mut continued = Vec::new();
+    let select = b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n";
+    let expected = [&select[..], set].concat();
+    read_until(&mut link, &mut continued, &expected).await;
+    assert!(continued.starts_with(format!("+CONTINUE {}\r\n", replid).as_bytes()));
+    // Pings and acks may have been asked for in between
+    let end = continued
+        .windows(expected.len())
+        .position(|x| x == expected)
+        .unwrap()
+        + expected.len();
+    let mut rest = continued.split_off(end);
+    read_until(&mut link, &mut rest, set).await;
+
+    // Any other stream, or an offset the backlog doesn't have, takes a full resync
+    for (replid, offset) in [(replid.as_str(), "1000000"), ("0123456789", next.as_str())] {
+        let mut link = tokio::net::TcpStream::connect(address).await.unwrap();
+        link.write_all(psync(replid, offset).as_bytes())
+            .await
+            .unwrap();
+        let mut stream = Vec::new();
+        read_until(&mut link, &mut stream, b"\r\n").await;
+        assert!(stream.starts_with(b"+FULLRESYNC "));
+    }
+    shutdown.shutdown();
+}
+
+#[tokio::test]
+async fn replicas_ask_to_continue_where_they_left_off() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let master = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = master.local_addr().unwrap().port();
+    let server = redis_starter_rust::Server::builder()
+        .port(0)
+        .replica_of("127.0.0.1", port)
+        .build()
+        .await
+        .unwrap();
+    let mut client = server.client();
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(server.run());
+
+    let replid = "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb";
+    let select = b"*2\r\n$6\r\nSELECT\r\n$1\r\n2\r\n";
+    let set = |value: &str| format!("*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\n{}\r\n", value);
+    let mut buffer = [0; 1024];
+    let mut psyncs = Vec::new();
+    for attempt in 0..2 {
+        let (mut link, _) = tokio::time::timeout(Duration::from_secs(5), master.accept())
+            .await
+            .expect("Replica didn't reconnect")
+            .unwrap();
+        for reply in ["+PONG\r\n", "+OK\r\n", "+OK\r\n"] {
+            assert!(link.read(&mut buffer).await.unwrap() > 0);
+            link.write_all(reply.as_bytes()).await.unwrap();
+        }
+        let read = link.read(&mut buffer).await.unwrap();
+        psyncs.push(String::from_utf8_lossy(&buffer[..read]).into_owned());
+        if attempt == 0 {
+            let resync = format!("+FULLRESYNC {} 100\r\n", replid);
+            link.write_all(resync.as_bytes()).await.unwrap();
+            link.write_all(EMPTY_RDB).await.unwrap();
+            link.write_all(select).await.unwrap();
+            link.write_all(set("bar").as_bytes()).await.unwrap();
+            client.command(&["SELECT", "2"]).await;
+            wait_for_reply(&mut client, &["GET", "foo"], bulk("bar")).await;
+        } else {
+            // The stream goes on in the database it had selected
+            link.write_all(b"+CONTINUE\r\n").await.unwrap();
+            link.write_all(set("baz").as_bytes()).await.unwrap();
+            wait_for_reply(&mut client, &["GET", "foo"], bulk("baz")).await;
+        }
+    }
+    assert!(psyncs[0].ends_with("$1\r\n?\r\n$2\r\n-1\r\n"));
+    let next = 100 + select.len() + set("bar").len() + 1;
+    assert!(psyncs[1].ends_with(&format!(
+        "${}\r\n{}\r\n${}\r\n{}\r\n",
+        replid.len(),
+        replid,
+        next.to_string().len(),
+        next
+    )));
+    shutdown.shutdown();
+}
+
+#[tokio::test]
+async fn replicas_that_stop_reading_are_dropped_without_holding_up_writes() {
+    use redis_starter_rust::redis::output::{ClientClass, OutputBufferLimit};
+    use tokio::io::AsyncWriteExt;
+
+    let limit = OutputBufferLimit {
+        hard: 1024 * 1024,
+        soft: 0,
+        soft_seconds: 0,
+    };
+    let master = redis_starter_rust::Server::builder()
+        .port(0)
+        .client_output_buffer_limit(ClientClass::Replica, limit)
+        .build()
+        .await
+        .unwrap();
+    let mut replica = tokio::net::TcpStream::connect(master.local_addr())
+        .await
+        .unwrap();
+    let mut client = master.client();
+    let shutdown = master.shutdown_handle();
+    tokio::spawn(master.run());
+
+    // Syncs, then never reads another byte
+    replica
+        .write_all(b"*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n")
+        .await
+        .unwrap();
+    wait_for_reply(&mut client, &["WAIT", "0", "0"], RespType::Integer(1)).await;
+
+    let value = "x".repeat(64 * 1024);
+    let writes = async {
+        for i in 0..256 {
+            let key = format!("key{}", i);
+            assert_eq!(client.command(&["SET", &key, &value]).await, Some(ok()));
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), writes)
+        .await
+        .expect("Writes waited on the replica");
+    wait_for_reply(&mut client, &["WAIT", "0", "0"], RespType::Integer(0)).await;
+    shutdown.shutdown();
+}
+
+#[tokio::test]
+async fn wait_times_out_once_for_all_replicas() {
+    use tokio::io::AsyncWriteExt;
+
+    let master = redis_starter_rust::Server::builder()
+        .port(0)
+        .build()
+        .await
+        .unwrap();
+    let address = master.local_addr();
+    let mut client = master.client();
+    let shutdown = master.shutdown_handle();
+    tokio::spawn(master.run());
+
+    // Replicas that never answer a GETACK
+    let mut replicas = Vec::new();
+    for _ in 0..3 {
+        let mut replica = tokio::net::TcpStream::connect(address).await.unwrap();
+        replica
+            .write_all(b"*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n")
+            .await
+            .unwrap();
+        replicas.push(replica);
+    }
+    wait_for_reply(&mut client, &["WAIT", "0", "0"], RespType::Integer(3)).await;
+    assert_eq!(client.command(&["SET", "a", "1"]).await, Some(ok()));
+
+    let started = tokio::time::Instant::now();
+    assert_eq!(
+        client.command(&["WAIT", "3", "300"]).await,
+        Some(RespType::Integer(0))
+    );
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(300));
+    assert!(
+        elapsed < Duration::from_millis(600),
+        "WAIT took {:?}",
+        elapsed
+    );
+    shutdown.shutdown();
+}
+
+#[tokio::test]
+async fn replicas_pass_the_stream_on_to_their_own_replicas() {
+    let start = |master: Option<u16>| async move {
+        let builder = redis_starter_rust::Server::builder().port(0);
+        let builder = match master {
+            Some(port) => builder.replica_of("127.0.0.1", port),
+            None => builder,
+        };
+        let server = builder.build().await.unwrap();
+        let (port, client, shutdown) = (
+            server.local_addr().port(),
+            server.client(),
+            server.shutdown_handle(),
+        );
+        tokio::spawn(server.run());
+        (port, client, shutdown)
+    };
+    let (master_port, mut master, master_shutdown) = start(None).await;
+    let (middle_port, mut middle, middle_shutdown) = start(Some(master_port)).await;
+    let (_, mut leaf, leaf_shutdown) = start(Some(middle_port)).await;
+    // Full resyncs don't carry the dataset yet, so the chain has to be linked before writing
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    loop {
+        match leaf.command(&["ROLE"]).await {
+            Some(RespType::Array(x)) if x[3] == bulk("connected") => break,
+            reply => assert!(
+                tokio::time::Instant::now() < deadline,
+                "Chain didn't link up, ROLE replied {:?}",
+                reply
+            ),
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    assert_eq!(master.command(&["SET", "foo", "bar"]).await, Some(ok()));
+    wait_for_reply(&mut leaf, &["GET", "foo"], bulk("bar")).await;
+    // Promoted, the middle node carries on the stream for its replica
+    assert_eq!(
+        middle.command(&["REPLICAOF", "NO", "ONE"]).await,
+        Some(ok())
+    );
+    assert_eq!(middle.command(&["SET", "foo", "baz"]).await, Some(ok()));
+    wait_for_reply(&mut leaf, &["GET", "foo"], bulk("baz")).await;
+
+    for shutdown in [master_shutdown, middle_shutdown, leaf_shutdown] {
+        shutdown.shutdown();
+    }
+}
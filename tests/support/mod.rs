@@ -0,0 +1,143 @@
+// Spins up replication topologies inside the test's own runtime. Every server binds an ephemeral
+// port, and is shut down when the topology is dropped.
+
+use redis_starter_rust::resp::RespType;
+use redis_starter_rust::{Client, Server, ShutdownHandle};
+
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+const SYNC_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// An RDB file with no keys, sent the way a master follows FULLRESYNC with it. A checksum of 0
+// isn't checked.
+pub const EMPTY_RDB: &[u8] = b"$18\r\nREDIS0011\xff\0\0\0\0\0\0\0\0";
+
+pub struct Topology {
+    pub master: Client,
+    pub replicas: Vec<Client>,
+    shutdown_handles: Vec<ShutdownHandle>,
+}
+
+impl Topology {
+    // Starts a master with `num_replicas` replicas, returning once every replica has synced
+    pub async fn start(num_replicas: usize) -> Self {
+        let master = Server::builder().port(0).build().await.unwrap();
+        let master_port = master.local_addr().port();
+        let mut topology = Topology {
+            master: master.client(),
+            replicas: Vec::new(),
+            shutdown_handles: vec![master.shutdown_handle()],
+        };
+        tokio::spawn(master.run());
+
+        for _ in 0..num_replicas {
+            let replica = Server::builder()
+                .port(0)
+                .replica_of("127.0.0.1", master_port)
+                .build()
+                .await
+                .unwrap();
+            topology.replicas.push(replica.client());
+            topology.shutdown_handles.push(replica.shutdown_handle());
+            tokio::spawn(replica.run());
+        }
+
+        // With no writes outstanding, WAIT reports how many replicas are connected
+        let wait = ["WAIT", "0", "0"];
+        let synced = RespType::Integer(num_replicas as i64);
+        let deadline = Instant::now() + SYNC_TIMEOUT;
+        while topology.master.command(&wait).await != Some(synced.clone()) {
+            assert!(Instant::now() < deadline, "Replicas didn't sync in time");
+            sleep(POLL_INTERVAL).await;
+        }
+        // The master counts a replica as soon as the RDB file is out, which can be before the
+        // replica has loaded it
+        for replica in &mut topology.replicas {
+            while !matches!(
+                replica.command(&["ROLE"]).await,
+                Some(RespType::Array(role)) if role.get(3) == Some(&bulk("connected"))
+            ) {
+                assert!(Instant::now() < deadline, "Replicas didn't sync in time");
+                sleep(POLL_INTERVAL).await;
+            }
+        }
+        topology
+    }
+
+    // Shuts a replica down, which closes its connection to the master
+    pub fn stop_replica(&self, index: usize) {
+        // The master's handle comes first
+        self.shutdown_handles[index + 1].shutdown();
+    }
+
+    // Runs a command on the master and returns its reply
+    pub async fn master(&mut self, args: &[&str]) -> RespType {
+        self.master
+            .command(args)
+            .await
+            .expect("Master didn't reply")
+    }
+
+    // Waits until every replica replies to `args` with `expected`
+    pub async fn assert_replicated(&mut self, args: &[&str], expected: RespType) {
+        let deadline = Instant::now() + SYNC_TIMEOUT;
+        for (index, replica) in self.replicas.iter_mut().enumerate() {
+            loop {
+                let reply = replica.command(args).await;
+                if reply.as_ref() == Some(&expected) {
+                    break;
+                }
+                assert!(
+                    Instant::now() < deadline,
+                    "Replica {} replied {:?} to {:?}, expected {:?}",
+                    index,
+                    reply,
+                    args,
+                    expected
+                );
+                sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+impl Drop for Topology {
+    fn drop(&mut self) {
+        for handle in &self.shutdown_handles {
+            handle.shutdown();
+        }
+    }
+}
+
+// Waits until `client` replies to `args` with `expected`
+pub async fn wait_for_reply(client: &mut Client, args: &[&str], expected: RespType) {
+    let deadline = Instant::now() + SYNC_TIMEOUT;
+    loop {
+        let reply = client.command(args).await;
+        if reply.as_ref() == Some(&expected) {
+            return;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "Got {:?} in reply to {:?}, expected {:?}",
+            reply,
+            args,
+            expected
+        );
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+pub fn ok() -> RespType {
+    RespType::SimpleString(String::from("OK"))
+}
+
+pub fn bulk(value: &str) -> RespType {
+    RespType::BulkString(Some(value.to_string().into()))
+}
+
+pub fn nil() -> RespType {
+    RespType::BulkString(None)
+}
@@ -0,0 +1,112 @@
+use redis_starter_rust::redis::keyspace::KeyspaceMode;
+use redis_starter_rust::resp::resp_deserializer::FrameDecoder;
+use redis_starter_rust::resp::resp_serializer::serialize_resp_data;
+use redis_starter_rust::resp::RespType;
+use redis_starter_rust::Server;
+
+use bytes::{Bytes, BytesMut};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+struct Connection {
+    stream: TcpStream,
+    buffer: BytesMut,
+    decoder: FrameDecoder,
+}
+
+impl Connection {
+    async fn open(address: SocketAddr) -> Self {
+        Connection {
+            stream: TcpStream::connect(address).await.unwrap(),
+            buffer: BytesMut::new(),
+            decoder: FrameDecoder::new(),
+        }
+    }
+
+    async fn command(&mut self, args: &[&str]) -> RespType {
+        let command = RespType::Array(
+            args.iter()
+                .map(|x| RespType::BulkString(Some(Bytes::from(x.to_string()))))
+                .collect(),
+        );
+        self.stream
+            .write_all(&serialize_resp_data(command))
+            .await
+            .unwrap();
+        loop {
+            if let Some((reply, _)) = self.decoder.decode(&mut self.buffer).unwrap() {
+                return reply;
+            }
+            assert_ne!(self.stream.read_buf(&mut self.buffer).await.unwrap(), 0);
+        }
+    }
+}
+
+async fn start_server() -> (Server, SocketAddr) {
+    let server = Server::builder()
+        .port(0)
+        .keyspace_mode(KeyspaceMode::ThreadPerCore)
+        .threads(4)
+        .build()
+        .await
+        .unwrap();
+    let address = server.local_addr();
+    (server, address)
+}
+
+fn bulk(value: &str) -> RespType {
+    RespType::BulkString(Some(Bytes::from(value.to_string())))
+}
+
+#[tokio::test]
+async fn commands_from_every_connection_see_the_same_keyspace() {
+    let (server, address) = start_server().await;
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(server.run());
+
+    let mut connections = Vec::new();
+    for _ in 0..8 {
+        connections.push(Connection::open(address).await);
+    }
+    for (index, connection) in connections.iter_mut().enumerate() {
+        for key in 0..50 {
+            let key = format!("key:{}:{}", index, key);
+            let reply = connection.command(&["SET", &key, &key]).await;
+            assert_eq!(reply, RespType::SimpleString(String::from("OK")));
+        }
+    }
+    // Read every key back through a different connection than the one that wrote it
+    for (index, connection) in connections.iter_mut().enumerate() {
+        for key in 0..50 {
+            let key = format!("key:{}:{}", (index + 1) % 8, key);
+            assert_eq!(connection.command(&["GET", &key]).await, bulk(&key));
+        }
+    }
+
+    // Keys owned by different workers in one command
+    let keys: Vec<String> = (0..50).map(|x| format!("key:0:{}", x)).collect();
+    let mut del = vec!["DEL"];
+    del.extend(keys.iter().map(|x| x.as_str()));
+    assert_eq!(connections[3].command(&del).await, RespType::Integer(50));
+    match connections[5].command(&["KEYS", "*"]).await {
+        RespType::Array(x) => assert_eq!(x.len(), 7 * 50),
+        other => panic!("Expected an array, got {:?}", other),
+    }
+    shutdown.shutdown();
+}
+
+#[tokio::test]
+async fn in_process_clients_are_routed_to_the_workers() {
+    let (server, _) = start_server().await;
+    let mut client = server.client();
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(server.run());
+
+    for key in 0..100 {
+        let key = key.to_string();
+        client.command(&["SET", &key, &key]).await;
+        assert_eq!(client.command(&["GET", &key]).await, Some(bulk(&key)));
+    }
+    shutdown.shutdown();
+}